@@ -0,0 +1,178 @@
+//! Support for W3C Clear Key license acquisition
+//
+// When a manifest signals the W3C Clear Key DRM system (system id
+// e2719d58-a985-b3c9-781a-b030af78d30e) together with a license acquisition URL (a dashif:Laurl
+// element, or the obsolete clearkey:Laurl element), the content keys can be obtained by a simple
+// JSON exchange with the license server, as specified in the Encrypted Media Extensions
+// specification
+//
+//   https://www.w3.org/TR/encrypted-media/#clear-key
+//
+// and in the DASH-IF IOP guidelines on content protection
+//
+//   https://dashif.org/docs/IOP-Guidelines/DASH-IF-IOP-Part6-v5.0.0.pdf
+//
+// The license request is a JSON object of the form
+//
+//    {"kids":["nrQFDeRLSAKTLifXUIPiZg"],"type":"temporary"}
+//
+// where each KID is a 16 octet key identifier encoded in base64url without padding. The license
+// server responds with a JSON Web Key set
+//
+//    {"keys":[{"kty":"oct","kid":"nrQFDeRLSAKTLifXUIPiZg","k":"FmY0xnWCPCNaSpRG-tUuTQ"}],"type":"temporary"}
+//
+// The keys are converted to the hexadecimal KID:KEY format used by our decryption helpers.
+
+
+use std::collections::HashMap;
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use serde::{Serialize, Deserialize};
+use crate::{ContentProtection, DashMpdError};
+
+
+/// The DASH schemeIdUri identifying the W3C Clear Key DRM system.
+pub const CLEARKEY_SCHEME_ID_URI: &str = "urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e";
+
+/// The body of a Clear Key license request.
+#[derive(Debug, Serialize)]
+struct LicenseRequest {
+    kids: Vec<String>,
+    #[serde(rename = "type")]
+    session_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonWebKey {
+    kty: Option<String>,
+    kid: String,
+    k: String,
+}
+
+/// The body of a Clear Key license response (a JSON Web Key set).
+#[derive(Debug, Deserialize)]
+struct LicenseResponse {
+    keys: Vec<JsonWebKey>,
+}
+
+/// Returns true if this ContentProtection element signals the W3C Clear Key DRM system.
+pub fn is_clearkey(cp: &ContentProtection) -> bool {
+    cp.schemeIdUri.to_lowercase().eq(CLEARKEY_SCHEME_ID_URI)
+}
+
+/// The license acquisition URL specified in a Clear Key ContentProtection element, if present.
+/// The dashif:Laurl element is preferred over the obsolete clearkey:Laurl element.
+pub fn clearkey_laurl(cp: &ContentProtection) -> Option<String> {
+    if !is_clearkey(cp) {
+        return None;
+    }
+    cp.laurl.iter()
+        .chain(cp.clearkey_laurl.iter())
+        .filter_map(|l| l.content.as_ref())
+        .map(|u| u.trim().to_string())
+        .find(|u| !u.is_empty())
+}
+
+/// Normalize a KID as found in the @cenc:default_KID attribute (in UUID format, with or without
+/// hyphens) to 32 lowercase hexadecimal characters.
+pub fn normalize_kid(kid: &str) -> Option<String> {
+    let hex: String = kid.trim()
+        .chars()
+        .filter(|c| *c != '-')
+        .collect::<String>()
+        .to_lowercase();
+    if hex.len() == 32 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(hex)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn hex_to_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i+2], 16).ok())
+        .collect()
+}
+
+pub(crate) fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Build the JSON body of a Clear Key license request for the KIDs `kids`, which are specified in
+/// hexadecimal or UUID format.
+pub fn license_request_body(kids: &[String]) -> Result<String, DashMpdError> {
+    let mut encoded = Vec::new();
+    for kid in kids {
+        let hex = normalize_kid(kid)
+            .ok_or_else(|| DashMpdError::Decrypting(format!("invalid Clear Key KID {kid}")))?;
+        let octets = hex_to_bytes(&hex)
+            .ok_or_else(|| DashMpdError::Decrypting(format!("invalid Clear Key KID {kid}")))?;
+        encoded.push(BASE64_URL_SAFE_NO_PAD.encode(octets));
+    }
+    let req = LicenseRequest { kids: encoded, session_type: String::from("temporary") };
+    serde_json::to_string(&req)
+        .map_err(|e| DashMpdError::Decrypting(format!("serializing Clear Key license request: {e}")))
+}
+
+// Some license servers include base64 padding or use the standard base64 alphabet, in violation of
+// the specification.
+fn decode_base64url(s: &str) -> Option<Vec<u8>> {
+    let cleaned: String = s.trim()
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_");
+    BASE64_URL_SAFE_NO_PAD.decode(cleaned).ok()
+}
+
+/// Parse the JSON Web Key set returned by a Clear Key license server. Returns a map from KID to
+/// key, both in hexadecimal format, suitable for use with `DashDownloader::add_decryption_key`.
+pub fn parse_license_response(body: &[u8]) -> Result<HashMap<String, String>, DashMpdError> {
+    let response: LicenseResponse = serde_json::from_slice(body)
+        .map_err(|e| DashMpdError::Decrypting(format!("parsing Clear Key license response: {e}")))?;
+    let mut keys = HashMap::new();
+    for jwk in response.keys {
+        if let Some(kty) = &jwk.kty {
+            if !kty.eq("oct") {
+                continue;
+            }
+        }
+        let kid = decode_base64url(&jwk.kid)
+            .ok_or_else(|| DashMpdError::Decrypting(format!("invalid KID {} in Clear Key license", jwk.kid)))?;
+        let key = decode_base64url(&jwk.k)
+            .ok_or_else(|| DashMpdError::Decrypting(String::from("invalid key in Clear Key license")))?;
+        if kid.len() != 16 || key.len() != 16 {
+            return Err(DashMpdError::Decrypting(String::from("unexpected KID or key length in Clear Key license")));
+        }
+        keys.insert(bytes_to_hex(&kid), bytes_to_hex(&key));
+    }
+    Ok(keys)
+}
+
+/// Collect the Clear Key license acquisition URL and the KIDs to request from a set of
+/// ContentProtection elements attached to a selected Representation and its AdaptationSet. The
+/// KIDs are those specified by the @cenc:default_KID attribute on any of the elements (usually
+/// the `urn:mpeg:dash:mp4protection:2011` element).
+pub fn clearkey_license_request<'a, I>(cps: I) -> Option<(String, Vec<String>)>
+where
+    I: IntoIterator<Item = &'a ContentProtection>
+{
+    let mut laurl = None;
+    let mut kids = Vec::new();
+    for cp in cps {
+        if laurl.is_none() {
+            laurl = clearkey_laurl(cp);
+        }
+        if let Some(kid) = cp.default_KID.as_ref().and_then(|k| normalize_kid(k)) {
+            if !kids.contains(&kid) {
+                kids.push(kid);
+            }
+        }
+    }
+    match laurl {
+        Some(u) if !kids.is_empty() => Some((u, kids)),
+        _ => None,
+    }
+}
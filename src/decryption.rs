@@ -17,12 +17,13 @@
 
 use std::env;
 use std::path::Path;
+use std::sync::Arc;
 use std::process::Command;
 use std::ffi::OsStr;
 use tokio::fs;
 use tracing::{info, warn, error};
 use crate::DashMpdError;
-use crate::fetch::{DashDownloader, partial_process_output, tmp_file_path};
+use crate::fetch::{DashDownloader, Decryptor, PluginFuture, partial_process_output, tmp_file_path};
 
 
 pub async fn decrypt_mp4decrypt(
@@ -388,3 +389,84 @@ pub async fn decrypt_mp4box_container(
     }
     Ok(())
 }
+
+
+/// The decryptors implemented by running an external application, either installed locally or
+/// run in a Podman/Docker container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinDecryptor { Mp4decrypt, Shaka, ShakaContainer, Mp4Box, Mp4BoxContainer }
+
+impl BuiltinDecryptor {
+    /// The built-in decryptor with this name ("mp4decrypt", "shaka", "shaka-container", "mp4box"
+    /// or "mp4box-container").
+    pub fn from_name(name: &str) -> Option<BuiltinDecryptor> {
+        match name {
+            "mp4decrypt" => Some(BuiltinDecryptor::Mp4decrypt),
+            "shaka" => Some(BuiltinDecryptor::Shaka),
+            "shaka-container" => Some(BuiltinDecryptor::ShakaContainer),
+            "mp4box" => Some(BuiltinDecryptor::Mp4Box),
+            "mp4box-container" => Some(BuiltinDecryptor::Mp4BoxContainer),
+            _ => None,
+        }
+    }
+}
+
+impl Decryptor for BuiltinDecryptor {
+    fn name(&self) -> &str {
+        match self {
+            BuiltinDecryptor::Mp4decrypt => "mp4decrypt",
+            BuiltinDecryptor::Shaka => "shaka",
+            BuiltinDecryptor::ShakaContainer => "shaka-container",
+            BuiltinDecryptor::Mp4Box => "mp4box",
+            BuiltinDecryptor::Mp4BoxContainer => "mp4box-container",
+        }
+    }
+
+    fn decrypt<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        input_path: &'a Path,
+        output_path: &'a Path,
+        media_type: &'a str) -> PluginFuture<'a> {
+        Box::pin(async move {
+            match self {
+                BuiltinDecryptor::Mp4decrypt => decrypt_mp4decrypt(downloader, input_path, output_path, media_type).await,
+                BuiltinDecryptor::Shaka => decrypt_shaka(downloader, input_path, output_path, media_type).await,
+                BuiltinDecryptor::ShakaContainer => decrypt_shaka_container(downloader, input_path, output_path, media_type).await,
+                BuiltinDecryptor::Mp4Box => decrypt_mp4box(downloader, input_path, output_path, media_type).await,
+                BuiltinDecryptor::Mp4BoxContainer => decrypt_mp4box_container(downloader, input_path, output_path, media_type).await,
+            }
+        })
+    }
+}
+
+// Decrypt the media at inpath to outpath, trying each of the decryptors in the comma-separated
+// decryptor preference in turn. Decryptors registered with DashDownloader::with_decryptor() take
+// precedence over the built-in decryptors. The error from the last decryptor tried is returned if
+// none succeeds.
+pub(crate) async fn decrypt_media(
+    downloader: &DashDownloader,
+    inpath: &Path,
+    outpath: &Path,
+    media_type: &str) -> Result<(), DashMpdError>
+{
+    let mut result = Err(DashMpdError::Decrypting(String::from("unknown decryption application")));
+    for name in downloader.decryptor_preference.split(',') {
+        let decryptor = match downloader.registered_decryptor(name) {
+            Some(d) => d,
+            None => match BuiltinDecryptor::from_name(name) {
+                Some(d) => Arc::new(d),
+                None => {
+                    warn!("  Ignoring unknown decryptor preference {name}");
+                    continue;
+                },
+            },
+        };
+        result = decryptor.decrypt(downloader, inpath, outpath, media_type).await;
+        match result {
+            Ok(()) => return Ok(()),
+            Err(ref e) => warn!("  Decrypting {media_type} with {name} failed: {e}"),
+        }
+    }
+    result
+}
@@ -16,6 +16,8 @@ use std::collections::HashMap;
 use std::cmp::min;
 use std::ffi::OsStr;
 use std::num::NonZeroU32;
+use std::future::Future;
+use std::pin::Pin;
 use futures_util::TryFutureExt;
 use tracing::{trace, info, warn, error};
 use regex::Regex;
@@ -27,22 +29,27 @@ use backon::{ExponentialBuilder, Retryable};
 use governor::{Quota, RateLimiter};
 use lazy_static::lazy_static;
 use xot::{xmlname, Xot};
-use edit_distance::edit_distance;
-use crate::{MPD, Period, Representation, AdaptationSet, SegmentBase, DashMpdError};
-use crate::{parse, mux_audio_video, copy_video_to_container, copy_audio_to_container};
+use crate::{MPD, Period, Representation, AdaptationSet, AudioChannelConfiguration, SegmentBase, SegmentTimeline, DashMpdError};
+use crate::{Preselection, Role, Accessibility};
+use crate::{parse, mux_audio_video, mux_audio_tracks, mux_subtitle_tracks, copy_video_to_container, copy_audio_to_container};
 use crate::{is_audio_adaptation, is_video_adaptation, is_subtitle_adaptation};
+use crate::{is_image_adaptation, is_trickmode_adaptation, thumbnail_tile_grid};
+use crate::{spatial_relationship, adaptation_viewpoints, SpatialRelationship};
 use crate::{subtitle_type, content_protection_type, SubtitleType};
+use crate::{video_dynamic_range, supplemental_dolby_vision_profile, VideoDynamicRange};
 use crate::check_conformity;
+use crate::clearkey::{clearkey_license_request, license_request_body, parse_license_response, normalize_kid};
+use crate::keys::{parse_key_file, parse_cpix, parse_document_key};
+use crate::playready::{PlayReadyObject, PLAYREADY_SYSTEM_ID};
 #[cfg(not(feature = "libav"))]
 use crate::ffmpeg::concat_output_files;
-use crate::media::{temporary_outpath, AudioTrack};
-use crate::decryption::{
-    decrypt_mp4decrypt,
-    decrypt_shaka,
-    decrypt_shaka_container,
-    decrypt_mp4box,
-    decrypt_mp4box_container
-};
+#[cfg(feature = "libav")]
+use crate::libav::{concat_output_files, periods_concatable};
+use crate::language::language_preference_rank;
+use crate::media::{temporary_outpath, language_label, AudioTrack, SubtitleTrack};
+use crate::decryption::decrypt_media;
+use crate::thumbnails::{tile_cues, image_extension};
+use crate::vtt::{VttCue, cues_to_webvtt};
 #[allow(unused_imports)]
 use crate::media::video_containers_concatable;
 
@@ -125,12 +132,114 @@ pub trait ProgressObserver: Send + Sync {
     fn update(&self, percent: u32, bandwidth: u64, message: &str);
 }
 
+/// Supplies content decryption keys at download time, for example by querying a key vault. The
+/// `keys` method is called once the manifest has been analyzed, with the KIDs (in 32 character
+/// lowercase hexadecimal format) of the selected streams for which no key is yet known. It should
+/// return a map from KID to key (in hexadecimal format) for the keys that it is able to provide.
+/// The method returns a future, so that providers can make network requests without blocking the
+/// download runtime.
+pub trait KeyProvider: Send + Sync {
+    fn keys<'a>(&'a self, kids: &'a [String]) -> KeyFuture<'a>;
+}
+
+/// The future returned by [KeyProvider::keys].
+pub type KeyFuture<'a> = Pin<Box<dyn Future<Output = Result<HashMap<String, String>, DashMpdError>> + Send + 'a>>;
+
+/// The future returned by the methods of the [Muxer], [Concatenator] and [Decryptor] traits.
+pub type PluginFuture<'a> = Pin<Box<dyn Future<Output = Result<(), DashMpdError>> + Send + 'a>>;
+
+/// Muxes (merges) audio and video streams into an output container. The built-in muxers are named
+/// "ffmpeg", "vlc", "mkvmerge", "mp4box" and "native" (or "libav" and "native" when built with the
+/// libav feature). A muxer registered with [DashDownloader::with_muxer] is tried when its name
+/// appears in the muxer preference for the output container type (see
+/// [DashDownloader::with_muxer_preference]), and replaces any built-in muxer of the same name.
+pub trait Muxer: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Mux the audio tracks and the video stream at `video_path` (if present) into `output_path`,
+    /// whose extension indicates the container type.
+    fn mux<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        output_path: &'a Path,
+        audio_tracks: &'a [AudioTrack],
+        video_path: Option<&'a Path>) -> PluginFuture<'a>;
+
+    /// Embed the subtitle tracks in the media file at `media_path`, replacing its contents. The
+    /// default implementation reports that subtitle embedding is not supported.
+    fn mux_subtitles<'a>(
+        &'a self,
+        _downloader: &'a DashDownloader,
+        _media_path: &'a Path,
+        _subtitle_tracks: &'a [SubtitleTrack]) -> PluginFuture<'a> {
+        let msg = format!("muxer {} cannot embed subtitles", self.name());
+        Box::pin(async move { Err(DashMpdError::Muxing(msg)) })
+    }
+}
+
+/// Concatenates the media files for several Periods. The built-in concat helpers are named
+/// "ffmpeg", "ffmpegdemuxer", "mkvmerge", "mp4box" and "native" (or "libav", "libav-reencode" and
+/// "native" when built with the libav feature). A concat helper registered with
+/// [DashDownloader::with_concatenator] is tried when its name appears in the concat preference for
+/// the container type (see [DashDownloader::with_concat_preference]).
+pub trait Concatenator: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Concatenate the media files in `paths`, writing the result to the first of them.
+    fn concat<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        paths: &'a [&'a Path]) -> PluginFuture<'a>;
+}
+
+/// Decrypts a media stream protected with ContentProtection, using the keys in
+/// `downloader.decryption_keys`. The built-in decryptors are named "mp4decrypt", "shaka",
+/// "shaka-container", "mp4box" and "mp4box-container". A decryptor registered with
+/// [DashDownloader::with_decryptor] is tried when its name appears in the decryptor preference (see
+/// [DashDownloader::with_decryptor_preference]).
+pub trait Decryptor: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Decrypt the media at `input_path` to `output_path`. `media_type` is "audio" or "video".
+    fn decrypt<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        input_path: &'a Path,
+        output_path: &'a Path,
+        media_type: &'a str) -> PluginFuture<'a>;
+}
+
 
 /// Preference for retrieving media representation with highest quality (and highest file size) or
 /// lowest quality (and lowest file size).
 #[derive(PartialEq, Eq, Clone, Copy, Default)]
 pub enum QualityPreference { #[default] Lowest, Intermediate, Highest }
 
+/// Policy applied when none of the candidate Representations satisfies a ceiling such as
+/// [DashDownloader::max_video_height] or [DashDownloader::max_total_bandwidth]: either select the
+/// Representations that are closest to satisfying the constraint (those with the lowest value of
+/// the constrained attribute), or fail the download with an error.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum ConstraintFallback { #[default] Closest, Error }
+
+/// Accessibility features of AdaptationSets, as signalled by their Role and Accessibility
+/// descriptors, which can be included or excluded using [DashDownloader::include_accessibility]
+/// and [DashDownloader::exclude_accessibility].
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum AccessibilityFeature {
+    /// Audio description for the blind and visually impaired (Role "description", or value 1 of
+    /// the `urn:tva:metadata:cs:AudioPurposeCS:2007` scheme)
+    AudioDescription,
+    /// Subtitles for the deaf and hard of hearing (SDH) and hard of hearing audio (Role "caption",
+    /// or value 2 of the `urn:tva:metadata:cs:AudioPurposeCS:2007` scheme)
+    HardOfHearing,
+    /// Forced subtitles (Role "forced-subtitle" or "forced")
+    ForcedSubtitles,
+    /// Sign language interpretation in a video AdaptationSet (Role "sign", or a sign language
+    /// @lang such as "sgn")
+    SignLanguage,
+}
+
 
 /// The `DashDownloader` allows the download of streaming media content from a DASH MPD manifest.
 ///
@@ -161,23 +270,55 @@ pub struct DashDownloader {
     auth_bearer_token: Option<String>,
     pub output_path: Option<PathBuf>,
     http_client: Option<HttpClient>,
-    quality_preference: QualityPreference,
-    language_preference_audio: Option<String>,
-    language_preference_subtitles: Option<String>,
+    quality_preference: Option<QualityPreference>,
+    language_preference_audio: Vec<String>,
+    fetch_matching_audio_languages: bool,
+    language_preference_subtitles: Vec<String>,
+    fetch_matching_subtitle_languages: bool,
     role_preference: Vec<String>,
     video_width_preference: Option<u64>,
     video_height_preference: Option<u64>,
     video_codec_preference: Vec<String>,
+    video_dynamic_range_preference: Vec<VideoDynamicRange>,
+    video_dynamic_range_excluded: Vec<VideoDynamicRange>,
+    accessibility_included: Vec<AccessibilityFeature>,
+    accessibility_excluded: Vec<AccessibilityFeature>,
+    preselection_tag: Option<String>,
+    viewpoint_preference: Option<String>,
+    audio_codec_preference: Vec<String>,
+    audio_channels_preference: Option<u32>,
+    audio_channels_max: Option<u32>,
+    audio_sampling_rate_preference: Option<u64>,
+    video_height_max: Option<u64>,
+    video_bandwidth_max: Option<u64>,
+    video_frame_rate_max: Option<f64>,
+    audio_bandwidth_max: Option<u64>,
+    total_bandwidth_max: Option<u64>,
+    constraint_fallback: ConstraintFallback,
+    size_budget: Option<u64>,
+    size_budget_per_period: bool,
+    period_continuity: bool,
     video_id_wanted: Option<String>,
     fetch_video: bool,
     fetch_audio: bool,
+    fetch_all_audio_tracks: bool,
     fetch_subtitles: bool,
+    fetch_thumbnails: bool,
+    fetch_srd_tiles: bool,
+    fetch_all_subtitle_tracks: bool,
+    embed_subtitles: bool,
     keep_video: Option<PathBuf>,
-    // FIXME this should be a Vec<PathBuf> to handle streams with multiple audio tracks
     keep_audio: Option<PathBuf>,
     concatenate_periods: bool,
     fragment_path: Option<PathBuf>,
     pub decryption_keys: HashMap<String, String>,
+    clearkey_license_acquisition: bool,
+    key_files: Vec<PathBuf>,
+    cpix_documents: Vec<(PathBuf, Option<String>)>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    muxers: Vec<Arc<dyn Muxer>>,
+    concatenators: Vec<Arc<dyn Concatenator>>,
+    decryptors: Vec<Arc<dyn Decryptor>>,
     xslt_stylesheets: Vec<PathBuf>,
     minimum_period_duration: Option<Duration>,
     content_type_checks: bool,
@@ -197,6 +338,7 @@ pub struct DashDownloader {
     pub verbosity: u8,
     record_metainformation: bool,
     pub muxer_preference: HashMap<String, String>,
+    pub fragmented_mp4_output: bool,
     pub concat_preference: HashMap<String, String>,
     pub decryptor_preference: String,
     pub ffmpeg_location: String,
@@ -247,22 +389,55 @@ impl DashDownloader {
             auth_bearer_token: None,
             output_path: None,
             http_client: None,
-            quality_preference: QualityPreference::Lowest,
-            language_preference_audio: None,
-            language_preference_subtitles: None,
+            quality_preference: None,
+            language_preference_audio: Vec::new(),
+            fetch_matching_audio_languages: false,
+            language_preference_subtitles: Vec::new(),
+            fetch_matching_subtitle_languages: false,
             role_preference: vec!["main".to_string(), "alternate".to_string()],
             video_width_preference: None,
             video_height_preference: None,
             video_codec_preference: Vec::new(),
+            video_dynamic_range_preference: Vec::new(),
+            video_dynamic_range_excluded: Vec::new(),
+            accessibility_included: Vec::new(),
+            accessibility_excluded: Vec::new(),
+            preselection_tag: None,
+            viewpoint_preference: None,
+            audio_codec_preference: Vec::new(),
+            audio_channels_preference: None,
+            audio_channels_max: None,
+            audio_sampling_rate_preference: None,
+            video_height_max: None,
+            video_bandwidth_max: None,
+            video_frame_rate_max: None,
+            audio_bandwidth_max: None,
+            total_bandwidth_max: None,
+            constraint_fallback: ConstraintFallback::Closest,
+            size_budget: None,
+            size_budget_per_period: false,
+            period_continuity: true,
             video_id_wanted: None,
             fetch_video: true,
             fetch_audio: true,
+            fetch_all_audio_tracks: false,
             fetch_subtitles: false,
+            fetch_thumbnails: false,
+            fetch_srd_tiles: false,
+            fetch_all_subtitle_tracks: false,
+            embed_subtitles: false,
             keep_video: None,
             keep_audio: None,
             concatenate_periods: true,
             fragment_path: None,
             decryption_keys: HashMap::new(),
+            clearkey_license_acquisition: true,
+            key_files: Vec::new(),
+            cpix_documents: Vec::new(),
+            key_provider: None,
+            muxers: Vec::new(),
+            concatenators: Vec::new(),
+            decryptors: Vec::new(),
             xslt_stylesheets: Vec::new(),
             minimum_period_duration: None,
             content_type_checks: true,
@@ -282,6 +457,7 @@ impl DashDownloader {
             verbosity: 0,
             record_metainformation: true,
             muxer_preference: HashMap::new(),
+            fragmented_mp4_output: false,
             concat_preference: HashMap::new(),
             decryptor_preference: String::from("mp4decrypt"),
             ffmpeg_location: String::from("ffmpeg"),
@@ -379,7 +555,7 @@ impl DashDownloader {
     /// quality), prefer the Adaptation with the highest bitrate (largest output file).
     #[must_use]
     pub fn best_quality(mut self) -> DashDownloader {
-        self.quality_preference = QualityPreference::Highest;
+        self.quality_preference = Some(QualityPreference::Highest);
         self
     }
 
@@ -387,7 +563,7 @@ impl DashDownloader {
     /// quality), prefer the Adaptation with an intermediate bitrate (closest to the median value).
     #[must_use]
     pub fn intermediate_quality(mut self) -> DashDownloader {
-        self.quality_preference = QualityPreference::Intermediate;
+        self.quality_preference = Some(QualityPreference::Intermediate);
         self
     }
 
@@ -395,40 +571,129 @@ impl DashDownloader {
     /// quality), prefer the Adaptation with the lowest bitrate (smallest output file).
     #[must_use]
     pub fn worst_quality(mut self) -> DashDownloader {
-        self.quality_preference = QualityPreference::Lowest;
+        self.quality_preference = Some(QualityPreference::Lowest);
         self
     }
 
+    // The quality preference used to rank Representations. When no preference has been specified,
+    // this is the lowest quality, unless a size budget is active, in which case we want the highest
+    // quality streams that fit within the budget.
+    fn quality_preference(&self) -> QualityPreference {
+        self.quality_preference.unwrap_or(if self.size_budget.is_some() {
+            QualityPreference::Highest
+        } else {
+            QualityPreference::Lowest
+        })
+    }
+
     /// Specify the preferred language for audio streams and subtitle streams, when multiple audio
-    /// streams or subtitle tracks with different languages are available. Must be in RFC 5646
-    /// format (e.g. "fr" or "en-AU"). If a preference is not specified and multiple streams are
+    /// streams or subtitle tracks with different languages are available. Must be in BCP 47 (RFC
+    /// 5646) format (e.g. "fr" or "en-AU") or an ISO 639-2 code (e.g. "fra" or "fre"), or a
+    /// comma-separated list of languages in order of preference (e.g. "pt-BR,pt,en"). Languages
+    /// are matched following the lookup scheme of RFC 4647, so that a preference for "zh-Hant-TW"
+    /// falls back to "zh-Hant" then to "zh" if no stream in that language is available. If a
+    /// preference is not specified (or none of the languages is available) and multiple streams are
     /// present, the first one listed in the DASH manifest will be downloaded.
-    //
-    // TODO: this could be modified to allow a comma-separated list, or the special value "all"
     #[must_use]
     pub fn prefer_language(mut self, lang: String) -> DashDownloader {
-        self.language_preference_audio = Some(lang.clone());
-        self.language_preference_subtitles = Some(lang);
+        self.language_preference_audio = language_list(&lang);
+        self.language_preference_subtitles = language_list(&lang);
+        self
+    }
+
+    /// Specify the preferred languages for audio streams and subtitle streams, in order of
+    /// preference. This is equivalent to [DashDownloader::prefer_language] with a comma-separated
+    /// list of languages: a single audio stream and a single subtitle track are selected, in the
+    /// first of the languages that is available.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/dubbed.mpd")
+    ///     .prefer_languages(vec!["pt-BR", "pt", "es"]);
+    /// ```
+    #[must_use]
+    pub fn prefer_languages<S: Into<String>>(mut self, langs: Vec<S>) -> DashDownloader {
+        let langs: Vec<String> = langs.into_iter().map(Into::into).collect();
+        self.language_preference_audio.clone_from(&langs);
+        self.language_preference_subtitles = langs;
         self
     }
 
     /// Specify the preferred language for audio, when multiple audio streams with different
-    /// languages are available. Must be in RFC 5646 format (e.g. "fr" or "en-AU"). If a preference
-    /// is not specified and multiple audio streams are present, the first one listed in the DASH
-    /// manifest will be downloaded.
+    /// languages are available. Must be in BCP 47 (RFC 5646) format (e.g. "fr" or "en-AU") or an
+    /// ISO 639-2 code, or a comma-separated list of languages in order of preference (see
+    /// [DashDownloader::prefer_language]). If a preference is not specified and multiple audio
+    /// streams are present, the first one listed in the DASH manifest will be downloaded.
     #[must_use]
     pub fn prefer_audio_language(mut self, lang: String) -> DashDownloader {
-        self.language_preference_audio = Some(lang);
+        self.language_preference_audio = language_list(&lang);
+        self
+    }
+
+    /// Download an audio track for each of the languages in `langs` that is available, when the
+    /// manifest contains audio streams in several languages, rather than the single audio stream
+    /// selected by [DashDownloader::prefer_audio_language]. Languages must be in BCP 47 (RFC 5646)
+    /// format (e.g. "fr" or "en-AU") or ISO 639-2 codes; a language without a region subtag such as
+    /// "en" also matches "en-US" and "en-GB", and "pt-BR" falls back to "pt" if it is unavailable.
+    /// One Representation is selected from each matching audio AdaptationSet (after applying the
+    /// role preference within each language), and the audio tracks are muxed into the output file
+    /// with their language tags. The track in the first listed language is flagged as the default
+    /// audio track. If none of the languages is available, a single audio stream is selected as if
+    /// no language preference had been specified.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/dubbed.mpd")
+    ///     .prefer_audio_languages(vec!["en", "fr", "de"]);
+    /// ```
+    #[must_use]
+    pub fn prefer_audio_languages<S: Into<String>>(mut self, langs: Vec<S>) -> DashDownloader {
+        self.language_preference_audio = langs.into_iter().map(Into::into).collect();
+        self.fetch_matching_audio_languages = true;
         self
     }
 
     /// Specify the preferred language for subtitles, when multiple subtitle tracks with different
-    /// languages are available. Must be in RFC 5646 format (e.g. "fr" or "en-AU"). If a preference
-    /// is not specified and multiple subtitle tracks are available, the first one listed in the
-    /// DASH manifest will be downloaded.
+    /// languages are available. Must be in BCP 47 (RFC 5646) format (e.g. "fr" or "en-AU") or an
+    /// ISO 639-2 code, or a comma-separated list of languages in order of preference (see
+    /// [DashDownloader::prefer_language]). If a preference is not specified and multiple subtitle
+    /// tracks are available, the first one listed in the DASH manifest will be downloaded.
     #[must_use]
     pub fn prefer_subtitle_language(mut self, lang: String) -> DashDownloader {
-        self.language_preference_subtitles = Some(lang);
+        self.language_preference_subtitles = language_list(&lang);
+        self
+    }
+
+    /// Download the subtitle tracks in each of the languages in `langs` that is available, when
+    /// the manifest contains subtitles in several languages, rather than the single subtitle track
+    /// selected by [DashDownloader::prefer_subtitle_language]. Languages must be in BCP 47 (RFC 5646)
+    /// format (e.g. "fr" or "en-AU") or ISO 639-2 codes; a language without a region subtag such as
+    /// "en" also matches "en-US", and "pt-BR" falls back to "pt" if it is unavailable.
+    /// All subtitle AdaptationSets in a wanted language are downloaded, including forced subtitles
+    /// and subtitles for the deaf and hard of hearing (SDH), which are identified by their Role and
+    /// Accessibility descriptors. Each track is saved to a sidecar file named after the output file
+    /// with the language and the kind of subtitles, such as `movie.fr.forced.vtt` or
+    /// `movie.en.sdh.srt`. Has no effect unless `fetch_subtitles` is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/subtitled.mpd")
+    ///     .fetch_subtitles(true)
+    ///     .prefer_subtitle_languages(vec!["fr", "en"]);
+    /// ```
+    #[must_use]
+    pub fn prefer_subtitle_languages<S: Into<String>>(mut self, langs: Vec<S>) -> DashDownloader {
+        self.language_preference_subtitles = langs.into_iter().map(Into::into).collect();
+        self.fetch_matching_subtitle_languages = true;
         self
     }
 
@@ -452,6 +717,80 @@ impl DashDownloader {
         self
     }
 
+    /// Download the AdaptationSets which have one of the accessibility features in `features` in
+    /// addition to the tracks that would otherwise be selected. Audio description and hard of
+    /// hearing audio AdaptationSets are downloaded as extra audio tracks, and SDH and forced
+    /// subtitles as extra subtitle tracks (if `fetch_subtitles` is enabled), restricted to the
+    /// preferred languages if any have been specified. Since a single video track is downloaded,
+    /// including [AccessibilityFeature::SignLanguage] selects a sign language video AdaptationSet
+    /// in preference to the others, when one is available.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::{DashDownloader, AccessibilityFeature};
+    ///
+    /// let dl = DashDownloader::new("https://example.com/accessible.mpd")
+    ///     .prefer_audio_language(String::from("en"))
+    ///     .include_accessibility(vec![AccessibilityFeature::AudioDescription]);
+    /// ```
+    #[must_use]
+    pub fn include_accessibility(mut self, features: Vec<AccessibilityFeature>) -> DashDownloader {
+        self.accessibility_included = features;
+        self
+    }
+
+    /// Never download AdaptationSets which have one of the accessibility features in `features`,
+    /// for example to avoid selecting an audio description track as the main audio track.
+    #[must_use]
+    pub fn exclude_accessibility(mut self, features: Vec<AccessibilityFeature>) -> DashDownloader {
+        self.accessibility_excluded = features;
+        self
+    }
+
+    /// Select the Preselection whose @tag is `tag`, when a Period contains Preselection elements (as
+    /// used for next-generation audio such as MPEG-H 3D Audio and AC-4). When a Period contains
+    /// Preselections, the audio AdaptationSets to download are those referenced by the
+    /// preselectionComponents of the selected Preselection, which are downloaded as separate audio
+    /// tracks with the main component first. If no tag is specified, or no Preselection has the
+    /// wanted tag, the Preselection is chosen according to the audio language preference, then the
+    /// role preference, then its @selectionPriority. The available Preselections are listed when
+    /// verbosity is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/ac4.mpd")
+    ///     .prefer_preselection(String::from("3"));
+    /// ```
+    #[must_use]
+    pub fn prefer_preselection(mut self, tag: String) -> DashDownloader {
+        self.preselection_tag = Some(tag);
+        self
+    }
+
+    /// For multi-view content such as a sports event filmed from several camera angles, select the
+    /// video and audio AdaptationSets whose Viewpoint descriptor has the @value (or, if it has no
+    /// @value, the @id) `viewpoint`, compared case-insensitively. AdaptationSets without a Viewpoint
+    /// descriptor remain candidates. If no AdaptationSet has the requested viewpoint, this
+    /// preference has no effect. The available viewpoints are listed when verbosity is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/match.mpd")
+    ///     .prefer_viewpoint("main-camera");
+    /// ```
+    #[must_use]
+    pub fn prefer_viewpoint(mut self, viewpoint: &str) -> DashDownloader {
+        self.viewpoint_preference = Some(viewpoint.to_string());
+        self
+    }
+
     /// If the DASH manifest specifies several video AdaptationSets with different resolutions, prefer
     /// the AdaptationSet and child Representations whose width is closest to the specified `width`.
     #[must_use]
@@ -468,6 +807,46 @@ impl DashDownloader {
         self
     }
 
+    /// Don't download video Representations whose height is greater than `height`. Unlike
+    /// [DashDownloader::prefer_video_height], this is a ceiling rather than a target: combined with
+    /// [DashDownloader::best_quality] it selects the best available video stream up to the
+    /// specified height. Representations whose height is not specified in the manifest are not
+    /// filtered out. See [DashDownloader::with_constraint_fallback] for the behaviour when no
+    /// Representation satisfies the ceiling.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// // best available up to 1080p and 6 Mbit/s
+    /// let dl = DashDownloader::new("https://example.com/ladder.mpd")
+    ///     .best_quality()
+    ///     .max_video_height(1080)
+    ///     .max_total_bandwidth(6_000_000);
+    /// ```
+    #[must_use]
+    pub fn max_video_height(mut self, height: u64) -> DashDownloader {
+        self.video_height_max = Some(height);
+        self
+    }
+
+    /// Don't download video Representations whose @bandwidth is greater than `bandwidth` (in bits
+    /// per second).
+    #[must_use]
+    pub fn max_video_bandwidth(mut self, bandwidth: u64) -> DashDownloader {
+        self.video_bandwidth_max = Some(bandwidth);
+        self
+    }
+
+    /// Don't download video Representations whose frame rate is greater than `fps` frames per
+    /// second (for example to exclude 50 fps streams when 25 fps streams are available).
+    #[must_use]
+    pub fn max_video_frame_rate(mut self, fps: f64) -> DashDownloader {
+        self.video_frame_rate_max = Some(fps);
+        self
+    }
+
     /// Specify a preference ordering for codecs used for video streams. The argument
     /// `codec_preference` is a vector of Strings of the form "h264", "vp09" and "av1". The matching
     /// of codecs is based on substring prefix, so for example a preference of "hev1" will match a
@@ -482,6 +861,164 @@ impl DashDownloader {
         self
     }
 
+    /// Specify a preference ordering for the dynamic range of video streams, such as
+    /// `vec![VideoDynamicRange::Hdr10, VideoDynamicRange::Sdr]`. The dynamic range is determined
+    /// from the Dolby Vision codec strings and from the CICP TransferCharacteristics descriptors
+    /// (or the transfer characteristics in VP9 and AV1 codec strings). A preference for
+    /// `VideoDynamicRange::DolbyVision` also matches streams with an HDR10 or SDR base layer and a
+    /// Dolby Vision enhancement signalled by `scte214:supplementalCodecs`. Streams whose dynamic
+    /// range is not in the list are only selected if no preferred dynamic range is available.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    /// use dash_mpd::VideoDynamicRange;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/hdr.mpd")
+    ///     .prefer_video_dynamic_ranges(vec![VideoDynamicRange::DolbyVision(Some(8)), VideoDynamicRange::Hdr10]);
+    /// ```
+    #[must_use]
+    pub fn prefer_video_dynamic_ranges(mut self, preference: Vec<VideoDynamicRange>) -> DashDownloader {
+        self.video_dynamic_range_preference = preference;
+        self
+    }
+
+    /// Never download video streams with one of the dynamic ranges in `excluded`, for example
+    /// `VideoDynamicRange::DolbyVision(Some(5))` to avoid Dolby Vision profile 5 streams, which
+    /// can't be decoded without Dolby Vision support. If none of the video streams in the manifest
+    /// is acceptable, the download fails. Streams with a backwards compatible base layer and a
+    /// Dolby Vision enhancement signalled by `scte214:supplementalCodecs` are classified according
+    /// to their base layer.
+    #[must_use]
+    pub fn exclude_video_dynamic_ranges(mut self, excluded: Vec<VideoDynamicRange>) -> DashDownloader {
+        self.video_dynamic_range_excluded = excluded;
+        self
+    }
+
+    /// Only download SDR video streams, failing if the manifest only contains HDR10, HLG or Dolby
+    /// Vision video streams. This is equivalent to excluding those dynamic ranges with
+    /// [DashDownloader::exclude_video_dynamic_ranges].
+    #[must_use]
+    pub fn sdr_video_only(self) -> DashDownloader {
+        self.exclude_video_dynamic_ranges(vec![
+            VideoDynamicRange::Hdr10,
+            VideoDynamicRange::Hlg,
+            VideoDynamicRange::DolbyVision(None)])
+    }
+
+    /// Specify a preference ordering for codecs used for audio streams. The argument
+    /// `codec_preference` is a vector of Strings of the form "ec-3", "mp4a" and "opus". As for
+    /// [DashDownloader::prefer_video_codecs], codecs are matched by prefix, so that "mp4a.40"
+    /// matches "mp4a.40.2" and "mp4a.40.5".
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/surround.mpd")
+    ///     .prefer_audio_codecs(vec![String::from("ec-3"), String::from("mp4a")]);
+    /// ```
+    #[must_use]
+    pub fn prefer_audio_codecs(mut self, codec_preference: Vec<String>) -> DashDownloader {
+        if codec_preference.len() < u8::MAX as usize {
+            self.audio_codec_preference = codec_preference;
+        } else {
+            warn!("Ignoring audio codec_preference due to excessive length");
+        }
+        self
+    }
+
+    /// If the DASH manifest specifies audio streams with different channel layouts, prefer the
+    /// stream whose number of channels (as specified by its AudioChannelConfiguration element) is
+    /// closest to `channels` (for example 2 for stereo or 6 for 5.1 surround).
+    #[must_use]
+    pub fn prefer_audio_channels(mut self, channels: u32) -> DashDownloader {
+        self.audio_channels_preference = Some(channels);
+        self
+    }
+
+    /// Don't download audio streams with more than `channels` channels (as specified by their
+    /// AudioChannelConfiguration element), for example to avoid 5.1 surround streams when the
+    /// playback device only handles stereo. See [DashDownloader::with_constraint_fallback] for the
+    /// behaviour when all the candidate audio streams have more channels.
+    #[must_use]
+    pub fn max_audio_channels(mut self, channels: u32) -> DashDownloader {
+        self.audio_channels_max = Some(channels);
+        self
+    }
+
+    /// Don't download audio Representations whose @bandwidth is greater than `bandwidth` (in bits
+    /// per second).
+    #[must_use]
+    pub fn max_audio_bandwidth(mut self, bandwidth: u64) -> DashDownloader {
+        self.audio_bandwidth_max = Some(bandwidth);
+        self
+    }
+
+    /// Specify a total bitrate budget (in bits per second) shared by the audio and video streams
+    /// downloaded for each Period. The audio streams are selected first, leaving enough of the
+    /// budget for the lowest bandwidth video stream, and the video stream is then selected within
+    /// the remaining budget. This is combined with the other ceilings, such as
+    /// [DashDownloader::max_video_bandwidth].
+    #[must_use]
+    pub fn max_total_bandwidth(mut self, bandwidth: u64) -> DashDownloader {
+        self.total_bandwidth_max = Some(bandwidth);
+        self
+    }
+
+    /// Specify what happens when none of the available Representations satisfies one of the
+    /// ceilings (maximum video height, frame rate, bandwidth and number of audio channels, and the
+    /// total bandwidth budget). The default is [ConstraintFallback::Closest], which selects the
+    /// Representation closest to satisfying the constraint (for example, the stream with the lowest
+    /// height), with a warning. With [ConstraintFallback::Error] the download fails.
+    #[must_use]
+    pub fn with_constraint_fallback(mut self, fallback: ConstraintFallback) -> DashDownloader {
+        self.constraint_fallback = fallback;
+        self
+    }
+
+    /// Select the highest quality combination of audio and video streams whose estimated total size
+    /// fits within `bytes` octets. The size of each Representation is estimated from its @bandwidth
+    /// attribute and the Period duration, unless the manifest exposes exact sizes: byte ranges of a
+    /// SegmentList, or the sidx index referenced by SegmentBase@indexRange (which is retrieved for
+    /// each candidate Representation). By default the budget applies to the whole presentation and
+    /// is shared between Periods in proportion to their duration (see
+    /// [DashDownloader::size_budget_per_period]). The estimate and the selected streams are logged.
+    /// Unless a quality preference is specified with [DashDownloader::worst_quality] or
+    /// [DashDownloader::intermediate_quality], the highest quality streams that fit are selected.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use dash_mpd::fetch::DashDownloader;
+    ///
+    /// let dl = DashDownloader::new("https://example.com/episode.mpd")
+    ///     .with_size_budget(500 * 1024 * 1024);
+    /// ```
+    #[must_use]
+    pub fn with_size_budget(mut self, bytes: u64) -> DashDownloader {
+        self.size_budget = Some(bytes);
+        self
+    }
+
+    /// If `per_period` is true, the budget specified with [DashDownloader::with_size_budget] applies
+    /// to each Period separately, rather than to the whole presentation.
+    #[must_use]
+    pub fn size_budget_per_period(mut self, per_period: bool) -> DashDownloader {
+        self.size_budget_per_period = per_period;
+        self
+    }
+
+    /// If the DASH manifest specifies audio streams with different sampling rates, prefer the
+    /// stream whose @audioSamplingRate is closest to `rate` (in Hz).
+    #[must_use]
+    pub fn prefer_audio_sampling_rate(mut self, rate: u64) -> DashDownloader {
+        self.audio_sampling_rate_preference = Some(rate);
+        self
+    }
+
     /// Specify a substring to use as a filter on video Representation @id attributes. When a
     /// manifest provides multiple video streams in different Representation elements, this makes it
     /// possible to select a specific video stream by providing its full id. If only a substring of
@@ -518,7 +1055,9 @@ impl DashDownloader {
     }
 
     /// Keep the file containing audio at the specified path. If the path already exists, file
-    /// contents will be overwritten.
+    /// contents will be overwritten. When several audio tracks are downloaded, the first track is
+    /// saved to `audio_path` and the other tracks are saved alongside it with their language
+    /// inserted before the extension (for example `audio.fr.mp4`).
     #[must_use]
     pub fn keep_audio_as<P: Into<PathBuf>>(mut self, audio_path: P) -> DashDownloader {
         self.keep_audio = Some(audio_path.into());
@@ -550,6 +1089,55 @@ impl DashDownloader {
         self
     }
 
+    /// Load decryption keys from the file at `path`, which contains one `KID:KEY` pair per line
+    /// (the same format as the arguments to `add_decryption_key`). Empty lines and lines starting
+    /// with `#` are ignored. The file is read when the download starts; the download fails if the
+    /// file cannot be read or is malformed. This function may be called several times.
+    #[must_use]
+    pub fn add_decryption_keys_from_file<P: Into<PathBuf>>(mut self, path: P) -> DashDownloader {
+        self.key_files.push(path.into());
+        self
+    }
+
+    /// Load decryption keys from the ContentKeyList of the CPIX (DASH-IF Content Protection
+    /// Information Exchange) document at `path`. If the content keys in the document are
+    /// encrypted, the 256-bit document key must be provided in hexadecimal format as
+    /// `document_key`, already decrypted using the private key of the document recipient. The
+    /// document is read when the download starts.
+    #[must_use]
+    pub fn add_decryption_keys_from_cpix<P: Into<PathBuf>>(
+        mut self,
+        path: P,
+        document_key: Option<&str>) -> DashDownloader
+    {
+        self.cpix_documents.push((path.into(), document_key.map(String::from)));
+        self
+    }
+
+    /// Register a `KeyProvider` that will be asked for the decryption keys of the selected streams
+    /// at download time, after analysis of the manifest. Keys specified with `add_decryption_key`,
+    /// loaded from files or obtained by Clear Key license acquisition take precedence: the
+    /// provider is only called for KIDs whose key is not already known.
+    #[must_use]
+    pub fn with_key_provider(mut self, provider: Arc<dyn KeyProvider>) -> DashDownloader {
+        self.key_provider = Some(provider);
+        self
+    }
+
+    /// Specify whether to obtain decryption keys automatically from a license server for content
+    /// protected with the W3C Clear Key system. When the manifest includes a Clear Key
+    /// ContentProtection element with a `dashif:Laurl` (or legacy `clearkey:Laurl`) license
+    /// acquisition URL, we send a license request for the KIDs of the selected audio and video
+    /// streams to that URL and add the keys in the license response to those used for decryption.
+    /// The license request uses the Referer and authentication credentials configured for this
+    /// `DashDownloader`. License acquisition is skipped for KIDs whose key was already specified
+    /// with `add_decryption_key`. Default is true.
+    #[must_use]
+    pub fn acquire_clearkey_licenses(mut self, value: bool) -> DashDownloader {
+        self.clearkey_license_acquisition = value;
+        self
+    }
+
     /// Register an XSLT stylesheet that will be applied to the MPD manifest after XLink processing
     /// and before deserialization into Rust structs. The stylesheet will be applied to the manifest
     /// using the xsltproc commandline tool, which supports XSLT 1.0. If multiple stylesheets are
@@ -584,6 +1172,18 @@ impl DashDownloader {
         self
     }
 
+    /// If the manifest contains several audio AdaptationSets (for example dubbed audio in different
+    /// languages, or an audio description track), download one audio Representation from each of
+    /// them and mux them all into the output file, with their language tags. The track in the
+    /// language specified with `prefer_audio_language` or `prefer_audio_languages`, if present, is
+    /// flagged as the default audio track; otherwise the first track listed in the manifest is the
+    /// default.
+    #[must_use]
+    pub fn fetch_all_audio_tracks(mut self, value: bool) -> DashDownloader {
+        self.fetch_all_audio_tracks = value;
+        self
+    }
+
     /// Parameter `value` determines whether video content is downloaded. If disabled, the output
     /// media file will either contain only an audio track (if `fetch_audio` is true and the manifest
     /// includes an audio stream which is separate from the video stream), or will be empty.
@@ -606,6 +1206,81 @@ impl DashDownloader {
         self
     }
 
+    /// Specify whether thumbnails should be fetched, if the manifest contains a thumbnail track (an
+    /// image AdaptationSet with the `http://dashif.org/thumbnail_tile` EssentialProperty). The tile
+    /// images are saved to a directory named after the media output with a `.thumbnails`
+    /// extension, and a WebVTT thumbnail track referencing each thumbnail with a `#xywh` media
+    /// fragment is written to a file with a `.thumbnails.vtt` extension, suitable for displaying
+    /// scrub thumbnails in a web player. When several thumbnail Representations are available, the
+    /// choice follows the quality preference.
+    ///
+    /// # Arguments
+    ///
+    /// * `value`: enable or disable the retrieval of thumbnails.
+    #[must_use]
+    pub fn fetch_thumbnails(mut self, value: bool) -> DashDownloader {
+        self.fetch_thumbnails = value;
+        self
+    }
+
+    /// Specify whether all the tiles of a spatially partitioned video should be fetched, for
+    /// manifests that describe tiles with Spatial Relationship Description (SRD) descriptors
+    /// (@schemeIdUri `urn:mpeg:dash:srd:2014`). The tiles of the SRD source of the selected video
+    /// stream are each saved to a separate file in a directory named after the media output with a
+    /// `.tiles` extension, and their position in the source (`object_x`, `object_y`,
+    /// `object_width`, `object_height` and the total size) is recorded in a `tiles.json` file in
+    /// that directory. The Representation of each tile is chosen according to the quality
+    /// preference.
+    ///
+    /// # Arguments
+    ///
+    /// * `value`: enable or disable the retrieval of SRD tiles.
+    #[must_use]
+    pub fn fetch_srd_tiles(mut self, value: bool) -> DashDownloader {
+        self.fetch_srd_tiles = value;
+        self
+    }
+
+    /// If the manifest contains several subtitle AdaptationSets, download all of them rather than
+    /// a single one. Each track is saved to a sidecar file named after the output file with the
+    /// language and the kind of subtitles, such as `movie.fr.vtt`, `movie.fr.forced.vtt` or
+    /// `movie.en.sdh.vtt`. Tracks in the languages specified with `prefer_subtitle_language` or
+    /// `prefer_subtitle_languages` are listed first. Has no effect unless `fetch_subtitles` is
+    /// enabled.
+    #[must_use]
+    pub fn fetch_all_subtitle_tracks(mut self, value: bool) -> DashDownloader {
+        self.fetch_all_subtitle_tracks = value;
+        self
+    }
+
+    /// Parameter `value` determines whether the downloaded subtitle tracks are embedded in the
+    /// output media container, in addition to being saved as sidecar files. The tracks are tagged
+    /// with their language and flagged as forced or hearing impaired where appropriate. Subtitles
+    /// are stored as mov_text (tx3g) in MP4 containers and as WebVTT or SRT in Matroska containers.
+    /// Embedding uses the muxing application configured with `with_muxer_preference` (mkvmerge,
+    /// ffmpeg or MP4Box), and is not possible when the Periods of a multi-Period manifest are saved
+    /// to separate files. Failure to embed the subtitles is not a fatal error.
+    #[must_use]
+    pub fn embed_subtitles(mut self, value: bool) -> DashDownloader {
+        self.embed_subtitles = value;
+        self
+    }
+
+    /// For multi-Period manifests, parameter `value` determines whether the selection of streams in
+    /// a Period tries to continue the streams selected in the previous Period (enabled by default),
+    /// so that the Periods can be concatenated without reencoding. An AdaptationSet with the same
+    /// @id as the previously selected one is preferred, in particular when it carries a
+    /// `urn:mpeg:dash:period-continuity:2015` or `urn:mpeg:dash:period-connectivity:2015`
+    /// SupplementalProperty referring to the previous Period, and otherwise one in the same
+    /// language. Within the AdaptationSet, a Representation with the same @id, or the same codec
+    /// and resolution, is preferred. This preference is applied after the explicit preferences on
+    /// language, role, resolution and codec, and before the quality preference.
+    #[must_use]
+    pub fn prefer_period_continuity(mut self, value: bool) -> DashDownloader {
+        self.period_continuity = value;
+        self
+    }
+
     /// For multi-Period manifests, parameter `value` determines whether the content of multiple
     /// Periods is concatenated into a single output file where their resolutions, frame rate and
     /// aspect ratios are compatible, or kept in individual files.
@@ -813,7 +1488,9 @@ impl DashDownloader {
     ///
     /// * `container`: the container type (e.g. "mp4", "mkv", "avi")
     /// * `ordering`: the comma-separated order of preference for trying muxing applications (e.g.
-    ///   "ffmpeg,vlc,mp4box")
+    ///   "ffmpeg,vlc,mp4box"). For the "mp4" container, "native" designates the built-in muxer,
+    ///   which doesn't require any external application but only handles fragmented MP4 streams.
+    ///   Muxers registered with [DashDownloader::with_muxer] are designated by their name.
     ///
     /// # Example
     ///
@@ -829,6 +1506,15 @@ impl DashDownloader {
         self
     }
 
+    /// When muxing with the native MP4 muxer (see [DashDownloader::with_muxer_preference]), produce
+    /// a fragmented MP4 file rather than a progressive MP4 file with the `moov` box at the front
+    /// (the default). Fragmented output is written in a single pass over the media data.
+    #[must_use]
+    pub fn fragmented_mp4_output(mut self, value: bool) -> DashDownloader {
+        self.fragmented_mp4_output = value;
+        self
+    }
+
     /// When concatenating streams from a multi-period manifest to a container of type `container`,
     /// try concat helper applications following the order given by `ordering`.
     ///
@@ -841,7 +1527,13 @@ impl DashDownloader {
     /// * `container`: the container type (e.g. "mp4", "mkv", "avi")
     /// * `ordering`: the comma-separated order of preference for trying concat helper applications.
     ///   Valid possibilities are "ffmpeg" (the ffmpeg concat filter, slow), "ffmpegdemuxer" (the
-    ///   ffmpeg concat demuxer, fast but less robust), "mkvmerge" (fast but not robust), and "mp4box".
+    ///   ffmpeg concat demuxer, fast but less robust), "mkvmerge" (fast but not robust), "mp4box"
+    ///   and "native" (lossless, for MP4 and WebM Periods with identical tracks). When the crate is
+    ///   built with the `libav` feature, valid possibilities are "native", "libav" (remuxing with
+    ///   timestamp offsets, for Periods with identical codec parameters) and "libav-reencode"
+    ///   (decoding and reencoding to the codec parameters of the first Period, slow and lossy).
+    ///   Concat helpers registered with [DashDownloader::with_concatenator] are designated by
+    ///   their name.
     ///
     /// # Example
     ///
@@ -863,14 +1555,67 @@ impl DashDownloader {
     /// # Arguments
     ///
     /// * `decryption_tool`: one of "mp4decrypt", "shaka", "mp4box", "shaka-container",
-    ///   "mp4box-container". The options with `-container` in the name are run via a Docker/Podman
-    ///   container.
+    ///   "mp4box-container", or the name of a decryptor registered with
+    ///   [DashDownloader::with_decryptor]. The options with `-container` in the name are run via a
+    ///   Docker/Podman container. A comma-separated list (e.g. "mp4decrypt,shaka") specifies an
+    ///   order of preference: the following decryptors are tried if the first one fails.
     #[must_use]
     pub fn with_decryptor_preference(mut self, decryption_tool: &str) -> DashDownloader {
         self.decryptor_preference = decryption_tool.to_string();
         self
     }
 
+    /// Register a muxer implementing the [Muxer] trait. It is used when its name appears in the
+    /// muxer preference for the output container type (see
+    /// [DashDownloader::with_muxer_preference]), and replaces any built-in muxer with the same name.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// let out = DashDownloader::new(url)
+    ///      .with_muxer(Arc::new(InHousePackager::new()))
+    ///      .with_muxer_preference("mp4", "inhouse,ffmpeg")
+    ///      .download_to("wonderful.mp4")
+    ///      .await?;
+    /// ```
+    #[must_use]
+    pub fn with_muxer(mut self, muxer: Arc<dyn Muxer>) -> DashDownloader {
+        self.muxers.push(muxer);
+        self
+    }
+
+    /// Register a concat helper implementing the [Concatenator] trait. It is used when its name
+    /// appears in the concat preference for the container type (see
+    /// [DashDownloader::with_concat_preference]), and replaces any built-in concat helper with the
+    /// same name.
+    #[must_use]
+    pub fn with_concatenator(mut self, concatenator: Arc<dyn Concatenator>) -> DashDownloader {
+        self.concatenators.push(concatenator);
+        self
+    }
+
+    /// Register a decryptor implementing the [Decryptor] trait. It is used when its name appears in
+    /// the decryptor preference (see [DashDownloader::with_decryptor_preference]), and replaces any
+    /// built-in decryptor with the same name.
+    #[must_use]
+    pub fn with_decryptor(mut self, decryptor: Arc<dyn Decryptor>) -> DashDownloader {
+        self.decryptors.push(decryptor);
+        self
+    }
+
+    // The most recently registered plugin with a given name takes precedence.
+    pub(crate) fn registered_muxer(&self, name: &str) -> Option<Arc<dyn Muxer>> {
+        self.muxers.iter().rev().find(|m| m.name().eq(name)).cloned()
+    }
+
+    pub(crate) fn registered_concatenator(&self, name: &str) -> Option<Arc<dyn Concatenator>> {
+        self.concatenators.iter().rev().find(|c| c.name().eq(name)).cloned()
+    }
+
+    pub(crate) fn registered_decryptor(&self, name: &str) -> Option<Arc<dyn Decryptor>> {
+        self.decryptors.iter().rev().find(|d| d.name().eq(name)).cloned()
+    }
+
     /// Specify the location of the `ffmpeg` application, if not located in PATH.
     ///
     /// # Arguments
@@ -1010,7 +1755,7 @@ impl DashDownloader {
 }
 
 
-fn mpd_is_dynamic(mpd: &MPD) -> bool {
+pub(crate) fn mpd_is_dynamic(mpd: &MPD) -> bool {
     if let Some(mpdtype) = mpd.mpdtype.as_ref() {
         return mpdtype.eq("dynamic");
     }
@@ -1019,7 +1764,7 @@ fn mpd_is_dynamic(mpd: &MPD) -> bool {
 
 // Parse a range specifier, such as Initialization@range or SegmentBase@indexRange attributes, of
 // the form "45-67"
-fn parse_range(range: &str) -> Result<(u64, u64), DashMpdError> {
+pub(crate) fn parse_range(range: &str) -> Result<(u64, u64), DashMpdError> {
     let v: Vec<&str> = range.split_terminator('-').collect();
     if v.len() != 2 {
         return Err(DashMpdError::Parsing(format!("invalid range specifier: {range}")));
@@ -1033,9 +1778,12 @@ fn parse_range(range: &str) -> Result<(u64, u64), DashMpdError> {
     Ok((start, end))
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct MediaFragment {
     period: u8,
+    // When several tracks of the same content type are downloaded (for example audio tracks in
+    // different languages), the index of the track to which this fragment belongs.
+    track: u8,
     url: Url,
     start_byte: Option<u64>,
     end_byte: Option<u64>,
@@ -1079,6 +1827,7 @@ impl MediaFragmentBuilder {
     pub fn build(self) -> MediaFragment {
         MediaFragment {
             period: self.period,
+            track: 0,
             url: self.url,
             start_byte: self.start_byte,
             end_byte: self.end_byte,
@@ -1095,27 +1844,118 @@ impl MediaFragmentBuilder {
 struct PeriodOutputs {
     fragments: Vec<MediaFragment>,
     diagnostics: Vec<String>,
-    subtitle_formats: Vec<SubtitleType>,
-    selected_audio_language: String,
-    selected_subtitle_language: String,
+    // The language of each selected audio track, indexed by MediaFragment.track.
+    selected_audio_languages: Vec<String>,
+    // The selected subtitle tracks, indexed by MediaFragment.track.
+    selected_subtitles: Vec<SelectedSubtitles>,
+    // Clear Key license acquisition URL and the KIDs to request from that license server.
+    clearkey_licenses: Vec<(Url, Vec<String>)>,
+    // KIDs (in normalized hex format) specified for the selected streams.
+    kids: Vec<String>,
 }
 
-#[derive(Debug, Default)]
-struct PeriodDownloads {
-    audio_fragments: Vec<MediaFragment>,
-    video_fragments: Vec<MediaFragment>,
+// The part of the size budget allocated to a Period, and the estimated size in octets of each audio
+// and video Representation in the Period, indexed by the position of the AdaptationSet in the Period
+// and of the Representation in the AdaptationSet.
+#[derive(Debug, Clone, Default)]
+struct PeriodSizeBudget {
+    budget: u64,
+    sizes: HashMap<(usize, usize), u64>,
+}
+
+// Stream selection state which only applies to the Period being prepared in fetch_mpd, passed to
+// the selection functions.
+#[derive(Debug, Clone, Default)]
+struct PeriodSelection {
+    size_budget: Option<PeriodSizeBudget>,
+    previous: Option<PeriodContinuity>,
+}
+
+// An audio or video stream selected in a Period, as recorded to try to select a matching stream in
+// the following Period.
+#[derive(Debug, Clone)]
+struct ContinuityStream {
+    adaptation_id: Option<String>,
+    representation_id: Option<String>,
+    codecs: Option<String>,
+    width: Option<u64>,
+    height: Option<u64>,
+    language: Option<String>,
+}
+
+// The streams selected in the previous Period that we downloaded, used to keep the same tracks
+// across Periods (for example after an ad break) when prefer_period_continuity is enabled.
+#[derive(Debug, Clone, Default)]
+struct PeriodContinuity {
+    period_id: Option<String>,
+    audio: Vec<ContinuityStream>,
+    video: Option<ContinuityStream>,
+}
+
+// A subtitle track selected for download. The output_path is the path of the media file from which
+// the names of the subtitle sidecar files are derived (by changing the extension), which for
+// additional subtitle tracks includes the language and the kind of subtitles, as in
+// "movie.fr.forced.mp4".
+#[derive(Debug, Clone)]
+struct SelectedSubtitles {
+    language: String,
+    formats: Vec<SubtitleType>,
+    forced: bool,
+    hearing_impaired: bool,
+    output_path: PathBuf,
+    // The timescale of the subtitle Representation announced in the manifest, used to interpret
+    // sample times in WVTT segments when the initialization segment doesn't specify one.
+    timescale: Option<u32>,
+}
+
+// The thumbnail Representation selected for download in a Period: the number of columns and rows
+// of thumbnails in each tile image, the size of the tile images, the extension for the image files,
+// and the start time and duration in milliseconds (relative to the start of the Period) of each
+// tile, in the order of the MediaFragments.
+#[derive(Debug, Clone)]
+struct SelectedThumbnails {
+    grid: (u32, u32),
+    width: Option<u64>,
+    height: Option<u64>,
+    extension: &'static str,
+    tile_times: Vec<(u64, u64)>,
+}
+
+// A tile of a spatially partitioned video selected for download, with its SRD descriptor and the
+// size of the reference space of its source. The tiles of a Period are indexed by MediaFragment.track.
+#[derive(Debug, Clone)]
+struct SelectedTile {
+    adaptation_id: Option<String>,
+    representation_id: Option<String>,
+    srd: SpatialRelationship,
+    reference_size: (u64, u64),
+    extension: &'static str,
+}
+
+#[derive(Debug, Default)]
+struct PeriodDownloads {
+    audio_fragments: Vec<MediaFragment>,
+    video_fragments: Vec<MediaFragment>,
     subtitle_fragments: Vec<MediaFragment>,
-    subtitle_formats: Vec<SubtitleType>,
+    thumbnail_fragments: Vec<MediaFragment>,
+    tile_fragments: Vec<MediaFragment>,
     period_counter: u8,
     id: Option<String>,
-    selected_audio_language: String,
-    selected_subtitle_language: String,
+    selected_audio_languages: Vec<String>,
+    selected_subtitles: Vec<SelectedSubtitles>,
+    thumbnails: Option<SelectedThumbnails>,
+    tiles: Vec<SelectedTile>,
+    // Start of this Period on the timeline of the output media (the concatenation of the Periods
+    // that we download), in milliseconds.
+    output_offset_ms: i64,
 }
 
 fn period_fragment_count(pd: &PeriodDownloads) -> usize {
     pd.audio_fragments.len() +
         pd.video_fragments.len() +
-        pd.subtitle_fragments.len()
+        pd.subtitle_fragments.len() +
+        pd.thumbnail_fragments.len() +
+        pd.tile_fragments.len()
 }
 
 
@@ -1205,7 +2045,7 @@ fn is_absolute_url(s: &str) -> bool {
         s.starts_with("ftp://")
 }
 
-fn merge_baseurls(current: &Url, new: &str) -> Result<Url, DashMpdError> {
+pub(crate) fn merge_baseurls(current: &Url, new: &str) -> Result<Url, DashMpdError> {
     if is_absolute_url(new) {
         Url::parse(new)
             .map_err(|e| parse_error("parsing BaseURL", e))
@@ -1259,24 +2099,37 @@ fn content_type_video_p(response: &reqwest::Response) -> bool {
     }
 }
 
-
-// Return a measure of the distance between this AdaptationSet's lang attribute and the language
-// code specified by language_preference. If the AdaptationSet node has no lang attribute, return an
-// arbitrary large distance.
-fn adaptation_lang_distance(a: &AdaptationSet, language_preference: &str) -> u8 {
-    if let Some(lang) = &a.lang {
-        if lang.eq(language_preference) {
-            return 0;
-        }
-        // The Levenshtein similarity measure for strings
-        edit_distance(lang, language_preference)
-            .try_into()
-            .unwrap_or(u8::MAX)
-    } else {
-        100
+// Return true if the response includes a content-type header corresponding to an image.
+fn content_type_image_p(response: &reqwest::Response) -> bool {
+    match response.headers().get("content-type") {
+        Some(ct) => {
+            let ctb = ct.as_bytes();
+            ctb.starts_with(b"image/") ||
+                ctb.starts_with(b"application/octet-stream")
+        },
+        None => false,
     }
 }
 
+
+// The rank of this AdaptationSet's language in the ordered list of language preferences, as a pair
+// (index of the matching preference, match rank) where lower is better. AdaptationSets without a
+// language or whose language matches none of the preferences are ranked last.
+fn adaptation_language_rank<S: AsRef<str>>(a: &AdaptationSet, wanted: &[S]) -> (usize, usize) {
+    adaptation_language(a)
+        .and_then(|l| language_preference_rank(l, wanted))
+        .unwrap_or((usize::MAX, usize::MAX))
+}
+
+// Split a comma-separated list of language preferences.
+fn language_list(langs: &str) -> Vec<String> {
+    langs.split(',')
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 // We can have a <Role value="foobles"> element directly within the AdaptationSet element, or within
 // a ContentComponent element in the AdaptationSet.
 fn adaptation_roles(a: &AdaptationSet) -> Vec<String> {
@@ -1310,21 +2163,20 @@ fn adaptation_role_distance(a: &AdaptationSet, role_preference: &[String]) -> u8
 // with our language preference, that with the role according to role_preference, and if no
 // role_preference, return all adaptations.
 //
-// Start by getting a Vec of adaptation_lang_distance
-// Take the min and collect all Adaptations where dist = min_distance
+// Start by getting a Vec of adaptation_language_rank
+// Take the min and collect all Adaptations where rank = min_distance
 // then apply role_preference
 fn select_preferred_adaptations<'a>(
     adaptations: Vec<&'a AdaptationSet>,
     downloader: &DashDownloader) -> Vec<&'a AdaptationSet>
 {
     let mut preferred: Vec<&'a AdaptationSet>;
-    // TODO: modify this algorithm to allow for multiple preferred languages
-    if let Some(ref lang) = downloader.language_preference_audio {
+    if !downloader.language_preference_audio.is_empty() {
         preferred = Vec::new();
-        let distance: Vec<u8> = adaptations.iter()
-            .map(|a| adaptation_lang_distance(a, lang))
+        let distance: Vec<(usize, usize)> = adaptations.iter()
+            .map(|a| adaptation_language_rank(a, &downloader.language_preference_audio))
             .collect();
-        let min_distance = distance.iter().min().unwrap_or(&0);
+        let min_distance = distance.iter().min().unwrap_or(&(0, 0));
         for (i, a) in adaptations.iter().enumerate() {
             if let Some(di) = distance.get(i) {
                 if di == min_distance {
@@ -1335,11 +2187,18 @@ fn select_preferred_adaptations<'a>(
     } else {
         preferred = adaptations;
     }
-    // Apply the role_preference. For example, a role_preference of ["main", "alternate",
-    // "supplementary", "commentary"] means we should prefer an AdaptationSet with role=main, and
-    // return only that AdaptationSet. If there are no role annotations on the AdaptationSets, or
-    // the specified roles don't match anything in our role_preference ordering, then all
-    // AdaptationSets will receive the maximum distance and they will all be returned.
+    filter_preferred_roles(preferred, downloader)
+}
+
+// Apply the role_preference. For example, a role_preference of ["main", "alternate",
+// "supplementary", "commentary"] means we should prefer an AdaptationSet with role=main, and return
+// only that AdaptationSet. If there are no role annotations on the AdaptationSets, or the specified
+// roles don't match anything in our role_preference ordering, then all AdaptationSets will receive
+// the maximum distance and they will all be returned.
+fn filter_preferred_roles<'a>(
+    preferred: Vec<&'a AdaptationSet>,
+    downloader: &DashDownloader) -> Vec<&'a AdaptationSet>
+{
     let role_distance: Vec<u8> = preferred.iter()
         .map(|a| adaptation_role_distance(a, &downloader.role_preference))
         .collect();
@@ -1356,6 +2215,801 @@ fn select_preferred_adaptations<'a>(
 }
 
 
+// The language of the content of an AdaptationSet, which may be specified on the AdaptationSet or
+// on its child Representations.
+pub(crate) fn adaptation_language(a: &AdaptationSet) -> Option<&str> {
+    a.lang.as_deref()
+        .or_else(|| a.representations.iter().find_map(|r| r.lang.as_deref()))
+}
+
+// A Representation with its parent AdaptationSet, from which it may inherit attributes.
+type Candidate<'a> = (&'a AdaptationSet, &'a Representation);
+
+fn adaptation_candidates<'a>(adaptations: &[&'a AdaptationSet]) -> Vec<Candidate<'a>> {
+    adaptations.iter()
+        .flat_map(|a| a.representations.iter().map(move |r| (*a, r)))
+        .collect()
+}
+
+fn audio_codecs<'a>(c: &Candidate<'a>) -> Option<&'a str> {
+    c.1.codecs.as_deref().or(c.0.codecs.as_deref())
+}
+
+// The number of channels of an audio Representation, from the AudioChannelConfiguration element of
+// the Representation or of its AdaptationSet.
+fn audio_channel_count(c: &Candidate) -> Option<u32> {
+    c.1.AudioChannelConfiguration.iter()
+        .chain(c.0.AudioChannelConfiguration.iter())
+        .find_map(AudioChannelConfiguration::channel_count)
+}
+
+// The @audioSamplingRate attribute may contain a single value, or the minimum and maximum sampling
+// rates separated by whitespace.
+fn audio_sampling_rate(c: &Candidate) -> Option<u64> {
+    c.1.audioSamplingRate.as_deref()
+        .or(c.0.audioSamplingRate.as_deref())
+        .and_then(|sr| sr.split_whitespace().next())
+        .and_then(|sr| sr.parse::<u64>().ok())
+}
+
+// Retain the candidates which minimize key.
+fn retain_best_candidates<'a, K: Ord>(
+    candidates: Vec<Candidate<'a>>,
+    key: impl Fn(&Candidate<'a>) -> K) -> Vec<Candidate<'a>>
+{
+    let Some(best) = candidates.iter().map(&key).min() else {
+        return candidates;
+    };
+    candidates.into_iter()
+        .filter(|c| key(c) == best)
+        .collect()
+}
+
+// The @frameRate attribute is a number of frames per second such as "25", or a ratio such as
+// "30000/1001".
+pub(crate) fn parse_manifest_frame_rate(fr: &str) -> Option<f64> {
+    match fr.split_once('/') {
+        Some((num, den)) => {
+            let num = num.trim().parse::<f64>().ok()?;
+            let den = den.trim().parse::<f64>().ok()?;
+            (den > 0.0).then(|| num / den)
+        },
+        None => fr.trim().parse::<f64>().ok(),
+    }
+}
+
+fn video_height(c: &Candidate) -> Option<u64> {
+    c.1.height.or(c.0.height)
+}
+
+fn video_frame_rate(c: &Candidate) -> Option<f64> {
+    c.1.frameRate.as_deref()
+        .or(c.0.frameRate.as_deref())
+        .and_then(parse_manifest_frame_rate)
+}
+
+fn candidate_bandwidth(c: &Candidate) -> Option<u64> {
+    c.1.bandwidth
+}
+
+// Retain the candidates whose value for an attribute (extracted by key) doesn't exceed the ceiling
+// max. Candidates for which the attribute is not specified are retained. If none of the candidates
+// satisfies the ceiling, apply the user's ConstraintFallback: either fail, or retain the candidates
+// with the lowest value of the attribute, which are closest to satisfying the constraint.
+fn filter_ceiling<'a, K: PartialOrd + Copy + std::fmt::Display>(
+    candidates: Vec<Candidate<'a>>,
+    max: Option<K>,
+    key: impl Fn(&Candidate<'a>) -> Option<K>,
+    attribute: &str,
+    downloader: &DashDownloader) -> Result<Vec<Candidate<'a>>, DashMpdError>
+{
+    let Some(max) = max else {
+        return Ok(candidates);
+    };
+    let within: Vec<Candidate> = candidates.iter()
+        .filter(|c| !key(c).is_some_and(|v| v > max))
+        .copied()
+        .collect();
+    if !within.is_empty() || candidates.is_empty() {
+        return Ok(within);
+    }
+    if downloader.constraint_fallback == ConstraintFallback::Error {
+        return Err(DashMpdError::UnhandledMediaStream(
+            format!("no Representation with {attribute} at most {max}")));
+    }
+    let lowest = candidates.iter()
+        .filter_map(&key)
+        .reduce(|a, b| if b < a { b } else { a });
+    if let Some(lowest) = lowest {
+        warn!("  No Representation with {attribute} at most {max}; selecting that with {attribute} {lowest}");
+        Ok(candidates.into_iter()
+           .filter(|c| key(c).is_some_and(|v| v == lowest))
+           .collect())
+    } else {
+        Ok(candidates)
+    }
+}
+
+// Filter out the audio Representations which exceed the max_audio_channels() and
+// max_audio_bandwidth() ceilings, and the part of the total bandwidth budget and of the size budget
+// available for audio.
+fn filter_audio_ceilings<'a>(
+    candidates: Vec<Candidate<'a>>,
+    period: &Period,
+    downloader: &DashDownloader,
+    ps: &PeriodSelection) -> Result<Vec<Candidate<'a>>, DashMpdError>
+{
+    let candidates = filter_ceiling(
+        candidates, downloader.audio_channels_max, audio_channel_count,
+        "audio channel count", downloader)?;
+    let candidates = filter_ceiling(
+        candidates, downloader.audio_bandwidth_max, candidate_bandwidth,
+        "audio bandwidth", downloader)?;
+    let budget = match downloader.total_bandwidth_max {
+        Some(total) if downloader.fetch_video => {
+            // Leave enough of the budget for the lowest bandwidth video stream.
+            let video_min = video_candidates(period, downloader)?.iter()
+                .filter_map(candidate_bandwidth)
+                .min()
+                .unwrap_or(0);
+            Some(total.saturating_sub(video_min))
+        },
+        other => other,
+    };
+    let candidates = filter_ceiling(
+        candidates, budget, candidate_bandwidth, "audio bandwidth within budget", downloader)?;
+    filter_ceiling(
+        candidates, audio_size_budget(period, downloader, ps)?, |c| candidate_size(c, period, ps),
+        "estimated audio size within budget", downloader)
+}
+
+// Does this AdaptationSet have a Viewpoint matching the wanted viewpoint?
+fn viewpoint_matches(a: &AdaptationSet, wanted: &str) -> bool {
+    adaptation_viewpoints(a).iter()
+        .any(|vp| vp.eq_ignore_ascii_case(wanted))
+}
+
+// Apply the viewpoint preference: if some AdaptationSets have the wanted Viewpoint, drop those
+// that have a different Viewpoint. AdaptationSets without a Viewpoint (such as an audio track
+// common to all camera angles) are retained.
+fn filter_viewpoint<'a>(
+    adaptations: Vec<&'a AdaptationSet>,
+    downloader: &DashDownloader) -> Vec<&'a AdaptationSet>
+{
+    let Some(wanted) = &downloader.viewpoint_preference else {
+        return adaptations;
+    };
+    if !adaptations.iter().any(|a| viewpoint_matches(a, wanted)) {
+        return adaptations;
+    }
+    adaptations.into_iter()
+        .filter(|a| viewpoint_matches(a, wanted) || adaptation_viewpoints(a).is_empty())
+        .collect()
+}
+
+// The size of the reference space of SRD source source_id in this Period. The total size need only
+// be specified on one of the SRD descriptors of a source; failing that, we use the extent of the
+// objects.
+fn srd_reference_size(period: &Period, source_id: u64) -> (u64, u64) {
+    let srds: Vec<SpatialRelationship> = period.adaptations.iter()
+        .filter_map(|a| spatial_relationship(a, None))
+        .filter(|srd| srd.source_id == source_id)
+        .collect();
+    if let Some(srd) = srds.iter().find(|srd| srd.total_width.is_some() && srd.total_height.is_some()) {
+        return (srd.total_width.unwrap_or(0), srd.total_height.unwrap_or(0));
+    }
+    let width = srds.iter().map(|srd| srd.object_x + srd.object_width).max().unwrap_or(0);
+    let height = srds.iter().map(|srd| srd.object_y + srd.object_height).max().unwrap_or(0);
+    (width, height)
+}
+
+// Does this AdaptationSet have an SRD descriptor covering the whole of its source?
+pub(crate) fn srd_full_frame(a: &AdaptationSet, period: &Period) -> bool {
+    spatial_relationship(a, None).is_some_and(|srd| {
+        let (width, height) = srd_reference_size(period, srd.source_id);
+        srd.object_x == 0 && srd.object_y == 0 &&
+            srd.object_width == width && srd.object_height == height
+    })
+}
+
+// The video Representations that satisfy the max_video_height(), max_video_frame_rate() and
+// max_video_bandwidth() ceilings and don't have an excluded dynamic range, from the AdaptationSets
+// that match the user's role and accessibility preferences.
+fn video_candidates<'a>(
+    period: &'a Period,
+    downloader: &DashDownloader) -> Result<Vec<Candidate<'a>>, DashMpdError>
+{
+    let mut video_adaptations: Vec<&AdaptationSet> = period.adaptations.iter()
+        .filter(is_video_adaptation)
+        .filter(|a| !accessibility_excluded(a, downloader))
+        .collect();
+    // Trick-mode tracks are only intended for fast forward and rewind.
+    if video_adaptations.iter().any(|a| !is_trickmode_adaptation(a)) {
+        video_adaptations.retain(|a| !is_trickmode_adaptation(a));
+    }
+    // Only one video track is downloaded, so including sign language interpretation means selecting
+    // a sign language AdaptationSet if one is available.
+    if video_adaptations.iter().any(|a| accessibility_included(a, downloader)) {
+        video_adaptations.retain(|a| accessibility_included(a, downloader));
+    }
+    let mut video_adaptations = filter_viewpoint(video_adaptations, downloader);
+    // The tiles of a spatially partitioned video are not alternatives to the full frame, so an
+    // AdaptationSet covering the whole of its SRD source is preferred to the tiles.
+    if video_adaptations.iter().any(|a| srd_full_frame(a, period)) {
+        video_adaptations.retain(|a| spatial_relationship(a, None).is_none() || srd_full_frame(a, period));
+    }
+    let adaptations = select_preferred_adaptations(video_adaptations, downloader);
+    let candidates = filter_video_dynamic_range_excluded(adaptation_candidates(&adaptations), downloader)?;
+    let candidates = filter_ceiling(
+        candidates, downloader.video_height_max, video_height, "video height", downloader)?;
+    let candidates = filter_ceiling(
+        candidates, downloader.video_frame_rate_max, video_frame_rate, "video frame rate", downloader)?;
+    filter_ceiling(candidates, downloader.video_bandwidth_max, candidate_bandwidth, "video bandwidth", downloader)
+}
+
+// The part of the total bandwidth budget which is available for the video stream, once the audio
+// streams have been selected.
+fn video_bandwidth_budget(
+    period: &Period,
+    downloader: &DashDownloader,
+    ps: &PeriodSelection) -> Result<Option<u64>, DashMpdError>
+{
+    let Some(total) = downloader.total_bandwidth_max else {
+        return Ok(None);
+    };
+    let audio: u64 = if downloader.fetch_audio {
+        select_audio_representations(period, downloader, ps)?.iter()
+            .filter_map(|(_, r)| r.bandwidth)
+            .sum()
+    } else {
+        0
+    };
+    Ok(Some(total.saturating_sub(audio)))
+}
+
+// The estimated size of a candidate Representation, when a size budget is active for this Period.
+fn candidate_size(c: &Candidate, period: &Period, ps: &PeriodSelection) -> Option<u64> {
+    let psb = ps.size_budget.as_ref()?;
+    let ai = period.adaptations.iter().position(|a| std::ptr::eq(a, c.0))?;
+    let ri = c.0.representations.iter().position(|r| std::ptr::eq(r, c.1))?;
+    psb.sizes.get(&(ai, ri)).copied()
+}
+
+// The part of the size budget for this Period which is available for audio streams, leaving enough
+// for the smallest video stream.
+fn audio_size_budget(
+    period: &Period,
+    downloader: &DashDownloader,
+    ps: &PeriodSelection) -> Result<Option<u64>, DashMpdError>
+{
+    let Some(psb) = &ps.size_budget else {
+        return Ok(None);
+    };
+    let video_min = if downloader.fetch_video {
+        video_candidates(period, downloader)?.iter()
+            .filter_map(|c| candidate_size(c, period, ps))
+            .min()
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    Ok(Some(psb.budget.saturating_sub(video_min)))
+}
+
+// The part of the size budget for this Period which is available for the video stream, once the
+// audio streams have been selected.
+fn video_size_budget(
+    period: &Period,
+    downloader: &DashDownloader,
+    ps: &PeriodSelection) -> Result<Option<u64>, DashMpdError>
+{
+    let Some(psb) = &ps.size_budget else {
+        return Ok(None);
+    };
+    let audio: u64 = if downloader.fetch_audio {
+        select_audio_representations(period, downloader, ps)?.iter()
+            .filter_map(|(a, r)| candidate_size(&(*a, *r), period, ps))
+            .sum()
+    } else {
+        0
+    };
+    Ok(Some(psb.budget.saturating_sub(audio)))
+}
+
+fn continuity_stream(c: &Candidate) -> ContinuityStream {
+    ContinuityStream {
+        adaptation_id: c.0.id.clone(),
+        representation_id: c.1.id.clone(),
+        codecs: c.1.codecs.clone().or_else(|| c.0.codecs.clone()),
+        width: c.1.width.or(c.0.width),
+        height: c.1.height.or(c.0.height),
+        language: adaptation_language(c.0).map(String::from),
+    }
+}
+
+// Does this AdaptationSet carry a period-continuity or period-connectivity SupplementalProperty
+// whose @value is the @id of the Period period_id? This signals that its content continues that of
+// the AdaptationSet with the same @id in that Period.
+fn adaptation_signals_continuity(a: &AdaptationSet, period_id: &str) -> bool {
+    a.supplemental_property.iter()
+        .filter(|sp| sp.schemeIdUri == "urn:mpeg:dash:period-continuity:2015" ||
+                sp.schemeIdUri == "urn:mpeg:dash:period-connectivity:2015")
+        .filter_map(|sp| sp.value.as_deref())
+        .any(|v| v.split([',', ' ']).any(|pid| pid == period_id))
+}
+
+// How well candidate c continues the stream prev selected in the previous Period, where lower is
+// better. The first element ranks the AdaptationSet: signalled as continuing prev's AdaptationSet,
+// then the same @id, then the same language. The second ranks the Representation: the same @id in
+// the same AdaptationSet, then the same codec and resolution, then the same codec.
+fn continuity_rank(c: &Candidate, prev: &ContinuityStream, prev_period_id: Option<&str>) -> (u8, u8) {
+    let same_adaptation = prev.adaptation_id.is_some() && c.0.id == prev.adaptation_id;
+    let same_language = match (adaptation_language(c.0), prev.language.as_deref()) {
+        (Some(l), Some(pl)) => l.eq_ignore_ascii_case(pl),
+        (None, None) => true,
+        _ => false,
+    };
+    let adaptation_rank = if same_adaptation && prev_period_id.is_some_and(|pid| adaptation_signals_continuity(c.0, pid)) {
+        0
+    } else if same_adaptation {
+        1
+    } else if same_language {
+        2
+    } else {
+        3
+    };
+    let s = continuity_stream(c);
+    let same_codec = s.codecs.is_some() && s.codecs == prev.codecs;
+    let representation_rank = if same_adaptation && same_codec &&
+        prev.representation_id.is_some() && s.representation_id == prev.representation_id
+    {
+        0
+    } else if same_codec && s.width == prev.width && s.height == prev.height {
+        1
+    } else if same_codec {
+        2
+    } else {
+        3
+    };
+    (adaptation_rank, representation_rank)
+}
+
+// Retain the candidates which best continue one of the streams (selected by the streams function)
+// that were selected in the previous Period, if prefer_period_continuity is enabled.
+fn filter_period_continuity<'a>(
+    candidates: Vec<Candidate<'a>>,
+    streams: impl Fn(&PeriodContinuity) -> &[ContinuityStream],
+    ps: &PeriodSelection) -> Vec<Candidate<'a>>
+{
+    let Some(pc) = &ps.previous else {
+        return candidates;
+    };
+    let previous = streams(pc);
+    if previous.is_empty() {
+        return candidates;
+    }
+    retain_best_candidates(candidates, |c| {
+        previous.iter()
+            .map(|prev| continuity_rank(c, prev, pc.period_id.as_deref()))
+            .min()
+            .unwrap_or((u8::MAX, u8::MAX))
+    })
+}
+
+// Apply the audio codec, channel count and sampling rate preferences, in that order. Each
+// preference retains the candidates that best match it, so a preference that no candidate matches
+// doesn't filter anything out.
+fn filter_audio_preferences<'a>(
+    candidates: Vec<Candidate<'a>>,
+    downloader: &DashDownloader) -> Vec<Candidate<'a>>
+{
+    let mut candidates = candidates;
+    if !downloader.audio_codec_preference.is_empty() {
+        candidates = retain_best_candidates(candidates, |c| {
+            audio_codecs(c)
+                .and_then(|codec| downloader.audio_codec_preference.iter().position(|p| codec.starts_with(p)))
+                .unwrap_or(usize::MAX)
+        });
+    }
+    if let Some(channels) = downloader.audio_channels_preference {
+        candidates = retain_best_candidates(candidates, |c| {
+            audio_channel_count(c).map_or(u32::MAX, |n| n.abs_diff(channels))
+        });
+    }
+    if let Some(rate) = downloader.audio_sampling_rate_preference {
+        candidates = retain_best_candidates(candidates, |c| {
+            audio_sampling_rate(c).map_or(u64::MAX, |sr| sr.abs_diff(rate))
+        });
+    }
+    candidates
+}
+
+// Select a Representation of AdaptationSet a from among the candidates, according to the period
+// continuity and quality preferences.
+fn select_audio_candidate<'a>(
+    a: &'a AdaptationSet,
+    candidates: &[Candidate<'a>],
+    downloader: &DashDownloader,
+    ps: &PeriodSelection) -> Option<&'a Representation>
+{
+    let own: Vec<Candidate> = candidates.iter()
+        .filter(|(ca, _)| std::ptr::eq(*ca, a))
+        .copied()
+        .collect();
+    let representations: Vec<&Representation> = filter_period_continuity(own, |pc| &pc.audio, ps)
+        .into_iter()
+        .map(|(_, r)| r)
+        .collect();
+    select_preferred_representation(&representations, downloader)
+}
+
+fn preselection_accessibility_excluded(p: &Preselection, downloader: &DashDownloader) -> bool {
+    downloader.accessibility_excluded.iter().any(|f| match f {
+        AccessibilityFeature::AudioDescription => audio_description_signalled(&p.roles, &p.accessibilities),
+        AccessibilityFeature::HardOfHearing => hearing_impaired_signalled(&p.roles, &p.accessibilities),
+        _ => false,
+    })
+}
+
+// The audio AdaptationSets referenced by the preselectionComponents of Preselection p, with the
+// main component first.
+fn preselection_adaptations<'a>(p: &Preselection, audio_adaptations: &[&'a AdaptationSet]) -> Vec<&'a AdaptationSet> {
+    p.components().iter()
+        .filter_map(|c| audio_adaptations.iter().find(|a| a.id.as_deref() == Some(*c)))
+        .copied()
+        .collect()
+}
+
+// Select a Preselection in this Period, if it contains Preselection elements that reference audio
+// AdaptationSets. The Preselection with the tag requested by the user is chosen if present, and
+// otherwise they are ranked according to the audio language preference, then the role preference,
+// then their @selectionPriority (higher values first; the default is 1).
+fn select_preselection<'a>(
+    period: &'a Period,
+    downloader: &DashDownloader) -> Option<&'a Preselection>
+{
+    let audio_adaptations: Vec<&AdaptationSet> = period.adaptations.iter()
+        .filter(is_audio_adaptation)
+        .filter(|a| !accessibility_excluded(a, downloader))
+        .collect();
+    let candidates: Vec<&Preselection> = period.pre_selections.iter()
+        .filter(|p| !preselection_adaptations(p, &audio_adaptations).is_empty())
+        .filter(|p| !preselection_accessibility_excluded(p, downloader))
+        .collect();
+    if let Some(tag) = &downloader.preselection_tag {
+        if let Some(p) = candidates.iter().find(|p| p.tag.eq(tag)) {
+            return Some(p);
+        }
+    }
+    let wanted_languages = &downloader.language_preference_audio;
+    candidates.into_iter()
+        .min_by_key(|p| {
+            let lang_rank = p.lang.as_deref()
+                .and_then(|l| language_preference_rank(l, wanted_languages))
+                .unwrap_or((usize::MAX, usize::MAX));
+            let role_rank = p.roles.iter()
+                .filter_map(|r| r.value.as_ref())
+                .filter_map(|v| downloader.role_preference.iter().position(|rp| rp == v))
+                .min()
+                .unwrap_or(usize::MAX);
+            (lang_rank, role_rank, std::cmp::Reverse(p.selectionPriority.unwrap_or(1)))
+        })
+}
+
+// Select the audio Representations to download from this Period, alongside their parent
+// AdaptationSet. By default this is a single Representation, chosen according to the language, role
+// and quality preferences. When several audio languages have been requested with
+// prefer_audio_languages(), we select one Representation from each audio AdaptationSet in a wanted
+// language (applying the role preference within each language), and when all audio tracks have been
+// requested, one Representation from each audio AdaptationSet. The audio codec, channel and sampling
+// rate preferences are applied across the candidate AdaptationSets, so that for example a stereo
+// AdaptationSet is chosen over a 5.1 AdaptationSet in the same language. The first track returned is
+// the one that will be flagged as the default audio track in the output container.
+fn select_audio_representations<'a>(
+    period: &'a Period,
+    downloader: &DashDownloader,
+    ps: &PeriodSelection) -> Result<Vec<(&'a AdaptationSet, &'a Representation)>, DashMpdError>
+{
+    let all_audio_adaptations: Vec<&AdaptationSet> = period.adaptations.iter()
+        .filter(is_audio_adaptation)
+        .filter(|a| !accessibility_excluded(a, downloader))
+        .collect();
+    let all_audio_adaptations = filter_viewpoint(all_audio_adaptations, downloader);
+    // AdaptationSets with an accessibility feature that is to be included as an extra track are
+    // not candidates for the main audio track, unless there is no other audio AdaptationSet.
+    let mut audio_adaptations: Vec<&AdaptationSet> = all_audio_adaptations.iter()
+        .filter(|a| !accessibility_included(a, downloader))
+        .copied()
+        .collect();
+    if audio_adaptations.is_empty() {
+        audio_adaptations.clone_from(&all_audio_adaptations);
+    }
+    let mut selected: Vec<(&AdaptationSet, &Representation)> = Vec::new();
+    // For next-generation audio, the meaningful choice is a Preselection, whose components are
+    // downloaded as separate audio tracks so that they can be muxed together.
+    if !downloader.fetch_all_audio_tracks {
+        if let Some(p) = select_preselection(period, downloader) {
+            for a in preselection_adaptations(p, &all_audio_adaptations) {
+                if selected.iter().any(|(s, _)| std::ptr::eq(*s, a)) {
+                    continue;
+                }
+                let candidates = filter_audio_ceilings(adaptation_candidates(&[a]), period, downloader, ps)?;
+                let candidates = filter_audio_preferences(candidates, downloader);
+                if let Some(r) = select_audio_candidate(a, &candidates, downloader, ps) {
+                    selected.push((a, r));
+                }
+            }
+            if !selected.is_empty() {
+                return Ok(selected);
+            }
+        }
+    }
+    let wanted_languages = &downloader.language_preference_audio;
+    if downloader.fetch_all_audio_tracks {
+        let mut adaptations = audio_adaptations.clone();
+        // Tracks in the preferred languages come first, so that they are flagged as the default
+        // track. The sort is stable, so manifest order is otherwise preserved.
+        adaptations.sort_by_key(|a| adaptation_language_rank(a, wanted_languages));
+        let candidates = filter_audio_ceilings(adaptation_candidates(&adaptations), period, downloader, ps)?;
+        for a in adaptations {
+            let own: Vec<Candidate> = candidates.iter()
+                .filter(|(ca, _)| std::ptr::eq(*ca, a))
+                .copied()
+                .collect();
+            let own = filter_audio_preferences(own, downloader);
+            if let Some(r) = select_audio_candidate(a, &own, downloader, ps) {
+                selected.push((a, r));
+            }
+        }
+    } else if downloader.fetch_matching_audio_languages && !wanted_languages.is_empty() {
+        for wanted in wanted_languages {
+            // The AdaptationSets which best match this language, following RFC 4647 lookup.
+            let ranks: Vec<(usize, usize)> = audio_adaptations.iter()
+                .map(|a| adaptation_language_rank(a, std::slice::from_ref(wanted)))
+                .collect();
+            let best = ranks.iter().min().copied().unwrap_or((usize::MAX, usize::MAX));
+            let matching: Vec<&AdaptationSet> = audio_adaptations.iter()
+                .zip(&ranks)
+                .filter(|(_, r)| best.0 != usize::MAX && **r == best)
+                .map(|(a, _)| *a)
+                .collect();
+            if matching.is_empty() {
+                info!("  No audio stream available in language {wanted}");
+            }
+            let group = filter_preferred_roles(matching, downloader);
+            let candidates = filter_audio_ceilings(adaptation_candidates(&group), period, downloader, ps)?;
+            let candidates = filter_audio_preferences(candidates, downloader);
+            for a in group {
+                if selected.iter().any(|(s, _)| std::ptr::eq(*s, a)) {
+                    continue;
+                }
+                if let Some(r) = select_audio_candidate(a, &candidates, downloader, ps) {
+                    selected.push((a, r));
+                }
+            }
+        }
+        if selected.is_empty() && !audio_adaptations.is_empty() {
+            warn!("  None of the preferred audio languages is available; selecting a single audio stream");
+        }
+    }
+    if selected.is_empty() {
+        let adaptations = select_preferred_adaptations(audio_adaptations, downloader);
+        let candidates = filter_audio_ceilings(adaptation_candidates(&adaptations), period, downloader, ps)?;
+        let candidates = filter_audio_preferences(candidates, downloader);
+        let candidates = filter_period_continuity(candidates, |pc| &pc.audio, ps);
+        let representations: Vec<&Representation> = candidates.iter()
+            .map(|(_, r)| *r)
+            .collect();
+        if let Some(audio_repr) = select_preferred_representation(&representations, downloader) {
+            // Find the AdaptationSet that is the parent of the selected Representation. This may be
+            // needed for certain Representation attributes whose value can be located higher in the
+            // XML tree.
+            let audio_adaptation = period.adaptations.iter()
+                .find(|a| a.representations.iter().any(|r| r.eq(audio_repr)))
+                .unwrap();
+            selected.push((audio_adaptation, audio_repr));
+        }
+    }
+    // Audio description and other accessibility tracks requested by the user are added as extra
+    // tracks, after the main audio track.
+    for a in included_accessibility_adaptations(&all_audio_adaptations, wanted_languages, downloader) {
+        if selected.iter().any(|(s, _)| std::ptr::eq(*s, a)) {
+            continue;
+        }
+        let candidates = filter_audio_ceilings(adaptation_candidates(&[a]), period, downloader, ps)?;
+        let candidates = filter_audio_preferences(candidates, downloader);
+        if let Some(r) = select_audio_candidate(a, &candidates, downloader, ps) {
+            selected.push((a, r));
+        }
+    }
+    Ok(selected)
+}
+
+// Does this AdaptationSet contain forced subtitles (those that are displayed when the viewer has
+// not enabled subtitles, for example to translate foreign-language dialogue or onscreen text)? The
+// DASH-IF IOP guidelines use the "forced-subtitle" Role value; some packagers use "forced_subtitle".
+pub(crate) fn adaptation_forced_subtitles(a: &AdaptationSet) -> bool {
+    a.Role.iter()
+        .filter_map(|r| r.value.as_deref())
+        .any(|v| v.eq_ignore_ascii_case("forced-subtitle") ||
+             v.eq_ignore_ascii_case("forced_subtitle") ||
+             v.eq_ignore_ascii_case("forced"))
+}
+
+// Does this AdaptationSet contain subtitles for the deaf and hard of hearing (SDH)? These are
+// signalled by the "caption" Role, or by an Accessibility descriptor using the "caption" value of
+// the DASH role scheme or the "hard of hearing" value (2) of the DVB AudioPurposeCS scheme.
+pub(crate) fn adaptation_hearing_impaired(a: &AdaptationSet) -> bool {
+    hearing_impaired_signalled(&a.Role, &a.Accessibility)
+}
+
+fn hearing_impaired_signalled(roles: &[Role], accessibility: &[Accessibility]) -> bool {
+    let caption_role = roles.iter()
+        .filter_map(|r| r.value.as_deref())
+        .any(|v| v.eq_ignore_ascii_case("caption"));
+    let accessibility = accessibility.iter().any(|acc| {
+        let value = acc.value.as_deref().unwrap_or("");
+        match acc.schemeIdUri.as_str() {
+            "urn:mpeg:dash:role:2011" => value.eq_ignore_ascii_case("caption"),
+            "urn:tva:metadata:cs:AudioPurposeCS:2007" => value == "2",
+            _ => false,
+        }
+    });
+    caption_role || accessibility
+}
+
+// Does this AdaptationSet contain audio description for the blind and visually impaired? This is
+// signalled by the "description" Role, or by an Accessibility descriptor using the "description"
+// value of the DASH role scheme or the "visually impaired" value (1) of the DVB AudioPurposeCS
+// scheme.
+pub(crate) fn adaptation_audio_description(a: &AdaptationSet) -> bool {
+    audio_description_signalled(&a.Role, &a.Accessibility)
+}
+
+fn audio_description_signalled(roles: &[Role], accessibility: &[Accessibility]) -> bool {
+    let description_role = roles.iter()
+        .filter_map(|r| r.value.as_deref())
+        .any(|v| v.eq_ignore_ascii_case("description"));
+    let accessibility = accessibility.iter().any(|acc| {
+        let value = acc.value.as_deref().unwrap_or("");
+        match acc.schemeIdUri.as_str() {
+            "urn:mpeg:dash:role:2011" => value.eq_ignore_ascii_case("description"),
+            "urn:tva:metadata:cs:AudioPurposeCS:2007" => value == "1",
+            _ => false,
+        }
+    });
+    description_role || accessibility
+}
+
+// Does this AdaptationSet contain sign language interpretation? This is signalled by the "sign"
+// Role or Accessibility value of the DASH role scheme, or by the "sgn" language code.
+fn adaptation_sign_language(a: &AdaptationSet) -> bool {
+    let sign_role = a.Role.iter()
+        .filter_map(|r| r.value.as_deref())
+        .chain(a.Accessibility.iter()
+               .filter(|acc| acc.schemeIdUri == "urn:mpeg:dash:role:2011")
+               .filter_map(|acc| acc.value.as_deref()))
+        .any(|v| v.eq_ignore_ascii_case("sign"));
+    let sign_lang = adaptation_language(a)
+        .and_then(|l| l.split('-').next())
+        .is_some_and(|l| l.eq_ignore_ascii_case("sgn"));
+    sign_role || sign_lang
+}
+
+fn adaptation_has_accessibility_feature(a: &AdaptationSet, feature: AccessibilityFeature) -> bool {
+    match feature {
+        AccessibilityFeature::AudioDescription => adaptation_audio_description(a),
+        AccessibilityFeature::HardOfHearing => adaptation_hearing_impaired(a),
+        AccessibilityFeature::ForcedSubtitles => adaptation_forced_subtitles(a),
+        AccessibilityFeature::SignLanguage => adaptation_sign_language(a),
+    }
+}
+
+fn accessibility_excluded(a: &AdaptationSet, downloader: &DashDownloader) -> bool {
+    downloader.accessibility_excluded.iter()
+        .any(|f| adaptation_has_accessibility_feature(a, *f))
+}
+
+fn accessibility_included(a: &AdaptationSet, downloader: &DashDownloader) -> bool {
+    downloader.accessibility_included.iter()
+        .any(|f| adaptation_has_accessibility_feature(a, *f))
+}
+
+// The AdaptationSets with an accessibility feature that the user has asked to include as extra
+// tracks, restricted to the wanted languages if any have been specified.
+fn included_accessibility_adaptations<'a>(
+    adaptations: &[&'a AdaptationSet],
+    wanted_languages: &[String],
+    downloader: &DashDownloader) -> Vec<&'a AdaptationSet>
+{
+    adaptations.iter()
+        .filter(|a| accessibility_included(a, downloader))
+        .filter(|a| wanted_languages.is_empty() ||
+                adaptation_language_rank(a, wanted_languages).0 < wanted_languages.len())
+        .copied()
+        .collect()
+}
+
+// Select the subtitle AdaptationSets to download from this Period. By default this is a single
+// AdaptationSet, chosen according to the subtitle language preference (or the first subtitle
+// AdaptationSet in the manifest). When several subtitle languages have been requested with
+// prefer_subtitle_languages(), we select all subtitle AdaptationSets in a wanted language
+// (including forced and SDH variants), and when all subtitle tracks have been requested, all
+// subtitle AdaptationSets, with those in a preferred language first.
+fn select_subtitle_adaptations<'a>(
+    period: &'a Period,
+    downloader: &DashDownloader) -> Vec<&'a AdaptationSet>
+{
+    let subtitle_adaptations: Vec<&AdaptationSet> = period.adaptations.iter()
+        .filter(is_subtitle_adaptation)
+        .filter(|a| !accessibility_excluded(a, downloader))
+        .collect();
+    let wanted_languages = &downloader.language_preference_subtitles;
+    let language_rank = |a: &AdaptationSet| adaptation_language_rank(a, wanted_languages);
+    let mut selected: Vec<&AdaptationSet> = Vec::new();
+    if downloader.fetch_all_subtitle_tracks {
+        selected = subtitle_adaptations.clone();
+        // The sort is stable, so manifest order is otherwise preserved.
+        selected.sort_by_key(|a| language_rank(a));
+    } else if downloader.fetch_matching_subtitle_languages && !wanted_languages.is_empty() {
+        selected = subtitle_adaptations.iter()
+            .filter(|a| language_rank(a).0 < wanted_languages.len())
+            .copied()
+            .collect();
+        selected.sort_by_key(|a| language_rank(a));
+        if selected.is_empty() && !subtitle_adaptations.is_empty() {
+            warn!("  None of the preferred subtitle languages is available; selecting a single subtitle track");
+        }
+    }
+    if selected.is_empty() {
+        let maybe_subtitle_adaptation = if !wanted_languages.is_empty() {
+            subtitle_adaptations.iter()
+                .min_by_key(|a| adaptation_language_rank(a, wanted_languages))
+        } else {
+            // returns the first subtitle adaptation found
+            subtitle_adaptations.first()
+        };
+        if let Some(a) = maybe_subtitle_adaptation {
+            selected.push(a);
+        }
+    }
+    for a in included_accessibility_adaptations(&subtitle_adaptations, wanted_languages, downloader) {
+        if !selected.iter().any(|s| std::ptr::eq(*s, a)) {
+            selected.push(a);
+        }
+    }
+    selected
+}
+
+// The path from which the sidecar files for an additional subtitle track are named, for example
+// "movie.fr.forced.mp4" (giving "movie.fr.forced.vtt") for forced French subtitles and output path
+// "movie.mp4". A numeric suffix is added if several tracks would otherwise share the same name.
+fn subtitle_track_output_path(
+    output_path: &Path,
+    language: &str,
+    forced: bool,
+    hearing_impaired: bool,
+    used: &[PathBuf]) -> PathBuf
+{
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = output_path.extension().unwrap_or_default().to_string_lossy();
+    let mut label = format!("{stem}.{language}");
+    if forced {
+        label += ".forced";
+    }
+    if hearing_impaired {
+        label += ".sdh";
+    }
+    let mut candidate = output_path.with_file_name(format!("{label}.{ext}"));
+    let mut n = 2;
+    while used.contains(&candidate) {
+        candidate = output_path.with_file_name(format!("{label}.{n}.{ext}"));
+        n += 1;
+    }
+    candidate
+}
+
+
 // Filter Representations according to their @id by filtering out those that do not have the
 // user-specified video_id_wanted substring in the id attribute.
 fn representation_filter_video_id<'a>(
@@ -1418,6 +3072,73 @@ fn representation_filter_video_height<'a>(
     }
 }
 
+// Whether the dynamic range of a video stream matches a dynamic range specified by the user, where
+// DolbyVision(None) matches any Dolby Vision profile.
+fn dynamic_range_matches(range: VideoDynamicRange, wanted: VideoDynamicRange) -> bool {
+    match (range, wanted) {
+        (VideoDynamicRange::DolbyVision(_), VideoDynamicRange::DolbyVision(None)) => true,
+        _ => range == wanted,
+    }
+}
+
+// Filter out the video Representations whose dynamic range has been excluded by the user. Unlike
+// the ceilings, there is no fallback when no Representation is acceptable, because downloading (for
+// example) PQ-encoded content when only SDR content can be handled downstream isn't useful.
+fn filter_video_dynamic_range_excluded<'a>(
+    candidates: Vec<Candidate<'a>>,
+    downloader: &DashDownloader) -> Result<Vec<Candidate<'a>>, DashMpdError>
+{
+    if downloader.video_dynamic_range_excluded.is_empty() {
+        return Ok(candidates);
+    }
+    let acceptable: Vec<Candidate> = candidates.iter()
+        .filter(|(a, r)| {
+            let range = video_dynamic_range(a, r);
+            !downloader.video_dynamic_range_excluded.iter().any(|x| dynamic_range_matches(range, *x))
+        })
+        .copied()
+        .collect();
+    if acceptable.is_empty() && !candidates.is_empty() {
+        let available: Vec<String> = candidates.iter()
+            .map(|(a, r)| video_dynamic_range(a, r).to_string())
+            .collect();
+        return Err(DashMpdError::UnhandledMediaStream(
+            format!("no video stream with an acceptable dynamic range (available: {})", available.join(", "))));
+    }
+    Ok(acceptable)
+}
+
+// Filter Representations according to their dynamic range, following the user-specified preference
+// ordering in video_dynamic_range_preference. A Dolby Vision preference also matches a stream whose
+// Dolby Vision enhancement is signalled by scte214:supplementalCodecs.
+fn representation_filter_video_dynamic_range<'a>(
+    representations: Vec<&'a Representation>,
+    candidates: &[Candidate<'a>],
+    downloader: &DashDownloader) -> Vec<&'a Representation>
+{
+    if downloader.video_dynamic_range_preference.is_empty() {
+        return representations;
+    }
+    let rank = |r: &Representation| -> usize {
+        let Some((a, _)) = candidates.iter().find(|(_, cr)| std::ptr::eq(*cr, r)) else {
+            return usize::MAX;
+        };
+        let range = video_dynamic_range(a, r);
+        let supplemental = supplemental_dolby_vision_profile(a, r).map(VideoDynamicRange::DolbyVision);
+        downloader.video_dynamic_range_preference.iter()
+            .position(|wanted| dynamic_range_matches(range, *wanted) ||
+                      supplemental.is_some_and(|s| dynamic_range_matches(s, *wanted)))
+            .unwrap_or(usize::MAX)
+    };
+    match representations.iter().map(|r| rank(r)).min() {
+        Some(best) => representations.iter()
+            .filter(|r| rank(r) == best)
+            .copied()
+            .collect(),
+        None => representations,
+    }
+}
+
 // Filter Representations according to the video codec, following the user-specified preference
 // ordering in video_codec_preference. If the preference is not specified (the
 // video_codec_preference is empty), then do not filter out any Representations.
@@ -1463,8 +3184,24 @@ fn representation_filter_video_codec<'a>(
     }
 }
 
-// Filter Representations according to the user-specified quality_preference. Rank following the
-// @qualityRanking attribute if it is present, and otherwise by the @bandwidth attribute. Note that
+// Retain the Representations which best continue the video stream selected in the previous Period.
+fn representation_filter_video_continuity<'a>(
+    representations: Vec<&'a Representation>,
+    candidates: &[Candidate<'a>],
+    ps: &PeriodSelection) -> Vec<&'a Representation>
+{
+    let retained: Vec<Candidate> = candidates.iter()
+        .filter(|(_, r)| representations.iter().any(|rr| std::ptr::eq(*r, *rr)))
+        .copied()
+        .collect();
+    filter_period_continuity(retained, |pc| pc.video.as_slice(), ps)
+        .into_iter()
+        .map(|(_, r)| r)
+        .collect()
+}
+
+// Filter Representations according to the user-specified quality_preference. Rank following the
+// @qualityRanking attribute if it is present, and otherwise by the @bandwidth attribute. Note that
 // quality ranking may be different from bandwidth ranking when different codecs are used. Note that
 // there is always a quality_preference, which defaults to the lowest quality and smallest file
 // size.
@@ -1475,7 +3212,7 @@ fn representation_filter_video_quality<'a>(
     if representations.iter().all(|x| x.qualityRanking.is_some()) {
         // rank according to the @qualityRanking attribute (lower values represent
         // higher quality content)
-        match downloader.quality_preference {
+        match downloader.quality_preference() {
             QualityPreference::Lowest => {
                 let best = representations.iter()
                     .max_by_key(|r| r.qualityRanking.unwrap_or(u8::MAX));
@@ -1524,7 +3261,7 @@ fn representation_filter_video_quality<'a>(
     } else {
         // rank according to the bandwidth attribute (lower values imply lower quality)
         let bw_large = 1_000_000_000;
-        match downloader.quality_preference {
+        match downloader.quality_preference() {
             QualityPreference::Lowest => {
                 let best = representations.iter()
                     .min_by_key(|r| r.bandwidth.unwrap_or(bw_large));
@@ -1586,7 +3323,7 @@ fn select_preferred_representation<'a>(
     if representations.iter().all(|x| x.qualityRanking.is_some()) {
         // rank according to the @qualityRanking attribute (lower values represent
         // higher quality content)
-        match downloader.quality_preference {
+        match downloader.quality_preference() {
             QualityPreference::Lowest =>
                 representations.iter()
                 .max_by_key(|r| r.qualityRanking.unwrap_or(u8::MAX))
@@ -1617,7 +3354,7 @@ fn select_preferred_representation<'a>(
         }
     } else {
         // rank according to the bandwidth attribute (lower values imply lower quality)
-        match downloader.quality_preference {
+        match downloader.quality_preference() {
             QualityPreference::Lowest => representations.iter()
                 .min_by_key(|r| r.bandwidth.unwrap_or(1_000_000_000))
                 .copied(),
@@ -1697,12 +3434,18 @@ fn print_available_streams_representation(r: &Representation, a: &AdaptationSet,
                      |r| r.value.as_ref().map_or_else(|| String::from(""), |v| format!(" role={v}")));
     let label = a.Label.first()
         .map_or_else(|| String::from(""), |l| format!(" label={}", l.clone().content));
+    let viewpoint = adaptation_viewpoints(a).first()
+        .map_or_else(|| String::from(""), |vp| format!(" viewpoint={vp}"));
+    let srd = spatial_relationship(a, Some(r))
+        .map_or_else(|| String::from(""),
+                     |srd| format!(" srd={}@{},{}+{}x{}", srd.source_id, srd.object_x, srd.object_y,
+                                   srd.object_width, srd.object_height));
     let maybe_id = if let Some(rid) = &r.id {
         format!(" (id={rid})")
     } else {
         String::from("")
     };
-    info!("  {typ} {codec:17} | {:5} Kbps | {fmt:>9}{role}{label}{maybe_id}", bw / 1024);
+    info!("  {typ} {codec:17} | {:5} Kbps | {fmt:>9}{role}{label}{viewpoint}{srd}{maybe_id}", bw / 1024);
 }
 
 fn print_available_streams_adaptation(a: &AdaptationSet, typ: &str) {
@@ -1710,16 +3453,51 @@ fn print_available_streams_adaptation(a: &AdaptationSet, typ: &str) {
         .for_each(|r| print_available_streams_representation(r, a, typ));
 }
 
+fn print_available_thumbnails_adaptation(a: &AdaptationSet) {
+    for r in &a.representations {
+        let unspecified = "<unspecified>".to_string();
+        let mime = r.mimeType.as_ref().unwrap_or(a.mimeType.as_ref().unwrap_or(&unspecified));
+        let w = r.width.unwrap_or(a.width.unwrap_or(0));
+        let h = r.height.unwrap_or(a.height.unwrap_or(0));
+        let tiles = thumbnail_tile_grid(a, r)
+            .map_or_else(|| String::from(""), |(c, r)| format!(" tiles={c}x{r}"));
+        let maybe_id = r.id.as_ref()
+            .map_or_else(|| String::from(""), |rid| format!(" (id={rid})"));
+        info!("  image {mime:17} | {w}x{h}{tiles}{maybe_id}");
+    }
+}
+
+fn print_available_preselection(p: &Preselection) {
+    let unknown = String::from("?");
+    let lang = p.lang.as_ref().unwrap_or(&unknown);
+    let role = p.roles.first()
+        .map_or_else(|| String::from(""),
+                     |r| r.value.as_ref().map_or_else(|| String::from(""), |v| format!(" role={v}")));
+    let label = p.Label.first()
+        .map_or_else(|| String::from(""), |l| format!(" label={}", l.content.trim()));
+    let components = p.components().join(",");
+    info!("  preselection {:17} | tag={:4} | lang={lang}{role}{label} (components={components})", p.codecs, p.tag);
+}
+
 fn print_available_streams_period(p: &Period) {
     p.adaptations.iter()
         .filter(is_audio_adaptation)
         .for_each(|a| print_available_streams_adaptation(a, "audio"));
     p.adaptations.iter()
         .filter(is_video_adaptation)
+        .filter(|a| !is_trickmode_adaptation(a))
         .for_each(|a| print_available_streams_adaptation(a, "video"));
+    p.adaptations.iter()
+        .filter(is_trickmode_adaptation)
+        .for_each(|a| print_available_streams_adaptation(a, "trickmode"));
+    p.adaptations.iter()
+        .filter(is_image_adaptation)
+        .for_each(print_available_thumbnails_adaptation);
     p.adaptations.iter()
         .filter(is_subtitle_adaptation)
         .for_each(print_available_subtitles_adaptation);
+    p.pre_selections.iter()
+        .for_each(print_available_preselection);
 }
 
 #[tracing::instrument(level="trace", skip_all)]
@@ -1750,7 +3528,103 @@ fn print_available_streams(mpd: &MPD) {
     }
 }
 
-async fn extract_init_pssh(downloader: &DashDownloader, init_url: Url) -> Option<Vec<u8>> {
+// The KIDs in `kids` (in normalized hex format) for which we don't yet have a decryption key.
+fn kids_without_keys(downloader: &DashDownloader, kids: &[String]) -> Vec<String> {
+    let known: Vec<String> = downloader.decryption_keys.keys()
+        .map(|k| k.replace('-', "").to_lowercase())
+        .collect();
+    kids.iter()
+        .filter(|kid| !known.contains(kid))
+        .cloned()
+        .collect()
+}
+
+// Load the decryption keys from any key files and CPIX documents registered by the user.
+async fn load_decryption_key_files(downloader: &mut DashDownloader) -> Result<(), DashMpdError> {
+    for path in downloader.key_files.clone() {
+        let contents = fs::read_to_string(&path).await
+            .map_err(|e| DashMpdError::Io(e, format!("reading key file {}", path.display())))?;
+        let keys = parse_key_file(&contents)
+            .map_err(|e| DashMpdError::Decrypting(format!("{} in key file {}", e, path.display())))?;
+        if downloader.verbosity > 1 {
+            info!("Loaded {} decryption keys from {}", keys.len(), path.display());
+        }
+        downloader.decryption_keys.extend(keys);
+    }
+    for (path, document_key) in downloader.cpix_documents.clone() {
+        let xml = fs::read_to_string(&path).await
+            .map_err(|e| DashMpdError::Io(e, format!("reading CPIX document {}", path.display())))?;
+        let dk = match document_key {
+            Some(hex) => Some(parse_document_key(&hex)?),
+            None => None,
+        };
+        let keys = parse_cpix(&xml, dk.as_deref())?;
+        if downloader.verbosity > 1 {
+            info!("Loaded {} decryption keys from CPIX document {}", keys.len(), path.display());
+        }
+        downloader.decryption_keys.extend(keys);
+    }
+    Ok(())
+}
+
+// Send a W3C Clear Key license request for the KIDs `kids` to the license server at `laurl`, and
+// add the content keys from the license response to our decryption keys. No request is sent if we
+// already have keys for all the requested KIDs.
+async fn acquire_clearkey_license(
+    downloader: &mut DashDownloader,
+    laurl: &Url,
+    kids: &[String]) -> Result<(), DashMpdError>
+{
+    let wanted = kids_without_keys(downloader, kids);
+    if wanted.is_empty() {
+        return Ok(());
+    }
+    let body = license_request_body(&wanted)?;
+    if downloader.verbosity > 0 {
+        info!("  Requesting Clear Key license for KIDs {} from {laurl}", wanted.join(","));
+    }
+    let client = &downloader.http_client.clone().unwrap();
+    let send_request = || async {
+        let mut req = client.post(laurl.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(referer) = &downloader.referer {
+            req = req.header("Referer", referer);
+        }
+        if let Some(username) = &downloader.auth_username {
+            if let Some(password) = &downloader.auth_password {
+                req = req.basic_auth(username, Some(password));
+            }
+        }
+        if let Some(token) = &downloader.auth_bearer_token {
+            req = req.bearer_auth(token);
+        }
+        req.send().await?
+            .error_for_status()
+    };
+    let response = send_request
+        .retry(ExponentialBuilder::default())
+        .when(reqwest_error_transient_p)
+        .notify(notify_transient)
+        .await
+        .map_err(|e| network_error("requesting Clear Key license", &e))?;
+    let license = response.bytes().await
+        .map_err(|e| network_error("fetching Clear Key license", &e))?;
+    let keys = parse_license_response(&license)?;
+    if keys.is_empty() {
+        return Err(DashMpdError::Decrypting(String::from("Clear Key license contains no keys")));
+    }
+    for (kid, key) in keys {
+        if downloader.verbosity > 1 {
+            info!("  Obtained Clear Key key for KID {kid}");
+        }
+        downloader.decryption_keys.insert(kid, key);
+    }
+    Ok(())
+}
+
+// Extract the Widevine and PlayReady PSSH boxes from the start of an init segment.
+async fn extract_init_pssh(downloader: &DashDownloader, init_url: Url) -> Vec<Vec<u8>> {
     use bstr::ByteSlice;
     use hex_literal::hex;
 
@@ -1776,7 +3650,7 @@ async fn extract_init_pssh(downloader: &DashDownloader, init_url: Url) -> Option
                 let size = min((chunk.len()/1024+1) as u32, u32::MAX);
                 #[allow(clippy::redundant_pattern_matching)]
                 if let Err(_) = throttle_download_rate(downloader, size).await {
-                    return None;
+                    return Vec::new();
                 }
                 segment_first_bytes.append(&mut chunk.to_vec());
                 chunk_counter += 1;
@@ -1785,7 +3659,11 @@ async fn extract_init_pssh(downloader: &DashDownloader, init_url: Url) -> Option
                 }
             }
             let needle = b"pssh";
+            let mut found = Vec::new();
             for offset in segment_first_bytes.find_iter(needle) {
+                if offset < 4 {
+                    continue;
+                }
                 #[allow(clippy::needless_range_loop)]
                 for i in offset-4..offset+2 {
                     if let Some(b) = segment_first_bytes.get(i) {
@@ -1805,26 +3683,42 @@ async fn extract_init_pssh(downloader: &DashDownloader, init_url: Url) -> Option
                 if offset+24 > segment_first_bytes.len() {
                     continue;
                 }
-                // const PLAYREADY_SYSID: [u8; 16] = hex!("9a04f07998404286ab92e65be0885f95");
                 const WIDEVINE_SYSID: [u8; 16] = hex!("edef8ba979d64acea3c827dcd51d21ed");
                 if let Some(sysid) = segment_first_bytes.get((offset+8)..(offset+24)) {
-                    if !sysid.eq(&WIDEVINE_SYSID) {
+                    if !sysid.eq(&WIDEVINE_SYSID) && !sysid.eq(&PLAYREADY_SYSTEM_ID) {
                         continue;
                     }
                 }
-                if let Some(length) = segment_first_bytes.get(offset-1) {
+                // PlayReady PSSH boxes are typically larger than 255 octets, so we need the full
+                // 32-bit box size.
+                if let Some(size) = segment_first_bytes.get(offset-4..offset) {
                     let start = offset - 4;
-                    let end = start + *length as usize;
+                    let end = start + u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
                     if let Some(pssh) = &segment_first_bytes.get(start..end) {
-                        return Some(pssh.to_vec());
+                        found.push(pssh.to_vec());
                     }
                 }
             }
+            return found;
+        }
+    }
+    Vec::new()
+}
+
+// Diagnostics information on the PSSH boxes found in an init segment.
+fn init_pssh_diagnostics(pssh_boxes: &[Vec<u8>]) -> Vec<String> {
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+    let mut diagnostics = Vec::new();
+    for pssh_bytes in pssh_boxes {
+        diagnostics.push(format!("    PSSH (from init segment): {}", BASE64_STANDARD.encode(pssh_bytes)));
+        if let Ok(pro) = PlayReadyObject::from_pssh(pssh_bytes) {
+            diagnostics.push(format!("    {pro}"));
+        } else if let Ok(pssh) = pssh_box::from_bytes(pssh_bytes) {
+            diagnostics.push(format!("    {pssh}"));
         }
-        None
-    } else {
-        None
     }
+    diagnostics
 }
 
 
@@ -1844,7 +3738,7 @@ lazy_static! {
     };
 }
 
-fn resolve_url_template(template: &str, params: &HashMap<&str, String>) -> String {
+pub(crate) fn resolve_url_template(template: &str, params: &HashMap<&str, String>) -> String {
     let mut result = template.to_string();
     for (k, ident, rx) in URL_TEMPLATE_IDS.iter() {
         // first check for simple cases such as $Number$
@@ -1868,6 +3762,41 @@ fn resolve_url_template(template: &str, params: &HashMap<&str, String>) -> Strin
     result
 }
 
+// The (time, duration) of each segment described by a SegmentTimeline, in timescale units. A
+// negative @r means that the segment is repeated until the start of the next S element, or until
+// end if this is the last S element.
+pub(crate) fn timeline_segments(stl: &SegmentTimeline, end: Option<u64>) -> Vec<(u64, u64)> {
+    let mut segments = Vec::new();
+    let mut time = 0;
+    for (i, s) in stl.segments.iter().enumerate() {
+        if let Some(t) = s.t {
+            time = t;
+        }
+        if s.d == 0 {
+            continue;
+        }
+        let repeats = match s.r {
+            Some(r) if r < 0 => {
+                let until = stl.segments.get(i + 1).and_then(|next| next.t).or(end);
+                match until {
+                    Some(until) if until > time => (until - time).div_ceil(s.d) - 1,
+                    _ => {
+                        warn!("Can't determine the number of repeats of a SegmentTimeline entry");
+                        0
+                    },
+                }
+            },
+            Some(r) => r as u64,
+            None => 0,
+        };
+        for _ in 0..=repeats {
+            segments.push((time, s.d));
+            time += s.d;
+        }
+    }
+    segments
+}
+
 
 fn reqwest_error_transient_p(e: &reqwest::Error) -> bool {
     if e.is_timeout() {
@@ -1888,7 +3817,7 @@ fn notify_transient<E: std::fmt::Debug>(err: &E, dur: Duration) {
     warn!("Transient error after {dur:?}: {err:?}");
 }
 
-fn network_error(why: &str, e: &reqwest::Error) -> DashMpdError {
+pub(crate) fn network_error(why: &str, e: &reqwest::Error) -> DashMpdError {
     if e.is_timeout() {
         DashMpdError::NetworkTimeout(format!("{why}: {e:?}"))
     } else if e.is_connect() {
@@ -2421,17 +4350,176 @@ async fn do_segmentbase_indexrange(
 }
 
 
+// The exact size of a Representation, when the manifest exposes it via the byte ranges of a
+// SegmentList, or via the sidx box referenced by SegmentBase@indexRange.
+async fn exact_representation_size(
+    downloader: &DashDownloader,
+    adaptation: &AdaptationSet,
+    representation: &Representation,
+    base_url: &Url) -> Option<u64>
+{
+    if let Some(sl) = representation.SegmentList.as_ref().or(adaptation.SegmentList.as_ref()) {
+        if sl.segment_urls.is_empty() {
+            return None;
+        }
+        let mut size = 0;
+        if let Some(range) = sl.Initialization.as_ref().and_then(|i| i.range.as_ref()) {
+            let (s, e) = parse_range(range).ok()?;
+            size += e.saturating_sub(s) + 1;
+        }
+        for su in &sl.segment_urls {
+            let (s, e) = parse_range(su.mediaRange.as_ref()?).ok()?;
+            size += e.saturating_sub(s) + 1;
+        }
+        return Some(size);
+    }
+    if !downloader.use_index_range {
+        return None;
+    }
+    let sb = representation.SegmentBase.as_ref().or(adaptation.SegmentBase.as_ref())?;
+    let (s, e) = parse_range(sb.indexRange.as_ref()?).ok()?;
+    let mut url = base_url.clone();
+    if let Some(bu) = adaptation.BaseURL.first() {
+        url = merge_baseurls(&url, &bu.base).ok()?;
+    }
+    if let Some(bu) = representation.BaseURL.first() {
+        url = merge_baseurls(&url, &bu.base).ok()?;
+    }
+    let mut req = downloader.http_client.as_ref()?
+        .get(url)
+        .header(RANGE, format!("bytes={s}-{e}"))
+        .header("Referer", downloader.redirected_url.to_string());
+    if let Some(username) = &downloader.auth_username {
+        if let Some(password) = &downloader.auth_password {
+            req = req.basic_auth(username, Some(password));
+        }
+    }
+    if let Some(token) = &downloader.auth_bearer_token {
+        req = req.bearer_auth(token);
+    }
+    let idx = req.send().await.ok()?
+        .error_for_status().ok()?
+        .bytes().await.ok()?;
+    if idx.len() as u64 != e - s + 1 {
+        return None;
+    }
+    // The sidx references are contiguous and start immediately after the index.
+    let chunks = crate::sidx::from_isobmff_sidx(&idx, e+1).ok()?;
+    chunks.last().map(|c| c.end + 1)
+}
+
+// Estimate the size of each audio and video Representation in this Period, using exact sizes when
+// they are exposed by the manifest, and otherwise @bandwidth × the Period duration.
+async fn estimate_period_sizes(
+    downloader: &DashDownloader,
+    period: &Period,
+    base_url: &Url,
+    duration_secs: f64) -> HashMap<(usize, usize), u64>
+{
+    let mut sizes = HashMap::new();
+    for (ai, a) in period.adaptations.iter().enumerate() {
+        if !is_audio_adaptation(&a) && !is_video_adaptation(&a) {
+            continue;
+        }
+        for (ri, r) in a.representations.iter().enumerate() {
+            let exact = exact_representation_size(downloader, a, r, base_url).await;
+            let estimate = exact.or_else(|| {
+                r.bandwidth.map(|bw| (bw as f64 * duration_secs / 8.0).round() as u64)
+            });
+            if let Some(size) = estimate {
+                sizes.insert((ai, ri), size);
+            }
+        }
+    }
+    sizes
+}
+
+// Is this Period to be skipped because its duration is less than the requested minimum?
+fn period_too_short(downloader: &DashDownloader, period: &Period) -> bool {
+    downloader.minimum_period_duration
+        .is_some_and(|min| period.duration.is_some_and(|d| d < min))
+}
+
+// The duration of the Period at index idx in the manifest, which may be implicit (determined by the
+// start of the following Period, or by the presentation duration if there is a single Period).
+fn period_duration_secs(downloader: &DashDownloader, mpd: &MPD, idx: usize) -> Option<f64> {
+    if let Some(s) = downloader.force_duration {
+        return Some(s);
+    }
+    let period = mpd.periods.get(idx)?;
+    let explicit = period.duration.or_else(|| {
+        let next = mpd.periods.get(idx + 1)?;
+        next.start?.checked_sub(period.start?)
+    });
+    match explicit {
+        Some(d) => Some(d.as_secs_f64()),
+        None if mpd.periods.len() == 1 => mpd.mediaPresentationDuration.map(|d| d.as_secs_f64()),
+        None => None,
+    }
+}
+
+// Prepare the download of the audio Representations selected in this Period by
+// select_audio_representations().
 #[tracing::instrument(level="trace", skip_all)]
 async fn do_period_audio(
     downloader: &DashDownloader,
     mpd: &MPD,
     period: &Period,
     period_counter: u8,
-    base_url: Url
+    base_url: Url,
+    selected: &[Candidate<'_>]
+) -> Result<PeriodOutputs, DashMpdError>
+{
+    let mut outputs = PeriodOutputs::default();
+    // Note that some streams don't separate out audio and video streams, so there may be no audio
+    // AdaptationSet in this Period.
+    if !downloader.fetch_all_audio_tracks && !selected.is_empty() {
+        if let Some(p) = select_preselection(period, downloader) {
+            if downloader.preselection_tag.as_ref().is_some_and(|t| !p.tag.eq(t)) {
+                warn!("  No Preselection with the requested tag in period #{period_counter}");
+            }
+            let unknown = String::from("unk");
+            let lang = p.lang.as_ref().unwrap_or(&unknown);
+            let label = p.Label.first()
+                .map_or_else(|| String::from(""), |l| format!(" label={}", l.content.trim()));
+            outputs.diagnostics.push(format!("  Preselection selected: tag={} lang={lang}{label} components={}",
+                                             p.tag, p.components().join(",")));
+        }
+    }
+    for (track, (audio_adaptation, audio_repr)) in selected.iter().take(u8::MAX.into()).enumerate() {
+        let track_outputs = do_audio_representation(
+            downloader, mpd, period, period_counter, base_url.clone(),
+            audio_adaptation, audio_repr).await?;
+        outputs.fragments.extend(track_outputs.fragments.into_iter()
+            .map(|mut f| { f.track = track as u8; f }));
+        outputs.diagnostics.extend(track_outputs.diagnostics);
+        outputs.selected_audio_languages.extend(track_outputs.selected_audio_languages);
+        outputs.clearkey_licenses.extend(track_outputs.clearkey_licenses);
+        for kid in track_outputs.kids {
+            if !outputs.kids.contains(&kid) {
+                outputs.kids.push(kid);
+            }
+        }
+    }
+    Ok(outputs)
+}
+
+// Prepare the download of the audio Representation audio_repr, a child of audio_adaptation.
+#[tracing::instrument(level="trace", skip_all)]
+async fn do_audio_representation(
+    downloader: &DashDownloader,
+    mpd: &MPD,
+    period: &Period,
+    period_counter: u8,
+    base_url: Url,
+    audio_adaptation: &AdaptationSet,
+    audio_repr: &Representation,
 ) -> Result<PeriodOutputs, DashMpdError>
 {
     let mut fragments = Vec::new();
     let mut diagnostics = Vec::new();
+    let mut clearkey_licenses = Vec::new();
+    let mut kids = Vec::new();
     let mut opt_init: Option<String> = None;
     let mut opt_media: Option<String> = None;
     let mut opt_duration: Option<f64> = None;
@@ -2470,427 +4558,521 @@ async fn do_period_audio(
         }
     }
     let mut selected_audio_language = "unk";
-    // Handle the AdaptationSet with audio content. Note that some streams don't separate out
-    // audio and video streams, so this might be None.
-    let audio_adaptations: Vec<&AdaptationSet> = period.adaptations.iter()
-        .filter(is_audio_adaptation)
-        .collect();
-    let representations: Vec<&Representation> = select_preferred_adaptations(audio_adaptations, downloader)
-        .iter()
-        .flat_map(|a| a.representations.iter())
-        .collect();
-    if let Some(audio_repr) = select_preferred_representation(&representations, downloader) {
-        // Find the AdaptationSet that is the parent of the selected Representation. This may be
-        // needed for certain Representation attributes whose value can be located higher in the XML
-        // tree.
-        let audio_adaptation = period.adaptations.iter()
-            .find(|a| a.representations.iter().any(|r| r.eq(audio_repr)))
-            .unwrap();
-        if let Some(lang) = audio_repr.lang.as_ref().or(audio_adaptation.lang.as_ref()) {
-            selected_audio_language = lang;
-        }
-        // The AdaptationSet may have a BaseURL (e.g. the test BBC streams). We use a local variable
-        // to make sure we don't "corrupt" the base_url for the video segments.
-        let mut base_url = base_url.clone();
-        if let Some(bu) = &audio_adaptation.BaseURL.first() {
-            base_url = merge_baseurls(&base_url, &bu.base)?;
-        }
-        if let Some(bu) = audio_repr.BaseURL.first() {
-            base_url = merge_baseurls(&base_url, &bu.base)?;
-        }
-        if downloader.verbosity > 0 {
-            let bw = if let Some(bw) = audio_repr.bandwidth {
-                format!("bw={} Kbps ", bw / 1024)
-            } else {
-                String::from("")
-            };
-            let unknown = String::from("?");
-            let lang = audio_repr.lang.as_ref()
-                .unwrap_or(audio_adaptation.lang.as_ref()
-                           .unwrap_or(&unknown));
-            let codec = audio_repr.codecs.as_ref()
-                .unwrap_or(audio_adaptation.codecs.as_ref()
-                           .unwrap_or(&unknown));
-            let maybe_id = if let Some(rid) = &audio_repr.id {
-                format!(" (id={rid})")
-            } else {
-                String::from("")
-            };
-            diagnostics.push(format!("  Audio stream selected: {bw}lang={lang} codec={codec}{maybe_id}"));
-            // Check for ContentProtection on the selected Representation/Adaptation
-            for cp in audio_repr.ContentProtection.iter()
-                .chain(audio_adaptation.ContentProtection.iter())
-            {
-                diagnostics.push(format!("  ContentProtection: {}", content_protection_type(cp)));
-                if let Some(kid) = &cp.default_KID {
-                    diagnostics.push(format!("    KID: {}", kid.replace('-', "")));
-                }
-                for pssh_element in &cp.cenc_pssh {
-                    if let Some(pssh_b64) = &pssh_element.content {
-                        diagnostics.push(format!("    PSSH (from manifest): {pssh_b64}"));
-                        if let Ok(pssh) = pssh_box::from_base64(pssh_b64) {
-                            diagnostics.push(format!("    {pssh}"));
-                        }
+    if let Some(lang) = audio_repr.lang.as_ref().or(audio_adaptation.lang.as_ref()) {
+        selected_audio_language = lang;
+    }
+    // The AdaptationSet may have a BaseURL (e.g. the test BBC streams). We use a local variable
+    // to make sure we don't "corrupt" the base_url for the video segments.
+    let mut base_url = base_url.clone();
+    if let Some(bu) = &audio_adaptation.BaseURL.first() {
+        base_url = merge_baseurls(&base_url, &bu.base)?;
+    }
+    if let Some(bu) = audio_repr.BaseURL.first() {
+        base_url = merge_baseurls(&base_url, &bu.base)?;
+    }
+    if downloader.verbosity > 0 {
+        let bw = if let Some(bw) = audio_repr.bandwidth {
+            format!("bw={} Kbps ", bw / 1024)
+        } else {
+            String::from("")
+        };
+        let unknown = String::from("?");
+        let lang = audio_repr.lang.as_ref()
+            .unwrap_or(audio_adaptation.lang.as_ref()
+                       .unwrap_or(&unknown));
+        let codec = audio_repr.codecs.as_ref()
+            .unwrap_or(audio_adaptation.codecs.as_ref()
+                       .unwrap_or(&unknown));
+        let maybe_id = if let Some(rid) = &audio_repr.id {
+            format!(" (id={rid})")
+        } else {
+            String::from("")
+        };
+        diagnostics.push(format!("  Audio stream selected: {bw}lang={lang} codec={codec}{maybe_id}"));
+        // Check for ContentProtection on the selected Representation/Adaptation
+        for cp in audio_repr.ContentProtection.iter()
+            .chain(audio_adaptation.ContentProtection.iter())
+        {
+            diagnostics.push(format!("  ContentProtection: {}", content_protection_type(cp)));
+            if let Some(kid) = &cp.default_KID {
+                diagnostics.push(format!("    KID: {}", kid.replace('-', "")));
+            }
+            for pssh_element in &cp.cenc_pssh {
+                if let Some(pssh_b64) = &pssh_element.content {
+                    diagnostics.push(format!("    PSSH (from manifest): {pssh_b64}"));
+                    if let Ok(pssh) = pssh_box::from_base64(pssh_b64) {
+                        diagnostics.push(format!("    {pssh}"));
                     }
                 }
             }
+            if let Some(pro_b64) = cp.msprpro.as_ref().and_then(|p| p.content.as_ref()) {
+                match PlayReadyObject::from_base64(pro_b64) {
+                    Ok(pro) => diagnostics.push(format!("    PlayReady Object (from manifest): {pro}")),
+                    Err(e) => diagnostics.push(format!("    Unparseable mspr:pro: {e}")),
+                }
+            }
         }
-        // SegmentTemplate as a direct child of an Adaptation node. This can specify some common
-        // attribute values (media, timescale, duration, startNumber) for child SegmentTemplate
-        // nodes in an enclosed Representation node. Don't download media segments here, only
-        // download for SegmentTemplate nodes that are children of a Representation node.
-        if let Some(st) = &audio_adaptation.SegmentTemplate {
-            if let Some(i) = &st.initialization {
-                opt_init = Some(i.clone());
+    }
+    for cp in audio_repr.ContentProtection.iter()
+        .chain(audio_adaptation.ContentProtection.iter())
+    {
+        if let Some(kid) = cp.default_KID.as_ref().and_then(|k| normalize_kid(k)) {
+            if !kids.contains(&kid) {
+                kids.push(kid);
             }
-            if let Some(m) = &st.media {
-                opt_media = Some(m.clone());
+        }
+    }
+    if downloader.clearkey_license_acquisition {
+        if let Some((laurl, kids)) = clearkey_license_request(
+            audio_repr.ContentProtection.iter()
+                .chain(audio_adaptation.ContentProtection.iter()))
+        {
+            clearkey_licenses.push((merge_baseurls(&base_url, &laurl)?, kids));
+        }
+    }
+    // SegmentTemplate as a direct child of an Adaptation node. This can specify some common
+    // attribute values (media, timescale, duration, startNumber) for child SegmentTemplate
+    // nodes in an enclosed Representation node. Don't download media segments here, only
+    // download for SegmentTemplate nodes that are children of a Representation node.
+    if let Some(st) = &audio_adaptation.SegmentTemplate {
+        if let Some(i) = &st.initialization {
+            opt_init = Some(i.clone());
+        }
+        if let Some(m) = &st.media {
+            opt_media = Some(m.clone());
+        }
+        if let Some(d) = st.duration {
+            opt_duration = Some(d);
+        }
+        if let Some(ts) = st.timescale {
+            timescale = ts;
+        }
+        if let Some(s) = st.startNumber {
+            start_number = s;
+        }
+    }
+    let mut dict = HashMap::new();
+    if let Some(rid) = &audio_repr.id {
+        dict.insert("RepresentationID", rid.clone());
+    }
+    if let Some(b) = &audio_repr.bandwidth {
+        dict.insert("Bandwidth", b.to_string());
+    }
+    // Now the 6 possible addressing modes: (1) SegmentList,
+    // (2) SegmentTemplate+SegmentTimeline, (3) SegmentTemplate@duration,
+    // (4) SegmentTemplate@index, (5) SegmentBase@indexRange, (6) plain BaseURL
+    
+    // Though SegmentBase and SegmentList addressing modes are supposed to be
+    // mutually exclusive, some manifests in the wild use both. So we try to work
+    // around the brokenness.
+    // Example: http://ftp.itec.aau.at/datasets/mmsys12/ElephantsDream/MPDs/ElephantsDreamNonSeg_6s_isoffmain_DIS_23009_1_v_2_1c2_2011_08_30.mpd
+    if let Some(sl) = &audio_adaptation.SegmentList {
+        // (1) AdaptationSet>SegmentList addressing mode (can be used in conjunction
+        // with Representation>SegmentList addressing mode)
+        if downloader.verbosity > 1 {
+            info!("  Using AdaptationSet>SegmentList addressing mode for audio representation");
+        }
+        let mut start_byte: Option<u64> = None;
+        let mut end_byte: Option<u64> = None;
+        if let Some(init) = &sl.Initialization {
+            if let Some(range) = &init.range {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
             }
-            if let Some(d) = st.duration {
-                opt_duration = Some(d);
+            if let Some(su) = &init.sourceURL {
+                let path = resolve_url_template(su, &dict);
+                let init_url = merge_baseurls(&base_url, &path)?;
+                let mf = MediaFragmentBuilder::new(period_counter, init_url)
+                    .with_range(start_byte, end_byte)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
+            } else {
+                let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                    .with_range(start_byte, end_byte)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
             }
-            if let Some(ts) = st.timescale {
-                timescale = ts;
+        }
+        for su in &sl.segment_urls {
+            start_byte = None;
+            end_byte = None;
+            // we are ignoring SegmentURL@indexRange
+            if let Some(range) = &su.mediaRange {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
             }
-            if let Some(s) = st.startNumber {
-                start_number = s;
+            if let Some(m) = &su.media {
+                let u = merge_baseurls(&base_url, m)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
+            } else if let Some(bu) = audio_adaptation.BaseURL.first() {
+                let u = merge_baseurls(&base_url, &bu.base)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
             }
         }
-        let mut dict = HashMap::new();
-        if let Some(rid) = &audio_repr.id {
-            dict.insert("RepresentationID", rid.clone());
-        }
-        if let Some(b) = &audio_repr.bandwidth {
-            dict.insert("Bandwidth", b.to_string());
+    }
+    if let Some(sl) = &audio_repr.SegmentList {
+        // (1) Representation>SegmentList addressing mode
+        if downloader.verbosity > 1 {
+            info!("  Using Representation>SegmentList addressing mode for audio representation");
         }
-        // Now the 6 possible addressing modes: (1) SegmentList,
-        // (2) SegmentTemplate+SegmentTimeline, (3) SegmentTemplate@duration,
-        // (4) SegmentTemplate@index, (5) SegmentBase@indexRange, (6) plain BaseURL
-        
-        // Though SegmentBase and SegmentList addressing modes are supposed to be
-        // mutually exclusive, some manifests in the wild use both. So we try to work
-        // around the brokenness.
-        // Example: http://ftp.itec.aau.at/datasets/mmsys12/ElephantsDream/MPDs/ElephantsDreamNonSeg_6s_isoffmain_DIS_23009_1_v_2_1c2_2011_08_30.mpd
-        if let Some(sl) = &audio_adaptation.SegmentList {
-            // (1) AdaptationSet>SegmentList addressing mode (can be used in conjunction
-            // with Representation>SegmentList addressing mode)
-            if downloader.verbosity > 1 {
-                info!("  Using AdaptationSet>SegmentList addressing mode for audio representation");
-            }
-            let mut start_byte: Option<u64> = None;
-            let mut end_byte: Option<u64> = None;
-            if let Some(init) = &sl.Initialization {
-                if let Some(range) = &init.range {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
-                }
-                if let Some(su) = &init.sourceURL {
-                    let path = resolve_url_template(su, &dict);
-                    let init_url = merge_baseurls(&base_url, &path)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, init_url)
-                        .with_range(start_byte, end_byte)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
-                } else {
-                    let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
-                        .with_range(start_byte, end_byte)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
-                }
+        let mut start_byte: Option<u64> = None;
+        let mut end_byte: Option<u64> = None;
+        if let Some(init) = &sl.Initialization {
+            if let Some(range) = &init.range {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
             }
-            for su in &sl.segment_urls {
-                start_byte = None;
-                end_byte = None;
-                // we are ignoring SegmentURL@indexRange
-                if let Some(range) = &su.mediaRange {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
-                }
-                if let Some(m) = &su.media {
-                    let u = merge_baseurls(&base_url, m)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .build();
-                    fragments.push(mf);
-                } else if let Some(bu) = audio_adaptation.BaseURL.first() {
-                    let u = merge_baseurls(&base_url, &bu.base)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .build();
-                    fragments.push(mf);
-                }
+            if let Some(su) = &init.sourceURL {
+                let path = resolve_url_template(su, &dict);
+                let init_url = merge_baseurls(&base_url, &path)?;
+                let mf = MediaFragmentBuilder::new(period_counter, init_url)
+                    .with_range(start_byte, end_byte)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
+            } else {
+                let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                    .with_range(start_byte, end_byte)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
             }
         }
-        if let Some(sl) = &audio_repr.SegmentList {
-            // (1) Representation>SegmentList addressing mode
-            if downloader.verbosity > 1 {
-                info!("  Using Representation>SegmentList addressing mode for audio representation");
-            }
-            let mut start_byte: Option<u64> = None;
-            let mut end_byte: Option<u64> = None;
-            if let Some(init) = &sl.Initialization {
-                if let Some(range) = &init.range {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
-                }
-                if let Some(su) = &init.sourceURL {
-                    let path = resolve_url_template(su, &dict);
-                    let init_url = merge_baseurls(&base_url, &path)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, init_url)
-                        .with_range(start_byte, end_byte)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
-                } else {
-                    let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
-                        .with_range(start_byte, end_byte)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
-                }
+        for su in &sl.segment_urls {
+            start_byte = None;
+            end_byte = None;
+            // we are ignoring SegmentURL@indexRange
+            if let Some(range) = &su.mediaRange {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
             }
-            for su in &sl.segment_urls {
-                start_byte = None;
-                end_byte = None;
-                // we are ignoring SegmentURL@indexRange
-                if let Some(range) = &su.mediaRange {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
-                }
-                if let Some(m) = &su.media {
-                    let u = merge_baseurls(&base_url, m)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .build();
-                    fragments.push(mf);
-                } else if let Some(bu) = audio_repr.BaseURL.first() {
-                    let u = merge_baseurls(&base_url, &bu.base)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .build();
-                    fragments.push(mf);
-                }
+            if let Some(m) = &su.media {
+                let u = merge_baseurls(&base_url, m)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
+            } else if let Some(bu) = audio_repr.BaseURL.first() {
+                let u = merge_baseurls(&base_url, &bu.base)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
             }
-        } else if audio_repr.SegmentTemplate.is_some() ||
-            audio_adaptation.SegmentTemplate.is_some()
+        }
+    } else if audio_repr.SegmentTemplate.is_some() ||
+        audio_adaptation.SegmentTemplate.is_some()
+    {
+        // Here we are either looking at a Representation.SegmentTemplate, or a
+        // higher-level AdaptationSet.SegmentTemplate
+        let st;
+        if let Some(it) = &audio_repr.SegmentTemplate {
+            st = it;
+        } else if let Some(it) = &audio_adaptation.SegmentTemplate {
+            st = it;
+        } else {
+            panic!("unreachable");
+        }
+        if let Some(i) = &st.initialization {
+            opt_init = Some(i.clone());
+        }
+        if let Some(m) = &st.media {
+            opt_media = Some(m.clone());
+        }
+        if let Some(ts) = st.timescale {
+            timescale = ts;
+        }
+        if let Some(sn) = st.startNumber {
+            start_number = sn;
+        }
+        if let Some(stl) = &audio_repr.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone())
+            .or(audio_adaptation.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone()))
         {
-            // Here we are either looking at a Representation.SegmentTemplate, or a
-            // higher-level AdaptationSet.SegmentTemplate
-            let st;
-            if let Some(it) = &audio_repr.SegmentTemplate {
-                st = it;
-            } else if let Some(it) = &audio_adaptation.SegmentTemplate {
-                st = it;
-            } else {
-                panic!("unreachable");
-            }
-            if let Some(i) = &st.initialization {
-                opt_init = Some(i.clone());
-            }
-            if let Some(m) = &st.media {
-                opt_media = Some(m.clone());
-            }
-            if let Some(ts) = st.timescale {
-                timescale = ts;
+            // (2) SegmentTemplate with SegmentTimeline addressing mode (also called
+            // "explicit addressing" in certain DASH-IF documents)
+            if downloader.verbosity > 1 {
+                info!("  Using SegmentTemplate+SegmentTimeline addressing mode for audio representation");
             }
-            if let Some(sn) = st.startNumber {
-                start_number = sn;
+            if let Some(init) = opt_init {
+                let path = resolve_url_template(&init, &dict);
+                let u = merge_baseurls(&base_url, &path)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
             }
-            if let Some(stl) = &audio_repr.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone())
-                .or(audio_adaptation.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone()))
-            {
-                // (2) SegmentTemplate with SegmentTimeline addressing mode (also called
-                // "explicit addressing" in certain DASH-IF documents)
-                if downloader.verbosity > 1 {
-                    info!("  Using SegmentTemplate+SegmentTimeline addressing mode for audio representation");
+            let mut elapsed_seconds = 0.0;
+            if let Some(media) = opt_media {
+                let audio_path = resolve_url_template(&media, &dict);
+                let mut segment_time = 0;
+                let mut segment_duration;
+                let mut number = start_number;
+                let mut target_duration = period_duration_secs;
+                if let Some(target) = downloader.force_duration {
+                    if target > period_duration_secs {
+                        warn!("  Requested forced duration exceeds available content");
+                    } else {
+                        target_duration = target;
+                    }
                 }
-                if let Some(init) = opt_init {
-                    let path = resolve_url_template(&init, &dict);
+                'segment_loop: for s in &stl.segments {
+                    if let Some(t) = s.t {
+                        segment_time = t;
+                    }
+                    segment_duration = s.d;
+                    // the URLTemplate may be based on $Time$, or on $Number$
+                    let dict = HashMap::from([("Time", segment_time.to_string()),
+                                              ("Number", number.to_string())]);
+                    let path = resolve_url_template(&audio_path, &dict);
                     let u = merge_baseurls(&base_url, &path)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
-                }
-                let mut elapsed_seconds = 0.0;
-                if let Some(media) = opt_media {
-                    let audio_path = resolve_url_template(&media, &dict);
-                    let mut segment_time = 0;
-                    let mut segment_duration;
-                    let mut number = start_number;
-                    let mut target_duration = period_duration_secs;
-                    if let Some(target) = downloader.force_duration {
-                        if target > period_duration_secs {
-                            warn!("  Requested forced duration exceeds available content");
-                        } else {
-                            target_duration = target;
-                        }
+                    fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
+                    number += 1;
+                    elapsed_seconds += segment_duration as f64 / timescale as f64;
+                    if downloader.force_duration.is_some() &&
+                        target_duration > 0.0 &&
+                        elapsed_seconds > target_duration {
+                        break 'segment_loop;
                     }
-                    'segment_loop: for s in &stl.segments {
-                        if let Some(t) = s.t {
-                            segment_time = t;
-                        }
-                        segment_duration = s.d;
-                        // the URLTemplate may be based on $Time$, or on $Number$
-                        let dict = HashMap::from([("Time", segment_time.to_string()),
-                                                  ("Number", number.to_string())]);
-                        let path = resolve_url_template(&audio_path, &dict);
-                        let u = merge_baseurls(&base_url, &path)?;
-                        fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
-                        number += 1;
-                        elapsed_seconds += segment_duration as f64 / timescale as f64;
-                        if downloader.force_duration.is_some() &&
-                            target_duration > 0.0 &&
-                            elapsed_seconds > target_duration {
-                            break 'segment_loop;
-                        }
-                        if let Some(r) = s.r {
-                            let mut count = 0i64;
-                            loop {
-                                count += 1;
-                                // Exit from the loop after @r iterations (if @r is positive). A
-                                // negative value of the @r attribute indicates that the duration
-                                // indicated in @d attribute repeats until the start of the next S
-                                // element, the end of the Period or until the next MPD update.
-                                if r >= 0 && count > r {
+                    if let Some(r) = s.r {
+                        let mut count = 0i64;
+                        loop {
+                            count += 1;
+                            // Exit from the loop after @r iterations (if @r is positive). A
+                            // negative value of the @r attribute indicates that the duration
+                            // indicated in @d attribute repeats until the start of the next S
+                            // element, the end of the Period or until the next MPD update.
+                            if r >= 0 && count > r {
+                                break;
+                            }
+                            if downloader.force_duration.is_some() &&
+                                target_duration > 0.0 &&
+                                elapsed_seconds > target_duration {
+                                break 'segment_loop;
+                            }
+                            if let Some(end_number) = st.endNumber {
+                                if count as u64 > end_number {
                                     break;
                                 }
-                                if downloader.force_duration.is_some() &&
-                                    target_duration > 0.0 &&
-                                    elapsed_seconds > target_duration {
-                                    break 'segment_loop;
-                                }
-                                if let Some(end_number) = st.endNumber {
-                                    if count as u64 > end_number {
-                                        break;
-                                    }
-                                }
-                                segment_time += segment_duration;
-                                elapsed_seconds += segment_duration as f64 / timescale as f64;
-                                let dict = HashMap::from([("Time", segment_time.to_string()),
-                                                          ("Number", number.to_string())]);
-                                let path = resolve_url_template(&audio_path, &dict);
-                                let u = merge_baseurls(&base_url, &path)?;
-                                fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
-                                number += 1;
                             }
+                            segment_time += segment_duration;
+                            elapsed_seconds += segment_duration as f64 / timescale as f64;
+                            let dict = HashMap::from([("Time", segment_time.to_string()),
+                                                      ("Number", number.to_string())]);
+                            let path = resolve_url_template(&audio_path, &dict);
+                            let u = merge_baseurls(&base_url, &path)?;
+                            fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
+                            number += 1;
                         }
-                        segment_time += segment_duration;
                     }
-                } else {
-                    return Err(DashMpdError::UnhandledMediaStream(
-                        "SegmentTimeline without a media attribute".to_string()));
+                    segment_time += segment_duration;
                 }
-            } else { // no SegmentTimeline element
-                // (3) SegmentTemplate@duration addressing mode or (4) SegmentTemplate@index
-                // addressing mode (also called "simple addressing" in certain DASH-IF
-                // documents)
-                if downloader.verbosity > 1 {
-                    info!("  Using SegmentTemplate addressing mode for audio representation");
-                }
-                let mut total_number = 0i64;
-                if let Some(init) = opt_init {
-                    let path = resolve_url_template(&init, &dict);
-                    let u = merge_baseurls(&base_url, &path)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
+            } else {
+                return Err(DashMpdError::UnhandledMediaStream(
+                    "SegmentTimeline without a media attribute".to_string()));
+            }
+        } else { // no SegmentTimeline element
+            // (3) SegmentTemplate@duration addressing mode or (4) SegmentTemplate@index
+            // addressing mode (also called "simple addressing" in certain DASH-IF
+            // documents)
+            if downloader.verbosity > 1 {
+                info!("  Using SegmentTemplate addressing mode for audio representation");
+            }
+            let mut total_number = 0i64;
+            if let Some(init) = opt_init {
+                let path = resolve_url_template(&init, &dict);
+                let u = merge_baseurls(&base_url, &path)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
+            }
+            if let Some(media) = opt_media {
+                let audio_path = resolve_url_template(&media, &dict);
+                let timescale = st.timescale.unwrap_or(timescale);
+                let mut segment_duration: f64 = -1.0;
+                if let Some(d) = opt_duration {
+                    // it was set on the Period.SegmentTemplate node
+                    segment_duration = d;
                 }
-                if let Some(media) = opt_media {
-                    let audio_path = resolve_url_template(&media, &dict);
-                    let timescale = st.timescale.unwrap_or(timescale);
-                    let mut segment_duration: f64 = -1.0;
-                    if let Some(d) = opt_duration {
-                        // it was set on the Period.SegmentTemplate node
-                        segment_duration = d;
-                    }
-                    if let Some(std) = st.duration {
-                        if timescale == 0 {
-                            return Err(DashMpdError::UnhandledMediaStream(
-                                "SegmentTemplate@duration attribute cannot be zero".to_string()));
-                        }
-                        segment_duration = std / timescale as f64;
-                    }
-                    if segment_duration < 0.0 {
+                if let Some(std) = st.duration {
+                    if timescale == 0 {
                         return Err(DashMpdError::UnhandledMediaStream(
-                            "Audio representation is missing SegmentTemplate@duration attribute".to_string()));
-                    }
-                    total_number += (period_duration_secs / segment_duration).round() as i64;
-                    let mut number = start_number;
-                    // For dynamic MPDs the latest available segment is numbered
-                    //    LSN = floor((now - (availabilityStartTime+PST))/segmentDuration + startNumber - 1)
-                    if mpd_is_dynamic(mpd) {
-                        if let Some(start_time) = mpd.availabilityStartTime {
-                            let elapsed = Utc::now().signed_duration_since(start_time).as_seconds_f64() / segment_duration;
-                            number = (elapsed + number as f64 - 1f64).floor() as u64;
-                        } else {
-                            return Err(DashMpdError::UnhandledMediaStream(
-                                "dynamic manifest is missing @availabilityStartTime".to_string()));
-                        }
+                            "SegmentTemplate@duration attribute cannot be zero".to_string()));
                     }
-                    if let Some(end_number) = st.endNumber {
-                        total_number = end_number as i64;
-                    }
-                    for _ in 1..=total_number {
-                        let dict = HashMap::from([("Number", number.to_string())]);
-                        let path = resolve_url_template(&audio_path, &dict);
-                        let u = merge_baseurls(&base_url, &path)?;
-                        fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
-                        number += 1;
+                    segment_duration = std / timescale as f64;
+                }
+                if segment_duration < 0.0 {
+                    return Err(DashMpdError::UnhandledMediaStream(
+                        "Audio representation is missing SegmentTemplate@duration attribute".to_string()));
+                }
+                total_number += (period_duration_secs / segment_duration).round() as i64;
+                let mut number = start_number;
+                // For dynamic MPDs the latest available segment is numbered
+                //    LSN = floor((now - (availabilityStartTime+PST))/segmentDuration + startNumber - 1)
+                if mpd_is_dynamic(mpd) {
+                    if let Some(start_time) = mpd.availabilityStartTime {
+                        let elapsed = Utc::now().signed_duration_since(start_time).as_seconds_f64() / segment_duration;
+                        number = (elapsed + number as f64 - 1f64).floor() as u64;
+                    } else {
+                        return Err(DashMpdError::UnhandledMediaStream(
+                            "dynamic manifest is missing @availabilityStartTime".to_string()));
                     }
                 }
-            }
-        } else if let Some(sb) = &audio_repr.SegmentBase {
-            // (5) SegmentBase@indexRange addressing mode
-            if downloader.verbosity > 1 {
-                info!("  Using SegmentBase@indexRange addressing mode for audio representation");
-            }
-            let mf = do_segmentbase_indexrange(downloader, period_counter, base_url, sb, &dict).await?;
-            fragments.extend(mf);
-        } else if fragments.is_empty() {
-            if let Some(bu) = audio_repr.BaseURL.first() {
-                // (6) plain BaseURL addressing mode
-                if downloader.verbosity > 1 {
-                    info!("  Using BaseURL addressing mode for audio representation");
+                if let Some(end_number) = st.endNumber {
+                    total_number = end_number as i64;
+                }
+                for _ in 1..=total_number {
+                    let dict = HashMap::from([("Number", number.to_string())]);
+                    let path = resolve_url_template(&audio_path, &dict);
+                    let u = merge_baseurls(&base_url, &path)?;
+                    fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
+                    number += 1;
                 }
-                let u = merge_baseurls(&base_url, &bu.base)?;
-                fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
             }
         }
-        if fragments.is_empty() {
-            return Err(DashMpdError::UnhandledMediaStream(
-                "no usable addressing mode identified for audio representation".to_string()));
+    } else if let Some(sb) = &audio_repr.SegmentBase {
+        // (5) SegmentBase@indexRange addressing mode
+        if downloader.verbosity > 1 {
+            info!("  Using SegmentBase@indexRange addressing mode for audio representation");
+        }
+        let mf = do_segmentbase_indexrange(downloader, period_counter, base_url, sb, &dict).await?;
+        fragments.extend(mf);
+    } else if fragments.is_empty() {
+        if let Some(bu) = audio_repr.BaseURL.first() {
+            // (6) plain BaseURL addressing mode
+            if downloader.verbosity > 1 {
+                info!("  Using BaseURL addressing mode for audio representation");
+            }
+            let u = merge_baseurls(&base_url, &bu.base)?;
+            fragments.push(MediaFragmentBuilder::new(period_counter, u).build());
         }
     }
+    if fragments.is_empty() {
+        return Err(DashMpdError::UnhandledMediaStream(
+            "no usable addressing mode identified for audio representation".to_string()));
+    }
     Ok(PeriodOutputs {
         fragments,
         diagnostics,
-        subtitle_formats: Vec::new(),
-        selected_audio_language: String::from(selected_audio_language),
-        selected_subtitle_language: String::from("")
-            
+        selected_audio_languages: vec![String::from(selected_audio_language)],
+        selected_subtitles: Vec::new(),
+        clearkey_licenses,
+        kids,
     })
 }
 
 
+// Select the video Representation to download from this Period, alongside its parent
+// AdaptationSet.
+fn select_video_representation<'a>(
+    period: &'a Period,
+    downloader: &DashDownloader,
+    ps: &PeriodSelection) -> Result<Option<(&'a AdaptationSet, &'a Representation)>, DashMpdError>
+{
+    // A manifest may contain multiple AdaptationSets with video content (in particular, when
+    // different codecs are offered). Each AdaptationSet often contains multiple video
+    // Representations with different bandwidths, video resolutions and codecs. We select the
+    // Representation to download by ranking them according to the following user-specified
+    // preferences:
+    //
+    //   - the excluded dynamic ranges
+    //   - the ceilings on video height, frame rate and bandwidth, the total bandwidth budget and
+    //     the size budget
+    //   - a substring of the video @id attribute
+    //   - the dynamic range preference ordering
+    //   - the preferred width
+    //   - the preferred height
+    //   - the video codec preference ordering
+    //   - continuity with the video stream selected in the previous Period
+    //   - the quality preference (defaulting to the lowest quality available)
+    //
+    // The preferences are applied in the order shown in the list above.
+    //
+    // If these preferences have not been specified, they have no filtering effect, except for the
+    // quality preference which defaults to preferring the lowest quality and smallest file size.
+    let candidates = video_candidates(period, downloader)?;
+    let candidates = filter_ceiling(
+        candidates, video_bandwidth_budget(period, downloader, ps)?, candidate_bandwidth,
+        "video bandwidth within budget", downloader)?;
+    let candidates = filter_ceiling(
+        candidates, video_size_budget(period, downloader, ps)?, |c| candidate_size(c, period, ps),
+        "estimated video size within budget", downloader)?;
+    let representations: Vec<&Representation> = candidates.iter()
+        .map(|(_, r)| *r)
+        .collect();
+    trace!("After ceiling filters we have {} Representations", representations.len());
+    let representations = representation_filter_video_id(representations, downloader);
+    trace!("After video_id filter we have {} Representations", representations.len());
+    let representations = representation_filter_video_dynamic_range(representations, &candidates, downloader);
+    trace!("After dynamic range filter we have {} Representations", representations.len());
+    let representations = representation_filter_video_width(representations, downloader);
+    trace!("After width filter we have {} Representations", representations.len());
+    let representations = representation_filter_video_height(representations, downloader);
+    trace!("After height filter we have {} Representations", representations.len());
+    let representations = representation_filter_video_codec(representations, downloader);
+    trace!("After video codec filter we have {} Representations", representations.len());
+    let representations = representation_filter_video_continuity(representations, &candidates, ps);
+    trace!("After period continuity filter we have {} Representations", representations.len());
+    let representations = representation_filter_video_quality(representations, downloader);
+    trace!("After quality filter we have {} Representations", representations.len());
+    // The parent AdaptationSet may be needed for certain Representation attributes whose value can
+    // be located higher in the XML tree.
+    Ok(representations.first()
+       .and_then(|r| candidates.iter().find(|(_, cr)| std::ptr::eq(*cr, *r)))
+       .copied())
+}
+
+
+// Prepare the download of the video Representation selected in this Period by
+// select_video_representation(), if any.
 #[tracing::instrument(level="trace", skip_all)]
 async fn do_period_video(
     downloader: &DashDownloader,
     mpd: &MPD,
     period: &Period,
     period_counter: u8,
-    base_url: Url
+    base_url: Url,
+    selected: Option<Candidate<'_>>
     ) -> Result<PeriodOutputs, DashMpdError>
+{
+    match selected {
+        Some((video_adaptation, video_repr)) =>
+            do_video_representation(downloader, mpd, period, period_counter, base_url,
+                                    video_adaptation, video_repr).await,
+        None => Ok(PeriodOutputs::default()),
+    }
+}
+
+// Prepare the download of the video Representation video_repr, a child of video_adaptation. This
+// is also used for the image Representations of thumbnail tracks, which use the same addressing
+// modes.
+#[tracing::instrument(level="trace", skip_all)]
+async fn do_video_representation(
+    downloader: &DashDownloader,
+    mpd: &MPD,
+    period: &Period,
+    period_counter: u8,
+    base_url: Url,
+    video_adaptation: &AdaptationSet,
+    video_repr: &Representation,
+) -> Result<PeriodOutputs, DashMpdError>
 {
     let mut fragments = Vec::new();
     let mut diagnostics = Vec::new();
+    let mut clearkey_licenses = Vec::new();
+    let mut kids = Vec::new();
     let mut period_duration_secs: f64 = 0.0;
     let mut opt_init: Option<String> = None;
     let mut opt_media: Option<String> = None;
@@ -2926,243 +5108,976 @@ async fn do_period_video(
             start_number = s;
         }
     }
-    // A manifest may contain multiple AdaptationSets with video content (in particular, when
-    // different codecs are offered). Each AdaptationSet often contains multiple video
-    // Representations with different bandwidths, video resolutions and codecs. We select the
-    // Representation to download by ranking them according to the following user-specified
-    // preferences:
-    //
-    //   - a substring of the video @id attribute
-    //   - the preferred width
-    //   - the preferred height
-    //   - the video codec preference ordering
-    //   - the quality preference (defaulting to the lowest quality available)
-    //
-    // The preferences are applied in the order shown in the list above.
-    //
-    // If these preferences have not been specified, they have no filtering effect, except for the
-    // quality preference which defaults to preferring the lowest quality and smallest file size.
-    let video_adaptations: Vec<&AdaptationSet> = period.adaptations.iter()
-        .filter(is_video_adaptation)
-        .collect();
-    let representations: Vec<&Representation> = select_preferred_adaptations(video_adaptations, downloader)
-        .iter()
-        .flat_map(|a| a.representations.iter())
-        .collect();
-    trace!("Before filtering we have {} Representations", representations.len());
-    let representations = representation_filter_video_id(representations, downloader);
-    trace!("After video_id filter we have {} Representations", representations.len());
-    let representations = representation_filter_video_width(representations, downloader);
-    trace!("After width filter we have {} Representations", representations.len());
-    let representations = representation_filter_video_height(representations, downloader);
-    trace!("After height filter we have {} Representations", representations.len());
-    let representations = representation_filter_video_codec(representations, downloader);
-    trace!("After video codec filter we have {} Representations", representations.len());
-    let representations = representation_filter_video_quality(representations, downloader);
-    trace!("After quality filter we have {} Representations", representations.len());
-    if let Some(video_repr) = representations.first() {
-        // Find the AdaptationSet that is the parent of the selected Representation. This may be
-        // needed for certain Representation attributes whose value can be located higher in the XML
-        // tree.
-        let video_adaptation = period.adaptations.iter()
-            .find(|a| a.representations.iter().any(|r| r.eq(video_repr)))
-            .unwrap();
-        // The AdaptationSet may have a BaseURL. We use a local variable to make sure we
-        // don't "corrupt" the base_url for the subtitle segments.
-        let mut base_url = base_url.clone();
-        if let Some(bu) = &video_adaptation.BaseURL.first() {
-            base_url = merge_baseurls(&base_url, &bu.base)?;
-        }
-        if let Some(bu) = &video_repr.BaseURL.first() {
-            base_url = merge_baseurls(&base_url, &bu.base)?;
-        }
-        if downloader.verbosity > 0 {
-            let bw = if let Some(bw) = video_repr.bandwidth.or(video_adaptation.maxBandwidth) {
-                format!("bw={} Kbps ", bw / 1024)
-            } else {
-                String::from("")
-            };
-            let unknown = String::from("?");
-            let w = video_repr.width.unwrap_or(video_adaptation.width.unwrap_or(0));
-            let h = video_repr.height.unwrap_or(video_adaptation.height.unwrap_or(0));
-            let fmt = if w == 0 || h == 0 {
-                String::from("")
-            } else {
-                format!("resolution={w}x{h} ")
-            };
-            let codec = video_repr.codecs.as_ref()
-                .unwrap_or(video_adaptation.codecs.as_ref().unwrap_or(&unknown));
-            let maybe_id = if let Some(rid) = &video_repr.id {
-                format!(" (id={rid})")
-            } else {
-                String::from("")
-            };
-            diagnostics.push(format!("  Video stream selected: {bw}{fmt}codec={codec}{maybe_id}"));
-            // Check for ContentProtection on the selected Representation/Adaptation
-            for cp in video_repr.ContentProtection.iter()
-                .chain(video_adaptation.ContentProtection.iter())
-            {
-                diagnostics.push(format!("  ContentProtection: {}", content_protection_type(cp)));
-                if let Some(kid) = &cp.default_KID {
-                    diagnostics.push(format!("    KID: {}", kid.replace('-', "")));
-                }
-                for pssh_element in &cp.cenc_pssh {
-                    if let Some(pssh_b64) = &pssh_element.content {
-                        diagnostics.push(format!("    PSSH (from manifest): {pssh_b64}"));
-                        if let Ok(pssh) = pssh_box::from_base64(pssh_b64) {
-                            diagnostics.push(format!("    {pssh}"));
-                        }
+    // The AdaptationSet may have a BaseURL. We use a local variable to make sure we
+    // don't "corrupt" the base_url for the subtitle segments.
+    let mut base_url = base_url.clone();
+    if let Some(bu) = &video_adaptation.BaseURL.first() {
+        base_url = merge_baseurls(&base_url, &bu.base)?;
+    }
+    if let Some(bu) = &video_repr.BaseURL.first() {
+        base_url = merge_baseurls(&base_url, &bu.base)?;
+    }
+    if downloader.verbosity > 0 {
+        let bw = if let Some(bw) = video_repr.bandwidth.or(video_adaptation.maxBandwidth) {
+            format!("bw={} Kbps ", bw / 1024)
+        } else {
+            String::from("")
+        };
+        let unknown = String::from("?");
+        let w = video_repr.width.unwrap_or(video_adaptation.width.unwrap_or(0));
+        let h = video_repr.height.unwrap_or(video_adaptation.height.unwrap_or(0));
+        let fmt = if w == 0 || h == 0 {
+            String::from("")
+        } else {
+            format!("resolution={w}x{h} ")
+        };
+        let codec = video_repr.codecs.as_ref()
+            .unwrap_or(video_adaptation.codecs.as_ref().unwrap_or(&unknown));
+        let maybe_id = if let Some(rid) = &video_repr.id {
+            format!(" (id={rid})")
+        } else {
+            String::from("")
+        };
+        let range = match video_dynamic_range(video_adaptation, video_repr) {
+            VideoDynamicRange::Sdr => String::from(""),
+            r => format!("range={r} "),
+        };
+        diagnostics.push(format!("  Video stream selected: {bw}{fmt}{range}codec={codec}{maybe_id}"));
+        // Check for ContentProtection on the selected Representation/Adaptation
+        for cp in video_repr.ContentProtection.iter()
+            .chain(video_adaptation.ContentProtection.iter())
+        {
+            diagnostics.push(format!("  ContentProtection: {}", content_protection_type(cp)));
+            if let Some(kid) = &cp.default_KID {
+                diagnostics.push(format!("    KID: {}", kid.replace('-', "")));
+            }
+            for pssh_element in &cp.cenc_pssh {
+                if let Some(pssh_b64) = &pssh_element.content {
+                    diagnostics.push(format!("    PSSH (from manifest): {pssh_b64}"));
+                    if let Ok(pssh) = pssh_box::from_base64(pssh_b64) {
+                        diagnostics.push(format!("    {pssh}"));
                     }
                 }
             }
+            if let Some(pro_b64) = cp.msprpro.as_ref().and_then(|p| p.content.as_ref()) {
+                match PlayReadyObject::from_base64(pro_b64) {
+                    Ok(pro) => diagnostics.push(format!("    PlayReady Object (from manifest): {pro}")),
+                    Err(e) => diagnostics.push(format!("    Unparseable mspr:pro: {e}")),
+                }
+            }
+        }
+    }
+    for cp in video_repr.ContentProtection.iter()
+        .chain(video_adaptation.ContentProtection.iter())
+    {
+        if let Some(kid) = cp.default_KID.as_ref().and_then(|k| normalize_kid(k)) {
+            if !kids.contains(&kid) {
+                kids.push(kid);
+            }
+        }
+    }
+    if downloader.clearkey_license_acquisition {
+        if let Some((laurl, kids)) = clearkey_license_request(
+            video_repr.ContentProtection.iter()
+                .chain(video_adaptation.ContentProtection.iter()))
+        {
+            clearkey_licenses.push((merge_baseurls(&base_url, &laurl)?, kids));
+        }
+    }
+    let mut dict = HashMap::new();
+    if let Some(rid) = &video_repr.id {
+        dict.insert("RepresentationID", rid.clone());
+    }
+    if let Some(b) = &video_repr.bandwidth {
+        dict.insert("Bandwidth", b.to_string());
+    }
+    // SegmentTemplate as a direct child of an Adaptation node. This can specify some common
+    // attribute values (media, timescale, duration, startNumber) for child SegmentTemplate
+    // nodes in an enclosed Representation node. Don't download media segments here, only
+    // download for SegmentTemplate nodes that are children of a Representation node.
+    if let Some(st) = &video_adaptation.SegmentTemplate {
+        if let Some(i) = &st.initialization {
+            opt_init = Some(i.clone());
+        }
+        if let Some(m) = &st.media {
+            opt_media = Some(m.clone());
         }
-        let mut dict = HashMap::new();
-        if let Some(rid) = &video_repr.id {
-            dict.insert("RepresentationID", rid.clone());
+        if let Some(d) = st.duration {
+            opt_duration = Some(d);
+        }
+        if let Some(ts) = st.timescale {
+            timescale = ts;
+        }
+        if let Some(s) = st.startNumber {
+            start_number = s;
         }
-        if let Some(b) = &video_repr.bandwidth {
-            dict.insert("Bandwidth", b.to_string());
+    }
+    // Now the 6 possible addressing modes: (1) SegmentList,
+    // (2) SegmentTemplate+SegmentTimeline, (3) SegmentTemplate@duration,
+    // (4) SegmentTemplate@index, (5) SegmentBase@indexRange, (6) plain BaseURL
+    if let Some(sl) = &video_adaptation.SegmentList {
+        // (1) AdaptationSet>SegmentList addressing mode
+        if downloader.verbosity > 1 {
+            info!("  Using AdaptationSet>SegmentList addressing mode for video representation");
         }
-        // SegmentTemplate as a direct child of an Adaptation node. This can specify some common
-        // attribute values (media, timescale, duration, startNumber) for child SegmentTemplate
-        // nodes in an enclosed Representation node. Don't download media segments here, only
-        // download for SegmentTemplate nodes that are children of a Representation node.
-        if let Some(st) = &video_adaptation.SegmentTemplate {
+        let mut start_byte: Option<u64> = None;
+        let mut end_byte: Option<u64> = None;
+        if let Some(init) = &sl.Initialization {
+            if let Some(range) = &init.range {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
+            }
+            if let Some(su) = &init.sourceURL {
+                let path = resolve_url_template(su, &dict);
+                let u = merge_baseurls(&base_url, &path)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
+            }
+        } else {
+            let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                .with_range(start_byte, end_byte)
+                .set_init()
+                .build();
+            fragments.push(mf);
+        }
+        for su in &sl.segment_urls {
+            start_byte = None;
+            end_byte = None;
+            // we are ignoring @indexRange
+            if let Some(range) = &su.mediaRange {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
+            }
+            if let Some(m) = &su.media {
+                let u = merge_baseurls(&base_url, m)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
+            } else if let Some(bu) = video_adaptation.BaseURL.first() {
+                let u = merge_baseurls(&base_url, &bu.base)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
+            }
+        }
+    }
+    if let Some(sl) = &video_repr.SegmentList {
+        // (1) Representation>SegmentList addressing mode
+        if downloader.verbosity > 1 {
+            info!("  Using Representation>SegmentList addressing mode for video representation");
+        }
+        let mut start_byte: Option<u64> = None;
+        let mut end_byte: Option<u64> = None;
+        if let Some(init) = &sl.Initialization {
+            if let Some(range) = &init.range {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
+            }
+            if let Some(su) = &init.sourceURL {
+                let path = resolve_url_template(su, &dict);
+                let u = merge_baseurls(&base_url, &path)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
+            } else {
+                let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                    .with_range(start_byte, end_byte)
+                    .set_init()
+                    .build();
+                fragments.push(mf);
+            }
+        }
+        for su in &sl.segment_urls {
+            start_byte = None;
+            end_byte = None;
+            // we are ignoring @indexRange
+            if let Some(range) = &su.mediaRange {
+                let (s, e) = parse_range(range)?;
+                start_byte = Some(s);
+                end_byte = Some(e);
+            }
+            if let Some(m) = &su.media {
+                let u = merge_baseurls(&base_url, m)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
+            } else if let Some(bu) = video_repr.BaseURL.first() {
+                let u = merge_baseurls(&base_url, &bu.base)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_range(start_byte, end_byte)
+                    .build();
+                fragments.push(mf);
+            }
+        }
+    } else if video_repr.SegmentTemplate.is_some() ||
+        video_adaptation.SegmentTemplate.is_some() {
+            // Here we are either looking at a Representation.SegmentTemplate, or a
+            // higher-level AdaptationSet.SegmentTemplate
+            let st;
+            if let Some(it) = &video_repr.SegmentTemplate {
+                st = it;
+            } else if let Some(it) = &video_adaptation.SegmentTemplate {
+                st = it;
+            } else {
+                panic!("impossible");
+            }
             if let Some(i) = &st.initialization {
                 opt_init = Some(i.clone());
             }
             if let Some(m) = &st.media {
                 opt_media = Some(m.clone());
             }
-            if let Some(d) = st.duration {
-                opt_duration = Some(d);
-            }
             if let Some(ts) = st.timescale {
                 timescale = ts;
             }
-            if let Some(s) = st.startNumber {
-                start_number = s;
+            if let Some(sn) = st.startNumber {
+                start_number = sn;
             }
-        }
-        // Now the 6 possible addressing modes: (1) SegmentList,
-        // (2) SegmentTemplate+SegmentTimeline, (3) SegmentTemplate@duration,
-        // (4) SegmentTemplate@index, (5) SegmentBase@indexRange, (6) plain BaseURL
-        if let Some(sl) = &video_adaptation.SegmentList {
-            // (1) AdaptationSet>SegmentList addressing mode
-            if downloader.verbosity > 1 {
-                info!("  Using AdaptationSet>SegmentList addressing mode for video representation");
-            }
-            let mut start_byte: Option<u64> = None;
-            let mut end_byte: Option<u64> = None;
-            if let Some(init) = &sl.Initialization {
-                if let Some(range) = &init.range {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
+            if let Some(stl) = &video_repr.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone())
+                .or(video_adaptation.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone()))
+            {
+                // (2) SegmentTemplate with SegmentTimeline addressing mode
+                if downloader.verbosity > 1 {
+                    info!("  Using SegmentTemplate+SegmentTimeline addressing mode for video representation");
                 }
-                if let Some(su) = &init.sourceURL {
-                    let path = resolve_url_template(su, &dict);
+                if let Some(init) = opt_init {
+                    let path = resolve_url_template(&init, &dict);
                     let u = merge_baseurls(&base_url, &path)?;
                     let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
                         .set_init()
                         .build();
                     fragments.push(mf);
                 }
-            } else {
-                let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
-                    .with_range(start_byte, end_byte)
-                    .set_init()
-                    .build();
-                fragments.push(mf);
-            }
-            for su in &sl.segment_urls {
-                start_byte = None;
-                end_byte = None;
-                // we are ignoring @indexRange
-                if let Some(range) = &su.mediaRange {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
+                let mut elapsed_seconds = 0.0;
+                if let Some(media) = opt_media {
+                    let video_path = resolve_url_template(&media, &dict);
+                    let mut segment_time = 0;
+                    let mut segment_duration;
+                    let mut number = start_number;
+                    let mut target_duration = period_duration_secs;
+                    if let Some(target) = downloader.force_duration {
+                        if target > period_duration_secs {
+                            warn!("  Requested forced duration exceeds available content");
+                        } else {
+                            target_duration = target;
+                        }
+                    }
+                    'segment_loop: for s in &stl.segments {
+                        if let Some(t) = s.t {
+                            segment_time = t;
+                        }
+                        segment_duration = s.d;
+                        // the URLTemplate may be based on $Time$, or on $Number$
+                        let dict = HashMap::from([("Time", segment_time.to_string()),
+                                                  ("Number", number.to_string())]);
+                        let path = resolve_url_template(&video_path, &dict);
+                        let u = merge_baseurls(&base_url, &path)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u).build();
+                        fragments.push(mf);
+                        number += 1;
+                        elapsed_seconds += segment_duration as f64 / timescale as f64;
+                        if downloader.force_duration.is_some() &&
+                            target_duration > 0.0 &&
+                            elapsed_seconds > target_duration
+                        {
+                            break 'segment_loop;
+                        }
+                        if let Some(r) = s.r {
+                            let mut count = 0i64;
+                            loop {
+                                count += 1;
+                                // Exit from the loop after @r iterations (if @r is
+                                // positive). A negative value of the @r attribute indicates
+                                // that the duration indicated in @d attribute repeats until
+                                // the start of the next S element, the end of the Period or
+                                // until the next MPD update.
+                                if r >= 0 && count > r {
+                                    break;
+                                }
+                                if downloader.force_duration.is_some() &&
+                                    target_duration > 0.0 &&
+                                    elapsed_seconds > target_duration
+                                {
+                                    break 'segment_loop;
+                                }
+                                if let Some(end_number) = st.endNumber {
+                                    if count as u64 > end_number {
+                                        break;
+                                    }
+                                }
+                                segment_time += segment_duration;
+                                elapsed_seconds += segment_duration as f64 / timescale as f64;
+                                let dict = HashMap::from([("Time", segment_time.to_string()),
+                                                          ("Number", number.to_string())]);
+                                let path = resolve_url_template(&video_path, &dict);
+                                let u = merge_baseurls(&base_url, &path)?;
+                                let mf = MediaFragmentBuilder::new(period_counter, u).build();
+                                fragments.push(mf);
+                                number += 1;
+                            }
+                        }
+                        segment_time += segment_duration;
+                    }
+                } else {
+                    return Err(DashMpdError::UnhandledMediaStream(
+                        "SegmentTimeline without a media attribute".to_string()));
                 }
-                if let Some(m) = &su.media {
-                    let u = merge_baseurls(&base_url, m)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .build();
-                    fragments.push(mf);
-                } else if let Some(bu) = video_adaptation.BaseURL.first() {
-                    let u = merge_baseurls(&base_url, &bu.base)?;
+            } else { // no SegmentTimeline element
+                // (3) SegmentTemplate@duration addressing mode or (4) SegmentTemplate@index addressing mode
+                if downloader.verbosity > 1 {
+                    info!("  Using SegmentTemplate addressing mode for video representation");
+                }
+                let mut total_number = 0i64;
+                if let Some(init) = opt_init {
+                    let path = resolve_url_template(&init, &dict);
+                    let u = merge_baseurls(&base_url, &path)?;
                     let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
+                        .set_init()
                         .build();
                     fragments.push(mf);
                 }
+                if let Some(media) = opt_media {
+                    let video_path = resolve_url_template(&media, &dict);
+                    let timescale = st.timescale.unwrap_or(timescale);
+                    let mut segment_duration: f64 = -1.0;
+                    if let Some(d) = opt_duration {
+                        // it was set on the Period.SegmentTemplate node
+                        segment_duration = d;
+                    }
+                    if let Some(std) = st.duration {
+                        if timescale == 0 {
+                            return Err(DashMpdError::UnhandledMediaStream(
+                                "SegmentTemplate@duration attribute cannot be zero".to_string()));
+                        }
+                        segment_duration = std / timescale as f64;
+                    }
+                    if segment_duration < 0.0 {
+                        return Err(DashMpdError::UnhandledMediaStream(
+                            "Video representation is missing SegmentTemplate@duration attribute".to_string()));
+                    }
+                    total_number += (period_duration_secs / segment_duration).round() as i64;
+                    let mut number = start_number;
+                    // For a live manifest (dynamic MPD), we look at the time elapsed since now
+                    // and the mpd.availabilityStartTime to determine the correct value for
+                    // startNumber, based on duration and timescale. The latest available
+                    // segment is numbered
+                    //
+                    //    LSN = floor((now - (availabilityStartTime+PST))/segmentDuration + startNumber - 1)
+
+                    // https://dashif.org/Guidelines-TimingModel/Timing-Model.pdf
+                    // To be more precise, any LeapSecondInformation should be added to the availabilityStartTime.
+                    if mpd_is_dynamic(mpd) {
+                        if let Some(start_time) = mpd.availabilityStartTime {
+                            let elapsed = Utc::now().signed_duration_since(start_time).as_seconds_f64() / segment_duration;
+                            number = (elapsed + number as f64 - 1f64).floor() as u64;
+                        } else {
+                            return Err(DashMpdError::UnhandledMediaStream(
+                                "dynamic manifest is missing @availabilityStartTime".to_string()));
+                        }
+                    }
+                    if let Some(end_number) = st.endNumber {
+                        total_number = end_number as i64;
+                    }
+                    for _ in 1..=total_number {
+                        let dict = HashMap::from([("Number", number.to_string())]);
+                        let path = resolve_url_template(&video_path, &dict);
+                        let u = merge_baseurls(&base_url, &path)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u).build();
+                        fragments.push(mf);
+                        number += 1;
+                    }
+                }
             }
-        }
-        if let Some(sl) = &video_repr.SegmentList {
-            // (1) Representation>SegmentList addressing mode
+        } else if let Some(sb) = &video_repr.SegmentBase {
+            // (5) SegmentBase@indexRange addressing mode
             if downloader.verbosity > 1 {
-                info!("  Using Representation>SegmentList addressing mode for video representation");
-            }
-            let mut start_byte: Option<u64> = None;
-            let mut end_byte: Option<u64> = None;
-            if let Some(init) = &sl.Initialization {
-                if let Some(range) = &init.range {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
+                info!("  Using SegmentBase@indexRange addressing mode for video representation");
+            }
+            let mf = do_segmentbase_indexrange(downloader, period_counter, base_url, sb, &dict).await?;
+            fragments.extend(mf);
+        } else if fragments.is_empty()  {
+            if let Some(bu) = video_repr.BaseURL.first() {
+                // (6) BaseURL addressing mode
+                if downloader.verbosity > 1 {
+                    info!("  Using BaseURL addressing mode for video representation");
                 }
-                if let Some(su) = &init.sourceURL {
-                    let path = resolve_url_template(su, &dict);
-                    let u = merge_baseurls(&base_url, &path)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
+                let u = merge_baseurls(&base_url, &bu.base)?;
+                let mf = MediaFragmentBuilder::new(period_counter, u)
+                    .with_timeout(Duration::new(10000, 0))
+                    .build();
+                fragments.push(mf);
+            }
+        }
+    if fragments.is_empty() {
+        return Err(DashMpdError::UnhandledMediaStream(
+            "no usable addressing mode identified for video representation".to_string()));
+    }
+    // FIXME we aren't correctly handling manifests without a Representation node
+    // eg https://raw.githubusercontent.com/zencoder/go-dash/master/mpd/fixtures/newperiod.mpd
+    Ok(PeriodOutputs {
+        fragments,
+        diagnostics,
+        selected_audio_languages: Vec::new(),
+        selected_subtitles: Vec::new(),
+        clearkey_licenses,
+        kids,
+    })
+}
+
+// The start time and duration in milliseconds, relative to the start of the Period, of the count
+// segments of the thumbnail Representation r. These are determined from the SegmentTimeline or the
+// SegmentTemplate@duration, or failing that by spreading the segments evenly over the Period.
+fn thumbnail_tile_times(
+    period: &Period,
+    a: &AdaptationSet,
+    r: &Representation,
+    count: usize,
+    period_duration_secs: f64) -> Vec<(u64, u64)>
+{
+    let st = r.SegmentTemplate.as_ref()
+        .or(a.SegmentTemplate.as_ref())
+        .or(period.SegmentTemplate.as_ref());
+    if let Some(st) = st {
+        let timescale = st.timescale.unwrap_or(1).max(1);
+        if let Some(stl) = &st.SegmentTimeline {
+            let pto = st.presentationTimeOffset.unwrap_or(0);
+            let end = (period_duration_secs > 0.0)
+                .then(|| pto + (period_duration_secs * timescale as f64).round() as u64);
+            let mut times: Vec<(u64, u64)> = timeline_segments(stl, end).into_iter()
+                .map(|(t, d)| (t.saturating_sub(pto) * 1000 / timescale, d * 1000 / timescale))
+                .collect();
+            if times.len() >= count {
+                times.truncate(count);
+                return times;
+            }
+        } else if let Some(d) = st.duration {
+            let d_ms = (d * 1000.0 / timescale as f64).round() as u64;
+            return (0..count as u64).map(|i| (i * d_ms, d_ms)).collect();
+        }
+    }
+    let d_ms = (period_duration_secs.max(0.0) * 1000.0 / count.max(1) as f64) as u64;
+    (0..count as u64).map(|i| (i * d_ms, d_ms)).collect()
+}
+
+// Select the thumbnail Representation to download in this Period, if thumbnails are requested and
+// the Period contains an image AdaptationSet, and prepare the download of its tile images.
+#[tracing::instrument(level="trace", skip_all)]
+async fn do_period_thumbnails(
+    downloader: &DashDownloader,
+    mpd: &MPD,
+    period: &Period,
+    period_counter: u8,
+    base_url: Url
+) -> Result<Option<(Vec<MediaFragment>, SelectedThumbnails)>, DashMpdError>
+{
+    if !downloader.fetch_thumbnails {
+        return Ok(None);
+    }
+    let mut candidates: Vec<Candidate> = period.adaptations.iter()
+        .filter(is_image_adaptation)
+        .flat_map(|a| a.representations.iter().map(move |r| (a, r)))
+        .collect();
+    // Prefer Representations that are explicitly signalled as thumbnail tiles.
+    if candidates.iter().any(|(a, r)| thumbnail_tile_grid(a, r).is_some()) {
+        candidates.retain(|(a, r)| thumbnail_tile_grid(a, r).is_some());
+    }
+    candidates.sort_by_key(|(a, r)| {
+        let pixels = r.width.or(a.width).unwrap_or(0) * r.height.or(a.height).unwrap_or(0);
+        (r.bandwidth.unwrap_or(0), pixels)
+    });
+    let chosen = match downloader.quality_preference() {
+        QualityPreference::Lowest => candidates.first(),
+        QualityPreference::Intermediate => candidates.get(candidates.len() / 2),
+        QualityPreference::Highest => candidates.last(),
+    };
+    let Some((a, r)) = chosen.copied() else {
+        return Ok(None);
+    };
+    let outputs = do_video_representation(downloader, mpd, period, period_counter, base_url, a, r).await?;
+    let fragments: Vec<MediaFragment> = outputs.fragments.into_iter()
+        .filter(|f| !f.is_init)
+        .collect();
+    let period_duration_secs = downloader.force_duration
+        .or(period.duration.map(|d| d.as_secs_f64()))
+        .or(mpd.mediaPresentationDuration.map(|d| d.as_secs_f64()))
+        .unwrap_or(0.0);
+    let grid = thumbnail_tile_grid(a, r).unwrap_or((1, 1));
+    if downloader.verbosity > 0 {
+        let w = r.width.or(a.width).unwrap_or(0);
+        let h = r.height.or(a.height).unwrap_or(0);
+        let maybe_id = r.id.as_ref()
+            .map_or_else(|| String::from(""), |rid| format!(" (id={rid})"));
+        info!("  Thumbnail track selected: {w}x{h} tiles={}x{}{maybe_id}", grid.0, grid.1);
+    }
+    let selected = SelectedThumbnails {
+        grid,
+        width: r.width.or(a.width),
+        height: r.height.or(a.height),
+        extension: image_extension(r.mimeType.as_deref().or(a.mimeType.as_deref())),
+        tile_times: thumbnail_tile_times(period, a, r, fragments.len(), period_duration_secs),
+    };
+    Ok(Some((fragments, selected)))
+}
+
+// Prepare the download of the tiles of a spatially partitioned video, described by SRD
+// descriptors. We download the tiles of the SRD source of the selected video stream or, if it has no
+// SRD descriptor, those of the first SRD source in the Period. An AdaptationSet covering the whole
+// source is not a tile.
+#[tracing::instrument(level="trace", skip_all)]
+async fn do_period_tiles(
+    downloader: &DashDownloader,
+    mpd: &MPD,
+    period: &Period,
+    period_counter: u8,
+    base_url: Url,
+    selected_video: Option<Candidate<'_>>
+) -> Result<Vec<(Vec<MediaFragment>, SelectedTile)>, DashMpdError>
+{
+    let mut tiles = Vec::new();
+    if !downloader.fetch_srd_tiles {
+        return Ok(tiles);
+    }
+    let video_adaptations: Vec<&AdaptationSet> = period.adaptations.iter()
+        .filter(is_video_adaptation)
+        .filter(|a| !is_trickmode_adaptation(a))
+        .collect();
+    let video_adaptations = filter_viewpoint(video_adaptations, downloader);
+    let Some(source_id) = selected_video
+        .and_then(|(a, r)| spatial_relationship(a, Some(r)))
+        .or_else(|| video_adaptations.iter().find_map(|a| spatial_relationship(a, None)))
+        .map(|srd| srd.source_id) else {
+            return Ok(tiles);
+        };
+    let reference_size = srd_reference_size(period, source_id);
+    for a in video_adaptations {
+        let Some(srd) = spatial_relationship(a, None).filter(|srd| srd.source_id == source_id) else {
+            continue;
+        };
+        if srd_full_frame(a, period) {
+            continue;
+        }
+        let representations: Vec<&Representation> = a.representations.iter().collect();
+        let Some(r) = select_preferred_representation(&representations, downloader) else {
+            continue;
+        };
+        let track = u8::try_from(tiles.len())
+            .map_err(|_| DashMpdError::UnhandledMediaStream(String::from("too many SRD tiles")))?;
+        let outputs = do_video_representation(downloader, mpd, period, period_counter, base_url.clone(), a, r).await?;
+        let fragments: Vec<MediaFragment> = outputs.fragments.into_iter()
+            .map(|mut f| { f.track = track; f })
+            .collect();
+        if downloader.verbosity > 0 {
+            let maybe_id = r.id.as_ref()
+                .map_or_else(|| String::from(""), |rid| format!(" (id={rid})"));
+            info!("  SRD tile selected: source={source_id} x={} y={} {}x{} of {}x{}{maybe_id}",
+                  srd.object_x, srd.object_y, srd.object_width, srd.object_height,
+                  reference_size.0, reference_size.1);
+        }
+        let mime = r.mimeType.as_deref().or(a.mimeType.as_deref()).unwrap_or("video/mp4");
+        let tile = SelectedTile {
+            adaptation_id: a.id.clone(),
+            representation_id: r.id.clone(),
+            srd,
+            reference_size,
+            extension: if mime.contains("webm") { "webm" } else { "mp4" },
+        };
+        tiles.push((fragments, tile));
+    }
+    Ok(tiles)
+}
+
+#[tracing::instrument(level="trace", skip_all)]
+async fn do_period_subtitles(
+    downloader: &DashDownloader,
+    mpd: &MPD,
+    period: &Period,
+    period_counter: u8,
+    base_url: Url
+    ) -> Result<PeriodOutputs, DashMpdError>
+{
+    let output_path = &downloader.output_path.as_ref().unwrap().clone();
+    let mut outputs = PeriodOutputs::default();
+    if !downloader.fetch_subtitles {
+        return Ok(outputs);
+    }
+    // When several subtitle tracks may be downloaded, each track's sidecar files are named with
+    // its language and kind. Otherwise we keep the historical naming (the output path with a
+    // subtitle extension).
+    let multiple_tracks = downloader.fetch_all_subtitle_tracks ||
+        downloader.fetch_matching_subtitle_languages;
+    let mut used_paths: Vec<PathBuf> = Vec::new();
+    for (track, subtitle_adaptation) in select_subtitle_adaptations(period, downloader).into_iter().enumerate() {
+        let Ok(track) = u8::try_from(track) else {
+            warn!("  Too many subtitle tracks; ignoring the remaining tracks");
+            break;
+        };
+        let language = adaptation_language(subtitle_adaptation).unwrap_or("unk").to_string();
+        let forced = adaptation_forced_subtitles(subtitle_adaptation);
+        let hearing_impaired = adaptation_hearing_impaired(subtitle_adaptation);
+        let track_output_path = if multiple_tracks {
+            subtitle_track_output_path(output_path, &language, forced, hearing_impaired, &used_paths)
+        } else {
+            output_path.clone()
+        };
+        used_paths.push(track_output_path.clone());
+        if downloader.verbosity > 1 && multiple_tracks {
+            info!("  Selected subtitle track in language {language}{}{}",
+                  if forced { " (forced)" } else { "" },
+                  if hearing_impaired { " (SDH)" } else { "" });
+        }
+        let period_output_path = output_path_for_period(&track_output_path, period_counter);
+        let timescale = subtitle_timescale(subtitle_adaptation);
+        let (fragments, formats) = do_subtitle_adaptation(
+            downloader, mpd, period, period_counter, &base_url,
+            subtitle_adaptation, &period_output_path).await?;
+        for mut f in fragments {
+            f.track = track;
+            outputs.fragments.push(f);
+        }
+        outputs.selected_subtitles.push(SelectedSubtitles {
+            language,
+            formats,
+            forced,
+            hearing_impaired,
+            output_path: track_output_path,
+            timescale,
+        });
+    }
+    Ok(outputs)
+}
+
+// The timescale announced in the manifest for the (first) Representation of a subtitle
+// AdaptationSet, from a SegmentTemplate, SegmentBase or SegmentList at the Representation or
+// AdaptationSet level.
+fn subtitle_timescale(subtitle_adaptation: &AdaptationSet) -> Option<u32> {
+    let rep = subtitle_adaptation.representations.first();
+    let timescales = [
+        rep.and_then(|r| r.SegmentTemplate.as_ref()).and_then(|st| st.timescale),
+        rep.and_then(|r| r.SegmentBase.as_ref()).and_then(|sb| sb.timescale),
+        rep.and_then(|r| r.SegmentList.as_ref()).and_then(|sl| sl.timescale),
+        subtitle_adaptation.SegmentTemplate.as_ref().and_then(|st| st.timescale),
+        subtitle_adaptation.SegmentList.as_ref().and_then(|sl| sl.timescale),
+    ];
+    timescales.into_iter()
+        .flatten()
+        .find_map(|ts| u32::try_from(ts).ok())
+}
+
+// Identify the media fragments to download for a subtitle AdaptationSet, returning them with the
+// subtitle formats found. Subtitles that are available as a single file (referenced by a BaseURL)
+// are downloaded immediately to a sidecar file named after period_output_path.
+async fn do_subtitle_adaptation(
+    downloader: &DashDownloader,
+    mpd: &MPD,
+    period: &Period,
+    period_counter: u8,
+    base_url: &Url,
+    subtitle_adaptation: &AdaptationSet,
+    period_output_path: &Path,
+    ) -> Result<(Vec<MediaFragment>, Vec<SubtitleType>), DashMpdError>
+{
+    let client = downloader.http_client.as_ref().unwrap();
+    let mut fragments = Vec::new();
+    let mut subtitle_formats = Vec::new();
+    let mut period_duration_secs: f64 = 0.0;
+    if let Some(d) = mpd.mediaPresentationDuration {
+        period_duration_secs = d.as_secs_f64();
+    }
+    if let Some(d) = period.duration {
+        period_duration_secs = d.as_secs_f64();
+    }
+    let subtitle_format = subtitle_type(&subtitle_adaptation);
+    subtitle_formats.push(subtitle_format);
+    if downloader.verbosity > 1 && downloader.fetch_subtitles {
+        info!("  Retrieving subtitles in format {subtitle_format:?}");
+    }
+    // The AdaptationSet may have a BaseURL. We use a local variable to make sure we
+    // don't "corrupt" the base_url for the subtitle segments.
+    let mut base_url = base_url.clone();
+    if let Some(bu) = &subtitle_adaptation.BaseURL.first() {
+        base_url = merge_baseurls(&base_url, &bu.base)?;
+    }
+    // We don't do any ranking on subtitle Representations, because there is probably only a
+    // single one for our selected Adaptation.
+    if let Some(rep) = subtitle_adaptation.representations.first() {
+        if !rep.BaseURL.is_empty() {
+            for st_bu in &rep.BaseURL {
+                let st_url = merge_baseurls(&base_url, &st_bu.base)?;
+                let mut req = client.get(st_url.clone());
+                if let Some(referer) = &downloader.referer {
+                    req = req.header("Referer", referer);
                 } else {
-                    let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
-                        .with_range(start_byte, end_byte)
-                        .set_init()
-                        .build();
-                    fragments.push(mf);
+                    req = req.header("Referer", base_url.to_string());
+                }
+                let rqw = req.build()
+                    .map_err(|e| network_error("building request", &e))?;
+                let subs = reqwest_bytes_with_retries(client, rqw, 5).await
+                    .map_err(|e| network_error("fetching subtitles", &e))?;
+                let mut subs_path = period_output_path.to_path_buf();
+                let subtitle_format = subtitle_type(&subtitle_adaptation);
+                match subtitle_format {
+                    SubtitleType::Vtt => subs_path.set_extension("vtt"),
+                    SubtitleType::Srt => subs_path.set_extension("srt"),
+                    SubtitleType::Sami => subs_path.set_extension("sami"),
+                    SubtitleType::Wvtt => subs_path.set_extension("wvtt"),
+                    SubtitleType::Ttml | SubtitleType::Stpp => subs_path.set_extension("ttml"),
+                    _ => subs_path.set_extension("sub"),
+                };
+                subtitle_formats.push(subtitle_format);
+                let mut subs_file = File::create(subs_path.clone()).await
+                    .map_err(|e| DashMpdError::Io(e, String::from("creating subtitle file")))?;
+                if downloader.verbosity > 2 {
+                    info!("  Subtitle {st_url} -> {} octets", subs.len());
+                }
+                match subs_file.write_all(&subs).await {
+                    Ok(()) => {
+                        if downloader.verbosity > 0 {
+                            info!("  Downloaded subtitles ({subtitle_format:?}) to {}",
+                                     subs_path.display());
+                        }
+                    },
+                    Err(e) => {
+                        error!("Unable to write subtitle file: {e:?}");
+                        return Err(DashMpdError::Io(e, String::from("writing subtitle data")));
+                    },
+                }
+                if subtitle_format == SubtitleType::Ttml || subtitle_format == SubtitleType::Stpp {
+                    let mut ttml = crate::stpp::StppDocument::new();
+                    let parsed = if subtitle_format == SubtitleType::Stpp {
+                        ttml.add_from_mp4(&subs)
+                    } else {
+                        ttml.add_bytes(&subs)
+                    };
+                    match parsed {
+                        Ok(()) => save_ttml_conversions(downloader, &ttml, &subs_path).await?,
+                        Err(e) => warn!("  Unable to convert TTML subtitles: {e}"),
+                    }
+                }
+                let mut converted = false;
+                if subtitle_formats.contains(&SubtitleType::Wvtt) {
+                    let mut wvtt = crate::wvtt::WvttDocument::new();
+                    if let Some(ts) = subtitle_timescale(subtitle_adaptation) {
+                        wvtt.set_timescale(ts);
+                    }
+                    match wvtt.add_from_mp4(&subs) {
+                        Ok(()) => {
+                            save_wvtt_subtitles(downloader, &wvtt, &subs_path).await?;
+                            converted = true;
+                        },
+                        Err(e) => warn!("  Unable to parse WVTT subtitles: {e}"),
+                    }
+                }
+                if !converted && (subtitle_formats.contains(&SubtitleType::Wvtt) ||
+                                  subtitle_formats.contains(&SubtitleType::Ttxt))
+                {
+                    if downloader.verbosity > 0 {
+                        info!("   Converting subtitles to SRT format with MP4Box ");
+                    }
+                    let out = subs_path.with_extension("srt");
+                    // We try to convert this to SRT format, which is more widely supported,
+                    // using MP4Box. However, it's not a fatal error if MP4Box is not
+                    // installed or the conversion fails.
+                    //
+                    // Could also try to convert to WebVTT with
+                    //   MP4Box -raw "0:output=output.vtt" input.mp4
+                    let out_str = out.to_string_lossy();
+                    let subs_str = subs_path.to_string_lossy();
+                    let args = vec![
+                        "-srt", "1",
+                        "-out", &out_str,
+                        &subs_str];
+                    if downloader.verbosity > 0 {
+                        info!("  Running MPBox {}", args.join(" "));
+                    }
+                    if let Ok(mp4box) = Command::new(downloader.mp4box_location.clone())
+                        .args(args)
+                        .output()
+                    {
+                        let msg = partial_process_output(&mp4box.stdout);
+                        if !msg.is_empty() {
+                            info!("MP4Box stdout: {msg}");
+                        }
+                        let msg = partial_process_output(&mp4box.stderr);
+                        if !msg.is_empty() {
+                            info!("MP4Box stderr: {msg}");
+                        }
+                        if mp4box.status.success() {
+                            info!("   Converted subtitles to SRT");
+                        } else {
+                            warn!("Error running MP4Box to convert subtitles");
+                        }
+                    }
+                }
+            }
+        } else if rep.SegmentTemplate.is_some() || subtitle_adaptation.SegmentTemplate.is_some() {
+            let mut opt_init: Option<String> = None;
+            let mut opt_media: Option<String> = None;
+            let mut opt_duration: Option<f64> = None;
+            let mut timescale = 1;
+            let mut start_number = 1;
+            // SegmentTemplate as a direct child of an Adaptation node. This can specify some common
+            // attribute values (media, timescale, duration, startNumber) for child SegmentTemplate
+            // nodes in an enclosed Representation node. Don't download media segments here, only
+            // download for SegmentTemplate nodes that are children of a Representation node.
+            if let Some(st) = &rep.SegmentTemplate {
+                if let Some(i) = &st.initialization {
+                    opt_init = Some(i.clone());
+                }
+                if let Some(m) = &st.media {
+                    opt_media = Some(m.clone());
+                }
+                if let Some(d) = st.duration {
+                    opt_duration = Some(d);
+                }
+                if let Some(ts) = st.timescale {
+                    timescale = ts;
+                }
+                if let Some(s) = st.startNumber {
+                    start_number = s;
                 }
             }
-            for su in &sl.segment_urls {
-                start_byte = None;
-                end_byte = None;
-                // we are ignoring @indexRange
-                if let Some(range) = &su.mediaRange {
-                    let (s, e) = parse_range(range)?;
-                    start_byte = Some(s);
-                    end_byte = Some(e);
+            let rid = match &rep.id {
+                Some(id) => id,
+                None => return Err(
+                    DashMpdError::UnhandledMediaStream(
+                        "Missing @id on Representation node".to_string())),
+            };
+            let mut dict = HashMap::from([("RepresentationID", rid.clone())]);
+            if let Some(b) = &rep.bandwidth {
+                dict.insert("Bandwidth", b.to_string());
+            }
+            // Now the 6 possible addressing modes: (1) SegmentList,
+            // (2) SegmentTemplate+SegmentTimeline, (3) SegmentTemplate@duration,
+            // (4) SegmentTemplate@index, (5) SegmentBase@indexRange, (6) plain BaseURL
+            if let Some(sl) = &rep.SegmentList {
+                // (1) AdaptationSet>SegmentList addressing mode (can be used in conjunction
+                // with Representation>SegmentList addressing mode)
+                if downloader.verbosity > 1 {
+                    info!("  Using AdaptationSet>SegmentList addressing mode for subtitle representation");
                 }
-                if let Some(m) = &su.media {
-                    let u = merge_baseurls(&base_url, m)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .build();
-                    fragments.push(mf);
-                } else if let Some(bu) = video_repr.BaseURL.first() {
-                    let u = merge_baseurls(&base_url, &bu.base)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_range(start_byte, end_byte)
-                        .build();
-                    fragments.push(mf);
+                let mut start_byte: Option<u64> = None;
+                let mut end_byte: Option<u64> = None;
+                if let Some(init) = &sl.Initialization {
+                    if let Some(range) = &init.range {
+                        let (s, e) = parse_range(range)?;
+                        start_byte = Some(s);
+                        end_byte = Some(e);
+                    }
+                    if let Some(su) = &init.sourceURL {
+                        let path = resolve_url_template(su, &dict);
+                        let u = merge_baseurls(&base_url, &path)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .with_range(start_byte, end_byte)
+                            .set_init()
+                            .build();
+                        fragments.push(mf);
+                    } else {
+                        let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                            .with_range(start_byte, end_byte)
+                            .set_init()
+                            .build();
+                        fragments.push(mf);
+                    }
+                }
+                for su in &sl.segment_urls {
+                    start_byte = None;
+                    end_byte = None;
+                    // we are ignoring SegmentURL@indexRange
+                    if let Some(range) = &su.mediaRange {
+                        let (s, e) = parse_range(range)?;
+                        start_byte = Some(s);
+                        end_byte = Some(e);
+                    }
+                    if let Some(m) = &su.media {
+                        let u = merge_baseurls(&base_url, m)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .with_range(start_byte, end_byte)
+                            .build();
+                        fragments.push(mf);
+                    } else if let Some(bu) = subtitle_adaptation.BaseURL.first() {
+                        let u = merge_baseurls(&base_url, &bu.base)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .with_range(start_byte, end_byte)
+                            .build();
+                        fragments.push(mf);
+                    }
                 }
             }
-        } else if video_repr.SegmentTemplate.is_some() ||
-            video_adaptation.SegmentTemplate.is_some() {
+            if let Some(sl) = &rep.SegmentList {
+                // (1) Representation>SegmentList addressing mode
+                if downloader.verbosity > 1 {
+                    info!("  Using Representation>SegmentList addressing mode for subtitle representation");
+                }
+                let mut start_byte: Option<u64> = None;
+                let mut end_byte: Option<u64> = None;
+                if let Some(init) = &sl.Initialization {
+                    if let Some(range) = &init.range {
+                        let (s, e) = parse_range(range)?;
+                        start_byte = Some(s);
+                        end_byte = Some(e);
+                    }
+                    if let Some(su) = &init.sourceURL {
+                        let path = resolve_url_template(su, &dict);
+                        let u = merge_baseurls(&base_url, &path)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .with_range(start_byte, end_byte)
+                            .set_init()
+                            .build();
+                        fragments.push(mf);
+                    } else {
+                        let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                            .with_range(start_byte, end_byte)
+                            .set_init()
+                            .build();
+                        fragments.push(mf);
+                    }
+                }
+                for su in &sl.segment_urls {
+                    start_byte = None;
+                    end_byte = None;
+                    // we are ignoring SegmentURL@indexRange
+                    if let Some(range) = &su.mediaRange {
+                        let (s, e) = parse_range(range)?;
+                        start_byte = Some(s);
+                        end_byte = Some(e);
+                    }
+                    if let Some(m) = &su.media {
+                        let u = merge_baseurls(&base_url, m)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .with_range(start_byte, end_byte)
+                            .build();
+                        fragments.push(mf);
+                    } else if let Some(bu) = &rep.BaseURL.first() {
+                        let u = merge_baseurls(&base_url, &bu.base)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .with_range(start_byte, end_byte)
+                            .build();
+                        fragments.push(mf);
+                    }
+                }
+            } else if rep.SegmentTemplate.is_some() ||
+                subtitle_adaptation.SegmentTemplate.is_some()
+            {
                 // Here we are either looking at a Representation.SegmentTemplate, or a
                 // higher-level AdaptationSet.SegmentTemplate
                 let st;
-                if let Some(it) = &video_repr.SegmentTemplate {
+                if let Some(it) = &rep.SegmentTemplate {
                     st = it;
-                } else if let Some(it) = &video_adaptation.SegmentTemplate {
+                } else if let Some(it) = &subtitle_adaptation.SegmentTemplate {
                     st = it;
                 } else {
-                    panic!("impossible");
+                    panic!("unreachable");
                 }
                 if let Some(i) = &st.initialization {
                     opt_init = Some(i.clone());
@@ -3176,12 +6091,14 @@ async fn do_period_video(
                 if let Some(sn) = st.startNumber {
                     start_number = sn;
                 }
-                if let Some(stl) = &video_repr.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone())
-                    .or(video_adaptation.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone()))
+                if let Some(stl) = &rep.SegmentTemplate.as_ref()
+                    .and_then(|st| st.SegmentTimeline.clone())
+                    .or(subtitle_adaptation.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone()))
                 {
-                    // (2) SegmentTemplate with SegmentTimeline addressing mode
+                    // (2) SegmentTemplate with SegmentTimeline addressing mode (also called
+                    // "explicit addressing" in certain DASH-IF documents)
                     if downloader.verbosity > 1 {
-                        info!("  Using SegmentTemplate+SegmentTimeline addressing mode for video representation");
+                        info!("  Using SegmentTemplate+SegmentTimeline addressing mode for subtitle representation");
                     }
                     if let Some(init) = opt_init {
                         let path = resolve_url_template(&init, &dict);
@@ -3191,21 +6108,12 @@ async fn do_period_video(
                             .build();
                         fragments.push(mf);
                     }
-                    let mut elapsed_seconds = 0.0;
                     if let Some(media) = opt_media {
-                        let video_path = resolve_url_template(&media, &dict);
+                        let sub_path = resolve_url_template(&media, &dict);
                         let mut segment_time = 0;
                         let mut segment_duration;
                         let mut number = start_number;
-                        let mut target_duration = period_duration_secs;
-                        if let Some(target) = downloader.force_duration {
-                            if target > period_duration_secs {
-                                warn!("  Requested forced duration exceeds available content");
-                            } else {
-                                target_duration = target;
-                            }
-                        }
-                        'segment_loop: for s in &stl.segments {
+                        for s in &stl.segments {
                             if let Some(t) = s.t {
                                 segment_time = t;
                             }
@@ -3213,20 +6121,15 @@ async fn do_period_video(
                             // the URLTemplate may be based on $Time$, or on $Number$
                             let dict = HashMap::from([("Time", segment_time.to_string()),
                                                       ("Number", number.to_string())]);
-                            let path = resolve_url_template(&video_path, &dict);
+                            let path = resolve_url_template(&sub_path, &dict);
                             let u = merge_baseurls(&base_url, &path)?;
                             let mf = MediaFragmentBuilder::new(period_counter, u).build();
                             fragments.push(mf);
                             number += 1;
-                            elapsed_seconds += segment_duration as f64 / timescale as f64;
-                            if downloader.force_duration.is_some() &&
-                                target_duration > 0.0 &&
-                                elapsed_seconds > target_duration
-                            {
-                                break 'segment_loop;
-                            }
                             if let Some(r) = s.r {
                                 let mut count = 0i64;
+                                // FIXME perhaps we also need to account for startTime?
+                                let end_time = period_duration_secs * timescale as f64;
                                 loop {
                                     count += 1;
                                     // Exit from the loop after @r iterations (if @r is
@@ -3234,592 +6137,26 @@ async fn do_period_video(
                                     // that the duration indicated in @d attribute repeats until
                                     // the start of the next S element, the end of the Period or
                                     // until the next MPD update.
-                                    if r >= 0 && count > r {
-                                        break;
-                                    }
-                                    if downloader.force_duration.is_some() &&
-                                        target_duration > 0.0 &&
-                                        elapsed_seconds > target_duration
-                                    {
-                                        break 'segment_loop;
-                                    }
-                                    if let Some(end_number) = st.endNumber {
-                                        if count as u64 > end_number {
+                                    if r >= 0 {
+                                        if count > r {
                                             break;
                                         }
-                                    }
-                                    segment_time += segment_duration;
-                                    elapsed_seconds += segment_duration as f64 / timescale as f64;
-                                    let dict = HashMap::from([("Time", segment_time.to_string()),
-                                                              ("Number", number.to_string())]);
-                                    let path = resolve_url_template(&video_path, &dict);
-                                    let u = merge_baseurls(&base_url, &path)?;
-                                    let mf = MediaFragmentBuilder::new(period_counter, u).build();
-                                    fragments.push(mf);
-                                    number += 1;
-                                }
-                            }
-                            segment_time += segment_duration;
-                        }
-                    } else {
-                        return Err(DashMpdError::UnhandledMediaStream(
-                            "SegmentTimeline without a media attribute".to_string()));
-                    }
-                } else { // no SegmentTimeline element
-                    // (3) SegmentTemplate@duration addressing mode or (4) SegmentTemplate@index addressing mode
-                    if downloader.verbosity > 1 {
-                        info!("  Using SegmentTemplate addressing mode for video representation");
-                    }
-                    let mut total_number = 0i64;
-                    if let Some(init) = opt_init {
-                        let path = resolve_url_template(&init, &dict);
-                        let u = merge_baseurls(&base_url, &path)?;
-                        let mf = MediaFragmentBuilder::new(period_counter, u)
-                            .set_init()
-                            .build();
-                        fragments.push(mf);
-                    }
-                    if let Some(media) = opt_media {
-                        let video_path = resolve_url_template(&media, &dict);
-                        let timescale = st.timescale.unwrap_or(timescale);
-                        let mut segment_duration: f64 = -1.0;
-                        if let Some(d) = opt_duration {
-                            // it was set on the Period.SegmentTemplate node
-                            segment_duration = d;
-                        }
-                        if let Some(std) = st.duration {
-                            if timescale == 0 {
-                                return Err(DashMpdError::UnhandledMediaStream(
-                                    "SegmentTemplate@duration attribute cannot be zero".to_string()));
-                            }
-                            segment_duration = std / timescale as f64;
-                        }
-                        if segment_duration < 0.0 {
-                            return Err(DashMpdError::UnhandledMediaStream(
-                                "Video representation is missing SegmentTemplate@duration attribute".to_string()));
-                        }
-                        total_number += (period_duration_secs / segment_duration).round() as i64;
-                        let mut number = start_number;
-                        // For a live manifest (dynamic MPD), we look at the time elapsed since now
-                        // and the mpd.availabilityStartTime to determine the correct value for
-                        // startNumber, based on duration and timescale. The latest available
-                        // segment is numbered
-                        //
-                        //    LSN = floor((now - (availabilityStartTime+PST))/segmentDuration + startNumber - 1)
-
-                        // https://dashif.org/Guidelines-TimingModel/Timing-Model.pdf
-                        // To be more precise, any LeapSecondInformation should be added to the availabilityStartTime.
-                        if mpd_is_dynamic(mpd) {
-                            if let Some(start_time) = mpd.availabilityStartTime {
-                                let elapsed = Utc::now().signed_duration_since(start_time).as_seconds_f64() / segment_duration;
-                                number = (elapsed + number as f64 - 1f64).floor() as u64;
-                            } else {
-                                return Err(DashMpdError::UnhandledMediaStream(
-                                    "dynamic manifest is missing @availabilityStartTime".to_string()));
-                            }
-                        }
-                        if let Some(end_number) = st.endNumber {
-                            total_number = end_number as i64;
-                        }
-                        for _ in 1..=total_number {
-                            let dict = HashMap::from([("Number", number.to_string())]);
-                            let path = resolve_url_template(&video_path, &dict);
-                            let u = merge_baseurls(&base_url, &path)?;
-                            let mf = MediaFragmentBuilder::new(period_counter, u).build();
-                            fragments.push(mf);
-                            number += 1;
-                        }
-                    }
-                }
-            } else if let Some(sb) = &video_repr.SegmentBase {
-                // (5) SegmentBase@indexRange addressing mode
-                if downloader.verbosity > 1 {
-                    info!("  Using SegmentBase@indexRange addressing mode for video representation");
-                }
-                let mf = do_segmentbase_indexrange(downloader, period_counter, base_url, sb, &dict).await?;
-                fragments.extend(mf);
-            } else if fragments.is_empty()  {
-                if let Some(bu) = video_repr.BaseURL.first() {
-                    // (6) BaseURL addressing mode
-                    if downloader.verbosity > 1 {
-                        info!("  Using BaseURL addressing mode for video representation");
-                    }
-                    let u = merge_baseurls(&base_url, &bu.base)?;
-                    let mf = MediaFragmentBuilder::new(period_counter, u)
-                        .with_timeout(Duration::new(10000, 0))
-                        .build();
-                    fragments.push(mf);
-                }
-            }
-        if fragments.is_empty() {
-            return Err(DashMpdError::UnhandledMediaStream(
-                "no usable addressing mode identified for video representation".to_string()));
-        }
-    }
-    // FIXME we aren't correctly handling manifests without a Representation node
-    // eg https://raw.githubusercontent.com/zencoder/go-dash/master/mpd/fixtures/newperiod.mpd
-    Ok(PeriodOutputs {
-        fragments,
-        diagnostics,
-        subtitle_formats: Vec::new(),
-        selected_audio_language: String::from("unk"),
-        selected_subtitle_language: String::from(""),
-    })
-}
-
-#[tracing::instrument(level="trace", skip_all)]
-async fn do_period_subtitles(
-    downloader: &DashDownloader,
-    mpd: &MPD,
-    period: &Period,
-    period_counter: u8,
-    base_url: Url
-    ) -> Result<PeriodOutputs, DashMpdError>
-{
-    let client = downloader.http_client.as_ref().unwrap();
-    let output_path = &downloader.output_path.as_ref().unwrap().clone();
-    let period_output_path = output_path_for_period(output_path, period_counter);
-    let mut fragments = Vec::new();
-    let mut subtitle_formats = Vec::new();
-    let mut period_duration_secs: f64 = 0.0;
-    if let Some(d) = mpd.mediaPresentationDuration {
-        period_duration_secs = d.as_secs_f64();
-    }
-    if let Some(d) = period.duration {
-        period_duration_secs = d.as_secs_f64();
-    }
-    let maybe_subtitle_adaptation = if let Some(ref lang) = downloader.language_preference_subtitles {
-        period.adaptations.iter().filter(is_subtitle_adaptation)
-            .min_by_key(|a| adaptation_lang_distance(a, lang))
-    } else {
-        // returns the first subtitle adaptation found
-        period.adaptations.iter().find(is_subtitle_adaptation)
-    };
-    let mut subtitle_lang: Option<String> = None;
-    if downloader.fetch_subtitles {
-        if let Some(subtitle_adaptation) = maybe_subtitle_adaptation {
-            if let Some(lang) = subtitle_adaptation.lang.as_ref() {
-                subtitle_lang = Some(lang.clone());
-            }
-            let subtitle_format = subtitle_type(&subtitle_adaptation);
-            subtitle_formats.push(subtitle_format);
-            if downloader.verbosity > 1 && downloader.fetch_subtitles {
-                info!("  Retrieving subtitles in format {subtitle_format:?}");
-            }
-            // The AdaptationSet may have a BaseURL. We use a local variable to make sure we
-            // don't "corrupt" the base_url for the subtitle segments.
-            let mut base_url = base_url.clone();
-            if let Some(bu) = &subtitle_adaptation.BaseURL.first() {
-                base_url = merge_baseurls(&base_url, &bu.base)?;
-            }
-            // We don't do any ranking on subtitle Representations, because there is probably only a
-            // single one for our selected Adaptation.
-            if let Some(rep) = subtitle_adaptation.representations.first() {
-                if subtitle_lang.is_none() {
-                    if let Some(lang) = rep.lang.as_ref() {
-                        subtitle_lang = Some(lang.clone());
-                    }
-                }
-                if !rep.BaseURL.is_empty() {
-                    for st_bu in &rep.BaseURL {
-                        let st_url = merge_baseurls(&base_url, &st_bu.base)?;
-                        let mut req = client.get(st_url.clone());
-                        if let Some(referer) = &downloader.referer {
-                            req = req.header("Referer", referer);
-                        } else {
-                            req = req.header("Referer", base_url.to_string());
-                        }
-                        let rqw = req.build()
-                            .map_err(|e| network_error("building request", &e))?;
-                        let subs = reqwest_bytes_with_retries(client, rqw, 5).await
-                            .map_err(|e| network_error("fetching subtitles", &e))?;
-                        let mut subs_path = period_output_path.clone();
-                        let subtitle_format = subtitle_type(&subtitle_adaptation);
-                        match subtitle_format {
-                            SubtitleType::Vtt => subs_path.set_extension("vtt"),
-                            SubtitleType::Srt => subs_path.set_extension("srt"),
-                            SubtitleType::Sami => subs_path.set_extension("sami"),
-                            SubtitleType::Wvtt => subs_path.set_extension("wvtt"),
-                            SubtitleType::Ttml | SubtitleType::Stpp => subs_path.set_extension("ttml"),
-                            _ => subs_path.set_extension("sub"),
-                        };
-                        subtitle_formats.push(subtitle_format);
-                        let mut subs_file = File::create(subs_path.clone()).await
-                            .map_err(|e| DashMpdError::Io(e, String::from("creating subtitle file")))?;
-                        if downloader.verbosity > 2 {
-                            info!("  Subtitle {st_url} -> {} octets", subs.len());
-                        }
-                        match subs_file.write_all(&subs).await {
-                            Ok(()) => {
-                                if downloader.verbosity > 0 {
-                                    info!("  Downloaded subtitles ({subtitle_format:?}) to {}",
-                                             subs_path.display());
-                                }
-                            },
-                            Err(e) => {
-                                error!("Unable to write subtitle file: {e:?}");
-                                return Err(DashMpdError::Io(e, String::from("writing subtitle data")));
-                            },
-                        }
-                        if subtitle_formats.contains(&SubtitleType::Wvtt) ||
-                            subtitle_formats.contains(&SubtitleType::Ttxt)
-                        {
-                            if downloader.verbosity > 0 {
-                                info!("   Converting subtitles to SRT format with MP4Box ");
-                            }
-                            let out = subs_path.with_extension("srt");
-                            // We try to convert this to SRT format, which is more widely supported,
-                            // using MP4Box. However, it's not a fatal error if MP4Box is not
-                            // installed or the conversion fails.
-                            //
-                            // Could also try to convert to WebVTT with
-                            //   MP4Box -raw "0:output=output.vtt" input.mp4
-                            let out_str = out.to_string_lossy();
-                            let subs_str = subs_path.to_string_lossy();
-                            let args = vec![
-                                "-srt", "1",
-                                "-out", &out_str,
-                                &subs_str];
-                            if downloader.verbosity > 0 {
-                                info!("  Running MPBox {}", args.join(" "));
-                            }
-                            if let Ok(mp4box) = Command::new(downloader.mp4box_location.clone())
-                                .args(args)
-                                .output()
-                            {
-                                let msg = partial_process_output(&mp4box.stdout);
-                                if !msg.is_empty() {
-                                    info!("MP4Box stdout: {msg}");
-                                }
-                                let msg = partial_process_output(&mp4box.stderr);
-                                if !msg.is_empty() {
-                                    info!("MP4Box stderr: {msg}");
-                                }
-                                if mp4box.status.success() {
-                                    info!("   Converted subtitles to SRT");
-                                } else {
-                                    warn!("Error running MP4Box to convert subtitles");
-                                }
-                            }
-                        }
-                    }
-                } else if rep.SegmentTemplate.is_some() || subtitle_adaptation.SegmentTemplate.is_some() {
-                    let mut opt_init: Option<String> = None;
-                    let mut opt_media: Option<String> = None;
-                    let mut opt_duration: Option<f64> = None;
-                    let mut timescale = 1;
-                    let mut start_number = 1;
-                    // SegmentTemplate as a direct child of an Adaptation node. This can specify some common
-                    // attribute values (media, timescale, duration, startNumber) for child SegmentTemplate
-                    // nodes in an enclosed Representation node. Don't download media segments here, only
-                    // download for SegmentTemplate nodes that are children of a Representation node.
-                    if let Some(st) = &rep.SegmentTemplate {
-                        if let Some(i) = &st.initialization {
-                            opt_init = Some(i.clone());
-                        }
-                        if let Some(m) = &st.media {
-                            opt_media = Some(m.clone());
-                        }
-                        if let Some(d) = st.duration {
-                            opt_duration = Some(d);
-                        }
-                        if let Some(ts) = st.timescale {
-                            timescale = ts;
-                        }
-                        if let Some(s) = st.startNumber {
-                            start_number = s;
-                        }
-                    }
-                    let rid = match &rep.id {
-                        Some(id) => id,
-                        None => return Err(
-                            DashMpdError::UnhandledMediaStream(
-                                "Missing @id on Representation node".to_string())),
-                    };
-                    let mut dict = HashMap::from([("RepresentationID", rid.clone())]);
-                    if let Some(b) = &rep.bandwidth {
-                        dict.insert("Bandwidth", b.to_string());
-                    }
-                    // Now the 6 possible addressing modes: (1) SegmentList,
-                    // (2) SegmentTemplate+SegmentTimeline, (3) SegmentTemplate@duration,
-                    // (4) SegmentTemplate@index, (5) SegmentBase@indexRange, (6) plain BaseURL
-                    if let Some(sl) = &rep.SegmentList {
-                        // (1) AdaptationSet>SegmentList addressing mode (can be used in conjunction
-                        // with Representation>SegmentList addressing mode)
-                        if downloader.verbosity > 1 {
-                            info!("  Using AdaptationSet>SegmentList addressing mode for subtitle representation");
-                        }
-                        let mut start_byte: Option<u64> = None;
-                        let mut end_byte: Option<u64> = None;
-                        if let Some(init) = &sl.Initialization {
-                            if let Some(range) = &init.range {
-                                let (s, e) = parse_range(range)?;
-                                start_byte = Some(s);
-                                end_byte = Some(e);
-                            }
-                            if let Some(su) = &init.sourceURL {
-                                let path = resolve_url_template(su, &dict);
-                                let u = merge_baseurls(&base_url, &path)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .with_range(start_byte, end_byte)
-                                    .set_init()
-                                    .build();
-                                fragments.push(mf);
-                            } else {
-                                let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
-                                    .with_range(start_byte, end_byte)
-                                    .set_init()
-                                    .build();
-                                fragments.push(mf);
-                            }
-                        }
-                        for su in &sl.segment_urls {
-                            start_byte = None;
-                            end_byte = None;
-                            // we are ignoring SegmentURL@indexRange
-                            if let Some(range) = &su.mediaRange {
-                                let (s, e) = parse_range(range)?;
-                                start_byte = Some(s);
-                                end_byte = Some(e);
-                            }
-                            if let Some(m) = &su.media {
-                                let u = merge_baseurls(&base_url, m)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .with_range(start_byte, end_byte)
-                                    .build();
-                                fragments.push(mf);
-                            } else if let Some(bu) = subtitle_adaptation.BaseURL.first() {
-                                let u = merge_baseurls(&base_url, &bu.base)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .with_range(start_byte, end_byte)
-                                    .build();
-                                fragments.push(mf);
-                            }
-                        }
-                    }
-                    if let Some(sl) = &rep.SegmentList {
-                        // (1) Representation>SegmentList addressing mode
-                        if downloader.verbosity > 1 {
-                            info!("  Using Representation>SegmentList addressing mode for subtitle representation");
-                        }
-                        let mut start_byte: Option<u64> = None;
-                        let mut end_byte: Option<u64> = None;
-                        if let Some(init) = &sl.Initialization {
-                            if let Some(range) = &init.range {
-                                let (s, e) = parse_range(range)?;
-                                start_byte = Some(s);
-                                end_byte = Some(e);
-                            }
-                            if let Some(su) = &init.sourceURL {
-                                let path = resolve_url_template(su, &dict);
-                                let u = merge_baseurls(&base_url, &path)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .with_range(start_byte, end_byte)
-                                    .set_init()
-                                    .build();
-                                fragments.push(mf);
-                            } else {
-                                let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
-                                    .with_range(start_byte, end_byte)
-                                    .set_init()
-                                    .build();
-                                fragments.push(mf);
-                            }
-                        }
-                        for su in &sl.segment_urls {
-                            start_byte = None;
-                            end_byte = None;
-                            // we are ignoring SegmentURL@indexRange
-                            if let Some(range) = &su.mediaRange {
-                                let (s, e) = parse_range(range)?;
-                                start_byte = Some(s);
-                                end_byte = Some(e);
-                            }
-                            if let Some(m) = &su.media {
-                                let u = merge_baseurls(&base_url, m)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .with_range(start_byte, end_byte)
-                                    .build();
-                                fragments.push(mf);
-                            } else if let Some(bu) = &rep.BaseURL.first() {
-                                let u = merge_baseurls(&base_url, &bu.base)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .with_range(start_byte, end_byte)
-                                    .build();
-                                fragments.push(mf);
-                            }
-                        }
-                    } else if rep.SegmentTemplate.is_some() ||
-                        subtitle_adaptation.SegmentTemplate.is_some()
-                    {
-                        // Here we are either looking at a Representation.SegmentTemplate, or a
-                        // higher-level AdaptationSet.SegmentTemplate
-                        let st;
-                        if let Some(it) = &rep.SegmentTemplate {
-                            st = it;
-                        } else if let Some(it) = &subtitle_adaptation.SegmentTemplate {
-                            st = it;
-                        } else {
-                            panic!("unreachable");
-                        }
-                        if let Some(i) = &st.initialization {
-                            opt_init = Some(i.clone());
-                        }
-                        if let Some(m) = &st.media {
-                            opt_media = Some(m.clone());
-                        }
-                        if let Some(ts) = st.timescale {
-                            timescale = ts;
-                        }
-                        if let Some(sn) = st.startNumber {
-                            start_number = sn;
-                        }
-                        if let Some(stl) = &rep.SegmentTemplate.as_ref()
-                            .and_then(|st| st.SegmentTimeline.clone())
-                            .or(subtitle_adaptation.SegmentTemplate.as_ref().and_then(|st| st.SegmentTimeline.clone()))
-                        {
-                            // (2) SegmentTemplate with SegmentTimeline addressing mode (also called
-                            // "explicit addressing" in certain DASH-IF documents)
-                            if downloader.verbosity > 1 {
-                                info!("  Using SegmentTemplate+SegmentTimeline addressing mode for subtitle representation");
-                            }
-                            if let Some(init) = opt_init {
-                                let path = resolve_url_template(&init, &dict);
-                                let u = merge_baseurls(&base_url, &path)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .set_init()
-                                    .build();
-                                fragments.push(mf);
-                            }
-                            if let Some(media) = opt_media {
-                                let sub_path = resolve_url_template(&media, &dict);
-                                let mut segment_time = 0;
-                                let mut segment_duration;
-                                let mut number = start_number;
-                                for s in &stl.segments {
-                                    if let Some(t) = s.t {
-                                        segment_time = t;
-                                    }
-                                    segment_duration = s.d;
-                                    // the URLTemplate may be based on $Time$, or on $Number$
-                                    let dict = HashMap::from([("Time", segment_time.to_string()),
-                                                              ("Number", number.to_string())]);
-                                    let path = resolve_url_template(&sub_path, &dict);
-                                    let u = merge_baseurls(&base_url, &path)?;
-                                    let mf = MediaFragmentBuilder::new(period_counter, u).build();
-                                    fragments.push(mf);
-                                    number += 1;
-                                    if let Some(r) = s.r {
-                                        let mut count = 0i64;
-                                        // FIXME perhaps we also need to account for startTime?
-                                        let end_time = period_duration_secs * timescale as f64;
-                                        loop {
-                                            count += 1;
-                                            // Exit from the loop after @r iterations (if @r is
-                                            // positive). A negative value of the @r attribute indicates
-                                            // that the duration indicated in @d attribute repeats until
-                                            // the start of the next S element, the end of the Period or
-                                            // until the next MPD update.
-                                            if r >= 0 {
-                                                if count > r {
-                                                    break;
-                                                }
-                                                if downloader.force_duration.is_some() &&
-                                                    segment_time as f64 > end_time
-                                                {
-                                                    break;
-                                                }
-                                            } else if segment_time as f64 > end_time {
-                                                break;
-                                            }
-                                            if let Some(end_number) = st.endNumber {
-                                                if count as u64 > end_number {
-                                                    break;
-                                                }
-                                            }
-                                            segment_time += segment_duration;
-                                            let dict = HashMap::from([("Time", segment_time.to_string()),
-                                                                      ("Number", number.to_string())]);
-                                            let path = resolve_url_template(&sub_path, &dict);
-                                            let u = merge_baseurls(&base_url, &path)?;
-                                            let mf = MediaFragmentBuilder::new(period_counter, u).build();
-                                            fragments.push(mf);
-                                            number += 1;
+                                        if downloader.force_duration.is_some() &&
+                                            segment_time as f64 > end_time
+                                        {
+                                            break;
                                         }
+                                    } else if segment_time as f64 > end_time {
+                                        break;
                                     }
-                                    segment_time += segment_duration;
-                                }
-                            } else {
-                                return Err(DashMpdError::UnhandledMediaStream(
-                                    "SegmentTimeline without a media attribute".to_string()));
-                            }
-                        } else { // no SegmentTimeline element
-                            // (3) SegmentTemplate@duration addressing mode or (4) SegmentTemplate@index
-                            // addressing mode (also called "simple addressing" in certain DASH-IF
-                            // documents)
-                            if downloader.verbosity > 0 {
-                                info!("  Using SegmentTemplate addressing mode for stpp subtitles");
-                            }
-                            if let Some(i) = &st.initialization {
-                                opt_init = Some(i.clone());
-                            }
-                            if let Some(m) = &st.media {
-                                opt_media = Some(m.clone());
-                            }
-                            if let Some(d) = st.duration {
-                                opt_duration = Some(d);
-                            }
-                            if let Some(ts) = st.timescale {
-                                timescale = ts;
-                            }
-                            if let Some(s) = st.startNumber {
-                                start_number = s;
-                            }
-                            let rid = match &rep.id {
-                                Some(id) => id,
-                                None => return Err(
-                                    DashMpdError::UnhandledMediaStream(
-                                        "Missing @id on Representation node".to_string())),
-                            };
-                            let mut dict = HashMap::from([("RepresentationID", rid.clone())]);
-                            if let Some(b) = &rep.bandwidth {
-                                dict.insert("Bandwidth", b.to_string());
-                            }
-                            let mut total_number = 0i64;
-                            if let Some(init) = opt_init {
-                                let path = resolve_url_template(&init, &dict);
-                                let u = merge_baseurls(&base_url, &path)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .set_init()
-                                    .build();
-                                fragments.push(mf);
-                            }
-                            if let Some(media) = opt_media {
-                                let sub_path = resolve_url_template(&media, &dict);
-                                let mut segment_duration: f64 = -1.0;
-                                if let Some(d) = opt_duration {
-                                    // it was set on the Period.SegmentTemplate node
-                                    segment_duration = d;
-                                }
-                                if let Some(std) = st.duration {
-                                    if timescale == 0 {
-                                        return Err(DashMpdError::UnhandledMediaStream(
-                                            "SegmentTemplate@duration attribute cannot be zero".to_string()));
+                                    if let Some(end_number) = st.endNumber {
+                                        if count as u64 > end_number {
+                                            break;
+                                        }
                                     }
-                                    segment_duration = std / timescale as f64;
-                                }
-                                if segment_duration < 0.0 {
-                                    return Err(DashMpdError::UnhandledMediaStream(
-                                        "Subtitle representation is missing SegmentTemplate@duration".to_string()));
-                                }
-                                total_number += (period_duration_secs / segment_duration).ceil() as i64;
-                                if let Some(end_number) = st.endNumber {
-                                    total_number = end_number as i64;
-                                }
-                                let mut number = start_number;
-                                #[allow(clippy::explicit_counter_loop)]
-                                for _ in 1..=total_number {
-                                    let dict = HashMap::from([("Number", number.to_string())]);
+                                    segment_time += segment_duration;
+                                    let dict = HashMap::from([("Time", segment_time.to_string()),
+                                                              ("Number", number.to_string())]);
                                     let path = resolve_url_template(&sub_path, &dict);
                                     let u = merge_baseurls(&base_url, &path)?;
                                     let mf = MediaFragmentBuilder::new(period_counter, u).build();
@@ -3827,49 +6164,121 @@ async fn do_period_subtitles(
                                     number += 1;
                                 }
                             }
+                            segment_time += segment_duration;
                         }
-                    } else if let Some(sb) = &rep.SegmentBase {
-                        // SegmentBase@indexRange addressing mode
-                        info!("  Using SegmentBase@indexRange for subs");
-                        if downloader.verbosity > 1 {
-                            info!("  Using SegmentBase@indexRange addressing mode for subtitle representation");
+                    } else {
+                        return Err(DashMpdError::UnhandledMediaStream(
+                            "SegmentTimeline without a media attribute".to_string()));
+                    }
+                } else { // no SegmentTimeline element
+                    // (3) SegmentTemplate@duration addressing mode or (4) SegmentTemplate@index
+                    // addressing mode (also called "simple addressing" in certain DASH-IF
+                    // documents)
+                    if downloader.verbosity > 0 {
+                        info!("  Using SegmentTemplate addressing mode for stpp subtitles");
+                    }
+                    if let Some(i) = &st.initialization {
+                        opt_init = Some(i.clone());
+                    }
+                    if let Some(m) = &st.media {
+                        opt_media = Some(m.clone());
+                    }
+                    if let Some(d) = st.duration {
+                        opt_duration = Some(d);
+                    }
+                    if let Some(ts) = st.timescale {
+                        timescale = ts;
+                    }
+                    if let Some(s) = st.startNumber {
+                        start_number = s;
+                    }
+                    let rid = match &rep.id {
+                        Some(id) => id,
+                        None => return Err(
+                            DashMpdError::UnhandledMediaStream(
+                                "Missing @id on Representation node".to_string())),
+                    };
+                    let mut dict = HashMap::from([("RepresentationID", rid.clone())]);
+                    if let Some(b) = &rep.bandwidth {
+                        dict.insert("Bandwidth", b.to_string());
+                    }
+                    let mut total_number = 0i64;
+                    if let Some(init) = opt_init {
+                        let path = resolve_url_template(&init, &dict);
+                        let u = merge_baseurls(&base_url, &path)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .set_init()
+                            .build();
+                        fragments.push(mf);
+                    }
+                    if let Some(media) = opt_media {
+                        let sub_path = resolve_url_template(&media, &dict);
+                        let mut segment_duration: f64 = -1.0;
+                        if let Some(d) = opt_duration {
+                            // it was set on the Period.SegmentTemplate node
+                            segment_duration = d;
                         }
-                        let mut start_byte: Option<u64> = None;
-                        let mut end_byte: Option<u64> = None;
-                        if let Some(init) = &sb.Initialization {
-                            if let Some(range) = &init.range {
-                                let (s, e) = parse_range(range)?;
-                                start_byte = Some(s);
-                                end_byte = Some(e);
-                            }
-                            if let Some(su) = &init.sourceURL {
-                                let path = resolve_url_template(su, &dict);
-                                let u = merge_baseurls(&base_url, &path)?;
-                                let mf = MediaFragmentBuilder::new(period_counter, u)
-                                    .with_range(start_byte, end_byte)
-                                    .set_init()
-                                    .build();
-                                fragments.push(mf);
+                        if let Some(std) = st.duration {
+                            if timescale == 0 {
+                                return Err(DashMpdError::UnhandledMediaStream(
+                                    "SegmentTemplate@duration attribute cannot be zero".to_string()));
                             }
+                            segment_duration = std / timescale as f64;
                         }
-                        let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                        if segment_duration < 0.0 {
+                            return Err(DashMpdError::UnhandledMediaStream(
+                                "Subtitle representation is missing SegmentTemplate@duration".to_string()));
+                        }
+                        total_number += (period_duration_secs / segment_duration).ceil() as i64;
+                        if let Some(end_number) = st.endNumber {
+                            total_number = end_number as i64;
+                        }
+                        let mut number = start_number;
+                        #[allow(clippy::explicit_counter_loop)]
+                        for _ in 1..=total_number {
+                            let dict = HashMap::from([("Number", number.to_string())]);
+                            let path = resolve_url_template(&sub_path, &dict);
+                            let u = merge_baseurls(&base_url, &path)?;
+                            let mf = MediaFragmentBuilder::new(period_counter, u).build();
+                            fragments.push(mf);
+                            number += 1;
+                        }
+                    }
+                }
+            } else if let Some(sb) = &rep.SegmentBase {
+                // SegmentBase@indexRange addressing mode
+                info!("  Using SegmentBase@indexRange for subs");
+                if downloader.verbosity > 1 {
+                    info!("  Using SegmentBase@indexRange addressing mode for subtitle representation");
+                }
+                let mut start_byte: Option<u64> = None;
+                let mut end_byte: Option<u64> = None;
+                if let Some(init) = &sb.Initialization {
+                    if let Some(range) = &init.range {
+                        let (s, e) = parse_range(range)?;
+                        start_byte = Some(s);
+                        end_byte = Some(e);
+                    }
+                    if let Some(su) = &init.sourceURL {
+                        let path = resolve_url_template(su, &dict);
+                        let u = merge_baseurls(&base_url, &path)?;
+                        let mf = MediaFragmentBuilder::new(period_counter, u)
+                            .with_range(start_byte, end_byte)
                             .set_init()
                             .build();
                         fragments.push(mf);
-                        // TODO also implement SegmentBase addressing mode for subtitles
-                        // (sample MPD: https://usp-cmaf-test.s3.eu-central-1.amazonaws.com/tears-of-steel-ttml.mpd)
                     }
                 }
+                let mf = MediaFragmentBuilder::new(period_counter, base_url.clone())
+                    .set_init()
+                    .build();
+                fragments.push(mf);
+                // TODO also implement SegmentBase addressing mode for subtitles
+                // (sample MPD: https://usp-cmaf-test.s3.eu-central-1.amazonaws.com/tears-of-steel-ttml.mpd)
             }
         }
     }
-    Ok(PeriodOutputs {
-        fragments,
-        diagnostics: Vec::new(),
-        subtitle_formats,
-        selected_audio_language: String::from("unk"),
-        selected_subtitle_language: subtitle_lang.unwrap_or_else(|| String::from("unk")),
-    })
+    Ok((fragments, subtitle_formats))
 }
 
 
@@ -3879,7 +6288,15 @@ struct DownloadState {
     period_counter: u8,
     segment_count: usize,
     segment_counter: usize,
-    download_errors: u32
+    download_errors: u32,
+    // WebVTT subtitles accumulated over all Periods, so that each subtitle file covers the complete
+    // output media. Indexed by the output path from which the subtitle sidecar files are named.
+    vtt_documents: HashMap<PathBuf, crate::vtt::VttDocument>,
+    // Likewise for STPP subtitles, and for WVTT subtitles which are saved once all Periods have been
+    // fetched. A WVTT document is None once a segment can't be parsed, in which case the subtitles
+    // are extracted with MP4Box instead.
+    stpp_documents: HashMap<PathBuf, crate::stpp::StppDocument>,
+    wvtt_documents: HashMap<PathBuf, Option<crate::wvtt::WvttDocument>>,
 }
 
 // Fetch a media fragment at URL frag.url, using the reqwest client in downloader.http_client.
@@ -3943,8 +6360,10 @@ async fn fetch_fragment(
                         content_type_audio_p
                     } else if fragment_type.eq("video") {
                         content_type_video_p
+                    } else if fragment_type.eq("image") {
+                        content_type_image_p
                     } else {
-                        panic!("fragment_type not audio or video");
+                        panic!("fragment_type not audio, video or image");
                     };
                     if !downloader.content_type_checks || content_type_checker(&resp) {
                         let mut fragment_out: Option<File> = None;
@@ -4015,6 +6434,26 @@ async fn fetch_fragment(
 }
 
 
+// The path at which to keep audio track number track (counting from zero) when the user has asked
+// to keep the audio stream at path keep. Tracks other than the first are saved alongside it with
+// their language inserted before the extension, or their track number if that would clash with a
+// path that is already used.
+fn keep_audio_track_path(keep: &Path, track: usize, language: &str, used: &[PathBuf]) -> PathBuf {
+    let ext = keep.extension().unwrap_or(OsStr::new("mp4"));
+    let mut candidate = keep.with_extension("");
+    if let Some(lang) = language_label(language) {
+        candidate.as_mut_os_string().push(format!(".{lang}"));
+    }
+    candidate.as_mut_os_string().push(".");
+    candidate.as_mut_os_string().push(ext);
+    if used.contains(&candidate) || candidate == keep {
+        candidate = keep.with_extension("");
+        candidate.as_mut_os_string().push(format!(".{}.", track + 1));
+        candidate.as_mut_os_string().push(ext);
+    }
+    candidate
+}
+
 // Retrieve the audio segments for period `period_counter` and concatenate them to a file at tmppath.
 #[tracing::instrument(level="trace", skip_all)]
 async fn fetch_period_audio(
@@ -4122,19 +6561,7 @@ async fn fetch_period_audio(
             .extension()
             .unwrap_or(OsStr::new("mp4"));
         let decrypted = tmp_file_path("dashmpd-decrypted-audio", out_ext)?;
-        if downloader.decryptor_preference.eq("mp4decrypt") {
-            decrypt_mp4decrypt(downloader, tmppath, &decrypted, "audio").await?;
-        } else if downloader.decryptor_preference.eq("shaka") {
-            decrypt_shaka(downloader, tmppath, &decrypted, "audio").await?;
-        } else if downloader.decryptor_preference.eq("shaka-container") {
-            decrypt_shaka_container(downloader, tmppath, &decrypted, "audio").await?;
-        } else if downloader.decryptor_preference.eq("mp4box") {
-            decrypt_mp4box(downloader, tmppath, &decrypted, "audio").await?;
-        } else if downloader.decryptor_preference.eq("mp4box-container") {
-            decrypt_mp4box_container(downloader, tmppath, &decrypted, "audio").await?;
-        } else {
-            return Err(DashMpdError::Decrypting(String::from("unknown decryption application")));
-        }
+        decrypt_media(downloader, tmppath, &decrypted, "audio").await?;
         if let Err(e) = fs::metadata(&decrypted).await {
             return Err(DashMpdError::Decrypting(format!("missing decrypted audio file: {e:?}")));
         }
@@ -4260,19 +6687,7 @@ async fn fetch_period_video(
             .extension()
             .unwrap_or(OsStr::new("mp4"));
         let decrypted = tmp_file_path("dashmpd-decrypted-video", out_ext)?;
-        if downloader.decryptor_preference.eq("mp4decrypt") {
-            decrypt_mp4decrypt(downloader, tmppath, &decrypted, "video").await?;
-        } else if downloader.decryptor_preference.eq("shaka") {
-            decrypt_shaka(downloader, tmppath, &decrypted, "video").await?;
-        } else if downloader.decryptor_preference.eq("shaka-container") {
-            decrypt_shaka_container(downloader, tmppath, &decrypted, "video").await?;
-        } else if downloader.decryptor_preference.eq("mp4box") {
-            decrypt_mp4box(downloader, tmppath, &decrypted, "video").await?;
-        } else if downloader.decryptor_preference.eq("mp4box-container") {
-            decrypt_mp4box_container(downloader, tmppath, &decrypted, "video").await?;
-        } else {
-            return Err(DashMpdError::Decrypting(String::from("unknown decryption application")));
-        }
+        decrypt_media(downloader, tmppath, &decrypted, "video").await?;
         if let Err(e) = fs::metadata(&decrypted).await {
             return Err(DashMpdError::Decrypting(format!("missing decrypted video file: {e:?}")));
         }
@@ -4293,28 +6708,233 @@ async fn fetch_period_video(
 }
 
 
-// Retrieve the video segments for period `ds.period_counter` and concatenate them to a file at `tmppath`.
+// Fetch the tile images of a thumbnail track, saving them in a directory named after the output
+// file with a ".thumbnails" extension, and add the WebVTT cues referencing each thumbnail to cues.
+// offset_ms is the start of the Period on the timeline of the output media.
+async fn fetch_period_thumbnails(
+    downloader: &mut DashDownloader,
+    output_path: &Path,
+    fragments: &[MediaFragment],
+    thumbnails: &SelectedThumbnails,
+    offset_ms: i64,
+    cues: &mut Vec<VttCue>,
+    ds: &mut DownloadState) -> Result<bool, DashMpdError>
+{
+    let dir = output_path.with_extension("thumbnails");
+    fs::create_dir_all(&dir).await
+        .map_err(|e| DashMpdError::Io(e, String::from("creating thumbnails directory")))?;
+    let dirname = dir.file_name()
+        .map_or_else(|| String::from("thumbnails"), |n| n.to_string_lossy().to_string());
+    let mut saved = 0;
+    for (i, frag) in fragments.iter().enumerate() {
+        ds.segment_counter += 1;
+        let progress_percent = min(98, (100.0 * ds.segment_counter as f32 / ds.segment_count as f32).ceil() as u32);
+        let mut image = Vec::new();
+        if frag.url.scheme() == "data" {
+            let us = &frag.url.to_string();
+            let du = DataUrl::process(us)
+                .map_err(|_| DashMpdError::Parsing(String::from("parsing data URL")))?;
+            let (body, _fragment) = du.decode_to_vec()
+                .map_err(|_| DashMpdError::Parsing(String::from("decoding data URL")))?;
+            image = body;
+        } else {
+            'done: for _ in 0..downloader.fragment_retry_count {
+                match fetch_fragment(downloader, frag, "image", progress_percent).await {
+                    Ok(mut frag_file) => {
+                        frag_file.rewind().await
+                            .map_err(|e| DashMpdError::Io(e, String::from("rewinding fragment tempfile")))?;
+                        frag_file.read_to_end(&mut image).await
+                            .map_err(|e| DashMpdError::Io(e, String::from("reading fragment tempfile")))?;
+                        break 'done;
+                    },
+                    Err(e) => {
+                        if downloader.verbosity > 0 {
+                            error!("  Error fetching thumbnail segment {}: {e:?}", frag.url);
+                        }
+                        ds.download_errors += 1;
+                        if ds.download_errors > downloader.max_error_count {
+                            return Err(DashMpdError::Network(
+                                String::from("more than max_error_count network errors")));
+                        }
+                    },
+                }
+                info!("  Retrying thumbnail segment {}", frag.url);
+                if downloader.sleep_between_requests > 0 {
+                    tokio::time::sleep(Duration::new(downloader.sleep_between_requests.into(), 0)).await;
+                }
+            }
+        }
+        if image.is_empty() {
+            continue;
+        }
+        let name = format!("tile-{}-{:05}.{}", ds.period_counter, i + 1, thumbnails.extension);
+        fs::write(dir.join(&name), &image).await
+            .map_err(|e| DashMpdError::Io(e, String::from("writing thumbnail image")))?;
+        if let Some((start_ms, duration_ms)) = thumbnails.tile_times.get(i) {
+            let start_ms = u64::try_from(offset_ms + *start_ms as i64).unwrap_or(0);
+            cues.extend(tile_cues(&format!("{dirname}/{name}"), start_ms, *duration_ms,
+                                  thumbnails.grid, thumbnails.width, thumbnails.height));
+        }
+        saved += 1;
+    }
+    if downloader.verbosity > 1 {
+        info!("  Saved {saved} thumbnail images to {}", dir.display());
+    }
+    Ok(saved > 0)
+}
+
+// Download each SRD tile of this Period to a separate file in the .tiles directory, and record its
+// position in the source in the tile index.
+async fn fetch_period_tiles(
+    downloader: &mut DashDownloader,
+    output_path: &Path,
+    fragments: &[MediaFragment],
+    tiles: &[SelectedTile],
+    index: &mut Vec<serde_json::Value>,
+    ds: &mut DownloadState) -> Result<bool, DashMpdError>
+{
+    let dir = output_path.with_extension("tiles");
+    fs::create_dir_all(&dir).await
+        .map_err(|e| DashMpdError::Io(e, String::from("creating SRD tiles directory")))?;
+    let mut saved = 0;
+    for (track, tile) in tiles.iter().enumerate() {
+        let tile_fragments: Vec<MediaFragment> = fragments.iter()
+            .filter(|f| usize::from(f.track) == track)
+            .cloned()
+            .collect();
+        let name = format!("tile-{}-{:02}.{}", ds.period_counter, track + 1, tile.extension);
+        if fetch_period_video(downloader, &dir.join(&name), &tile_fragments, ds).await? {
+            index.push(serde_json::json!({
+                "period": ds.period_counter,
+                "file": name,
+                "adaptation_id": tile.adaptation_id,
+                "representation_id": tile.representation_id,
+                "source_id": tile.srd.source_id,
+                "object_x": tile.srd.object_x,
+                "object_y": tile.srd.object_y,
+                "object_width": tile.srd.object_width,
+                "object_height": tile.srd.object_height,
+                "total_width": tile.reference_size.0,
+                "total_height": tile.reference_size.1,
+                "spatial_set_id": tile.srd.spatial_set_id,
+            }));
+            saved += 1;
+        }
+    }
+    if downloader.verbosity > 1 {
+        info!("  Saved {saved} SRD tiles to {}", dir.display());
+    }
+    Ok(saved > 0)
+}
+
+// Write the cues extracted from WVTT subtitles in WebVTT and SRT format, to files with the same
+// basename as path.
+async fn save_wvtt_subtitles(
+    downloader: &DashDownloader,
+    wvtt: &crate::wvtt::WvttDocument,
+    path: &Path) -> Result<(), DashMpdError>
+{
+    let vtt_path = path.with_extension("vtt");
+    fs::write(&vtt_path, wvtt.to_webvtt()).await
+        .map_err(|e| DashMpdError::Io(e, String::from("writing VTT subtitles")))?;
+    let srt_path = path.with_extension("srt");
+    fs::write(&srt_path, wvtt.to_srt()).await
+        .map_err(|e| DashMpdError::Io(e, String::from("writing SRT subtitles")))?;
+    if downloader.verbosity > 0 {
+        info!("  Converted {} WVTT subtitle cues to {} and {}",
+              wvtt.cues().len(), vtt_path.display(), srt_path.display());
+    }
+    Ok(())
+}
+
+// Write WebVTT and SRT conversions of TTML subtitles to files with the same basename as path. Image
+// subtitles (IMSC1 image profile) are written as PNG files in a directory with the same basename,
+// together with an index.vtt file whose cues contain the name of the image file to display.
+async fn save_ttml_conversions(
+    downloader: &DashDownloader,
+    ttml: &crate::stpp::StppDocument,
+    path: &Path) -> Result<(), DashMpdError>
+{
+    let cues = ttml.cues();
+    if !cues.is_empty() {
+        let vtt_path = path.with_extension("vtt");
+        fs::write(&vtt_path, crate::vtt::cues_to_webvtt("WEBVTT", &cues)).await
+            .map_err(|e| DashMpdError::Io(e, String::from("writing VTT subtitles")))?;
+        let srt_path = path.with_extension("srt");
+        fs::write(&srt_path, crate::vtt::cues_to_srt(&cues)).await
+            .map_err(|e| DashMpdError::Io(e, String::from("writing SRT subtitles")))?;
+        if downloader.verbosity > 0 {
+            info!("  Converted {} TTML subtitle cues to {} and {}",
+                  cues.len(), vtt_path.display(), srt_path.display());
+        }
+    }
+    if !ttml.images().is_empty() {
+        let dir = path.with_extension("subtitle-images");
+        fs::create_dir_all(&dir).await
+            .map_err(|e| DashMpdError::Io(e, String::from("creating subtitle image directory")))?;
+        let mut index = Vec::new();
+        for (i, image) in ttml.images().iter().enumerate() {
+            let name = format!("{:04}.png", i + 1);
+            fs::write(dir.join(&name), &image.png).await
+                .map_err(|e| DashMpdError::Io(e, String::from("writing subtitle image")))?;
+            index.push(crate::vtt::VttCue {
+                identifier: None,
+                start_ms: image.start_ms,
+                end_ms: image.end_ms,
+                settings: image.settings.clone(),
+                payload: name,
+            });
+        }
+        fs::write(dir.join("index.vtt"), crate::vtt::cues_to_webvtt("WEBVTT", &index)).await
+            .map_err(|e| DashMpdError::Io(e, String::from("writing subtitle image index")))?;
+        if downloader.verbosity > 0 {
+            info!("  Wrote {} subtitle images to {}", index.len(), dir.display());
+        }
+    }
+    Ok(())
+}
+
+// Retrieve the subtitle segments for period `ds.period_counter` and concatenate them to a file at
+// `tmppath`. Subtitle sidecar files are named after `output_path`. Cue times are shifted by
+// `offset_ms`, the start of the Period in the output media.
 #[tracing::instrument(level="trace", skip_all)]
 async fn fetch_period_subtitles(
     downloader: &DashDownloader,
     tmppath: &Path,
     subtitle_fragments: &[MediaFragment],
-    subtitle_formats: &[SubtitleType],
+    subtitles: &SelectedSubtitles,
+    offset_ms: i64,
     ds: &mut DownloadState) -> Result<bool, DashMpdError>
 {
     use crate::stpp::StppDocument;
-    use crate::vtt::VttDocument;
-    
+    use crate::wvtt::WvttDocument;
+
     let client = downloader.http_client.clone().unwrap();
+    let subtitle_formats = &subtitles.formats;
+    let output_path = subtitles.output_path.as_path();
     let start_download = Instant::now();
     let mut have_subtitles = false;
+    // Only used if subtitle_formats contains SubtitleFormat::Stpp. The TTML document for this
+    // Period is embedded in the Period's media, and all Periods are accumulated in stpp_all.
+    let mut stpp_document = StppDocument::new();
+    let mut stpp_all = ds.stpp_documents.remove(output_path).unwrap_or_default();
+    stpp_all.set_offset_ms(offset_ms);
+    // Only used if subtitle_formats contains SubtitleFormat::Wvtt
+    let mut wvtt_document = ds.wvtt_documents.remove(output_path)
+        .unwrap_or_else(|| Some(WvttDocument::new()));
+    if let Some(wvtt) = wvtt_document.as_mut() {
+        if let Some(ts) = subtitles.timescale {
+            wvtt.set_timescale(ts);
+        }
+        wvtt.set_offset_ms(offset_ms);
+    }
+    let mut wvtt_failed = wvtt_document.is_none();
+    // Only used if subtitle_formats contains SubtitleFormat::Vtt
+    let mut vtt_document = ds.vtt_documents.remove(output_path).unwrap_or_default();
+    vtt_document.set_offset_ms(offset_ms);
     {
         let tmpfile_subs = File::create(tmppath).await
             .map_err(|e| DashMpdError::Io(e, String::from("creating subs tmpfile")))?;
-        // Only used if subtitle_formats contains SubtitleFormat::Stpp
-        let mut stpp_document = StppDocument::new();
-        // Only used if subtitle_formats contains SubtitleFormat::Vtt
-        let mut vtt_document = VttDocument::new();
         ensure_permissions_readable(tmppath).await?;
         let mut tmpfile_subs = BufWriter::new(tmpfile_subs);
         for frag in subtitle_fragments {
@@ -4337,6 +6957,13 @@ async fn fetch_period_subtitles(
                 if downloader.verbosity > 2 {
                     info!("  Subtitle segment data URL -> {} octets", body.len());
                 }
+                if subtitle_formats.contains(&SubtitleType::Wvtt) {
+                    if let Some(Err(e)) = wvtt_document.as_mut().map(|w| w.add_from_mp4(&body)) {
+                        warn!("  Unable to parse WVTT subtitle segment: {e}");
+                        wvtt_document = None;
+                        wvtt_failed = true;
+                    }
+                }
                 tmpfile_subs.write_all(&body)
                     .map_err(|e| DashMpdError::Io(e, String::from("writing DASH subtitle data")))
                     .await?;
@@ -4391,9 +7018,18 @@ async fn fetch_period_subtitles(
                             throttle_download_rate(downloader, size).await?;
                             if subtitle_formats.contains(&SubtitleType::Stpp) {
                                 stpp_document.add_from_mp4(&content_bytes)?;
-                                // TODO: likewise handle fMP4 segments that contain WebVTT
-                                // (codec=wvtt), using vttc boxes for text cues and vtte boxes for
-                                // empty samples.
+                                stpp_all.add_from_mp4(&content_bytes)?;
+                            } else if subtitle_formats.contains(&SubtitleType::Wvtt) {
+                                // We convert natively to WebVTT and SRT, but also save the fMP4
+                                // content so that we can fall back to MP4Box if our conversion fails.
+                                if let Some(Err(e)) = wvtt_document.as_mut().map(|w| w.add_from_mp4(&content_bytes)) {
+                                    warn!("  Unable to parse WVTT subtitle segment: {e}");
+                                    wvtt_document = None;
+                                    wvtt_failed = true;
+                                }
+                                tmpfile_subs.write_all(&content_bytes)
+                                    .map_err(|e| DashMpdError::Io(e, String::from("writing DASH subtitle data")))
+                                    .await?;
                             } else if subtitle_formats.contains(&SubtitleType::Vtt) {
                                 vtt_document.add_bytes(&content_bytes)?;
                             } else {
@@ -4455,9 +7091,14 @@ async fn fetch_period_subtitles(
         }
         // TODO: for subtitle_formats sub and srt we could also try to embed them in the output
         // file, for example using MP4Box or mkvmerge
-        if subtitle_formats.contains(&SubtitleType::Wvtt) ||
-           subtitle_formats.contains(&SubtitleType::Ttxt)
-        {
+        // WVTT subtitles that we have converted natively are saved once all Periods have been
+        // fetched, by save_accumulated_subtitles().
+        let use_mp4box = if subtitle_formats.contains(&SubtitleType::Wvtt) {
+            wvtt_failed
+        } else {
+            subtitle_formats.contains(&SubtitleType::Ttxt)
+        };
+        if use_mp4box {
             // We can extract these from the MP4 container in .srt format, using MP4Box.
             if downloader.verbosity > 0 {
                 if let Some(fmt) = subtitle_formats.first() {
@@ -4465,7 +7106,7 @@ async fn fetch_period_subtitles(
                 }
                 info!("  Running MP4Box to extract subtitles");
             }
-            let out = downloader.output_path.as_ref().unwrap()
+            let out = output_path
                 .with_extension("srt");
             let out_str = out.to_string_lossy();
             let tmp_str = tmppath.to_string_lossy();
@@ -4498,20 +7139,15 @@ async fn fetch_period_subtitles(
             }
         }
         if subtitle_formats.contains(&SubtitleType::Stpp) {
-            // Copy from the temporary filename for the subtitle file to a .ttml file with the same
-            // basename as the requested media output file. Copy rather than rename in case we a
-            // crossing filesystems.
-            let tmpfile_in = File::open(tmppath).await
-                .map_err(|e| DashMpdError::Io(
-                    e, String::from("opening tmp subtitle output")))?;
-            let ttml_path = downloader.output_path.as_ref().unwrap()
+            // Save the TTML subtitles of all Periods fetched so far to a .ttml file with the same
+            // basename as the requested media output file, with their conversions. These files
+            // are rewritten after each Period, because the legacy embedding of subtitles in
+            // Matroska uses the SRT conversion of the first Period.
+            let ttml_path = output_path
                 .with_extension("ttml");
-            let ttml_file = File::create(ttml_path.clone()).await
-                .map_err(|e| DashMpdError::Io(
-                    e, String::from("opening TTML output file")))?;
-            io::copy(&mut BufReader::new(tmpfile_in), &mut BufWriter::new(ttml_file)).await
-                .map_err(|e| DashMpdError::Io(
-                    e, String::from("copying TTML subtitles")))?;
+            fs::write(&ttml_path, stpp_all.to_string()).await
+                .map_err(|e| DashMpdError::Io(e, String::from("writing TTML subtitles")))?;
+            save_ttml_conversions(downloader, &stpp_all, &ttml_path).await?;
         }
         if subtitle_formats.contains(&SubtitleType::Vtt) {
             // Copy from the temporary filename for the subtitle file to a .vtt file with the same
@@ -4520,7 +7156,7 @@ async fn fetch_period_subtitles(
             let tmpfile_in = File::open(tmppath).await
                 .map_err(|e| DashMpdError::Io(
                     e, String::from("opening tmp subtitle output")))?;
-            let vtt_path = downloader.output_path.as_ref().unwrap()
+            let vtt_path = output_path
                 .with_extension("vtt");
             let vtt_file = File::create(vtt_path.clone()).await
                 .map_err(|e| DashMpdError::Io(
@@ -4528,15 +7164,83 @@ async fn fetch_period_subtitles(
             io::copy(&mut BufReader::new(tmpfile_in), &mut BufWriter::new(vtt_file)).await
                 .map_err(|e| DashMpdError::Io(
                     e, String::from("copying VTT subtitles")))?;
+            let srt_path = vtt_path.with_extension("srt");
+            fs::write(&srt_path, vtt_document.to_srt()).await
+                .map_err(|e| DashMpdError::Io(e, String::from("writing SRT subtitles")))?;
         }
         // TODO: it might be useful to convert the subtitles to SRT/WebVTT format, as they tend to
         // be better supported. However, ffmpeg does not seem able to convert from TTML to these
         // formats. We could perhaps use the Python ttconv package, or below with MP4Box. Could
         // perhaps use the captionrs crate, https://crates.io/crates/captionrs
     }
+    ds.vtt_documents.insert(output_path.to_path_buf(), vtt_document);
+    if subtitle_formats.contains(&SubtitleType::Stpp) {
+        ds.stpp_documents.insert(output_path.to_path_buf(), stpp_all);
+    }
+    if subtitle_formats.contains(&SubtitleType::Wvtt) {
+        ds.wvtt_documents.insert(output_path.to_path_buf(), wvtt_document);
+    }
     Ok(have_subtitles)
 }
 
+// Save the WVTT subtitles accumulated over all Periods in WebVTT and SRT format.
+async fn save_accumulated_subtitles(
+    downloader: &DashDownloader,
+    ds: &DownloadState) -> Result<(), DashMpdError>
+{
+    for (output_path, wvtt) in &ds.wvtt_documents {
+        if let Some(wvtt) = wvtt.as_ref().filter(|w| !w.cues().is_empty()) {
+            save_wvtt_subtitles(downloader, wvtt, output_path).await?;
+        }
+    }
+    Ok(())
+}
+
+
+// Embed the subtitle tracks that we have downloaded in the media file at media_path, using the
+// sidecar file saved for each track in a format suitable for the container. Failure to embed the
+// subtitles is not a fatal error, since they remain available in the sidecar files.
+async fn embed_subtitle_tracks(
+    downloader: &DashDownloader,
+    media_path: &Path,
+    selected: &[SelectedSubtitles])
+{
+    let container = media_path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("mp4");
+    // WebM only allows WebVTT subtitles, and MP4 muxers convert SRT to tx3g most reliably.
+    let extensions: &[&str] = match container {
+        "mkv" => &["vtt", "srt", "ass"],
+        "webm" => &["vtt"],
+        _ => &["srt", "vtt"],
+    };
+    let mut tracks = Vec::new();
+    for st in selected {
+        let sidecar = extensions.iter()
+            .map(|ext| st.output_path.with_extension(ext))
+            .find(|p| p.exists());
+        match sidecar {
+            Some(path) => tracks.push(SubtitleTrack {
+                language: st.language.clone(),
+                path,
+                forced: st.forced,
+                hearing_impaired: st.hearing_impaired,
+            }),
+            None => warn!("  No subtitle file for language {} in a format that can be embedded in {container}",
+                          st.language),
+        }
+    }
+    if tracks.is_empty() {
+        return;
+    }
+    for observer in &downloader.progress_observers {
+        observer.update(99, 1, "Embedding subtitles");
+    }
+    if let Err(e) = mux_subtitle_tracks(downloader, media_path, &tracks).await {
+        warn!("  Failed to embed subtitles in {}: {e}", media_path.display());
+    }
+}
+
 
 // Fetch XML content of manifest from an HTTP/HTTPS URL
 async fn fetch_mpd_http(downloader: &mut DashDownloader) -> Result<Bytes, DashMpdError> {
@@ -4609,6 +7313,7 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
             warn!("Sandboxing failed: {e:?}");
         }
     }
+    load_decryption_key_files(downloader).await?;
     let xml = if downloader.mpd_url.starts_with("file://") {
         fetch_mpd_file(downloader).await?
     } else {
@@ -4695,26 +7400,36 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
     // fragments that we are going to retrieve, so that the ProgressBar shows information relevant
     // to the total download (we don't want a per-Period ProgressBar).
     let mut pds: Vec<PeriodDownloads> = Vec::new();
+    let mut discovered_kids: Vec<String> = Vec::new();
     let mut period_counter = 0;
+    let mut output_time_ms: i64 = 0;
+    let mut size_budget_remaining = downloader.size_budget.unwrap_or(0);
+    // The streams selected in the previous Period that we downloaded, when prefer_period_continuity
+    // is enabled.
+    let mut previous_period: Option<PeriodContinuity> = None;
     for mpd_period in &mpd.periods {
         let period = mpd_period.clone();
         period_counter += 1;
-        if let Some(min) = downloader.minimum_period_duration {
-            if let Some(duration) = period.duration {
-                if duration < min {
-                    if let Some(id) = period.id.as_ref() {
-                        info!("Skipping period {id} (#{period_counter}): duration is less than requested minimum");
-                    } else {
-                        info!("Skipping period #{period_counter}: duration is less than requested minimum");
-                    }
-                    continue;
-                }
+        if period_too_short(downloader, &period) {
+            if let Some(id) = period.id.as_ref() {
+                info!("Skipping period {id} (#{period_counter}): duration is less than requested minimum");
+            } else {
+                info!("Skipping period #{period_counter}: duration is less than requested minimum");
             }
+            continue;
         }
-        let mut pd = PeriodDownloads { period_counter, ..Default::default() };
+        let mut pd = PeriodDownloads { period_counter, output_offset_ms: output_time_ms, ..Default::default() };
         if let Some(id) = period.id.as_ref() {
             pd.id = Some(id.clone());
         }
+        // The duration of a Period may be implicit, determined by the start of the following Period.
+        let period_duration = period.duration.or_else(|| {
+            let next = mpd.periods.get(period_counter as usize)?;
+            next.start?.checked_sub(period.start?)
+        });
+        if let Some(d) = period_duration {
+            output_time_ms += d.as_millis() as i64;
+        }
         if downloader.verbosity > 0 && !downloader.fetch_audio && !downloader.fetch_video && !downloader.fetch_subtitles {
             if let Some(id) = period.id.as_ref() {
                 info!("Preparing download for period {id} (#{period_counter})");
@@ -4727,69 +7442,161 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
         if let Some(bu) = period.BaseURL.first() {
             base_url = merge_baseurls(&base_url, &bu.base)?;
         }
+        let mut ps = PeriodSelection { previous: previous_period.take(), ..Default::default() };
+        if let Some(budget) = downloader.size_budget {
+            let duration_secs = period_duration_secs(downloader, &mpd, period_counter as usize - 1).unwrap_or(0.0);
+            // When the budget applies to the whole presentation, the budget remaining after the
+            // previous Periods is shared among this Period and the following ones that will be
+            // downloaded, in proportion to their duration.
+            let share = if downloader.size_budget_per_period {
+                budget
+            } else {
+                let remaining_secs: f64 = (period_counter as usize - 1..mpd.periods.len())
+                    .filter(|i| !period_too_short(downloader, &mpd.periods[*i]))
+                    .filter_map(|i| period_duration_secs(downloader, &mpd, i))
+                    .sum();
+                if remaining_secs > duration_secs && duration_secs > 0.0 {
+                    (size_budget_remaining as f64 * duration_secs / remaining_secs) as u64
+                } else {
+                    size_budget_remaining
+                }
+            };
+            let sizes = estimate_period_sizes(downloader, &period, &base_url, duration_secs).await;
+            ps.size_budget = Some(PeriodSizeBudget { budget: share, sizes });
+        }
+        // The audio and video streams are selected once for each Period, and the selection is used
+        // for the download, the size budget report and the continuity with the next Period.
+        let selected_audio = if downloader.fetch_audio {
+            select_audio_representations(&period, downloader, &ps)?
+        } else {
+            Vec::new()
+        };
+        let selected_video = if downloader.fetch_video || downloader.fetch_srd_tiles {
+            select_video_representation(&period, downloader, &ps)?
+        } else {
+            None
+        };
+        if let Some(psb) = &ps.size_budget {
+            let share = psb.budget;
+            let selected: Vec<&Candidate> = selected_audio.iter()
+                .chain(selected_video.iter().filter(|_| downloader.fetch_video))
+                .collect();
+            let estimate: u64 = selected.iter()
+                .filter_map(|c| candidate_size(c, &period, &ps))
+                .sum();
+            let choice: Vec<String> = selected.iter()
+                .map(|c| format!("{}={}", c.1.id.as_deref().unwrap_or("?"),
+                                 candidate_size(c, &period, &ps).map_or(String::from("?"), |sz| sz.to_string())))
+                .collect();
+            info!("  Size budget for period #{period_counter}: {share} octets; estimated size of selected streams {estimate} octets ({})",
+                  choice.join(", "));
+            size_budget_remaining = size_budget_remaining.saturating_sub(estimate);
+        }
         let mut audio_outputs = PeriodOutputs::default();
         if downloader.fetch_audio {
-            audio_outputs = do_period_audio(downloader, &mpd, &period, period_counter, base_url.clone()).await?;
+            audio_outputs = do_period_audio(downloader, &mpd, &period, period_counter, base_url.clone(),
+                                            &selected_audio).await?;
             for f in audio_outputs.fragments {
                 pd.audio_fragments.push(f);
             }
-            pd.selected_audio_language = audio_outputs.selected_audio_language;
+            pd.selected_audio_languages = audio_outputs.selected_audio_languages;
         }
         let mut video_outputs = PeriodOutputs::default();
         if downloader.fetch_video {
-            video_outputs = do_period_video(downloader, &mpd, &period, period_counter, base_url.clone()).await?;
+            video_outputs = do_period_video(downloader, &mpd, &period, period_counter, base_url.clone(),
+                                            selected_video).await?;
             for f in video_outputs.fragments {
                 pd.video_fragments.push(f);
             }
         }
+        if downloader.period_continuity {
+            previous_period = Some(PeriodContinuity {
+                period_id: period.id.clone(),
+                audio: selected_audio.iter().map(continuity_stream).collect(),
+                video: selected_video.as_ref()
+                    .filter(|_| downloader.fetch_video)
+                    .map(continuity_stream),
+            });
+        }
         match do_period_subtitles(downloader, &mpd, &period, period_counter, base_url.clone()).await {
             Ok(subtitle_outputs) => {
                 for f in subtitle_outputs.fragments {
                     pd.subtitle_fragments.push(f);
                 }
-                for f in subtitle_outputs.subtitle_formats {
-                    pd.subtitle_formats.push(f);
-                }
-                pd.selected_subtitle_language = subtitle_outputs.selected_subtitle_language;
+                pd.selected_subtitles = subtitle_outputs.selected_subtitles;
             },
             Err(e) => warn!("  Ignoring error triggered while processing subtitles: {e}"),
         }
+        match do_period_thumbnails(downloader, &mpd, &period, period_counter, base_url.clone()).await {
+            Ok(Some((fragments, thumbnails))) => {
+                pd.thumbnail_fragments = fragments;
+                pd.thumbnails = Some(thumbnails);
+            },
+            Ok(None) => (),
+            Err(e) => warn!("  Ignoring error triggered while processing thumbnails: {e}"),
+        }
+        for (fragments, tile) in do_period_tiles(downloader, &mpd, &period, period_counter, base_url.clone(),
+                                                 selected_video).await? {
+            pd.tile_fragments.extend(fragments);
+            pd.tiles.push(tile);
+        }
+        for (laurl, kids) in audio_outputs.clearkey_licenses.iter()
+            .chain(video_outputs.clearkey_licenses.iter())
+        {
+            if let Err(e) = acquire_clearkey_license(downloader, laurl, kids).await {
+                warn!("Clear Key license acquisition from {laurl} failed: {e}");
+            }
+        }
+        for kid in audio_outputs.kids.iter().chain(video_outputs.kids.iter()) {
+            if !discovered_kids.contains(kid) {
+                discovered_kids.push(kid.clone());
+            }
+        }
         // Print some diagnostics information on the selected streams
         if downloader.verbosity > 0 {
-            use base64::prelude::{Engine as _, BASE64_STANDARD};
-
             audio_outputs.diagnostics.iter().for_each(|msg| info!("{}", msg));
             for f in pd.audio_fragments.iter().filter(|f| f.is_init) {
-                if let Some(pssh_bytes) = extract_init_pssh(downloader, f.url.clone()).await {
-                    info!("    PSSH (from init segment): {}", BASE64_STANDARD.encode(&pssh_bytes));
-                    if let Ok(pssh) = pssh_box::from_bytes(&pssh_bytes) {
-                        info!("    {}", pssh.to_string());
-                    }
-                }
+                let pssh_boxes = extract_init_pssh(downloader, f.url.clone()).await;
+                init_pssh_diagnostics(&pssh_boxes).iter().for_each(|msg| info!("{}", msg));
             }
             video_outputs.diagnostics.iter().for_each(|msg| info!("{}", msg));
             for f in pd.video_fragments.iter().filter(|f| f.is_init) {
-                if let Some(pssh_bytes) = extract_init_pssh(downloader, f.url.clone()).await {
-                    info!("    PSSH (from init segment): {}", BASE64_STANDARD.encode(&pssh_bytes));
-                    if let Ok(pssh) = pssh_box::from_bytes(&pssh_bytes) {
-                        info!("    {}", pssh.to_string());
-                    }
-                }
+                let pssh_boxes = extract_init_pssh(downloader, f.url.clone()).await;
+                init_pssh_diagnostics(&pssh_boxes).iter().for_each(|msg| info!("{}", msg));
             }
         }
         pds.push(pd);
     } // loop over Periods
+    if let Some(provider) = downloader.key_provider.clone() {
+        let wanted = kids_without_keys(downloader, &discovered_kids);
+        if !wanted.is_empty() {
+            if downloader.verbosity > 0 {
+                info!("Requesting decryption keys for KIDs {} from key provider", wanted.join(","));
+            }
+            let keys = provider.keys(&wanted).await?;
+            downloader.decryption_keys.extend(keys);
+        }
+    }
 
     // To collect the muxed audio and video segments for each Period in the MPD, before their
     // final concatenation-with-reencoding.
     let output_path = &downloader.output_path.as_ref().unwrap().clone();
     let mut period_output_pathbufs: Vec<PathBuf> = Vec::new();
+    // The subtitle tracks to embed in the output media, over all Periods.
+    let mut embedded_subtitles: Vec<SelectedSubtitles> = Vec::new();
+    // The cues of the WebVTT thumbnail track, over all Periods.
+    let mut thumbnail_cues: Vec<VttCue> = Vec::new();
+    // The index of the SRD tiles saved, over all Periods.
+    let mut tile_index: Vec<serde_json::Value> = Vec::new();
     let mut ds = DownloadState {
         period_counter: 0,
         // The additional +2 is for our initial .mpd fetch action and final muxing action
         segment_count: pds.iter().map(period_fragment_count).sum(),
         segment_counter: 0,
-        download_errors: 0
+        download_errors: 0,
+        vtt_documents: HashMap::new(),
+        stpp_documents: HashMap::new(),
+        wvtt_documents: HashMap::new(),
     };
     for pd in pds {
         let mut have_audio = false;
@@ -4825,13 +7632,30 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
             tmp_file_path("dashmpd-video", output_ext)?
         };
         let tmppath_subs = tmp_file_path("dashmpd-subs", OsStr::new("sub"))?;
+        // Each selected audio track is downloaded to a separate file, and the tracks are then muxed
+        // together (and with the video stream) into the output file for this Period.
+        let mut audio_tracks: Vec<AudioTrack> = Vec::new();
+        let mut tmppaths_audio: Vec<PathBuf> = Vec::new();
+        let audio_ext = output_ext.to_os_string();
         if downloader.fetch_audio && !pd.audio_fragments.is_empty() {
-            // TODO: to allow the download of multiple audio tracks (with multiple languages), we
-            // need to call fetch_period_audio multiple times with a different file path each time,
-            // and with the audio_fragments only relevant for that language.
-            have_audio = fetch_period_audio(downloader,
-                                            &tmppath_audio, &pd.audio_fragments,
-                                            &mut ds).await?;
+            for (track, language) in pd.selected_audio_languages.iter().enumerate() {
+                let path = if track == 0 {
+                    tmppath_audio.clone()
+                } else if let Some(ref keep) = downloader.keep_audio {
+                    keep_audio_track_path(keep, track, language, &tmppaths_audio)
+                } else {
+                    tmp_file_path("dashmpd-audio", &audio_ext)?
+                };
+                let fragments: Vec<MediaFragment> = pd.audio_fragments.iter()
+                    .filter(|f| usize::from(f.track) == track)
+                    .cloned()
+                    .collect();
+                tmppaths_audio.push(path.clone());
+                if fetch_period_audio(downloader, &path, &fragments, &mut ds).await? {
+                    audio_tracks.push(AudioTrack { language: language.clone(), path });
+                }
+            }
+            have_audio = !audio_tracks.is_empty();
         }
         if downloader.fetch_video && !pd.video_fragments.is_empty() {
             have_video = fetch_period_video(downloader,
@@ -4841,13 +7665,45 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
         // Here we handle subtitles that are distributed in fragmented MP4 segments, rather than as a
         // single .srt or .vtt file file. This is the case for WVTT (WebVTT) and STPP (which should be
         // formatted as EBU-TT for DASH media) formats.
+        let mut tmppaths_subs = vec![tmppath_subs.clone()];
         if downloader.fetch_subtitles && !pd.subtitle_fragments.is_empty() {
-            have_subtitles = fetch_period_subtitles(downloader,
-                                                    &tmppath_subs,
-                                                    &pd.subtitle_fragments,
-                                                    &pd.subtitle_formats,
-                                                    &mut ds).await?;
+            for (track, subtitles) in pd.selected_subtitles.iter().enumerate() {
+                let fragments: Vec<MediaFragment> = pd.subtitle_fragments.iter()
+                    .filter(|f| usize::from(f.track) == track)
+                    .cloned()
+                    .collect();
+                if fragments.is_empty() {
+                    continue;
+                }
+                let path = if track == 0 {
+                    tmppath_subs.clone()
+                } else {
+                    let p = tmp_file_path("dashmpd-subs", OsStr::new("sub"))?;
+                    tmppaths_subs.push(p.clone());
+                    p
+                };
+                if fetch_period_subtitles(downloader, &path, &fragments, subtitles,
+                                          pd.output_offset_ms, &mut ds).await? {
+                    have_subtitles = true;
+                }
+            }
+        }
+        if downloader.fetch_thumbnails && !pd.thumbnail_fragments.is_empty() {
+            if let Some(thumbnails) = &pd.thumbnails {
+                fetch_period_thumbnails(downloader, output_path, &pd.thumbnail_fragments, thumbnails,
+                                        pd.output_offset_ms, &mut thumbnail_cues, &mut ds).await?;
+            }
         }
+        if downloader.fetch_srd_tiles && !pd.tile_fragments.is_empty() {
+            fetch_period_tiles(downloader, output_path, &pd.tile_fragments, &pd.tiles,
+                               &mut tile_index, &mut ds).await?;
+        }
+        // The legacy embedding of a single subtitle track, used when embed_subtitles is disabled.
+        let legacy_subtitles = pd.selected_subtitles.first()
+            .filter(|_| !downloader.embed_subtitles);
+        let legacy_subtitle_formats = legacy_subtitles
+            .map(|st| st.formats.clone())
+            .unwrap_or_default();
 
         // The output file for this Period is either a mux of the audio and video streams, if both
         // are present, or just the audio stream, or just the video stream.
@@ -4858,20 +7714,15 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
             if downloader.verbosity > 1 {
                 info!("  Muxing audio and video streams");
             }
-            let audio_tracks = vec![
-                AudioTrack {
-                    language: pd.selected_audio_language,
-                    path: tmppath_audio.clone()
-                }];
             mux_audio_video(downloader, &period_output_path, &audio_tracks, &tmppath_video).await?;
-            if pd.subtitle_formats.contains(&SubtitleType::Stpp) {
+            if legacy_subtitle_formats.contains(&SubtitleType::Stpp) {
                 let container = match &period_output_path.extension() {
                     Some(ext) => ext.to_str().unwrap_or("mp4"),
                     None => "mp4",
                 };
                 if container.eq("mp4") {
                     if downloader.verbosity > 1 {
-                        if let Some(fmt) = &pd.subtitle_formats.first() {
+                        if let Some(fmt) = &legacy_subtitle_formats.first() {
                             info!("  Downloaded media contains subtitles in {fmt:?} format");
                         }
                         info!("  Running MP4Box to merge subtitles with output MP4 container");
@@ -4880,7 +7731,9 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
                     // works with MP4 containers.
                     let tmp_str = tmppath_subs.to_string_lossy();
                     let period_output_str = period_output_path.to_string_lossy();
-                    let subtitle_lang = format!("3={}", pd.selected_subtitle_language);
+                    // The subtitle track is added after the video track and the audio tracks.
+                    let language = legacy_subtitles.map_or("unk", |st| st.language.as_str());
+                    let subtitle_lang = format!("{}={}", audio_tracks.len() + 2, language);
                     let args = vec!["-lang", &subtitle_lang, "-add", &tmp_str, &period_output_str];
                     if downloader.verbosity > 0 {
                         info!("  Running MP4Box {}", args.join(" "));
@@ -4918,7 +7771,7 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
                     //
                     //    mkvmerge -o output.mkv input.mkv subs.srt
                     let srt = period_output_path.with_extension("srt");
-                    if srt.exists() {
+                    if srt.exists() && !downloader.embed_subtitles {
                         if downloader.verbosity > 0 {
                             info!("  Running mkvmerge to merge subtitles with output Matroska container");
                         }
@@ -4972,7 +7825,14 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
                 }
             }
         } else if have_audio {
-            copy_audio_to_container(downloader, &period_output_path, &tmppath_audio).await?;
+            if audio_tracks.len() > 1 {
+                for observer in &downloader.progress_observers {
+                    observer.update(99, 1, "Muxing audio tracks");
+                }
+                mux_audio_tracks(downloader, &period_output_path, &audio_tracks).await?;
+            } else {
+                copy_audio_to_container(downloader, &period_output_path, &audio_tracks[0].path).await?;
+            }
         } else if have_video {
             copy_video_to_container(downloader, &period_output_path, &tmppath_video).await?;
         } else if downloader.fetch_video && downloader.fetch_audio {
@@ -4985,8 +7845,10 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
         #[allow(clippy::collapsible_if)]
         if downloader.keep_audio.is_none() && downloader.fetch_audio {
             if env::var("DASHMPD_PERSIST_FILES").is_err() {
-                if tmppath_audio.exists() && fs::remove_file(tmppath_audio).await.is_err() {
-                    info!("  Failed to delete temporary file for audio stream");
+                for path in tmppaths_audio {
+                    if path.exists() && fs::remove_file(path).await.is_err() {
+                        info!("  Failed to delete temporary file for audio stream");
+                    }
                 }
             }
         }
@@ -4999,10 +7861,11 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
             }
         }
         #[allow(clippy::collapsible_if)]
-        if env::var("DASHMPD_PERSIST_FILES").is_err() {
-            if downloader.fetch_subtitles && tmppath_subs.exists() &&
-                fs::remove_file(tmppath_subs).await.is_err() {
-                info!("  Failed to delete temporary file for subtitles");
+        if env::var("DASHMPD_PERSIST_FILES").is_err() && downloader.fetch_subtitles {
+            for path in tmppaths_subs {
+                if path.exists() && fs::remove_file(path).await.is_err() {
+                    info!("  Failed to delete temporary file for subtitles");
+                }
             }
         }
         if downloader.verbosity > 1 && (downloader.fetch_audio || downloader.fetch_video || have_subtitles) {
@@ -5013,7 +7876,33 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
         if have_audio || have_video {
             period_output_pathbufs.push(period_output_path);
         }
+        if downloader.embed_subtitles {
+            for st in pd.selected_subtitles {
+                if !embedded_subtitles.iter().any(|e| e.output_path == st.output_path) {
+                    embedded_subtitles.push(st);
+                }
+            }
+        }
     } // Period iterator
+    save_accumulated_subtitles(downloader, &ds).await?;
+    if !thumbnail_cues.is_empty() {
+        let vtt_path = output_path.with_extension("thumbnails.vtt");
+        fs::write(&vtt_path, cues_to_webvtt("WEBVTT", &thumbnail_cues)).await
+            .map_err(|e| DashMpdError::Io(e, String::from("writing thumbnail track")))?;
+        if downloader.verbosity > 0 {
+            info!("Wrote thumbnail track to {}", vtt_path.display());
+        }
+    }
+    if !tile_index.is_empty() {
+        let index_path = output_path.with_extension("tiles").join("tiles.json");
+        let json = serde_json::to_string_pretty(&tile_index)
+            .map_err(|e| DashMpdError::Other(format!("serializing SRD tile index: {e}")))?;
+        fs::write(&index_path, json).await
+            .map_err(|e| DashMpdError::Io(e, String::from("writing SRD tile index")))?;
+        if downloader.verbosity > 0 {
+            info!("Wrote SRD tile index to {}", index_path.display());
+        }
+    }
     let period_output_paths: Vec<&Path> = period_output_pathbufs
         .iter()
         .map(PathBuf::as_path)
@@ -5021,17 +7910,20 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
     #[allow(clippy::comparison_chain)]
     if period_output_paths.len() == 1 {
         // We already arranged to write directly to the requested output_path.
+        embed_subtitle_tracks(downloader, output_path, &embedded_subtitles).await;
         maybe_record_metainformation(output_path, downloader, &mpd);
     } else if period_output_paths.len() > 1 {
         // If the streams for the different periods are all of the same resolution, we can
         // concatenate them (with reencoding) into a single media file. Otherwise, we can't
         // concatenate without rescaling and loss of quality, so we leave them in separate files.
-        // This feature isn't implemented using libav instead of ffmpeg as a subprocess.
-        #[allow(unused_mut)]
+        // When built with libav, Periods with different resolutions can also be concatenated by
+        // reencoding, if the "libav-reencode" concat helper is requested.
         let mut concatenated = false;
         #[cfg(not(feature = "libav"))]
-        // if downloader.concatenate_periods && video_containers_concatable(downloader, &period_output_paths) {
-        if downloader.concatenate_periods && video_containers_concatable(downloader, &period_output_paths) {
+        let concatable = video_containers_concatable(downloader, &period_output_paths);
+        #[cfg(feature = "libav")]
+        let concatable = periods_concatable(downloader, &period_output_paths);
+        if downloader.concatenate_periods && concatable {
             info!("Preparing to concatenate multiple Periods into one output file");
             concat_output_files(downloader, &period_output_paths).await?;
             for p in &period_output_paths[1..] {
@@ -5041,10 +7933,14 @@ async fn fetch_mpd(downloader: &mut DashDownloader) -> Result<PathBuf, DashMpdEr
             }
             concatenated = true;
             if let Some(pop) = period_output_paths.first() {
+                embed_subtitle_tracks(downloader, pop, &embedded_subtitles).await;
                 maybe_record_metainformation(pop, downloader, &mpd);
             }
         }
         if !concatenated {
+            if !embedded_subtitles.is_empty() {
+                warn!("Not embedding subtitles, because Periods have been saved to separate files");
+            }
             info!("Media content has been saved in a separate file for each period:");
             // FIXME this is not the original period number if we have dropped periods
             period_counter = 0;
@@ -5087,4 +7983,19 @@ mod tests {
         assert_eq!(resolve_url_template("AA/$RepresentationID$/segment-$Number%05d$.mp4", &dict),
                    "AA/640x480/segment-00042.mp4");
     }
+
+    #[test]
+    fn test_subtitle_track_output_path() {
+        use std::path::{Path, PathBuf};
+        use super::subtitle_track_output_path;
+
+        let out = Path::new("/tmp/movie.mp4");
+        assert_eq!(subtitle_track_output_path(out, "fr", true, false, &[]),
+                   PathBuf::from("/tmp/movie.fr.forced.mp4"));
+        assert_eq!(subtitle_track_output_path(out, "en", false, true, &[]),
+                   PathBuf::from("/tmp/movie.en.sdh.mp4"));
+        let used = vec![PathBuf::from("/tmp/movie.de.mp4")];
+        assert_eq!(subtitle_track_output_path(out, "de", false, false, &used),
+                   PathBuf::from("/tmp/movie.de.2.mp4"));
+    }
 }
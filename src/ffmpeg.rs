@@ -17,17 +17,28 @@ use tokio::io::{BufReader, BufWriter};
 use std::io::Write;
 use std::path::Path;
 use std::process::Command;
+use std::sync::Arc;
 use ffprobe::ffprobe;
 use tracing::{trace, info, warn, error};
 use crate::DashMpdError;
-use crate::fetch::{DashDownloader, partial_process_output};
+use crate::fetch::{DashDownloader, Muxer, Concatenator, PluginFuture, partial_process_output};
+use crate::fmp4::fmp4_files_concatable;
 use crate::media::{
     audio_container_type,
     video_container_type,
     container_has_video,
     container_has_audio,
     temporary_outpath,
+    container_language,
+    subtitle_track_name,
+    container_preference,
+    try_muxers,
+    try_subtitle_muxers,
+    try_concatenators,
     AudioTrack,
+    SubtitleTrack,
+    NativeMuxer,
+    NativeConcatenator,
 };
 
 #[allow(dead_code)]
@@ -55,6 +66,17 @@ pub async fn mux_multiaudio_video_ffmpeg(
     output_path: &Path,
     audio_tracks: &Vec<AudioTrack>,
     video_path: &Path) -> Result<(), DashMpdError> {
+    mux_multiaudio_ffmpeg(downloader, output_path, audio_tracks, Some(video_path)).await
+}
+
+// Mux multiple audio tracks, and optionally a video track, into the container at output_path. Each
+// audio track is tagged with its language, and the first audio track is flagged as the default track.
+#[tracing::instrument(level="trace", skip(downloader))]
+async fn mux_multiaudio_ffmpeg(
+    downloader: &DashDownloader,
+    output_path: &Path,
+    audio_tracks: &[AudioTrack],
+    video_path: Option<&Path>) -> Result<(), DashMpdError> {
     if audio_tracks.is_empty() {
         return Err(DashMpdError::Muxing(String::from("no audio tracks")));
     }
@@ -81,29 +103,14 @@ pub async fn mux_multiaudio_video_ffmpeg(
         .ok_or_else(|| DashMpdError::Io(
             io::Error::other("obtaining tmpfile name"),
             String::from("")))?;
-    let video_str = video_path
-        .to_str()
-        .ok_or_else(|| DashMpdError::Io(
-            io::Error::other("obtaining videopath name"),
-            String::from("")))?;
     if downloader.verbosity > 0 {
-        info!("  Muxing audio ({} track{}) and video content with ffmpeg",
+        info!("  Muxing audio ({} track{}){} with ffmpeg",
               audio_tracks.len(),
-              if audio_tracks.len() == 1 { "" } else { "s" });
-        if let Ok(attr) = fs::metadata(video_path).await {
-            info!("  Video file {} of size {} octets", video_path.display(), attr.len());
-        }
-    }
-    #[allow(unused_variables, unused_assignments)]
-    // let mut audio_delay = 0.0;
-    let mut video_delay = 0.0;
-    if let Ok(audio_start_time) = ffprobe_start_time(&audio_tracks[0].path) {
-        if let Ok(video_start_time) = ffprobe_start_time(video_path) {
-            if audio_start_time > video_start_time {
-                video_delay = audio_start_time - video_start_time;
-            } else {
-                // we are not currently using the audio_delay
-                // audio_delay = video_start_time - audio_start_time;
+              if audio_tracks.len() == 1 { "" } else { "s" },
+              if video_path.is_some() { " and video content" } else { "" });
+        if let Some(video_path) = video_path {
+            if let Ok(attr) = fs::metadata(video_path).await {
+                info!("  Video file {} of size {} octets", video_path.display(), attr.len());
             }
         }
     }
@@ -114,24 +121,49 @@ pub async fn mux_multiaudio_video_ffmpeg(
         String::from("-y"),  // overwrite output file if it exists
         String::from("-nostdin")];
     let mut mappings = Vec::new();
-    mappings.push(String::from("-map"));
-    mappings.push(String::from("0:v"));
-    let vd = format!("{video_delay}");
-    if video_delay > 0.001 {
-        args.push(String::from("-ss"));
-        args.push(vd);
+    if let Some(video_path) = video_path {
+        let video_str = video_path
+            .to_str()
+            .ok_or_else(|| DashMpdError::Io(
+                io::Error::other("obtaining videopath name"),
+                String::from("")))?;
+        #[allow(unused_variables, unused_assignments)]
+        // let mut audio_delay = 0.0;
+        let mut video_delay = 0.0;
+        if let Ok(audio_start_time) = ffprobe_start_time(&audio_tracks[0].path) {
+            if let Ok(video_start_time) = ffprobe_start_time(video_path) {
+                if audio_start_time > video_start_time {
+                    video_delay = audio_start_time - video_start_time;
+                } else {
+                    // we are not currently using the audio_delay
+                    // audio_delay = video_start_time - audio_start_time;
+                }
+            }
+        }
+        mappings.push(String::from("-map"));
+        mappings.push(String::from("0:v"));
+        let vd = format!("{video_delay}");
+        if video_delay > 0.001 {
+            args.push(String::from("-ss"));
+            args.push(vd);
+        }
+        args.push(String::from("-i"));
+        args.push(String::from(video_str));
     }
-    args.push(String::from("-i"));
-    args.push(String::from(video_str));
+    // The input index of the first audio file.
+    let audio_input_offset = usize::from(video_path.is_some());
     // https://superuser.com/questions/1078298/ffmpeg-combine-multiple-audio-files-and-one-video-in-to-the-multi-language-vid
     for (i, at) in audio_tracks.iter().enumerate() {
-        // note that the -map commandline argument counts from 1, whereas the -metadata argument counts from 0
+        // note that the -map commandline argument counts input files (the video input is number 0),
+        // whereas the -metadata and -disposition arguments count output audio streams from 0
         mappings.push(String::from("-map"));
-        mappings.push(format!("{}:a", i+1));
-        mappings.push(format!("-metadata:s:a:{i}"));
-        let mut lang_sanitized = at.language.clone();
-        lang_sanitized.retain(|c: char| c.is_ascii_lowercase());
-        mappings.push(format!("language={lang_sanitized}"));
+        mappings.push(format!("{}:a", i + audio_input_offset));
+        if let Some(lang) = container_language(&at.language, container) {
+            mappings.push(format!("-metadata:s:a:{i}"));
+            mappings.push(format!("language={lang}"));
+        }
+        mappings.push(format!("-disposition:a:{i}"));
+        mappings.push(String::from(if i == 0 { "default" } else { "0" }));
         args.push(String::from("-i"));
         let audio_str = at.path
             .to_str()
@@ -534,7 +566,7 @@ async fn mux_stream_ffmpeg(
 async fn mux_audio_video_vlc(
     downloader: &DashDownloader,
     output_path: &Path,
-    audio_tracks: &Vec<AudioTrack>,
+    audio_tracks: &[AudioTrack],
     video_path: &Path) -> Result<(), DashMpdError> {
     if audio_tracks.len() > 1 {
         error!("Cannot mux more than a single audio track with VLC");
@@ -629,17 +661,18 @@ async fn mux_audio_video_vlc(
 
 // MP4Box from the GPAC suite for muxing audio and video streams
 // https://github.com/gpac/gpac/wiki/MP4Box
+//
+// Each audio track is tagged with its language. The audio tracks are placed in the same alternate
+// group, and all but the first are disabled so that players select the first as the default track.
 #[tracing::instrument(level="trace", skip(downloader))]
 async fn mux_audio_video_mp4box(
     downloader: &DashDownloader,
     output_path: &Path,
-    audio_tracks: &Vec<AudioTrack>,
-    video_path: &Path) -> Result<(), DashMpdError> {
-    if audio_tracks.len() > 1 {
-        error!("Cannot mux more than a single audio track with MP4Box");
-        return Err(DashMpdError::Muxing(String::from("cannot mux more than one audio track with MP4Box")));
+    audio_tracks: &[AudioTrack],
+    video_path: Option<&Path>) -> Result<(), DashMpdError> {
+    if audio_tracks.is_empty() {
+        return Err(DashMpdError::Muxing(String::from("no audio tracks")));
     }
-    let audio_path = &audio_tracks[0].path;
     let container = match output_path.extension() {
         Some(ext) => ext.to_str().unwrap_or("mp4"),
         None => "mp4",
@@ -657,21 +690,37 @@ async fn mux_audio_video_mp4box(
         .ok_or_else(|| DashMpdError::Io(
             io::Error::other("obtaining tmpfile name"),
             String::from("")))?;
-    let audio_str = audio_path
-        .to_str()
-        .ok_or_else(|| DashMpdError::Io(
-            io::Error::other("obtaining audiopath name"),
-            String::from("")))?;
-    let video_str = video_path
-        .to_str()
-        .ok_or_else(|| DashMpdError::Io(
-            io::Error::other("obtaining videopath name"),
-            String::from("")))?;
-    let args = vec![
-        "-flat",
-        "-add", video_str,
-        "-add", audio_str,
-        "-new", tmppath];
+    let mut args = vec![String::from("-flat")];
+    if let Some(video_path) = video_path {
+        let video_str = video_path
+            .to_str()
+            .ok_or_else(|| DashMpdError::Io(
+                io::Error::other("obtaining videopath name"),
+                String::from("")))?;
+        args.push(String::from("-add"));
+        args.push(String::from(video_str));
+    }
+    for (i, at) in audio_tracks.iter().enumerate() {
+        let audio_str = at.path
+            .to_str()
+            .ok_or_else(|| DashMpdError::Io(
+                io::Error::other("obtaining audiopath name"),
+                String::from("")))?;
+        let mut add = String::from(audio_str);
+        if audio_tracks.len() > 1 {
+            add += "#audio:group=1";
+            if i > 0 {
+                add += ":disable";
+            }
+            if let Some(lang) = container_language(&at.language, container) {
+                add += &format!(":lang={lang}");
+            }
+        }
+        args.push(String::from("-add"));
+        args.push(add);
+    }
+    args.push(String::from("-new"));
+    args.push(String::from(tmppath));
     if downloader.verbosity > 0 {
         info!("  Running MP4Box {}", args.join(" "));
     }
@@ -771,31 +820,46 @@ async fn mux_stream_mp4box(
     }
 }
 
+// Each audio track is tagged with its language, and the first audio track is flagged as the default
+// track. Track options in mkvmerge apply to the following input file, and refer to track ID 0 (the
+// only audio track in each of our audio files).
 #[tracing::instrument(level="trace", skip(downloader))]
 async fn mux_audio_video_mkvmerge(
     downloader: &DashDownloader,
     output_path: &Path,
-    audio_tracks: &Vec<AudioTrack>,
-    video_path: &Path) -> Result<(), DashMpdError> {
-    if audio_tracks.len() > 1 {
-        error!("Cannot mux more than a single audio track with mkvmerge");
-        return Err(DashMpdError::Muxing(String::from("cannot mux more than one audio track with mkvmerge")));
+    audio_tracks: &[AudioTrack],
+    video_path: Option<&Path>) -> Result<(), DashMpdError> {
+    if audio_tracks.is_empty() {
+        return Err(DashMpdError::Muxing(String::from("no audio tracks")));
     }
-    let audio_path = &audio_tracks[0].path;
     let tmppath = temporary_outpath(".mkv")?;
-    let audio_str = audio_path
-        .to_str()
-        .ok_or_else(|| DashMpdError::Io(
-            io::Error::other("obtaining audiopath name"),
-            String::from("")))?;
-    let video_str = video_path
-        .to_str()
-        .ok_or_else(|| DashMpdError::Io(
-            io::Error::other("obtaining videopath name"),
-            String::from("")))?;
-    let args = vec!["--output", &tmppath,
-                    "--no-video", audio_str,
-                    "--no-audio", video_str];
+    let mut args = vec![String::from("--output"), tmppath.clone()];
+    for (i, at) in audio_tracks.iter().enumerate() {
+        let audio_str = at.path
+            .to_str()
+            .ok_or_else(|| DashMpdError::Io(
+                io::Error::other("obtaining audiopath name"),
+                String::from("")))?;
+        if audio_tracks.len() > 1 {
+            if let Some(lang) = container_language(&at.language, "mkv") {
+                args.push(String::from("--language"));
+                args.push(format!("0:{lang}"));
+            }
+            args.push(String::from("--default-track-flag"));
+            args.push(String::from(if i == 0 { "0:1" } else { "0:0" }));
+        }
+        args.push(String::from("--no-video"));
+        args.push(String::from(audio_str));
+    }
+    if let Some(video_path) = video_path {
+        let video_str = video_path
+            .to_str()
+            .ok_or_else(|| DashMpdError::Io(
+                io::Error::other("obtaining videopath name"),
+                String::from("")))?;
+        args.push(String::from("--no-audio"));
+        args.push(String::from(video_str));
+    }
     if downloader.verbosity > 0 {
         info!("  Running mkvmerge {}", args.join(" "));
     }
@@ -929,6 +993,83 @@ async fn mux_audio_mkvmerge(
 }
 
 
+/// The muxers implemented by running an external application as a subprocess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinMuxer { Ffmpeg, Vlc, Mkvmerge, Mp4Box }
+
+impl BuiltinMuxer {
+    /// The built-in muxer with this name ("ffmpeg", "vlc", "mkvmerge" or "mp4box").
+    pub fn from_name(name: &str) -> Option<BuiltinMuxer> {
+        match name {
+            "ffmpeg" => Some(BuiltinMuxer::Ffmpeg),
+            "vlc" => Some(BuiltinMuxer::Vlc),
+            "mkvmerge" => Some(BuiltinMuxer::Mkvmerge),
+            "mp4box" => Some(BuiltinMuxer::Mp4Box),
+            _ => None,
+        }
+    }
+}
+
+impl Muxer for BuiltinMuxer {
+    fn name(&self) -> &str {
+        match self {
+            BuiltinMuxer::Ffmpeg => "ffmpeg",
+            BuiltinMuxer::Vlc => "vlc",
+            BuiltinMuxer::Mkvmerge => "mkvmerge",
+            BuiltinMuxer::Mp4Box => "mp4box",
+        }
+    }
+
+    fn mux<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        output_path: &'a Path,
+        audio_tracks: &'a [AudioTrack],
+        video_path: Option<&'a Path>) -> PluginFuture<'a> {
+        Box::pin(async move {
+            match self {
+                BuiltinMuxer::Ffmpeg => mux_multiaudio_ffmpeg(downloader, output_path, audio_tracks, video_path).await,
+                // VLC is unable to mux multiple audio tracks without video.
+                BuiltinMuxer::Vlc => match video_path {
+                    Some(vp) => mux_audio_video_vlc(downloader, output_path, audio_tracks, vp).await,
+                    None => Err(DashMpdError::Muxing(String::from("vlc requires a video stream"))),
+                },
+                BuiltinMuxer::Mkvmerge => mux_audio_video_mkvmerge(downloader, output_path, audio_tracks, video_path).await,
+                BuiltinMuxer::Mp4Box => mux_audio_video_mp4box(downloader, output_path, audio_tracks, video_path).await,
+            }
+        })
+    }
+
+    fn mux_subtitles<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        media_path: &'a Path,
+        subtitle_tracks: &'a [SubtitleTrack]) -> PluginFuture<'a> {
+        Box::pin(async move {
+            match self {
+                BuiltinMuxer::Ffmpeg => mux_subtitles_ffmpeg(downloader, media_path, subtitle_tracks).await,
+                BuiltinMuxer::Mkvmerge => mux_subtitles_mkvmerge(downloader, media_path, subtitle_tracks).await,
+                BuiltinMuxer::Mp4Box => mux_subtitles_mp4box(downloader, media_path, subtitle_tracks).await,
+                BuiltinMuxer::Vlc => Err(DashMpdError::Muxing(String::from("vlc cannot embed subtitles"))),
+            }
+        })
+    }
+}
+
+fn builtin_muxer(name: &str) -> Option<Arc<dyn Muxer>> {
+    if name.eq("native") {
+        return Some(Arc::new(NativeMuxer));
+    }
+    BuiltinMuxer::from_name(name).map(|m| Arc::new(m) as Arc<dyn Muxer>)
+}
+
+fn output_container(output_path: &Path) -> &str {
+    match output_path.extension() {
+        Some(ext) => ext.to_str().unwrap_or("mp4"),
+        None => "mp4",
+    }
+}
+
 // Mux (merge) audio and video using an external tool, selecting the tool based on the output
 // container format and on the user-specified muxer preference ordering (e.g. "ffmpeg,vlc,mp4box")
 // or our hardcoded container-dependent preference ordering.
@@ -939,75 +1080,261 @@ pub async fn mux_audio_video(
     audio_tracks: &Vec<AudioTrack>,
     video_path: &Path) -> Result<(), DashMpdError> {
     trace!("Muxing {} audio tracks with video {}", audio_tracks.len(), video_path.display());
-    let container = match output_path.extension() {
-        Some(ext) => ext.to_str().unwrap_or("mp4"),
-        None => "mp4",
-    };
-    let mut muxer_preference = vec![];
-    if container.eq("mkv") {
-        muxer_preference.push("mkvmerge");
-        muxer_preference.push("ffmpeg");
-        muxer_preference.push("mp4box");
+    let container = output_container(output_path);
+    let default = if container.eq("mkv") {
+        vec!["mkvmerge", "ffmpeg", "mp4box"]
     } else if container.eq("webm") {
         // VLC is a better default than ffmpeg, because ffmpeg (with the options we supply) doesn't
         // automatically reencode the video and audio streams when they are incompatible with the
         // container format requested, whereas VLC does do so.
-        muxer_preference.push("vlc");
-        muxer_preference.push("ffmpeg");
+        vec!["vlc", "ffmpeg"]
     } else if container.eq("mp4") {
-        muxer_preference.push("ffmpeg");
-        muxer_preference.push("vlc");
-        muxer_preference.push("mp4box");
+        vec!["ffmpeg", "vlc", "mp4box"]
     } else {
-        muxer_preference.push("ffmpeg");
-        muxer_preference.push("mp4box");
+        vec!["ffmpeg", "mp4box"]
+    };
+    let muxer_preference = container_preference(&downloader.muxer_preference, container, default);
+    info!("  Muxer preference for {container} is {muxer_preference:?}");
+    try_muxers(downloader, &muxer_preference, builtin_muxer, output_path, audio_tracks, Some(video_path)).await
+}
+
+
+// Mux several audio tracks (without video) into the container at output_path, selecting the muxer
+// based on the output container format and on the user-specified muxer preference ordering. VLC is
+// not used by default because it is unable to mux multiple audio tracks.
+#[tracing::instrument(level="trace", skip(downloader))]
+pub async fn mux_audio_tracks(
+    downloader: &DashDownloader,
+    output_path: &Path,
+    audio_tracks: &[AudioTrack]) -> Result<(), DashMpdError> {
+    trace!("Muxing {} audio tracks to {}", audio_tracks.len(), output_path.display());
+    let container = output_container(output_path);
+    let default = if container.eq("mkv") {
+        vec!["mkvmerge", "ffmpeg", "mp4box"]
+    } else {
+        vec!["ffmpeg", "mp4box"]
+    };
+    let muxer_preference = container_preference(&downloader.muxer_preference, container, default);
+    info!("  Muxer preference for {container} is {muxer_preference:?}");
+    try_muxers(downloader, &muxer_preference, builtin_muxer, output_path, audio_tracks, None).await
+}
+
+
+// Copy the temporary output of a muxing subprocess to the output path. Copy rather than rename in
+// case we are crossing filesystems.
+async fn copy_muxed_output(tmppath: &Path, output_path: &Path, muxer: &str) -> Result<(), DashMpdError> {
+    {
+        let tmpfile = File::open(tmppath).await
+            .map_err(|e| DashMpdError::Io(e, format!("opening {muxer} output")))?;
+        let mut muxed = BufReader::new(tmpfile);
+        let outfile = File::create(output_path).await
+            .map_err(|e| DashMpdError::Io(e, String::from("creating output file")))?;
+        let mut sink = BufWriter::new(outfile);
+        io::copy(&mut muxed, &mut sink).await
+            .map_err(|e| DashMpdError::Io(e, format!("copying {muxer} output to output file")))?;
     }
-    if let Some(ordering) = downloader.muxer_preference.get(container) {
-        muxer_preference.clear();
-        for m in ordering.split(',') {
-            muxer_preference.push(m);
+    if env::var("DASHMPD_PERSIST_FILES").is_err() {
+        if let Err(e) = fs::remove_file(tmppath).await {
+            warn!("  Error deleting temporary {muxer} output: {e}");
         }
     }
-    info!("  Muxer preference for {container} is {muxer_preference:?}");
-    for muxer in muxer_preference {
-        info!("  Trying muxer {muxer}");
-        if muxer.eq("mkvmerge") {
-            if let Err(e) =  mux_audio_video_mkvmerge(downloader, output_path, audio_tracks, video_path).await {
-                warn!("  Muxing with mkvmerge subprocess failed: {e}");
-            } else {
-                info!("  Muxing with mkvmerge subprocess succeeded");
-                return Ok(());
-            }
-        } else if muxer.eq("ffmpeg") {
-            // if let Err(e) = mux_audio_video_ffmpeg(downloader, output_path, audio_tracks, video_path).await {
-            if let Err(e) = mux_multiaudio_video_ffmpeg(downloader, output_path, audio_tracks, video_path).await {
-                warn!("  Muxing with ffmpeg subprocess failed: {e}");
-            } else {
-                info!("  Muxing with ffmpeg subprocess succeeded");
-                return Ok(());
-            }
-        } else if muxer.eq("vlc") {
-            if let Err(e) = mux_audio_video_vlc(downloader, output_path, audio_tracks, video_path).await {
-                warn!("  Muxing with vlc subprocess failed: {e}");
-            } else {
-                info!("  Muxing with vlc subprocess succeeded");
-                return Ok(());
-            }
-        } else if muxer.eq("mp4box") {
-            if let Err(e) = mux_audio_video_mp4box(downloader, output_path, audio_tracks, video_path).await {
-                warn!("  Muxing with MP4Box subprocess failed: {e}");
-            } else {
-                info!("  Muxing with MP4Box subprocess succeeded");
-                return Ok(());
-            }
-        } else {
-            warn!("  Ignoring unknown muxer preference {muxer}");
+    Ok(())
+}
+
+// Add the subtitle tracks to the media file at media_path, using ffmpeg. Subtitles are converted to
+// mov_text (tx3g) for MP4 containers and copied for Matroska containers.
+async fn mux_subtitles_ffmpeg(
+    downloader: &DashDownloader,
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack]) -> Result<(), DashMpdError> {
+    let container = match media_path.extension() {
+        Some(ext) => ext.to_str().unwrap_or("mp4"),
+        None => "mp4",
+    };
+    let tmppath = temporary_outpath(&format!(".{container}"))?;
+    let mut args = vec![String::from("-hide_banner"),
+                        String::from("-nostats"),
+                        String::from("-loglevel"), String::from("error"),
+                        String::from("-y"),
+                        String::from("-nostdin"),
+                        String::from("-i"), media_path.to_string_lossy().to_string()];
+    for st in subtitle_tracks {
+        args.push(String::from("-i"));
+        args.push(st.path.to_string_lossy().to_string());
+    }
+    args.push(String::from("-map"));
+    args.push(String::from("0:v?"));
+    args.push(String::from("-map"));
+    args.push(String::from("0:a?"));
+    for i in 1..=subtitle_tracks.len() {
+        args.push(String::from("-map"));
+        args.push(format!("{i}:s"));
+    }
+    args.push(String::from("-c:v"));
+    args.push(String::from("copy"));
+    args.push(String::from("-c:a"));
+    args.push(String::from("copy"));
+    args.push(String::from("-c:s"));
+    args.push(String::from(if container.eq("mkv") || container.eq("webm") { "copy" } else { "mov_text" }));
+    for (i, st) in subtitle_tracks.iter().enumerate() {
+        if let Some(lang) = container_language(&st.language, container) {
+            args.push(format!("-metadata:s:s:{i}"));
+            args.push(format!("language={lang}"));
+        }
+        if let Some(name) = subtitle_track_name(st) {
+            args.push(format!("-metadata:s:s:{i}"));
+            args.push(format!("title={name}"));
+        }
+        let mut disposition = Vec::new();
+        if st.forced {
+            disposition.push("forced");
+        }
+        if st.hearing_impaired {
+            disposition.push("hearing_impaired");
+        }
+        args.push(format!("-disposition:s:{i}"));
+        args.push(if disposition.is_empty() { String::from("0") } else { disposition.join("+") });
+    }
+    args.push(tmppath.clone());
+    if downloader.verbosity > 0 {
+        info!("  Running ffmpeg {}", args.join(" "));
+    }
+    let ffmpeg = Command::new(&downloader.ffmpeg_location)
+        .args(args)
+        .output()
+        .map_err(|e| DashMpdError::Io(e, String::from("spawning ffmpeg subprocess")))?;
+    let msg = partial_process_output(&ffmpeg.stderr);
+    if downloader.verbosity > 0 && !msg.is_empty() {
+        info!("  ffmpeg stderr: {msg}");
+    }
+    if ffmpeg.status.success() {
+        copy_muxed_output(Path::new(&tmppath), media_path, "ffmpeg").await
+    } else {
+        if env::var("DASHMPD_PERSIST_FILES").is_err() {
+            let _ = fs::remove_file(&tmppath).await;
         }
+        Err(DashMpdError::Muxing(format!("running ffmpeg: {msg}")))
     }
-    warn!("All muxers failed");
-    warn!("  unmuxed audio streams: {}", audio_tracks.len());
-    warn!("  unmuxed video stream: {}", video_path.display());
-    Err(DashMpdError::Muxing(String::from("all muxers failed")))
+}
+
+// Add the subtitle tracks to the Matroska media file at media_path, using mkvmerge.
+async fn mux_subtitles_mkvmerge(
+    downloader: &DashDownloader,
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack]) -> Result<(), DashMpdError> {
+    let tmppath = temporary_outpath(".mkv")?;
+    let mut args = vec![String::from("--output"), tmppath.clone(),
+                        media_path.to_string_lossy().to_string()];
+    for st in subtitle_tracks {
+        if let Some(lang) = container_language(&st.language, "mkv") {
+            args.push(String::from("--language"));
+            args.push(format!("0:{lang}"));
+        }
+        if let Some(name) = subtitle_track_name(st) {
+            args.push(String::from("--track-name"));
+            args.push(format!("0:{name}"));
+        }
+        args.push(String::from("--default-track-flag"));
+        args.push(String::from("0:0"));
+        if st.forced {
+            args.push(String::from("--forced-display-flag"));
+            args.push(String::from("0:1"));
+        }
+        if st.hearing_impaired {
+            args.push(String::from("--hearing-impaired-flag"));
+            args.push(String::from("0:1"));
+        }
+        args.push(st.path.to_string_lossy().to_string());
+    }
+    if downloader.verbosity > 0 {
+        info!("  Running mkvmerge {}", args.join(" "));
+    }
+    let mkv = Command::new(&downloader.mkvmerge_location)
+        .args(args)
+        .output()
+        .map_err(|e| DashMpdError::Io(e, String::from("spawning mkvmerge subprocess")))?;
+    let msg = partial_process_output(&mkv.stderr);
+    if downloader.verbosity > 0 && !msg.is_empty() {
+        info!("  mkvmerge stderr: {msg}");
+    }
+    // mkvmerge exits with status 1 when it has emitted warnings but the output file is usable.
+    if mkv.status.success() || mkv.status.code() == Some(1) {
+        copy_muxed_output(Path::new(&tmppath), media_path, "mkvmerge").await
+    } else {
+        if env::var("DASHMPD_PERSIST_FILES").is_err() {
+            let _ = fs::remove_file(&tmppath).await;
+        }
+        // mkvmerge writes error messages to stdout, not to stderr
+        let msg = String::from_utf8_lossy(&mkv.stdout);
+        Err(DashMpdError::Muxing(format!("running mkvmerge: {msg}")))
+    }
+}
+
+// Add the subtitle tracks to the MP4 media file at media_path, using MP4Box. MP4Box imports SRT and
+// WebVTT files as tx3g and wvtt tracks, respectively.
+async fn mux_subtitles_mp4box(
+    downloader: &DashDownloader,
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack]) -> Result<(), DashMpdError> {
+    let tmppath = temporary_outpath(".mp4")?;
+    // Importing the existing media file first preserves its track order.
+    let mut args = vec![String::from("-add"), media_path.to_string_lossy().to_string()];
+    for st in subtitle_tracks {
+        let mut spec = st.path.to_string_lossy().to_string();
+        if let Some(lang) = container_language(&st.language, "mp4") {
+            spec += &format!(":lang={lang}");
+        }
+        if let Some(name) = subtitle_track_name(st) {
+            spec += &format!(":name={name}");
+        }
+        args.push(String::from("-add"));
+        args.push(spec);
+    }
+    args.push(String::from("-new"));
+    args.push(tmppath.clone());
+    if downloader.verbosity > 0 {
+        info!("  Running MP4Box {}", args.join(" "));
+    }
+    let cmd = Command::new(&downloader.mp4box_location)
+        .args(args)
+        .output()
+        .map_err(|e| DashMpdError::Io(e, String::from("spawning MP4Box subprocess")))?;
+    let msg = partial_process_output(&cmd.stderr);
+    if downloader.verbosity > 0 && !msg.is_empty() {
+        info!("  MP4Box stderr: {msg}");
+    }
+    if cmd.status.success() {
+        copy_muxed_output(Path::new(&tmppath), media_path, "MP4Box").await
+    } else {
+        if env::var("DASHMPD_PERSIST_FILES").is_err() {
+            let _ = fs::remove_file(&tmppath).await;
+        }
+        Err(DashMpdError::Muxing(format!("running MP4Box: {msg}")))
+    }
+}
+
+/// Embed the subtitle files in `subtitle_tracks` as additional tracks in the media file at
+/// `media_path`, tagged with their language and flagged as forced or hearing impaired where
+/// appropriate. The muxing application is chosen according to the muxer preference for the
+/// container type of `media_path`.
+#[tracing::instrument(level="trace", skip(downloader))]
+pub async fn mux_subtitle_tracks(
+    downloader: &DashDownloader,
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack]) -> Result<(), DashMpdError> {
+    trace!("Embedding {} subtitle tracks in {}", subtitle_tracks.len(), media_path.display());
+    if subtitle_tracks.is_empty() {
+        return Ok(());
+    }
+    let container = output_container(media_path);
+    let default = if container.eq("mkv") || container.eq("webm") {
+        vec!["mkvmerge", "ffmpeg"]
+    } else {
+        vec!["ffmpeg", "mp4box"]
+    };
+    let muxer_preference = container_preference(&downloader.muxer_preference, container, default);
+    info!("  Muxer preference for {container} is {muxer_preference:?}");
+    try_subtitle_muxers(downloader, &muxer_preference, builtin_muxer, media_path, subtitle_tracks).await
 }
 
 
@@ -1574,6 +1901,56 @@ pub(crate) async fn concat_output_files_mkvmerge(
     }
 }
 
+/// The Period concat helpers implemented by running an external application as a subprocess:
+/// the ffmpeg concat filter, the ffmpeg concat demuxer, mkvmerge and MP4Box.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinConcatenator { Ffmpeg, FfmpegDemuxer, Mkvmerge, Mp4Box }
+
+impl BuiltinConcatenator {
+    /// The built-in concat helper with this name ("ffmpeg", "ffmpegdemuxer", "mkvmerge" or "mp4box").
+    pub fn from_name(name: &str) -> Option<BuiltinConcatenator> {
+        match name {
+            "ffmpeg" => Some(BuiltinConcatenator::Ffmpeg),
+            "ffmpegdemuxer" => Some(BuiltinConcatenator::FfmpegDemuxer),
+            "mkvmerge" => Some(BuiltinConcatenator::Mkvmerge),
+            "mp4box" => Some(BuiltinConcatenator::Mp4Box),
+            _ => None,
+        }
+    }
+}
+
+impl Concatenator for BuiltinConcatenator {
+    fn name(&self) -> &str {
+        match self {
+            BuiltinConcatenator::Ffmpeg => "ffmpeg",
+            BuiltinConcatenator::FfmpegDemuxer => "ffmpegdemuxer",
+            BuiltinConcatenator::Mkvmerge => "mkvmerge",
+            BuiltinConcatenator::Mp4Box => "mp4box",
+        }
+    }
+
+    fn concat<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        paths: &'a [&'a Path]) -> PluginFuture<'a> {
+        Box::pin(async move {
+            match self {
+                BuiltinConcatenator::Ffmpeg => concat_output_files_ffmpeg_filter(downloader, paths).await,
+                BuiltinConcatenator::FfmpegDemuxer => concat_output_files_ffmpeg_demuxer(downloader, paths).await,
+                BuiltinConcatenator::Mkvmerge => concat_output_files_mkvmerge(downloader, paths).await,
+                BuiltinConcatenator::Mp4Box => concat_output_files_mp4box(downloader, paths).await,
+            }
+        })
+    }
+}
+
+fn builtin_concatenator(name: &str) -> Option<Arc<dyn Concatenator>> {
+    if name.eq("native") {
+        return Some(Arc::new(NativeConcatenator));
+    }
+    BuiltinConcatenator::from_name(name).map(|c| Arc::new(c) as Arc<dyn Concatenator>)
+}
+
 // Merge all media files named by paths into the file named by the first element of the vector.
 #[tracing::instrument(level="trace", skip(downloader))]
 pub(crate) async fn concat_output_files(
@@ -1582,15 +1959,14 @@ pub(crate) async fn concat_output_files(
     if paths.len() < 2 {
         return Ok(());
     }
-    let container = if let Some(p0) = paths.first() {
-        match p0.extension() {
-            Some(ext) => ext.to_str().unwrap_or("mp4"),
-            None => "mp4",
-        }
-    } else {
-        "mp4"
-    };
-    let mut concat_preference = vec![];
+    let container = paths.first()
+        .map_or("mp4", |p0| output_container(p0));
+    let mut default = vec![];
+    // When the files for each Period have the same tracks and codec parameters, the native
+    // concatenation is lossless and much faster than the ffmpeg concat filter, so it is preferred.
+    if container.eq("mp4") && fmp4_files_concatable(paths) {
+        default.push("native");
+    }
     if container.eq("mp4") ||
         container.eq("mkv") ||
         container.eq("webm")
@@ -1598,55 +1974,18 @@ pub(crate) async fn concat_output_files(
         // We will probably make ffmpegdemuxer the default concat helper in a future release; it's
         // much more robust than mkvmerge and much faster than ffmpeg ("concat filter"). But wait
         // until it gets more testing.
-        // concat_preference.push("ffmpegdemuxer");
-        concat_preference.push("mkvmerge");
-        concat_preference.push("ffmpeg");
-    } else {
-        concat_preference.push("ffmpeg");
-    }
-    if let Some(ordering) = downloader.concat_preference.get(container) {
-        concat_preference.clear();
-        for m in ordering.split(',') {
-            concat_preference.push(m);
+        // default.push("ffmpegdemuxer");
+        default.push("mkvmerge");
+        default.push("ffmpeg");
+        if container.eq("mkv") || container.eq("webm") {
+            default.push("native");
         }
+    } else {
+        default.push("ffmpeg");
     }
+    let concat_preference = container_preference(&downloader.concat_preference, container, default);
     info!("  Concat helper preference for {container} is {concat_preference:?}");
-    for concat in concat_preference {
-        info!("  Trying concat helper {concat}");
-        if concat.eq("mkvmerge") {
-            if let Err(e) = concat_output_files_mkvmerge(downloader, paths).await {
-                warn!("  Concatenation with mkvmerge failed: {e}");
-            } else {
-                info!("  Concatenation with mkvmerge succeeded");
-                return Ok(());
-            }
-        } else if concat.eq("ffmpeg") {
-            if let Err(e) = concat_output_files_ffmpeg_filter(downloader, paths).await {
-                warn!("  Concatenation with ffmpeg filter failed: {e}");
-            } else {
-                info!("  Concatenation with ffmpeg filter succeeded");
-                return Ok(());
-            }
-        } else if concat.eq("ffmpegdemuxer") {
-            if let Err(e) = concat_output_files_ffmpeg_demuxer(downloader, paths).await {
-                warn!("  Concatenation with ffmpeg demuxer failed: {e}");
-            } else {
-                info!("  Concatenation with ffmpeg demuxer succeeded");
-                return Ok(());
-            }
-        } else if concat.eq("mp4box") {
-            if let Err(e) = concat_output_files_mp4box(downloader, paths).await {
-                warn!("  Concatenation with MP4Box failed: {e}");
-            } else {
-                info!("  Concatenation with MP4Box succeeded");
-                return Ok(());
-            }
-        } else {
-            warn!("  Ignoring unknown concat helper preference {concat}");
-        }
-    }
-    warn!("  All concat helpers failed");
-    Err(DashMpdError::Muxing(String::from("all concat helpers failed")))
+    try_concatenators(downloader, &concat_preference, builtin_concatenator, paths).await
 }
 
 
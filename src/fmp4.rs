@@ -0,0 +1,1144 @@
+//! Native muxing of fragmented MP4 streams, without an external muxing application.
+//
+// DASH audio and video streams are very often delivered as fragmented MP4 (in particular in the
+// CMAF profile), where the initialization segment contains a moov box describing a single track
+// and each media segment contains one or more moof+mdat pairs. Muxing such streams does not require
+// any knowledge of the codecs: we merge the tracks from the moov boxes of each input, renumbering
+// the track IDs, and then either
+//
+//   - interleave the fragments of the different inputs in order of their decode time, rewriting
+//     the track IDs and data offsets in each moof (fragmented MP4 output), or
+//
+//   - rebuild complete sample tables in the moov from the information in the moof boxes, and copy
+//     the media samples into a single mdat that follows the moov (progressive MP4 output, with the
+//     moov at the front of the file as for "faststart" files).
+//
+// The media sample data is copied from the input files, so that large inputs are not loaded into
+// memory. Only the ftyp, moov and moof boxes are parsed, using the mp4-atom crate. Encrypted
+// fragments (with sample auxiliary information) are not supported.
+
+use std::fs::{self, File};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use mp4_atom::{
+    Atom, Encode, FourCC, FixedPoint, Header, ReadFrom,
+    Ftyp, Moov, Moof, Mvex, Mehd, Mvhd, Trak, Trex, Tfdt, TrunEntry, Codec,
+    Edts, Elst, ElstEntry,
+    Stts, SttsEntry, Ctts, CttsEntry, Stss, Stsc, StscEntry, Stsz, StszSamples, Stco, Co64,
+    Traf, Tfhd, Trun,
+};
+use tracing::{info, trace, warn};
+use crate::DashMpdError;
+use crate::fetch::DashDownloader;
+use crate::media::{AudioTrack, run_blocking};
+use crate::language::iso639_2t;
+
+
+// Sample flags bit sample_is_non_sync_sample (ISO/IEC 14496-12 section 8.8.3.1).
+const SAMPLE_IS_NON_SYNC: u32 = 0x0001_0000;
+
+fn mux_error(msg: &str) -> DashMpdError {
+    DashMpdError::Muxing(format!("native MP4 muxer: {msg}"))
+}
+
+fn atom_error(e: mp4_atom::Error, context: &str) -> DashMpdError {
+    DashMpdError::Muxing(format!("native MP4 muxer: {context}: {e}"))
+}
+
+fn encode_atom<T: Encode>(atom: &T, context: &str) -> Result<Vec<u8>, DashMpdError> {
+    let mut buf = Vec::new();
+    atom.encode(&mut buf)
+        .map_err(|e| atom_error(e, context))?;
+    Ok(buf)
+}
+
+fn write_all<W: Write>(out: &mut W, buf: &[u8]) -> Result<(), DashMpdError> {
+    out.write_all(buf)
+        .map_err(|e| DashMpdError::Io(e, String::from("writing native muxer output")))
+}
+
+#[derive(Debug, Clone)]
+struct Sample {
+    size: u32,
+    duration: u32,
+    flags: u32,
+    cts: i32,
+}
+
+// A run of samples from one track, stored contiguously in an input file, as described by a trun box.
+#[derive(Debug)]
+struct Run {
+    // Index of the track in the muxer output.
+    track: usize,
+    // Index of the traf in the moof, and of the trun within the traf.
+    traf: usize,
+    trun: usize,
+    // Absolute offset of the sample data in the input file, and its length.
+    offset: u64,
+    length: u64,
+    decode_time: u64,
+    sample_description_index: u32,
+    samples: Vec<Sample>,
+}
+
+#[derive(Debug)]
+struct Fragment {
+    input: usize,
+    moof: Moof,
+    runs: Vec<Run>,
+    // Decode time of the start of the fragment, in seconds.
+    start: f64,
+}
+
+// A track in the muxer output, taken from the moov box of one of the inputs.
+#[derive(Debug)]
+struct Track {
+    input: usize,
+    input_track_id: u32,
+    trak: Trak,
+    trex: Trex,
+    timescale: u32,
+}
+
+// An input to the native muxer, with the language to record for its tracks (if known).
+struct MuxInput {
+    path: PathBuf,
+    language: Option<String>,
+    is_audio: bool,
+}
+
+// Read the top-level boxes of an input file, retaining the moov and moof boxes (with the offset of
+// each moof in the file). The mdat boxes and any other boxes are skipped over.
+fn scan_boxes(path: &Path) -> Result<(Moov, Vec<(Moof, u64)>), DashMpdError> {
+    let file = File::open(path)
+        .map_err(|e| DashMpdError::Io(e, String::from("opening native muxer input")))?;
+    let file_size = file.metadata()
+        .map_err(|e| DashMpdError::Io(e, String::from("reading native muxer input metadata")))?
+        .len();
+    let mut reader = BufReader::new(file);
+    let mut moov = None;
+    let mut fragments = Vec::new();
+    let mut box_start = 0;
+    while box_start < file_size {
+        let header = match Option::<Header>::read_from(&mut reader) {
+            Ok(Some(h)) => h,
+            Ok(None) => break,
+            Err(e) => return Err(atom_error(e, "reading box header")),
+        };
+        let body_start = reader.stream_position()
+            .map_err(|e| DashMpdError::Io(e, String::from("reading native muxer input")))?;
+        let body_size = match header.size {
+            Some(s) => s as u64,
+            None => file_size.saturating_sub(body_start),
+        };
+        if body_start + body_size > file_size {
+            return Err(mux_error(&format!("truncated {} box in {}", header.kind, path.display())));
+        }
+        if header.kind == Moov::KIND || header.kind == Moof::KIND {
+            let mut body = vec![0u8; body_size as usize];
+            reader.read_exact(&mut body)
+                .map_err(|e| DashMpdError::Io(e, String::from("reading native muxer input")))?;
+            let buf = &mut body.as_slice();
+            if header.kind == Moov::KIND {
+                if moov.is_some() {
+                    return Err(mux_error(&format!("several moov boxes in {}", path.display())));
+                }
+                moov = Some(Moov::decode_body(buf).map_err(|e| atom_error(e, "parsing moov box"))?);
+            } else {
+                let moof = Moof::decode_body(buf).map_err(|e| atom_error(e, "parsing moof box"))?;
+                fragments.push((moof, box_start));
+            }
+        } else {
+            reader.seek(SeekFrom::Start(body_start + body_size))
+                .map_err(|e| DashMpdError::Io(e, String::from("seeking in native muxer input")))?;
+        }
+        box_start = body_start + body_size;
+    }
+    let moov = moov.ok_or_else(|| mux_error(&format!("no moov box in {}", path.display())))?;
+    Ok((moov, fragments))
+}
+
+// The language recorded in the mdhd box of each track of the MP4 file at path, in track order.
+#[cfg(feature = "libav")]
+pub(crate) fn mp4_track_languages(path: &Path) -> Result<Vec<Option<String>>, DashMpdError> {
+    let (moov, _) = scan_boxes(path)?;
+    Ok(moov.trak.iter()
+       .map(|t| Some(t.mdia.mdhd.language.clone()).filter(|l| !l.is_empty() && !l.eq("und")))
+       .collect())
+}
+
+// The offset and the size (including the header) of the moov box in the MP4 file at path.
+#[cfg(feature = "libav")]
+fn moov_position(path: &Path) -> Result<(u64, u64), DashMpdError> {
+    let file = File::open(path)
+        .map_err(|e| DashMpdError::Io(e, String::from("opening MP4 file")))?;
+    let file_size = file.metadata()
+        .map_err(|e| DashMpdError::Io(e, String::from("reading MP4 file metadata")))?
+        .len();
+    let mut reader = BufReader::new(file);
+    let mut box_start = 0;
+    while box_start < file_size {
+        let header = match Option::<Header>::read_from(&mut reader) {
+            Ok(Some(h)) => h,
+            Ok(None) => break,
+            Err(e) => return Err(atom_error(e, "reading box header")),
+        };
+        let body_start = reader.stream_position()
+            .map_err(|e| DashMpdError::Io(e, String::from("reading MP4 file")))?;
+        let body_size = match header.size {
+            Some(s) => s as u64,
+            None => file_size.saturating_sub(body_start),
+        };
+        if header.kind == Moov::KIND {
+            return Ok((box_start, body_start - box_start + body_size));
+        }
+        reader.seek(SeekFrom::Start(body_start + body_size))
+            .map_err(|e| DashMpdError::Io(e, String::from("seeking in MP4 file")))?;
+        box_start = body_start + body_size;
+    }
+    Err(mux_error(&format!("no moov box in {}", path.display())))
+}
+
+// Record the role of each track of the (progressive) MP4 file at path in a kind box using the DASH
+// role scheme, in the udta box of the track. This is how the ffmpeg mov muxer records the forced
+// and captions dispositions of a stream. Roles are given in track order; tracks without a role are
+// left unchanged. The chunk offsets are updated if the moov box precedes the media data.
+#[cfg(feature = "libav")]
+pub(crate) fn set_mp4_track_roles(path: &Path, roles: &[Option<&str>]) -> Result<(), DashMpdError> {
+    let (mut moov, fragments) = scan_boxes(path)?;
+    if !fragments.is_empty() {
+        return Err(mux_error("setting track roles in fragmented MP4 files is not supported"));
+    }
+    let (moov_start, moov_size) = moov_position(path)?;
+    for (trak, role) in moov.trak.iter_mut().zip(roles) {
+        if let Some(value) = role {
+            trak.udta.get_or_insert_with(mp4_atom::Udta::default).kind = Some(mp4_atom::Kind {
+                scheme_uri: String::from("urn:mpeg:dash:role:2011"),
+                value: String::from(*value),
+            });
+        }
+    }
+    // The size of the chunk offset boxes doesn't depend on their values, so the size of the
+    // updated moov box is known before the offsets are updated.
+    let delta = encode_atom(&moov, "encoding moov box")?.len() as i64 - moov_size as i64;
+    let shift = |offset: u64| -> Result<u64, DashMpdError> {
+        if offset < moov_start {
+            return Ok(offset);
+        }
+        offset.checked_add_signed(delta)
+            .ok_or_else(|| mux_error("invalid chunk offset"))
+    };
+    for trak in &mut moov.trak {
+        let stbl = &mut trak.mdia.minf.stbl;
+        if let Some(stco) = &mut stbl.stco {
+            for o in &mut stco.entries {
+                *o = u32::try_from(shift(u64::from(*o))?)
+                    .map_err(|_| mux_error("chunk offset overflow"))?;
+            }
+        }
+        if let Some(co64) = &mut stbl.co64 {
+            for o in &mut co64.entries {
+                *o = shift(*o)?;
+            }
+        }
+    }
+    let dir = path.parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let tmpout = tempfile::Builder::new()
+        .prefix("dashmpdrs")
+        .suffix(".mp4")
+        .rand_bytes(5)
+        .tempfile_in(dir)
+        .map_err(|e| DashMpdError::Io(e, String::from("creating temporary output file")))?;
+    {
+        let mut input = File::open(path)
+            .map_err(|e| DashMpdError::Io(e, String::from("opening MP4 file")))?;
+        let file_size = input.metadata()
+            .map_err(|e| DashMpdError::Io(e, String::from("reading MP4 file metadata")))?
+            .len();
+        let mut out = BufWriter::new(tmpout.as_file());
+        copy_range(&mut input, 0, moov_start, &mut out)?;
+        write_all(&mut out, &encode_atom(&moov, "encoding moov box")?)?;
+        let moov_end = moov_start + moov_size;
+        copy_range(&mut input, moov_end, file_size - moov_end, &mut out)?;
+        out.flush()
+            .map_err(|e| DashMpdError::Io(e, String::from("writing MP4 file")))?;
+    }
+    tmpout.persist(path)
+        .map_err(|e| DashMpdError::Io(e.error, String::from("replacing MP4 file")))?;
+    Ok(())
+}
+
+fn scan_input(path: &Path) -> Result<(Moov, Vec<(Moof, u64)>), DashMpdError> {
+    let (moov, fragments) = scan_boxes(path)?;
+    if fragments.is_empty() {
+        return Err(mux_error(&format!("{} is not a fragmented MP4 stream", path.display())));
+    }
+    Ok((moov, fragments))
+}
+
+// The tracks described by the moov box of input number input, with the defaults for their samples
+// from the trex boxes.
+fn input_tracks(moov: &Moov, input: usize, path: &Path) -> Result<Vec<Track>, DashMpdError> {
+    if moov.trak.is_empty() {
+        return Err(mux_error(&format!("no tracks in {}", path.display())));
+    }
+    let mut tracks = Vec::new();
+    for trak in &moov.trak {
+        ensure_supported_codecs(trak)?;
+        let input_track_id = trak.tkhd.track_id;
+        let trex = moov.mvex.as_ref()
+            .and_then(|mvex| mvex.trex.iter().find(|t| t.track_id == input_track_id))
+            .cloned()
+            .unwrap_or(Trex {
+                track_id: input_track_id,
+                default_sample_description_index: 1,
+                ..Default::default()
+            });
+        tracks.push(Track {
+            input,
+            input_track_id,
+            trak: trak.clone(),
+            trex,
+            timescale: trak.mdia.mdhd.timescale.max(1),
+        });
+    }
+    Ok(tracks)
+}
+
+// Determine the position and properties of the samples in each trun of a moof, resolving the
+// default values specified in the tfhd and trex boxes and the various ways of specifying the data
+// offset (ISO/IEC 14496-12 section 8.8.7).
+fn fragment_runs(
+    moof: &Moof,
+    moof_offset: u64,
+    input: usize,
+    tracks: &[Track],
+    next_decode_time: &mut [u64]) -> Result<Vec<Run>, DashMpdError>
+{
+    let mut runs = Vec::new();
+    let mut previous_traf_end = moof_offset;
+    for (traf_index, traf) in moof.traf.iter().enumerate() {
+        let tfhd = &traf.tfhd;
+        let track = tracks.iter()
+            .position(|t| t.input == input && t.input_track_id == tfhd.track_id)
+            .ok_or_else(|| mux_error(&format!("moof refers to unknown track {}", tfhd.track_id)))?;
+        if !traf.saio.is_empty() || !traf.saiz.is_empty() || traf.senc.is_some() {
+            return Err(mux_error("encrypted fragments are not supported"));
+        }
+        let trex = &tracks[track].trex;
+        let base = if let Some(bdo) = tfhd.base_data_offset {
+            bdo
+        } else if tfhd.default_base_is_moof || traf_index == 0 {
+            moof_offset
+        } else {
+            previous_traf_end
+        };
+        if let Some(tfdt) = &traf.tfdt {
+            next_decode_time[track] = tfdt.base_media_decode_time;
+        }
+        let sample_description_index = tfhd.sample_description_index
+            .unwrap_or(trex.default_sample_description_index);
+        let mut position = base;
+        for (trun_index, trun) in traf.trun.iter().enumerate() {
+            if let Some(offset) = trun.data_offset {
+                position = base.checked_add_signed(offset.into())
+                    .ok_or_else(|| mux_error("invalid trun data offset"))?;
+            }
+            let decode_time = next_decode_time[track];
+            let mut samples = Vec::with_capacity(trun.entries.len());
+            let mut length = 0;
+            for entry in &trun.entries {
+                let sample = Sample {
+                    size: entry.size
+                        .or(tfhd.default_sample_size)
+                        .unwrap_or(trex.default_sample_size),
+                    duration: entry.duration
+                        .or(tfhd.default_sample_duration)
+                        .unwrap_or(trex.default_sample_duration),
+                    flags: entry.flags
+                        .or(tfhd.default_sample_flags)
+                        .unwrap_or(trex.default_sample_flags),
+                    cts: entry.cts.unwrap_or(0),
+                };
+                length += u64::from(sample.size);
+                next_decode_time[track] += u64::from(sample.duration);
+                samples.push(sample);
+            }
+            runs.push(Run {
+                track,
+                traf: traf_index,
+                trun: trun_index,
+                offset: position,
+                length,
+                decode_time,
+                sample_description_index,
+                samples,
+            });
+            position += length;
+        }
+        previous_traf_end = position;
+    }
+    Ok(runs)
+}
+
+// Copy length octets at offset in the input file to the output.
+fn copy_range<W: Write>(input: &mut File, offset: u64, length: u64, out: &mut W) -> Result<(), DashMpdError> {
+    input.seek(SeekFrom::Start(offset))
+        .map_err(|e| DashMpdError::Io(e, String::from("seeking in native muxer input")))?;
+    let copied = io::copy(&mut input.take(length), out)
+        .map_err(|e| DashMpdError::Io(e, String::from("copying media samples")))?;
+    if copied != length {
+        return Err(mux_error("media data is truncated"));
+    }
+    Ok(())
+}
+
+// Encode the header of an mdat box containing size octets of data.
+fn mdat_header(size: u64) -> Vec<u8> {
+    let mut header = Vec::new();
+    if size + 8 > u64::from(u32::MAX) {
+        header.extend_from_slice(&1u32.to_be_bytes());
+        header.extend_from_slice(b"mdat");
+        header.extend_from_slice(&(size + 16).to_be_bytes());
+    } else {
+        header.extend_from_slice(&((size + 8) as u32).to_be_bytes());
+        header.extend_from_slice(b"mdat");
+    }
+    header
+}
+
+// Run-length encode the sample durations of a track.
+fn build_stts(samples: &[&Sample]) -> Stts {
+    let mut entries: Vec<SttsEntry> = Vec::new();
+    for s in samples {
+        match entries.last_mut() {
+            Some(e) if e.sample_delta == s.duration => e.sample_count += 1,
+            _ => entries.push(SttsEntry { sample_count: 1, sample_delta: s.duration }),
+        }
+    }
+    Stts { entries }
+}
+
+// The composition offset table is omitted when composition and decode times are identical.
+fn build_ctts(samples: &[&Sample]) -> Option<Ctts> {
+    if samples.iter().all(|s| s.cts == 0) {
+        return None;
+    }
+    let mut entries: Vec<CttsEntry> = Vec::new();
+    for s in samples {
+        match entries.last_mut() {
+            Some(e) if e.sample_offset == i64::from(s.cts) => e.sample_count += 1,
+            _ => entries.push(CttsEntry { sample_count: 1, sample_offset: i64::from(s.cts) }),
+        }
+    }
+    Some(Ctts { entries })
+}
+
+// The sync sample table is omitted when all samples are sync samples.
+fn build_stss(samples: &[&Sample]) -> Option<Stss> {
+    if samples.iter().all(|s| s.flags & SAMPLE_IS_NON_SYNC == 0) {
+        return None;
+    }
+    let entries = samples.iter()
+        .enumerate()
+        .filter(|(_, s)| s.flags & SAMPLE_IS_NON_SYNC == 0)
+        .map(|(i, _)| i as u32 + 1)
+        .collect();
+    Some(Stss { entries })
+}
+
+fn build_stsz(samples: &[&Sample]) -> Stsz {
+    let samples = match samples.first() {
+        Some(first) if samples.iter().all(|s| s.size == first.size) =>
+            StszSamples::Identical { count: samples.len() as u32, size: first.size },
+        _ => StszSamples::Different { sizes: samples.iter().map(|s| s.size).collect() },
+    };
+    Stsz { samples }
+}
+
+// Each run of samples is stored as one chunk in the progressive output.
+fn build_stsc(runs: &[&Run]) -> Stsc {
+    let mut entries: Vec<StscEntry> = Vec::new();
+    for (i, run) in runs.iter().enumerate() {
+        let spc = run.samples.len() as u32;
+        match entries.last() {
+            Some(e) if e.samples_per_chunk == spc && e.sample_description_index == run.sample_description_index => (),
+            _ => entries.push(StscEntry {
+                first_chunk: i as u32 + 1,
+                samples_per_chunk: spc,
+                sample_description_index: run.sample_description_index,
+            }),
+        }
+    }
+    Stsc { entries }
+}
+
+// Convert a duration from one timescale to another.
+fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    if from == 0 {
+        return 0;
+    }
+    (u128::from(value) * u128::from(to) / u128::from(from)) as u64
+}
+
+// Whether the track has protected sample entries (ISO/IEC 23001-7), whose sinf box carries the
+// original format and scheme information. mp4-atom does not decode these.
+fn encrypted_sample_entries(trak: &Trak) -> bool {
+    trak.mdia.minf.stbl.stsd.codecs.iter().any(|codec| {
+        matches!(codec, Codec::Unknown(kind)
+                 if [b"encv", b"enca", b"enct", b"encs"].iter().any(|e| *kind == FourCC::new(e)))
+    })
+}
+
+// mp4-atom preserves sample entries that it doesn't understand only as their four-character code,
+// so we must not attempt to rewrite them.
+fn ensure_supported_codecs(trak: &Trak) -> Result<(), DashMpdError> {
+    if encrypted_sample_entries(trak) {
+        return Err(mux_error("encrypted tracks are not supported"));
+    }
+    for codec in &trak.mdia.minf.stbl.stsd.codecs {
+        if let Codec::Unknown(kind) = codec {
+            return Err(mux_error(&format!("unsupported sample entry {kind}")));
+        }
+    }
+    Ok(())
+}
+
+fn movie_header(template: &Mvhd, timescale: u32, duration: u64, track_count: usize) -> Mvhd {
+    Mvhd {
+        timescale,
+        duration,
+        next_track_id: track_count as u32 + 1,
+        ..template.clone()
+    }
+}
+
+fn write_progressive<W: Write>(
+    out: &mut W,
+    files: &mut [File],
+    mvhd: &Mvhd,
+    tracks: &mut [Track],
+    fragments: &[Fragment]) -> Result<(), DashMpdError>
+{
+    let movie_timescale = mvhd.timescale;
+    // The earliest decode time over all tracks, in seconds, which becomes time zero in the output.
+    let origin = fragments.iter()
+        .map(|f| f.start)
+        .fold(f64::INFINITY, f64::min);
+    let mut movie_duration = 0;
+    for (ti, track) in tracks.iter_mut().enumerate() {
+        let runs: Vec<&Run> = fragments.iter()
+            .flat_map(|f| f.runs.iter())
+            .filter(|r| r.track == ti && !r.samples.is_empty())
+            .collect();
+        let samples: Vec<&Sample> = runs.iter().flat_map(|r| r.samples.iter()).collect();
+        let media_duration: u64 = samples.iter().map(|s| u64::from(s.duration)).sum();
+        let stbl = &mut track.trak.mdia.minf.stbl;
+        stbl.stts = build_stts(&samples);
+        stbl.ctts = build_ctts(&samples);
+        stbl.stss = build_stss(&samples);
+        stbl.stsz = build_stsz(&samples);
+        stbl.stsc = build_stsc(&runs);
+        // Placeholder chunk offsets, filled in below once the size of the moov is known.
+        stbl.stco = Some(Stco { entries: vec![0; runs.len()] });
+        stbl.co64 = None;
+        stbl.sbgp.clear();
+        stbl.sgpd.clear();
+        stbl.subs.clear();
+        stbl.saiz.clear();
+        stbl.saio.clear();
+        stbl.cslg = None;
+        track.trak.mdia.mdhd.duration = media_duration;
+
+        // An edit list delays the start of tracks that begin later than the earliest track, and
+        // retains the media start time from an edit list in the input (used for example to skip
+        // the priming samples of an AAC stream, or to compensate for composition offsets).
+        let first_decode = runs.first()
+            .map(|r| r.decode_time as f64 / f64::from(track.timescale))
+            .unwrap_or(origin);
+        let delay = ((first_decode - origin) * f64::from(movie_timescale)).round() as u64;
+        let media_time = track.trak.edts.as_ref()
+            .and_then(|edts| edts.elst.as_ref())
+            .and_then(|elst| elst.entries.iter().find_map(|e| e.media_time))
+            .unwrap_or(0)
+            .min(media_duration);
+        let presented = rescale(media_duration - media_time, track.timescale, movie_timescale);
+        if delay > 0 || media_time > 0 {
+            let mut entries = Vec::new();
+            if delay > 0 {
+                entries.push(ElstEntry {
+                    segment_duration: delay,
+                    media_time: None,
+                    media_rate: FixedPoint::new(1, 0),
+                });
+            }
+            entries.push(ElstEntry {
+                segment_duration: presented,
+                media_time: Some(media_time),
+                media_rate: FixedPoint::new(1, 0),
+            });
+            track.trak.edts = Some(Edts { elst: Some(Elst { entries }) });
+        } else {
+            track.trak.edts = None;
+        }
+        track.trak.tkhd.duration = delay + presented;
+        movie_duration = movie_duration.max(delay + presented);
+    }
+    let mut moov = Moov {
+        mvhd: movie_header(mvhd, movie_timescale, movie_duration, tracks.len()),
+        trak: tracks.iter().map(|t| t.trak.clone()).collect(),
+        ..Default::default()
+    };
+    let ftyp = Ftyp {
+        major_brand: FourCC::new(b"isom"),
+        minor_version: 512,
+        compatible_brands: vec![FourCC::new(b"isom"), FourCC::new(b"iso2"), FourCC::new(b"mp41")],
+    };
+    let ftyp_bytes = encode_atom(&ftyp, "encoding ftyp box")?;
+    let data_size: u64 = fragments.iter()
+        .flat_map(|f| f.runs.iter())
+        .map(|r| r.length)
+        .sum();
+    let mdat_hdr = mdat_header(data_size);
+    // Switch to 64-bit chunk offsets if the media data extends beyond 4GB. The size of the moov
+    // depends only on the number of chunk offsets, not on their values.
+    let moov_size = encode_atom(&moov, "encoding moov box")?.len() as u64;
+    let mut data_start = ftyp_bytes.len() as u64 + moov_size + mdat_hdr.len() as u64;
+    let use_co64 = data_start + data_size > u64::from(u32::MAX);
+    if use_co64 {
+        for trak in moov.trak.iter_mut() {
+            let stbl = &mut trak.mdia.minf.stbl;
+            let count = stbl.stco.take().map_or(0, |stco| stco.entries.len());
+            stbl.co64 = Some(Co64 { entries: vec![0; count] });
+        }
+        let moov_size = encode_atom(&moov, "encoding moov box")?.len() as u64;
+        data_start = ftyp_bytes.len() as u64 + moov_size + mdat_hdr.len() as u64;
+    }
+    let mut chunk_offsets: Vec<Vec<u64>> = vec![Vec::new(); tracks.len()];
+    let mut position = data_start;
+    for run in fragments.iter().flat_map(|f| f.runs.iter()) {
+        if !run.samples.is_empty() {
+            chunk_offsets[run.track].push(position);
+        }
+        position += run.length;
+    }
+    for (trak, offsets) in moov.trak.iter_mut().zip(chunk_offsets) {
+        let stbl = &mut trak.mdia.minf.stbl;
+        if use_co64 {
+            stbl.co64 = Some(Co64 { entries: offsets });
+        } else {
+            let entries = offsets.into_iter().map(|o| o as u32).collect();
+            stbl.stco = Some(Stco { entries });
+        }
+    }
+    let moov_bytes = encode_atom(&moov, "encoding moov box")?;
+    if ftyp_bytes.len() + moov_bytes.len() + mdat_hdr.len() != data_start as usize {
+        return Err(mux_error("inconsistent moov size"));
+    }
+    write_all(out, &ftyp_bytes)?;
+    write_all(out, &moov_bytes)?;
+    write_all(out, &mdat_hdr)?;
+    for f in fragments {
+        for run in &f.runs {
+            copy_range(&mut files[f.input], run.offset, run.length, out)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_fragmented<W: Write>(
+    out: &mut W,
+    files: &mut [File],
+    mvhd: &Mvhd,
+    tracks: &mut [Track],
+    fragments: &[Fragment]) -> Result<(), DashMpdError>
+{
+    let movie_timescale = mvhd.timescale;
+    let mut movie_duration = 0;
+    for (ti, track) in tracks.iter_mut().enumerate() {
+        let media_duration: u64 = fragments.iter()
+            .flat_map(|f| f.runs.iter())
+            .filter(|r| r.track == ti)
+            .flat_map(|r| r.samples.iter())
+            .map(|s| u64::from(s.duration))
+            .sum();
+        movie_duration = movie_duration.max(rescale(media_duration, track.timescale, movie_timescale));
+        // The sample tables in the moov of a fragmented file describe no samples.
+        let stbl = &mut track.trak.mdia.minf.stbl;
+        stbl.stts = Stts::default();
+        stbl.ctts = None;
+        stbl.stss = None;
+        stbl.stsc = Stsc::default();
+        stbl.stsz = Stsz::default();
+        stbl.stco = Some(Stco::default());
+        stbl.co64 = None;
+        track.trak.tkhd.duration = 0;
+        track.trak.mdia.mdhd.duration = 0;
+    }
+    let moov = Moov {
+        mvhd: movie_header(mvhd, movie_timescale, 0, tracks.len()),
+        mvex: Some(Mvex {
+            mehd: Some(Mehd { fragment_duration: movie_duration }),
+            trex: tracks.iter().map(|t| t.trex.clone()).collect(),
+        }),
+        trak: tracks.iter().map(|t| t.trak.clone()).collect(),
+        ..Default::default()
+    };
+    let ftyp = Ftyp {
+        major_brand: FourCC::new(b"iso6"),
+        minor_version: 0,
+        compatible_brands: vec![FourCC::new(b"iso6"), FourCC::new(b"isom"), FourCC::new(b"mp41")],
+    };
+    write_all(out, &encode_atom(&ftyp, "encoding ftyp box")?)?;
+    write_all(out, &encode_atom(&moov, "encoding moov box")?)?;
+    for (seq, f) in fragments.iter().enumerate() {
+        let mut moof = f.moof.clone();
+        moof.mfhd.sequence_number = seq as u32 + 1;
+        for run in &f.runs {
+            let traf = &mut moof.traf[run.traf];
+            traf.tfhd.track_id = tracks[run.track].trex.track_id;
+            traf.tfhd.base_data_offset = None;
+            traf.tfhd.default_base_is_moof = true;
+            traf.tfhd.duration_is_empty = false;
+            if run.trun == 0 {
+                traf.tfdt = Some(Tfdt { base_media_decode_time: run.decode_time });
+            }
+            // Record the resolved sample properties explicitly, so that the trun does not depend
+            // on default values in the input's tfhd and trex boxes.
+            let with_cts = run.samples.iter().any(|s| s.cts != 0);
+            let trun = &mut traf.trun[run.trun];
+            trun.entries = run.samples.iter()
+                .map(|s| TrunEntry {
+                    duration: Some(s.duration),
+                    size: Some(s.size),
+                    flags: Some(s.flags),
+                    cts: if with_cts { Some(s.cts) } else { None },
+                })
+                .collect();
+            trun.data_offset = Some(0);
+        }
+        // The sample data follows the moof in a single mdat, in the same order as the truns.
+        let moof_size = encode_atom(&moof, "encoding moof box")?.len() as u64;
+        let data_size: u64 = f.runs.iter().map(|r| r.length).sum();
+        let mdat_hdr = mdat_header(data_size);
+        let mut position = moof_size + mdat_hdr.len() as u64;
+        for run in &f.runs {
+            let offset = i32::try_from(position)
+                .map_err(|_| mux_error("fragment is too large"))?;
+            moof.traf[run.traf].trun[run.trun].data_offset = Some(offset);
+            position += run.length;
+        }
+        let moof_bytes = encode_atom(&moof, "encoding moof box")?;
+        if moof_bytes.len() as u64 != moof_size {
+            return Err(mux_error("inconsistent moof size"));
+        }
+        write_all(out, &moof_bytes)?;
+        write_all(out, &mdat_hdr)?;
+        for run in &f.runs {
+            copy_range(&mut files[f.input], run.offset, run.length, out)?;
+        }
+    }
+    Ok(())
+}
+
+fn mux_fmp4_files(
+    inputs: &[MuxInput],
+    output_path: &Path,
+    fragmented: bool) -> Result<(), DashMpdError>
+{
+    let mut scanned = Vec::new();
+    for input in inputs {
+        scanned.push(scan_input(&input.path)?);
+    }
+    let mvhd = scanned[0].0.mvhd.clone();
+    let mvhd = Mvhd { timescale: mvhd.timescale.max(1), ..mvhd };
+    // Collect the output tracks from the moov box of each input.
+    let mut tracks: Vec<Track> = Vec::new();
+    for (i, (moov, _)) in scanned.iter().enumerate() {
+        tracks.extend(input_tracks(moov, i, &inputs[i].path)?);
+    }
+    // Identify the samples in each fragment of each input.
+    let mut fragments: Vec<Fragment> = Vec::new();
+    let mut next_decode_time = vec![0u64; tracks.len()];
+    for (i, (_, moofs)) in scanned.into_iter().enumerate() {
+        for (moof, offset) in moofs {
+            let runs = fragment_runs(&moof, offset, i, &tracks, &mut next_decode_time)?;
+            let start = runs.iter()
+                .map(|r| r.decode_time as f64 / f64::from(tracks[r.track].timescale))
+                .fold(f64::INFINITY, f64::min);
+            if runs.is_empty() {
+                continue;
+            }
+            fragments.push(Fragment { input: i, moof, runs, start });
+        }
+    }
+    // Interleave the fragments from the different inputs in order of decode time. The sort is
+    // stable, so the order of fragments within each input is preserved.
+    fragments.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+    // Update the track headers with the new track ID and the language. When there are several
+    // audio tracks, they are placed in the same alternate group and only the first is enabled.
+    let audio_count = tracks.iter().filter(|t| inputs[t.input].is_audio).count();
+    let mut seen_audio = false;
+    for (i, t) in tracks.iter_mut().enumerate() {
+        let track_id = i as u32 + 1;
+        t.trak.tkhd.track_id = track_id;
+        t.trex.track_id = track_id;
+        // Track references would refer to the track IDs of the input file.
+        t.trak.tref = None;
+        t.trak.senc = None;
+        if let Some(lang) = inputs[t.input].language.as_deref().and_then(iso639_2t) {
+            t.trak.mdia.mdhd.language = lang;
+        }
+        if inputs[t.input].is_audio && audio_count > 1 {
+            t.trak.tkhd.alternate_group = 1;
+            t.trak.tkhd.enabled = !seen_audio;
+            seen_audio = true;
+        }
+    }
+    let mut files = Vec::new();
+    for input in inputs {
+        files.push(File::open(&input.path)
+                   .map_err(|e| DashMpdError::Io(e, String::from("opening native muxer input")))?);
+    }
+    let out = File::create(output_path)
+        .map_err(|e| DashMpdError::Io(e, String::from("creating native muxer output")))?;
+    let mut out = BufWriter::new(out);
+    if fragmented {
+        write_fragmented(&mut out, &mut files, &mvhd, &mut tracks, &fragments)?;
+    } else {
+        write_progressive(&mut out, &mut files, &mvhd, &mut tracks, &fragments)?;
+    }
+    out.flush()
+        .map_err(|e| DashMpdError::Io(e, String::from("writing native muxer output")))?;
+    Ok(())
+}
+
+/// Mux audio tracks and an optional video track, each of which must be a fragmented MP4 stream,
+/// into the MP4 file at `output_path`, without using an external muxing application. The output
+/// is a progressive MP4 file with the `moov` box at the front, or a fragmented MP4 file if
+/// requested with [DashDownloader::fragmented_mp4_output].
+#[tracing::instrument(level="trace", skip(downloader))]
+pub async fn mux_audio_video_native(
+    downloader: &DashDownloader,
+    output_path: &Path,
+    audio_tracks: &[AudioTrack],
+    video_path: Option<&Path>) -> Result<(), DashMpdError>
+{
+    trace!("Muxing {} audio tracks natively to {}", audio_tracks.len(), output_path.display());
+    let mut inputs = Vec::new();
+    if let Some(vp) = video_path {
+        inputs.push(MuxInput { path: vp.to_path_buf(), language: None, is_audio: false });
+    }
+    for at in audio_tracks {
+        inputs.push(MuxInput { path: at.path.clone(), language: Some(at.language.clone()), is_audio: true });
+    }
+    if inputs.is_empty() {
+        return Err(mux_error("no input streams"));
+    }
+    let fragmented = downloader.fragmented_mp4_output;
+    let output_path = output_path.to_path_buf();
+    run_blocking(move || {
+        if let Err(e) = mux_fmp4_files(&inputs, &output_path, fragmented) {
+            if output_path.exists() {
+                if let Err(e) = fs::remove_file(&output_path) {
+                    warn!("  Error deleting partial native muxer output: {e}");
+                }
+            }
+            return Err(e);
+        }
+        info!("  Muxed {} streams to {} MP4 with native muxer",
+              inputs.len(), if fragmented { "fragmented" } else { "progressive" });
+        Ok(())
+    }).await
+}
+
+
+// Concatenation of the MP4 files produced for successive Periods.
+//
+// When the files for each Period contain the same tracks with identical sample descriptions, they
+// can be concatenated without reencoding: the sample data is copied unchanged, and only the decode
+// times (the tfdt boxes of fragmented output, or the sample tables of progressive output) and the
+// moof sequence numbers are rewritten. The inputs may be fragmented MP4 files (as produced by the
+// native muxer or when no muxing was needed) or progressive MP4 files (as produced by ffmpeg or
+// MP4Box), whose sample tables are converted to runs of samples, one per chunk.
+
+// The samples of each chunk of a progressive MP4 file, as fragments containing a single run. The
+// moof of each fragment is synthesized so that it can be rewritten for fragmented output.
+fn progressive_fragments(moov: &Moov, input: usize, tracks: &[Track]) -> Result<Vec<Fragment>, DashMpdError> {
+    let mut fragments = Vec::new();
+    for (ti, trak) in moov.trak.iter().enumerate() {
+        let stbl = &trak.mdia.minf.stbl;
+        let sizes: Vec<u32> = match &stbl.stsz.samples {
+            StszSamples::Identical { count, size } => vec![*size; *count as usize],
+            StszSamples::Different { sizes } => sizes.clone(),
+        };
+        let durations: Vec<u32> = stbl.stts.entries.iter()
+            .flat_map(|e| std::iter::repeat_n(e.sample_delta, e.sample_count as usize))
+            .collect();
+        let offsets: Vec<i32> = stbl.ctts.as_ref()
+            .map(|ctts| ctts.entries.iter()
+                 .flat_map(|e| std::iter::repeat_n(e.sample_offset as i32, e.sample_count as usize))
+                 .collect())
+            .unwrap_or_default();
+        let chunk_offsets: Vec<u64> = match (&stbl.co64, &stbl.stco) {
+            (Some(co64), _) => co64.entries.clone(),
+            (None, Some(stco)) => stco.entries.iter().map(|o| u64::from(*o)).collect(),
+            (None, None) => Vec::new(),
+        };
+        if durations.len() < sizes.len() {
+            return Err(mux_error("inconsistent sample tables"));
+        }
+        let mut sample = 0;
+        let mut decode_time = 0;
+        for (ci, chunk_offset) in chunk_offsets.iter().enumerate() {
+            let chunk = ci as u32 + 1;
+            let Some(entry) = stbl.stsc.entries.iter().rev().find(|e| e.first_chunk <= chunk) else {
+                return Err(mux_error("inconsistent sample-to-chunk table"));
+            };
+            let count = entry.samples_per_chunk as usize;
+            if sample + count > sizes.len() {
+                return Err(mux_error("inconsistent sample-to-chunk table"));
+            }
+            let samples: Vec<Sample> = (sample..sample + count)
+                .map(|n| Sample {
+                    size: sizes[n],
+                    duration: durations[n],
+                    flags: match &stbl.stss {
+                        Some(stss) if !stss.entries.contains(&(n as u32 + 1)) => SAMPLE_IS_NON_SYNC,
+                        _ => 0,
+                    },
+                    cts: offsets.get(n).copied().unwrap_or(0),
+                })
+                .collect();
+            let length = samples.iter().map(|s| u64::from(s.size)).sum();
+            let duration: u64 = samples.iter().map(|s| u64::from(s.duration)).sum();
+            let moof = Moof {
+                traf: vec![Traf {
+                    tfhd: Tfhd { track_id: trak.tkhd.track_id, ..Default::default() },
+                    trun: vec![Trun { data_offset: Some(0), entries: Vec::new() }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            let run = Run {
+                track: ti,
+                traf: 0,
+                trun: 0,
+                offset: *chunk_offset,
+                length,
+                decode_time,
+                sample_description_index: entry.sample_description_index,
+                samples,
+            };
+            let start = decode_time as f64 / f64::from(tracks[ti].timescale);
+            fragments.push(Fragment { input, moof, runs: vec![run], start });
+            sample += count;
+            decode_time += duration;
+        }
+    }
+    fragments.sort_by(|a, b| a.start.total_cmp(&b.start));
+    Ok(fragments)
+}
+
+// The fragments of input number input, whether it is a fragmented or a progressive MP4 file.
+fn input_fragments(
+    moov: &Moov,
+    moofs: Vec<(Moof, u64)>,
+    input: usize,
+    tracks: &[Track]) -> Result<Vec<Fragment>, DashMpdError>
+{
+    if moofs.is_empty() {
+        return progressive_fragments(moov, input, tracks);
+    }
+    let mut fragments = Vec::new();
+    let mut next_decode_time = vec![0u64; tracks.len()];
+    for (moof, offset) in moofs {
+        let runs = fragment_runs(&moof, offset, input, tracks, &mut next_decode_time)?;
+        if runs.is_empty() {
+            continue;
+        }
+        let start = runs.iter()
+            .map(|r| r.decode_time as f64 / f64::from(tracks[r.track].timescale))
+            .fold(f64::INFINITY, f64::min);
+        fragments.push(Fragment { input, moof, runs, start });
+    }
+    Ok(fragments)
+}
+
+// Tracks can be concatenated if they have the same type, timescale and sample descriptions.
+fn concat_compatible(a: &[Track], b: &[Track]) -> bool {
+    a.len() == b.len() &&
+        a.iter().zip(b.iter()).all(|(ta, tb)| {
+            ta.timescale == tb.timescale &&
+                ta.trak.mdia.hdlr.handler == tb.trak.mdia.hdlr.handler &&
+                encode_atom(&ta.trak.mdia.minf.stbl.stsd, "encoding stsd box").ok() ==
+                encode_atom(&tb.trak.mdia.minf.stbl.stsd, "encoding stsd box").ok()
+        })
+}
+
+fn concat_fmp4_to(paths: &[&Path], output_path: &Path, fragmented: bool) -> Result<(), DashMpdError> {
+    let mut mvhd = None;
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut fragments: Vec<Fragment> = Vec::new();
+    // The start time of the current Period in the output, in seconds.
+    let mut offset = 0.0;
+    // The decode time at which the next sample of each track is expected, and the position in
+    // fragments of the last run of each track.
+    let mut expected: Vec<Option<u64>> = Vec::new();
+    let mut last_run: Vec<Option<(usize, usize)>> = Vec::new();
+    for (k, path) in paths.iter().enumerate() {
+        let (moov, moofs) = scan_boxes(path)?;
+        let file_tracks = input_tracks(&moov, k, path)?;
+        if k == 0 {
+            mvhd = Some(Mvhd { timescale: moov.mvhd.timescale.max(1), ..moov.mvhd.clone() });
+            expected = vec![None; file_tracks.len()];
+            last_run = vec![None; file_tracks.len()];
+            tracks = file_tracks;
+            let file_fragments = input_fragments(&moov, moofs, k, &tracks)?;
+            fragments.extend(file_fragments);
+        } else {
+            if !concat_compatible(&tracks, &file_tracks) {
+                return Err(mux_error(&format!("tracks in {} differ from those of the first Period", path.display())));
+            }
+            let file_fragments = input_fragments(&moov, moofs, k, &file_tracks)?;
+            fragments.extend(file_fragments);
+        }
+        // Rebase the decode times of this Period so that its earliest sample starts at the end of
+        // the preceding Period.
+        let first = fragments.iter().position(|f| f.input == k).unwrap_or(fragments.len());
+        let origin = fragments[first..].iter()
+            .map(|f| f.start)
+            .fold(f64::INFINITY, f64::min);
+        let shift: Vec<i64> = tracks.iter()
+            .map(|t| ((offset - origin) * f64::from(t.timescale)).round() as i64)
+            .collect();
+        let mut end = offset;
+        for fi in first..fragments.len() {
+            for ri in 0..fragments[fi].runs.len() {
+                let run = &fragments[fi].runs[ri];
+                let track = run.track;
+                let timescale = tracks[track].timescale;
+                let mut decode_time = run.decode_time.saturating_add_signed(shift[track]);
+                if let Some(exp) = expected[track] {
+                    if decode_time > exp {
+                        // Fill the gap before the start of this Period by extending the duration
+                        // of the last sample of the preceding Period.
+                        if let Some((lf, lr)) = last_run[track] {
+                            if let Some(s) = fragments[lf].runs[lr].samples.last_mut() {
+                                s.duration = u32::try_from(decode_time - exp).ok()
+                                    .and_then(|gap| s.duration.checked_add(gap))
+                                    .ok_or_else(|| mux_error(&format!("gap before {} too long to fill", path.display())))?;
+                            }
+                        }
+                    }
+                    decode_time = decode_time.max(exp);
+                }
+                let run = &mut fragments[fi].runs[ri];
+                run.decode_time = decode_time;
+                let duration: u64 = run.samples.iter().map(|s| u64::from(s.duration)).sum();
+                expected[track] = Some(decode_time + duration);
+                last_run[track] = Some((fi, ri));
+                end = f64::max(end, (decode_time + duration) as f64 / f64::from(timescale));
+            }
+            let f = &mut fragments[fi];
+            f.start = f.runs.iter()
+                .map(|r| r.decode_time as f64 / f64::from(tracks[r.track].timescale))
+                .fold(f64::INFINITY, f64::min);
+        }
+        trace!("MP4 concat: {} ends at {end}s", path.display());
+        offset = end;
+    }
+    let mvhd = mvhd.ok_or_else(|| mux_error("no input files"))?;
+    for (i, t) in tracks.iter_mut().enumerate() {
+        let track_id = i as u32 + 1;
+        t.trak.tkhd.track_id = track_id;
+        t.trex.track_id = track_id;
+        t.trak.tref = None;
+        t.trak.senc = None;
+    }
+    let mut files = Vec::new();
+    for path in paths {
+        files.push(File::open(path)
+                   .map_err(|e| DashMpdError::Io(e, String::from("opening native muxer input")))?);
+    }
+    let out = File::create(output_path)
+        .map_err(|e| DashMpdError::Io(e, String::from("creating native muxer output")))?;
+    let mut out = BufWriter::new(out);
+    if fragmented {
+        write_fragmented(&mut out, &mut files, &mvhd, &mut tracks, &fragments)?;
+    } else {
+        write_progressive(&mut out, &mut files, &mvhd, &mut tracks, &fragments)?;
+    }
+    out.flush()
+        .map_err(|e| DashMpdError::Io(e, String::from("writing native muxer output")))?;
+    Ok(())
+}
+
+/// Concatenate the MP4 files named by `paths` into the file named by the first element, without
+/// reencoding. The files must contain the same tracks with identical sample descriptions (as
+/// checked by [fmp4_files_concatable]); the decode times of each file are rebased to follow on
+/// from the end of the preceding file. The output is a progressive MP4 file, or a fragmented MP4
+/// file if requested with [DashDownloader::fragmented_mp4_output].
+#[tracing::instrument(level="trace", skip(downloader))]
+pub async fn concat_fmp4_files(
+    downloader: &DashDownloader,
+    paths: &[&Path]) -> Result<(), DashMpdError>
+{
+    if paths.len() < 2 {
+        return Err(mux_error("need at least two files"));
+    }
+    let fragmented = downloader.fragmented_mp4_output;
+    let paths: Vec<PathBuf> = paths.iter().map(|p| p.to_path_buf()).collect();
+    run_blocking(move || {
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let dir = paths[0].parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let tmpout = tempfile::Builder::new()
+            .prefix("dashmpdrs")
+            .suffix(".mp4")
+            .rand_bytes(5)
+            .tempfile_in(dir)
+            .map_err(|e| DashMpdError::Io(e, String::from("creating temporary output file")))?;
+        concat_fmp4_to(&paths, tmpout.path(), fragmented)?;
+        tmpout.persist(paths[0])
+            .map_err(|e| DashMpdError::Io(e.error, String::from("replacing concatenated output file")))?;
+        info!("  Concatenated {} MP4 files with native concatenator", paths.len());
+        Ok(())
+    }).await
+}
+
+/// Whether the MP4 files named by `paths` can be concatenated losslessly by [concat_fmp4_files]:
+/// they must all contain the same tracks, with the same timescales and sample descriptions, and
+/// must not be encrypted.
+pub fn fmp4_files_concatable(paths: &[&Path]) -> bool {
+    let mut first: Option<Vec<Track>> = None;
+    for (k, path) in paths.iter().enumerate() {
+        let Ok((moov, moofs)) = scan_boxes(path) else {
+            return false;
+        };
+        if moov.trak.iter().any(|trak| encrypted_sample_entries(trak) || trak.senc.is_some()) {
+            return false;
+        }
+        let Ok(tracks) = input_tracks(&moov, k, path) else {
+            return false;
+        };
+        let encrypted = moofs.iter()
+            .flat_map(|(moof, _)| moof.traf.iter())
+            .any(|traf| !traf.saio.is_empty() || !traf.saiz.is_empty() || traf.senc.is_some());
+        if encrypted {
+            return false;
+        }
+        match &first {
+            None => first = Some(tracks),
+            Some(t) => if !concat_compatible(t, &tracks) {
+                return false;
+            },
+        }
+    }
+    first.is_some() && paths.len() > 1
+}
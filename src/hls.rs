@@ -0,0 +1,778 @@
+//! Conversion of DASH manifests to HLS playlists
+//
+// Many packagers produce CMAF content (fragmented MP4 segments) that can be described both by a DASH
+// manifest and by HLS playlists (RFC 8216bis). This module generates an HLS multivariant playlist
+// and one media playlist per Representation from a parsed MPD, so that the same media segments can
+// be served to HLS clients without repackaging.
+//
+//   - Each video Representation becomes an EXT-X-STREAM-INF variant, whose BANDWIDTH and CODECS
+//     include those of the audio rendition group it references.
+//
+//   - Each audio AdaptationSet becomes an EXT-X-MEDIA rendition in the group for its codec (we use
+//     the Representation with the highest bandwidth, since HLS renditions can't switch bitrate), and
+//     WebVTT and IMSC1 (stpp in fragmented MP4) subtitle AdaptationSets become EXT-X-MEDIA subtitle
+//     renditions. Other subtitle formats have no HLS equivalent and are ignored.
+//
+//   - Media playlists use EXT-X-MAP for the initialization segment, and segment URIs (with
+//     EXT-X-BYTERANGE where the segment is a byte range of a larger resource) for the SegmentList,
+//     SegmentTemplate, SegmentTimeline and plain BaseURL addressing modes. For SegmentBase@indexRange
+//     addressing the segments are described by the sidx box of the media resource, which must be
+//     provided with `with_segment_index()` or fetched with `fetch_segment_indexes()`.
+//
+//   - The Periods of a multi-Period manifest are joined in each media playlist, separated by
+//     EXT-X-DISCONTINUITY tags, using the Representations with the same @id in each Period. The
+//     multivariant playlist is generated from the first Period.
+//
+//   - For encrypted content, ContentProtection elements for Widevine, PlayReady and FairPlay are
+//     converted to EXT-X-KEY tags (and EXT-X-SESSION-KEY tags in the multivariant playlist), with
+//     METHOD=SAMPLE-AES for the cbcs protection scheme and METHOD=SAMPLE-AES-CTR for cenc.
+//
+// Reference: https://datatracker.ietf.org/doc/html/draft-pantos-hls-rfc8216bis
+
+
+use std::collections::HashMap;
+use reqwest::header::RANGE;
+use url::Url;
+use tracing::warn;
+use crate::{MPD, Period, AdaptationSet, Representation, ContentProtection, DashMpdError};
+use crate::{AudioChannelConfiguration, SubtitleType, VideoDynamicRange};
+use crate::{is_audio_adaptation, is_video_adaptation, is_subtitle_adaptation, is_trickmode_adaptation};
+use crate::{subtitle_type, spatial_relationship, video_dynamic_range};
+use crate::clearkey::normalize_kid;
+use crate::fetch::{HttpClient, merge_baseurls, parse_range, resolve_url_template, mpd_is_dynamic,
+                   network_error, parse_manifest_frame_rate, srd_full_frame, adaptation_language,
+                   adaptation_forced_subtitles, adaptation_hearing_impaired,
+                   adaptation_audio_description, timeline_segments};
+use crate::sidx::SidxBox;
+
+
+const WIDEVINE_SYSTEM_ID: &str = "urn:uuid:edef8ba9-79d6-4ace-a3c8-27dcd51d21ed";
+const PLAYREADY_SYSTEM_ID: &str = "urn:uuid:9a04f079-9840-4286-ab92-e65be0885f95";
+const FAIRPLAY_SYSTEM_ID: &str = "urn:uuid:94ce86fb-07ff-4f43-adb8-93d2fa968ca9";
+
+// The HLS CODECS value for IMSC1 text subtitles in fragmented MP4.
+const IMSC1_CODEC: &str = "stpp.ttml.im1t";
+
+
+/// The name of the media playlist generated for the Representation with the given @id, as it is
+/// referenced from the multivariant playlist.
+#[must_use]
+pub fn media_playlist_name(representation_id: &str) -> String {
+    let name: String = representation_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+        .collect();
+    format!("{name}.m3u8")
+}
+
+// A value for a quoted-string attribute, which may not contain double quotes or line breaks.
+fn quoted(s: &str) -> String {
+    let clean: String = s.chars()
+        .map(|c| if c == '"' { '\'' } else if c == '\n' || c == '\r' { ' ' } else { c })
+        .collect();
+    format!("\"{clean}\"")
+}
+
+// The length@offset form of an inclusive byte range, as used by EXT-X-BYTERANGE.
+fn byterange((start, end): (u64, u64)) -> String {
+    format!("{}@{start}", end + 1 - start)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HlsSegment {
+    url: Url,
+    range: Option<(u64, u64)>,
+    duration: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HlsInit {
+    url: Url,
+    range: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HlsKey {
+    method: &'static str,
+    uri: String,
+    keyid: Option<String>,
+    keyformat: &'static str,
+}
+
+impl HlsKey {
+    fn attributes(&self) -> String {
+        let mut attrs = format!("METHOD={},URI={}", self.method, quoted(&self.uri));
+        if let Some(kid) = &self.keyid {
+            attrs += &format!(",KEYID=0x{kid}");
+        }
+        attrs + &format!(",KEYFORMAT={},KEYFORMATVERSIONS=\"1\"", quoted(self.keyformat))
+    }
+}
+
+// The media segments of one Representation in one Period.
+#[derive(Debug, Clone)]
+struct PeriodTrack {
+    init: Option<HlsInit>,
+    segments: Vec<HlsSegment>,
+    keys: Vec<HlsKey>,
+    first_number: u64,
+}
+
+// An audio or subtitle rendition (EXT-X-MEDIA) of the multivariant playlist.
+struct Rendition<'a> {
+    adaptation: &'a AdaptationSet,
+    representation: &'a Representation,
+    group: String,
+    name: String,
+}
+
+// The ContentProtection elements that apply to Representation r, from the most specific to the
+// least specific level.
+fn content_protections<'a>(
+    mpd: &'a MPD,
+    period: &'a Period,
+    a: &'a AdaptationSet,
+    r: &'a Representation) -> impl Iterator<Item = &'a ContentProtection>
+{
+    r.ContentProtection.iter()
+        .chain(a.ContentProtection.iter())
+        .chain(period.ContentProtection.iter())
+        .chain(mpd.ContentProtection.iter())
+}
+
+// The EXT-X-KEY attributes for the DRM systems signalled by the ContentProtection elements cps which
+// can be expressed in HLS. The protection scheme is given by the mp4protection descriptor, and
+// defaults to cenc.
+fn content_protection_keys<'a>(cps: impl Iterator<Item = &'a ContentProtection>) -> Vec<HlsKey> {
+    let cps: Vec<&ContentProtection> = cps.collect();
+    if cps.is_empty() {
+        return Vec::new();
+    }
+    let scheme = cps.iter()
+        .find(|cp| cp.schemeIdUri.eq_ignore_ascii_case("urn:mpeg:dash:mp4protection:2011"))
+        .and_then(|cp| cp.value.as_deref())
+        .unwrap_or("cenc");
+    let method = match scheme {
+        "cbcs" => "SAMPLE-AES",
+        "cenc" => "SAMPLE-AES-CTR",
+        other => {
+            warn!("Protection scheme {other} can't be signalled in HLS");
+            return Vec::new();
+        },
+    };
+    let keyid = cps.iter()
+        .find_map(|cp| cp.default_KID.as_deref().and_then(normalize_kid));
+    let mut keys = Vec::new();
+    for cp in &cps {
+        let system = cp.schemeIdUri.to_lowercase();
+        let key = if system == WIDEVINE_SYSTEM_ID {
+            cp.cenc_pssh.iter()
+                .find_map(|p| p.content.as_deref())
+                .map(|pssh| HlsKey {
+                    method,
+                    uri: format!("data:text/plain;base64,{}", pssh.trim()),
+                    keyid: keyid.clone(),
+                    keyformat: WIDEVINE_SYSTEM_ID,
+                })
+        } else if system == PLAYREADY_SYSTEM_ID {
+            cp.msprpro.as_ref()
+                .and_then(|p| p.content.as_deref())
+                .map(|pro| HlsKey {
+                    method,
+                    uri: format!("data:text/plain;charset=UTF-16;base64,{}", pro.trim()),
+                    keyid: keyid.clone(),
+                    keyformat: "com.microsoft.playready",
+                })
+        } else if system == FAIRPLAY_SYSTEM_ID && method == "SAMPLE-AES" {
+            cp.laurl.as_ref()
+                .and_then(|l| l.content.as_deref())
+                .map(|laurl| HlsKey {
+                    method,
+                    uri: laurl.trim().to_string(),
+                    keyid: keyid.clone(),
+                    keyformat: "com.apple.streamingkeydelivery",
+                })
+        } else {
+            None
+        };
+        if let Some(key) = key {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+    }
+    keys
+}
+
+// The duration of Period idx in seconds, if it is known.
+fn period_duration(mpd: &MPD, idx: usize) -> Option<f64> {
+    let period = mpd.periods.get(idx)?;
+    if let Some(d) = period.duration {
+        return Some(d.as_secs_f64());
+    }
+    if let Some(next) = mpd.periods.get(idx + 1) {
+        return next.start?.checked_sub(period.start.unwrap_or_default()).map(|d| d.as_secs_f64());
+    }
+    let total = mpd.mediaPresentationDuration?;
+    total.checked_sub(period.start.unwrap_or_default()).map(|d| d.as_secs_f64())
+}
+
+// The durations in seconds of count segments of nominal duration segment_duration in a Period of
+// period_duration seconds. The last segment is truncated at the end of the Period.
+fn template_segment_durations(count: u64, segment_duration: f64, period_duration: Option<f64>) -> Vec<f64> {
+    (0..count)
+        .map(|i| {
+            let start = i as f64 * segment_duration;
+            match period_duration {
+                Some(pd) if pd > start => segment_duration.min(pd - start),
+                _ => segment_duration,
+            }
+        })
+        .collect()
+}
+
+/// A converter from a DASH manifest to HLS playlists.
+///
+/// ```rust
+/// use dash_mpd::parse;
+/// use dash_mpd::hls::HlsConverter;
+///
+/// let xml = r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT4S">
+///   <Period><AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+///     <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+///                      media="$RepresentationID$/$Number$.m4s"/>
+///     <Representation id="v1" bandwidth="2000000" width="1280" height="720"/>
+///   </AdaptationSet></Period></MPD>"#;
+/// let mpd = parse(xml).unwrap();
+/// let converter = HlsConverter::new(&mpd, "https://example.com/dash/manifest.mpd").unwrap();
+/// let multivariant = converter.multivariant_playlist().unwrap();
+/// assert!(multivariant.contains("RESOLUTION=1280x720"));
+/// let media = converter.media_playlist("v1").unwrap();
+/// assert!(media.contains("https://example.com/dash/v1/2.m4s"));
+/// ```
+pub struct HlsConverter<'a> {
+    mpd: &'a MPD,
+    mpd_url: Url,
+    segment_indexes: HashMap<(usize, String), Vec<u8>>,
+}
+
+impl<'a> HlsConverter<'a> {
+    /// Create a converter for manifest `mpd`, which was retrieved from `mpd_url`. The URIs in the
+    /// generated playlists are absolute URLs, resolved against `mpd_url` and the BaseURL elements of
+    /// the manifest.
+    pub fn new(mpd: &'a MPD, mpd_url: &str) -> Result<HlsConverter<'a>, DashMpdError> {
+        let mpd_url = Url::parse(mpd_url)
+            .map_err(|e| DashMpdError::Parsing(format!("invalid manifest URL: {e}")))?;
+        Ok(HlsConverter { mpd, mpd_url, segment_indexes: HashMap::new() })
+    }
+
+    /// Provide the content of the segment index (the byte range designated by SegmentBase@indexRange,
+    /// which contains an sidx box) of the Representation with @id `representation_id` in the Period
+    /// at position `period` in the manifest. This is required to generate the media playlists of
+    /// Representations that use SegmentBase addressing.
+    #[must_use]
+    pub fn with_segment_index(mut self, period: usize, representation_id: &str, sidx: Vec<u8>) -> HlsConverter<'a> {
+        self.segment_indexes.insert((period, representation_id.to_string()), sidx);
+        self
+    }
+
+    /// Fetch the segment index of each Representation that uses SegmentBase addressing, using HTTP
+    /// Range requests made with `client`.
+    pub async fn fetch_segment_indexes(mut self, client: &HttpClient) -> Result<HlsConverter<'a>, DashMpdError> {
+        let mpd = self.mpd;
+        for (pidx, period) in mpd.periods.iter().enumerate() {
+            for a in &period.adaptations {
+                for r in &a.representations {
+                    let Some(rid) = &r.id else { continue };
+                    let Some(sb) = r.SegmentBase.as_ref().or(a.SegmentBase.as_ref()).or(period.SegmentBase.as_ref())
+                    else { continue };
+                    let Some(ir) = &sb.indexRange else { continue };
+                    let (s, e) = parse_range(ir)?;
+                    let url = self.base_url(period, a, r)?;
+                    let idx = client.get(url)
+                        .header(RANGE, format!("bytes={s}-{e}"))
+                        .send().await
+                        .map_err(|e| network_error("fetching index data", &e))?
+                        .error_for_status()
+                        .map_err(|e| network_error("fetching index data", &e))?
+                        .bytes().await
+                        .map_err(|e| network_error("fetching index data", &e))?;
+                    self.segment_indexes.insert((pidx, rid.clone()), idx.to_vec());
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    fn base_url(&self, period: &Period, a: &AdaptationSet, r: &Representation) -> Result<Url, DashMpdError> {
+        let mut base_url = self.mpd_url.clone();
+        for bu in [self.mpd.base_url.first(), period.BaseURL.first(), a.BaseURL.first(), r.BaseURL.first()]
+            .into_iter()
+            .flatten()
+        {
+            base_url = merge_baseurls(&base_url, &bu.base)?;
+        }
+        Ok(base_url)
+    }
+
+    // The initialization segment and media segments of Representation r of AdaptationSet a in the
+    // Period at position pidx, for each of the addressing modes: (1) SegmentList,
+    // (2) SegmentTemplate+SegmentTimeline, (3) SegmentTemplate@duration, (4) SegmentBase@indexRange,
+    // (5) plain BaseURL.
+    fn period_track(&self, pidx: usize, a: &AdaptationSet, r: &Representation) -> Result<PeriodTrack, DashMpdError> {
+        let mpd = self.mpd;
+        let period = &mpd.periods[pidx];
+        let period_duration = period_duration(mpd, pidx);
+        let base_url = self.base_url(period, a, r)?;
+        let keys = content_protection_keys(content_protections(mpd, period, a, r));
+        let rid = r.id.clone().unwrap_or_default();
+        let mut dict = HashMap::from([("RepresentationID", rid.clone())]);
+        if let Some(b) = r.bandwidth {
+            dict.insert("Bandwidth", b.to_string());
+        }
+        let resolve = |path: &str| merge_baseurls(&base_url, &resolve_url_template(path, &dict));
+        let mut track = PeriodTrack { init: None, segments: Vec::new(), keys, first_number: 0 };
+        // Attributes of a SegmentTemplate are inherited from the AdaptationSet and Period levels.
+        let templates: Vec<_> = [r.SegmentTemplate.as_ref(), a.SegmentTemplate.as_ref(), period.SegmentTemplate.as_ref()]
+            .into_iter()
+            .flatten()
+            .collect();
+        if let Some(sl) = r.SegmentList.as_ref().or(a.SegmentList.as_ref()).or(period.SegmentList.as_ref()) {
+            // (1) SegmentList addressing mode
+            if let Some(init) = &sl.Initialization {
+                let url = match &init.sourceURL {
+                    Some(su) => resolve(su)?,
+                    None => base_url.clone(),
+                };
+                let range = init.range.as_deref().map(parse_range).transpose()?;
+                track.init = Some(HlsInit { url, range });
+            }
+            let timescale = sl.timescale.unwrap_or(1).max(1) as f64;
+            let count = sl.segment_urls.len();
+            let durations: Vec<f64> = if let Some(stl) = &sl.SegmentTimeline {
+                timeline_segments(stl, None).iter().map(|(_, d)| *d as f64 / timescale).collect()
+            } else if let Some(d) = sl.duration {
+                template_segment_durations(count as u64, d as f64 / timescale, period_duration)
+            } else if let Some(pd) = period_duration {
+                vec![pd / count.max(1) as f64; count]
+            } else {
+                return Err(DashMpdError::UnhandledMediaStream(
+                    "can't determine the duration of SegmentList segments".to_string()));
+            };
+            for (i, su) in sl.segment_urls.iter().enumerate() {
+                let url = match &su.media {
+                    Some(m) => merge_baseurls(&base_url, m)?,
+                    None => base_url.clone(),
+                };
+                let range = su.mediaRange.as_deref().map(parse_range).transpose()?;
+                let duration = durations.get(i).copied().unwrap_or_default();
+                track.segments.push(HlsSegment { url, range, duration });
+            }
+        } else if !templates.is_empty() {
+            let initialization = templates.iter().find_map(|t| t.initialization.as_deref())
+                .or_else(|| templates.iter().find_map(|t| t.Initialization.as_ref()?.sourceURL.as_deref()));
+            if let Some(init) = initialization {
+                track.init = Some(HlsInit { url: resolve(init)?, range: None });
+            }
+            let Some(media) = templates.iter().find_map(|t| t.media.as_deref()) else {
+                return Err(DashMpdError::UnhandledMediaStream(
+                    "SegmentTemplate without a media attribute".to_string()));
+            };
+            let media = resolve_url_template(media, &dict);
+            let timescale = templates.iter().find_map(|t| t.timescale).unwrap_or(1).max(1);
+            let start_number = templates.iter().find_map(|t| t.startNumber).unwrap_or(1);
+            let end_number = templates.iter().find_map(|t| t.endNumber);
+            let pto = templates.iter().find_map(|t| t.presentationTimeOffset).unwrap_or(0);
+            track.first_number = start_number;
+            if let Some(stl) = templates.iter().find_map(|t| t.SegmentTimeline.as_ref()) {
+                // (2) SegmentTemplate+SegmentTimeline addressing mode
+                let end = period_duration.map(|pd| pto + (pd * timescale as f64).round() as u64);
+                for (number, (time, d)) in (start_number..).zip(timeline_segments(stl, end)) {
+                    if end_number.is_some_and(|en| number > en) {
+                        break;
+                    }
+                    let dict = HashMap::from([("Time", time.to_string()), ("Number", number.to_string())]);
+                    let url = merge_baseurls(&base_url, &resolve_url_template(&media, &dict))?;
+                    track.segments.push(HlsSegment { url, range: None, duration: d as f64 / timescale as f64 });
+                }
+            } else if let Some(d) = templates.iter().find_map(|t| t.duration) {
+                // (3) SegmentTemplate@duration addressing mode
+                if mpd_is_dynamic(mpd) {
+                    return Err(DashMpdError::UnhandledMediaStream(
+                        "can't convert a dynamic manifest using SegmentTemplate@duration addressing".to_string()));
+                }
+                let segment_duration = d / timescale as f64;
+                if segment_duration <= 0.0 {
+                    return Err(DashMpdError::UnhandledMediaStream(
+                        "SegmentTemplate@duration attribute cannot be zero".to_string()));
+                }
+                let count = match (end_number, period_duration) {
+                    (Some(en), _) => (en + 1).saturating_sub(start_number),
+                    (None, Some(pd)) => (pd / segment_duration - 1e-6).ceil().max(0.0) as u64,
+                    (None, None) => return Err(DashMpdError::UnhandledMediaStream(
+                        "can't determine the Period duration for SegmentTemplate@duration addressing".to_string())),
+                };
+                let durations = template_segment_durations(count, segment_duration, period_duration);
+                for (number, duration) in (start_number..).zip(durations) {
+                    let dict = HashMap::from([("Number", number.to_string())]);
+                    let url = merge_baseurls(&base_url, &resolve_url_template(&media, &dict))?;
+                    track.segments.push(HlsSegment { url, range: None, duration });
+                }
+            } else {
+                return Err(DashMpdError::UnhandledMediaStream(
+                    "SegmentTemplate without a SegmentTimeline or @duration".to_string()));
+            }
+        } else if let Some((sb, ir)) = r.SegmentBase.as_ref()
+            .or(a.SegmentBase.as_ref())
+            .or(period.SegmentBase.as_ref())
+            .and_then(|sb| Some((sb, sb.indexRange.as_deref()?)))
+        {
+            // (4) SegmentBase@indexRange addressing mode
+            let (index_start, index_end) = parse_range(ir)?;
+            let Some(idx) = self.segment_indexes.get(&(pidx, rid.clone())) else {
+                return Err(DashMpdError::UnhandledMediaStream(
+                    format!("the segment index of Representation {rid} is needed for SegmentBase addressing")));
+            };
+            let sidx = SidxBox::parse(idx)
+                .map_err(|e| DashMpdError::Parsing(format!("parsing sidx box of Representation {rid}: {e}")))?;
+            if sidx.references.iter().any(|sref| sref.reference_type != 0) {
+                return Err(DashMpdError::UnhandledMediaStream(
+                    "hierarchical sidx boxes are not supported".to_string()));
+            }
+            let init = sb.Initialization.as_ref();
+            let url = match init.and_then(|i| i.sourceURL.as_deref()) {
+                Some(su) => resolve(su)?,
+                None => base_url.clone(),
+            };
+            let range = match init.and_then(|i| i.range.as_deref()) {
+                Some(range) => parse_range(range)?,
+                None if index_start > 0 => (0, index_start - 1),
+                None => return Err(DashMpdError::UnhandledMediaStream(
+                    "SegmentBase without an initialization range".to_string())),
+            };
+            track.init = Some(HlsInit { url, range: Some(range) });
+            let timescale = f64::from(sidx.timescale.max(1));
+            let mut offset = index_end + 1 + sidx.first_offset;
+            for sref in &sidx.references {
+                let size = u64::from(sref.referenced_size);
+                track.segments.push(HlsSegment {
+                    url: base_url.clone(),
+                    range: Some((offset, offset + size - 1)),
+                    duration: f64::from(sref.subsegment_duration) / timescale,
+                });
+                offset += size;
+            }
+        } else {
+            // (5) BaseURL addressing mode: the whole resource is a single segment.
+            let Some(duration) = period_duration else {
+                return Err(DashMpdError::UnhandledMediaStream(
+                    "can't determine the Period duration for BaseURL addressing".to_string()));
+            };
+            track.segments.push(HlsSegment { url: base_url, range: None, duration });
+        }
+        if track.segments.is_empty() {
+            return Err(DashMpdError::UnhandledMediaStream(
+                format!("no media segments identified for Representation {rid}")));
+        }
+        Ok(track)
+    }
+
+    /// Generate the HLS media playlist for the Representation with @id `representation_id`. In a
+    /// multi-Period manifest, the playlist includes the segments of the Representation with this @id
+    /// in each Period.
+    pub fn media_playlist(&self, representation_id: &str) -> Result<String, DashMpdError> {
+        let mut tracks = Vec::new();
+        for (pidx, period) in self.mpd.periods.iter().enumerate() {
+            let found = period.adaptations.iter()
+                .flat_map(|a| a.representations.iter().map(move |r| (a, r)))
+                .find(|(_, r)| r.id.as_deref() == Some(representation_id));
+            match found {
+                Some((a, r)) => tracks.push(self.period_track(pidx, a, r)?),
+                None => warn!("Period {pidx} has no Representation {representation_id}"),
+            }
+        }
+        if tracks.is_empty() {
+            return Err(DashMpdError::Other(format!("no Representation with @id {representation_id}")));
+        }
+        let dynamic = mpd_is_dynamic(self.mpd);
+        let target_duration = tracks.iter()
+            .flat_map(|t| t.segments.iter())
+            .map(|s| s.duration.round() as u64)
+            .max()
+            .unwrap_or(1)
+            .max(1);
+        let mut lines = vec![
+            String::from("#EXTM3U"),
+            String::from("#EXT-X-VERSION:6"),
+            format!("#EXT-X-TARGETDURATION:{target_duration}"),
+        ];
+        if dynamic {
+            lines.push(format!("#EXT-X-MEDIA-SEQUENCE:{}", tracks[0].first_number));
+        } else {
+            lines.push(String::from("#EXT-X-PLAYLIST-TYPE:VOD"));
+        }
+        lines.push(String::from("#EXT-X-INDEPENDENT-SEGMENTS"));
+        let mut current_keys: Vec<HlsKey> = Vec::new();
+        let mut current_init: Option<HlsInit> = None;
+        for (i, track) in tracks.iter().enumerate() {
+            if i > 0 {
+                lines.push(String::from("#EXT-X-DISCONTINUITY"));
+            }
+            if track.keys != current_keys {
+                if track.keys.is_empty() {
+                    lines.push(String::from("#EXT-X-KEY:METHOD=NONE"));
+                }
+                for key in &track.keys {
+                    lines.push(format!("#EXT-X-KEY:{}", key.attributes()));
+                }
+                current_keys.clone_from(&track.keys);
+            }
+            if track.init != current_init {
+                if let Some(init) = &track.init {
+                    let mut map = format!("#EXT-X-MAP:URI={}", quoted(init.url.as_str()));
+                    if let Some(range) = init.range {
+                        map += &format!(",BYTERANGE=\"{}\"", byterange(range));
+                    }
+                    lines.push(map);
+                }
+                current_init.clone_from(&track.init);
+            }
+            for segment in &track.segments {
+                lines.push(format!("#EXTINF:{:.3},", segment.duration));
+                if let Some(range) = segment.range {
+                    lines.push(format!("#EXT-X-BYTERANGE:{}", byterange(range)));
+                }
+                lines.push(segment.url.to_string());
+            }
+        }
+        if !dynamic {
+            lines.push(String::from("#EXT-X-ENDLIST"));
+        }
+        Ok(lines.join("\n") + "\n")
+    }
+
+    // The audio renditions of the first Period, grouped by codec, and its subtitle renditions.
+    fn renditions(&self) -> (Vec<Rendition<'a>>, Vec<Rendition<'a>>) {
+        let Some(period) = self.mpd.periods.first() else {
+            return (Vec::new(), Vec::new());
+        };
+        let mut audio: Vec<Rendition> = Vec::new();
+        for a in period.adaptations.iter().filter(is_audio_adaptation) {
+            let mut by_codec: Vec<(&str, &Representation)> = Vec::new();
+            for r in a.representations.iter().filter(|r| r.id.is_some()) {
+                let codec = r.codecs.as_deref().or(a.codecs.as_deref()).unwrap_or("mp4a.40.2");
+                match by_codec.iter_mut().find(|(c, _)| *c == codec) {
+                    Some(best) if best.1.bandwidth < r.bandwidth => best.1 = r,
+                    Some(_) => (),
+                    None => by_codec.push((codec, r)),
+                }
+            }
+            for (codec, r) in by_codec {
+                let group = format!("audio-{codec}");
+                let name = rendition_name(a, &audio, &group, "Audio");
+                audio.push(Rendition { adaptation: a, representation: r, group, name });
+            }
+        }
+        let mut subtitles: Vec<Rendition> = Vec::new();
+        for a in period.adaptations.iter().filter(is_subtitle_adaptation) {
+            if !matches!(subtitle_type(&a), SubtitleType::Vtt | SubtitleType::Stpp) {
+                warn!("Ignoring subtitle AdaptationSet in a format not supported by HLS");
+                continue;
+            }
+            if let Some(r) = a.representations.iter().filter(|r| r.id.is_some()).max_by_key(|r| r.bandwidth) {
+                let group = String::from("subs");
+                let name = rendition_name(a, &subtitles, &group, "Subtitles");
+                subtitles.push(Rendition { adaptation: a, representation: r, group, name });
+            }
+        }
+        (audio, subtitles)
+    }
+
+    /// Generate the HLS multivariant playlist, which references the media playlists named by
+    /// [media_playlist_name]. The variants and renditions are those of the first Period of the
+    /// manifest.
+    pub fn multivariant_playlist(&self) -> Result<String, DashMpdError> {
+        let Some(period) = self.mpd.periods.first() else {
+            return Err(DashMpdError::Other(String::from("manifest has no Period")));
+        };
+        let (audio, subtitles) = self.renditions();
+        let mut lines = vec![
+            String::from("#EXTM3U"),
+            String::from("#EXT-X-VERSION:6"),
+            String::from("#EXT-X-INDEPENDENT-SEGMENTS"),
+        ];
+        let mut session_keys: Vec<HlsKey> = Vec::new();
+        let mut add_session_keys = |a: &AdaptationSet, r: &Representation| {
+            for key in content_protection_keys(content_protections(self.mpd, period, a, r)) {
+                if !session_keys.contains(&key) {
+                    session_keys.push(key);
+                }
+            }
+        };
+        let videos: Vec<(&AdaptationSet, &Representation)> = period.adaptations.iter()
+            .filter(is_video_adaptation)
+            .filter(|a| !is_trickmode_adaptation(a))
+            .filter(|a| spatial_relationship(a, None).is_none() || srd_full_frame(a, period))
+            .flat_map(|a| a.representations.iter().map(move |r| (a, r)))
+            .filter(|(_, r)| r.id.is_some())
+            .collect();
+        for (a, r) in videos.iter().copied() {
+            add_session_keys(a, r);
+        }
+        for rendition in audio.iter().chain(subtitles.iter()) {
+            add_session_keys(rendition.adaptation, rendition.representation);
+        }
+        for key in &session_keys {
+            lines.push(format!("#EXT-X-SESSION-KEY:{}", key.attributes()));
+        }
+        let mut groups: Vec<&str> = Vec::new();
+        for rendition in &audio {
+            let default = !groups.contains(&rendition.group.as_str());
+            if default {
+                groups.push(&rendition.group);
+            }
+            lines.push(media_tag("AUDIO", rendition, default));
+        }
+        for rendition in &subtitles {
+            lines.push(media_tag("SUBTITLES", rendition, false));
+        }
+        let subtitle_codec = subtitles.iter()
+            .any(|s| subtitle_type(&s.adaptation) == SubtitleType::Stpp)
+            .then_some(IMSC1_CODEC);
+        let subtitle_group = (!subtitles.is_empty()).then_some("subs");
+        if videos.is_empty() {
+            // An audio-only presentation: each audio rendition is a variant.
+            for rendition in &audio {
+                let (a, r) = (rendition.adaptation, rendition.representation);
+                let codecs = [r.codecs.as_deref().or(a.codecs.as_deref()), subtitle_codec];
+                let mut attrs = vec![format!("BANDWIDTH={}", r.bandwidth.unwrap_or(0))];
+                attrs.push(format!("CODECS={}", quoted(&codecs.into_iter().flatten().collect::<Vec<_>>().join(","))));
+                if let Some(g) = subtitle_group {
+                    attrs.push(format!("SUBTITLES={}", quoted(g)));
+                }
+                lines.push(format!("#EXT-X-STREAM-INF:{}", attrs.join(",")));
+                lines.push(media_playlist_name(r.id.as_deref().unwrap_or_default()));
+            }
+        }
+        for (a, r) in videos {
+            let video_codec = r.codecs.as_deref().or(a.codecs.as_deref());
+            // Each video Representation is combined with each audio rendition group.
+            let audio_groups: Vec<Option<&str>> = if groups.is_empty() {
+                vec![None]
+            } else {
+                groups.iter().map(|g| Some(*g)).collect()
+            };
+            for group in audio_groups {
+                let group_renditions = audio.iter().filter(|ar| Some(ar.group.as_str()) == group);
+                let audio_bandwidth = group_renditions.clone()
+                    .filter_map(|ar| ar.representation.bandwidth)
+                    .max()
+                    .unwrap_or(0);
+                let audio_codec = group_renditions.clone()
+                    .find_map(|ar| ar.representation.codecs.as_deref().or(ar.adaptation.codecs.as_deref()));
+                let codecs: Vec<&str> = [video_codec, audio_codec, subtitle_codec].into_iter().flatten().collect();
+                let mut attrs = vec![format!("BANDWIDTH={}", r.bandwidth.unwrap_or(0) + audio_bandwidth)];
+                if !codecs.is_empty() {
+                    attrs.push(format!("CODECS={}", quoted(&codecs.join(","))));
+                }
+                if let (Some(w), Some(h)) = (r.width.or(a.width), r.height.or(a.height)) {
+                    attrs.push(format!("RESOLUTION={w}x{h}"));
+                }
+                if let Some(fr) = r.frameRate.as_deref().or(a.frameRate.as_deref()).and_then(parse_manifest_frame_rate) {
+                    attrs.push(format!("FRAME-RATE={fr:.3}"));
+                }
+                let range = match video_dynamic_range(a, r) {
+                    VideoDynamicRange::Sdr => "SDR",
+                    VideoDynamicRange::Hlg => "HLG",
+                    VideoDynamicRange::Hdr10 | VideoDynamicRange::DolbyVision(_) => "PQ",
+                };
+                attrs.push(format!("VIDEO-RANGE={range}"));
+                if let Some(g) = group {
+                    attrs.push(format!("AUDIO={}", quoted(g)));
+                }
+                if let Some(g) = subtitle_group {
+                    attrs.push(format!("SUBTITLES={}", quoted(g)));
+                }
+                lines.push(format!("#EXT-X-STREAM-INF:{}", attrs.join(",")));
+                lines.push(media_playlist_name(r.id.as_deref().unwrap_or_default()));
+            }
+        }
+        Ok(lines.join("\n") + "\n")
+    }
+
+    /// Generate the media playlists referenced by the multivariant playlist, as a list of (name,
+    /// content) pairs, where the name is that given by [media_playlist_name].
+    pub fn media_playlists(&self) -> Result<Vec<(String, String)>, DashMpdError> {
+        let Some(period) = self.mpd.periods.first() else {
+            return Ok(Vec::new());
+        };
+        let (audio, subtitles) = self.renditions();
+        let mut ids: Vec<&str> = period.adaptations.iter()
+            .filter(is_video_adaptation)
+            .filter(|a| !is_trickmode_adaptation(a))
+            .filter(|a| spatial_relationship(a, None).is_none() || srd_full_frame(a, period))
+            .flat_map(|a| a.representations.iter())
+            .filter_map(|r| r.id.as_deref())
+            .collect();
+        ids.extend(audio.iter().chain(subtitles.iter()).filter_map(|r| r.representation.id.as_deref()));
+        ids.iter()
+            .map(|id| Ok((media_playlist_name(id), self.media_playlist(id)?)))
+            .collect()
+    }
+}
+
+// The NAME of a rendition, from the Label or the language of its AdaptationSet. It must be unique
+// within its group.
+fn rendition_name(a: &AdaptationSet, existing: &[Rendition], group: &str, fallback: &str) -> String {
+    let base = a.Label.first()
+        .map(|l| l.content.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .or_else(|| adaptation_language(a).map(String::from))
+        .unwrap_or_else(|| String::from(fallback));
+    let taken = |name: &str| existing.iter().any(|r| r.group == group && r.name == name);
+    let mut name = base.clone();
+    let mut n = 2;
+    while taken(&name) {
+        name = format!("{base} ({n})");
+        n += 1;
+    }
+    name
+}
+
+fn media_tag(media_type: &str, rendition: &Rendition, default: bool) -> String {
+    let a = rendition.adaptation;
+    let r = rendition.representation;
+    let mut attrs = vec![
+        format!("TYPE={media_type}"),
+        format!("GROUP-ID={}", quoted(&rendition.group)),
+        format!("NAME={}", quoted(&rendition.name)),
+    ];
+    if let Some(lang) = adaptation_language(a) {
+        attrs.push(format!("LANGUAGE={}", quoted(lang)));
+    }
+    attrs.push(format!("DEFAULT={}", if default { "YES" } else { "NO" }));
+    attrs.push(String::from("AUTOSELECT=YES"));
+    let mut characteristics = Vec::new();
+    if media_type == "SUBTITLES" {
+        let forced = adaptation_forced_subtitles(a);
+        attrs.push(format!("FORCED={}", if forced { "YES" } else { "NO" }));
+        if adaptation_hearing_impaired(a) {
+            characteristics.push("public.accessibility.transcribes-spoken-dialog");
+            characteristics.push("public.accessibility.describes-music-and-sound");
+        }
+    } else if adaptation_audio_description(a) {
+        characteristics.push("public.accessibility.describes-video");
+    }
+    if !characteristics.is_empty() {
+        attrs.push(format!("CHARACTERISTICS={}", quoted(&characteristics.join(","))));
+    }
+    if media_type == "AUDIO" {
+        let channels = r.AudioChannelConfiguration.iter()
+            .chain(a.AudioChannelConfiguration.iter())
+            .find_map(AudioChannelConfiguration::channel_count);
+        if let Some(channels) = channels {
+            attrs.push(format!("CHANNELS=\"{channels}\""));
+        }
+    }
+    attrs.push(format!("URI={}", quoted(&media_playlist_name(r.id.as_deref().unwrap_or_default()))));
+    format!("#EXT-X-MEDIA:{}", attrs.join(","))
+}
@@ -0,0 +1,138 @@
+//! Support for loading content decryption keys from files
+//
+// We support two formats:
+//
+//   - the simple text format used by many tools, with one KID:KEY pair per line, where KID is a
+//     128-bit key identifier (32 hexadecimal characters, optionally in UUID format with hyphens) or
+//     a decimal track ID, and KEY is a 128-bit key in hexadecimal. Empty lines and lines starting
+//     with # are ignored.
+//
+//   - CPIX documents (DASH-IF Content Protection Information Exchange format), from which we read
+//     the ContentKeyList. Content keys may be present as a pskc:PlainValue, or as a
+//     pskc:EncryptedValue that is encrypted with the document key using AES-256-CBC. The document
+//     key is itself encrypted in the DeliveryDataList using the public key of the recipient; the
+//     caller is responsible for decrypting it and providing it to us.
+//
+//     https://dashif.org/guidelines/others/#content-protection-information-exchange-format-cpix
+
+
+use std::collections::HashMap;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use aes::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+use xot::Xot;
+use crate::DashMpdError;
+use crate::clearkey::{bytes_to_hex, hex_to_bytes};
+
+
+fn is_hex(s: &str) -> bool {
+    s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// A KID in UUID format or as 32 hex characters is normalized to 32 lowercase hex characters. A
+// decimal track ID is returned unchanged.
+fn normalize_key_id(kid: &str) -> Option<String> {
+    let kid = kid.trim();
+    if !kid.is_empty() && kid.chars().all(|c| c.is_ascii_digit()) && kid.len() < 10 {
+        return Some(kid.to_string());
+    }
+    let hex = kid.replace('-', "").to_lowercase();
+    if hex.len() == 32 && is_hex(&hex) {
+        Some(hex)
+    } else {
+        None
+    }
+}
+
+/// Parse the contents of a key file in the `KID:KEY` format (one pair per line) into a map from
+/// KID to key, in the format expected by `DashDownloader::add_decryption_key`.
+pub fn parse_key_file(contents: &str) -> Result<HashMap<String, String>, DashMpdError> {
+    let mut keys = HashMap::new();
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = || DashMpdError::Decrypting(format!("invalid KID:KEY pair on line {}", lineno + 1));
+        let (kid, key) = line.split_once(':').ok_or_else(bad_line)?;
+        let kid = normalize_key_id(kid).ok_or_else(bad_line)?;
+        let key = key.trim().to_lowercase();
+        if key.len() != 32 || !is_hex(&key) {
+            return Err(bad_line());
+        }
+        keys.insert(kid, key);
+    }
+    Ok(keys)
+}
+
+/// Parse the ContentKeyList of a CPIX document into a map from KID to key, both in hexadecimal
+/// format. `document_key` is the 256-bit document key (already decrypted using the private key of
+/// the recipient) which is needed if the document contains encrypted content keys.
+pub fn parse_cpix(xml: &str, document_key: Option<&[u8]>) -> Result<HashMap<String, String>, DashMpdError> {
+    let mut xot = Xot::new();
+    let root = xot.parse(xml)
+        .map_err(|e| DashMpdError::Parsing(format!("parsing CPIX document: {e}")))?;
+    let doc = xot.document_element(root)
+        .map_err(|e| DashMpdError::Parsing(format!("parsing CPIX document: {e}")))?;
+    let is_named = |xot: &Xot, n: xot::Node, name: &str| {
+        xot.element(n).is_some_and(|e| xot.name_ns_str(e.name()).0.eq(name))
+    };
+    if !is_named(&xot, doc, "CPIX") {
+        return Err(DashMpdError::Parsing(String::from("expecting CPIX root element")));
+    }
+    let kid_name = xot.add_name("kid");
+    let mut keys = HashMap::new();
+    for ck in xot.descendants(doc).filter(|n| is_named(&xot, *n, "ContentKey")) {
+        let kid = xot.get_attribute(ck, kid_name)
+            .and_then(normalize_key_id)
+            .ok_or_else(|| DashMpdError::Parsing(String::from("missing or invalid kid on CPIX ContentKey")))?;
+        let plain = xot.descendants(ck)
+            .find(|n| is_named(&xot, *n, "PlainValue"))
+            .map(|n| xot.string_value(n));
+        let cipher = xot.descendants(ck)
+            .find(|n| is_named(&xot, *n, "CipherValue"))
+            .map(|n| xot.string_value(n));
+        let key = if let Some(b64) = plain {
+            let b64: String = b64.split_whitespace().collect();
+            BASE64_STANDARD.decode(b64)
+                .map_err(|e| DashMpdError::Parsing(format!("decoding CPIX PlainValue: {e}")))?
+        } else if let Some(b64) = cipher {
+            let dk = document_key.ok_or_else(|| DashMpdError::Decrypting(
+                String::from("CPIX document contains encrypted content keys but no document key was provided")))?;
+            let b64: String = b64.split_whitespace().collect();
+            let encrypted = BASE64_STANDARD.decode(b64)
+                .map_err(|e| DashMpdError::Parsing(format!("decoding CPIX CipherValue: {e}")))?;
+            aes256_cbc_decrypt(dk, &encrypted)?
+        } else {
+            // A ContentKey without key data, used only to reference the KID.
+            continue;
+        };
+        if key.len() != 16 {
+            return Err(DashMpdError::Decrypting(format!("unexpected length for CPIX content key {kid}")));
+        }
+        keys.insert(kid, bytes_to_hex(&key));
+    }
+    Ok(keys)
+}
+
+/// Parse a 256-bit CPIX document key specified in hexadecimal.
+pub fn parse_document_key(hex: &str) -> Result<Vec<u8>, DashMpdError> {
+    match hex_to_bytes(hex.trim()) {
+        Some(key) if key.len() == 32 => Ok(key),
+        _ => Err(DashMpdError::Decrypting(String::from("CPIX document key must be 64 hex characters"))),
+    }
+}
+
+
+// Decrypt data encrypted as specified by XML Encryption for the aes256-cbc algorithm: the first 16
+// octets are the IV, and the plaintext is padded to a multiple of the block size using PKCS#7
+// padding. A padding error generally means that the document key is wrong.
+fn aes256_cbc_decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>, DashMpdError> {
+    if data.len() < 32 {
+        return Err(DashMpdError::Decrypting(String::from("invalid length for AES-256-CBC ciphertext")));
+    }
+    let (iv, ciphertext) = data.split_at(16);
+    cbc::Decryptor::<aes::Aes256>::new_from_slices(key, iv)
+        .map_err(|_| DashMpdError::Decrypting(String::from("AES-256 key must be 32 octets")))?
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| DashMpdError::Decrypting(String::from("invalid padding in AES-256-CBC plaintext (wrong document key?)")))
+}
@@ -0,0 +1,299 @@
+//! Language tags and language matching
+//
+// Language information appears in DASH manifests in the lang attribute of AdaptationSet and
+// Representation elements, which should contain a BCP 47 (RFC 5646) language tag such as "fr",
+// "pt-BR" or "zh-Hant-TW". In practice, manifests also use ISO 639-2 three-letter codes in their
+// terminology ("deu") or bibliographic ("ger") forms, so we map ISO 639-1, 639-2/T and 639-2/B codes
+// to each other before comparing languages.
+//
+// The language preferences specified by the user are matched against the available languages
+// following the lookup scheme of RFC 4647: when no track matches a preference such as
+// "zh-Hant-TW", the preference is progressively truncated to "zh-Hant" then "zh". Unlike strict
+// lookup, a preference also matches tags which are more specific than it ("en" matches "en-US"),
+// with a lower rank than an exact match.
+//
+//   https://www.rfc-editor.org/rfc/rfc5646
+//   https://www.rfc-editor.org/rfc/rfc4647#section-3.4
+
+
+use std::fmt;
+
+
+// The ISO 639-2/T three-letter code corresponding to each ISO 639-1 two-letter language code.
+static ISO639_1_TO_2: &[(&str, &str)] = &[
+    ("aa", "aar"), ("ab", "abk"), ("ae", "ave"), ("af", "afr"), ("ak", "aka"), ("am", "amh"),
+    ("an", "arg"), ("ar", "ara"), ("as", "asm"), ("av", "ava"), ("ay", "aym"), ("az", "aze"),
+    ("ba", "bak"), ("be", "bel"), ("bg", "bul"), ("bh", "bih"), ("bi", "bis"), ("bm", "bam"),
+    ("bn", "ben"), ("bo", "bod"), ("br", "bre"), ("bs", "bos"), ("ca", "cat"), ("ce", "che"),
+    ("ch", "cha"), ("co", "cos"), ("cr", "cre"), ("cs", "ces"), ("cu", "chu"), ("cv", "chv"),
+    ("cy", "cym"), ("da", "dan"), ("de", "deu"), ("dv", "div"), ("dz", "dzo"), ("ee", "ewe"),
+    ("el", "ell"), ("en", "eng"), ("eo", "epo"), ("es", "spa"), ("et", "est"), ("eu", "eus"),
+    ("fa", "fas"), ("ff", "ful"), ("fi", "fin"), ("fj", "fij"), ("fo", "fao"), ("fr", "fra"),
+    ("fy", "fry"), ("ga", "gle"), ("gd", "gla"), ("gl", "glg"), ("gn", "grn"), ("gu", "guj"),
+    ("gv", "glv"), ("ha", "hau"), ("he", "heb"), ("hi", "hin"), ("ho", "hmo"), ("hr", "hrv"),
+    ("ht", "hat"), ("hu", "hun"), ("hy", "hye"), ("hz", "her"), ("ia", "ina"), ("id", "ind"),
+    ("ie", "ile"), ("ig", "ibo"), ("ii", "iii"), ("ik", "ipk"), ("io", "ido"), ("is", "isl"),
+    ("it", "ita"), ("iu", "iku"), ("ja", "jpn"), ("jv", "jav"), ("ka", "kat"), ("kg", "kon"),
+    ("ki", "kik"), ("kj", "kua"), ("kk", "kaz"), ("kl", "kal"), ("km", "khm"), ("kn", "kan"),
+    ("ko", "kor"), ("kr", "kau"), ("ks", "kas"), ("ku", "kur"), ("kv", "kom"), ("kw", "cor"),
+    ("ky", "kir"), ("la", "lat"), ("lb", "ltz"), ("lg", "lug"), ("li", "lim"), ("ln", "lin"),
+    ("lo", "lao"), ("lt", "lit"), ("lu", "lub"), ("lv", "lav"), ("mg", "mlg"), ("mh", "mah"),
+    ("mi", "mri"), ("mk", "mkd"), ("ml", "mal"), ("mn", "mon"), ("mr", "mar"), ("ms", "msa"),
+    ("mt", "mlt"), ("my", "mya"), ("na", "nau"), ("nb", "nob"), ("nd", "nde"), ("ne", "nep"),
+    ("ng", "ndo"), ("nl", "nld"), ("nn", "nno"), ("no", "nor"), ("nr", "nbl"), ("nv", "nav"),
+    ("ny", "nya"), ("oc", "oci"), ("oj", "oji"), ("om", "orm"), ("or", "ori"), ("os", "oss"),
+    ("pa", "pan"), ("pi", "pli"), ("pl", "pol"), ("ps", "pus"), ("pt", "por"), ("qu", "que"),
+    ("rm", "roh"), ("rn", "run"), ("ro", "ron"), ("ru", "rus"), ("rw", "kin"), ("sa", "san"),
+    ("sc", "srd"), ("sd", "snd"), ("se", "sme"), ("sg", "sag"), ("si", "sin"), ("sk", "slk"),
+    ("sl", "slv"), ("sm", "smo"), ("sn", "sna"), ("so", "som"), ("sq", "sqi"), ("sr", "srp"),
+    ("ss", "ssw"), ("st", "sot"), ("su", "sun"), ("sv", "swe"), ("sw", "swa"), ("ta", "tam"),
+    ("te", "tel"), ("tg", "tgk"), ("th", "tha"), ("ti", "tir"), ("tk", "tuk"), ("tl", "tgl"),
+    ("tn", "tsn"), ("to", "ton"), ("tr", "tur"), ("ts", "tso"), ("tt", "tat"), ("tw", "twi"),
+    ("ty", "tah"), ("ug", "uig"), ("uk", "ukr"), ("ur", "urd"), ("uz", "uzb"), ("ve", "ven"),
+    ("vi", "vie"), ("vo", "vol"), ("wa", "wln"), ("wo", "wol"), ("xh", "xho"), ("yi", "yid"),
+    ("yo", "yor"), ("za", "zha"), ("zh", "zho"), ("zu", "zul"),
+];
+
+// ISO 639-2 languages whose bibliographic code differs from the terminology code.
+static ISO639_2T_TO_2B: &[(&str, &str)] = &[
+    ("sqi", "alb"), ("hye", "arm"), ("eus", "baq"), ("bod", "tib"), ("mya", "bur"), ("ces", "cze"),
+    ("zho", "chi"), ("cym", "wel"), ("deu", "ger"), ("nld", "dut"), ("ell", "gre"), ("fas", "per"),
+    ("fra", "fre"), ("kat", "geo"), ("isl", "ice"), ("mkd", "mac"), ("mri", "mao"), ("msa", "may"),
+    ("ron", "rum"), ("slk", "slo"),
+];
+
+
+// The ISO 639-1 two-letter code for a lowercase ISO 639 code, or None if the language has no
+// two-letter code.
+fn two_letter_code(code: &str) -> Option<&'static str> {
+    match code.len() {
+        2 => ISO639_1_TO_2.iter()
+            .find(|(two, _)| code.eq(*two))
+            .map(|(two, _)| *two),
+        3 => {
+            let term = ISO639_2T_TO_2B.iter()
+                .find(|(_, bib)| code.eq(*bib))
+                .map_or(code, |(term, _)| *term);
+            ISO639_1_TO_2.iter()
+                .find(|(_, three)| term.eq(*three))
+                .map(|(two, _)| *two)
+        },
+        _ => None,
+    }
+}
+
+/// A BCP 47 (RFC 5646) language tag, split into its subtags. The primary language subtag is
+/// normalized to its ISO 639-1 code when one exists, so that "fr", "fra" and "fre" are equal. Other
+/// subtags are normalized to the case recommended by RFC 5646 ("zh-Hant-TW").
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanguageTag {
+    pub language: String,
+    pub extlangs: Vec<String>,
+    pub script: Option<String>,
+    pub region: Option<String>,
+    /// Variant, extension and private use subtags, in lowercase.
+    pub variants: Vec<String>,
+}
+
+impl LanguageTag {
+    /// Parse a language tag. Subtags may be separated by "-" or "_". Returns None if the primary
+    /// language subtag is not alphabetic. Parsing is lenient: subtags which are not well-formed are
+    /// kept with the variants rather than rejected, because they appear in real-world manifests.
+    pub fn parse(tag: &str) -> Option<LanguageTag> {
+        let mut subtags = tag.trim().split(['-', '_']);
+        let primary = subtags.next()?.to_ascii_lowercase();
+        if primary.is_empty() || primary.len() > 8 || !primary.chars().all(|c| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        let language = two_letter_code(&primary).map_or(primary, String::from);
+        let mut lt = LanguageTag {
+            language, extlangs: Vec::new(), script: None, region: None, variants: Vec::new()
+        };
+        for st in subtags {
+            let alpha = st.chars().all(|c| c.is_ascii_alphabetic());
+            let digit = st.chars().all(|c| c.is_ascii_digit());
+            if !lt.variants.is_empty() {
+                lt.variants.push(st.to_ascii_lowercase());
+            } else if alpha && st.len() == 3 && lt.script.is_none() && lt.region.is_none() && lt.extlangs.len() < 3 {
+                lt.extlangs.push(st.to_ascii_lowercase());
+            } else if alpha && st.len() == 4 && lt.script.is_none() && lt.region.is_none() {
+                let mut script = st.to_ascii_lowercase();
+                script[..1].make_ascii_uppercase();
+                lt.script = Some(script);
+            } else if ((alpha && st.len() == 2) || (digit && st.len() == 3)) && lt.region.is_none() {
+                lt.region = Some(st.to_ascii_uppercase());
+            } else if !st.is_empty() {
+                lt.variants.push(st.to_ascii_lowercase());
+            }
+        }
+        Some(lt)
+    }
+
+    // The subtags in order, in lowercase, for comparison purposes.
+    fn subtags(&self) -> Vec<String> {
+        let mut out = vec![self.language.clone()];
+        out.extend(self.extlangs.iter().cloned());
+        out.extend(self.script.iter().map(|s| s.to_ascii_lowercase()));
+        out.extend(self.region.iter().map(|r| r.to_ascii_lowercase()));
+        out.extend(self.variants.iter().cloned());
+        out
+    }
+
+    /// Is this the tag for an undetermined language ("und") or for content without linguistic
+    /// content ("zxx")?
+    pub fn is_undetermined(&self) -> bool {
+        matches!(self.language.as_str(), "und" | "unk" | "zxx" | "mis" | "mul")
+    }
+
+    /// The ISO 639-2/T three-letter code for the primary language, as used in the mdhd box of an
+    /// MP4 file.
+    pub fn iso639_2t(&self) -> Option<String> {
+        if self.is_undetermined() {
+            return None;
+        }
+        match self.language.len() {
+            2 => ISO639_1_TO_2.iter()
+                .find(|(two, _)| self.language.eq(two))
+                .map(|(_, three)| String::from(*three)),
+            3 => Some(self.language.clone()),
+            _ => None,
+        }
+    }
+
+    /// The ISO 639-2/B three-letter code for the primary language, as used in the Language element
+    /// of a Matroska track.
+    pub fn iso639_2b(&self) -> Option<String> {
+        let term = self.iso639_2t()?;
+        Some(ISO639_2T_TO_2B.iter()
+             .find(|(t, _)| term.eq(t))
+             .map_or(term.clone(), |(_, bib)| String::from(*bib)))
+    }
+
+    /// The language ranges tried by RFC 4647 lookup for this tag, from the most to the least
+    /// specific: "zh-Hant-TW", then "zh-Hant", then "zh". Singleton subtags (which introduce an
+    /// extension or private use sequence) are removed along with the subtag following them.
+    pub fn fallbacks(&self) -> Vec<String> {
+        let mut subtags = self.subtags();
+        let mut out = Vec::new();
+        while !subtags.is_empty() {
+            out.push(subtags.join("-"));
+            subtags.pop();
+            while subtags.last().is_some_and(|s| s.len() == 1) {
+                subtags.pop();
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for LanguageTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.language)?;
+        for e in &self.extlangs {
+            write!(f, "-{e}")?;
+        }
+        if let Some(s) = &self.script {
+            write!(f, "-{s}")?;
+        }
+        if let Some(r) = &self.region {
+            write!(f, "-{r}")?;
+        }
+        for v in &self.variants {
+            write!(f, "-{v}")?;
+        }
+        Ok(())
+    }
+}
+
+/// How well the available language tag `available` matches the language preference `wanted`, with
+/// zero the best match, or None if they don't match. An exact match (after normalization of ISO
+/// 639 codes) ranks 0 and a tag which is more specific than the preference ranks 1 ("en-US" for
+/// "en"). When the preference has to be truncated following RFC 4647 lookup, each truncation adds 2
+/// to the rank, so that for a preference of "zh-Hant-TW", "zh-Hant" ranks 2 and "zh-Hans" ranks 5.
+pub fn language_match_rank(available: &str, wanted: &str) -> Option<usize> {
+    let available = LanguageTag::parse(available)?.subtags();
+    let wanted = LanguageTag::parse(wanted)?;
+    for (truncations, range) in wanted.fallbacks().iter().enumerate() {
+        let range: Vec<&str> = range.split('-').collect();
+        if available.len() >= range.len() && available.iter().zip(&range).all(|(a, r)| a.eq(r)) {
+            let more_specific = usize::from(available.len() > range.len());
+            return Some(2 * truncations + more_specific);
+        }
+    }
+    None
+}
+
+/// The rank of the best match for `available` in the ordered list of language preferences
+/// `wanted`, as a pair (index of the matching preference, match rank), or None if it matches none
+/// of the preferences. Lower ranks are better.
+pub fn language_preference_rank<S: AsRef<str>>(available: &str, wanted: &[S]) -> Option<(usize, usize)> {
+    wanted.iter()
+        .enumerate()
+        .find_map(|(i, w)| language_match_rank(available, w.as_ref()).map(|r| (i, r)))
+}
+
+/// The ISO 639-2/T three-letter code for the primary language of a language tag or ISO 639 code.
+pub fn iso639_2t(lang: &str) -> Option<String> {
+    LanguageTag::parse(lang)?.iso639_2t()
+}
+
+/// The ISO 639-2/B three-letter code for the primary language of a language tag or ISO 639 code.
+pub fn iso639_2b(lang: &str) -> Option<String> {
+    LanguageTag::parse(lang)?.iso639_2b()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_language_tag() {
+        let lt = LanguageTag::parse("zh-hant-tw").unwrap();
+        assert_eq!(lt.language, "zh");
+        assert_eq!(lt.script.as_deref(), Some("Hant"));
+        assert_eq!(lt.region.as_deref(), Some("TW"));
+        assert_eq!(lt.to_string(), "zh-Hant-TW");
+        assert_eq!(LanguageTag::parse("ger").unwrap().language, "de");
+        assert_eq!(LanguageTag::parse("deu").unwrap().language, "de");
+        assert_eq!(LanguageTag::parse("pt_BR").unwrap().to_string(), "pt-BR");
+        assert_eq!(LanguageTag::parse("es-419").unwrap().region.as_deref(), Some("419"));
+        assert_eq!(LanguageTag::parse("yue").unwrap().language, "yue");
+        assert!(LanguageTag::parse("").is_none());
+        assert!(LanguageTag::parse("42").is_none());
+    }
+
+    #[test]
+    fn test_iso639_codes() {
+        assert_eq!(iso639_2t("fr-CA").as_deref(), Some("fra"));
+        assert_eq!(iso639_2b("fr-CA").as_deref(), Some("fre"));
+        assert_eq!(iso639_2t("chi").as_deref(), Some("zho"));
+        assert_eq!(iso639_2b("zh-Hant").as_deref(), Some("chi"));
+        assert_eq!(iso639_2b("spa").as_deref(), Some("spa"));
+        assert_eq!(iso639_2t("und"), None);
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let lt = LanguageTag::parse("zh-Hant-TW").unwrap();
+        assert_eq!(lt.fallbacks(), vec!["zh-hant-tw", "zh-hant", "zh"]);
+        let lt = LanguageTag::parse("en-US-x-twain").unwrap();
+        assert_eq!(lt.fallbacks(), vec!["en-us-x-twain", "en-us", "en"]);
+    }
+
+    #[test]
+    fn test_language_match_rank() {
+        assert_eq!(language_match_rank("en", "en"), Some(0));
+        assert_eq!(language_match_rank("en-GB", "en"), Some(1));
+        assert_eq!(language_match_rank("EN-us", "en-US"), Some(0));
+        assert_eq!(language_match_rank("en", "en-US"), Some(2));
+        assert_eq!(language_match_rank("eng", "en"), Some(0));
+        assert_eq!(language_match_rank("por", "pt-BR"), Some(2));
+        assert_eq!(language_match_rank("fr-CA", "en"), None);
+        assert_eq!(language_match_rank("de", "eng"), None);
+        assert_eq!(language_match_rank("zh-Hant", "zh-Hant-TW"), Some(2));
+        assert_eq!(language_match_rank("zh-Hans", "zh-Hant-TW"), Some(5));
+        assert_eq!(language_preference_rank("fr", &["de", "fra"]), Some((1, 0)));
+        assert_eq!(language_preference_rank("it", &["de", "fra"]), None);
+    }
+}
@@ -74,15 +74,33 @@ mod libav;
 #[cfg(all(feature = "fetch", not(feature = "libav")))]
 pub mod ffmpeg;
 #[cfg(feature = "fetch")]
+pub mod fmp4;
+#[cfg(feature = "fetch")]
+pub mod webm;
+#[cfg(feature = "fetch")]
 pub mod sidx;
 #[cfg(feature = "fetch")]
 pub mod fetch;
 #[cfg(feature = "fetch")]
 pub mod decryption;
 #[cfg(feature = "fetch")]
+pub mod clearkey;
+#[cfg(feature = "fetch")]
+pub mod keys;
+#[cfg(feature = "fetch")]
 pub mod stpp;
 #[cfg(feature = "fetch")]
 pub mod vtt;
+#[cfg(feature = "fetch")]
+pub mod wvtt;
+#[cfg(feature = "fetch")]
+pub mod thumbnails;
+#[cfg(feature = "fetch")]
+pub mod hls;
+// Parsing of PlayReady Objects (PlayReady DRM initialization data)
+pub mod playready;
+// Matching and conversion of language tags (BCP 47 and ISO 639 codes)
+pub mod language;
 // Support for the SCTE-35 standard for insertion of alternate content
 #[cfg(feature = "scte35")]
 pub mod scte35;
@@ -90,9 +108,9 @@ pub mod scte35;
 use crate::scte35::{Signal, SpliceInfoSection};
 
 #[cfg(all(feature = "fetch", feature = "libav"))]
-use crate::libav::{mux_audio_video, copy_video_to_container, copy_audio_to_container};
+use crate::libav::{mux_audio_video, mux_audio_tracks, mux_subtitle_tracks, copy_video_to_container, copy_audio_to_container};
 #[cfg(all(feature = "fetch", not(feature = "libav")))]
-use crate::ffmpeg::{mux_audio_video, copy_video_to_container, copy_audio_to_container};
+use crate::ffmpeg::{mux_audio_video, mux_audio_tracks, mux_subtitle_tracks, copy_video_to_container, copy_audio_to_container};
 
 #[cfg(all(feature = "sandbox", feature = "fetch", target_os = "linux"))]
 pub mod sandbox;
@@ -981,6 +999,63 @@ pub struct AudioChannelConfiguration {
     pub id: Option<String>,
 }
 
+// The number of channels for each value of the MPEG ChannelConfiguration (ISO/IEC 23091-3, formerly
+// ISO/IEC 23001-8), indexed by value. Value 0 means that the channel layout is not specified.
+const CICP_CHANNEL_COUNTS: [u32; 21] = [0, 1, 2, 3, 4, 5, 6, 8, 2, 3, 4, 7, 8, 24, 8, 12, 10, 12, 14, 12, 14];
+
+// The Dolby channel configuration (ETSI TS 102 366 Annex I) is a 16-bit mask, whose bits from the
+// most significant represent L, C, R, Ls, Rs, Lc/Rc, Lrs/Rrs, Cs, Ts, Lsd/Rsd, Lw/Rw, Vhl/Vhr, Vhc,
+// Lts/Rts, LFE2 and LFE. Some bits designate a pair of channels.
+const DOLBY_CHANNEL_PAIR_BITS: u32 = 0x0400 | 0x0200 | 0x0040 | 0x0020 | 0x0010 | 0x0004;
+
+// The Dolby AC-4 channel configuration (ETSI TS 103 190-2 Annex G.3) is a 24-bit mask, whose bits
+// from the least significant represent L/R, C, Ls/Rs, Lb/Rb, Tfl/Tfr, Tbl/Tbr, LFE, Tl/Tr, Tsl/Tsr,
+// Tfc, Tbc, Tc, LFE2, Bfl/Bfr, Bfc, Cb, Lscr/Rscr and Lw/Rw. Some bits designate a pair of channels.
+const AC4_CHANNEL_BITS: u32 = 0x3_FFFF;
+const AC4_CHANNEL_PAIR_BITS: u32 = 0x2_0000 | 0x1_0000 | 0x2000 | 0x0100 | 0x0080 | 0x0020 |
+    0x0010 | 0x0008 | 0x0004 | 0x0001;
+
+impl AudioChannelConfiguration {
+    /// The number of audio channels described by this element, if the scheme is recognized. We
+    /// handle the MPEG-DASH scheme (where the value is the number of channels), the MPEG
+    /// ChannelConfiguration and OutputChannelPosition schemes, the Dolby channel masks for AC-3,
+    /// E-AC-3 and AC-4, and the DTS schemes.
+    pub fn channel_count(&self) -> Option<u32> {
+        let value = self.value.as_deref()?.trim();
+        match self.schemeIdUri.as_str() {
+            "urn:mpeg:dash:23003:3:audio_channel_configuration:2011" |
+            "tag:dts.com,2014:dash:audio_channel_configuration:2012" |
+            "urn:dts:dash:audio_channel_configuration:2012" =>
+                value.parse::<u32>().ok().filter(|c| *c > 0),
+            "urn:mpeg:mpegB:cicp:ChannelConfiguration" =>
+                value.parse::<usize>().ok()
+                    .and_then(|v| CICP_CHANNEL_COUNTS.get(v))
+                    .copied()
+                    .filter(|c| *c > 0),
+            // A space-separated list of the position of each channel.
+            "urn:mpeg:mpegB:cicp:OutputChannelPosition" =>
+                u32::try_from(value.split_whitespace().count()).ok().filter(|c| *c > 0),
+            "tag:dolby.com,2014:dash:audio_channel_configuration:2011" |
+            "urn:dolby:dash:audio_channel_configuration:2011" => {
+                let mask = u32::from_str_radix(value, 16).ok()?;
+                Some(mask.count_ones() + (mask & DOLBY_CHANNEL_PAIR_BITS).count_ones())
+                    .filter(|c| *c > 0)
+            },
+            "tag:dolby.com,2015:dash:audio_channel_configuration:2015" => {
+                let mask = u32::from_str_radix(value, 16).ok()? & AC4_CHANNEL_BITS;
+                Some(mask.count_ones() + (mask & AC4_CHANNEL_PAIR_BITS).count_ones())
+                    .filter(|c| *c > 0)
+            },
+            // DTS-UHD uses a 32-bit mask with one bit per channel.
+            "tag:dts.com,2018:uhd:audio_channel_configuration" =>
+                u32::from_str_radix(value, 16).ok()
+                    .map(u32::count_ones)
+                    .filter(|c| *c > 0),
+            _ => None,
+        }
+    }
+}
+
 // This element is not specified in ISO/IEC 23009-1:2022; exact format is unclear.
 #[skip_serializing_none]
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Hash)]
@@ -1046,6 +1121,15 @@ pub struct Preselection {
     pub languages: Vec<Language>,
 }
 
+impl Preselection {
+    /// The ids of the AdaptationSets or ContentComponents referenced by this Preselection, the first
+    /// of which is the main component.
+    #[must_use]
+    pub fn components(&self) -> Vec<&str> {
+        self.preselectionComponents.split_whitespace().collect()
+    }
+}
+
 /// Specifies that content is suitable for presentation to audiences for which that rating is known to be
 /// appropriate, or for unrestricted audiences.
 #[skip_serializing_none]
@@ -1420,7 +1504,7 @@ pub struct ContentProtection {
     #[serde(rename = "@cenc:default_KID", alias = "@default_KID")]
     pub default_KID: Option<String>,
     /// License acquisition URL.
-    #[serde(rename = "dashif:laurl", alias = "laurl")]
+    #[serde(rename = "dashif:laurl", alias = "laurl", alias = "dashif:Laurl")]
     pub laurl: Option<Laurl>,
     /// License acquisition URL. The name clearkey:Laurl is obsolete and replaced by dashif:laurl.
     /// Some manifests in the wild include both, and the parser does not allow for duplicate fields,
@@ -2354,6 +2438,113 @@ pub fn is_video_adaptation(a: &&AdaptationSet) -> bool {
     false
 }
 
+/// Returns `true` if this AdaptationSet contains trick-mode video, signalled by an
+/// EssentialProperty with @schemeIdUri "http://dashif.org/guidelines/trickmode" (a low frame rate
+/// video track intended for fast forward and rewind, whose @value is the id of the AdaptationSet
+/// containing the main video).
+pub fn is_trickmode_adaptation(a: &&AdaptationSet) -> bool {
+    a.essential_property.iter()
+        .any(|ep| ep.schemeIdUri == "http://dashif.org/guidelines/trickmode")
+}
+
+/// Returns `true` if this AdaptationSet contains images (for example thumbnail tiles), with a
+/// `@contentType` of "image" or an image `@mimeType`.
+pub fn is_image_adaptation(a: &&AdaptationSet) -> bool {
+    if a.contentType.as_deref().is_some_and(|ct| ct == "image") {
+        return true;
+    }
+    if a.mimeType.as_deref().is_some_and(|mt| mt.starts_with("image/")) {
+        return true;
+    }
+    a.representations.iter()
+        .any(|r| r.contentType.as_deref().is_some_and(|ct| ct == "image") ||
+             r.mimeType.as_deref().is_some_and(|mt| mt.starts_with("image/")))
+}
+
+/// The number of columns and rows of thumbnails in each image of a thumbnail tile Representation,
+/// specified by an EssentialProperty with @schemeIdUri "http://dashif.org/thumbnail_tile" and a
+/// @value such as "10x20" on the Representation or its parent AdaptationSet. Returns `None` if the
+/// Representation does not contain thumbnail tiles.
+pub fn thumbnail_tile_grid(a: &AdaptationSet, r: &Representation) -> Option<(u32, u32)> {
+    let ep = r.essential_property.iter()
+        .chain(a.essential_property.iter())
+        .find(|ep| ep.schemeIdUri == "http://dashif.org/thumbnail_tile"
+              || ep.schemeIdUri == "http://dashif.org/guidelines/thumbnail_tile")?;
+    let value = ep.value.as_deref().unwrap_or("1x1");
+    let (cols, rows) = value.trim().split_once(['x', 'X'])?;
+    let cols: u32 = cols.trim().parse().ok()?;
+    let rows: u32 = rows.trim().parse().ok()?;
+    if cols == 0 || rows == 0 {
+        return None;
+    }
+    Some((cols, rows))
+}
+
+
+/// A Spatial Relationship Description (SRD), which locates the video of an AdaptationSet or
+/// Representation as a rectangular region of a source, for example one tile of a panoramic video.
+/// It is signalled by a SupplementalProperty or EssentialProperty with @schemeIdUri
+/// "urn:mpeg:dash:srd:2014". Coordinates are expressed in the arbitrary units of the reference space
+/// of the source, whose size is given by `total_width` and `total_height` (which may only be
+/// specified on one of the descriptors of the source).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpatialRelationship {
+    pub source_id: u64,
+    pub object_x: u64,
+    pub object_y: u64,
+    pub object_width: u64,
+    pub object_height: u64,
+    pub total_width: Option<u64>,
+    pub total_height: Option<u64>,
+    pub spatial_set_id: Option<u64>,
+}
+
+/// Parse the @value of an SRD descriptor, of the form
+/// "source_id,object_x,object_y,object_width,object_height[,total_width,total_height[,spatial_set_id]]".
+pub fn parse_srd(value: &str) -> Option<SpatialRelationship> {
+    let fields: Vec<u64> = value.split(',')
+        .map(|f| f.trim().parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()?;
+    if !matches!(fields.len(), 5 | 7 | 8) {
+        return None;
+    }
+    Some(SpatialRelationship {
+        source_id: fields[0],
+        object_x: fields[1],
+        object_y: fields[2],
+        object_width: fields[3],
+        object_height: fields[4],
+        total_width: fields.get(5).copied(),
+        total_height: fields.get(6).copied(),
+        spatial_set_id: fields.get(7).copied(),
+    })
+}
+
+/// The Spatial Relationship Description of Representation r (if specified) or of its parent
+/// AdaptationSet a, if any.
+pub fn spatial_relationship(a: &AdaptationSet, r: Option<&Representation>) -> Option<SpatialRelationship> {
+    let srd_value = |essential: &[EssentialProperty], supplemental: &[SupplementalProperty]| {
+        essential.iter()
+            .map(|ep| (&ep.schemeIdUri, &ep.value))
+            .chain(supplemental.iter().map(|sp| (&sp.schemeIdUri, &sp.value)))
+            .filter(|(scheme, _)| scheme.as_str() == "urn:mpeg:dash:srd:2014")
+            .find_map(|(_, value)| value.as_deref().and_then(parse_srd))
+    };
+    r.and_then(|r| srd_value(&r.essential_property, &r.supplemental_property))
+        .or_else(|| srd_value(&a.essential_property, &a.supplemental_property))
+}
+
+/// The values of the Viewpoint descriptors of an AdaptationSet and of its ContentComponents, which
+/// identify for example the camera angle of a multi-view presentation. The @id of a Viewpoint is
+/// used if it has no @value.
+pub fn adaptation_viewpoints(a: &AdaptationSet) -> Vec<&str> {
+    a.Viewpoint.iter()
+        .chain(a.ContentComponent.iter().flat_map(|cc| cc.Viewpoint.iter()))
+        .filter_map(|vp| vp.value.as_deref().or(vp.id.as_deref()))
+        .collect()
+}
+
 
 fn is_subtitle_mimetype(mt: &str) -> bool {
     mt.eq("text/vtt") ||
@@ -2506,6 +2697,136 @@ pub fn subtitle_type(a: &&AdaptationSet) -> SubtitleType {
 }
 
 
+/// Colour properties of a video stream, as code points defined in ISO/IEC 23091-2 (CICP). They are
+/// signalled by `urn:mpeg:mpegB:cicp:ColourPrimaries`, `urn:mpeg:mpegB:cicp:TransferCharacteristics`
+/// and `urn:mpeg:mpegB:cicp:MatrixCoefficients` EssentialProperty or SupplementalProperty descriptors
+/// on the Representation or its parent AdaptationSet, or are included in VP9 and AV1 codec strings.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct ColourProperties {
+    /// 1 for BT.709, 9 for BT.2020
+    pub colour_primaries: Option<u8>,
+    /// 1 for BT.709, 16 for SMPTE ST 2084 (PQ), 18 for ARIB STD-B67 (HLG)
+    pub transfer_characteristics: Option<u8>,
+    /// 1 for BT.709, 9 for BT.2020 non-constant luminance
+    pub matrix_coefficients: Option<u8>,
+}
+
+/// The dynamic range of a video stream.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VideoDynamicRange {
+    /// Standard dynamic range
+    Sdr,
+    /// HDR10 and other HDR formats using the Perceptual Quantizer transfer function
+    Hdr10,
+    /// Hybrid Log-Gamma
+    Hlg,
+    /// Dolby Vision, with the Dolby Vision profile if it is known. When used in a preference,
+    /// `DolbyVision(None)` matches any profile.
+    DolbyVision(Option<u8>),
+}
+
+impl std::fmt::Display for VideoDynamicRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VideoDynamicRange::Sdr => write!(f, "SDR"),
+            VideoDynamicRange::Hdr10 => write!(f, "HDR10"),
+            VideoDynamicRange::Hlg => write!(f, "HLG"),
+            VideoDynamicRange::DolbyVision(Some(p)) => write!(f, "Dolby Vision profile {p}"),
+            VideoDynamicRange::DolbyVision(None) => write!(f, "Dolby Vision"),
+        }
+    }
+}
+
+fn cicp_property(r: &Representation, a: &AdaptationSet, scheme: &str) -> Option<u8> {
+    let essential = r.essential_property.iter()
+        .chain(a.essential_property.iter())
+        .map(|p| (&p.schemeIdUri, &p.value));
+    let supplemental = r.supplemental_property.iter()
+        .chain(a.supplemental_property.iter())
+        .map(|p| (&p.schemeIdUri, &p.value));
+    essential.chain(supplemental)
+        .filter(|(uri, _)| uri.as_str() == scheme)
+        .find_map(|(_, value)| value.as_ref()?.trim().parse::<u8>().ok())
+}
+
+// The VP9 codec string is of the form vp09.PP.LL.DD.CC.cp.tc.mc.FF, and the AV1 codec string of the
+// form av01.P.LLT.DD.M.CCC.cp.tc.mc.F, where cp, tc and mc are the colour primaries, transfer
+// characteristics and matrix coefficients.
+fn codec_colour_properties(codecs: &str) -> ColourProperties {
+    let fields: Vec<&str> = codecs.split('.').collect();
+    let offset = match fields.first() {
+        Some(&"vp09") => 5,
+        Some(&"av01") => 6,
+        _ => return ColourProperties::default(),
+    };
+    let field = |i: usize| fields.get(offset + i).and_then(|f| f.parse::<u8>().ok());
+    ColourProperties {
+        colour_primaries: field(0),
+        transfer_characteristics: field(1),
+        matrix_coefficients: field(2),
+    }
+}
+
+/// The colour properties of video Representation `r`, whose parent AdaptationSet is `a`. The CICP
+/// descriptors in the manifest take precedence over the values in the codec string.
+#[must_use]
+pub fn colour_properties(a: &AdaptationSet, r: &Representation) -> ColourProperties {
+    let from_codec = r.codecs.as_ref().or(a.codecs.as_ref())
+        .map(|c| codec_colour_properties(c))
+        .unwrap_or_default();
+    ColourProperties {
+        colour_primaries: cicp_property(r, a, "urn:mpeg:mpegB:cicp:ColourPrimaries")
+            .or(from_codec.colour_primaries),
+        transfer_characteristics: cicp_property(r, a, "urn:mpeg:mpegB:cicp:TransferCharacteristics")
+            .or(from_codec.transfer_characteristics),
+        matrix_coefficients: cicp_property(r, a, "urn:mpeg:mpegB:cicp:MatrixCoefficients")
+            .or(from_codec.matrix_coefficients),
+    }
+}
+
+/// The Dolby Vision profile of a codec string such as "dvh1.05.06" or "dav1.10.09", if it
+/// designates a Dolby Vision stream.
+#[must_use]
+pub fn dolby_vision_profile(codec: &str) -> Option<Option<u8>> {
+    let (fourcc, rest) = codec.split_once('.').unwrap_or((codec, ""));
+    if !matches!(fourcc, "dvh1" | "dvhe" | "dva1" | "dvav" | "dav1") {
+        return None;
+    }
+    Some(rest.split('.').next().and_then(|p| p.parse::<u8>().ok()))
+}
+
+/// The dynamic range of video Representation `r`, whose parent AdaptationSet is `a`. A stream whose
+/// codec is Dolby Vision is classified as `DolbyVision`. Otherwise the classification follows the
+/// transfer characteristics: PQ for HDR10, HLG, and SDR otherwise. A stream whose @codecs designates
+/// an HDR10 or SDR base layer is classified according to that base layer, even if it also carries a
+/// Dolby Vision enhancement signalled by `scte214:supplementalCodecs` (see
+/// [supplemental_dolby_vision_profile]).
+#[must_use]
+pub fn video_dynamic_range(a: &AdaptationSet, r: &Representation) -> VideoDynamicRange {
+    if let Some(codecs) = r.codecs.as_ref().or(a.codecs.as_ref()) {
+        if let Some(profile) = dolby_vision_profile(codecs) {
+            return VideoDynamicRange::DolbyVision(profile);
+        }
+    }
+    match colour_properties(a, r).transfer_characteristics {
+        Some(16) => VideoDynamicRange::Hdr10,
+        Some(18) => VideoDynamicRange::Hlg,
+        _ => VideoDynamicRange::Sdr,
+    }
+}
+
+/// The Dolby Vision profile signalled by the `scte214:supplementalCodecs` attribute of video
+/// Representation `r` or of its parent AdaptationSet `a`, for streams whose base layer is
+/// backwards compatible with non Dolby Vision decoders.
+#[must_use]
+pub fn supplemental_dolby_vision_profile(a: &AdaptationSet, r: &Representation) -> Option<Option<u8>> {
+    r.scte214_supplemental_codecs.as_ref()
+        .or(a.scte214_supplemental_codecs.as_ref())?
+        .split_whitespace()
+        .find_map(dolby_vision_profile)
+}
+
+
 #[allow(dead_code)]
 fn content_protection_type(cp: &ContentProtection) -> String {
     if let Some(v) = &cp.value {
@@ -2534,6 +2855,9 @@ fn content_protection_type(cp: &ContentProtection) -> String {
     if uri.eq("urn:uuid:94ce86fb-07ff-4f43-adb8-93d2fa968ca2") {
         return String::from("FairPlay");
     }
+    if uri.eq("urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e") {
+        return String::from("ClearKey");
+    }
     if uri.eq("urn:uuid:3ea8778f-7742-4bf9-b18b-e834b2acbd47") {
         return String::from("Clear Key AES-128");
     }
@@ -3045,4 +3369,92 @@ mod tests {
             assert!(anomaly.starts_with("SegmentTimeline has segment@d > @maxSegmentDuration"));
         }
     }
+
+    #[test]
+    fn test_audio_channel_count() {
+        use super::AudioChannelConfiguration;
+
+        let acc = |scheme: &str, value: &str| AudioChannelConfiguration {
+            schemeIdUri: String::from(scheme),
+            value: Some(String::from(value)),
+            id: None,
+        };
+        assert_eq!(acc("urn:mpeg:dash:23003:3:audio_channel_configuration:2011", "2").channel_count(), Some(2));
+        assert_eq!(acc("urn:mpeg:mpegB:cicp:ChannelConfiguration", "6").channel_count(), Some(6));
+        assert_eq!(acc("urn:mpeg:mpegB:cicp:ChannelConfiguration", "12").channel_count(), Some(8));
+        assert_eq!(acc("urn:mpeg:mpegB:cicp:ChannelConfiguration", "0").channel_count(), None);
+        assert_eq!(acc("urn:mpeg:mpegB:cicp:OutputChannelPosition", "0 1 2").channel_count(), Some(3));
+        assert_eq!(acc("tag:dolby.com,2014:dash:audio_channel_configuration:2011", "F801").channel_count(), Some(6));
+        assert_eq!(acc("tag:dolby.com,2014:dash:audio_channel_configuration:2011", "a000").channel_count(), Some(2));
+        assert_eq!(acc("tag:dolby.com,2014:dash:audio_channel_configuration:2011", "FA01").channel_count(), Some(8));
+        assert_eq!(acc("tag:dolby.com,2015:dash:audio_channel_configuration:2015", "000001").channel_count(), Some(2));
+        assert_eq!(acc("tag:dolby.com,2015:dash:audio_channel_configuration:2015", "000047").channel_count(), Some(6));
+        assert_eq!(acc("tag:dolby.com,2015:dash:audio_channel_configuration:2015", "0000C7").channel_count(), Some(8));
+        assert_eq!(acc("tag:dolby.com,2015:dash:audio_channel_configuration:2015", "00007F").channel_count(), Some(12));
+        assert_eq!(acc("tag:dolby.com,2015:dash:audio_channel_configuration:2015", "000000").channel_count(), None);
+        assert_eq!(acc("tag:dts.com,2014:dash:audio_channel_configuration:2012", "6").channel_count(), Some(6));
+        assert_eq!(acc("urn:example:unknown", "2").channel_count(), None);
+    }
+
+    #[test]
+    fn test_video_dynamic_range() {
+        use super::{parse, video_dynamic_range, supplemental_dolby_vision_profile, colour_properties};
+        use super::{VideoDynamicRange, ColourProperties};
+
+        let xml = r#"<MPD><Period><AdaptationSet contentType="video">
+          <Representation id="sdr" codecs="avc1.640028"/>
+          <Representation id="pq" codecs="hvc1.2.4.L150.90">
+            <SupplementalProperty schemeIdUri="urn:mpeg:mpegB:cicp:TransferCharacteristics" value="16"/>
+          </Representation>
+          <Representation id="hlg" codecs="hvc1.2.4.L150.90">
+            <EssentialProperty schemeIdUri="urn:mpeg:mpegB:cicp:TransferCharacteristics" value="18"/>
+          </Representation>
+          <Representation id="dv5" codecs="dvh1.05.06"/>
+          <Representation id="dv8" codecs="hvc1.2.4.L150.90" scte214:supplementalCodecs="dvh1.08.06">
+            <SupplementalProperty schemeIdUri="urn:mpeg:mpegB:cicp:TransferCharacteristics" value="16"/>
+          </Representation>
+          <Representation id="vp9" codecs="vp09.02.10.10.01.09.16.09.01"/>
+          <Representation id="av1" codecs="av01.0.04M.10.0.112.09.18.09.0"/>
+        </AdaptationSet></Period></MPD>"#;
+        let mpd = parse(xml).unwrap();
+        let a = &mpd.periods[0].adaptations[0];
+        let ranges: Vec<VideoDynamicRange> = a.representations.iter()
+            .map(|r| video_dynamic_range(a, r))
+            .collect();
+        assert_eq!(ranges, vec![VideoDynamicRange::Sdr, VideoDynamicRange::Hdr10, VideoDynamicRange::Hlg,
+                                VideoDynamicRange::DolbyVision(Some(5)), VideoDynamicRange::Hdr10,
+                                VideoDynamicRange::Hdr10, VideoDynamicRange::Hlg]);
+        assert_eq!(supplemental_dolby_vision_profile(a, &a.representations[4]), Some(Some(8)));
+        assert_eq!(supplemental_dolby_vision_profile(a, &a.representations[1]), None);
+        assert_eq!(colour_properties(a, &a.representations[5]),
+                   ColourProperties { colour_primaries: Some(9), transfer_characteristics: Some(16), matrix_coefficients: Some(9) });
+    }
+
+    #[test]
+    fn test_thumbnail_adaptations() {
+        use super::{parse, is_image_adaptation, is_video_adaptation, is_trickmode_adaptation, thumbnail_tile_grid};
+
+        let xml = r#"<MPD><Period>
+          <AdaptationSet id="1" contentType="video" mimeType="video/mp4"/>
+          <AdaptationSet id="2" contentType="video" mimeType="video/mp4">
+            <EssentialProperty schemeIdUri="http://dashif.org/guidelines/trickmode" value="1"/>
+          </AdaptationSet>
+          <AdaptationSet id="3" contentType="image" mimeType="image/jpeg">
+            <Representation id="tiles" width="1280" height="720">
+              <EssentialProperty schemeIdUri="http://dashif.org/thumbnail_tile" value="5x4"/>
+            </Representation>
+            <Representation id="bad">
+              <EssentialProperty schemeIdUri="http://dashif.org/thumbnail_tile" value="5"/>
+            </Representation>
+          </AdaptationSet>
+        </Period></MPD>"#;
+        let mpd = parse(xml).unwrap();
+        let a = &mpd.periods[0].adaptations;
+        assert!(is_video_adaptation(&&a[1]) && is_trickmode_adaptation(&&a[1]));
+        assert!(!is_trickmode_adaptation(&&a[0]));
+        assert!(is_image_adaptation(&&a[2]) && !is_video_adaptation(&&a[2]));
+        assert_eq!(thumbnail_tile_grid(&a[2], &a[2].representations[0]), Some((5, 4)));
+        assert_eq!(thumbnail_tile_grid(&a[2], &a[2].representations[1]), None);
+        assert_eq!(thumbnail_tile_grid(&a[0], &a[0].representations.first().cloned().unwrap_or_default()), None);
+    }
 }
@@ -23,22 +23,38 @@ use std::cmp::{min, max};
 use std::fs;
 use fs::File;
 use std::path::Path;
+use std::sync::Arc;
 use std::io::{BufReader, BufWriter};
-use tracing::{error, info, trace};
-use ac_ffmpeg::codec::CodecParameters;
-use ac_ffmpeg::packet::Packet;
-use ac_ffmpeg::time::Timestamp;
+use tracing::{info, trace, warn};
+use ac_ffmpeg::codec::{CodecParameters, SubtitleCodecParameters, Decoder, Encoder};
+use ac_ffmpeg::codec::audio::{AudioDecoder, AudioEncoder, AudioResampler};
+use ac_ffmpeg::codec::video::{VideoDecoder, VideoEncoder, VideoFrameScaler};
+use ac_ffmpeg::packet::{Packet, PacketMut};
+use ac_ffmpeg::time::{TimeBase, Timestamp};
 use ac_ffmpeg::format::io::IO;
 use ac_ffmpeg::format::demuxer::Demuxer;
 use ac_ffmpeg::format::demuxer::DemuxerWithStreamInfo;
 use ac_ffmpeg::format::muxer::Muxer;
 use ac_ffmpeg::format::muxer::OutputFormat;
 use crate::DashMpdError;
-use crate::fetch::DashDownloader;
-use crate::media::{audio_container_type, video_container_type, AudioTrack};
+use crate::fetch::{DashDownloader, Muxer as MuxerPlugin, Concatenator, PluginFuture};
+use crate::fmp4::{fmp4_files_concatable, mp4_track_languages, set_mp4_track_roles};
+use crate::webm::webm_files_concatable;
+use crate::webm::{set_webm_track_flags, webm_track_languages, TrackFlags};
+use crate::media::{audio_container_type, video_container_type, video_containers_concatable};
+use crate::media::{container_language, subtitle_track_name, AudioTrack, SubtitleTrack};
+use crate::media::{container_preference, try_muxers, try_subtitle_muxers, try_concatenators};
+use crate::media::{NativeMuxer, NativeConcatenator};
 
 
 
+fn libav_path_str(path: &Path) -> Result<&str, DashMpdError> {
+    path.to_str()
+        .ok_or_else(|| DashMpdError::Io(
+            io::Error::other("obtaining path name"),
+            String::from("")))
+}
+
 fn libav_open_input(path: &str) -> Result<DemuxerWithStreamInfo<File>, DashMpdError> {
     let input = File::open(path)
         .map_err(|_| DashMpdError::Muxing(String::from("opening libav input path")))?;
@@ -50,7 +66,14 @@ fn libav_open_input(path: &str) -> Result<DemuxerWithStreamInfo<File>, DashMpdEr
         .map_err(|(_, _e)| DashMpdError::Muxing(String::from("building libav demuxer")))
 }
 
-fn libav_open_output(path: &str, elementary_streams: &[CodecParameters]) -> Result<Muxer<File>, DashMpdError> {
+// An output stream, with the metadata (such as language and title) that is recorded for it in the
+// output container.
+struct OutputStream {
+    params: CodecParameters,
+    metadata: Vec<(&'static str, String)>,
+}
+
+fn libav_open_output_streams(path: &str, streams: &[OutputStream]) -> Result<Muxer<File>, DashMpdError> {
     let output_format = OutputFormat::guess_from_file_name(path)
         .or_else(|| OutputFormat::find_by_name("mp4"))
         .ok_or_else(|| DashMpdError::Muxing(String::from("guessing libav output format")))?;
@@ -58,9 +81,12 @@ fn libav_open_output(path: &str, elementary_streams: &[CodecParameters]) -> Resu
         .map_err(|e| DashMpdError::Io(e, String::from("creating output file")))?;
     let io = IO::from_seekable_write_stream(output);
     let mut muxer_builder = Muxer::builder();
-    for codec_parameters in elementary_streams {
-        muxer_builder.add_stream(codec_parameters)
+    for stream in streams {
+        let idx = muxer_builder.add_stream(&stream.params)
             .map_err(|_| DashMpdError::Muxing(String::from("adding libav stream to muxer")))?;
+        for (key, value) in &stream.metadata {
+            muxer_builder.streams_mut()[idx].set_metadata(key, value);
+        }
     }
     muxer_builder
         // .interleaved(true)
@@ -69,6 +95,13 @@ fn libav_open_output(path: &str, elementary_streams: &[CodecParameters]) -> Resu
             format!("building libav muxer: {:?}", e)))
 }
 
+fn libav_open_output(path: &str, elementary_streams: &[CodecParameters]) -> Result<Muxer<File>, DashMpdError> {
+    let streams: Vec<OutputStream> = elementary_streams.iter()
+        .map(|params| OutputStream { params: params.clone(), metadata: Vec::new() })
+        .collect();
+    libav_open_output_streams(path, &streams)
+}
+
 
 // The dts is always valid when the last_dts was null.
 // The dts is invalid if it's non-monotonic.
@@ -76,151 +109,235 @@ fn has_invalid_timestamps(p: &Packet, last_dts: Timestamp) -> bool {
     !last_dts.is_null() && (p.dts().is_null() || p.dts() <= last_dts)
 }
 
-
-pub async fn mux_audio_video(
-    _downloader: &DashDownloader,
-    output_path: &Path,
-    audio_tracks: &[AudioTrack],
-    video_path: &Path) -> Result<(), DashMpdError> {
-    ac_ffmpeg::set_log_callback(|_count, msg: &str| info!("ffmpeg: {msg}"));
-    if audio_tracks.len() > 1 {
-        error!("Cannot mux more than a single audio track with libav");
-        return Err(DashMpdError::Muxing(String::from("cannot mux more than one audio track with libav")));
+// We try to work around malformed media streams with fluctuating dts (decompression timestamp).
+// The dts must be strictly increasing according to av_write_frame(), but some streams (eg Vevo)
+// have buggy inputs. There is special handling in ffmpeg.c around line 814 to avoid the error
+// "Application provided invalid, non monotonically increasing dts to muxer", and also in
+// videostreamer (see patch
+// https://github.com/horgh/videostreamer/commit/c2aa2d30b59332a7257e4c0c57a09bc8a0358b96 ) We
+// rewrite the dts in this situation.
+fn fix_invalid_timestamps(mut pkt: Packet, last_dts: Timestamp) -> Packet {
+    if has_invalid_timestamps(&pkt, last_dts) {
+        let next_dts = Timestamp::new(last_dts.timestamp() + 1, last_dts.time_base());
+        if !pkt.pts().is_null() && pkt.pts() > pkt.dts() {
+            // we can't use std::cmp::max because only partial order available on Timestamp
+            let mut max = next_dts;
+            if pkt.pts() > max {
+                max = pkt.pts();
+            }
+            pkt = pkt.with_pts(max);
+        }
+        if pkt.pts().is_null() {
+            pkt = pkt.with_pts(next_dts);
+        }
+        pkt = pkt.with_dts(next_dts);
     }
-    let audio_str = &audio_tracks[0].path
-        .to_str()
-        .ok_or_else(|| DashMpdError::Io(
-            io::Error::other("obtaining audiopath name"),
-            String::from("")))?;
-    let video_str = video_path
-        .to_str()
-        .ok_or_else(|| DashMpdError::Io(
-            io::Error::other("obtaining videopath name"),
-            String::from("")))?;
-    let mut video_demuxer = libav_open_input(video_str)
-        .map_err(|_| DashMpdError::Muxing(String::from("opening input video stream")))?;
-    let (video_pos, video_codec) = video_demuxer
-        .streams()
-        .iter()
-        .enumerate()
-        .find_map(|(pos, stream)| {
+    // Here a workaround for invalid media streams where dts is sometimes larger than pts.
+    // Reproducing workaround from this patch to ffmpeg.c
+    // http://git.videolan.org/?p=ffmpeg.git;a=commitdiff;h=22844132069ebd2c0b2ac4e7b41c93c33890bfb9
+    if !pkt.pts().is_null() && !pkt.dts().is_null() && pkt.dts() > pkt.pts() {
+        info!("Fixing invalid DTS (dts > pts) in DASH stream");
+        let pts_ts = pkt.pts().timestamp();
+        let dts_ts = pkt.dts().timestamp();
+        let next_ts = last_dts.timestamp() + 1;
+        let fixed_dts_ts = pts_ts + dts_ts + next_ts
+            - min(pts_ts, min(dts_ts, next_ts))
+            - max(pts_ts, max(dts_ts, next_ts));
+        let fixed_dts = Timestamp::new(fixed_dts_ts, last_dts.time_base());
+        pkt = pkt.with_dts(fixed_dts).with_pts(fixed_dts);
+    }
+    pkt
+}
+
+
+// An input file whose packets are copied to the output, with the output stream index for each of
+// its streams (None for streams that are not copied) and the next packet to be copied.
+struct CopySource {
+    demuxer: DemuxerWithStreamInfo<File>,
+    mapping: Vec<Option<usize>>,
+    next: Option<Packet>,
+}
+
+impl CopySource {
+    // Open the input file at path, adding an output stream for the first of its streams for which
+    // select returns true, or for all such streams if all is true.
+    fn open(
+        path: &Path,
+        select: fn(&CodecParameters) -> bool,
+        all: bool,
+        outputs: &mut Vec<OutputStream>) -> Result<CopySource, DashMpdError> {
+        let demuxer = libav_open_input(libav_path_str(path)?)?;
+        let mut mapping = Vec::new();
+        let mut selected = 0;
+        for stream in demuxer.streams() {
             let params = stream.codec_parameters();
-            if params.is_video_codec() {
-                return Some((pos, params));
+            if select(&params) && (all || selected == 0) {
+                mapping.push(Some(outputs.len()));
+                outputs.push(OutputStream { params, metadata: Vec::new() });
+                selected += 1;
+            } else {
+                mapping.push(None);
             }
-            None
-        })
-        .ok_or_else(|| DashMpdError::Muxing(String::from("finding libav video codec")))?;
-    let mut audio_demuxer = libav_open_input(audio_str)?;
-    let (audio_pos, audio_codec) = audio_demuxer
-        .streams()
-        .iter()
-        .enumerate()
-        .find_map(|(pos, stream)| {
-            let params = stream.codec_parameters();
-            if params.is_audio_codec() {
-                return Some((pos, params));
+        }
+        if selected == 0 {
+            return Err(DashMpdError::Muxing(format!("no suitable stream in {}", path.display())));
+        }
+        let mut source = CopySource { demuxer, mapping, next: None };
+        source.fill()?;
+        Ok(source)
+    }
+
+    // Read the next packet that is to be copied to the output, if any.
+    fn fill(&mut self) -> Result<(), DashMpdError> {
+        while let Some(pkt) = self.demuxer.take()
+            .map_err(|_| DashMpdError::Muxing(String::from("fetching packet from libav demuxer")))? {
+            if let Some(Some(out)) = self.mapping.get(pkt.stream_index()) {
+                self.next = Some(pkt.with_stream_index(*out));
+                return Ok(());
             }
-            None
-        })
-        .ok_or_else(|| DashMpdError::Muxing(String::from("finding libav audio codec")))?;
-
-    let out = output_path.to_str()
-        .ok_or_else(|| DashMpdError::Muxing(String::from("converting output path")))?;
-    let mut muxer = libav_open_output(out, &[video_codec, audio_codec])?;
-    let mut last_dts: Timestamp = Timestamp::null();
-
-    // wonder about memory consumption here, should we interleave the pushes?
-    while let Some(mut pkt) = video_demuxer.take()
-        .map_err(|_| DashMpdError::Muxing(String::from("fetching video packet from libav demuxer")))? {
-        if pkt.stream_index() == video_pos {
-            // We try to work around malformed media streams with fluctuating dts (decompression timestamp).
-            // The dts must be strictly increasing according to av_write_frame(), but some streams (eg
-            // Vevo) have buggy inputs. There is special handling in ffmpeg.c around line 814 to avoid
-            // the error "Application provided invalid, non monotonically increasing dts to muxer", and
-            // also in videostreamer (see patch
-            // https://github.com/horgh/videostreamer/commit/c2aa2d30b59332a7257e4c0c57a09bc8a0358b96 )
-            // We rewrite the dts in this situation.
-            if has_invalid_timestamps(&pkt, last_dts) {
-                let next_dts = Timestamp::new(last_dts.timestamp() + 1, last_dts.time_base());
-                if !pkt.pts().is_null() && pkt.pts() > pkt.dts() {
-                    // we can't use std::cmp::max because only partial order available on Timestamp
-                    let mut max = next_dts;
-                    if pkt.pts() > max {
-                        max = pkt.pts();
-                    }
-                    pkt = pkt.with_pts(max);
-                }
-                if pkt.pts().is_null() {
-                    pkt = pkt.with_pts(next_dts);
+        }
+        self.next = None;
+        Ok(())
+    }
+}
+
+// The timestamp used to interleave packets from different inputs.
+fn interleave_timestamp(pkt: &Packet) -> Option<i64> {
+    pkt.dts().as_micros().or_else(|| pkt.pts().as_micros())
+}
+
+// Copy the packets of all sources to the muxer, interleaved in decoding order. Each packet is passed
+// through the convert function before being written (used to convert the format of subtitles).
+fn copy_interleaved(
+    sources: &mut [CopySource],
+    muxer: &mut Muxer<File>,
+    stream_count: usize,
+    mut convert: impl FnMut(Packet) -> Packet) -> Result<(), DashMpdError> {
+    let mut last_dts = vec![Timestamp::null(); stream_count];
+    loop {
+        let mut best: Option<(usize, Option<i64>)> = None;
+        for (i, source) in sources.iter().enumerate() {
+            if let Some(pkt) = &source.next {
+                let ts = interleave_timestamp(pkt);
+                if best.is_none_or(|(_, best_ts)| ts < best_ts) {
+                    best = Some((i, ts));
                 }
-                pkt = pkt.with_dts(next_dts);
-            }
-            // Here a workaround for invalid media streams where dts is sometimes larger than pts.
-            // Reproducing workaround from this patch to ffmpeg.c
-            // http://git.videolan.org/?p=ffmpeg.git;a=commitdiff;h=22844132069ebd2c0b2ac4e7b41c93c33890bfb9
-            if !pkt.pts().is_null() && !pkt.dts().is_null() && pkt.dts() > pkt.pts() {
-                info!("Fixing invalid DTS (dts > pts) in DASH video stream");
-                let pts_ts = pkt.pts().timestamp();
-                let dts_ts = pkt.dts().timestamp();
-                let next_ts = last_dts.timestamp() + 1;
-                let fixed_dts_ts = pts_ts + dts_ts + next_ts
-                    - min(pts_ts, min(dts_ts, next_ts))
-                    - max(pts_ts, max(dts_ts, next_ts));
-                let fixed_dts = Timestamp::new(fixed_dts_ts, last_dts.time_base());
-                pkt = pkt.with_dts(fixed_dts).with_pts(fixed_dts);
             }
-            last_dts = pkt.dts();
-            muxer.push(pkt.with_stream_index(0))
-                .map_err(|_| DashMpdError::Muxing(String::from("pushing video packet to libav muxer")))?;
         }
+        let Some((i, _)) = best else { break };
+        let Some(pkt) = sources[i].next.take() else { break };
+        sources[i].fill()?;
+        let idx = pkt.stream_index();
+        let pkt = fix_invalid_timestamps(convert(pkt), last_dts[idx]);
+        last_dts[idx] = pkt.dts();
+        muxer.push(pkt)
+            .map_err(|_| DashMpdError::Muxing(String::from("pushing packet to libav muxer")))?;
     }
     muxer.flush()
-        .map_err(|_| DashMpdError::Muxing(String::from("flushing libav muxer")))?;
-    last_dts = Timestamp::null();
-    while let Some(mut pkt) = audio_demuxer.take()
-        .map_err(|_| DashMpdError::Muxing(String::from("fetching audio packet from libav demuxer")))? {
-        if pkt.stream_index() == audio_pos {
-            // See comments concerning workarounds for invalid media streams in the code for the
-            // video stream, above.
-            if has_invalid_timestamps(&pkt, last_dts) {
-                let next_dts = Timestamp::new(last_dts.timestamp() + 1, last_dts.time_base());
-                if !pkt.pts().is_null() && (pkt.pts() > pkt.dts()) {
-                    let mut max = next_dts;
-                    if pkt.pts() > max {
-                        max = pkt.pts();
-                    }
-                    pkt = pkt.with_pts(max);
-                }
-                if pkt.pts().is_null() {
-                    pkt = pkt.with_pts(next_dts);
-                }
-                pkt = pkt.with_dts(next_dts);
-            }
-            if !pkt.pts().is_null() && !pkt.dts().is_null() && pkt.dts() > pkt.pts() {
-                info!("Fixing invalid DTS (dts > pts) in DASH audio stream");
-                let pts_ts = pkt.pts().timestamp();
-                let dts_ts = pkt.dts().timestamp();
-                let next_ts = last_dts.timestamp() + 1;
-                let fixed_dts_ts = pts_ts + dts_ts + next_ts
-                    - min(pts_ts, min(dts_ts, next_ts))
-                    - max(pts_ts, max(dts_ts, next_ts));
-                let fixed_dts = Timestamp::new(fixed_dts_ts, last_dts.time_base());
-                pkt = pkt.with_dts(fixed_dts).with_pts(fixed_dts);
+        .map_err(|_| DashMpdError::Muxing(String::from("flushing libav muxer")))
+}
+
+
+// The muxer preference for the output container. Muxing with libav is always tried last if it does
+// not appear in the user's preference ordering, so that preferences intended for the external
+// muxers (which are not available in this build) don't prevent muxing.
+fn libav_muxer_preference<'a>(downloader: &'a DashDownloader, container: &str) -> Vec<&'a str> {
+    let mut preference = container_preference(&downloader.muxer_preference, container, vec!["libav"]);
+    if !preference.contains(&"libav") {
+        preference.push("libav");
+    }
+    preference
+}
+
+fn output_container(output_path: &Path) -> &str {
+    output_path.extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("mp4")
+}
+
+// Mux the video stream from video_path (if present) and the audio stream from each of the audio
+// tracks into output_path, recording the language of each audio track. When no audio stream is
+// flagged as default, the libav MP4 and Matroska muxers flag the first audio stream as default.
+fn libav_mux_tracks(
+    output_path: &Path,
+    audio_tracks: &[AudioTrack],
+    video_path: Option<&Path>) -> Result<(), DashMpdError> {
+    let mut outputs = Vec::new();
+    let mut sources = Vec::new();
+    if let Some(vp) = video_path {
+        sources.push(CopySource::open(vp, CodecParameters::is_video_codec, false, &mut outputs)
+                     .map_err(|_| DashMpdError::Muxing(String::from("opening input video stream")))?);
+    }
+    let container = output_container(output_path);
+    for at in audio_tracks {
+        sources.push(CopySource::open(&at.path, CodecParameters::is_audio_codec, false, &mut outputs)?);
+        if let Some(lang) = container_language(&at.language, container) {
+            if let Some(o) = outputs.last_mut() {
+                o.metadata.push(("language", lang));
             }
-            last_dts = pkt.dts();
-            muxer.push(pkt.with_stream_index(1))
-                .map_err(|_| DashMpdError::Muxing(String::from("pushing audio packet to libav muxer")))?;
         }
     }
-    muxer.flush()
-        .map_err(|_| DashMpdError::Muxing(String::from("flushing libav muxer")))?;
+    let mut muxer = libav_open_output_streams(libav_path_str(output_path)?, &outputs)?;
+    copy_interleaved(&mut sources, &mut muxer, outputs.len(), |pkt| pkt)?;
     muxer.close()
         .map_err(|_| DashMpdError::Muxing(String::from("closing libav muxer")))?;
     Ok(())
 }
 
 
+/// Muxing with libav, named "libav". Subtitle tracks are embedded as well.
+pub(crate) struct LibavMuxer;
+
+impl MuxerPlugin for LibavMuxer {
+    fn name(&self) -> &str {
+        "libav"
+    }
+
+    fn mux<'a>(
+        &'a self,
+        _downloader: &'a DashDownloader,
+        output_path: &'a Path,
+        audio_tracks: &'a [AudioTrack],
+        video_path: Option<&'a Path>) -> PluginFuture<'a> {
+        Box::pin(async move {
+            ac_ffmpeg::set_log_callback(|_count, msg: &str| info!("ffmpeg: {msg}"));
+            trace!("Muxing {} audio tracks with libav", audio_tracks.len());
+            libav_mux_tracks(output_path, audio_tracks, video_path)
+        })
+    }
+
+    fn mux_subtitles<'a>(
+        &'a self,
+        _downloader: &'a DashDownloader,
+        media_path: &'a Path,
+        subtitle_tracks: &'a [SubtitleTrack]) -> PluginFuture<'a> {
+        Box::pin(async move {
+            ac_ffmpeg::set_log_callback(|_count, msg: &str| info!("ffmpeg: {msg}"));
+            libav_embed_subtitles(media_path, subtitle_tracks)
+        })
+    }
+}
+
+fn builtin_muxer(name: &str) -> Option<Arc<dyn MuxerPlugin>> {
+    match name {
+        "libav" => Some(Arc::new(LibavMuxer)),
+        "native" => Some(Arc::new(NativeMuxer)),
+        _ => None,
+    }
+}
+
+pub async fn mux_audio_video(
+    downloader: &DashDownloader,
+    output_path: &Path,
+    audio_tracks: &[AudioTrack],
+    video_path: &Path) -> Result<(), DashMpdError> {
+    let container = output_container(output_path);
+    let muxer_preference = libav_muxer_preference(downloader, container);
+    info!("  Muxer preference for {container} is {muxer_preference:?}");
+    try_muxers(downloader, &muxer_preference, builtin_muxer, output_path, audio_tracks, Some(video_path)).await
+}
+
+
 pub async fn copy_video_to_container(
     _downloader: &DashDownloader,
     output_path: &Path,
@@ -248,6 +365,199 @@ pub async fn copy_video_to_container(
 }
 
 
+pub async fn mux_audio_tracks(
+    downloader: &DashDownloader,
+    output_path: &Path,
+    audio_tracks: &[AudioTrack]) -> Result<(), DashMpdError> {
+    trace!("Muxing {} audio tracks", audio_tracks.len());
+    let container = output_container(output_path);
+    let muxer_preference = libav_muxer_preference(downloader, container);
+    info!("  Muxer preference for {container} is {muxer_preference:?}");
+    try_muxers(downloader, &muxer_preference, builtin_muxer, output_path, audio_tracks, None).await
+}
+
+
+// Remove the markup from subtitle text: tags delimited by < and > in SRT and WebVTT cues, override
+// blocks delimited by { and } in ASS events.
+fn strip_markup(text: &str, open: char, close: char) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut depth = 0;
+    for c in text.chars() {
+        if c == open {
+            depth += 1;
+        } else if c == close && depth > 0 {
+            depth -= 1;
+        } else if depth == 0 {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Convert the payload of a subtitle packet to a mov_text (3GPP timed text) sample, which is a
+// 16-bit length followed by the UTF-8 text. Styling is not preserved. The payload of packets from
+// the libav ASS demuxer contains the event fields ReadOrder, Layer, Style, Name, MarginL, MarginR,
+// MarginV and Effect before the text.
+fn mov_text_sample(data: &[u8], ass: bool) -> Vec<u8> {
+    let payload = String::from_utf8_lossy(data);
+    let text = if ass {
+        let event_text = payload.splitn(9, ',').nth(8).unwrap_or("");
+        strip_markup(event_text, '{', '}')
+            .replace("\\N", "\n")
+            .replace("\\n", "\n")
+    } else {
+        strip_markup(&payload, '<', '>')
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&nbsp;", "\u{a0}")
+            .replace("&amp;", "&")
+    };
+    let bytes = text.trim_end().as_bytes();
+    let len = bytes.len().min(usize::from(u16::MAX));
+    let mut sample = Vec::with_capacity(len + 2);
+    sample.extend_from_slice(&(len as u16).to_be_bytes());
+    sample.extend_from_slice(&bytes[..len]);
+    sample
+}
+
+fn with_packet_data(pkt: &Packet, data: &[u8]) -> Packet {
+    let mut out = PacketMut::new(data.len());
+    out.data_mut().copy_from_slice(data);
+    out.with_time_base(pkt.time_base())
+        .with_stream_index(pkt.stream_index())
+        .with_pts(pkt.pts())
+        .with_dts(pkt.dts())
+        .with_raw_duration(pkt.raw_duration())
+        .with_key_flag(true)
+        .freeze()
+}
+
+// Copy the streams of media_path and the subtitle tracks to output_path. Subtitles are copied
+// unchanged to Matroska containers (which accept SRT, WebVTT and ASS subtitles) and are converted
+// to mov_text for MP4 containers. Returns the number of streams copied from media_path.
+fn libav_mux_subtitles(
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack],
+    output_path: &Path,
+    matroska: bool) -> Result<usize, DashMpdError> {
+    let container = if matroska { "mkv" } else { "mp4" };
+    let mut outputs = Vec::new();
+    let mut sources = vec![CopySource::open(media_path, |_| true, true, &mut outputs)?];
+    let media_streams = outputs.len();
+    // libav doesn't give us access to the metadata of the input streams, so we read the language
+    // of the existing tracks directly from the container.
+    let languages = if matroska {
+        webm_track_languages(media_path)
+    } else {
+        mp4_track_languages(media_path)
+    };
+    match languages {
+        Ok(languages) if languages.len() == media_streams => {
+            for (o, lang) in outputs.iter_mut().zip(languages) {
+                if let Some(l) = lang.and_then(|l| container_language(&l, container)) {
+                    o.metadata.push(("language", l));
+                }
+            }
+        },
+        _ => warn!("  Unable to determine the language of the tracks in {}", media_path.display()),
+    }
+    // Whether each output stream contains ASS subtitles that need converting to mov_text.
+    let mut ass = vec![false; media_streams];
+    for st in subtitle_tracks {
+        sources.push(CopySource::open(&st.path, CodecParameters::is_subtitle_codec, false, &mut outputs)?);
+        let Some(o) = outputs.last_mut() else { continue };
+        if !matroska {
+            let mov_text = SubtitleCodecParameters::new("mov_text")
+                .map_err(|_| DashMpdError::Muxing(String::from("creating mov_text codec parameters")))?;
+            o.params = CodecParameters::from(mov_text);
+        }
+        if let Some(lang) = container_language(&st.language, container) {
+            o.metadata.push(("language", lang));
+        }
+        if let Some(name) = subtitle_track_name(st) {
+            o.metadata.push(("title", String::from(name)));
+        }
+        ass.push(st.path.extension().is_some_and(|e| e.eq_ignore_ascii_case("ass")));
+    }
+    let mut muxer = libav_open_output_streams(libav_path_str(output_path)?, &outputs)?;
+    copy_interleaved(&mut sources, &mut muxer, outputs.len(), |pkt| {
+        let idx = pkt.stream_index();
+        if matroska || idx < media_streams {
+            pkt
+        } else {
+            with_packet_data(&pkt, &mov_text_sample(pkt.data(), ass[idx]))
+        }
+    })?;
+    muxer.close()
+        .map_err(|_| DashMpdError::Muxing(String::from("closing libav muxer")))?;
+    Ok(media_streams)
+}
+
+fn libav_embed_subtitles(
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack]) -> Result<(), DashMpdError> {
+    let container = output_container(media_path);
+    let matroska = container.eq("mkv") || container.eq("webm");
+    let dir = media_path.parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let tmpout = tempfile::Builder::new()
+        .prefix("dashmpdrs")
+        .suffix(&format!(".{container}"))
+        .rand_bytes(5)
+        .tempfile_in(dir)
+        .map_err(|e| DashMpdError::Io(e, String::from("creating temporary output file")))?;
+    let media_streams = libav_mux_subtitles(media_path, subtitle_tracks, tmpout.path(), matroska)?;
+    // The libav API doesn't allow us to set the dispositions of the output streams, and the libav
+    // Matroska muxer flags the first subtitle track as default. We set the track flags natively. In
+    // MP4 containers, forced and SDH subtitles are identified by a kind box with the DASH role of
+    // the track, as the libav MP4 muxer does for the forced and captions dispositions.
+    if matroska {
+        let mut flags = vec![None; media_streams];
+        for st in subtitle_tracks {
+            flags.push(Some(TrackFlags {
+                default: false,
+                forced: st.forced,
+                hearing_impaired: st.hearing_impaired,
+                name: subtitle_track_name(st).map(String::from),
+            }));
+        }
+        set_webm_track_flags(tmpout.path(), &flags)?;
+    } else if subtitle_tracks.iter().any(|st| st.forced || st.hearing_impaired) {
+        let mut roles = vec![None; media_streams];
+        for st in subtitle_tracks {
+            roles.push(if st.forced {
+                Some("forced-subtitle")
+            } else if st.hearing_impaired {
+                Some("caption")
+            } else {
+                None
+            });
+        }
+        set_mp4_track_roles(tmpout.path(), &roles)?;
+    }
+    tmpout.persist(media_path)
+        .map_err(|e| DashMpdError::Io(e.error, String::from("replacing media file with subtitled output")))?;
+    info!("  Embedded {} subtitle tracks in {} with libav", subtitle_tracks.len(), media_path.display());
+    Ok(())
+}
+
+
+pub async fn mux_subtitle_tracks(
+    downloader: &DashDownloader,
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack]) -> Result<(), DashMpdError> {
+    trace!("Embedding {} subtitle tracks in {}", subtitle_tracks.len(), media_path.display());
+    if subtitle_tracks.is_empty() {
+        return Ok(());
+    }
+    let container = output_container(media_path);
+    let muxer_preference = libav_muxer_preference(downloader, container);
+    info!("  Muxer preference for {container} is {muxer_preference:?}");
+    try_subtitle_muxers(downloader, &muxer_preference, builtin_muxer, media_path, subtitle_tracks).await
+}
+
+
 pub async fn copy_audio_to_container(
     _downloader: &DashDownloader,
     output_path: &Path,
@@ -273,3 +583,481 @@ pub async fn copy_audio_to_container(
     }
     todo!()
 }
+
+
+// Period concatenation using libav. When the media files for each Period contain the same streams
+// with the same codec parameters, we remux their packets into a single output file, shifting the
+// timestamps of each Period so that it starts where the previous Period ended. When the Periods
+// are heterogeneous (different resolution, sampling rate, codec), the "libav-reencode" concat
+// helper decodes each Period and reencodes it using the codec parameters of the first Period. This
+// is slow and lossy, so is only used when requested with DashDownloader::with_concat_preference().
+
+fn libav_open_inputs(paths: &[&Path]) -> Result<Vec<DemuxerWithStreamInfo<File>>, DashMpdError> {
+    let mut inputs = Vec::new();
+    for p in paths {
+        inputs.push(libav_open_input(libav_path_str(p)?)?);
+    }
+    Ok(inputs)
+}
+
+// Whether packets from a stream with codec parameters b can be copied into an output stream that
+// was created with codec parameters a.
+fn codec_parameters_match(a: &CodecParameters, b: &CodecParameters) -> bool {
+    if let (Some(va), Some(vb)) = (a.as_video_codec_parameters(), b.as_video_codec_parameters()) {
+        return va.decoder_name() == vb.decoder_name() &&
+            va.width() == vb.width() &&
+            va.height() == vb.height() &&
+            va.pixel_format() == vb.pixel_format();
+    }
+    if let (Some(aa), Some(ab)) = (a.as_audio_codec_parameters(), b.as_audio_codec_parameters()) {
+        return aa.decoder_name() == ab.decoder_name() &&
+            aa.sample_rate() == ab.sample_rate() &&
+            aa.channel_layout().channels() == ab.channel_layout().channels();
+    }
+    false
+}
+
+fn stream_codec_parameters(input: &DemuxerWithStreamInfo<File>) -> Vec<CodecParameters> {
+    input.streams()
+        .iter()
+        .map(|s| s.codec_parameters())
+        .collect()
+}
+
+// The Periods can be concatenated by remuxing if each file has the same number of streams as the
+// first file, with matching codec parameters.
+fn libav_streams_compatible(paths: &[&Path]) -> bool {
+    let Ok(inputs) = libav_open_inputs(paths) else {
+        return false;
+    };
+    let Some(first) = inputs.first() else {
+        return false;
+    };
+    let params0 = stream_codec_parameters(first);
+    inputs.iter().all(|i| {
+        let params = stream_codec_parameters(i);
+        params.len() == params0.len() &&
+            params.iter().zip(params0.iter()).all(|(b, a)| codec_parameters_match(a, b))
+    })
+}
+
+fn wants_reencode(downloader: &DashDownloader, paths: &[&Path]) -> bool {
+    let container = paths.first()
+        .and_then(|p| p.extension())
+        .and_then(|e| e.to_str())
+        .unwrap_or("mp4");
+    downloader.concat_preference.get(container)
+        .is_some_and(|ordering| ordering.split(',').any(|c| c.eq("libav-reencode")))
+}
+
+// Whether the media files for the different Periods can be concatenated. When the "libav-reencode"
+// concat helper has been requested, heterogeneous Periods are also concatenable.
+pub(crate) fn periods_concatable(downloader: &DashDownloader, paths: &[&Path]) -> bool {
+    video_containers_concatable(downloader, paths) ||
+        libav_streams_compatible(paths) ||
+        wants_reencode(downloader, paths)
+}
+
+// The earliest start time of the streams in this input, which is mapped to the end of the
+// previous Period in the output.
+fn input_origin(input: &DemuxerWithStreamInfo<File>) -> Timestamp {
+    let mut origin = Timestamp::null();
+    for s in input.streams() {
+        let st = s.start_time();
+        if !st.is_null() && (origin.is_null() || st < origin) {
+            origin = st;
+        }
+    }
+    if origin.is_null() {
+        Timestamp::from_micros(0)
+    } else {
+        origin
+    }
+}
+
+// Shift ts so that origin is mapped to offset (expressed in microseconds). The shift is converted
+// once into the time base of ts, so that all timestamps of a stream are shifted by the same integer
+// amount.
+fn shift_timestamp(ts: Timestamp, origin: Timestamp, offset: i64) -> Timestamp {
+    if ts.is_null() {
+        return ts;
+    }
+    let tb = ts.time_base();
+    let delta = Timestamp::from_micros(offset).with_time_base(tb).timestamp() -
+        origin.with_time_base(tb).timestamp();
+    Timestamp::new(ts.timestamp() + delta, tb)
+}
+
+// The end of this packet in microseconds, used to determine where the next Period starts.
+fn packet_end_micros(pkt: &Packet) -> Option<i64> {
+    let ts = if pkt.pts().is_null() { pkt.dts() } else { pkt.pts() };
+    if ts.is_null() {
+        return None;
+    }
+    Timestamp::new(ts.timestamp() + pkt.raw_duration().max(0), ts.time_base()).as_micros()
+}
+
+fn libav_concat_remux(paths: &[&Path], output: &Path) -> Result<(), DashMpdError> {
+    let mut inputs = libav_open_inputs(paths)?;
+    let params0 = inputs.first()
+        .map(stream_codec_parameters)
+        .ok_or_else(|| DashMpdError::Muxing(String::from("no input files to concatenate")))?;
+    for (i, input) in inputs.iter().enumerate().skip(1) {
+        let params = stream_codec_parameters(input);
+        if params.len() != params0.len() ||
+            !params.iter().zip(params0.iter()).all(|(b, a)| codec_parameters_match(a, b))
+        {
+            return Err(DashMpdError::Muxing(
+                format!("streams of {} are incompatible with those of the first Period", paths[i].display())));
+        }
+    }
+    let mut muxer = libav_open_output(libav_path_str(output)?, &params0)?;
+    let mut last_dts = vec![Timestamp::null(); params0.len()];
+    let mut offset: i64 = 0;
+    for input in inputs.iter_mut() {
+        let origin = input_origin(input);
+        let mut end = offset;
+        while let Some(pkt) = input.take()
+            .map_err(|_| DashMpdError::Muxing(String::from("fetching packet from libav demuxer")))? {
+            let idx = pkt.stream_index();
+            let pts = shift_timestamp(pkt.pts(), origin, offset);
+            let mut dts = shift_timestamp(pkt.dts(), origin, offset);
+            let mut pkt = pkt.with_pts(pts).with_dts(dts);
+            if let Some(e) = packet_end_micros(&pkt) {
+                end = end.max(e);
+            }
+            // Rounding of the shift into the packet time base can lead to a dts at the boundary
+            // between Periods that is not strictly increasing, which the muxer rejects.
+            if has_invalid_timestamps(&pkt, last_dts[idx]) {
+                let tb = pkt.time_base();
+                dts = Timestamp::new(last_dts[idx].with_time_base(tb).timestamp() + 1, tb);
+                if pkt.pts().is_null() || pkt.pts() < dts {
+                    pkt = pkt.with_pts(dts);
+                }
+                pkt = pkt.with_dts(dts);
+            }
+            last_dts[idx] = pkt.dts();
+            muxer.push(pkt)
+                .map_err(|_| DashMpdError::Muxing(String::from("pushing packet to libav muxer")))?;
+        }
+        offset = end;
+    }
+    muxer.flush()
+        .map_err(|_| DashMpdError::Muxing(String::from("flushing libav muxer")))?;
+    muxer.close()
+        .map_err(|_| DashMpdError::Muxing(String::from("closing libav muxer")))?;
+    Ok(())
+}
+
+enum ConcatEncoder {
+    Video(VideoEncoder),
+    // The audio encoder and the number of samples encoded so far, used to generate the pts.
+    Audio(AudioEncoder, i64),
+}
+
+enum ConcatDecoder {
+    Video(VideoDecoder, Option<VideoFrameScaler>),
+    Audio(AudioDecoder, AudioResampler),
+}
+
+fn drain_encoder<E: Encoder>(
+    encoder: &mut E,
+    muxer: &mut Muxer<File>,
+    stream_index: usize) -> Result<(), DashMpdError> {
+    while let Some(pkt) = encoder.take()
+        .map_err(|_| DashMpdError::Muxing(String::from("fetching packet from libav encoder")))? {
+        muxer.push(pkt.with_stream_index(stream_index))
+            .map_err(|_| DashMpdError::Muxing(String::from("pushing packet to libav muxer")))?;
+    }
+    Ok(())
+}
+
+fn libav_encoders(input: &DemuxerWithStreamInfo<File>) -> Result<Vec<ConcatEncoder>, DashMpdError> {
+    let mut encoders = Vec::new();
+    for s in input.streams() {
+        let params = s.codec_parameters();
+        if let Some(vp) = params.as_video_codec_parameters() {
+            let enc = VideoEncoder::from_codec_parameters(vp)
+                .and_then(|b| b.time_base(s.time_base()).build())
+                .map_err(|e| DashMpdError::Muxing(format!("building libav video encoder: {e:?}")))?;
+            encoders.push(ConcatEncoder::Video(enc));
+        } else if let Some(ap) = params.as_audio_codec_parameters() {
+            let enc = AudioEncoder::from_codec_parameters(ap)
+                .and_then(|b| b.time_base(TimeBase::new(1, ap.sample_rate() as i32)).build())
+                .map_err(|e| DashMpdError::Muxing(format!("building libav audio encoder: {e:?}")))?;
+            encoders.push(ConcatEncoder::Audio(enc, 0));
+        } else {
+            return Err(DashMpdError::Muxing(String::from("can only reencode audio and video streams")));
+        }
+    }
+    Ok(encoders)
+}
+
+fn libav_decoders(
+    input: &DemuxerWithStreamInfo<File>,
+    encoders: &[ConcatEncoder]) -> Result<Vec<ConcatDecoder>, DashMpdError> {
+    let mut decoders = Vec::new();
+    if input.streams().len() != encoders.len() {
+        return Err(DashMpdError::Muxing(String::from("Periods have a different number of streams")));
+    }
+    for (s, enc) in input.streams().iter().zip(encoders.iter()) {
+        let params = s.codec_parameters();
+        match (enc, params.as_video_codec_parameters(), params.as_audio_codec_parameters()) {
+            (ConcatEncoder::Video(venc), Some(vp), _) => {
+                let dec = VideoDecoder::from_stream(s)
+                    .and_then(|b| b.build())
+                    .map_err(|e| DashMpdError::Muxing(format!("building libav video decoder: {e:?}")))?;
+                let target = venc.codec_parameters();
+                let scaler = if vp.width() == target.width() &&
+                    vp.height() == target.height() &&
+                    vp.pixel_format() == target.pixel_format()
+                {
+                    None
+                } else {
+                    let scaler = VideoFrameScaler::builder()
+                        .source_pixel_format(vp.pixel_format())
+                        .source_width(vp.width())
+                        .source_height(vp.height())
+                        .target_pixel_format(target.pixel_format())
+                        .target_width(target.width())
+                        .target_height(target.height())
+                        .build()
+                        .map_err(|e| DashMpdError::Muxing(format!("building libav video scaler: {e:?}")))?;
+                    Some(scaler)
+                };
+                decoders.push(ConcatDecoder::Video(dec, scaler));
+            },
+            (ConcatEncoder::Audio(aenc, _), _, Some(ap)) => {
+                let dec = AudioDecoder::from_stream(s)
+                    .and_then(|b| b.build())
+                    .map_err(|e| DashMpdError::Muxing(format!("building libav audio decoder: {e:?}")))?;
+                let target = aenc.codec_parameters();
+                let resampler = AudioResampler::builder()
+                    .source_channel_layout(ap.channel_layout().to_owned())
+                    .source_sample_format(ap.sample_format())
+                    .source_sample_rate(ap.sample_rate())
+                    .target_channel_layout(target.channel_layout().to_owned())
+                    .target_sample_format(target.sample_format())
+                    .target_sample_rate(target.sample_rate())
+                    .target_frame_samples(aenc.samples_per_frame())
+                    .build()
+                    .map_err(|e| DashMpdError::Muxing(format!("building libav audio resampler: {e:?}")))?;
+                decoders.push(ConcatDecoder::Audio(dec, resampler));
+            },
+            _ => return Err(DashMpdError::Muxing(String::from("Periods have different stream types"))),
+        }
+    }
+    Ok(decoders)
+}
+
+// Move all frames available from the decoder (and the scaler or resampler) to the encoder, and
+// all packets available from the encoder to the muxer.
+fn transcode_frames(
+    decoder: &mut ConcatDecoder,
+    encoder: &mut ConcatEncoder,
+    muxer: &mut Muxer<File>,
+    stream_index: usize,
+    origin: Timestamp,
+    offset: i64) -> Result<(), DashMpdError> {
+    match (decoder, encoder) {
+        (ConcatDecoder::Video(dec, scaler), ConcatEncoder::Video(enc)) => {
+            while let Some(frame) = dec.take()
+                .map_err(|_| DashMpdError::Muxing(String::from("fetching frame from libav decoder")))? {
+                let frame = match scaler {
+                    Some(sc) => sc.scale(&frame)
+                        .map_err(|_| DashMpdError::Muxing(String::from("scaling video frame")))?,
+                    None => frame,
+                };
+                let pts = shift_timestamp(frame.pts(), origin, offset);
+                enc.push(frame.with_pts(pts))
+                    .map_err(|_| DashMpdError::Muxing(String::from("pushing frame to libav encoder")))?;
+                drain_encoder(enc, muxer, stream_index)?;
+            }
+        },
+        (ConcatDecoder::Audio(dec, resampler), ConcatEncoder::Audio(enc, samples)) => {
+            while let Some(frame) = dec.take()
+                .map_err(|_| DashMpdError::Muxing(String::from("fetching frame from libav decoder")))? {
+                resampler.push(frame)
+                    .map_err(|_| DashMpdError::Muxing(String::from("pushing frame to libav resampler")))?;
+                while let Some(frame) = resampler.take()
+                    .map_err(|_| DashMpdError::Muxing(String::from("fetching frame from libav resampler")))? {
+                    let tb = TimeBase::new(1, frame.sample_rate() as i32);
+                    let pts = Timestamp::new(*samples, tb);
+                    *samples += frame.samples() as i64;
+                    enc.push(frame.with_pts(pts))
+                        .map_err(|_| DashMpdError::Muxing(String::from("pushing frame to libav encoder")))?;
+                    drain_encoder(enc, muxer, stream_index)?;
+                }
+            }
+        },
+        _ => return Err(DashMpdError::Muxing(String::from("mismatched libav decoder and encoder"))),
+    }
+    Ok(())
+}
+
+fn libav_concat_reencode(paths: &[&Path], output: &Path) -> Result<(), DashMpdError> {
+    let mut inputs = libav_open_inputs(paths)?;
+    let first = inputs.first()
+        .ok_or_else(|| DashMpdError::Muxing(String::from("no input files to concatenate")))?;
+    let mut encoders = libav_encoders(first)?;
+    let params: Vec<CodecParameters> = encoders.iter()
+        .map(|e| match e {
+            ConcatEncoder::Video(v) => CodecParameters::from(v.codec_parameters()),
+            ConcatEncoder::Audio(a, _) => CodecParameters::from(a.codec_parameters()),
+        })
+        .collect();
+    let mut muxer = libav_open_output(libav_path_str(output)?, &params)?;
+    let mut offset: i64 = 0;
+    let count = inputs.len();
+    for (i, input) in inputs.iter_mut().enumerate() {
+        let mut decoders = libav_decoders(input, &encoders)?;
+        let origin = input_origin(input);
+        let mut end = offset;
+        while let Some(pkt) = input.take()
+            .map_err(|_| DashMpdError::Muxing(String::from("fetching packet from libav demuxer")))? {
+            let idx = pkt.stream_index();
+            if let Some(e) = packet_end_micros(&pkt) {
+                end = end.max(e - origin.as_micros().unwrap_or(0) + offset);
+            }
+            let pushed = match &mut decoders[idx] {
+                ConcatDecoder::Video(dec, _) => dec.push(pkt),
+                ConcatDecoder::Audio(dec, _) => dec.push(pkt),
+            };
+            pushed.map_err(|_| DashMpdError::Muxing(String::from("pushing packet to libav decoder")))?;
+            transcode_frames(&mut decoders[idx], &mut encoders[idx], &mut muxer, idx, origin, offset)?;
+        }
+        for (idx, dec) in decoders.iter_mut().enumerate() {
+            let flushed = match dec {
+                ConcatDecoder::Video(d, _) => d.flush(),
+                ConcatDecoder::Audio(d, _) => d.flush(),
+            };
+            flushed.map_err(|_| DashMpdError::Muxing(String::from("flushing libav decoder")))?;
+            // The resampler emits frames of exactly the size expected by the encoder, except for
+            // the very last frame. The few samples left over at the end of the intermediate
+            // Periods are dropped.
+            if i + 1 == count {
+                if let ConcatDecoder::Audio(_, r) = dec {
+                    r.flush()
+                        .map_err(|_| DashMpdError::Muxing(String::from("flushing libav resampler")))?;
+                }
+            }
+            transcode_frames(dec, &mut encoders[idx], &mut muxer, idx, origin, offset)?;
+        }
+        offset = end;
+    }
+    for (idx, enc) in encoders.iter_mut().enumerate() {
+        match enc {
+            ConcatEncoder::Video(e) => {
+                e.flush()
+                    .map_err(|_| DashMpdError::Muxing(String::from("flushing libav encoder")))?;
+                drain_encoder(e, &mut muxer, idx)?;
+            },
+            ConcatEncoder::Audio(e, _) => {
+                e.flush()
+                    .map_err(|_| DashMpdError::Muxing(String::from("flushing libav encoder")))?;
+                drain_encoder(e, &mut muxer, idx)?;
+            },
+        }
+    }
+    muxer.flush()
+        .map_err(|_| DashMpdError::Muxing(String::from("flushing libav muxer")))?;
+    muxer.close()
+        .map_err(|_| DashMpdError::Muxing(String::from("closing libav muxer")))?;
+    Ok(())
+}
+
+// Run one of the libav concatenation functions, writing to a temporary file in the same directory
+// as the first Period's file, which is then renamed over the first Period's file.
+fn libav_concat_with(
+    paths: &[&Path],
+    concat: fn(&[&Path], &Path) -> Result<(), DashMpdError>) -> Result<(), DashMpdError> {
+    let dir = paths[0].parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let suffix = paths[0].extension()
+        .and_then(|e| e.to_str())
+        .map(|e| format!(".{e}"))
+        .unwrap_or_else(|| String::from(".mp4"));
+    let tmpout = tempfile::Builder::new()
+        .prefix("dashmpdrs")
+        .suffix(&suffix)
+        .rand_bytes(5)
+        .tempfile_in(dir)
+        .map_err(|e| DashMpdError::Io(e, String::from("creating temporary output file")))?;
+    concat(paths, tmpout.path())?;
+    tmpout.persist(paths[0])
+        .map_err(|e| DashMpdError::Io(e.error, String::from("replacing first Period with concatenated output")))?;
+    Ok(())
+}
+
+/// Period concatenation with libav, named "libav" when remuxing and "libav-reencode" when
+/// reencoding.
+pub(crate) enum LibavConcatenator { Remux, Reencode }
+
+impl Concatenator for LibavConcatenator {
+    fn name(&self) -> &str {
+        match self {
+            LibavConcatenator::Remux => "libav",
+            LibavConcatenator::Reencode => "libav-reencode",
+        }
+    }
+
+    fn concat<'a>(
+        &'a self,
+        _downloader: &'a DashDownloader,
+        paths: &'a [&'a Path]) -> PluginFuture<'a> {
+        Box::pin(async move {
+            ac_ffmpeg::set_log_callback(|_count, msg: &str| info!("ffmpeg: {msg}"));
+            match self {
+                LibavConcatenator::Remux => libav_concat_with(paths, libav_concat_remux),
+                LibavConcatenator::Reencode => libav_concat_with(paths, libav_concat_reencode),
+            }
+        })
+    }
+}
+
+fn builtin_concatenator(name: &str) -> Option<Arc<dyn Concatenator>> {
+    match name {
+        "libav" => Some(Arc::new(LibavConcatenator::Remux)),
+        "libav-reencode" => Some(Arc::new(LibavConcatenator::Reencode)),
+        "native" => Some(Arc::new(NativeConcatenator)),
+        _ => None,
+    }
+}
+
+// Merge all media files named by paths into the file named by the first element of the vector.
+// The concat helpers are tried in the order specified by DashDownloader::with_concat_preference(),
+// the default order being the native concatenation (when possible), then remuxing with libav.
+#[tracing::instrument(level="trace", skip(downloader))]
+pub(crate) async fn concat_output_files(
+    downloader: &DashDownloader,
+    paths: &[&Path]) -> Result<(), DashMpdError> {
+    if paths.len() < 2 {
+        return Ok(());
+    }
+    let container = output_container(paths[0]);
+    let mut default = vec![];
+    if (container.eq("mp4") && fmp4_files_concatable(paths)) ||
+        ((container.eq("mkv") || container.eq("webm")) && webm_files_concatable(paths))
+    {
+        default.push("native");
+    }
+    default.push("libav");
+    let concat_preference = container_preference(&downloader.concat_preference, container, default);
+    info!("  Concat helper preference for {container} is {concat_preference:?}");
+    try_concatenators(downloader, &concat_preference, builtin_concatenator, paths).await
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_mov_text_sample() {
+        use super::mov_text_sample;
+
+        assert_eq!(mov_text_sample(b"<i>Hello</i> &amp; goodbye\n", false), b"\x00\x0fHello & goodbye");
+        assert_eq!(mov_text_sample(b"<v Roger>Hi</v>", false), b"\x00\x02Hi");
+        assert_eq!(mov_text_sample(b"3,0,Default,,0,0,0,,{\\an8}Top\\Nline", true), b"\x00\x08Top\nline");
+        assert_eq!(mov_text_sample(b"", false), b"\x00\x00");
+    }
+}
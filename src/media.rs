@@ -7,19 +7,83 @@
 // When building with the libav feature, several functions here are unused.
 #![allow(dead_code)]
 
+use std::collections::HashMap;
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use file_format::FileFormat;
-use tracing::warn;
+use tracing::{info, warn};
 use crate::DashMpdError;
-use crate::fetch::DashDownloader;
+use crate::language::{iso639_2b, iso639_2t};
+use crate::fetch::{DashDownloader, Muxer, Concatenator, PluginFuture};
+use crate::fmp4::{fmp4_files_concatable, mux_audio_video_native, concat_fmp4_files};
+use crate::webm::{webm_files_concatable, mux_audio_video_webm, concat_webm_files};
 
 
+// An audio stream to be muxed into the output container. When several audio tracks are muxed, the
+// first one is flagged as the default track.
 #[derive(Debug, Clone)]
 pub struct AudioTrack {
     pub language: String,
     pub path: PathBuf,
 }
 
+// Run f, which does blocking file IO (for example the native muxing and concatenation), on a thread
+// where blocking is acceptable rather than on the async runtime's worker threads.
+pub(crate) async fn run_blocking<F, T>(f: F) -> Result<T, DashMpdError>
+where
+    F: FnOnce() -> Result<T, DashMpdError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await
+        .map_err(|e| DashMpdError::Muxing(format!("running blocking task: {e}")))?
+}
+
+// A subtitle file (WebVTT, SRT or ASS) to be embedded in the output container, with the flags that
+// are recorded for the track in the container.
+#[derive(Debug, Clone)]
+pub struct SubtitleTrack {
+    pub language: String,
+    pub path: PathBuf,
+    pub forced: bool,
+    pub hearing_impaired: bool,
+}
+
+// A human-readable name for a subtitle track, recorded in the output container to help the user
+// choose between several tracks in the same language.
+pub(crate) fn subtitle_track_name(st: &SubtitleTrack) -> Option<&'static str> {
+    if st.forced {
+        Some("Forced")
+    } else if st.hearing_impaired {
+        Some("SDH")
+    } else {
+        None
+    }
+}
+
+// The language code to record in the output container for a track whose language in the manifest
+// is the BCP 47 tag lang: the ISO 639-2/B code for Matroska containers and the ISO 639-2/T code for
+// MP4 and other containers. Returns None when the language is unknown or undetermined.
+pub(crate) fn container_language(lang: &str, container: &str) -> Option<String> {
+    if container.eq("mkv") || container.eq("webm") {
+        iso639_2b(lang)
+    } else {
+        iso639_2t(lang)
+    }
+}
+
+// The primary language subtag of the RFC 5646 tag lang, used to name the sidecar files for
+// additional tracks. Returns None when the language is unknown.
+pub(crate) fn language_label(lang: &str) -> Option<String> {
+    let primary = lang.split(['-', '_']).next()?.to_ascii_lowercase();
+    if primary.len() < 2 || primary.len() > 3 || !primary.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    if primary.eq("unk") || primary.eq("und") {
+        return None;
+    }
+    Some(primary)
+}
 
 // Returns "mp4", "mkv", "avi" etc. Based on analyzing the media content rather than on the filename
 // extension.
@@ -153,11 +217,22 @@ pub(crate) fn container_has_video(path: &Path) -> bool {
 // (concatenated, possibly reencoding if the codecs used are different)? They can if:
 //   - they have identical resolutions, frame rate and aspect ratio
 //   - they all only contain audio content
+//   - they are MP4 or WebM/Matroska files with identical tracks, which our native concatenation
+//     can handle without ffprobe
 #[tracing::instrument(level="trace", skip(_downloader))]
 pub(crate) fn video_containers_concatable(_downloader: &DashDownloader, paths: &[&Path]) -> bool {
     if paths.is_empty() {
         return false;
     }
+    let matroska = |p: &&Path| p.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("webm") || e.eq_ignore_ascii_case("mkv"));
+    if paths.iter().all(matroska) && webm_files_concatable(paths) {
+        return true;
+    }
+    let mp4 = |p: &&Path| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("mp4"));
+    if paths.iter().all(mp4) && fmp4_files_concatable(paths) {
+        return true;
+    }
     if let Some(p0) = &paths.first() {
         if let Ok(p0m) = video_container_metainfo(p0) {
             return paths.iter().all(
@@ -167,6 +242,164 @@ pub(crate) fn video_containers_concatable(_downloader: &DashDownloader, paths: &
     paths.iter().all(|p: &&Path| container_only_audio(p))
 }
 
+/// The muxer implemented natively in this crate, named "native", which doesn't require any external
+/// application. It handles fragmented MP4 output (in fmp4.rs) and WebM/Matroska output (in webm.rs).
+pub struct NativeMuxer;
+
+impl Muxer for NativeMuxer {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    fn mux<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        output_path: &'a Path,
+        audio_tracks: &'a [AudioTrack],
+        video_path: Option<&'a Path>) -> PluginFuture<'a> {
+        Box::pin(async move {
+            let container = output_path.extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("mp4");
+            if container.eq("webm") || container.eq("mkv") {
+                mux_audio_video_webm(downloader, output_path, audio_tracks, video_path).await
+            } else {
+                mux_audio_video_native(downloader, output_path, audio_tracks, video_path).await
+            }
+        })
+    }
+}
+
+/// The Period concatenation implemented natively in this crate, named "native", for MP4 files (in
+/// fmp4.rs) and WebM/Matroska files (in webm.rs) with identical tracks.
+pub struct NativeConcatenator;
+
+impl Concatenator for NativeConcatenator {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    fn concat<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        paths: &'a [&'a Path]) -> PluginFuture<'a> {
+        Box::pin(async move {
+            let container = paths.first()
+                .and_then(|p| p.extension())
+                .and_then(|e| e.to_str())
+                .unwrap_or("mp4");
+            if container.eq("mp4") {
+                concat_fmp4_files(downloader, paths).await
+            } else if container.eq("mkv") || container.eq("webm") {
+                concat_webm_files(downloader, paths).await
+            } else {
+                Err(DashMpdError::Muxing(format!("native concatenation of {container} files is not supported")))
+            }
+        })
+    }
+}
+
+// The order in which muxers or concat helpers are tried for a container type: that specified by
+// the user with DashDownloader::with_muxer_preference() or with_concat_preference(), or else our
+// container-dependent default ordering.
+pub(crate) fn container_preference<'a>(
+    configured: &'a HashMap<String, String>,
+    container: &str,
+    default: Vec<&'a str>) -> Vec<&'a str> {
+    match configured.get(container) {
+        Some(ordering) => ordering.split(',').collect(),
+        None => default,
+    }
+}
+
+// Try each helper (muxer or concat helper) named in the preference ordering in turn, until one
+// succeeds, returning whether one succeeded. The kind of helper and the action it performs are only
+// used in log messages.
+async fn try_in_order<H, F, Fut>(
+    preference: &[&str],
+    lookup: impl Fn(&str) -> Option<Arc<H>>,
+    run: F,
+    kind: &str,
+    action: &str) -> bool
+where
+    H: ?Sized,
+    F: Fn(Arc<H>) -> Fut,
+    Fut: Future<Output = Result<(), DashMpdError>>,
+{
+    for name in preference {
+        let Some(helper) = lookup(name) else {
+            warn!("  Ignoring unknown {kind} preference {name}");
+            continue;
+        };
+        info!("  Trying {kind} {name}");
+        match run(helper).await {
+            Ok(()) => {
+                info!("  {action} with {name} succeeded");
+                return true;
+            },
+            Err(e) => warn!("  {action} with {name} failed: {e}"),
+        }
+    }
+    false
+}
+
+// Try each muxer in the preference ordering in turn, until one succeeds. Muxers registered by the
+// user with DashDownloader::with_muxer() take precedence over the built-in muxers.
+pub(crate) async fn try_muxers(
+    downloader: &DashDownloader,
+    preference: &[&str],
+    builtin: fn(&str) -> Option<Arc<dyn Muxer>>,
+    output_path: &Path,
+    audio_tracks: &[AudioTrack],
+    video_path: Option<&Path>) -> Result<(), DashMpdError> {
+    let lookup = |name: &str| downloader.registered_muxer(name).or_else(|| builtin(name));
+    let mux = |muxer: Arc<dyn Muxer>| async move {
+        muxer.mux(downloader, output_path, audio_tracks, video_path).await
+    };
+    if try_in_order(preference, lookup, mux, "muxer", "Muxing").await {
+        return Ok(());
+    }
+    warn!("All muxers failed");
+    warn!("  unmuxed audio streams: {}", audio_tracks.len());
+    if let Some(vp) = video_path {
+        warn!("  unmuxed video stream: {}", vp.display());
+    }
+    Err(DashMpdError::Muxing(String::from("all muxers failed")))
+}
+
+pub(crate) async fn try_subtitle_muxers(
+    downloader: &DashDownloader,
+    preference: &[&str],
+    builtin: fn(&str) -> Option<Arc<dyn Muxer>>,
+    media_path: &Path,
+    subtitle_tracks: &[SubtitleTrack]) -> Result<(), DashMpdError> {
+    let lookup = |name: &str| downloader.registered_muxer(name).or_else(|| builtin(name));
+    let embed = |muxer: Arc<dyn Muxer>| async move {
+        muxer.mux_subtitles(downloader, media_path, subtitle_tracks).await
+    };
+    if try_in_order(preference, lookup, embed, "muxer", "Embedding subtitles").await {
+        return Ok(());
+    }
+    warn!("All muxers failed");
+    Err(DashMpdError::Muxing(String::from("all muxers failed to embed subtitles")))
+}
+
+pub(crate) async fn try_concatenators(
+    downloader: &DashDownloader,
+    preference: &[&str],
+    builtin: fn(&str) -> Option<Arc<dyn Concatenator>>,
+    paths: &[&Path]) -> Result<(), DashMpdError> {
+    let lookup = |name: &str| downloader.registered_concatenator(name).or_else(|| builtin(name));
+    let concat = |concatenator: Arc<dyn Concatenator>| async move {
+        concatenator.concat(downloader, paths).await
+    };
+    if try_in_order(preference, lookup, concat, "concat helper", "Concatenation").await {
+        return Ok(());
+    }
+    warn!("  All concat helpers failed");
+    Err(DashMpdError::Muxing(String::from("all concat helpers failed")))
+}
+
 // mkvmerge on Windows is compiled using MinGW and isn't able to handle native pathnames, so we
 // create the temporary file in the current directory.
 #[cfg(target_os = "windows")]
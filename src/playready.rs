@@ -0,0 +1,335 @@
+//! Support for parsing Microsoft PlayReady DRM initialization data
+//
+// The PlayReady Object (PRO) is found base64-encoded in the mspr:pro element of a ContentProtection
+// element in the manifest, and as the data of a PlayReady PSSH box (in the manifest as cenc:pssh, or
+// in the init segment of a stream). Its format is specified at
+//
+//   https://learn.microsoft.com/en-us/playready/specifications/playready-header-specification
+//
+// A PRO is a little-endian length (u32), a record count (u16) and a list of records. Each record has
+// a type (u16), a length (u16) and a value. A record of type 1 contains a PlayReady Header, a
+// WRMHEADER XML document encoded in UTF-16LE. Versions 4.0 to 4.3 of the WRMHEADER differ in the
+// location of KID information: version 4.0 has a single base64-encoded KID element and an
+// ALGID in the PROTECTINFO element, version 4.1 has a single KID element with VALUE, ALGID and
+// CHECKSUM attributes inside PROTECTINFO, and versions 4.2 and 4.3 have a KIDS element containing
+// multiple KID elements. KIDs are encoded with the GUID byte order (the first three fields
+// little-endian), which differs from the big-endian byte order used for the cenc:default_KID
+// attribute in DASH manifests and in mp4decrypt arguments; we convert to the latter.
+
+
+use std::fmt;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use crate::DashMpdError;
+
+
+/// The DRM system ID for PlayReady, as found in PSSH boxes.
+pub const PLAYREADY_SYSTEM_ID: [u8; 16] = [
+    0x9a, 0x04, 0xf0, 0x79, 0x98, 0x40, 0x42, 0x86,
+    0xab, 0x92, 0xe6, 0x5b, 0xe0, 0x88, 0x5f, 0x95];
+
+/// A key identifier in a PlayReady Header.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PlayReadyKid {
+    /// The KID as 32 lowercase hexadecimal characters, in the byte order used for DASH
+    /// @cenc:default_KID attributes (not the GUID byte order used in the PlayReady Header).
+    pub kid: String,
+    /// The encryption algorithm: AESCTR, AESCBC or COCKTAIL.
+    pub algid: Option<String>,
+    pub checksum: Option<String>,
+}
+
+/// A PlayReady Header (the WRMHEADER XML document).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WrmHeader {
+    /// The header version, for example "4.0.0.0" or "4.3.0.0".
+    pub version: String,
+    pub kids: Vec<PlayReadyKid>,
+    pub keylen: Option<u32>,
+    /// License acquisition URL.
+    pub la_url: Option<String>,
+    /// License user interface URL.
+    pub lui_url: Option<String>,
+    /// Domain service identifier.
+    pub ds_id: Option<String>,
+    /// The (XML) content of the CUSTOMATTRIBUTES element, which is specific to the content provider.
+    pub custom_attributes: Option<String>,
+    pub decryptor_setup: Option<String>,
+    /// The full XML text of the header.
+    pub xml: String,
+}
+
+/// A record in a PlayReady Object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayReadyRecord {
+    /// Record type 1.
+    RightsManagementHeader(WrmHeader),
+    /// Record type 3.
+    EmbeddedLicenseStore(Vec<u8>),
+    Unknown(u16, Vec<u8>),
+}
+
+/// A PlayReady Object (PRO), containing a list of records.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PlayReadyObject {
+    pub records: Vec<PlayReadyRecord>,
+}
+
+fn pr_error(why: &str) -> DashMpdError {
+    DashMpdError::Parsing(format!("PlayReady Object: {why}"))
+}
+
+// Convert a base64-encoded KID in GUID byte order to hexadecimal in big-endian byte order.
+fn guid_kid_to_hex(b64: &str) -> Result<String, DashMpdError> {
+    let b = BASE64_STANDARD.decode(b64.trim())
+        .map_err(|_| pr_error("invalid base64 in KID"))?;
+    if b.len() != 16 {
+        return Err(pr_error("KID is not 16 octets"));
+    }
+    let order = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+    Ok(order.iter().map(|i| format!("{:02x}", b[*i])).collect())
+}
+
+fn unescape(s: &str) -> String {
+    match quick_xml::escape::unescape(s) {
+        Ok(u) => u.to_string(),
+        Err(_) => s.to_string(),
+    }
+}
+
+impl WrmHeader {
+    /// Parse the WRMHEADER XML document.
+    pub fn parse(xml: &str) -> Result<WrmHeader, DashMpdError> {
+        use xmlparser::{Token, ElementEnd};
+
+        let mut hdr = WrmHeader { xml: xml.to_string(), ..Default::default() };
+        let mut stack: Vec<String> = Vec::new();
+        // Attributes of the element whose start tag is currently being tokenized
+        let mut attrs: Vec<(String, String)> = Vec::new();
+        let mut pending: Option<String> = None;
+        // For version 4.0 headers, the ALGID and CHECKSUM are separate from the KID element.
+        let mut v4_algid: Option<String> = None;
+        let mut v4_checksum: Option<String> = None;
+        let mut custom_start: Option<usize> = None;
+        for token in xmlparser::Tokenizer::from(xml) {
+            let token = token.map_err(|e| pr_error(&format!("invalid WRMHEADER XML: {e}")))?;
+            match token {
+                Token::ElementStart { local, .. } => {
+                    pending = Some(local.to_string());
+                    attrs.clear();
+                },
+                Token::Attribute { local, value, .. } => {
+                    attrs.push((local.to_string(), unescape(value.as_str())));
+                },
+                Token::ElementEnd { end, span } => {
+                    match end {
+                        ElementEnd::Open | ElementEnd::Empty => {
+                            let name = pending.take().unwrap_or_default();
+                            let attr = |n: &str| attrs.iter().find(|a| a.0.eq(n)).map(|a| a.1.clone());
+                            if name.eq("WRMHEADER") {
+                                hdr.version = attr("version").unwrap_or_default();
+                            }
+                            let in_custom = stack.iter().any(|n| n.eq("CUSTOMATTRIBUTES"));
+                            if name.eq("KID") && !in_custom {
+                                if let Some(value) = attr("VALUE") {
+                                    hdr.kids.push(PlayReadyKid {
+                                        kid: guid_kid_to_hex(&value)?,
+                                        algid: attr("ALGID"),
+                                        checksum: attr("CHECKSUM"),
+                                    });
+                                }
+                            }
+                            if name.eq("CUSTOMATTRIBUTES") && matches!(end, ElementEnd::Open) {
+                                custom_start = Some(span.end());
+                            }
+                            if matches!(end, ElementEnd::Open) {
+                                stack.push(name);
+                            }
+                        },
+                        ElementEnd::Close(_, local) => {
+                            if local.as_str().eq("CUSTOMATTRIBUTES") {
+                                if let Some(start) = custom_start.take() {
+                                    hdr.custom_attributes = Some(xml[start..span.start()].trim().to_string());
+                                }
+                            }
+                            stack.pop();
+                        },
+                    }
+                },
+                Token::Text { text } => {
+                    let text = unescape(text.as_str().trim());
+                    if text.is_empty() {
+                        continue;
+                    }
+                    let parent = stack.last().map(String::as_str);
+                    let in_custom = stack.iter().any(|n| n.eq("CUSTOMATTRIBUTES"));
+                    if in_custom {
+                        continue;
+                    }
+                    match parent {
+                        Some("KID") => {
+                            hdr.kids.push(PlayReadyKid { kid: guid_kid_to_hex(&text)?, ..Default::default() });
+                        },
+                        Some("ALGID") => v4_algid = Some(text),
+                        Some("CHECKSUM") => v4_checksum = Some(text),
+                        Some("KEYLEN") => hdr.keylen = text.parse().ok(),
+                        Some("LA_URL") => hdr.la_url = Some(text),
+                        Some("LUI_URL") => hdr.lui_url = Some(text),
+                        Some("DS_ID") => hdr.ds_id = Some(text),
+                        _ => (),
+                    }
+                },
+                _ => (),
+            }
+        }
+        if hdr.version.is_empty() {
+            return Err(pr_error("missing WRMHEADER element"));
+        }
+        if let Some(start) = xml.find("<DECRYPTORSETUP>") {
+            if let Some(end) = xml.find("</DECRYPTORSETUP>") {
+                hdr.decryptor_setup = Some(xml[start + 16..end].trim().to_string());
+            }
+        }
+        // In a version 4.0 header, the ALGID and CHECKSUM apply to the single KID.
+        if hdr.version.starts_with("4.0") {
+            if let Some(k) = hdr.kids.first_mut() {
+                if k.algid.is_none() {
+                    k.algid = v4_algid;
+                }
+                if k.checksum.is_none() {
+                    k.checksum = v4_checksum;
+                }
+            }
+        }
+        Ok(hdr)
+    }
+}
+
+impl fmt::Display for WrmHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PlayReady header v{}", self.version)?;
+        for k in &self.kids {
+            write!(f, " KID={}", k.kid)?;
+            if let Some(a) = &k.algid {
+                write!(f, " ALGID={a}")?;
+            }
+        }
+        if let Some(u) = &self.la_url {
+            write!(f, " LA_URL={u}")?;
+        }
+        if let Some(u) = &self.lui_url {
+            write!(f, " LUI_URL={u}")?;
+        }
+        if let Some(d) = &self.ds_id {
+            write!(f, " DS_ID={d}")?;
+        }
+        if let Some(ca) = &self.custom_attributes {
+            write!(f, " CUSTOMATTRIBUTES={ca}")?;
+        }
+        Ok(())
+    }
+}
+
+impl PlayReadyObject {
+    /// Parse a PlayReady Object from its binary representation.
+    pub fn from_bytes(bytes: &[u8]) -> Result<PlayReadyObject, DashMpdError> {
+        let u16_at = |pos: usize| -> Result<u16, DashMpdError> {
+            bytes.get(pos..pos+2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .ok_or_else(|| pr_error("truncated data"))
+        };
+        let length = bytes.get(0..4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| pr_error("truncated data"))?;
+        if length as usize > bytes.len() {
+            return Err(pr_error("length exceeds data size"));
+        }
+        let count = u16_at(4)?;
+        let mut pos = 6;
+        let mut records = Vec::new();
+        for _ in 0..count {
+            let rtype = u16_at(pos)?;
+            let rlen = u16_at(pos + 2)? as usize;
+            let value = bytes.get(pos+4..pos+4+rlen)
+                .ok_or_else(|| pr_error("truncated record"))?;
+            pos += 4 + rlen;
+            let record = match rtype {
+                1 => {
+                    if !rlen.is_multiple_of(2) {
+                        return Err(pr_error("odd length for UTF-16 PlayReady Header"));
+                    }
+                    let utf16: Vec<u16> = value.chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .collect();
+                    let xml = String::from_utf16(&utf16)
+                        .map_err(|_| pr_error("invalid UTF-16 in PlayReady Header"))?;
+                    let xml = xml.trim_start_matches('\u{feff}');
+                    PlayReadyRecord::RightsManagementHeader(WrmHeader::parse(xml)?)
+                },
+                3 => PlayReadyRecord::EmbeddedLicenseStore(value.to_vec()),
+                _ => PlayReadyRecord::Unknown(rtype, value.to_vec()),
+            };
+            records.push(record);
+        }
+        Ok(PlayReadyObject { records })
+    }
+
+    /// Parse a base64-encoded PlayReady Object, such as the content of an mspr:pro element.
+    pub fn from_base64(b64: &str) -> Result<PlayReadyObject, DashMpdError> {
+        let cleaned: String = b64.split_whitespace().collect();
+        let bytes = BASE64_STANDARD.decode(cleaned)
+            .map_err(|_| pr_error("invalid base64"))?;
+        PlayReadyObject::from_bytes(&bytes)
+    }
+
+    /// Parse the PlayReady Object contained in a PlayReady PSSH box (including the box header).
+    pub fn from_pssh(pssh: &[u8]) -> Result<PlayReadyObject, DashMpdError> {
+        if pssh.len() < 32 || !pssh[4..8].eq(b"pssh") {
+            return Err(pr_error("not a PSSH box"));
+        }
+        if !pssh[12..28].eq(&PLAYREADY_SYSTEM_ID) {
+            return Err(pr_error("not a PlayReady PSSH box"));
+        }
+        let version = pssh[8];
+        let mut pos = 28;
+        if version > 0 {
+            let kid_count = pssh.get(pos..pos+4)
+                .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| pr_error("truncated PSSH box"))?;
+            pos += 4 + 16 * kid_count as usize;
+        }
+        let data_size = pssh.get(pos..pos+4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| pr_error("truncated PSSH box"))? as usize;
+        let data = pssh.get(pos+4..pos+4+data_size)
+            .ok_or_else(|| pr_error("truncated PSSH box"))?;
+        PlayReadyObject::from_bytes(data)
+    }
+
+    /// The PlayReady Headers contained in this object.
+    pub fn headers(&self) -> impl Iterator<Item = &WrmHeader> {
+        self.records.iter().filter_map(|r| match r {
+            PlayReadyRecord::RightsManagementHeader(h) => Some(h),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for PlayReadyObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for r in &self.records {
+            if !first {
+                write!(f, "; ")?;
+            }
+            first = false;
+            match r {
+                PlayReadyRecord::RightsManagementHeader(h) => write!(f, "{h}")?,
+                PlayReadyRecord::EmbeddedLicenseStore(d) =>
+                    write!(f, "PlayReady embedded license store ({} octets)", d.len())?,
+                PlayReadyRecord::Unknown(t, d) =>
+                    write!(f, "PlayReady record of unknown type {t} ({} octets)", d.len())?,
+            }
+        }
+        Ok(())
+    }
+}
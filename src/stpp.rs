@@ -13,8 +13,15 @@
 //
 //  - serializing to a single merged TTML subtitle file
 //
-// We only support the text-only IMSC1 profile for TTML subtitles ("stpp.ttml.im1t"); the image-only
-// profile ("stpp.ttml.im1i") is not supported.
+//  - converting the subtitles to WebVTT or SRT format. This requires resolving the timing of each
+//  paragraph (begin, end and dur attributes, which are relative to the enclosing body, div and
+//  span time containers and are interpreted according to the ttp:frameRate, ttp:subFrameRate and
+//  ttp:tickRate parameters, and for the smpte time base to ttp:dropMode), and mapping styles and
+//  regions to WebVTT cue settings and markup where possible.
+//
+// For the image-only IMSC1 profile ("stpp.ttml.im1i"), we extract the PNG images referenced by
+// smpte:backgroundImage attributes, either embedded in the document as smpte:image elements or
+// stored as subsamples in the mdat box following the TTML document, together with their timing.
 //
 // An example of the XML content in a TTML/STPP fragment:
 //
@@ -50,12 +57,183 @@
 
 
 use std::io::Cursor;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
 use xot::{Xot, output};
 use xot::xmlname::NameStrInfo;
 use xmlparser::{ElementEnd, Token, Tokenizer};
 use tracing::{trace, warn, error};
 use bytes::Bytes;
 use crate::DashMpdError;
+use crate::vtt::{VttCue, cues_to_webvtt, cues_to_srt, merge_duplicate_cues};
+
+
+const TTML_PARAMETER_NS: &str = "http://www.w3.org/ns/ttml#parameter";
+const TTML_STYLING_NS: &str = "http://www.w3.org/ns/ttml#styling";
+const SMPTE_TT_NS: &str = "http://www.smpte-ra.org/schemas/2052-1/2010/smpte-tt";
+
+
+/// An image subtitle from the IMSC1 image profile, with its timing (in milliseconds) and the
+/// WebVTT cue settings corresponding to its region.
+#[derive(Clone, Debug)]
+pub struct TtmlImage {
+    pub start_ms: u64,
+    pub end_ms: u64,
+    pub settings: Option<String>,
+    pub png: Vec<u8>,
+}
+
+// The ttp:dropMode parameter, which specifies how SMPTE time codes skip frame numbers when the
+// smpte time base is used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum DropMode {
+    #[default]
+    NonDrop,
+    // Frames 0 and 1 are dropped at the start of each minute, except minutes 0, 10, 20 and so on.
+    DropNtsc,
+    // Frames 0 to 3 are dropped at the start of each even minute, except minutes 0, 20, 40.
+    DropPal,
+}
+
+// Parameters used to interpret time expressions, from the ttp: attributes on the tt element.
+#[derive(Clone, Copy, Debug)]
+struct TimingParameters {
+    frame_rate: f64,
+    sub_frame_rate: f64,
+    tick_rate: f64,
+    drop_mode: DropMode,
+}
+
+impl Default for TimingParameters {
+    fn default() -> Self {
+        TimingParameters { frame_rate: 30.0, sub_frame_rate: 1.0, tick_rate: 1.0, drop_mode: DropMode::NonDrop }
+    }
+}
+
+// The active interval of a timed element, in seconds. An end of None means that the element
+// remains active indefinitely.
+#[derive(Clone, Copy, Debug)]
+struct Interval {
+    begin: f64,
+    end: Option<f64>,
+}
+
+// Parse a TTML time expression (clock-time or offset-time), returning a number of seconds.
+//
+// https://www.w3.org/TR/ttml2/#timing-value-time-expression
+fn parse_time_expression(expr: &str, params: &TimingParameters) -> Option<f64> {
+    let expr = expr.trim();
+    if expr.contains(':') {
+        let parts: Vec<&str> = expr.split(':').collect();
+        let hours: f64 = parts.first()?.parse().ok()?;
+        let minutes: f64 = parts.get(1)?.parse().ok()?;
+        let seconds: f64 = parts.get(2)?.parse().ok()?;
+        // hh:mm:ss:frames[.subframes]
+        let (frames, subframes) = match parts.get(3) {
+            Some(f) => match f.split_once('.') {
+                Some((f, sf)) => (f.parse::<f64>().ok()?, sf.parse::<f64>().ok()?),
+                None => (f.parse::<f64>().ok()?, 0.0),
+            },
+            None if parts.len() == 3 => (0.0, 0.0),
+            None => return None,
+        };
+        if params.drop_mode == DropMode::NonDrop {
+            let t = hours * 3600.0 + minutes * 60.0 + seconds;
+            return Some(t + (frames + subframes / params.sub_frame_rate) / params.frame_rate);
+        }
+        // A drop-frame time code counts frames at the nominal (integer) frame rate, skipping some
+        // frame numbers so that the time code stays close to clock time.
+        let total_minutes = (hours * 60.0 + minutes) as u64;
+        let dropped = match params.drop_mode {
+            DropMode::DropNtsc => 2 * (total_minutes - total_minutes / 10),
+            _ => 4 * (total_minutes / 2 - total_minutes / 20),
+        };
+        let nominal = params.frame_rate.round();
+        let count = (hours * 3600.0 + minutes * 60.0 + seconds) * nominal + frames - dropped as f64;
+        return Some((count + subframes / params.sub_frame_rate) / params.frame_rate);
+    }
+    let split = expr.find(|c: char| c.is_ascii_alphabetic())?;
+    let (value, metric) = expr.split_at(split);
+    let value: f64 = value.parse().ok()?;
+    match metric {
+        "h" => Some(value * 3600.0),
+        "m" => Some(value * 60.0),
+        "s" => Some(value),
+        "ms" => Some(value / 1000.0),
+        "f" => Some(value / params.frame_rate),
+        "t" => Some(value / params.tick_rate),
+        _ => None,
+    }
+}
+
+// Parse a pair of lengths such as "10% 85%" or "192px 918px" into percentages of the root
+// container region. Pixel values are only supported if the root container extent is known.
+fn parse_length_pair(value: &str, root_extent: Option<(f64, f64)>) -> Option<(f64, f64)> {
+    let mut parts = value.split_whitespace();
+    let parse_one = |v: &str, root: Option<f64>| -> Option<f64> {
+        if let Some(pct) = v.strip_suffix('%') {
+            pct.parse().ok()
+        } else if let Some(px) = v.strip_suffix("px") {
+            let px: f64 = px.parse().ok()?;
+            Some(100.0 * px / root?)
+        } else {
+            None
+        }
+    };
+    let x = parse_one(parts.next()?, root_extent.map(|r| r.0))?;
+    let y = parse_one(parts.next()?, root_extent.map(|r| r.1))?;
+    Some((x, y))
+}
+
+fn format_percent(value: f64) -> String {
+    let s = format!("{value:.2}");
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    format!("{s}%")
+}
+
+fn seconds_to_ms(secs: f64) -> u64 {
+    (secs.max(0.0) * 1000.0).round() as u64
+}
+
+fn vtt_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// A fragment of the text of a paragraph, with its active interval and WebVTT markup.
+struct TextRun {
+    interval: Interval,
+    text: String,
+}
+
+// Extract the PNG images stored as subsamples after the TTML document in an mdat box, in order.
+fn split_png_images(data: &[u8]) -> Vec<Vec<u8>> {
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let mut images = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = data[pos..].windows(8).position(|w| w == PNG_SIGNATURE) {
+        let start = pos + start;
+        let mut chunk = start + 8;
+        // Each chunk has a 4-octet length, 4-octet type, the data and a 4-octet CRC.
+        let end = loop {
+            let Some(len) = data.get(chunk..chunk+4) else { break None };
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            let ctype = data.get(chunk+4..chunk+8);
+            chunk += 12 + len;
+            if ctype == Some(b"IEND") {
+                break Some(chunk);
+            }
+        };
+        match end {
+            Some(end) if end <= data.len() => {
+                images.push(data[start..end].to_vec());
+                pos = end;
+            },
+            _ => break,
+        }
+    }
+    images
+}
 
 
 #[derive(Clone, Debug)]
@@ -65,7 +243,13 @@ pub struct StppDocument {
     regions: Vec<xot::Node>,
     // A paragraph is a single subtitle cue.
     paragraphs: Vec<xot::Node>,
+    // The body element of each fragment, with the offset in milliseconds of the Period it belongs to.
+    bodies: Vec<(xot::Node, i64)>,
+    offset_ms: i64,
+    // Image subtitles, for the IMSC1 image profile.
+    images: Vec<TtmlImage>,
     warned_binary_contents: bool,
+    warned_time_base: bool,
 }
 
 impl Default for StppDocument {
@@ -84,11 +268,22 @@ impl StppDocument {
             styles: Vec::new(),
             regions: Vec::new(),
             paragraphs: Vec::new(),
+            bodies: Vec::new(),
+            offset_ms: 0,
+            images: Vec::new(),
             warned_binary_contents: false,
+            warned_time_base: false,
         }
     }
 
 
+    /// Set the offset (in milliseconds) of the Period whose fragments are added after this call, as
+    /// for [VttDocument::set_offset_ms](crate::vtt::VttDocument::set_offset_ms). In the merged
+    /// TTML document, the paragraphs of each Period are placed in a div that begins at its offset.
+    pub fn set_offset_ms(&mut self, offset_ms: i64) {
+        self.offset_ms = offset_ms;
+    }
+
     // Extract XML content from a fragmented MP4 segment (argument bytes) and add its contents to
     // the content accumulated in the parent StppDocument. The content is typically in an mdat box,
     // or sometimes an stpp box.
@@ -103,8 +298,17 @@ impl StppDocument {
                 Ok(maybe_atom) => {
                     match maybe_atom {
                         // Some(mp4_atom::Any::Stpp(_stpp)) => (),
-                        Some(mp4_atom::Any::Mdat(mdat)) => if let Ok(xml) = str::from_utf8(&mdat.data) {
-                            self.add_content(xml)?;
+                        Some(mp4_atom::Any::Mdat(mdat)) => {
+                            // With the IMSC1 image profile, the TTML document is followed by
+                            // subsamples containing PNG images.
+                            let xml = match str::from_utf8(&mdat.data) {
+                                Ok(xml) => xml,
+                                Err(e) => str::from_utf8(&mdat.data[..e.valid_up_to()]).unwrap_or_default(),
+                            };
+                            let subsamples = split_png_images(&mdat.data[xml.len()..]);
+                            if !xml.trim().is_empty() {
+                                self.add_content_with_images(xml, &subsamples)?;
+                            }
                         },
                         Some(_) => (),
                         None => break,
@@ -145,6 +349,12 @@ impl StppDocument {
     // a media player to jump to a random point in the subtitle stream without requiring it to load
     // all the previous subtitle segments. We filter these out.
     pub fn add_content(&mut self, xml: &str) -> Result<(), DashMpdError> {
+        self.add_content_with_images(xml, &[])
+    }
+
+    // As for add_content, where subsamples contains the images stored as subsamples following the
+    // TTML document in an MP4 sample, which may be referenced from the document.
+    fn add_content_with_images(&mut self, xml: &str, subsamples: &[Vec<u8>]) -> Result<(), DashMpdError> {
         trace!("adding STPP content {xml}");
         let mut clean_xml = xml;
         let epos = identify_xml_endpos(xml)
@@ -165,6 +375,17 @@ impl StppDocument {
             warn!("Missing tt root element in STPP XML: {xml}");
             return Ok(());
         }
+        if !self.warned_time_base {
+            let time_base = self.attribute(tt, TTML_PARAMETER_NS, "timeBase").unwrap_or("media");
+            let marker_mode = self.attribute(tt, TTML_PARAMETER_NS, "markerMode").unwrap_or("continuous");
+            if time_base.eq("clock") {
+                warn!("TTML subtitles use the clock time base; interpreting wall-clock times as media times");
+                self.warned_time_base = true;
+            } else if time_base.eq("smpte") && marker_mode.eq("discontinuous") {
+                warn!("TTML subtitles use discontinuous SMPTE time codes; interpreting them as continuous");
+                self.warned_time_base = true;
+            }
+        }
         let xml_ns = self.xot.add_namespace("http://www.w3.org/XML/1998/namespace");
         let id_name = self.xot.add_name_ns("id", xml_ns);
         if let Some(head) = self.find_child_named(tt, "head") {
@@ -202,10 +423,390 @@ impl StppDocument {
             for d in self.xot.children(body) {
                 self.paragraphs.push(d);
             }
+            self.bodies.push((body, self.offset_ms));
+            for image in self.collect_images(tt, body, subsamples) {
+                // An image that spans a segment boundary is repeated in both segments.
+                let same = self.images.iter_mut()
+                    .rev()
+                    .take(16)
+                    .find(|i| i.png == image.png && i.end_ms >= image.start_ms && i.settings == image.settings);
+                match same {
+                    Some(i) => i.end_ms = i.end_ms.max(image.end_ms),
+                    None => self.images.push(image),
+                }
+            }
         }
         Ok(())
     }
 
+    // The value of the attribute named local in namespace ns (the empty string for attributes
+    // without a namespace) on node.
+    fn attribute(&self, node: xot::Node, ns: &str, local: &str) -> Option<&str> {
+        let nsid = if ns.is_empty() {
+            self.xot.no_namespace()
+        } else {
+            self.xot.namespace(ns)?
+        };
+        let name = self.xot.name_ns(local, nsid)?;
+        self.xot.get_attribute(node, name)
+    }
+
+    fn local_name(&self, node: xot::Node) -> Option<&str> {
+        self.xot.element(node).map(|e| self.xot.local_name_str(e.name()))
+    }
+
+    // Time expressions in the media time base, and SMPTE time codes in the smpte time base (which
+    // may be drop-frame time codes), are converted to media time. Times in the clock time base and
+    // discontinuous SMPTE time codes can't be related to the media timeline, and are interpreted as
+    // media times (a warning is emitted when the document is added).
+    fn timing_parameters(&self, tt: xot::Node) -> TimingParameters {
+        let number = |local| self.attribute(tt, TTML_PARAMETER_NS, local)
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|v| *v > 0.0);
+        let mut params = TimingParameters::default();
+        let frame_rate = number("frameRate");
+        if let Some(fr) = frame_rate {
+            params.frame_rate = fr;
+        }
+        if let Some(frm) = self.attribute(tt, TTML_PARAMETER_NS, "frameRateMultiplier") {
+            let mut parts = frm.split_whitespace().map(|p| p.parse::<f64>().ok());
+            if let (Some(Some(num)), Some(Some(den))) = (parts.next(), parts.next()) {
+                if num > 0.0 && den > 0.0 {
+                    params.frame_rate *= num / den;
+                }
+            }
+        }
+        if let Some(sfr) = number("subFrameRate") {
+            params.sub_frame_rate = sfr;
+        }
+        params.tick_rate = match number("tickRate") {
+            Some(tr) => tr,
+            None if frame_rate.is_some() => params.frame_rate * params.sub_frame_rate,
+            None => 1.0,
+        };
+        if self.attribute(tt, TTML_PARAMETER_NS, "timeBase").is_some_and(|tb| tb.eq("smpte")) {
+            params.drop_mode = match self.attribute(tt, TTML_PARAMETER_NS, "dropMode") {
+                Some("dropNTSC") => DropMode::DropNtsc,
+                Some("dropPAL") => DropMode::DropPal,
+                _ => DropMode::NonDrop,
+            };
+        }
+        params
+    }
+
+    // The active interval of element node, whose parent has active interval parent. Within a seq
+    // time container, begin times are relative to the end of the previous sibling (seq_base).
+    fn child_interval(
+        &self,
+        node: xot::Node,
+        parent: Interval,
+        seq_base: Option<f64>,
+        params: &TimingParameters) -> Interval
+    {
+        let base = seq_base.unwrap_or(parent.begin);
+        let time = |local| self.attribute(node, "", local)
+            .and_then(|t| parse_time_expression(t, params));
+        let begin = base + time("begin").unwrap_or(0.0);
+        let end = match (time("end"), time("dur")) {
+            (Some(e), Some(d)) => Some((base + e).min(begin + d)),
+            (Some(e), None) => Some(base + e),
+            (None, Some(d)) => Some(begin + d),
+            (None, None) => parent.end,
+        };
+        let end = match (end, parent.end) {
+            (Some(e), Some(pe)) => Some(e.min(pe)),
+            (e, _) => e,
+        };
+        Interval { begin, end }
+    }
+
+    // Call f on each element descendant of node (whose active interval is interval), with its
+    // active interval. The descendants of an element are visited only if f returns true.
+    fn walk_timed(
+        &self,
+        node: xot::Node,
+        interval: Interval,
+        params: &TimingParameters,
+        f: &mut dyn FnMut(xot::Node, Interval) -> bool)
+    {
+        let seq = self.attribute(node, "", "timeContainer").is_some_and(|tc| tc.eq("seq"));
+        let mut seq_base = seq.then_some(interval.begin);
+        for child in self.xot.children(node) {
+            if !self.xot.is_element(child) {
+                continue;
+            }
+            let ci = self.child_interval(child, interval, seq_base, params);
+            if f(child, ci) {
+                self.walk_timed(child, ci, params, f);
+            }
+            if seq {
+                seq_base = Some(ci.end.unwrap_or(ci.begin));
+            }
+        }
+    }
+
+    fn find_by_id(&self, nodes: &[xot::Node], id: &str) -> Option<xot::Node> {
+        let xml_id = self.xot.xml_id_name();
+        nodes.iter().find(|n| self.xot.get_attribute(**n, xml_id) == Some(id)).copied()
+    }
+
+    // The value of the styling attribute local specified on node, either inline or through
+    // (possibly chained) referential styling.
+    fn specified_style(&self, node: xot::Node, local: &str, depth: usize) -> Option<String> {
+        if let Some(v) = self.attribute(node, TTML_STYLING_NS, local) {
+            return Some(v.to_string());
+        }
+        if depth < 8 {
+            if let Some(refs) = self.attribute(node, "", "style") {
+                for id in refs.split_whitespace() {
+                    if let Some(style) = self.find_by_id(&self.styles, id) {
+                        if let Some(v) = self.specified_style(style, local, depth + 1) {
+                            return Some(v);
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn region_for(&self, node: xot::Node) -> Option<xot::Node> {
+        let id = self.xot.ancestors(node).find_map(|n| self.attribute(n, "", "region"))?;
+        self.find_by_id(&self.regions, id)
+    }
+
+    // The computed value of the styling attribute local for node, inherited from its ancestors or
+    // from its region.
+    fn computed_style(&self, node: xot::Node, local: &str) -> Option<String> {
+        for n in self.xot.ancestors(node) {
+            if self.local_name(n).is_none_or(|name| name.eq("tt")) {
+                break;
+            }
+            if let Some(v) = self.specified_style(n, local, 0) {
+                return Some(v);
+            }
+        }
+        self.region_for(node).and_then(|r| self.specified_style(r, local, 0))
+    }
+
+    // WebVTT cue settings corresponding to the region and text alignment of node.
+    fn cue_settings(&self, node: xot::Node) -> Option<String> {
+        let mut settings = Vec::new();
+        if let Some(region) = self.region_for(node) {
+            let root_extent = self.xot.ancestors(node)
+                .find(|n| self.local_name(*n).is_some_and(|name| name.eq("tt")))
+                .and_then(|tt| self.attribute(tt, TTML_STYLING_NS, "extent"))
+                .and_then(|e| {
+                    let mut parts = e.split_whitespace()
+                        .map(|p| p.strip_suffix("px").and_then(|px| px.parse::<f64>().ok()));
+                    Some((parts.next()??, parts.next()??))
+                });
+            let origin = self.specified_style(region, "origin", 0)
+                .and_then(|o| parse_length_pair(&o, root_extent));
+            let extent = self.specified_style(region, "extent", 0)
+                .and_then(|e| parse_length_pair(&e, root_extent));
+            if let (Some((x, y)), Some((w, h))) = (origin, extent) {
+                let line = match self.specified_style(region, "displayAlign", 0).as_deref() {
+                    Some("after") => format!("line:{},end", format_percent(y + h)),
+                    Some("center") => format!("line:{},center", format_percent(y + h / 2.0)),
+                    _ => format!("line:{}", format_percent(y)),
+                };
+                settings.push(line);
+                settings.push(format!("position:{},line-left", format_percent(x)));
+                settings.push(format!("size:{}", format_percent(w)));
+            }
+        }
+        if let Some(align) = self.computed_style(node, "textAlign") {
+            if matches!(align.as_str(), "left" | "right" | "center" | "start" | "end") {
+                settings.push(format!("align:{align}"));
+            }
+        }
+        (!settings.is_empty()).then(|| settings.join(" "))
+    }
+
+    // Wrap text in the WebVTT markup corresponding to the computed style of element node.
+    fn markup(&self, node: xot::Node, text: String) -> String {
+        let mut text = text;
+        if matches!(self.computed_style(node, "fontStyle").as_deref(), Some("italic" | "oblique")) {
+            text = format!("<i>{text}</i>");
+        }
+        if self.computed_style(node, "fontWeight").is_some_and(|w| w.eq("bold")) {
+            text = format!("<b>{text}</b>");
+        }
+        if self.computed_style(node, "textDecoration").is_some_and(|d| d.contains("underline")) {
+            text = format!("<u>{text}</u>");
+        }
+        text
+    }
+
+    // Collect the text runs in paragraph or span node, with their active intervals.
+    fn text_runs(&self, node: xot::Node, interval: Interval, params: &TimingParameters, runs: &mut Vec<TextRun>) {
+        for child in self.xot.children(node) {
+            if let Some(text) = self.xot.text_str(child) {
+                // Collapse whitespace, as for the default xml:space="default" handling.
+                let mut collapsed = String::new();
+                for c in text.chars() {
+                    if c.is_whitespace() {
+                        if !collapsed.ends_with(' ') {
+                            collapsed.push(' ');
+                        }
+                    } else {
+                        collapsed.push(c);
+                    }
+                }
+                if !collapsed.is_empty() {
+                    runs.push(TextRun { interval, text: self.markup(node, vtt_escape(&collapsed)) });
+                }
+            } else if self.xot.is_element(child) {
+                let ci = self.child_interval(child, interval, None, params);
+                match self.local_name(child) {
+                    Some("br") => runs.push(TextRun { interval: ci, text: String::from("\n") }),
+                    Some("span") => self.text_runs(child, ci, params, runs),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Convert paragraph node to one or more cues. A paragraph whose spans have different timing
+    // (for example for roll-up captions) is split into a cue for each interval during which the
+    // displayed text is constant.
+    fn paragraph_cues(&self, node: xot::Node, interval: Interval, params: &TimingParameters, cues: &mut Vec<VttCue>) {
+        let Some(end) = interval.end else {
+            trace!("Ignoring TTML paragraph with unresolved end time");
+            return;
+        };
+        let mut runs = Vec::new();
+        self.text_runs(node, interval, params, &mut runs);
+        let mut times = vec![interval.begin, end];
+        for r in &runs {
+            times.push(r.interval.begin);
+            times.extend(r.interval.end);
+        }
+        times.retain(|t| *t >= interval.begin && *t <= end);
+        times.sort_by(f64::total_cmp);
+        times.dedup();
+        let settings = self.cue_settings(node);
+        for w in times.windows(2) {
+            let text: String = runs.iter()
+                .filter(|r| r.interval.begin <= w[0] && r.interval.end.is_none_or(|e| e >= w[1]))
+                .map(|r| r.text.as_str())
+                .collect();
+            let mut payload = text;
+            for tag in ["i", "b", "u"] {
+                payload = payload.replace(&format!("</{tag}><{tag}>"), "");
+            }
+            let payload = payload.lines()
+                .map(|l| l.split(' ').filter(|w| !w.is_empty()).collect::<Vec<_>>().join(" "))
+                .filter(|l| !l.is_empty())
+                .collect::<Vec<String>>()
+                .join("\n");
+            if payload.is_empty() {
+                continue;
+            }
+            cues.push(VttCue {
+                identifier: None,
+                start_ms: seconds_to_ms(w[0]),
+                end_ms: seconds_to_ms(w[1]),
+                settings: settings.clone(),
+                payload,
+            });
+        }
+    }
+
+    // The interval from which the times of a fragment added with offset_ms are computed.
+    fn period_interval(offset_ms: i64) -> Interval {
+        Interval { begin: offset_ms as f64 / 1000.0, end: None }
+    }
+
+    // The offset of the Period containing paragraph p.
+    fn paragraph_offset_ms(&self, p: xot::Node) -> i64 {
+        let body = self.xot.parent(p);
+        self.bodies.iter()
+            .find(|(b, _)| Some(*b) == body)
+            .map_or(0, |(_, offset)| *offset)
+    }
+
+    /// Compute the WebVTT cues corresponding to the paragraphs seen so far, sorted by start time.
+    #[must_use]
+    pub fn cues(&self) -> Vec<VttCue> {
+        let mut cues = Vec::new();
+        for &(body, offset_ms) in &self.bodies {
+            let Some(tt) = self.xot.parent(body) else { continue };
+            let params = self.timing_parameters(tt);
+            let interval = self.child_interval(body, Self::period_interval(offset_ms), None, &params);
+            self.walk_timed(body, interval, &params, &mut |node, interval| {
+                if self.local_name(node).is_some_and(|name| name.eq("p")) {
+                    self.paragraph_cues(node, interval, &params, &mut cues);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        merge_duplicate_cues(&mut cues);
+        cues
+    }
+
+    // Collect the images referenced by smpte:backgroundImage attributes in body, which is part of
+    // the TTML document tt. Images are either embedded in smpte:image elements, referenced as
+    // "#id", or stored in subsamples, referenced as "urn:mpeg:14496-30:subs:N" (where N counts the
+    // image subsamples from 1).
+    fn collect_images(&self, tt: xot::Node, body: xot::Node, subsamples: &[Vec<u8>]) -> Vec<TtmlImage> {
+        let mut images = Vec::new();
+        let params = self.timing_parameters(tt);
+        let interval = self.child_interval(body, Self::period_interval(self.offset_ms), None, &params);
+        self.walk_timed(body, interval, &params, &mut |node, interval| {
+            let Some(src) = self.attribute(node, SMPTE_TT_NS, "backgroundImage") else {
+                return true;
+            };
+            let png = if let Some(id) = src.strip_prefix('#') {
+                self.xot.descendants(tt)
+                    .find(|n| self.xot.element(*n).is_some_and(|e| self.xot.name_ns_str(e.name()) == ("image", SMPTE_TT_NS)) &&
+                          self.xot.get_attribute(*n, self.xot.xml_id_name()) == Some(id))
+                    .and_then(|n| {
+                        let b64: String = self.xot.string_value(n).split_whitespace().collect();
+                        BASE64_STANDARD.decode(b64).ok()
+                    })
+            } else if let Some(n) = src.strip_prefix("urn:mpeg:14496-30:subs:") {
+                n.parse::<usize>().ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|n| subsamples.get(n).cloned())
+            } else {
+                None
+            };
+            match (png, interval.end) {
+                (Some(png), Some(end)) => images.push(TtmlImage {
+                    start_ms: seconds_to_ms(interval.begin),
+                    end_ms: seconds_to_ms(end),
+                    settings: self.cue_settings(node),
+                    png,
+                }),
+                _ => warn!("Unable to resolve TTML image subtitle {src}"),
+            }
+            true
+        });
+        images
+    }
+
+    /// The image subtitles (IMSC1 image profile) seen so far.
+    #[must_use]
+    pub fn images(&self) -> &[TtmlImage] {
+        &self.images
+    }
+
+    /// Convert the subtitles seen so far to WebVTT format.
+    #[must_use]
+    pub fn to_webvtt(&self) -> String {
+        cues_to_webvtt("WEBVTT", &self.cues())
+    }
+
+    /// Convert the subtitles seen so far to SRT format.
+    #[must_use]
+    pub fn to_srt(&self) -> String {
+        cues_to_srt(&self.cues())
+    }
+
     // Generate a complete TTML document corresponding to the merge of all the fragments seen so
     // far. Note that we can't implement this using the fmt::Display trait for StppDocument, because
     // we need a mutable reference to self, which is not available for Display.
@@ -242,9 +843,18 @@ impl StppDocument {
             let new = self.xot.clone_with_prefixes(*r);
             let _ = self.xot.append(layout, new);
         }
-        for p in &self.paragraphs {
-            let new = self.xot.clone_with_prefixes(*p);
-            let _ = self.xot.append(div, new);
+        let begin_name = self.xot.add_name("begin");
+        let mut period_div = (0, div);
+        for p in self.paragraphs.clone() {
+            let offset_ms = self.paragraph_offset_ms(p);
+            if offset_ms != period_div.0 {
+                let d = self.xot.new_element(div_name);
+                self.xot.attributes_mut(d).insert(begin_name, format!("{}s", offset_ms as f64 / 1000.0));
+                let _ = self.xot.append(div, d);
+                period_div = (offset_ms, d);
+            }
+            let new = self.xot.clone_with_prefixes(p);
+            let _ = self.xot.append(period_div.1, new);
         }
         self.xot.create_missing_prefixes(tt).unwrap();
         self.xot.deduplicate_namespaces(tt);
@@ -0,0 +1,83 @@
+//! Support for thumbnail tracks
+//
+// DASH-IF IOP §6.2.6 specifies thumbnail tracks as an AdaptationSet with @contentType="image", whose
+// Representations have an EssentialProperty with @schemeIdUri "http://dashif.org/thumbnail_tile"
+// and a @value such as "10x1", which gives the number of columns and rows of thumbnails in each
+// image. Each segment of the Representation is a single JPEG or PNG image (a tile grid), whose size
+// is given by the @width and @height of the Representation. The thumbnails in a tile are evenly
+// spread over the duration of the segment, in row-major order.
+//
+// We save the tile images and generate a WebVTT thumbnail track, with one cue per thumbnail whose
+// payload is the relative path of the tile image followed by a media fragment identifying the
+// thumbnail within the tile, as in "movie.thumbnails/tile-1-00001.jpg#xywh=256,0,256,144". This is
+// the format used by many web players to display scrub thumbnails.
+//
+// Reference: https://dashif.org/docs/DASH-IF-IOP-v4.3.pdf
+
+
+use crate::vtt::VttCue;
+
+
+/// The file extension to use for images of the given @mimeType.
+#[must_use]
+pub fn image_extension(mime_type: Option<&str>) -> &'static str {
+    match mime_type {
+        Some("image/png") => "png",
+        Some("image/webp") => "webp",
+        Some("image/avif") => "avif",
+        _ => "jpg",
+    }
+}
+
+/// The WebVTT cues for the thumbnails contained in one tile image of `width` x `height` pixels,
+/// split into a grid of `grid.0` columns and `grid.1` rows. The tile covers the time interval of
+/// `duration_ms` milliseconds starting at `start_ms` on the timeline of the output media, and
+/// `image` is the path to the tile image relative to the WebVTT file. If the dimensions of the tile
+/// are unknown, each cue refers to the whole image.
+#[must_use]
+pub fn tile_cues(image: &str, start_ms: u64, duration_ms: u64, grid: (u32, u32), width: Option<u64>, height: Option<u64>) -> Vec<VttCue> {
+    let (cols, rows) = (u64::from(grid.0.max(1)), u64::from(grid.1.max(1)));
+    let count = cols * rows;
+    let (w, h) = match (width, height) {
+        (Some(w), Some(h)) => (Some(w / cols), Some(h / rows)),
+        _ => (None, None),
+    };
+    (0..count)
+        .map(|i| {
+            let payload = match (w, h) {
+                (Some(w), Some(h)) => format!("{image}#xywh={},{},{w},{h}", (i % cols) * w, (i / cols) * h),
+                _ => String::from(image),
+            };
+            VttCue {
+                identifier: None,
+                start_ms: start_ms + duration_ms * i / count,
+                end_ms: start_ms + duration_ms * (i + 1) / count,
+                settings: None,
+                payload,
+            }
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{tile_cues, image_extension};
+
+    #[test]
+    fn test_tile_cues() {
+        let cues = tile_cues("thumbs/tile-1-00002.jpg", 10_000, 10_000, (2, 2), Some(512), Some(288));
+        let payloads: Vec<&str> = cues.iter().map(|c| c.payload.as_str()).collect();
+        assert_eq!(payloads, vec!["thumbs/tile-1-00002.jpg#xywh=0,0,256,144",
+                                  "thumbs/tile-1-00002.jpg#xywh=256,0,256,144",
+                                  "thumbs/tile-1-00002.jpg#xywh=0,144,256,144",
+                                  "thumbs/tile-1-00002.jpg#xywh=256,144,256,144"]);
+        let times: Vec<(u64, u64)> = cues.iter().map(|c| (c.start_ms, c.end_ms)).collect();
+        assert_eq!(times, vec![(10_000, 12_500), (12_500, 15_000), (15_000, 17_500), (17_500, 20_000)]);
+        let cues = tile_cues("t.png", 0, 3000, (1, 1), None, Some(100));
+        assert_eq!(cues.len(), 1);
+        assert_eq!(cues[0].payload, "t.png");
+        assert_eq!(image_extension(Some("image/png")), "png");
+        assert_eq!(image_extension(None), "jpg");
+    }
+}
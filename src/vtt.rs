@@ -1,17 +1,25 @@
 //! Support for the VTT subtitle format
 //
-// This module provides support for VTT subtitles that are distributed in fragmented MP4 segments.
-// These subtitles are provided as a separate media stream of fMP4 segments, that the media player
-// retrieves incrementally.
+// This module provides support for WebVTT subtitles that are distributed as a sequence of
+// segments. These subtitles are provided as a separate media stream of segments, each of which is
+// a small standalone WebVTT document, that the media player retrieves incrementally.
 //
 // This module implements:
 //
-//  - extracting the VTT fragments from an MP4 fragment
+//  - parsing each segment into its header, STYLE and REGION blocks and cues
 //
-//  - appending them to the VttDocument object
+//  - adjusting cue times according to any X-TIMESTAMP-MAP header in the segment (as used when the
+//    same segments are shared with HLS streams) and to the start of the Period in the output media
 //
-//  - serializing to a single merged VTT subtitle file
+//  - merging them into the VttDocument object, dropping cues that are repeated across segments
+//    and joining cues that were split at a segment boundary
 //
+//  - serializing to a single merged VTT subtitle file, or to SRT format
+//
+// It also provides a simple model of WebVTT cues, shared with the wvtt module, that can be
+// serialized to WebVTT or to SRT format.
+//
+// Reference: https://www.w3.org/TR/webvtt1/
 
 use tracing::{trace, warn};
 use bytes::Bytes;
@@ -20,7 +28,13 @@ use crate::DashMpdError;
 
 #[derive(Clone, Debug)]
 pub struct VttDocument {
-    contents: Vec<String>,
+    // Header lines following the WEBVTT line in the first segment (excluding X-TIMESTAMP-MAP).
+    header: Vec<String>,
+    // STYLE and REGION blocks, without duplicates.
+    blocks: Vec<String>,
+    cues: Vec<VttCue>,
+    // Offset in milliseconds added to the time of cues in segments added subsequently.
+    offset_ms: i64,
     warned_binary_contents: bool,
 }
 
@@ -30,15 +44,65 @@ impl Default for VttDocument {
     }
 }
 
+// Parse a WebVTT timestamp in the format [HH:]MM:SS.mmm into milliseconds.
+fn parse_vtt_timestamp(ts: &str) -> Option<u64> {
+    let (hms, frac) = ts.trim().split_once('.')?;
+    if frac.len() != 3 {
+        return None;
+    }
+    let ms: u64 = frac.parse().ok()?;
+    let parts: Vec<u64> = hms.split(':')
+        .map(|p| p.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    let secs = match parts.as_slice() {
+        [m, s] if *s < 60 && *m < 60 => m * 60 + s,
+        [h, m, s] if *s < 60 && *m < 60 => h * 3600 + m * 60 + s,
+        _ => return None,
+    };
+    Some(secs * 1000 + ms)
+}
+
+// Parse the value of an X-TIMESTAMP-MAP header, such as "MPEGTS:900000,LOCAL:00:00:00.000",
+// returning the offset in milliseconds to add to cue times in the segment. MPEGTS values are
+// expressed in the 90kHz clock of an MPEG-2 transport stream.
+fn parse_timestamp_map(value: &str) -> Option<i64> {
+    let mut mpegts: Option<i64> = None;
+    let mut local: Option<i64> = None;
+    for part in value.split(',') {
+        if let Some(ts) = part.trim().strip_prefix("MPEGTS:") {
+            mpegts = ts.trim().parse().ok();
+        } else if let Some(ts) = part.trim().strip_prefix("LOCAL:") {
+            local = parse_vtt_timestamp(ts).and_then(|l| i64::try_from(l).ok());
+        }
+    }
+    Some(mpegts? * 1000 / 90000 - local?)
+}
+
 impl VttDocument {
     #[must_use]
     pub fn new() -> VttDocument {
         VttDocument {
-            contents: Vec::new(),
+            header: Vec::new(),
+            blocks: Vec::new(),
+            cues: Vec::new(),
+            offset_ms: 0,
             warned_binary_contents: false,
         }
     }
 
+    /// Set an offset (in milliseconds) that is added to the times of cues in segments added after
+    /// this call. This is used to shift cues from the timeline of a Period to the timeline of the
+    /// output media, when several Periods are concatenated.
+    pub fn set_offset_ms(&mut self, offset_ms: i64) {
+        self.offset_ms = offset_ms;
+    }
+
+    /// The cues that have been added so far, in the order in which they were added.
+    #[must_use]
+    pub fn cues(&self) -> &[VttCue] {
+        &self.cues
+    }
+
     // Extract VTT content from the binary data in bytes.
     pub fn add_bytes(&mut self, bytes: &Bytes) -> Result<(), DashMpdError> {
         if let Ok(s) = str::from_utf8(bytes) {
@@ -52,18 +116,250 @@ impl VttDocument {
         Ok(())
     }
 
+    // Parse a WebVTT segment and merge its contents into the document.
     pub fn add_content(&mut self, content: &str) -> Result<(), DashMpdError> {
         trace!("adding VTT content {content}");
-        self.contents.push(content.to_string());
+        let content = content.trim_start_matches('\u{feff}')
+            .replace("\r\n", "\n")
+            .replace('\r', "\n");
+        let mut segment_offset = 0;
+        let mut blocks = content.split("\n\n")
+            .map(|b| b.trim_matches('\n'))
+            .filter(|b| !b.is_empty())
+            .peekable();
+        if let Some(first) = blocks.peek() {
+            if first.starts_with("WEBVTT") {
+                let is_first_segment = self.header.is_empty() && self.cues.is_empty();
+                for line in first.lines().skip(1) {
+                    if let Some(map) = line.strip_prefix("X-TIMESTAMP-MAP=") {
+                        segment_offset = parse_timestamp_map(map)
+                            .ok_or_else(|| DashMpdError::Parsing(
+                                format!("invalid X-TIMESTAMP-MAP in WebVTT segment: {map}")))?;
+                    } else if is_first_segment && !line.trim().is_empty() {
+                        self.header.push(line.to_string());
+                    }
+                }
+                blocks.next();
+            }
+        }
+        let offset = segment_offset + self.offset_ms;
+        for block in blocks {
+            if block.starts_with("NOTE") {
+                continue;
+            }
+            if (block.starts_with("STYLE") || block.starts_with("REGION")) && !block.contains("-->") {
+                if !self.blocks.iter().any(|b| b.eq(block)) {
+                    self.blocks.push(block.to_string());
+                }
+                continue;
+            }
+            let mut lines = block.lines();
+            let mut identifier = None;
+            let mut timing = lines.next().unwrap_or_default();
+            if !timing.contains("-->") {
+                identifier = Some(timing.to_string());
+                timing = lines.next().unwrap_or_default();
+            }
+            let Some((start, rest)) = timing.split_once("-->") else {
+                warn!("Ignoring malformed WebVTT cue: {block}");
+                continue;
+            };
+            let rest = rest.trim_start();
+            let (end, settings) = match rest.split_once([' ', '\t']) {
+                Some((end, settings)) => (end, Some(settings.trim().to_string()).filter(|s| !s.is_empty())),
+                None => (rest, None),
+            };
+            let (Some(start), Some(end)) = (parse_vtt_timestamp(start), parse_vtt_timestamp(end)) else {
+                warn!("Ignoring WebVTT cue with malformed timing: {timing}");
+                continue;
+            };
+            let shift = |t: u64| u64::try_from(t as i64 + offset).unwrap_or(0);
+            let cue = VttCue {
+                identifier,
+                start_ms: shift(start),
+                end_ms: shift(end),
+                settings,
+                payload: lines.collect::<Vec<&str>>().join("\n"),
+            };
+            merge_cue(&mut self.cues, cue);
+        }
         Ok(())
     }
 
+    fn header_text(&self) -> String {
+        let mut header = String::from("WEBVTT");
+        for line in &self.header {
+            header.push('\n');
+            header.push_str(line);
+        }
+        for block in &self.blocks {
+            header.push_str("\n\n");
+            header.push_str(block);
+        }
+        header
+    }
+
+    fn sorted_cues(&self) -> Vec<VttCue> {
+        let mut cues = self.cues.clone();
+        cues.sort_by_key(|c| (c.start_ms, c.end_ms));
+        cues
+    }
+
     // Generate a complete VTT document corresponding to the merge of all the fragments seen so
     // far. Note that we can't implement this using the fmt::Display trait for VttDocument, because
     // we need a mutable reference to self, which is not available for Display.
     #[allow(clippy::inherent_to_string)]
     pub fn to_string(&mut self) -> String {
-        self.contents.concat()
+        cues_to_webvtt(&self.header_text(), &self.sorted_cues())
+    }
+
+    /// Generate an SRT document containing all the cues seen so far.
+    pub fn to_srt(&mut self) -> String {
+        cues_to_srt(&self.sorted_cues())
     }
 }
 
+
+
+/// A single WebVTT cue. Times are expressed in milliseconds.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VttCue {
+    pub identifier: Option<String>,
+    pub start_ms: u64,
+    pub end_ms: u64,
+    /// Cue settings such as `line:90% align:center`, in WebVTT syntax.
+    pub settings: Option<String>,
+    pub payload: String,
+}
+
+fn split_ms(ms: u64) -> (u64, u64, u64, u64) {
+    (ms / 3_600_000, (ms / 60_000) % 60, (ms / 1000) % 60, ms % 1000)
+}
+
+/// Format a time in milliseconds as a WebVTT timestamp (HH:MM:SS.mmm).
+#[must_use]
+pub fn format_vtt_timestamp(ms: u64) -> String {
+    let (h, m, s, ms) = split_ms(ms);
+    format!("{h:02}:{m:02}:{s:02}.{ms:03}")
+}
+
+/// Format a time in milliseconds as an SRT timestamp (HH:MM:SS,mmm).
+#[must_use]
+pub fn format_srt_timestamp(ms: u64) -> String {
+    let (h, m, s, ms) = split_ms(ms);
+    format!("{h:02}:{m:02}:{s:02},{ms:03}")
+}
+
+/// Serialize a list of cues as a WebVTT document. `header` is the content of the header block (the
+/// first line of which should be "WEBVTT"), which may include STYLE and REGION blocks.
+#[must_use]
+pub fn cues_to_webvtt(header: &str, cues: &[VttCue]) -> String {
+    let mut out = String::new();
+    let header = header.trim_end();
+    if header.starts_with("WEBVTT") {
+        out.push_str(header);
+    } else {
+        out.push_str("WEBVTT");
+    }
+    out.push_str("\n\n");
+    for cue in cues {
+        if let Some(id) = &cue.identifier {
+            out.push_str(id);
+            out.push('\n');
+        }
+        out.push_str(&format_vtt_timestamp(cue.start_ms));
+        out.push_str(" --> ");
+        out.push_str(&format_vtt_timestamp(cue.end_ms));
+        if let Some(settings) = &cue.settings {
+            if !settings.trim().is_empty() {
+                out.push(' ');
+                out.push_str(settings.trim());
+            }
+        }
+        out.push('\n');
+        out.push_str(cue.payload.trim_end());
+        out.push_str("\n\n");
+    }
+    out
+}
+
+// SRT only supports a few basic formatting tags. We keep <b>, <i> and <u> and strip WebVTT-specific
+// markup such as voice spans, class spans, ruby text and karaoke timestamps.
+fn vtt_payload_to_srt(payload: &str) -> String {
+    let mut out = String::new();
+    let mut rest = payload.trim_end();
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        match rest[start..].find('>') {
+            Some(len) => {
+                let tag = &rest[start+1..start+len];
+                let name = tag.trim_start_matches('/')
+                    .split(['.', ' ', '\t'])
+                    .next()
+                    .unwrap_or("");
+                if matches!(name, "b" | "i" | "u") {
+                    let closing = if tag.starts_with('/') { "/" } else { "" };
+                    out.push_str(&format!("<{closing}{name}>"));
+                }
+                rest = &rest[start+len+1..];
+            },
+            None => {
+                rest = &rest[start..];
+                break;
+            },
+        }
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", "\u{a0}")
+        .replace("&lrm;", "\u{200e}")
+        .replace("&rlm;", "\u{200f}")
+        .replace("&amp;", "&")
+}
+
+/// Serialize a list of cues in SubRip (SRT) format. Cue settings, which have no SRT equivalent, are
+/// dropped.
+#[must_use]
+pub fn cues_to_srt(cues: &[VttCue]) -> String {
+    let mut out = String::new();
+    for (i, cue) in cues.iter().enumerate() {
+        out.push_str(&format!("{}\n{} --> {}\n{}\n\n",
+                              i + 1,
+                              format_srt_timestamp(cue.start_ms),
+                              format_srt_timestamp(cue.end_ms),
+                              vtt_payload_to_srt(&cue.payload)));
+    }
+    out
+}
+
+// Add cue to cues. Segmenters typically repeat a cue that spans a segment boundary in both
+// segments, either with its full timing or with its timing clipped to the segment boundaries. We
+// drop exact duplicates and join cues with the same content whose times overlap or touch.
+fn merge_cue(cues: &mut Vec<VttCue>, cue: VttCue) {
+    let same = cues.iter_mut()
+        .rev()
+        .take(64)
+        .find(|c| c.payload == cue.payload &&
+              c.settings == cue.settings &&
+              c.start_ms <= cue.end_ms &&
+              cue.start_ms <= c.end_ms);
+    match same {
+        Some(c) => {
+            c.start_ms = c.start_ms.min(cue.start_ms);
+            c.end_ms = c.end_ms.max(cue.end_ms);
+        },
+        None => cues.push(cue),
+    }
+}
+
+// Sort cues by start time, and join cues with identical content and settings whose times overlap
+// or touch, as in merge_cue.
+pub(crate) fn merge_duplicate_cues(cues: &mut Vec<VttCue>) {
+    cues.sort_by_key(|c| (c.start_ms, c.end_ms));
+    let mut merged: Vec<VttCue> = Vec::with_capacity(cues.len());
+    for cue in cues.drain(..) {
+        merge_cue(&mut merged, cue);
+    }
+    *cues = merged;
+}
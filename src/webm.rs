@@ -0,0 +1,917 @@
+//! Native muxing and concatenation of WebM/Matroska streams, without an external application.
+//
+// WebM DASH streams deliver each AdaptationSet as a separate Matroska Segment, with the EBML header,
+// Info and Tracks elements in the initialization segment and a sequence of Clusters in the media
+// segments. Muxing such streams doesn't require any knowledge of the codecs: we merge the TrackEntry
+// elements of each input, renumbering the tracks, and interleave the blocks of the different
+// inputs in timestamp order into new Clusters, rewriting the track number and relative timestamp
+// in each block. The output Segment starts with a SeekHead and Info element (written once the
+// duration is known, in space reserved at the start of the Segment), and ends with Cues that
+// reference the Clusters starting with a video keyframe.
+//
+// Concatenating the media files produced for successive Periods works in the same way, with the
+// timestamps of each Period rebased to follow on from the end of the previous Period.
+//
+// Element parsing and serialization uses the webm-iterable crate. Inputs are read one Cluster at a
+// time, so that large files are not loaded into memory.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use webm_iterable::{WebmIterator, WebmWriter, WriteOptions};
+use webm_iterable::iterator::AllowableErrors;
+use webm_iterable::matroska_spec::{MatroskaSpec, Master, EbmlTag};
+use tracing::{info, trace, warn};
+use crate::DashMpdError;
+use crate::fetch::DashDownloader;
+use crate::media::{AudioTrack, run_blocking};
+use crate::language::iso639_2b;
+
+
+const INFO_ID: [u8; 4] = [0x15, 0x49, 0xA9, 0x66];
+const TRACKS_ID: [u8; 4] = [0x16, 0x54, 0xAE, 0x6B];
+const CUES_ID: [u8; 4] = [0x1C, 0x53, 0xBB, 0x6B];
+// The Segment ID followed by an 8-octet size field, which is filled in once the output is complete.
+const SEGMENT_HEADER: [u8; 12] = [0x18, 0x53, 0x80, 0x67, 0x01, 0, 0, 0, 0, 0, 0, 0];
+// Space reserved at the start of the Segment for the SeekHead and Info elements.
+const RESERVED_SIZE: usize = 256;
+// The default TimestampScale of 1ms.
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+// The maximum duration of a Cluster in audio-only output, in nanoseconds.
+const MAX_CLUSTER_DURATION: i64 = 5_000_000_000;
+// Codecs allowed in the WebM profile of Matroska.
+const WEBM_CODECS: [&str; 6] = ["V_VP8", "V_VP9", "V_AV1", "A_OPUS", "A_VORBIS", "D_WEBVTT"];
+
+fn mux_error(msg: &str) -> DashMpdError {
+    DashMpdError::Muxing(format!("native WebM muxer: {msg}"))
+}
+
+// Serialize a single element. The writer checks that elements are written at a valid position in
+// the Matroska hierarchy, so children of the Segment element are written inside an unknown-sized
+// Segment whose header (4 octets of ID and 8 octets of size) is then removed.
+fn encode_element(tag: &MatroskaSpec, size_length: Option<usize>) -> Result<Vec<u8>, DashMpdError> {
+    let mut writer = WebmWriter::new(Vec::new());
+    let top_level = matches!(tag, MatroskaSpec::Ebml(_));
+    if !top_level {
+        writer.write_advanced(&MatroskaSpec::Segment(Master::Start), WriteOptions::is_unknown_sized_element())
+            .map_err(|e| mux_error(&format!("encoding element: {e}")))?;
+    }
+    let written = match size_length {
+        Some(len) => writer.write_advanced(tag, WriteOptions::set_size_byte_count(len)),
+        None => writer.write(tag),
+    };
+    written.map_err(|e| mux_error(&format!("encoding element: {e}")))?;
+    let mut bytes = writer.into_inner()
+        .map_err(|e| mux_error(&format!("encoding element: {e}")))?;
+    if !top_level {
+        bytes.drain(..SEGMENT_HEADER.len());
+    }
+    Ok(bytes)
+}
+
+// A Void element occupying exactly len octets (len must be at least 2).
+fn void_element(len: usize) -> Vec<u8> {
+    let mut v = vec![0xEC];
+    if len - 2 < 127 {
+        v.push(0x80 | (len - 2) as u8);
+    } else {
+        let n = len - 3;
+        v.push(0x40 | (n >> 8) as u8);
+        v.push((n & 0xFF) as u8);
+    }
+    v.resize(len, 0);
+    v
+}
+
+// Parse the track number (an EBML variable-size integer) and the relative timestamp at the start
+// of a SimpleBlock or Block. Returns the track number, the offset of the timestamp (the length of
+// the track number field) and the timestamp relative to the Cluster timestamp.
+fn parse_block_header(data: &[u8]) -> Option<(u64, usize, i16)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || data.len() < len + 3 {
+        return None;
+    }
+    let mut track = u64::from(first) & (0xFF >> len);
+    for b in &data[1..len] {
+        track = (track << 8) | u64::from(*b);
+    }
+    let rel = i16::from_be_bytes([data[len], data[len + 1]]);
+    Some((track, len, rel))
+}
+
+// Rewrite the track number and relative timestamp of a SimpleBlock or Block.
+fn rewrite_block(data: &[u8], track: u64, rel: i16) -> Option<Vec<u8>> {
+    let (_, len, _) = parse_block_header(data)?;
+    if track > 126 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len() + 1);
+    out.push(0x80 | track as u8);
+    out.extend_from_slice(&rel.to_be_bytes());
+    out.extend_from_slice(&data[len + 2..]);
+    Some(out)
+}
+
+fn child_unsigned(children: &[MatroskaSpec], pred: fn(&MatroskaSpec) -> bool) -> Option<u64> {
+    children.iter().find(|c| pred(c)).and_then(|c| c.as_unsigned_int()).copied()
+}
+
+fn track_number(entry: &[MatroskaSpec]) -> Option<u64> {
+    child_unsigned(entry, |c| matches!(c, MatroskaSpec::TrackNumber(_)))
+}
+
+fn track_type(entry: &[MatroskaSpec]) -> Option<u64> {
+    child_unsigned(entry, |c| matches!(c, MatroskaSpec::TrackType(_)))
+}
+
+fn track_codec(entry: &[MatroskaSpec]) -> Option<&str> {
+    entry.iter().find_map(|c| match c {
+        MatroskaSpec::CodecID(id) => Some(id.as_str()),
+        _ => None,
+    })
+}
+
+fn track_codec_private(entry: &[MatroskaSpec]) -> Option<&[u8]> {
+    entry.iter().find_map(|c| match c {
+        MatroskaSpec::CodecPrivate(p) => Some(p.as_slice()),
+        _ => None,
+    })
+}
+
+// The DefaultDuration of a track, in nanoseconds.
+fn track_default_duration(entry: &[MatroskaSpec]) -> Option<u64> {
+    child_unsigned(entry, |c| matches!(c, MatroskaSpec::DefaultDuration(_)))
+}
+
+// Matroska TrackType value for video tracks.
+fn is_video_track(entry: &[MatroskaSpec]) -> bool {
+    track_type(entry) == Some(1)
+}
+
+#[derive(Debug)]
+enum BlockContent {
+    Simple(Vec<u8>),
+    Group(Vec<MatroskaSpec>),
+}
+
+#[derive(Debug)]
+struct InputBlock {
+    // Index of the track in the input's Tracks element.
+    track: usize,
+    // Absolute timestamp, in nanoseconds.
+    timestamp: i64,
+    // Block duration (from a BlockGroup), in nanoseconds.
+    duration: Option<i64>,
+    keyframe: bool,
+    content: BlockContent,
+}
+
+// A WebM/Matroska input file, read one Cluster at a time.
+struct WebmInput {
+    iter: WebmIterator<BufReader<File>>,
+    timestamp_scale: u64,
+    // Duration from the Info element, in nanoseconds.
+    duration: Option<i64>,
+    // The children of each TrackEntry element.
+    tracks: Vec<Vec<MatroskaSpec>>,
+    track_numbers: Vec<u64>,
+    pending: VecDeque<InputBlock>,
+    finished: bool,
+}
+
+impl WebmInput {
+    fn open(path: &Path) -> Result<WebmInput, DashMpdError> {
+        let file = File::open(path)
+            .map_err(|e| DashMpdError::Io(e, String::from("opening WebM input")))?;
+        let buffered = [
+            MatroskaSpec::Info(Master::Start),
+            MatroskaSpec::Tracks(Master::Start),
+            MatroskaSpec::Cluster(Master::Start),
+        ];
+        let mut iter = WebmIterator::new(BufReader::new(file), &buffered);
+        // The media segments appended to a WebM initialization segment may extend beyond the size
+        // declared for the Segment element.
+        iter.allow_errors(&[AllowableErrors::HierarchyProblems, AllowableErrors::OversizedTags]);
+        let mut input = WebmInput {
+            iter,
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+            duration: None,
+            tracks: Vec::new(),
+            track_numbers: Vec::new(),
+            pending: VecDeque::new(),
+            finished: false,
+        };
+        let mut info_duration = None;
+        while input.tracks.is_empty() {
+            match input.iter.next() {
+                None => break,
+                Some(Err(e)) => return Err(mux_error(&format!("reading {}: {e}", path.display()))),
+                Some(Ok(MatroskaSpec::Info(Master::Full(children)))) => {
+                    for c in &children {
+                        match c {
+                            MatroskaSpec::TimestampScale(ts) if *ts > 0 => input.timestamp_scale = *ts,
+                            MatroskaSpec::Duration(d) => info_duration = Some(*d),
+                            _ => (),
+                        }
+                    }
+                },
+                Some(Ok(MatroskaSpec::Tracks(Master::Full(children)))) => {
+                    for c in children {
+                        if let MatroskaSpec::TrackEntry(Master::Full(entry)) = c {
+                            let number = track_number(&entry)
+                                .ok_or_else(|| mux_error("TrackEntry without TrackNumber"))?;
+                            input.track_numbers.push(number);
+                            input.tracks.push(entry);
+                        }
+                    }
+                },
+                Some(Ok(MatroskaSpec::Cluster(_))) => {
+                    return Err(mux_error(&format!("Cluster before Tracks in {}", path.display())));
+                },
+                Some(Ok(_)) => (),
+            }
+        }
+        if input.tracks.is_empty() {
+            return Err(mux_error(&format!("no tracks in {}", path.display())));
+        }
+        input.duration = info_duration.map(|d| (d * input.timestamp_scale as f64) as i64);
+        Ok(input)
+    }
+
+    fn expand_cluster(&mut self, children: Vec<MatroskaSpec>) {
+        let scale = self.timestamp_scale as i64;
+        let cluster_ts = child_unsigned(&children, |c| matches!(c, MatroskaSpec::Timestamp(_)))
+            .unwrap_or(0) as i64;
+        for c in children {
+            let (data, keyframe, duration, content) = match c {
+                MatroskaSpec::SimpleBlock(data) => {
+                    let keyframe = data.get(parse_block_header(&data).map_or(0, |(_, len, _)| len + 2))
+                        .is_some_and(|flags| flags & 0x80 != 0);
+                    (data.clone(), keyframe, None, BlockContent::Simple(data))
+                },
+                MatroskaSpec::BlockGroup(Master::Full(group)) => {
+                    let Some(data) = group.iter().find_map(|g| match g {
+                        MatroskaSpec::Block(d) => Some(d.clone()),
+                        _ => None,
+                    }) else {
+                        continue;
+                    };
+                    let keyframe = !group.iter().any(|g| matches!(g, MatroskaSpec::ReferenceBlock(_)));
+                    let duration = child_unsigned(&group, |g| matches!(g, MatroskaSpec::BlockDuration(_)))
+                        .map(|d| d as i64 * scale);
+                    (data, keyframe, duration, BlockContent::Group(group))
+                },
+                _ => continue,
+            };
+            let Some((number, _, rel)) = parse_block_header(&data) else {
+                warn!("  Ignoring invalid WebM block");
+                continue;
+            };
+            let Some(track) = self.track_numbers.iter().position(|n| *n == number) else {
+                warn!("  Ignoring WebM block for unknown track {number}");
+                continue;
+            };
+            self.pending.push_back(InputBlock {
+                track,
+                timestamp: (cluster_ts + i64::from(rel)) * scale,
+                duration,
+                keyframe,
+                content,
+            });
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), DashMpdError> {
+        while self.pending.is_empty() && !self.finished {
+            match self.iter.next() {
+                None => self.finished = true,
+                Some(Err(e)) => return Err(mux_error(&format!("reading input: {e}"))),
+                Some(Ok(MatroskaSpec::Cluster(Master::Full(children)))) => self.expand_cluster(children),
+                Some(Ok(_)) => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn peek_timestamp(&mut self) -> Result<Option<i64>, DashMpdError> {
+        self.fill()?;
+        Ok(self.pending.front().map(|b| b.timestamp))
+    }
+
+    // The earliest timestamp in the first Cluster containing blocks.
+    fn start_timestamp(&mut self) -> Result<i64, DashMpdError> {
+        self.fill()?;
+        Ok(self.pending.iter().map(|b| b.timestamp).min().unwrap_or(0))
+    }
+
+    fn next_block(&mut self) -> Result<Option<InputBlock>, DashMpdError> {
+        self.fill()?;
+        Ok(self.pending.pop_front())
+    }
+}
+
+// Tracks the end time of each output track, to determine the duration of the output.
+#[derive(Debug, Default, Clone)]
+struct TrackEnd {
+    last_timestamp: Option<i64>,
+    last_delta: i64,
+    end: i64,
+}
+
+impl TrackEnd {
+    fn update(&mut self, timestamp: i64, duration: Option<i64>, default_duration: Option<u64>) {
+        if let Some(last) = self.last_timestamp {
+            if timestamp > last {
+                self.last_delta = timestamp - last;
+            }
+        }
+        self.last_timestamp = Some(timestamp);
+        let duration = duration
+            .or(default_duration.map(|d| d as i64))
+            .unwrap_or(self.last_delta);
+        self.end = self.end.max(timestamp + duration);
+    }
+}
+
+struct Cluster {
+    // Timestamp in output TimestampScale units.
+    timestamp: i64,
+    start: i64,
+    blocks: Vec<MatroskaSpec>,
+}
+
+// The output Matroska file.
+struct WebmOutput {
+    out: BufWriter<File>,
+    timestamp_scale: u64,
+    segment_data_start: u64,
+    // Current position relative to the start of the Segment data.
+    position: u64,
+    tracks_position: u64,
+    has_video: bool,
+    cluster: Option<Cluster>,
+    cues: Vec<MatroskaSpec>,
+}
+
+impl WebmOutput {
+    fn create(
+        path: &Path,
+        doc_type: &str,
+        timestamp_scale: u64,
+        tracks: Vec<Vec<MatroskaSpec>>) -> Result<WebmOutput, DashMpdError>
+    {
+        let file = File::create(path)
+            .map_err(|e| DashMpdError::Io(e, String::from("creating WebM output")))?;
+        let out = BufWriter::new(file);
+        let has_video = tracks.iter().any(|t| is_video_track(t));
+        let ebml = MatroskaSpec::Ebml(Master::Full(vec![
+            MatroskaSpec::EbmlVersion(1),
+            MatroskaSpec::EbmlReadVersion(1),
+            MatroskaSpec::EbmlMaxIdLength(4),
+            MatroskaSpec::EbmlMaxSizeLength(8),
+            MatroskaSpec::DocType(String::from(doc_type)),
+            MatroskaSpec::DocTypeVersion(4),
+            MatroskaSpec::DocTypeReadVersion(2),
+        ]));
+        let ebml = encode_element(&ebml, None)?;
+        let tracks = encode_element(&MatroskaSpec::Tracks(Master::Full(
+            tracks.into_iter().map(|t| MatroskaSpec::TrackEntry(Master::Full(t))).collect())), None)?;
+        let mut output = WebmOutput {
+            out,
+            timestamp_scale,
+            segment_data_start: (ebml.len() + SEGMENT_HEADER.len()) as u64,
+            position: 0,
+            tracks_position: RESERVED_SIZE as u64,
+            has_video,
+            cluster: None,
+            cues: Vec::new(),
+        };
+        output.write_raw(&ebml)?;
+        output.write_raw(&SEGMENT_HEADER)?;
+        output.position = 0;
+        output.write_raw(&void_element(RESERVED_SIZE))?;
+        output.write_raw(&tracks)?;
+        Ok(output)
+    }
+
+    fn write_raw(&mut self, buf: &[u8]) -> Result<(), DashMpdError> {
+        self.out.write_all(buf)
+            .map_err(|e| DashMpdError::Io(e, String::from("writing WebM output")))?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush_cluster(&mut self) -> Result<(), DashMpdError> {
+        if let Some(cluster) = self.cluster.take() {
+            let mut children = vec![MatroskaSpec::Timestamp(cluster.timestamp as u64)];
+            children.extend(cluster.blocks);
+            let bytes = encode_element(&MatroskaSpec::Cluster(Master::Full(children)), None)?;
+            self.write_raw(&bytes)?;
+        }
+        Ok(())
+    }
+
+    // Add a block for output track number track, with timestamp in nanoseconds relative to the
+    // start of the output.
+    fn push(
+        &mut self,
+        track: u64,
+        is_video: bool,
+        timestamp: i64,
+        keyframe: bool,
+        content: BlockContent,
+        input_scale: u64) -> Result<(), DashMpdError>
+    {
+        let scale = self.timestamp_scale as i64;
+        let ts = timestamp.max(0) / scale;
+        let new_cluster = match &self.cluster {
+            None => true,
+            Some(c) => {
+                let rel = ts - c.timestamp;
+                !c.blocks.is_empty() && (
+                    (self.has_video && is_video && keyframe) ||
+                    (!self.has_video && timestamp - c.start >= MAX_CLUSTER_DURATION) ||
+                    rel < i64::from(i16::MIN) || rel > i64::from(i16::MAX))
+            },
+        };
+        if new_cluster {
+            self.flush_cluster()?;
+            if !self.has_video || (is_video && keyframe) {
+                self.cues.push(MatroskaSpec::CuePoint(Master::Full(vec![
+                    MatroskaSpec::CueTime(ts as u64),
+                    MatroskaSpec::CueTrackPositions(Master::Full(vec![
+                        MatroskaSpec::CueTrack(track),
+                        MatroskaSpec::CueClusterPosition(self.position),
+                    ])),
+                ])));
+            }
+            self.cluster = Some(Cluster { timestamp: ts, start: timestamp, blocks: Vec::new() });
+        }
+        let cluster = self.cluster.as_mut()
+            .ok_or_else(|| mux_error("no open Cluster"))?;
+        let rel = (ts - cluster.timestamp) as i16;
+        let block = match content {
+            BlockContent::Simple(data) => {
+                let data = rewrite_block(&data, track, rel)
+                    .ok_or_else(|| mux_error("invalid SimpleBlock"))?;
+                MatroskaSpec::SimpleBlock(data)
+            },
+            BlockContent::Group(group) => {
+                let mut children = Vec::new();
+                for g in group {
+                    children.push(match g {
+                        MatroskaSpec::Block(data) => MatroskaSpec::Block(
+                            rewrite_block(&data, track, rel).ok_or_else(|| mux_error("invalid Block"))?),
+                        // These values are expressed in units of the TimestampScale.
+                        MatroskaSpec::BlockDuration(d) =>
+                            MatroskaSpec::BlockDuration(d * input_scale / self.timestamp_scale),
+                        MatroskaSpec::ReferenceBlock(r) =>
+                            MatroskaSpec::ReferenceBlock(r * input_scale as i64 / scale),
+                        other => other,
+                    });
+                }
+                MatroskaSpec::BlockGroup(Master::Full(children))
+            },
+        };
+        cluster.blocks.push(block);
+        Ok(())
+    }
+
+    // Write the Cues, then the SeekHead and Info elements in the space reserved at the start of the
+    // Segment, and the final size of the Segment.
+    fn finish(mut self, duration: i64) -> Result<(), DashMpdError> {
+        self.flush_cluster()?;
+        let cues_position = self.position;
+        let have_cues = !self.cues.is_empty();
+        if have_cues {
+            let cues = std::mem::take(&mut self.cues);
+            let bytes = encode_element(&MatroskaSpec::Cues(Master::Full(cues)), None)?;
+            self.write_raw(&bytes)?;
+        }
+        let segment_size = self.position;
+        let info = MatroskaSpec::Info(Master::Full(vec![
+            MatroskaSpec::TimestampScale(self.timestamp_scale),
+            MatroskaSpec::MuxingApp(String::from("dash-mpd-rs")),
+            MatroskaSpec::WritingApp(String::from("dash-mpd-rs")),
+            MatroskaSpec::Duration(duration as f64 / self.timestamp_scale as f64),
+        ]));
+        let seek = |id: &[u8], pos: u64| MatroskaSpec::Seek(Master::Full(vec![
+            MatroskaSpec::SeekID(id.to_vec()),
+            MatroskaSpec::SeekPosition(pos),
+        ]));
+        // The position of the Info element depends on the size of the SeekHead that precedes it.
+        let mut info_position = 0;
+        let mut seekhead;
+        loop {
+            let mut seeks = vec![seek(&INFO_ID, info_position), seek(&TRACKS_ID, self.tracks_position)];
+            if have_cues {
+                seeks.push(seek(&CUES_ID, cues_position));
+            }
+            seekhead = encode_element(&MatroskaSpec::SeekHead(Master::Full(seeks)), None)?;
+            if seekhead.len() as u64 == info_position {
+                break;
+            }
+            info_position = seekhead.len() as u64;
+        }
+        let mut info_bytes = encode_element(&info, None)?;
+        // A Void element occupies at least two octets, so use a longer size field for the Info
+        // element if only one octet would remain.
+        if seekhead.len() + info_bytes.len() + 1 == RESERVED_SIZE {
+            info_bytes = encode_element(&info, Some(2))?;
+        }
+        let used = seekhead.len() + info_bytes.len();
+        if used > RESERVED_SIZE {
+            return Err(mux_error("SeekHead and Info elements are too large"));
+        }
+        let mut header = seekhead;
+        header.extend(info_bytes);
+        if used < RESERVED_SIZE {
+            header.extend(void_element(RESERVED_SIZE - used));
+        }
+        let mut size_field = vec![0x01];
+        size_field.extend_from_slice(&segment_size.to_be_bytes()[1..]);
+        let segment_data_start = self.segment_data_start;
+        let mut file = self.out.into_inner()
+            .map_err(|e| DashMpdError::Io(e.into_error(), String::from("writing WebM output")))?;
+        file.seek(SeekFrom::Start(segment_data_start))
+            .and_then(|_| file.write_all(&header))
+            .and_then(|_| file.seek(SeekFrom::Start(segment_data_start - 8)))
+            .and_then(|_| file.write_all(&size_field))
+            .and_then(|_| file.flush())
+            .map_err(|e| DashMpdError::Io(e, String::from("writing WebM output")))?;
+        Ok(())
+    }
+}
+
+fn doc_type_for(output_path: &Path) -> &'static str {
+    if output_path.extension().is_some_and(|e| e.eq_ignore_ascii_case("webm")) {
+        "webm"
+    } else {
+        "matroska"
+    }
+}
+
+fn check_webm_codecs(doc_type: &str, tracks: &[Vec<MatroskaSpec>]) -> Result<(), DashMpdError> {
+    if doc_type.eq("webm") {
+        for t in tracks {
+            let codec = track_codec(t).unwrap_or("");
+            if !WEBM_CODECS.contains(&codec) {
+                return Err(mux_error(&format!("codec {codec} is not allowed in a WebM container")));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Set the TrackNumber, TrackUID, language and default flag of a TrackEntry in the output.
+fn output_track_entry(
+    entry: &[MatroskaSpec],
+    number: u64,
+    language: Option<&str>,
+    default: Option<bool>) -> Vec<MatroskaSpec>
+{
+    let mut out: Vec<MatroskaSpec> = entry.iter()
+        .filter(|c| !matches!(c, MatroskaSpec::TrackNumber(_) | MatroskaSpec::TrackUID(_)))
+        .filter(|c| language.is_none() || !matches!(c, MatroskaSpec::Language(_) | MatroskaSpec::LanguageIETF(_)))
+        .filter(|c| default.is_none() || !matches!(c, MatroskaSpec::FlagDefault(_)))
+        .cloned()
+        .collect();
+    out.insert(0, MatroskaSpec::TrackUID(number));
+    out.insert(0, MatroskaSpec::TrackNumber(number));
+    if let Some(lang) = language {
+        if let Some(l) = iso639_2b(lang) {
+            out.push(MatroskaSpec::Language(l));
+        }
+        out.push(MatroskaSpec::LanguageIETF(String::from(lang)));
+    }
+    if let Some(d) = default {
+        out.push(MatroskaSpec::FlagDefault(u64::from(d)));
+    }
+    out
+}
+
+// An input to the native muxer, with the language to record for its tracks (if known).
+struct MuxInput {
+    path: PathBuf,
+    language: Option<String>,
+}
+
+fn mux_webm_files(inputs: &[MuxInput], output_path: &Path) -> Result<(), DashMpdError> {
+    let mut readers = Vec::new();
+    for i in inputs {
+        readers.push(WebmInput::open(&i.path)?);
+    }
+    let doc_type = doc_type_for(output_path);
+    let audio_count = inputs.iter().filter(|i| i.language.is_some()).count();
+    let mut seen_audio = false;
+    let mut tracks = Vec::new();
+    // The output track number and DefaultDuration for each track of each input.
+    let mut track_map: Vec<Vec<(u64, bool, Option<u64>)>> = Vec::new();
+    for (input, reader) in inputs.iter().zip(readers.iter()) {
+        let mut map = Vec::new();
+        for entry in &reader.tracks {
+            let number = tracks.len() as u64 + 1;
+            let default = if input.language.is_some() && audio_count > 1 {
+                let d = !seen_audio;
+                seen_audio = true;
+                Some(d)
+            } else {
+                None
+            };
+            tracks.push(output_track_entry(entry, number, input.language.as_deref(), default));
+            map.push((number, is_video_track(entry), track_default_duration(entry)));
+        }
+        track_map.push(map);
+    }
+    check_webm_codecs(doc_type, &tracks)?;
+    let timestamp_scale = readers.iter().map(|r| r.timestamp_scale).min().unwrap_or(DEFAULT_TIMESTAMP_SCALE);
+    // Timestamps are rebased so that the output starts at zero.
+    let mut origin = i64::MAX;
+    for r in readers.iter_mut() {
+        origin = origin.min(r.start_timestamp()?);
+    }
+    if origin == i64::MAX {
+        return Err(mux_error("no media blocks in input"));
+    }
+    let mut ends = vec![TrackEnd::default(); tracks.len()];
+    let mut output = WebmOutput::create(output_path, doc_type, timestamp_scale, tracks)?;
+    loop {
+        let mut next: Option<(usize, i64)> = None;
+        for (i, r) in readers.iter_mut().enumerate() {
+            if let Some(ts) = r.peek_timestamp()? {
+                if next.is_none_or(|(_, best)| ts < best) {
+                    next = Some((i, ts));
+                }
+            }
+        }
+        let Some((i, _)) = next else { break };
+        let scale = readers[i].timestamp_scale;
+        let Some(block) = readers[i].next_block()? else { break };
+        let (number, is_video, default_duration) = track_map[i][block.track];
+        let ts = block.timestamp - origin;
+        ends[number as usize - 1].update(ts, block.duration, default_duration);
+        output.push(number, is_video, ts, block.keyframe, block.content, scale)?;
+    }
+    let mut duration = ends.iter().map(|e| e.end).max().unwrap_or(0);
+    for r in &readers {
+        if let Some(d) = r.duration {
+            duration = duration.max(d - origin);
+        }
+    }
+    output.finish(duration)
+}
+
+// The media files for each Period can be concatenated if they contain the same tracks, with the
+// same codecs and codec initialization data.
+fn tracks_compatible(a: &[Vec<MatroskaSpec>], b: &[Vec<MatroskaSpec>]) -> bool {
+    a.len() == b.len() &&
+        a.iter().zip(b.iter()).all(|(ta, tb)| {
+            track_type(ta) == track_type(tb) &&
+                track_codec(ta) == track_codec(tb) &&
+                track_codec_private(ta) == track_codec_private(tb)
+        })
+}
+
+fn concat_webm_to(paths: &[&Path], output_path: &Path) -> Result<(), DashMpdError> {
+    let first = WebmInput::open(paths[0])?;
+    let doc_type = doc_type_for(paths[0]);
+    let timestamp_scale = first.timestamp_scale;
+    let tracks: Vec<Vec<MatroskaSpec>> = first.tracks.iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let number = i as u64 + 1;
+            let mut entry = t.clone();
+            entry.retain(|c| !matches!(c, MatroskaSpec::TrackNumber(_) | MatroskaSpec::TrackUID(_)));
+            entry.insert(0, MatroskaSpec::TrackUID(number));
+            entry.insert(0, MatroskaSpec::TrackNumber(number));
+            entry
+        })
+        .collect();
+    let track_info: Vec<(bool, Option<u64>)> = first.tracks.iter()
+        .map(|t| (is_video_track(t), track_default_duration(t)))
+        .collect();
+    drop(first);
+    let mut output = WebmOutput::create(output_path, doc_type, timestamp_scale, tracks.clone())?;
+    let mut offset = 0;
+    for p in paths {
+        let mut reader = WebmInput::open(p)?;
+        if !tracks_compatible(&tracks, &reader.tracks) {
+            return Err(mux_error(&format!("tracks in {} differ from those of the first Period", p.display())));
+        }
+        let start = reader.start_timestamp()?;
+        let scale = reader.timestamp_scale;
+        let mut ends = vec![TrackEnd::default(); tracks.len()];
+        while let Some(block) = reader.next_block()? {
+            let (is_video, default_duration) = track_info[block.track];
+            let ts = block.timestamp - start;
+            ends[block.track].update(ts, block.duration, default_duration);
+            output.push(block.track as u64 + 1, is_video, offset + ts, block.keyframe, block.content, scale)?;
+        }
+        let mut period_duration = ends.iter().map(|e| e.end).max().unwrap_or(0);
+        if let Some(d) = reader.duration {
+            period_duration = period_duration.max(d - start);
+        }
+        trace!("WebM concat: {} has duration {period_duration}ns", p.display());
+        offset += period_duration;
+    }
+    output.finish(offset)
+}
+
+/// Mux audio tracks and an optional video track, each of which must be a WebM (or Matroska)
+/// stream, into the WebM or Matroska file at `output_path`, without using an external muxing
+/// application. The output contains Cues and a SeekHead to allow seeking.
+#[tracing::instrument(level="trace", skip(_downloader))]
+pub async fn mux_audio_video_webm(
+    _downloader: &DashDownloader,
+    output_path: &Path,
+    audio_tracks: &[AudioTrack],
+    video_path: Option<&Path>) -> Result<(), DashMpdError>
+{
+    let mut inputs = Vec::new();
+    if let Some(vp) = video_path {
+        inputs.push(MuxInput { path: vp.to_path_buf(), language: None });
+    }
+    for at in audio_tracks {
+        inputs.push(MuxInput { path: at.path.clone(), language: Some(at.language.clone()) });
+    }
+    if inputs.is_empty() {
+        return Err(mux_error("no input streams"));
+    }
+    let output_path = output_path.to_path_buf();
+    run_blocking(move || {
+        if let Err(e) = mux_webm_files(&inputs, &output_path) {
+            if output_path.exists() {
+                if let Err(e) = fs::remove_file(&output_path) {
+                    warn!("  Error deleting partial native WebM muxer output: {e}");
+                }
+            }
+            return Err(e);
+        }
+        info!("  Muxed {} streams to {} with native WebM muxer", inputs.len(), output_path.display());
+        Ok(())
+    }).await
+}
+
+/// Concatenate the WebM or Matroska files named by `paths` into the file named by the first
+/// element, rebasing the timestamps of each file to follow on from the end of the preceding file.
+/// The files must contain the same tracks, with identical codec parameters.
+#[tracing::instrument(level="trace", skip(_downloader))]
+pub async fn concat_webm_files(
+    _downloader: &DashDownloader,
+    paths: &[&Path]) -> Result<(), DashMpdError>
+{
+    if paths.len() < 2 {
+        return Err(mux_error("need at least two files"));
+    }
+    let paths: Vec<PathBuf> = paths.iter().map(|p| p.to_path_buf()).collect();
+    run_blocking(move || {
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let dir = paths[0].parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let tmpout = tempfile::Builder::new()
+            .prefix("dashmpdrs")
+            .suffix(".mkv")
+            .rand_bytes(5)
+            .tempfile_in(dir)
+            .map_err(|e| DashMpdError::Io(e, String::from("creating temporary output file")))?;
+        concat_webm_to(&paths, tmpout.path())?;
+        tmpout.persist(paths[0])
+            .map_err(|e| DashMpdError::Io(e.error, String::from("replacing concatenated output file")))?;
+        Ok(())
+    }).await
+}
+
+/// Whether the WebM or Matroska files named by `paths` can be concatenated by
+/// [concat_webm_files]: they must all contain the same tracks with the same codec parameters.
+pub fn webm_files_concatable(paths: &[&Path]) -> bool {
+    let mut tracks: Option<Vec<Vec<MatroskaSpec>>> = None;
+    for p in paths {
+        let Ok(input) = WebmInput::open(p) else {
+            return false;
+        };
+        match &tracks {
+            None => tracks = Some(input.tracks),
+            Some(t) => if !tracks_compatible(t, &input.tracks) {
+                return false;
+            },
+        }
+    }
+    tracks.is_some()
+}
+
+
+/// The flags recorded in the TrackEntry of a track in a Matroska file, which can be set by
+/// [set_webm_track_flags].
+#[cfg(feature = "libav")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TrackFlags {
+    pub default: bool,
+    pub forced: bool,
+    pub hearing_impaired: bool,
+    pub name: Option<String>,
+}
+
+#[cfg(feature = "libav")]
+fn rewrite_webm_track_flags(path: &Path, flags: &[Option<TrackFlags>], output_path: &Path) -> Result<(), DashMpdError> {
+    let mut reader = WebmInput::open(path)?;
+    let doc_type = doc_type_for(path);
+    let tracks: Vec<Vec<MatroskaSpec>> = reader.tracks.iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let number = i as u64 + 1;
+            let mut entry = output_track_entry(t, number, None, None);
+            if let Some(Some(f)) = flags.get(i) {
+                entry.retain(|c| !matches!(c,
+                                           MatroskaSpec::FlagDefault(_) |
+                                           MatroskaSpec::FlagForced(_) |
+                                           MatroskaSpec::FlagHearingImpaired(_)));
+                entry.push(MatroskaSpec::FlagDefault(u64::from(f.default)));
+                entry.push(MatroskaSpec::FlagForced(u64::from(f.forced)));
+                if f.hearing_impaired {
+                    entry.push(MatroskaSpec::FlagHearingImpaired(1));
+                }
+                if let Some(name) = &f.name {
+                    entry.retain(|c| !matches!(c, MatroskaSpec::Name(_)));
+                    entry.push(MatroskaSpec::Name(name.clone()));
+                }
+            }
+            entry
+        })
+        .collect();
+    let track_info: Vec<(bool, Option<u64>)> = reader.tracks.iter()
+        .map(|t| (is_video_track(t), track_default_duration(t)))
+        .collect();
+    let start = reader.start_timestamp()?;
+    let scale = reader.timestamp_scale;
+    let mut output = WebmOutput::create(output_path, doc_type, scale, tracks)?;
+    let mut ends = vec![TrackEnd::default(); track_info.len()];
+    while let Some(block) = reader.next_block()? {
+        let (is_video, default_duration) = track_info[block.track];
+        let ts = block.timestamp - start;
+        ends[block.track].update(ts, block.duration, default_duration);
+        output.push(block.track as u64 + 1, is_video, ts, block.keyframe, block.content, scale)?;
+    }
+    let mut duration = ends.iter().map(|e| e.end).max().unwrap_or(0);
+    if let Some(d) = reader.duration {
+        duration = duration.max(d - start);
+    }
+    output.finish(duration)
+}
+
+/// Set the default, forced and hearing impaired flags and the name of the tracks of the WebM or
+/// Matroska file at `path`, for the tracks whose element in `flags` is not None. The file is
+/// rewritten, because the TrackEntry elements generally can't be modified in place.
+#[cfg(feature = "libav")]
+pub(crate) fn set_webm_track_flags(path: &Path, flags: &[Option<TrackFlags>]) -> Result<(), DashMpdError> {
+    let dir = path.parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let tmpout = tempfile::Builder::new()
+        .prefix("dashmpdrs")
+        .suffix(".mkv")
+        .rand_bytes(5)
+        .tempfile_in(dir)
+        .map_err(|e| DashMpdError::Io(e, String::from("creating temporary output file")))?;
+    rewrite_webm_track_flags(path, flags, tmpout.path())?;
+    tmpout.persist(path)
+        .map_err(|e| DashMpdError::Io(e.error, String::from("replacing output file")))?;
+    Ok(())
+}
+
+/// The language of each track of the WebM or Matroska file at `path`, in track order.
+#[cfg(feature = "libav")]
+pub(crate) fn webm_track_languages(path: &Path) -> Result<Vec<Option<String>>, DashMpdError> {
+    let input = WebmInput::open(path)?;
+    Ok(input.tracks.iter()
+       .map(|t| t.iter().find_map(|c| match c {
+           MatroskaSpec::LanguageIETF(l) => Some(l.clone()),
+           _ => None,
+       }).or_else(|| t.iter().find_map(|c| match c {
+           MatroskaSpec::Language(l) => Some(l.clone()),
+           _ => None,
+       })))
+       .map(|l| l.filter(|l| !l.eq("und")))
+       .collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_webm_block_header() {
+        use super::{parse_block_header, rewrite_block, void_element};
+
+        assert_eq!(parse_block_header(&[0x81, 0x00, 0x10, 0x80, 0xAA]), Some((1, 1, 16)));
+        assert_eq!(parse_block_header(&[0x40, 0x85, 0xFF, 0xFE, 0x00]), Some((133, 2, -2)));
+        assert_eq!(parse_block_header(&[0x81, 0x00]), None);
+        assert_eq!(rewrite_block(&[0x40, 0x85, 0xFF, 0xFE, 0x80, 0xAA], 3, 42),
+                   Some(vec![0x83, 0x00, 0x2A, 0x80, 0xAA]));
+        assert_eq!(void_element(2), vec![0xEC, 0x80]);
+        assert_eq!(void_element(5), vec![0xEC, 0x83, 0, 0, 0]);
+        assert_eq!(void_element(200).len(), 200);
+        assert_eq!(&void_element(200)[..3], &[0xEC, 0x40, 197]);
+    }
+}
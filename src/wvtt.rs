@@ -0,0 +1,360 @@
+//! Support for WebVTT subtitles packaged in fragmented MP4 (the wvtt codec)
+//
+// ISO/IEC 14496-30 specifies a binary encapsulation of WebVTT in the ISO base media file format,
+// used for example by many live streams which deliver subtitles as a sequence of fMP4 segments. The
+// initialization segment contains a wvtt sample entry, whose vttC box holds the WebVTT header
+// (the "WEBVTT" line, and any STYLE or REGION blocks). Each media sample is a sequence of boxes:
+//
+//  - vttc: a cue, which contains a payl box (cue text) and optionally an iden box (cue identifier),
+//    an sttg box (cue settings) and a ctim box (current time, for karaoke-style cues)
+//
+//  - vtte: an empty sample, used to fill gaps between cues
+//
+//  - vtta: additional text such as comments, which we ignore
+//
+// The timing of a cue is that of the sample that contains it, calculated from the tfdt box of the
+// track fragment and the sample durations in the trun box (or the defaults in the tfhd and trex
+// boxes), expressed in the timescale of the mdhd box in the initialization segment. A cue that
+// overlaps other cues is split across several consecutive samples by the packager; we merge these
+// back into a single cue.
+//
+// This module implements parsing of the boxes (we only need a small subset of the MP4 format, so
+// we don't use a general purpose MP4 parser) and serialization of the cues to WebVTT or SRT format,
+// so that these subtitles can be converted without requiring MP4Box.
+
+
+use tracing::{trace, warn};
+use crate::DashMpdError;
+use crate::vtt::{VttCue, cues_to_webvtt, cues_to_srt};
+
+
+fn be_u32(data: &[u8], pos: usize) -> Result<u32, DashMpdError> {
+    data.get(pos..pos+4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| DashMpdError::Parsing(String::from("truncated MP4 box in WVTT stream")))
+}
+
+fn be_u64(data: &[u8], pos: usize) -> Result<u64, DashMpdError> {
+    Ok((u64::from(be_u32(data, pos)?) << 32) | u64::from(be_u32(data, pos + 4)?))
+}
+
+// An MP4 box: its four character type, the offsets of the start of the box and of its payload
+// within the buffer being parsed, and the payload (the content following the box header).
+struct Mp4Box<'a> {
+    boxtype: [u8; 4],
+    offset: usize,
+    payload_offset: usize,
+    payload: &'a [u8],
+}
+
+// Split data into a sequence of boxes. Offsets are relative to the start of data, plus base.
+fn parse_boxes(data: &[u8], base: usize) -> Result<Vec<Mp4Box<'_>>, DashMpdError> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size32 = be_u32(data, pos)?;
+        let boxtype: [u8; 4] = data[pos+4..pos+8].try_into().unwrap();
+        let (header_len, size) = match size32 {
+            0 => (8, data.len() - pos),
+            1 => (16, usize::try_from(be_u64(data, pos + 8)?)
+                  .map_err(|_| DashMpdError::Parsing(String::from("MP4 box too large")))?),
+            n => (8, n as usize),
+        };
+        if size < header_len || pos + size > data.len() {
+            return Err(DashMpdError::Parsing(
+                format!("invalid size for MP4 box {} in WVTT stream", String::from_utf8_lossy(&boxtype))));
+        }
+        boxes.push(Mp4Box {
+            boxtype,
+            offset: base + pos,
+            payload_offset: base + pos + header_len,
+            payload: &data[pos+header_len..pos+size],
+        });
+        pos += size;
+    }
+    Ok(boxes)
+}
+
+fn find_box<'a>(boxes: &'a [Mp4Box<'a>], boxtype: &[u8; 4]) -> Option<&'a Mp4Box<'a>> {
+    boxes.iter().find(|b| b.boxtype.eq(boxtype))
+}
+
+fn box_string(payload: &[u8]) -> String {
+    String::from_utf8_lossy(payload).trim_end_matches('\0').to_string()
+}
+
+
+/// A WebVTT document built from a sequence of fragmented MP4 segments containing wvtt samples.
+#[derive(Clone, Debug)]
+pub struct WvttDocument {
+    timescale: Option<u32>,
+    header: Option<String>,
+    default_sample_duration: u32,
+    default_sample_size: u32,
+    // Decode time (in timescale units) following the last sample seen, used if a fragment has no
+    // tfdt box.
+    next_decode_time: u64,
+    // Added to the times of cues, to place the current Period on the timeline of the output media.
+    offset_ms: i64,
+    cues: Vec<VttCue>,
+    warned_missing_timescale: bool,
+}
+
+impl Default for WvttDocument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WvttDocument {
+    #[must_use]
+    pub fn new() -> WvttDocument {
+        WvttDocument {
+            timescale: None,
+            header: None,
+            default_sample_duration: 0,
+            default_sample_size: 0,
+            next_decode_time: 0,
+            offset_ms: 0,
+            cues: Vec::new(),
+            warned_missing_timescale: false,
+        }
+    }
+
+    /// Set the timescale used to interpret sample times, if it is known from the manifest. The
+    /// timescale specified in the mdhd box of an initialization segment takes precedence.
+    pub fn set_timescale(&mut self, timescale: u32) {
+        if timescale > 0 {
+            self.timescale = Some(timescale);
+        }
+    }
+
+    /// Set the offset (in milliseconds) of the Period whose segments are added after this call, as
+    /// for [VttDocument::set_offset_ms](crate::vtt::VttDocument::set_offset_ms). Sample times in
+    /// the new Period are relative to its start.
+    pub fn set_offset_ms(&mut self, offset_ms: i64) {
+        self.offset_ms = offset_ms;
+        self.next_decode_time = 0;
+    }
+
+    /// The cues that have been extracted so far.
+    #[must_use]
+    pub fn cues(&self) -> &[VttCue] {
+        &self.cues
+    }
+
+    /// Parse the content of an MP4 segment (initialization segment, media segment, or a complete
+    /// fragmented MP4 file) and add any cues that it contains to the document.
+    pub fn add_from_mp4(&mut self, bytes: &[u8]) -> Result<(), DashMpdError> {
+        let boxes = parse_boxes(bytes, 0)?;
+        let mut pending_moof = None;
+        for b in &boxes {
+            match &b.boxtype {
+                b"moov" => self.parse_moov(b.payload)?,
+                b"moof" => pending_moof = Some(b),
+                b"mdat" => {
+                    if let Some(moof) = pending_moof.take() {
+                        self.parse_fragment(bytes, moof, b)?;
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_moov(&mut self, payload: &[u8]) -> Result<(), DashMpdError> {
+        let moov = parse_boxes(payload, 0)?;
+        if let Some(trak) = find_box(&moov, b"trak") {
+            let trak = parse_boxes(trak.payload, 0)?;
+            if let Some(mdia) = find_box(&trak, b"mdia") {
+                let mdia = parse_boxes(mdia.payload, 0)?;
+                if let Some(mdhd) = find_box(&mdia, b"mdhd") {
+                    let version = mdhd.payload.first().copied().unwrap_or(0);
+                    let ts = if version == 1 {
+                        be_u32(mdhd.payload, 20)?
+                    } else {
+                        be_u32(mdhd.payload, 12)?
+                    };
+                    self.set_timescale(ts);
+                }
+                if let Some(minf) = find_box(&mdia, b"minf") {
+                    let minf = parse_boxes(minf.payload, 0)?;
+                    if let Some(stbl) = find_box(&minf, b"stbl") {
+                        let stbl = parse_boxes(stbl.payload, 0)?;
+                        if let Some(stsd) = find_box(&stbl, b"stsd") {
+                            // FullBox header and entry_count, followed by the sample entries
+                            let entries = parse_boxes(stsd.payload.get(8..).unwrap_or_default(), 0)?;
+                            if let Some(wvtt) = find_box(&entries, b"wvtt") {
+                                // The SampleEntry has 6 reserved octets and a data_reference_index
+                                let children = parse_boxes(wvtt.payload.get(8..).unwrap_or_default(), 0)?;
+                                if let Some(vttc) = find_box(&children, b"vttC") {
+                                    self.header = Some(box_string(vttc.payload));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(mvex) = find_box(&moov, b"mvex") {
+            let mvex = parse_boxes(mvex.payload, 0)?;
+            if let Some(trex) = find_box(&mvex, b"trex") {
+                self.default_sample_duration = be_u32(trex.payload, 12)?;
+                self.default_sample_size = be_u32(trex.payload, 16)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_fragment(&mut self, segment: &[u8], moof: &Mp4Box, mdat: &Mp4Box) -> Result<(), DashMpdError> {
+        let children = parse_boxes(moof.payload, 0)?;
+        let Some(traf) = find_box(&children, b"traf") else {
+            return Ok(());
+        };
+        let traf = parse_boxes(traf.payload, 0)?;
+        let mut base_offset = moof.offset;
+        let mut default_duration = self.default_sample_duration;
+        let mut default_size = self.default_sample_size;
+        if let Some(tfhd) = find_box(&traf, b"tfhd") {
+            let flags = be_u32(tfhd.payload, 0)? & 0x00FF_FFFF;
+            let mut pos = 8;
+            if flags & 0x01 != 0 {
+                base_offset = usize::try_from(be_u64(tfhd.payload, pos)?)
+                    .map_err(|_| DashMpdError::Parsing(String::from("invalid base_data_offset")))?;
+                pos += 8;
+            }
+            if flags & 0x02 != 0 {
+                pos += 4;
+            }
+            if flags & 0x08 != 0 {
+                default_duration = be_u32(tfhd.payload, pos)?;
+                pos += 4;
+            }
+            if flags & 0x10 != 0 {
+                default_size = be_u32(tfhd.payload, pos)?;
+            }
+        }
+        let mut decode_time = match find_box(&traf, b"tfdt") {
+            Some(tfdt) if tfdt.payload.first() == Some(&1) => be_u64(tfdt.payload, 4)?,
+            Some(tfdt) => u64::from(be_u32(tfdt.payload, 4)?),
+            None => self.next_decode_time,
+        };
+        let timescale = match self.timescale {
+            Some(ts) => u64::from(ts),
+            None => {
+                if !self.warned_missing_timescale {
+                    warn!("Timescale for WVTT subtitles is unknown, assuming milliseconds");
+                    self.warned_missing_timescale = true;
+                }
+                1000
+            },
+        };
+        // The data of a trun without a data_offset immediately follows the data of the preceding
+        // trun, or starts at the beginning of the mdat payload for the first trun.
+        let mut data_pos = mdat.payload_offset;
+        for trun in traf.iter().filter(|b| b.boxtype.eq(b"trun")) {
+            let flags = be_u32(trun.payload, 0)? & 0x00FF_FFFF;
+            let count = be_u32(trun.payload, 4)?;
+            let mut pos = 8;
+            if flags & 0x01 != 0 {
+                let data_offset = be_u32(trun.payload, pos)? as i32;
+                data_pos = usize::try_from(base_offset as i64 + i64::from(data_offset))
+                    .map_err(|_| DashMpdError::Parsing(String::from("invalid trun data_offset")))?;
+                pos += 4;
+            }
+            if flags & 0x04 != 0 {
+                pos += 4;
+            }
+            for _ in 0..count {
+                let mut duration = default_duration;
+                let mut size = default_size;
+                let mut composition_offset: i64 = 0;
+                if flags & 0x100 != 0 {
+                    duration = be_u32(trun.payload, pos)?;
+                    pos += 4;
+                }
+                if flags & 0x200 != 0 {
+                    size = be_u32(trun.payload, pos)?;
+                    pos += 4;
+                }
+                if flags & 0x400 != 0 {
+                    pos += 4;
+                }
+                if flags & 0x800 != 0 {
+                    // Signed in version 1 of the trun box, but negative offsets are unusual.
+                    composition_offset = i64::from(be_u32(trun.payload, pos)? as i32);
+                    pos += 4;
+                }
+                let sample = segment.get(data_pos..data_pos + size as usize)
+                    .ok_or_else(|| DashMpdError::Parsing(String::from("WVTT sample extends beyond segment")))?;
+                let start = (decode_time as i64 + composition_offset).max(0) as u64;
+                let start_ms = self.media_time_ms(start, timescale)?;
+                let end_ms = self.media_time_ms(start.saturating_add(u64::from(duration)), timescale)?;
+                self.add_sample(sample, start_ms, end_ms)?;
+                data_pos += size as usize;
+                decode_time += u64::from(duration);
+            }
+        }
+        self.next_decode_time = decode_time;
+        Ok(())
+    }
+
+    // Convert a time in timescale units to milliseconds on the output timeline. The intermediate
+    // product is computed in u128, because tfdt values based on the Unix epoch overflow u64 when
+    // multiplied at common timescales such as 10 MHz.
+    fn media_time_ms(&self, time: u64, timescale: u64) -> Result<u64, DashMpdError> {
+        let ms = u128::from(time) * 1000 / u128::from(timescale);
+        let ms = i128::try_from(ms).unwrap_or(i128::MAX) + i128::from(self.offset_ms);
+        u64::try_from(ms.max(0))
+            .map_err(|_| DashMpdError::Parsing(String::from("WVTT sample time out of range")))
+    }
+
+    fn add_sample(&mut self, sample: &[u8], start_ms: u64, end_ms: u64) -> Result<(), DashMpdError> {
+        for b in parse_boxes(sample, 0)? {
+            if b.boxtype.ne(b"vttc") {
+                // vtte (empty sample) or vtta (additional text)
+                continue;
+            }
+            let mut cue = VttCue { start_ms, end_ms, ..Default::default() };
+            for child in parse_boxes(b.payload, 0)? {
+                match &child.boxtype {
+                    b"payl" => cue.payload = box_string(child.payload),
+                    b"iden" => cue.identifier = Some(box_string(child.payload)),
+                    b"sttg" => cue.settings = Some(box_string(child.payload)),
+                    _ => (),
+                }
+            }
+            trace!("WVTT cue {cue:?}");
+            // If this cue is the continuation of a cue in the immediately preceding sample (the
+            // packager splits cues at each point where the set of active cues changes), extend that
+            // cue rather than adding a new one.
+            let continued = self.cues.iter_mut()
+                .rev()
+                .take(16)
+                .find(|c| c.end_ms == start_ms &&
+                      c.payload == cue.payload &&
+                      c.settings == cue.settings &&
+                      c.identifier == cue.identifier);
+            if let Some(c) = continued {
+                c.end_ms = end_ms;
+            } else {
+                self.cues.push(cue);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize the cues extracted so far as a WebVTT document, using the header from the wvtt
+    /// sample entry if one was present in an initialization segment.
+    #[must_use]
+    pub fn to_webvtt(&self) -> String {
+        cues_to_webvtt(self.header.as_deref().unwrap_or("WEBVTT"), &self.cues)
+    }
+
+    /// Serialize the cues extracted so far in SRT format.
+    #[must_use]
+    pub fn to_srt(&self) -> String {
+        cues_to_srt(&self.cues)
+    }
+}
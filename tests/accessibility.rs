@@ -0,0 +1,112 @@
+//! Tests for track selection according to accessibility features (audio description, SDH and forced
+//! subtitles, sign language)
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test accessibility -- --show-output
+//
+
+pub mod common;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::{DashDownloader, AccessibilityFeature};
+use common::{serve_manifests, ffprobe_audio_languages, setup_logging};
+
+
+#[tokio::test]
+async fn test_accessibility_selection() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT2S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="main"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="video" bandwidth="2000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="sign"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="sign" bandwidth="2500000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="main"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="en" bandwidth="128000"/>
+    </AdaptationSet>
+    <AdaptationSet id="4" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <Accessibility schemeIdUri="urn:tva:metadata:cs:AudioPurposeCS:2007" value="1"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="en-ad" bandwidth="192000"/>
+    </AdaptationSet>
+    <AdaptationSet id="5" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="fr">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="description"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="fr-ad" bandwidth="192000"/>
+    </AdaptationSet>
+    <AdaptationSet id="6" contentType="text" mimeType="text/vtt" lang="en">
+      <Representation id="sub-en" bandwidth="1000">
+        <BaseURL>/media/sub-en.vtt</BaseURL>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet id="7" contentType="text" mimeType="text/vtt" lang="en">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="caption"/>
+      <Representation id="sub-en-sdh" bandwidth="1000">
+        <BaseURL>/media/sub-en-sdh.vtt</BaseURL>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet id="8" contentType="text" mimeType="text/vtt" lang="en">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="forced-subtitle"/>
+      <Representation id="sub-en-forced" bandwidth="1000">
+        <BaseURL>/media/sub-en-forced.vtt</BaseURL>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6690, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("accessible.mp4");
+    let url = "http://localhost:6690/mpd";
+    let en = || DashDownloader::new(url).best_quality().prefer_audio_language(String::from("en"));
+    // The audio tracks are muxed with their language when there are several of them, and otherwise
+    // copied unchanged to the output file.
+    let cases: [(DashDownloader, Vec<&str>, Vec<&str>); 8] = [
+        // Without accessibility preferences, the higher bandwidth audio description track wins.
+        (en().audio_only(), vec!["en-ad-1.m4s"], vec!["und"]),
+        (en().audio_only().exclude_accessibility(vec![AccessibilityFeature::AudioDescription]),
+         vec!["en-1.m4s"], vec!["und"]),
+        // The audio description track in the wanted language is added as an extra track.
+        (en().audio_only().include_accessibility(vec![AccessibilityFeature::AudioDescription]),
+         vec!["en-1.m4s", "en-ad-1.m4s"], vec!["eng", "eng"]),
+        (DashDownloader::new(url).best_quality().audio_only()
+         .prefer_audio_languages(vec!["en", "fr"])
+         .include_accessibility(vec![AccessibilityFeature::AudioDescription]),
+         vec!["en-1.m4s", "en-ad-1.m4s", "fr-ad-1.m4s"], vec!["eng", "eng", "fra"]),
+        (en().video_only(), vec!["sign-1.m4s"], vec![]),
+        (en().video_only().exclude_accessibility(vec![AccessibilityFeature::SignLanguage]),
+         vec!["video-1.m4s"], vec![]),
+        (en().video_only().worst_quality().include_accessibility(vec![AccessibilityFeature::SignLanguage]),
+         vec!["sign-1.m4s"], vec![]),
+        (en().audio_only().exclude_accessibility(vec![AccessibilityFeature::AudioDescription])
+         .fetch_subtitles(true)
+         .prefer_subtitle_language(String::from("en"))
+         .include_accessibility(vec![AccessibilityFeature::HardOfHearing, AccessibilityFeature::ForcedSubtitles]),
+         vec!["en-1.m4s", "sub-en-forced.vtt", "sub-en-sdh.vtt", "sub-en.vtt"], vec!["und"]),
+    ];
+    for (downloader, expected, languages) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .download_to(&out).await
+            .unwrap();
+        let mut fetched = requests.lock().unwrap().clone();
+        fetched.sort();
+        assert_eq!(fetched, expected);
+        assert_eq!(ffprobe_audio_languages(&out), languages);
+    }
+    // The SDH and forced subtitles are saved alongside the main subtitle track.
+    assert!(out.with_extension("vtt").exists());
+    assert!(tmpd.path().join("accessible.en.sdh.vtt").exists());
+    assert!(tmpd.path().join("accessible.en.forced.vtt").exists());
+    server_handle.shutdown();
+}
@@ -0,0 +1,64 @@
+//! Tests for audio stream selection by codec, channel count and sampling rate
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test audio_selection -- --show-output
+//
+
+pub mod common;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use common::{serve_manifests, ffprobe_stream_types, setup_logging};
+
+
+// A stereo AAC AdaptationSet and a 5.1 E-AC-3 AdaptationSet in the same language, with the channel
+// layout expressed using the MPEG and Dolby schemes.
+#[tokio::test]
+async fn test_audio_codec_channels_rate() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT2S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="audio" mimeType="audio/mp4" lang="en" codecs="ec-3" audioSamplingRate="48000">
+      <AudioChannelConfiguration schemeIdUri="tag:dolby.com,2014:dash:audio_channel_configuration:2011" value="F801"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="ec3" bandwidth="384000"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="audio" mimeType="audio/mp4" lang="en" codecs="mp4a.40.2">
+      <AudioChannelConfiguration schemeIdUri="urn:mpeg:mpegB:cicp:ChannelConfiguration" value="2"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="aac48" bandwidth="128000" audioSamplingRate="48000"/>
+      <Representation id="aac44" bandwidth="96000" audioSamplingRate="44100"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6686, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("audio.mp4");
+    let url = "http://localhost:6686/mpd";
+    let cases: [(DashDownloader, &str); 7] = [
+        // Without preferences, the highest bandwidth stream is selected.
+        (DashDownloader::new(url).best_quality(), "ec3-1.m4s"),
+        (DashDownloader::new(url).best_quality().max_audio_channels(2), "aac48-1.m4s"),
+        (DashDownloader::new(url).best_quality().prefer_audio_channels(2), "aac48-1.m4s"),
+        (DashDownloader::new(url).worst_quality().prefer_audio_codecs(vec![String::from("ec-3")]), "ec3-1.m4s"),
+        (DashDownloader::new(url).prefer_audio_codecs(vec![String::from("opus"), String::from("mp4a")])
+         .prefer_audio_sampling_rate(44100), "aac44-1.m4s"),
+        // The quality preference applies between streams which match the other preferences equally well.
+        (DashDownloader::new(url).worst_quality().prefer_audio_sampling_rate(48000), "aac48-1.m4s"),
+        // No stream respects the channel ceiling: that with the fewest channels is selected.
+        (DashDownloader::new(url).best_quality().max_audio_channels(1), "aac48-1.m4s"),
+    ];
+    for (downloader, expected) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .fetch_video(false)
+            .download_to(&out).await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap().clone(), vec![expected]);
+        assert_eq!(ffprobe_stream_types(&out), vec!["audio"]);
+    }
+    server_handle.shutdown();
+}
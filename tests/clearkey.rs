@@ -0,0 +1,172 @@
+//! Testing support for W3C Clear Key license acquisition.
+//
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    RUST_LOG=info cargo test --test clearkey -- --show-output
+//
+// What happens in this test:
+//
+//   - Start an axum HTTP server that serves a manifest whose video stream is protected using the
+//   Clear Key DRM system, with a dashif:Laurl license acquisition URL pointing to the server. The
+//   server also serves the media segments and answers Clear Key license requests.
+//
+//   - Fetch the media content using DashDownloader with a fake "mp4decrypt" helper application
+//   that records its commandline arguments, and check that the license server was asked for the
+//   right KID and that the key in the license was passed to the decryption helper.
+
+
+pub mod common;
+use std::fs;
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use axum::{routing::get, routing::post, Router};
+use axum::extract::State;
+use axum::response::{Response, IntoResponse};
+use axum::http::header;
+use axum::body::{Body, Bytes};
+use axum_server::{Handle, bind};
+use dash_mpd::clearkey::{license_request_body, parse_license_response};
+use dash_mpd::fetch::DashDownloader;
+use anyhow::Result;
+use tracing::info;
+use common::{generate_minimal_mp4_rust, setup_logging};
+#[cfg(unix)]
+use common::fake_mp4decrypt;
+
+
+const KID: &str = "9eb4050d-e44b-4802-932e-27d75083e266";
+const KEY_HEX: &str = "166634c675823c235a4a9446fad52e4d";
+
+
+#[test]
+fn test_clearkey_license_messages() {
+    let body = license_request_body(&[KID.to_string()]).unwrap();
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["type"], "temporary");
+    assert_eq!(json["kids"][0], "nrQFDeRLSAKTLifXUIPiZg");
+    assert!(license_request_body(&[String::from("nothex")]).is_err());
+
+    let license = r#"{"keys":[{"kty":"oct","kid":"nrQFDeRLSAKTLifXUIPiZg","k":"FmY0xnWCPCNaSpRG-tUuTQ"}],"type":"temporary"}"#;
+    let keys = parse_license_response(license.as_bytes()).unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys.get("9eb4050de44b4802932e27d75083e266").unwrap(), KEY_HEX);
+    // Some license servers use padded base64.
+    let license = r#"{"keys":[{"kty":"oct","kid":"nrQFDeRLSAKTLifXUIPiZg==","k":"FmY0xnWCPCNaSpRG-tUuTQ=="}]}"#;
+    let keys = parse_license_response(license.as_bytes()).unwrap();
+    assert_eq!(keys.get("9eb4050de44b4802932e27d75083e266").unwrap(), KEY_HEX);
+    assert!(parse_license_response(b"<html>Forbidden</html>").is_err());
+}
+
+
+#[derive(Debug, Default)]
+struct AppState {
+    license_requests: AtomicUsize,
+    requested_kids: Mutex<Vec<String>>,
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_clearkey_license_acquisition() -> Result<()> {
+    let shared_state = Arc::new(AppState::default());
+
+    // We use a literal manifest rather than serializing an MPD struct, because the serialized
+    // manifest does not declare the dashif namespace.
+    async fn send_mpd() -> impl IntoResponse {
+        let xml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" xmlns:cenc="urn:mpeg:cenc:2013" xmlns:dashif="https://dashif.org/CPS"
+     type="static" mediaPresentationDuration="PT5S" minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-on-demand:2011">
+  <Period id="1" duration="PT5S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4">
+      <ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc" cenc:default_KID="{KID}"/>
+      <ContentProtection schemeIdUri="urn:uuid:e2719d58-a985-b3c9-781a-b030af78d30e" value="ClearKey1.0">
+        <dashif:Laurl Lic_type="EME-1.0">/license</dashif:Laurl>
+      </ContentProtection>
+      <Representation id="1" codecs="avc1.640028" width="1920" height="800" bandwidth="1980081">
+        <SegmentTemplate initialization="/media/init.mp4"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#);
+        ([(header::CONTENT_TYPE, "application/dash+xml")], xml)
+    }
+
+    async fn send_mp4() -> Response {
+        Response::builder()
+            .status(axum::http::StatusCode::OK)
+            .header(header::CONTENT_TYPE, "video/mp4")
+            .body(Body::from(generate_minimal_mp4_rust()))
+            .unwrap()
+    }
+
+    async fn send_license(State(state): State<Arc<AppState>>, body: Bytes) -> impl IntoResponse {
+        info!("license request: {}", String::from_utf8_lossy(&body));
+        state.license_requests.fetch_add(1, Ordering::SeqCst);
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        if let Some(kids) = json["kids"].as_array() {
+            let mut requested = state.requested_kids.lock().unwrap();
+            for kid in kids {
+                requested.push(kid.as_str().unwrap().to_string());
+            }
+        }
+        let license = r#"{"keys":[{"kty":"oct","kid":"nrQFDeRLSAKTLifXUIPiZg","k":"FmY0xnWCPCNaSpRG-tUuTQ"}],"type":"temporary"}"#;
+        ([(header::CONTENT_TYPE, "application/json")], license)
+    }
+
+    setup_logging();
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/media/{seg}", get(send_mp4))
+        .route("/license", post(send_license))
+        .with_state(shared_state.clone());
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    let backend_handle = server_handle.clone();
+    let backend = async move {
+        bind("127.0.0.1:6670".parse().unwrap())
+            .handle(backend_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // A fake mp4decrypt that records its arguments and copies its input to its output.
+    let tmpd = tempfile::tempdir()?;
+    let (mp4decrypt, argslog) = fake_mp4decrypt(tmpd.path())?;
+
+    let outpath = env::temp_dir().join("clearkey.mp4");
+    DashDownloader::new("http://localhost:6670/mpd")
+        .with_mp4decrypt(&mp4decrypt.to_string_lossy())
+        .verbosity(2)
+        .download_to(&outpath).await
+        .unwrap();
+    assert!(fs::metadata(&outpath).is_ok());
+    assert_eq!(shared_state.license_requests.load(Ordering::SeqCst), 1);
+    assert_eq!(*shared_state.requested_kids.lock().unwrap(), vec!["nrQFDeRLSAKTLifXUIPiZg".to_string()]);
+    let args = fs::read_to_string(&argslog)?;
+    assert!(args.contains(&format!("--key 9eb4050de44b4802932e27d75083e266:{KEY_HEX}")),
+            "unexpected mp4decrypt arguments: {args}");
+
+    // When the key is already known, no license request is made.
+    let outpath = env::temp_dir().join("clearkey-known.mp4");
+    DashDownloader::new("http://localhost:6670/mpd")
+        .with_mp4decrypt(&mp4decrypt.to_string_lossy())
+        .add_decryption_key(String::from("9eb4050de44b4802932e27d75083e266"), String::from(KEY_HEX))
+        .download_to(&outpath).await
+        .unwrap();
+    assert_eq!(shared_state.license_requests.load(Ordering::SeqCst), 1);
+
+    // License acquisition can be disabled.
+    let outpath = env::temp_dir().join("clearkey-disabled.mp4");
+    DashDownloader::new("http://localhost:6670/mpd")
+        .acquire_clearkey_licenses(false)
+        .download_to(&outpath).await
+        .unwrap();
+    assert_eq!(shared_state.license_requests.load(Ordering::SeqCst), 1);
+    server_handle.shutdown();
+
+    Ok(())
+}
@@ -7,6 +7,14 @@ use std::env;
 use std::path::Path;
 use std::process::Command;
 use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::{routing::get, Router};
+use axum::extract::{Path as AxumPath, State};
+use axum::response::{IntoResponse, Response};
+use axum::http::header;
+use axum_server::{Handle, bind};
 use ffprobe::ffprobe;
 use anyhow::{Context, Result};
 use lazy_static::lazy_static;
@@ -224,6 +232,117 @@ pub fn generate_minimal_mp4_rust () -> Vec<u8> {
     writer.into_writer().into_inner()
 }
 
+// A minimal MP4 file containing a single AAC audio track. Like generate_minimal_mp4_rust(), the
+// files generated cannot be appended.
+pub fn generate_minimal_audio_mp4_rust () -> Vec<u8> {
+    let config = mp4::Mp4Config {
+        major_brand: str::parse("isom").unwrap(),
+        minor_version: 512,
+        compatible_brands: vec![
+            str::parse("isom").unwrap(),
+            str::parse("iso2").unwrap(),
+            str::parse("mp41").unwrap(),
+        ],
+        timescale: 48000,
+    };
+    let data = Cursor::new(Vec::<u8>::new());
+    let mut writer = mp4::Mp4Writer::write_start(data, &config).unwrap();
+    let track_conf = mp4::TrackConfig {
+        track_type: mp4::TrackType::Audio,
+        timescale: 48000,
+        language: "und".to_string(),
+        media_conf: mp4::MediaConfig::AacConfig(mp4::AacConfig {
+            bitrate: 128000,
+            profile: mp4::AudioObjectType::AacLowComplexity,
+            freq_index: mp4::SampleFreqIndex::Freq48000,
+            chan_conf: mp4::ChannelConfig::Stereo,
+        }),
+    };
+    writer.add_track(&track_conf).unwrap();
+    for i in 0..4 {
+        let sample = mp4::Mp4Sample {
+            start_time: i * 1024,
+            duration: 1024,
+            rendering_offset: 0,
+            is_sync: true,
+            // An AAC frame containing silence.
+            bytes: mp4::Bytes::from_static(&[0x21, 0x10, 0x04, 0x60, 0x8c, 0x1c]),
+        };
+        writer.write_sample(1, &sample).unwrap();
+    }
+    writer.write_end().unwrap();
+    writer.into_writer().into_inner()
+}
+
+
+// The names of the media segments requested from a server started with serve_manifests().
+pub type Requests = Arc<Mutex<Vec<String>>>;
+
+async fn send_audio_segment(AxumPath(name): AxumPath<String>, State(requests): State<Requests>) -> Response {
+    requests.lock().unwrap().push(name);
+    ([(header::CONTENT_TYPE, "audio/mp4")], generate_minimal_audio_mp4_rust()).into_response()
+}
+
+async fn send_media_segment(AxumPath(name): AxumPath<String>, State(requests): State<Requests>) -> Response {
+    requests.lock().unwrap().push(name.clone());
+    if name.ends_with(".vtt") {
+        ([(header::CONTENT_TYPE, "text/vtt")], "WEBVTT\n\n00:00.000 --> 00:01.000\nHello\n").into_response()
+    } else if name.ends_with(".jpg") {
+        ([(header::CONTENT_TYPE, "image/jpeg")], name).into_response()
+    } else {
+        ([(header::CONTENT_TYPE, "video/mp4")], generate_minimal_mp4_rust()).into_response()
+    }
+}
+
+// Start an HTTP server on localhost:port which serves each (path, manifest) pair in manifests, and
+// media segments under /audio/ and /media/, recording the name of each segment requested. Segments
+// under /audio/ are a minimal AAC MP4 file. Segments under /media/ are a WebVTT document for names
+// ending in .vtt, their own name for .jpg images (so that we can check which image was saved
+// where), and otherwise a minimal video MP4 file.
+pub async fn serve_manifests(port: u16, manifests: &[(&str, &'static str)]) -> (Handle<SocketAddr>, Requests) {
+    let requests: Requests = Arc::new(Mutex::new(Vec::new()));
+    let mut app = Router::new()
+        .route("/audio/{name}", get(send_audio_segment))
+        .route("/media/{name}", get(send_media_segment));
+    for (path, mpd) in manifests {
+        let mpd = *mpd;
+        app = app.route(path, get(move || async move {
+            ([(header::CONTENT_TYPE, "application/dash+xml")], mpd)
+        }));
+    }
+    let app = app.with_state(requests.clone());
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    let backend_handle = server_handle.clone();
+    let backend = async move {
+        bind(SocketAddr::from(([127, 0, 0, 1], port)))
+            .handle(backend_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    (server_handle, requests)
+}
+
+// The type of each stream in a media file ("video", "audio", "subtitle"), as reported by ffprobe.
+pub fn ffprobe_stream_types(p: &Path) -> Vec<String> {
+    let meta = ffprobe(p).unwrap();
+    meta.streams.iter()
+        .map(|s| s.codec_type.clone().unwrap_or_default())
+        .collect()
+}
+
+// The language of each audio stream in a media file, as reported by ffprobe.
+pub fn ffprobe_audio_languages(p: &Path) -> Vec<String> {
+    let meta = ffprobe(p).unwrap();
+    meta.streams.iter()
+        .filter(|s| s.codec_type.as_deref() == Some("audio"))
+        .map(|s| s.tags.as_ref()
+             .and_then(|t| t.language.clone())
+             .unwrap_or_else(|| String::from("und")))
+        .collect()
+}
+
 
 // Useful ffmpeg recipes: https://github.com/videojs/http-streaming/blob/main/docs/creating-content.md
 //
@@ -276,3 +395,21 @@ pub fn curl(url: &str, output: &Path) -> Result<()> {
         .context("copying reqwest data to file")?;
     Ok(())
 }
+
+
+// Create an executable shell script in `dir` that can stand in for the mp4decrypt application: it
+// records its commandline arguments to a file and copies its input file to its output file. Returns
+// the path to the script and the path to the file where the arguments are recorded.
+#[cfg(unix)]
+pub fn fake_mp4decrypt(dir: &Path) -> Result<(std::path::PathBuf, std::path::PathBuf)> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let argslog = dir.join("mp4decrypt-args.txt");
+    let script_path = dir.join("mp4decrypt");
+    let script = format!("#!/bin/sh\necho \"$@\" > {}\n\
+                          n=$#\nin=$(eval echo \\${{$((n-1))}})\nout=$(eval echo \\${{$n}})\n\
+                          cp \"$in\" \"$out\"\n", argslog.display());
+    fs::write(&script_path, script)?;
+    fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+    Ok((script_path, argslog))
+}
@@ -0,0 +1,75 @@
+//! Tests for ceilings on video height, frame rate and bandwidth, and the total bandwidth budget
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test constraints -- --show-output
+//
+
+pub mod common;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::{DashDownloader, ConstraintFallback};
+use dash_mpd::DashMpdError;
+use common::{serve_manifests, ffprobe_stream_types, setup_logging};
+
+
+#[tokio::test]
+async fn test_constraints_ceilings() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT2S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640028" frameRate="25">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="v480" bandwidth="1000000" width="854" height="480"/>
+      <Representation id="v720" bandwidth="3000000" width="1280" height="720"/>
+      <Representation id="v1080p25" bandwidth="5000000" width="1920" height="1080"/>
+      <Representation id="v1080p50" bandwidth="6000000" width="1920" height="1080" frameRate="50/1"/>
+      <Representation id="v2160" bandwidth="12000000" width="3840" height="2160"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="a128" bandwidth="128000"/>
+      <Representation id="a384" bandwidth="384000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6687, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("constrained.mp4");
+    let url = "http://localhost:6687/mpd";
+    let cases: [(DashDownloader, [&str; 2]); 6] = [
+        (DashDownloader::new(url).best_quality(), ["a384-1.m4s", "v2160-1.m4s"]),
+        (DashDownloader::new(url).best_quality().max_video_height(1080), ["a384-1.m4s", "v1080p50-1.m4s"]),
+        (DashDownloader::new(url).best_quality().max_video_height(1080).max_video_frame_rate(30.0),
+         ["a384-1.m4s", "v1080p25-1.m4s"]),
+        // The audio stream leaves enough of the budget for the lowest bandwidth video stream, and
+        // the video stream is selected within the remaining 5.616 Mbit/s.
+        (DashDownloader::new(url).best_quality().max_total_bandwidth(6_000_000),
+         ["a384-1.m4s", "v1080p25-1.m4s"]),
+        (DashDownloader::new(url).best_quality().max_total_bandwidth(3_200_000).max_audio_bandwidth(200_000),
+         ["a128-1.m4s", "v720-1.m4s"]),
+        // No Representation satisfies the ceiling: the closest is selected.
+        (DashDownloader::new(url).best_quality().max_video_bandwidth(500_000), ["a384-1.m4s", "v480-1.m4s"]),
+    ];
+    for (downloader, expected) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .download_to(&out).await
+            .unwrap();
+        let mut fetched = requests.lock().unwrap().clone();
+        fetched.sort();
+        assert_eq!(fetched, expected);
+        assert_eq!(ffprobe_stream_types(&out), vec!["video", "audio"]);
+    }
+    requests.lock().unwrap().clear();
+    let result = DashDownloader::new(url)
+        .best_quality()
+        .max_video_bandwidth(500_000)
+        .with_constraint_fallback(ConstraintFallback::Error)
+        .download_to(&out).await;
+    assert!(matches!(result, Err(DashMpdError::UnhandledMediaStream(_))));
+    assert!(requests.lock().unwrap().is_empty());
+    server_handle.shutdown();
+}
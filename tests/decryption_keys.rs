@@ -0,0 +1,197 @@
+//! Tests for loading decryption keys from key files and CPIX documents, and for the KeyProvider trait.
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    RUST_LOG=info cargo test --test decryption_keys -- --show-output
+
+
+pub mod common;
+use std::fs;
+use std::env;
+use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use axum::{routing::get, Router};
+use axum::response::{Response, IntoResponse};
+use axum::http::header;
+use axum::body::Body;
+use axum_server::{Handle, bind};
+use dash_mpd::DashMpdError;
+use dash_mpd::keys::{parse_key_file, parse_cpix, parse_document_key};
+use dash_mpd::fetch::{DashDownloader, KeyProvider, KeyFuture};
+use anyhow::Result;
+use common::{generate_minimal_mp4_rust, setup_logging};
+
+
+const DOCUMENT_KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn fixture(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests");
+    path.push("fixtures");
+    path.push(name);
+    path
+}
+
+
+#[test]
+fn test_parse_key_file() {
+    let contents = "# Keys for our test stream\n\
+                    \n\
+                    9eb4050de44b4802932e27d75083e266:166634C675823C235A4A9446FAD52E4D\n\
+                    b7e59dca-5b3e-4a1e-93fd-3c4d1b6c2a10 : 3a2a1b68dd2bd9b2eeb25e84c4776668\n\
+                    2:00112233445566778899aabbccddeeff\n";
+    let keys = parse_key_file(contents).unwrap();
+    assert_eq!(keys.len(), 3);
+    assert_eq!(keys.get("9eb4050de44b4802932e27d75083e266").unwrap(), "166634c675823c235a4a9446fad52e4d");
+    assert_eq!(keys.get("b7e59dca5b3e4a1e93fd3c4d1b6c2a10").unwrap(), "3a2a1b68dd2bd9b2eeb25e84c4776668");
+    assert_eq!(keys.get("2").unwrap(), "00112233445566778899aabbccddeeff");
+
+    assert!(parse_key_file("9eb4050de44b4802932e27d75083e266").is_err());
+    assert!(parse_key_file("9eb4050de44b4802932e27d75083e266:1234").is_err());
+    assert!(parse_key_file("nothex:166634c675823c235a4a9446fad52e4d").is_err());
+}
+
+#[test]
+fn test_parse_cpix_plain() {
+    let xml = fs::read_to_string(fixture("cpix-plain.xml")).unwrap();
+    let keys = parse_cpix(&xml, None).unwrap();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys.get("9eb4050de44b4802932e27d75083e266").unwrap(), "166634c675823c235a4a9446fad52e4d");
+    assert_eq!(keys.get("b7e59dca5b3e4a1e93fd3c4d1b6c2a10").unwrap(), "3a2a1b68dd2bd9b2eeb25e84c4776668");
+}
+
+#[test]
+fn test_parse_cpix_encrypted() {
+    let xml = fs::read_to_string(fixture("cpix-encrypted.xml")).unwrap();
+    assert!(matches!(parse_cpix(&xml, None), Err(DashMpdError::Decrypting(_))));
+    let dk = parse_document_key(DOCUMENT_KEY).unwrap();
+    let keys = parse_cpix(&xml, Some(&dk)).unwrap();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys.get("9eb4050de44b4802932e27d75083e266").unwrap(), "166634c675823c235a4a9446fad52e4d");
+    assert_eq!(keys.get("b7e59dca5b3e4a1e93fd3c4d1b6c2a10").unwrap(), "3a2a1b68dd2bd9b2eeb25e84c4776668");
+    // Decrypting with the wrong document key fails the PKCS#7 padding check.
+    let wrong = parse_document_key(&"11".repeat(32)).unwrap();
+    assert!(matches!(parse_cpix(&xml, Some(&wrong)), Err(DashMpdError::Decrypting(_))));
+    // A plaintext whose last octet is a plausible padding length, but whose other padding octets
+    // are not equal to it, is not valid PKCS#7 padding.
+    let bad_padding = xml.replace("Dw4NDAsKCQgHBgUEAwIBABGLPqQL5rk6oNortwQ54ls9x1OjSatdFHwb6sfBGs8m",
+                                  "Dw4NDAsKCQgHBgUEAwIBABGLPqQL5rk6oNortwQ54lsWs5ylVuMOXYPnv12xIBY1");
+    assert!(matches!(parse_cpix(&bad_padding, Some(&dk)), Err(DashMpdError::Decrypting(_))));
+    assert!(parse_document_key("0011").is_err());
+    assert!(parse_cpix("<MPD/>", None).is_err());
+}
+
+
+struct VaultKeyProvider {
+    keys: HashMap<String, String>,
+    requested: Mutex<Vec<String>>,
+}
+
+impl KeyProvider for VaultKeyProvider {
+    fn keys<'a>(&'a self, kids: &'a [String]) -> KeyFuture<'a> {
+        Box::pin(async move {
+            self.requested.lock().unwrap().extend(kids.iter().cloned());
+            Ok(kids.iter()
+               .filter_map(|kid| self.keys.get(kid).map(|k| (kid.clone(), k.clone())))
+               .collect())
+        })
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_key_sources() -> Result<()> {
+    use common::fake_mp4decrypt;
+
+    async fn send_mpd() -> impl IntoResponse {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" xmlns:cenc="urn:mpeg:cenc:2013"
+     type="static" mediaPresentationDuration="PT5S" minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-on-demand:2011">
+  <Period id="1" duration="PT5S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4">
+      <ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc"
+                         cenc:default_KID="9EB4050D-E44B-4802-932E-27D75083E266"/>
+      <Representation id="1" codecs="avc1.640028" width="1920" height="800" bandwidth="1980081">
+        <SegmentTemplate initialization="/media/init.mp4"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        ([(header::CONTENT_TYPE, "application/dash+xml")], xml)
+    }
+
+    async fn send_mp4() -> Response {
+        Response::builder()
+            .status(axum::http::StatusCode::OK)
+            .header(header::CONTENT_TYPE, "video/mp4")
+            .body(Body::from(generate_minimal_mp4_rust()))
+            .unwrap()
+    }
+
+    setup_logging();
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/media/{seg}", get(send_mp4));
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    let backend_handle = server_handle.clone();
+    let backend = async move {
+        bind("127.0.0.1:6671".parse().unwrap())
+            .handle(backend_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let tmpd = tempfile::tempdir()?;
+    let (mp4decrypt, argslog) = fake_mp4decrypt(tmpd.path())?;
+    let expected_key = "--key 9eb4050de44b4802932e27d75083e266:166634c675823c235a4a9446fad52e4d";
+
+    // Keys from a KID:KEY file
+    let keyfile = tmpd.path().join("keys.txt");
+    fs::write(&keyfile, "9eb4050de44b4802932e27d75083e266:166634c675823c235a4a9446fad52e4d\n")?;
+    let outpath = env::temp_dir().join("decryption-keyfile.mp4");
+    DashDownloader::new("http://localhost:6671/mpd")
+        .with_mp4decrypt(&mp4decrypt.to_string_lossy())
+        .add_decryption_keys_from_file(&keyfile)
+        .download_to(&outpath).await
+        .unwrap();
+    assert!(fs::read_to_string(&argslog)?.contains(expected_key));
+    fs::remove_file(&argslog)?;
+
+    // Keys from an encrypted CPIX document
+    let outpath = env::temp_dir().join("decryption-cpix.mp4");
+    DashDownloader::new("http://localhost:6671/mpd")
+        .with_mp4decrypt(&mp4decrypt.to_string_lossy())
+        .add_decryption_keys_from_cpix(fixture("cpix-encrypted.xml"), Some(DOCUMENT_KEY))
+        .download_to(&outpath).await
+        .unwrap();
+    assert!(fs::read_to_string(&argslog)?.contains(expected_key));
+    fs::remove_file(&argslog)?;
+
+    // A missing key file causes the download to fail.
+    let outpath = env::temp_dir().join("decryption-missing.mp4");
+    let res = DashDownloader::new("http://localhost:6671/mpd")
+        .add_decryption_keys_from_file(tmpd.path().join("nonexistent.txt"))
+        .download_to(&outpath).await;
+    assert!(matches!(res, Err(DashMpdError::Io(_, _))));
+
+    // Keys from a KeyProvider, which is called with the KIDs discovered in the manifest.
+    let mut vault = HashMap::new();
+    vault.insert(String::from("9eb4050de44b4802932e27d75083e266"),
+                 String::from("166634c675823c235a4a9446fad52e4d"));
+    let provider = Arc::new(VaultKeyProvider { keys: vault, requested: Mutex::new(Vec::new()) });
+    let outpath = env::temp_dir().join("decryption-provider.mp4");
+    DashDownloader::new("http://localhost:6671/mpd")
+        .with_mp4decrypt(&mp4decrypt.to_string_lossy())
+        .with_key_provider(provider.clone())
+        .download_to(&outpath).await
+        .unwrap();
+    assert_eq!(*provider.requested.lock().unwrap(), vec![String::from("9eb4050de44b4802932e27d75083e266")]);
+    assert!(fs::read_to_string(&argslog)?.contains(expected_key));
+    server_handle.shutdown();
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Tests for video stream selection according to dynamic range (SDR, HDR10, HLG, Dolby Vision)
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test dynamic_range -- --show-output
+//
+
+pub mod common;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use dash_mpd::{DashMpdError, VideoDynamicRange};
+use common::{serve_manifests, ffprobe_stream_types, setup_logging};
+
+
+#[tokio::test]
+async fn test_dynamic_range_selection() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" xmlns:scte214="urn:scte:dash:scte214-extensions"
+     type="static" mediaPresentationDuration="PT2S" minBufferTime="PT2S"
+     profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="sdr" bandwidth="2000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="video" mimeType="video/mp4" codecs="hvc1.2.4.L150.90">
+      <EssentialProperty schemeIdUri="urn:mpeg:mpegB:cicp:TransferCharacteristics" value="18"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="hlg" bandwidth="4000000" width="3840" height="2160"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="video" mimeType="video/mp4" codecs="hvc1.2.4.L150.90">
+      <SupplementalProperty schemeIdUri="urn:mpeg:mpegB:cicp:ColourPrimaries" value="9"/>
+      <SupplementalProperty schemeIdUri="urn:mpeg:mpegB:cicp:TransferCharacteristics" value="16"/>
+      <SupplementalProperty schemeIdUri="urn:mpeg:mpegB:cicp:MatrixCoefficients" value="9"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="hdr10" bandwidth="5000000" width="3840" height="2160"/>
+      <Representation id="hdr10dv8" bandwidth="6000000" width="3840" height="2160" scte214:supplementalCodecs="dvh1.08.06"/>
+    </AdaptationSet>
+    <AdaptationSet id="4" contentType="video" mimeType="video/mp4" codecs="dvh1.05.06">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="dv5" bandwidth="8000000" width="3840" height="2160"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    // A manifest offering only HDR10 content.
+    const MPD_PQ: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT2S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="hvc1.2.4.L150.90">
+      <SupplementalProperty schemeIdUri="urn:mpeg:mpegB:cicp:TransferCharacteristics" value="16"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="hdr10" bandwidth="5000000" width="3840" height="2160"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6689, &[("/mpd", MPD), ("/mpd-pq", MPD_PQ)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("hdr.mp4");
+    let url = "http://localhost:6689/mpd";
+    let cases: [(DashDownloader, &str); 7] = [
+        (DashDownloader::new(url).best_quality(), "dv5-1.m4s"),
+        (DashDownloader::new(url).best_quality().sdr_video_only(), "sdr-1.m4s"),
+        (DashDownloader::new(url).best_quality().prefer_video_dynamic_ranges(vec![VideoDynamicRange::Hlg]), "hlg-1.m4s"),
+        (DashDownloader::new(url).worst_quality().prefer_video_dynamic_ranges(vec![VideoDynamicRange::Hdr10]), "hdr10-1.m4s"),
+        // The Dolby Vision profile 8 enhancement is signalled by scte214:supplementalCodecs.
+        (DashDownloader::new(url).worst_quality()
+         .prefer_video_dynamic_ranges(vec![VideoDynamicRange::DolbyVision(Some(8)), VideoDynamicRange::Sdr]),
+         "hdr10dv8-1.m4s"),
+        (DashDownloader::new(url).best_quality()
+         .exclude_video_dynamic_ranges(vec![VideoDynamicRange::DolbyVision(Some(5))]), "hdr10dv8-1.m4s"),
+        // A preference that no stream matches doesn't filter anything out.
+        (DashDownloader::new(url).worst_quality()
+         .prefer_video_dynamic_ranges(vec![VideoDynamicRange::DolbyVision(Some(7))]), "sdr-1.m4s"),
+    ];
+    for (downloader, expected) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .video_only()
+            .download_to(&out).await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap().clone(), vec![expected]);
+        assert_eq!(ffprobe_stream_types(&out), vec!["video"]);
+    }
+    requests.lock().unwrap().clear();
+    let result = DashDownloader::new("http://localhost:6689/mpd-pq")
+        .sdr_video_only()
+        .download_to(&out).await;
+    assert!(matches!(result, Err(DashMpdError::UnhandledMediaStream(_))));
+    assert!(requests.lock().unwrap().is_empty());
+    server_handle.shutdown();
+}
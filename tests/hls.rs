@@ -0,0 +1,378 @@
+//! Tests for the conversion of DASH manifests to HLS playlists
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test hls -- --show-output
+//
+
+pub mod common;
+use pretty_assertions::assert_eq;
+use dash_mpd::parse;
+use dash_mpd::hls::{HlsConverter, media_playlist_name};
+use common::setup_logging;
+
+
+const MPD_URL: &str = "https://example.com/content/manifest.mpd";
+
+// The lines of playlist that are segment URIs or tags with the given prefix.
+fn playlist_lines<'a>(playlist: &'a str, prefix: &str) -> Vec<&'a str> {
+    playlist.lines().filter(|l| l.starts_with(prefix)).collect()
+}
+
+
+#[test]
+fn test_hls_multivariant() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT8S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1">
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028" frameRate="30000/1001">
+      <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+                       media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="v1080" bandwidth="4000000" width="1920" height="1080"/>
+      <Representation id="v720" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <EssentialProperty schemeIdUri="http://dashif.org/guidelines/trickmode" value="1"/>
+      <SegmentTemplate timescale="1000" duration="2000" media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="trick" bandwidth="300000" width="320" height="180"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="2"/>
+      <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+                       media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="a-en-lo" bandwidth="64000"/>
+      <Representation id="a-en" bandwidth="128000"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="fr">
+      <Label>Français</Label>
+      <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+                       media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="a-fr" bandwidth="96000"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <Accessibility schemeIdUri="urn:mpeg:dash:role:2011" value="description"/>
+      <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+                       media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="a-en-ad" bandwidth="96000"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="ec-3" lang="en">
+      <AudioChannelConfiguration schemeIdUri="tag:dolby.com,2014:dash:audio_channel_configuration:2011" value="F801"/>
+      <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+                       media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="a-en-ec3" bandwidth="384000"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="text" mimeType="text/vtt" lang="en">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="forced-subtitle"/>
+      <Representation id="s-en-forced" bandwidth="1000">
+        <BaseURL>subs/en-forced.vtt</BaseURL>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="text" mimeType="application/mp4" codecs="stpp" lang="de">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="caption"/>
+      <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+                       media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="s-de" bandwidth="2000"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="text" mimeType="application/ttml+xml" lang="es">
+      <Representation id="s-es" bandwidth="1000">
+        <BaseURL>subs/es.ttml</BaseURL>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    setup_logging();
+    let mpd = parse(xml).unwrap();
+    let converter = HlsConverter::new(&mpd, MPD_URL).unwrap();
+    let multivariant = converter.multivariant_playlist().unwrap();
+    let expected = r#"#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="audio-mp4a.40.2",NAME="en",LANGUAGE="en",DEFAULT=YES,AUTOSELECT=YES,CHANNELS="2",URI="a-en.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="audio-mp4a.40.2",NAME="Français",LANGUAGE="fr",DEFAULT=NO,AUTOSELECT=YES,URI="a-fr.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="audio-mp4a.40.2",NAME="en (2)",LANGUAGE="en",DEFAULT=NO,AUTOSELECT=YES,CHARACTERISTICS="public.accessibility.describes-video",URI="a-en-ad.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="audio-ec-3",NAME="en",LANGUAGE="en",DEFAULT=YES,AUTOSELECT=YES,CHANNELS="6",URI="a-en-ec3.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="en",LANGUAGE="en",DEFAULT=NO,AUTOSELECT=YES,FORCED=YES,URI="s-en-forced.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="de",LANGUAGE="de",DEFAULT=NO,AUTOSELECT=YES,FORCED=NO,CHARACTERISTICS="public.accessibility.transcribes-spoken-dialog,public.accessibility.describes-music-and-sound",URI="s-de.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=4128000,CODECS="avc1.640028,mp4a.40.2,stpp.ttml.im1t",RESOLUTION=1920x1080,FRAME-RATE=29.970,VIDEO-RANGE=SDR,AUDIO="audio-mp4a.40.2",SUBTITLES="subs"
+v1080.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=4384000,CODECS="avc1.640028,ec-3,stpp.ttml.im1t",RESOLUTION=1920x1080,FRAME-RATE=29.970,VIDEO-RANGE=SDR,AUDIO="audio-ec-3",SUBTITLES="subs"
+v1080.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2128000,CODECS="avc1.640028,mp4a.40.2,stpp.ttml.im1t",RESOLUTION=1280x720,FRAME-RATE=29.970,VIDEO-RANGE=SDR,AUDIO="audio-mp4a.40.2",SUBTITLES="subs"
+v720.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2384000,CODECS="avc1.640028,ec-3,stpp.ttml.im1t",RESOLUTION=1280x720,FRAME-RATE=29.970,VIDEO-RANGE=SDR,AUDIO="audio-ec-3",SUBTITLES="subs"
+v720.m3u8
+"#;
+    assert_eq!(multivariant, expected);
+
+    let names: Vec<String> = converter.media_playlists().unwrap()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, vec!["v1080.m3u8", "v720.m3u8", "a-en.m3u8", "a-fr.m3u8", "a-en-ad.m3u8",
+                           "a-en-ec3.m3u8", "s-en-forced.m3u8", "s-de.m3u8"]);
+    let subs = converter.media_playlist("s-en-forced").unwrap();
+    assert_eq!(playlist_lines(&subs, "https://"), vec!["https://example.com/content/subs/en-forced.vtt"]);
+    assert_eq!(playlist_lines(&subs, "#EXTINF"), vec!["#EXTINF:8.000,"]);
+    assert!(playlist_lines(&subs, "#EXT-X-MAP").is_empty());
+    assert_eq!(media_playlist_name("video/1080 p"), "video_1080_p.m3u8");
+    assert!(converter.media_playlist("missing").is_err());
+}
+
+#[test]
+fn test_hls_segment_template() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT7S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <BaseURL>https://cdn.example.net/vod/</BaseURL>
+  <Period id="1">
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="90000" duration="180000" startNumber="5" initialization="$RepresentationID$-init.mp4"
+                       media="$RepresentationID$-$Number%04d$.m4s"/>
+      <Representation id="v1" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <SegmentTemplate timescale="48000" initialization="audio/$Bandwidth$/init.mp4" media="audio/$Bandwidth$/$Time$.m4s">
+        <SegmentTimeline>
+          <S t="0" d="96000" r="1"/>
+          <S d="48000" r="-1"/>
+        </SegmentTimeline>
+      </SegmentTemplate>
+      <Representation id="a1" bandwidth="128000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    setup_logging();
+    let mpd = parse(xml).unwrap();
+    let converter = HlsConverter::new(&mpd, MPD_URL).unwrap();
+    let video = converter.media_playlist("v1").unwrap();
+    let expected = r#"#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:2
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MAP:URI="https://cdn.example.net/vod/v1-init.mp4"
+#EXTINF:2.000,
+https://cdn.example.net/vod/v1-0005.m4s
+#EXTINF:2.000,
+https://cdn.example.net/vod/v1-0006.m4s
+#EXTINF:2.000,
+https://cdn.example.net/vod/v1-0007.m4s
+#EXTINF:1.000,
+https://cdn.example.net/vod/v1-0008.m4s
+#EXT-X-ENDLIST
+"#;
+    assert_eq!(video, expected);
+
+    // The last S element is repeated until the end of the Period.
+    let audio = converter.media_playlist("a1").unwrap();
+    assert_eq!(playlist_lines(&audio, "#EXT-X-MAP"),
+               vec![r#"#EXT-X-MAP:URI="https://cdn.example.net/vod/audio/128000/init.mp4""#]);
+    assert_eq!(playlist_lines(&audio, "https://"),
+               vec!["https://cdn.example.net/vod/audio/128000/0.m4s",
+                    "https://cdn.example.net/vod/audio/128000/96000.m4s",
+                    "https://cdn.example.net/vod/audio/128000/192000.m4s",
+                    "https://cdn.example.net/vod/audio/128000/240000.m4s",
+                    "https://cdn.example.net/vod/audio/128000/288000.m4s"]);
+    assert_eq!(playlist_lines(&audio, "#EXTINF"),
+               vec!["#EXTINF:2.000,", "#EXTINF:2.000,", "#EXTINF:1.000,", "#EXTINF:1.000,", "#EXTINF:1.000,"]);
+}
+
+#[test]
+fn test_hls_segment_list() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT6S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:full:2011">
+  <Period id="1">
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <Representation id="v1" bandwidth="2000000" width="1280" height="720">
+        <BaseURL>media/video.mp4</BaseURL>
+        <SegmentList timescale="1000" duration="3000">
+          <Initialization range="0-799"/>
+          <SegmentURL mediaRange="800-10799"/>
+          <SegmentURL mediaRange="10800-19999"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    setup_logging();
+    let mpd = parse(xml).unwrap();
+    let converter = HlsConverter::new(&mpd, MPD_URL).unwrap();
+    let video = converter.media_playlist("v1").unwrap();
+    assert_eq!(playlist_lines(&video, "#EXT-X-MAP"),
+               vec![r#"#EXT-X-MAP:URI="https://example.com/content/media/video.mp4",BYTERANGE="800@0""#]);
+    assert_eq!(playlist_lines(&video, "#EXT-X-BYTERANGE"),
+               vec!["#EXT-X-BYTERANGE:10000@800", "#EXT-X-BYTERANGE:9200@10800"]);
+    assert_eq!(playlist_lines(&video, "#EXTINF"), vec!["#EXTINF:3.000,", "#EXTINF:3.000,"]);
+    assert_eq!(playlist_lines(&video, "https://").len(), 2);
+}
+
+// An sidx box (version 0) with references of the given (size, duration).
+fn sidx_box(timescale: u32, first_offset: u32, references: &[(u32, u32)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&[0, 0, 0, 0]);
+    body.extend_from_slice(&1u32.to_be_bytes());
+    body.extend_from_slice(&timescale.to_be_bytes());
+    body.extend_from_slice(&0u32.to_be_bytes());
+    body.extend_from_slice(&first_offset.to_be_bytes());
+    body.extend_from_slice(&0u16.to_be_bytes());
+    body.extend_from_slice(&u16::try_from(references.len()).unwrap().to_be_bytes());
+    for (size, duration) in references {
+        body.extend_from_slice(&size.to_be_bytes());
+        body.extend_from_slice(&duration.to_be_bytes());
+        body.extend_from_slice(&0x9000_0000u32.to_be_bytes());
+    }
+    let mut sidx = u32::try_from(body.len() + 8).unwrap().to_be_bytes().to_vec();
+    sidx.extend_from_slice(b"sidx");
+    sidx.extend_from_slice(&body);
+    sidx
+}
+
+#[test]
+fn test_hls_segment_base() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT9S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-on-demand:2011">
+  <Period id="1">
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <Representation id="a1" bandwidth="128000">
+        <BaseURL>audio.mp4</BaseURL>
+        <SegmentBase indexRange="700-799">
+          <Initialization range="0-699"/>
+        </SegmentBase>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    setup_logging();
+    let mpd = parse(xml).unwrap();
+    let converter = HlsConverter::new(&mpd, MPD_URL).unwrap();
+    // The segment index is required to convert SegmentBase addressing.
+    assert!(converter.media_playlist("a1").is_err());
+    let converter = converter
+        .with_segment_index(0, "a1", sidx_box(48000, 20, &[(5000, 192_000), (4000, 192_000), (2500, 48000)]));
+    let audio = converter.media_playlist("a1").unwrap();
+    let expected = r#"#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:4
+#EXT-X-PLAYLIST-TYPE:VOD
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-MAP:URI="https://example.com/content/audio.mp4",BYTERANGE="700@0"
+#EXTINF:4.000,
+#EXT-X-BYTERANGE:5000@820
+https://example.com/content/audio.mp4
+#EXTINF:4.000,
+#EXT-X-BYTERANGE:4000@5820
+https://example.com/content/audio.mp4
+#EXTINF:1.000,
+#EXT-X-BYTERANGE:2500@9820
+https://example.com/content/audio.mp4
+#EXT-X-ENDLIST
+"#;
+    assert_eq!(audio, expected);
+}
+
+#[test]
+fn test_hls_content_protection() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" xmlns:cenc="urn:mpeg:cenc:2013"
+     xmlns:mspr="urn:microsoft:playready" xmlns:dashif="https://dashif.org/CPS"
+     type="static" mediaPresentationDuration="PT4S" minBufferTime="PT2S"
+     profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1">
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cbcs"
+                         cenc:default_KID="10000000-1000-1000-1000-100000000001"/>
+      <ContentProtection schemeIdUri="urn:uuid:EDEF8BA9-79D6-4ACE-A3C8-27DCD51D21ED">
+        <cenc:pssh>AAAANHBzc2gAAAAA7e+LqXnWSs6jyCfc1R0h7QAAABQIARIQEAAAABAAEAAQABAAAAAAAQ==</cenc:pssh>
+      </ContentProtection>
+      <ContentProtection schemeIdUri="urn:uuid:9a04f079-9840-4286-ab92-e65be0885f95">
+        <mspr:pro>cAEAAAEAAQBmATwAVwBSAE0A</mspr:pro>
+      </ContentProtection>
+      <ContentProtection schemeIdUri="urn:uuid:94ce86fb-07ff-4f43-adb8-93d2fa968ca9">
+        <dashif:laurl>skd://key.example.com/10000000100010001000100000000001</dashif:laurl>
+      </ContentProtection>
+      <SegmentTemplate timescale="1000" duration="2000" initialization="$RepresentationID$/init.mp4"
+                       media="$RepresentationID$/$Number$.m4s"/>
+      <Representation id="v1" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    setup_logging();
+    let mpd = parse(xml).unwrap();
+    let converter = HlsConverter::new(&mpd, MPD_URL).unwrap();
+    let video = converter.media_playlist("v1").unwrap();
+    let keys = playlist_lines(&video, "#EXT-X-KEY");
+    assert_eq!(keys, vec![
+        concat!(r#"#EXT-X-KEY:METHOD=SAMPLE-AES,URI="data:text/plain;base64,AAAANHBzc2gAAAAA7e+LqXnWSs6jyCfc1R0h7QAAABQIARIQEAAAABAAEAAQABAAAAAAAQ==","#,
+                r#"KEYID=0x10000000100010001000100000000001,KEYFORMAT="urn:uuid:edef8ba9-79d6-4ace-a3c8-27dcd51d21ed",KEYFORMATVERSIONS="1""#),
+        concat!(r#"#EXT-X-KEY:METHOD=SAMPLE-AES,URI="data:text/plain;charset=UTF-16;base64,cAEAAAEAAQBmATwAVwBSAE0A","#,
+                r#"KEYID=0x10000000100010001000100000000001,KEYFORMAT="com.microsoft.playready",KEYFORMATVERSIONS="1""#),
+        concat!(r#"#EXT-X-KEY:METHOD=SAMPLE-AES,URI="skd://key.example.com/10000000100010001000100000000001","#,
+                r#"KEYID=0x10000000100010001000100000000001,KEYFORMAT="com.apple.streamingkeydelivery",KEYFORMATVERSIONS="1""#),
+    ]);
+    // The keys precede the initialization segment.
+    let key_pos = video.find("#EXT-X-KEY").unwrap();
+    let map_pos = video.find("#EXT-X-MAP").unwrap();
+    assert!(key_pos < map_pos);
+    let multivariant = converter.multivariant_playlist().unwrap();
+    let session_keys = playlist_lines(&multivariant, "#EXT-X-SESSION-KEY");
+    assert_eq!(session_keys.len(), 3);
+    assert!(session_keys[0].ends_with(&keys[0]["#EXT-X-KEY".len()..]));
+
+    // With the cenc protection scheme, FairPlay can't be used.
+    let cenc = xml.replace(r#"value="cbcs""#, r#"value="cenc""#);
+    let mpd = parse(&cenc).unwrap();
+    let video = HlsConverter::new(&mpd, MPD_URL).unwrap().media_playlist("v1").unwrap();
+    let keys = playlist_lines(&video, "#EXT-X-KEY");
+    assert_eq!(keys.len(), 2);
+    assert!(keys.iter().all(|k| k.starts_with("#EXT-X-KEY:METHOD=SAMPLE-AES-CTR,")));
+}
+
+#[test]
+fn test_hls_multiperiod() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT8S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="main1" duration="PT4S">
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="1000" duration="2000" initialization="main/$RepresentationID$/init.mp4"
+                       media="main/$RepresentationID$/$Number$.m4s"/>
+      <Representation id="v1" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+  </Period>
+  <Period id="ad" duration="PT2S">
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="1000" duration="2000" initialization="ad/$RepresentationID$/init.mp4"
+                       media="ad/$RepresentationID$/$Number$.m4s"/>
+      <Representation id="v1" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+  </Period>
+  <Period id="main2" duration="PT2S">
+    <AdaptationSet contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="3" initialization="main/$RepresentationID$/init.mp4"
+                       media="main/$RepresentationID$/$Number$.m4s"/>
+      <Representation id="v1" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+    setup_logging();
+    let mpd = parse(xml).unwrap();
+    let converter = HlsConverter::new(&mpd, MPD_URL).unwrap();
+    let video = converter.media_playlist("v1").unwrap();
+    let tags: Vec<&str> = video.lines()
+        .filter(|l| l.starts_with("#EXT-X-MAP") || l.starts_with("#EXT-X-DISCONTINUITY") || l.starts_with("https://"))
+        .collect();
+    assert_eq!(tags, vec![
+        r#"#EXT-X-MAP:URI="https://example.com/content/main/v1/init.mp4""#,
+        "https://example.com/content/main/v1/1.m4s",
+        "https://example.com/content/main/v1/2.m4s",
+        "#EXT-X-DISCONTINUITY",
+        r#"#EXT-X-MAP:URI="https://example.com/content/ad/v1/init.mp4""#,
+        "https://example.com/content/ad/v1/1.m4s",
+        "#EXT-X-DISCONTINUITY",
+        r#"#EXT-X-MAP:URI="https://example.com/content/main/v1/init.mp4""#,
+        "https://example.com/content/main/v1/3.m4s",
+    ]);
+}
@@ -9,11 +9,19 @@ pub mod common;
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::net::SocketAddr;
+use std::time::Duration;
 use ffprobe::ffprobe;
 use file_format::FileFormat;
+use axum::{routing::get, Router};
+use axum::extract::Path as AxumPath;
+use axum::response::IntoResponse;
+use axum::http::{header, StatusCode};
+use axum_server::{Handle, bind};
 use pretty_assertions::assert_eq;
 use dash_mpd::fetch::DashDownloader;
 use common::{check_file_size_approx, check_media_duration, setup_logging};
+use common::{serve_manifests, ffprobe_stream_types, ffprobe_audio_languages};
 
 
 
@@ -218,3 +226,271 @@ async fn test_lang_en_multilang() {
     assert_eq!(count, 1, "Expecting a single output file, got {count}");
     let _ = fs::remove_dir_all(tmpd);
 }
+
+
+// Download two of the audio languages available in this manifest, and check that both audio tracks
+// are present in the output file with their language tags.
+#[tokio::test]
+async fn test_lang_multiple_audio() {
+    setup_logging();
+    if env::var("CI").is_ok() {
+        return;
+    }
+    let mpd_url = "https://refapp.hbbtv.org/videos/02_gran_dillama_1080p_ma_25f75g6sv5/manifest.mpd";
+    let out = env::temp_dir().join("dillama-spa-chi.mp4");
+    if out.exists() {
+        let _ = fs::remove_file(&out);
+    }
+    DashDownloader::new(mpd_url)
+        .worst_quality()
+        .sandbox(true)
+        .content_type_checks(false)
+        .max_error_count(5)
+        .prefer_audio_languages(vec!["spa", "chi"])
+        .download_to(&out).await
+        .unwrap();
+    let meta = ffprobe(&out).unwrap();
+    assert_eq!(meta.streams.len(), 3);
+    let languages: Vec<Option<String>> = meta.streams.iter()
+        .filter(|s| s.codec_type.eq(&Some(String::from("audio"))))
+        .map(|s| s.tags.as_ref().and_then(|t| t.language.clone()))
+        .collect();
+    assert_eq!(languages, vec![Some(String::from("spa")), Some(String::from("chi"))]);
+    let _ = fs::remove_file(out);
+}
+
+
+// A manifest with audio in three languages. We check which audio segments are requested when
+// selecting several audio languages, that each audio track is kept in a separate file, and that the
+// audio tracks are muxed into the output file in order of preference.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_lang_select_multiple_audio() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT4S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT4S">
+    <AdaptationSet id="1" contentType="audio" mimeType="audio/mp4" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/en-$Number$.m4s"/>
+      <Representation id="en" codecs="mp4a.40.2" bandwidth="64000"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="audio" mimeType="audio/mp4" lang="fr-CA">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/fr-$Number$.m4s"/>
+      <Representation id="fr" codecs="mp4a.40.2" bandwidth="64000"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="audio" mimeType="audio/mp4" lang="de">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/de-$Number$.m4s"/>
+      <Representation id="de" codecs="mp4a.40.2" bandwidth="64000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6675, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("multiaudio.mp4");
+    let keep = tmpd.path().join("audio.mp4");
+    DashDownloader::new("http://localhost:6675/mpd")
+        .fetch_video(false)
+        .prefer_audio_languages(vec!["de", "fr", "it"])
+        .keep_audio_as(&keep)
+        .download_to(&out).await
+        .unwrap();
+    let mut fetched = requests.lock().unwrap().clone();
+    fetched.sort();
+    assert_eq!(fetched, vec!["de-1.m4s", "de-2.m4s", "fr-1.m4s", "fr-2.m4s"]);
+    assert_eq!(ffprobe_audio_languages(&out), vec!["deu", "fra"]);
+    // The first preferred language is saved to the keep_audio_as path, the others alongside it.
+    assert!(keep.exists());
+    assert!(tmpd.path().join("audio.fr.mp4").exists());
+
+    requests.lock().unwrap().clear();
+    let out = tmpd.path().join("allaudio.mp4");
+    DashDownloader::new("http://localhost:6675/mpd")
+        .fetch_video(false)
+        .fetch_all_audio_tracks(true)
+        .prefer_audio_language(String::from("fr"))
+        .keep_audio_as(&keep)
+        .download_to(&out).await
+        .unwrap();
+    assert_eq!(requests.lock().unwrap().len(), 6);
+    // The track in the preferred language comes first, then the others in manifest order.
+    assert_eq!(ffprobe_audio_languages(&out), vec!["fra", "eng", "deu"]);
+    assert!(tmpd.path().join("audio.en.mp4").exists());
+    assert!(tmpd.path().join("audio.de.mp4").exists());
+    server_handle.shutdown();
+}
+
+
+// A manifest with WebVTT subtitles in several languages, including forced subtitles (identified by
+// their Role) and subtitles for the hard of hearing (identified by an Accessibility descriptor), and
+// a TTML sidecar file. We check that each selected subtitle track is saved to sidecar files named
+// with its language and kind.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_lang_select_multiple_subtitles() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT4S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT4S">
+    <AdaptationSet id="1" contentType="text" mimeType="text/vtt" lang="en">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="subtitle"/>
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1" media="/subs/en-$Number$.vtt"/>
+      <Representation id="en" bandwidth="1000"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="text" mimeType="text/vtt" lang="fr">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="forced-subtitle"/>
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1" media="/subs/fr-forced-$Number$.vtt"/>
+      <Representation id="fr-forced" bandwidth="1000"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="text" mimeType="text/vtt" lang="en-US">
+      <Accessibility schemeIdUri="urn:tva:metadata:cs:AudioPurposeCS:2007" value="2"/>
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="subtitle"/>
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="1" media="/subs/en-sdh-$Number$.vtt"/>
+      <Representation id="en-sdh" bandwidth="1000"/>
+    </AdaptationSet>
+    <AdaptationSet id="4" contentType="text" mimeType="application/ttml+xml" lang="de">
+      <Representation id="de" bandwidth="1000">
+        <BaseURL>/subs/de.ttml</BaseURL>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    const TTML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tt xmlns="http://www.w3.org/ns/ttml" xml:lang="de">
+  <body><div><p begin="00:00:01.000" end="00:00:02.000">Hallo</p></div></body>
+</tt>"#;
+
+    async fn send_mpd() -> impl IntoResponse {
+        ([(header::CONTENT_TYPE, "application/dash+xml")], MPD)
+    }
+
+    async fn send_subtitles(AxumPath(name): AxumPath<String>) -> impl IntoResponse {
+        if name.eq("de.ttml") {
+            return ([(header::CONTENT_TYPE, "application/ttml+xml")], String::from(TTML)).into_response();
+        }
+        let Some(track) = name.strip_suffix("-1.vtt") else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let vtt = format!("WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nSubtitle {track}\n");
+        ([(header::CONTENT_TYPE, "text/vtt")], vtt).into_response()
+    }
+
+    setup_logging();
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/subs/{name}", get(send_subtitles));
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    let backend_handle = server_handle.clone();
+    let backend = async move {
+        bind("127.0.0.1:6676".parse().unwrap())
+            .handle(backend_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("movie.mp4");
+    DashDownloader::new("http://localhost:6676/mpd")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .prefer_subtitle_languages(vec!["fr", "en"])
+        .download_to(&out).await
+        .unwrap();
+    let en = fs::read_to_string(tmpd.path().join("movie.en.vtt")).unwrap();
+    assert!(en.contains("Subtitle en\n"));
+    let forced = fs::read_to_string(tmpd.path().join("movie.fr.forced.vtt")).unwrap();
+    assert!(forced.contains("Subtitle fr-forced\n"));
+    let sdh = fs::read_to_string(tmpd.path().join("movie.en-US.sdh.vtt")).unwrap();
+    assert!(sdh.contains("Subtitle en-sdh\n"));
+    let srt = fs::read_to_string(tmpd.path().join("movie.en-US.sdh.srt")).unwrap();
+    assert!(srt.contains("00:00:01,000 --> 00:00:02,000\nSubtitle en-sdh\n"));
+    assert!(!tmpd.path().join("movie.vtt").exists());
+    assert!(!tmpd.path().join("movie.de.ttml").exists());
+
+    // All subtitle tracks, including the TTML sidecar file.
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("movie.mkv");
+    DashDownloader::new("http://localhost:6676/mpd")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .fetch_all_subtitle_tracks(true)
+        .download_to(&out).await
+        .unwrap();
+    assert!(tmpd.path().join("movie.en.vtt").exists());
+    assert!(tmpd.path().join("movie.fr.forced.vtt").exists());
+    assert!(tmpd.path().join("movie.en-US.sdh.vtt").exists());
+    let ttml = fs::read_to_string(tmpd.path().join("movie.de.ttml")).unwrap();
+    assert!(ttml.contains("Hallo"));
+
+    // With a single language preference, only one subtitle track is downloaded, to a sidecar file
+    // named like the output file.
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("movie.mp4");
+    DashDownloader::new("http://localhost:6676/mpd")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .prefer_subtitle_language(String::from("fr"))
+        .download_to(&out).await
+        .unwrap();
+    let vtt = fs::read_to_string(tmpd.path().join("movie.vtt")).unwrap();
+    assert!(vtt.contains("Subtitle fr-forced\n"));
+    assert!(!tmpd.path().join("movie.fr.forced.vtt").exists());
+    server_handle.shutdown();
+}
+
+
+// Language preferences are matched after mapping between ISO 639-1 and ISO 639-2 codes, and fall
+// back from a specific preference such as "zh-Hant-TW" to "zh-Hant" following RFC 4647 lookup.
+#[tokio::test]
+async fn test_lang_bcp47_lookup() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT2S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="audio" mimeType="audio/mp4" lang="eng">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/eng-$Number$.m4s"/>
+      <Representation id="eng" codecs="mp4a.40.2" bandwidth="64000"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="audio" mimeType="audio/mp4" lang="de">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/de-$Number$.m4s"/>
+      <Representation id="de" codecs="mp4a.40.2" bandwidth="64000"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="audio" mimeType="audio/mp4" lang="zh-Hant">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/zh-$Number$.m4s"/>
+      <Representation id="zh" codecs="mp4a.40.2" bandwidth="64000"/>
+    </AdaptationSet>
+    <AdaptationSet id="4" contentType="audio" mimeType="audio/mp4" lang="pt-BR">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/pt-$Number$.m4s"/>
+      <Representation id="pt" codecs="mp4a.40.2" bandwidth="64000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6685, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("lookup.mp4");
+    let cases: [(DashDownloader, &str); 5] = [
+        (DashDownloader::new("http://localhost:6685/mpd").prefer_audio_language(String::from("en")), "eng-1.m4s"),
+        (DashDownloader::new("http://localhost:6685/mpd").prefer_audio_language(String::from("ger")), "de-1.m4s"),
+        (DashDownloader::new("http://localhost:6685/mpd").prefer_language(String::from("zh-Hant-TW,en")), "zh-1.m4s"),
+        (DashDownloader::new("http://localhost:6685/mpd").prefer_languages(vec!["it", "por"]), "pt-1.m4s"),
+        // None of the languages is available: the first audio stream in the manifest is selected.
+        (DashDownloader::new("http://localhost:6685/mpd").prefer_audio_language(String::from("ja")), "eng-1.m4s"),
+    ];
+    for (downloader, expected) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .fetch_video(false)
+            .download_to(&out).await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap().clone(), vec![expected]);
+        assert_eq!(ffprobe_stream_types(&out), vec!["audio"]);
+    }
+    server_handle.shutdown();
+}
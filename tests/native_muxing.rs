@@ -0,0 +1,626 @@
+// Tests for the native fragmented MP4 muxer.
+//
+// These tests use synthetic CMAF streams (an H.264 video stream and two AAC audio streams, whose
+// samples contain arbitrary data), generated with the mp4-atom crate and served by a local axum
+// server. They don't require any external muxing application.
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test native_muxing -- --show-output
+
+pub mod common;
+use std::fs;
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use axum::{routing::get, Router};
+use axum::extract::{Path as AxumPath, State};
+use axum::response::IntoResponse;
+use axum::http::{header, StatusCode};
+use axum_server::{Handle, bind};
+use mp4_atom::{
+    Decode, Encode, FixedPoint, FourCC, Header, Any,
+    Ftyp, Moov, Mvhd, Mvex, Trex, Trak, Tkhd, Mdia, Mdhd, Hdlr, Minf, Vmhd, Smhd, Dinf, Stbl, Stsd,
+    Stco, Codec, Avc1, Avcc, Visual, Mp4a, Audio, Esds, Moof, Mfhd, Traf, Tfhd, Tfdt, Trun, TrunEntry,
+};
+use mp4_atom::esds::{EsDescriptor, DecoderConfig, DecoderSpecific};
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::{DashDownloader, Muxer, Concatenator, PluginFuture};
+use dash_mpd::media::{AudioTrack, NativeMuxer, NativeConcatenator};
+use common::setup_logging;
+
+
+const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static"
+     profiles="urn:mpeg:dash:profile:isoff-live:2011"
+     mediaPresentationDuration="PT0.32S" minBufferTime="PT2S">
+  <Period id="1">
+    <AdaptationSet contentType="video" mimeType="video/mp4" segmentAlignment="true">
+      <Representation id="video" codecs="avc1.64001f" bandwidth="200000" width="320" height="240">
+        <SegmentTemplate timescale="12800" duration="2048" startNumber="1"
+                         initialization="/media/video-init.mp4" media="/media/video-$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" lang="en">
+      <Representation id="audio-en" codecs="mp4a.40.2" bandwidth="64000" audioSamplingRate="48000">
+        <SegmentTemplate timescale="48000" duration="8192" startNumber="1"
+                         initialization="/media/en-init.mp4" media="/media/en-$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" lang="fr">
+      <Representation id="audio-fr" codecs="mp4a.40.2" bandwidth="64000" audioSamplingRate="48000">
+        <SegmentTemplate timescale="48000" duration="8192" startNumber="1"
+                         initialization="/media/fr-init.mp4" media="/media/fr-$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+// Two Periods with the same content, which can be concatenated without reencoding.
+const MPD_TWO_PERIODS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static"
+     profiles="urn:mpeg:dash:profile:isoff-live:2011"
+     mediaPresentationDuration="PT0.64S" minBufferTime="PT2S">
+  <Period id="1" duration="PT0.32S">
+    <AdaptationSet contentType="video" mimeType="video/mp4" segmentAlignment="true">
+      <Representation id="video" codecs="avc1.64001f" bandwidth="200000" width="320" height="240">
+        <SegmentTemplate timescale="12800" duration="2048" startNumber="1"
+                         initialization="/media/video-init.mp4" media="/media/video-$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" lang="en">
+      <Representation id="audio-en" codecs="mp4a.40.2" bandwidth="64000" audioSamplingRate="48000">
+        <SegmentTemplate timescale="48000" duration="8192" startNumber="1"
+                         initialization="/media/en-init.mp4" media="/media/en-$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+  <Period id="2" duration="PT0.32S">
+    <AdaptationSet contentType="video" mimeType="video/mp4" segmentAlignment="true">
+      <Representation id="video" codecs="avc1.64001f" bandwidth="200000" width="320" height="240">
+        <SegmentTemplate timescale="12800" duration="2048" startNumber="1"
+                         initialization="/media/video-init.mp4" media="/media/video-$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" lang="en">
+      <Representation id="audio-en" codecs="mp4a.40.2" bandwidth="64000" audioSamplingRate="48000">
+        <SegmentTemplate timescale="48000" duration="8192" startNumber="1"
+                         initialization="/media/en-init.mp4" media="/media/en-$Number$.m4s"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+const VIDEO_TIMESCALE: u32 = 12800;
+const VIDEO_FRAME_DURATION: u32 = 512;
+const AUDIO_TIMESCALE: u32 = 48000;
+const AUDIO_FRAME_DURATION: u32 = 1024;
+
+fn encode<T: Encode>(atom: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    atom.encode(&mut buf).unwrap();
+    buf
+}
+
+// The content of video sample n (counting from zero over all segments).
+fn video_sample(n: usize) -> Vec<u8> {
+    vec![0x40 + n as u8; 100 + 10 * n]
+}
+
+// The content of audio sample n in the stream with the given marker octet.
+fn audio_sample(marker: u8, n: usize) -> Vec<u8> {
+    let mut s = vec![marker; 20 + n];
+    s[0] = n as u8;
+    s
+}
+
+fn init_segment(codec: Codec, timescale: u32, is_video: bool) -> Vec<u8> {
+    let ftyp = Ftyp {
+        major_brand: FourCC::new(b"cmfc"),
+        minor_version: 0,
+        compatible_brands: vec![FourCC::new(b"iso6"), FourCC::new(b"cmfc")],
+    };
+    let moov = Moov {
+        mvhd: Mvhd {
+            creation_time: 0,
+            modification_time: 0,
+            timescale: 1000,
+            duration: 0,
+            rate: FixedPoint::new(1, 0),
+            volume: FixedPoint::new(1, 0),
+            matrix: Default::default(),
+            next_track_id: 2,
+        },
+        mvex: Some(Mvex {
+            mehd: None,
+            trex: vec![Trex { track_id: 1, default_sample_description_index: 1, ..Default::default() }],
+        }),
+        trak: vec![Trak {
+            tkhd: Tkhd {
+                track_id: 1,
+                enabled: true,
+                in_movie: true,
+                width: FixedPoint::new(if is_video { 320 } else { 0 }, 0),
+                height: FixedPoint::new(if is_video { 240 } else { 0 }, 0),
+                ..Default::default()
+            },
+            mdia: Mdia {
+                mdhd: Mdhd { timescale, language: String::from("und"), ..Default::default() },
+                hdlr: Hdlr {
+                    handler: FourCC::new(if is_video { b"vide" } else { b"soun" }),
+                    name: String::from(if is_video { "VideoHandler" } else { "SoundHandler" }),
+                },
+                minf: Minf {
+                    vmhd: if is_video { Some(Vmhd::default()) } else { None },
+                    smhd: if is_video { None } else { Some(Smhd::default()) },
+                    dinf: Dinf::default(),
+                    stbl: Stbl {
+                        stsd: Stsd { codecs: vec![codec] },
+                        stco: Some(Stco::default()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            },
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let mut buf = encode(&ftyp);
+    buf.extend(encode(&moov));
+    buf
+}
+
+fn video_init() -> Vec<u8> {
+    let avc1 = Avc1 {
+        visual: Visual { width: 320, height: 240, ..Default::default() },
+        avcc: Avcc {
+            configuration_version: 1,
+            avc_profile_indication: 0x64,
+            profile_compatibility: 0,
+            avc_level_indication: 0x1f,
+            length_size: 4,
+            sequence_parameter_sets: vec![vec![0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50]],
+            picture_parameter_sets: vec![vec![0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0]],
+            ext: None,
+        },
+        btrt: None,
+        colr: None,
+        pasp: None,
+        taic: None,
+        fiel: None,
+    };
+    init_segment(Codec::Avc1(avc1), VIDEO_TIMESCALE, true)
+}
+
+fn audio_init() -> Vec<u8> {
+    let mp4a = Mp4a {
+        audio: Audio {
+            data_reference_index: 1,
+            channel_count: 2,
+            sample_size: 16,
+            sample_rate: FixedPoint::new(48000, 0),
+        },
+        esds: Esds {
+            es_desc: EsDescriptor {
+                es_id: 1,
+                dec_config: DecoderConfig {
+                    object_type_indication: 0x40,
+                    stream_type: 5,
+                    up_stream: 0,
+                    buffer_size_db: Default::default(),
+                    max_bitrate: 64000,
+                    avg_bitrate: 64000,
+                    dec_specific: DecoderSpecific { profile: 2, freq_index: 3, chan_conf: 2 },
+                },
+                sl_config: Default::default(),
+            },
+        },
+        btrt: None,
+        taic: None,
+    };
+    init_segment(Codec::Mp4a(mp4a), AUDIO_TIMESCALE, false)
+}
+
+// A media segment containing a single moof+mdat pair. The trun data offset is relative to the
+// start of the moof.
+fn media_segment(sequence_number: u32, tfhd: Tfhd, decode_time: u64, entries: Vec<TrunEntry>, samples: &[Vec<u8>]) -> Vec<u8> {
+    let mut moof = Moof {
+        mfhd: Mfhd { sequence_number },
+        traf: vec![Traf {
+            tfhd,
+            tfdt: Some(Tfdt { base_media_decode_time: decode_time }),
+            trun: vec![Trun { data_offset: Some(0), entries }],
+            ..Default::default()
+        }],
+    };
+    let moof_size = encode(&moof).len();
+    moof.traf[0].trun[0].data_offset = Some((moof_size + 8) as i32);
+    let data: Vec<u8> = samples.concat();
+    let mut buf = encode(&moof);
+    buf.extend(((data.len() + 8) as u32).to_be_bytes());
+    buf.extend(b"mdat");
+    buf.extend(data);
+    buf
+}
+
+// Each video segment contains four frames, of which the first is a sync sample. The sample
+// properties are all specified explicitly in the trun.
+fn video_segment(number: usize) -> Vec<u8> {
+    let first = (number - 1) * 4;
+    let samples: Vec<Vec<u8>> = (first..first + 4).map(video_sample).collect();
+    let entries = samples.iter().enumerate()
+        .map(|(i, s)| TrunEntry {
+            duration: Some(VIDEO_FRAME_DURATION),
+            size: Some(s.len() as u32),
+            flags: Some(if i == 0 { 0x0200_0000 } else { 0x0101_0000 }),
+            cts: Some(if i == 0 { 0 } else { 2 * VIDEO_FRAME_DURATION as i32 }),
+        })
+        .collect();
+    let tfhd = Tfhd { track_id: 1, default_base_is_moof: true, ..Default::default() };
+    media_segment(number as u32, tfhd, (first as u64) * u64::from(VIDEO_FRAME_DURATION), entries, &samples)
+}
+
+// Each audio segment contains eight frames. The sample duration and flags are taken from the
+// defaults in the tfhd.
+fn audio_segment(marker: u8, number: usize) -> Vec<u8> {
+    let first = (number - 1) * 8;
+    let samples: Vec<Vec<u8>> = (first..first + 8).map(|n| audio_sample(marker, n)).collect();
+    let entries = samples.iter()
+        .map(|s| TrunEntry { size: Some(s.len() as u32), ..Default::default() })
+        .collect();
+    let tfhd = Tfhd {
+        track_id: 1,
+        default_sample_duration: Some(AUDIO_FRAME_DURATION),
+        default_sample_flags: Some(0x0200_0000),
+        default_base_is_moof: true,
+        ..Default::default()
+    };
+    media_segment(number as u32, tfhd, (first as u64) * u64::from(AUDIO_FRAME_DURATION), entries, &samples)
+}
+
+fn media_files() -> HashMap<String, Vec<u8>> {
+    let mut files = HashMap::new();
+    files.insert(String::from("video-init.mp4"), video_init());
+    files.insert(String::from("en-init.mp4"), audio_init());
+    files.insert(String::from("fr-init.mp4"), audio_init());
+    for n in 1..=2 {
+        files.insert(format!("video-{n}.m4s"), video_segment(n));
+        files.insert(format!("en-{n}.m4s"), audio_segment(0xE0, n));
+        files.insert(format!("fr-{n}.m4s"), audio_segment(0xF0, n));
+    }
+    files
+}
+
+async fn serve_media(server_handle: Handle<SocketAddr>, port: u16) {
+    async fn send_mpd() -> impl IntoResponse {
+        ([(header::CONTENT_TYPE, "application/dash+xml")], MPD)
+    }
+    async fn send_mpd_periods() -> impl IntoResponse {
+        ([(header::CONTENT_TYPE, "application/dash+xml")], MPD_TWO_PERIODS)
+    }
+    async fn send_media(
+        AxumPath(name): AxumPath<String>,
+        State(files): State<Arc<HashMap<String, Vec<u8>>>>) -> impl IntoResponse
+    {
+        match files.get(&name) {
+            Some(content) => ([(header::CONTENT_TYPE, "video/mp4")], content.clone()).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/mpd-periods", get(send_mpd_periods))
+        .route("/media/{name}", get(send_media))
+        .with_state(Arc::new(media_files()));
+    let backend = async move {
+        bind(format!("127.0.0.1:{port}").parse().unwrap())
+            .handle(server_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+}
+
+// The kinds of the top-level boxes in an MP4 file.
+fn top_level_boxes(content: &[u8]) -> Vec<FourCC> {
+    let mut kinds = Vec::new();
+    let mut buf = content;
+    while !buf.is_empty() {
+        let atom = Any::decode(&mut buf).unwrap();
+        kinds.push(atom.kind());
+    }
+    kinds
+}
+
+
+#[tokio::test]
+async fn test_native_mux_progressive() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6677).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("native.mp4");
+    DashDownloader::new("http://localhost:6677/mpd")
+        .prefer_audio_languages(vec!["en", "fr"])
+        .with_muxer_preference("mp4", "native")
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    let content = fs::read(&out).unwrap();
+    // The moov box is at the front of the file, before the media data.
+    assert_eq!(top_level_boxes(&content),
+               vec![FourCC::new(b"ftyp"), FourCC::new(b"moov"), FourCC::new(b"mdat")]);
+    let size = content.len() as u64;
+    let mp4 = mp4::Mp4Reader::read_header(BufReader::new(fs::File::open(&out).unwrap()), size).unwrap();
+    assert_eq!(mp4.tracks().len(), 3);
+    let mut mp4 = mp4;
+    // The video track, with its sync samples and composition offsets.
+    assert_eq!(mp4.sample_count(1).unwrap(), 8);
+    for n in 0..8 {
+        let sample = mp4.read_sample(1, n as u32 + 1).unwrap().unwrap();
+        assert_eq!(sample.bytes.as_ref(), video_sample(n).as_slice());
+        assert_eq!(sample.start_time, n as u64 * u64::from(VIDEO_FRAME_DURATION));
+        assert_eq!(sample.is_sync, n % 4 == 0);
+        assert_eq!(sample.rendering_offset, if n % 4 == 0 { 0 } else { 1024 });
+    }
+    // The audio tracks, in order of language preference.
+    for (track_id, marker, lang) in [(2, 0xE0, "eng"), (3, 0xF0, "fra")] {
+        let track = mp4.tracks().get(&track_id).unwrap();
+        assert_eq!(track.language(), lang);
+        assert_eq!(track.timescale(), AUDIO_TIMESCALE);
+        assert_eq!(mp4.sample_count(track_id).unwrap(), 16);
+        for n in 0..16 {
+            let sample = mp4.read_sample(track_id, n as u32 + 1).unwrap().unwrap();
+            assert_eq!(sample.bytes.as_ref(), audio_sample(marker, n).as_slice());
+            assert_eq!(sample.duration, AUDIO_FRAME_DURATION);
+            assert!(sample.is_sync);
+        }
+    }
+    // Only the first audio track is enabled by default, in the same alternate group as the second.
+    let mut buf = &content[..];
+    let _ftyp = Ftyp::decode(&mut buf).unwrap();
+    let moov = Moov::decode(&mut buf).unwrap();
+    assert!(moov.mvex.is_none());
+    assert!(moov.trak[1].tkhd.enabled);
+    assert!(!moov.trak[2].tkhd.enabled);
+    assert_eq!(moov.trak[1].tkhd.alternate_group, 1);
+    assert_eq!(moov.trak[2].tkhd.alternate_group, 1);
+    assert_eq!(moov.mvhd.next_track_id, 4);
+}
+
+
+#[tokio::test]
+async fn test_native_mux_fragmented() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6678).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("native-fragmented.mp4");
+    DashDownloader::new("http://localhost:6678/mpd")
+        .prefer_audio_languages(vec!["fr", "en"])
+        .with_muxer_preference("mp4", "native")
+        .fragmented_mp4_output(true)
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    let content = fs::read(&out).unwrap();
+    let mut buf = &content[..];
+    let _ftyp = Ftyp::decode(&mut buf).unwrap();
+    let moov = Moov::decode(&mut buf).unwrap();
+    assert_eq!(moov.trak.len(), 3);
+    assert_eq!(moov.mvex.as_ref().unwrap().trex.len(), 3);
+    assert_eq!(moov.trak[1].mdia.mdhd.language, "fra");
+    assert_eq!(moov.trak[2].mdia.mdhd.language, "eng");
+    // Reassemble the samples of each track from the interleaved fragments.
+    let mut track_data: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut sequence_numbers = Vec::new();
+    let mut decode_times: HashMap<u32, Vec<u64>> = HashMap::new();
+    while !buf.is_empty() {
+        let moof_start = content.len() - buf.len();
+        let moof = Moof::decode(&mut buf).unwrap();
+        let header = Header::decode(&mut buf).unwrap();
+        assert_eq!(header.kind, FourCC::new(b"mdat"));
+        buf = &buf[header.size.unwrap()..];
+        sequence_numbers.push(moof.mfhd.sequence_number);
+        for traf in &moof.traf {
+            assert!(traf.tfhd.default_base_is_moof);
+            let track_id = traf.tfhd.track_id;
+            decode_times.entry(track_id).or_default()
+                .push(traf.tfdt.as_ref().unwrap().base_media_decode_time);
+            for trun in &traf.trun {
+                let mut offset = moof_start + trun.data_offset.unwrap() as usize;
+                for entry in &trun.entries {
+                    let size = entry.size.unwrap() as usize;
+                    track_data.entry(track_id).or_default()
+                        .extend_from_slice(&content[offset..offset + size]);
+                    offset += size;
+                }
+            }
+        }
+    }
+    assert_eq!(sequence_numbers, (1..=6).collect::<Vec<u32>>());
+    assert_eq!(decode_times[&1], vec![0, 2048]);
+    assert_eq!(decode_times[&2], vec![0, 8192]);
+    let video: Vec<u8> = (0..8).flat_map(video_sample).collect();
+    assert_eq!(track_data[&1], video);
+    let fr: Vec<u8> = (0..16).flat_map(|n| audio_sample(0xF0, n)).collect();
+    assert_eq!(track_data[&2], fr);
+    let en: Vec<u8> = (0..16).flat_map(|n| audio_sample(0xE0, n)).collect();
+    assert_eq!(track_data[&3], en);
+}
+
+
+// The MP4 files for the two Periods are concatenated natively (without ffmpeg being available),
+// with the decode times of the second Period following on from those of the first.
+#[tokio::test]
+async fn test_native_concat_periods_progressive() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6681).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("periods.mp4");
+    DashDownloader::new("http://localhost:6681/mpd-periods")
+        .with_muxer_preference("mp4", "native")
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    assert!(!tmpd.path().join("periods-p2.mp4").exists());
+    let content = fs::read(&out).unwrap();
+    assert_eq!(top_level_boxes(&content),
+               vec![FourCC::new(b"ftyp"), FourCC::new(b"moov"), FourCC::new(b"mdat")]);
+    let size = content.len() as u64;
+    let mut mp4 = mp4::Mp4Reader::read_header(BufReader::new(fs::File::open(&out).unwrap()), size).unwrap();
+    assert_eq!(mp4.tracks().len(), 2);
+    // Each Period lasts as long as its longest track: the audio stream (16 frames of 1024 samples
+    // at 48kHz) is slightly longer than the video stream. The gap before the start of the second
+    // Period is filled by extending the last video frame of the first Period.
+    let second_period = u64::from(VIDEO_TIMESCALE) * 16 * u64::from(AUDIO_FRAME_DURATION) / u64::from(AUDIO_TIMESCALE);
+    assert_eq!(mp4.sample_count(1).unwrap(), 16);
+    for n in 0..16 {
+        let sample = mp4.read_sample(1, n as u32 + 1).unwrap().unwrap();
+        assert_eq!(sample.bytes.as_ref(), video_sample(n % 8).as_slice());
+        let start = if n < 8 { 0 } else { second_period };
+        assert_eq!(sample.start_time, start + (n as u64 % 8) * u64::from(VIDEO_FRAME_DURATION));
+        assert_eq!(sample.is_sync, n % 4 == 0);
+    }
+    assert_eq!(mp4.sample_count(2).unwrap(), 32);
+    for n in 0..32 {
+        let sample = mp4.read_sample(2, n as u32 + 1).unwrap().unwrap();
+        assert_eq!(sample.bytes.as_ref(), audio_sample(0xE0, n % 16).as_slice());
+        assert_eq!(sample.start_time, n as u64 * u64::from(AUDIO_FRAME_DURATION));
+    }
+}
+
+
+#[tokio::test]
+async fn test_native_concat_periods_fragmented() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6682).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("periods-fragmented.mp4");
+    DashDownloader::new("http://localhost:6682/mpd-periods")
+        .with_muxer_preference("mp4", "native")
+        .with_concat_preference("mp4", "native")
+        .fragmented_mp4_output(true)
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    let content = fs::read(&out).unwrap();
+    let mut buf = &content[..];
+    let _ftyp = Ftyp::decode(&mut buf).unwrap();
+    let moov = Moov::decode(&mut buf).unwrap();
+    assert_eq!(moov.trak.len(), 2);
+    let mut sequence_numbers = Vec::new();
+    let mut decode_times: HashMap<u32, Vec<u64>> = HashMap::new();
+    while !buf.is_empty() {
+        let moof = Moof::decode(&mut buf).unwrap();
+        let header = Header::decode(&mut buf).unwrap();
+        buf = &buf[header.size.unwrap()..];
+        sequence_numbers.push(moof.mfhd.sequence_number);
+        for traf in &moof.traf {
+            decode_times.entry(traf.tfhd.track_id).or_default()
+                .push(traf.tfdt.as_ref().unwrap().base_media_decode_time);
+        }
+    }
+    assert_eq!(sequence_numbers, (1..=8).collect::<Vec<u32>>());
+    assert_eq!(decode_times[&1], vec![0, 2048, 4369, 6417]);
+    assert_eq!(decode_times[&2], vec![0, 8192, 16384, 24576]);
+}
+
+
+// A user-supplied muxer, which counts its invocations and delegates to the native muxer.
+struct CountingMuxer {
+    calls: AtomicUsize,
+}
+
+impl Muxer for CountingMuxer {
+    fn name(&self) -> &str {
+        "inhouse"
+    }
+
+    fn mux<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        output_path: &'a Path,
+        audio_tracks: &'a [AudioTrack],
+        video_path: Option<&'a Path>) -> PluginFuture<'a> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        NativeMuxer.mux(downloader, output_path, audio_tracks, video_path)
+    }
+}
+
+// A user-supplied concat helper which replaces the built-in "native" concat helper.
+struct CountingConcatenator {
+    calls: AtomicUsize,
+}
+
+impl Concatenator for CountingConcatenator {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    fn concat<'a>(
+        &'a self,
+        downloader: &'a DashDownloader,
+        paths: &'a [&'a Path]) -> PluginFuture<'a> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        NativeConcatenator.concat(downloader, paths)
+    }
+}
+
+
+// A registered muxer is used when it appears in the muxer preference, and unknown muxers in the
+// preference are skipped.
+#[tokio::test]
+async fn test_registered_muxer() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6683).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("registered.mp4");
+    let muxer = Arc::new(CountingMuxer { calls: AtomicUsize::new(0) });
+    DashDownloader::new("http://localhost:6683/mpd")
+        .prefer_audio_languages(vec!["en", "fr"])
+        .with_muxer(muxer.clone())
+        .with_muxer_preference("mp4", "nonexistent,inhouse")
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    assert_eq!(muxer.calls.load(Ordering::SeqCst), 1);
+    let content = fs::read(&out).unwrap();
+    let size = content.len() as u64;
+    let mp4 = mp4::Mp4Reader::read_header(BufReader::new(fs::File::open(&out).unwrap()), size).unwrap();
+    assert_eq!(mp4.tracks().len(), 3);
+}
+
+
+// A registered concat helper replaces the built-in concat helper with the same name.
+#[tokio::test]
+async fn test_registered_concatenator() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6684).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("registered-periods.mp4");
+    let concatenator = Arc::new(CountingConcatenator { calls: AtomicUsize::new(0) });
+    DashDownloader::new("http://localhost:6684/mpd-periods")
+        .with_muxer_preference("mp4", "native")
+        .with_concatenator(concatenator.clone())
+        .with_concat_preference("mp4", "native")
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    assert_eq!(concatenator.calls.load(Ordering::SeqCst), 1);
+    let content = fs::read(&out).unwrap();
+    let size = content.len() as u64;
+    let mp4 = mp4::Mp4Reader::read_header(BufReader::new(fs::File::open(&out).unwrap()), size).unwrap();
+    assert_eq!(mp4.sample_count(1).unwrap(), 16);
+}
+
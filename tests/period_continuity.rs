@@ -0,0 +1,103 @@
+//! Tests for the continuity of the selected tracks across the Periods of a multi-Period manifest
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test period_continuity -- --show-output
+//
+
+pub mod common;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use common::{serve_manifests, ffprobe_stream_types, setup_logging};
+
+
+#[tokio::test]
+async fn test_period_continuity() {
+    // The second Period (after an ad break) adds a higher quality HEVC AdaptationSet and a French
+    // audio track with a higher bandwidth. The video AdaptationSet that continues the first Period
+    // is signalled with a period-continuity descriptor; the audio AdaptationSet only has the same
+    // @id. The third Period has no AdaptationSet @ids, so the previous codec and resolution are used.
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT6S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="p1" duration="PT2S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/p1-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="v1080" bandwidth="4000000" width="1920" height="1080"/>
+      <Representation id="v720" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/p1-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="a-en" bandwidth="128000"/>
+    </AdaptationSet>
+  </Period>
+  <Period id="p2" duration="PT2S">
+    <AdaptationSet id="2" contentType="video" mimeType="video/mp4" codecs="hvc1.2.4.L153.B0">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/p2-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="v2160" bandwidth="12000000" width="3840" height="2160"/>
+    </AdaptationSet>
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SupplementalProperty schemeIdUri="urn:mpeg:dash:period-continuity:2015" value="p1"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/p2-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="v1080" bandwidth="4000000" width="1920" height="1080"/>
+      <Representation id="v720" bandwidth="2000000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet id="4" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="fr">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/p2-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="a-fr" bandwidth="256000"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="audio" mimeType="audio/mp4" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/p2-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="a-en-ec3" bandwidth="192000" codecs="ec-3"/>
+      <Representation id="a-en" bandwidth="128000" codecs="mp4a.40.2"/>
+    </AdaptationSet>
+  </Period>
+  <Period id="p3" duration="PT2S">
+    <AdaptationSet contentType="video" mimeType="video/mp4">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/p3-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="hevc-2160" bandwidth="12000000" width="3840" height="2160" codecs="hvc1.2.4.L153.B0"/>
+      <Representation id="avc-1080" bandwidth="4500000" width="1920" height="1080" codecs="avc1.640028"/>
+      <Representation id="avc-720" bandwidth="2500000" width="1280" height="720" codecs="avc1.640028"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="fr">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/p3-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="fr" bandwidth="256000"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/p3-$RepresentationID$-$Number$.m4s"/>
+      <Representation id="en" bandwidth="96000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6693, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("continuity.mp4");
+    let url = "http://localhost:6693/mpd";
+    // Each Period is saved to a separate output file, with the stream type given in each case.
+    let cases: [(DashDownloader, Vec<&str>, &str); 4] = [
+        (DashDownloader::new(url).best_quality().video_only(),
+         vec!["p1-v1080-1.m4s", "p2-v1080-1.m4s", "p3-avc-1080-1.m4s"], "video"),
+        (DashDownloader::new(url).best_quality().video_only().prefer_period_continuity(false),
+         vec!["p1-v1080-1.m4s", "p2-v2160-1.m4s", "p3-hevc-2160-1.m4s"], "video"),
+        (DashDownloader::new(url).best_quality().audio_only(),
+         vec!["p1-a-en-1.m4s", "p2-a-en-1.m4s", "p3-en-1.m4s"], "audio"),
+        (DashDownloader::new(url).best_quality().audio_only().prefer_period_continuity(false),
+         vec!["p1-a-en-1.m4s", "p2-a-fr-1.m4s", "p3-fr-1.m4s"], "audio"),
+    ];
+    for (downloader, expected, stream_type) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .concatenate_periods(false)
+            .download_to(&out).await
+            .unwrap();
+        let mut fetched = requests.lock().unwrap().clone();
+        fetched.sort();
+        assert_eq!(fetched, expected);
+        for name in ["continuity.mp4", "continuity-p2.mp4", "continuity-p3.mp4"] {
+            assert_eq!(ffprobe_stream_types(&tmpd.path().join(name)), vec![stream_type]);
+        }
+    }
+    server_handle.shutdown();
+}
@@ -0,0 +1,121 @@
+// Tests for parsing PlayReady DRM initialization data (PlayReady Objects and PlayReady PSSH boxes)
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test playready -- --show-output
+
+
+use std::fs;
+use std::path::PathBuf;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use dash_mpd::{parse, MPD};
+use dash_mpd::playready::{PlayReadyObject, PlayReadyRecord, PLAYREADY_SYSTEM_ID};
+
+
+// Build the binary representation of a PlayReady Object containing a PlayReady Header with the
+// specified XML content, followed by the extra records specified.
+fn make_pro(xml: &str, extra: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut records: Vec<(u16, Vec<u8>)> = vec![
+        (1, xml.encode_utf16().flat_map(|c| c.to_le_bytes()).collect())];
+    records.extend_from_slice(extra);
+    let length = 6 + records.iter().map(|r| 4 + r.1.len()).sum::<usize>();
+    let mut pro = Vec::new();
+    pro.extend_from_slice(&(length as u32).to_le_bytes());
+    pro.extend_from_slice(&(records.len() as u16).to_le_bytes());
+    for (rtype, value) in records {
+        pro.extend_from_slice(&rtype.to_le_bytes());
+        pro.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        pro.extend_from_slice(&value);
+    }
+    pro
+}
+
+fn make_pssh(data: &[u8]) -> Vec<u8> {
+    let mut pssh = Vec::new();
+    pssh.extend_from_slice(&((32 + data.len()) as u32).to_be_bytes());
+    pssh.extend_from_slice(b"pssh");
+    pssh.extend_from_slice(&[0, 0, 0, 0]);
+    pssh.extend_from_slice(&PLAYREADY_SYSTEM_ID);
+    pssh.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    pssh.extend_from_slice(data);
+    pssh
+}
+
+
+#[test]
+fn test_playready_manifest_pro() {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests");
+    path.push("fixtures");
+    path.push("jurassic-compact-5975");
+    path.set_extension("mpd");
+    let xml = fs::read_to_string(path).unwrap();
+    let mpd: MPD = parse(&xml).unwrap();
+    let adaptation = &mpd.periods[0].adaptations[0];
+    let cp = adaptation.ContentProtection.iter()
+        .find(|cp| cp.msprpro.is_some())
+        .unwrap();
+    let pro_b64 = cp.msprpro.as_ref().unwrap().content.as_ref().unwrap();
+    let pro = PlayReadyObject::from_base64(pro_b64).unwrap();
+    assert_eq!(pro.records.len(), 1);
+    let header = pro.headers().next().unwrap();
+    assert_eq!(header.version, "4.0.0.0");
+    assert_eq!(header.keylen, Some(16));
+    assert_eq!(header.kids.len(), 1);
+    // The KID must match the cenc:default_KID in the manifest, which uses a different byte order.
+    assert_eq!(header.kids[0].kid, "001637069fb5d1ac3c4747e01322e4c2");
+    assert_eq!(header.kids[0].algid.as_deref(), Some("AESCTR"));
+    assert_eq!(header.kids[0].checksum.as_deref(), Some("G320OiAw3Ho="));
+    assert_eq!(header.la_url.as_deref(), Some("http://playready.directtaps.net/pr/svc/rightsmanager.asmx"));
+    assert_eq!(header.lui_url.as_deref(), Some("http://playready.directtaps.net/pr/svc/rightsmanager.asmx"));
+    assert!(pro.to_string().contains("KID=001637069fb5d1ac3c4747e01322e4c2"));
+
+    // The PlayReady PSSH box in the same ContentProtection element contains the same header.
+    let pssh_b64 = cp.cenc_pssh[0].content.as_ref().unwrap();
+    let pssh = BASE64_STANDARD.decode(pssh_b64).unwrap();
+    let from_pssh = PlayReadyObject::from_pssh(&pssh).unwrap();
+    assert_eq!(from_pssh, pro);
+}
+
+#[test]
+fn test_playready_header_versions() {
+    let v41 = r#"<WRMHEADER xmlns="http://schemas.microsoft.com/DRM/2007/03/PlayReadyHeader" version="4.1.0.0"><DATA><PROTECTINFO><KID ALGID="AESCTR" CHECKSUM="xNojN/GXfxI=" VALUE="BjcWALWfrNE8R0fgEyLkwg=="></KID></PROTECTINFO><LA_URL>https://license.example.com/rightsmanager.asmx?a=1&amp;b=2</LA_URL></DATA></WRMHEADER>"#;
+    let pro = PlayReadyObject::from_bytes(&make_pro(v41, &[])).unwrap();
+    let header = pro.headers().next().unwrap();
+    assert_eq!(header.version, "4.1.0.0");
+    assert_eq!(header.kids.len(), 1);
+    assert_eq!(header.kids[0].kid, "001637069fb5d1ac3c4747e01322e4c2");
+    assert_eq!(header.kids[0].checksum.as_deref(), Some("xNojN/GXfxI="));
+    assert_eq!(header.la_url.as_deref(), Some("https://license.example.com/rightsmanager.asmx?a=1&b=2"));
+
+    let v43 = r#"<WRMHEADER xmlns="http://schemas.microsoft.com/DRM/2007/03/PlayReadyHeader" version="4.3.0.0"><DATA><PROTECTINFO><KIDS><KID ALGID="AESCBC" VALUE="BjcWALWfrNE8R0fgEyLkwg=="></KID><KID ALGID="AESCTR" VALUE="mZCiZoD5PgmlWr81pw2AZQ=="></KID></KIDS></PROTECTINFO><LA_URL>https://pr.example.com/</LA_URL><LUI_URL>https://ui.example.com/</LUI_URL><DS_ID>AH+03juKbUGbHl1V/QIwRA==</DS_ID><CUSTOMATTRIBUTES xmlns=""><CONTENTID>foo-42</CONTENTID><KID VALUE="ignored"/></CUSTOMATTRIBUTES><DECRYPTORSETUP>ONDEMAND</DECRYPTORSETUP></DATA></WRMHEADER>"#;
+    let pro = PlayReadyObject::from_bytes(&make_pro(v43, &[(3, vec![1, 2, 3, 4])])).unwrap();
+    assert_eq!(pro.records.len(), 2);
+    assert!(matches!(&pro.records[1], PlayReadyRecord::EmbeddedLicenseStore(d) if d.len() == 4));
+    let header = pro.headers().next().unwrap();
+    assert_eq!(header.version, "4.3.0.0");
+    assert_eq!(header.kids.len(), 2);
+    assert_eq!(header.kids[0].kid, "001637069fb5d1ac3c4747e01322e4c2");
+    assert_eq!(header.kids[0].algid.as_deref(), Some("AESCBC"));
+    assert_eq!(header.kids[1].kid, "66a29099f980093ea55abf35a70d8065");
+    assert_eq!(header.kids[1].algid.as_deref(), Some("AESCTR"));
+    assert_eq!(header.lui_url.as_deref(), Some("https://ui.example.com/"));
+    assert_eq!(header.ds_id.as_deref(), Some("AH+03juKbUGbHl1V/QIwRA=="));
+    assert_eq!(header.custom_attributes.as_deref(), Some(r#"<CONTENTID>foo-42</CONTENTID><KID VALUE="ignored"/>"#));
+    assert_eq!(header.decryptor_setup.as_deref(), Some("ONDEMAND"));
+
+    let from_pssh = PlayReadyObject::from_pssh(&make_pssh(&make_pro(v43, &[]))).unwrap();
+    assert_eq!(from_pssh.headers().next().unwrap().kids.len(), 2);
+}
+
+#[test]
+fn test_playready_invalid() {
+    assert!(PlayReadyObject::from_base64("not base64!").is_err());
+    assert!(PlayReadyObject::from_bytes(&[1, 0]).is_err());
+    // Record length exceeds the available data
+    assert!(PlayReadyObject::from_bytes(&[12, 0, 0, 0, 1, 0, 1, 0, 200, 0, 0, 0]).is_err());
+    assert!(PlayReadyObject::from_bytes(&make_pro("<NOTAHEADER/>", &[])).is_err());
+    // A Widevine PSSH box
+    let widevine = BASE64_STANDARD.decode("AAAAOHBzc2gAAAAA7e+LqXnWSs6jyCfc1R0h7QAAABgSEAAWNwaftdGsPEdH4BMi5MJI49yVmwY=").unwrap();
+    assert!(PlayReadyObject::from_pssh(&widevine).is_err());
+}
@@ -0,0 +1,105 @@
+//! Tests for the selection of Preselections (next-generation audio) and their component AdaptationSets
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test preselection -- --show-output
+//
+
+pub mod common;
+use std::fs;
+use std::path::PathBuf;
+use pretty_assertions::assert_eq;
+use dash_mpd::parse;
+use dash_mpd::fetch::{DashDownloader, AccessibilityFeature};
+use common::{serve_manifests, ffprobe_audio_languages, setup_logging};
+
+
+#[test]
+fn test_preselection_parsing() {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("tests");
+    path.push("fixtures");
+    path.push("dolby-ac4");
+    path.set_extension("xml");
+    let xml = fs::read_to_string(path).unwrap();
+    let mpd = parse(&xml).unwrap();
+    let preselections = &mpd.periods[0].pre_selections;
+    assert_eq!(preselections.len(), 4);
+    let tags: Vec<&str> = preselections.iter().map(|p| p.tag.as_str()).collect();
+    assert_eq!(tags, vec!["1", "2", "3", "4"]);
+    assert!(preselections.iter().all(|p| p.components() == vec!["11"]));
+    assert_eq!(preselections[1].lang.as_deref(), Some("es"));
+    assert_eq!(preselections[2].roles[0].value.as_deref(), Some("alternate"));
+}
+
+// An MPEG-H 3D Audio stream with a main component (music and effects with English dialogue) and
+// two further components with French and commentary dialogue.
+#[tokio::test]
+async fn test_preselection_selection() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT2S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="audio" mimeType="audio/mp4" codecs="mhm1.0x0D" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="main" bandwidth="256000"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="audio" mimeType="audio/mp4" codecs="mhm2.0x0D" lang="fr">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="dialog-fr" bandwidth="64000"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="audio" mimeType="audio/mp4" codecs="mhm2.0x0D" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="commentary-low" bandwidth="32000"/>
+      <Representation id="commentary" bandwidth="64000"/>
+    </AdaptationSet>
+    <Preselection id="10" tag="100" preselectionComponents="1" codecs="mhm1.0x0D" lang="en">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="main"/>
+    </Preselection>
+    <Preselection id="20" tag="200" preselectionComponents="1 2" codecs="mhm1.0x0D" lang="fr">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="dub"/>
+    </Preselection>
+    <Preselection id="30" tag="300" preselectionComponents="1 3" codecs="mhm1.0x0D" lang="en" selectionPriority="2">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="commentary"/>
+      <Accessibility schemeIdUri="urn:mpeg:dash:role:2011" value="description"/>
+      <Label>Match commentary</Label>
+    </Preselection>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6691, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("preselection.mp4");
+    let url = "http://localhost:6691/mpd";
+    let commentary = || DashDownloader::new(url).prefer_roles(vec![String::from("commentary")]);
+    // The components of a Preselection are muxed as separate audio tracks with their language, and
+    // a single component is copied unchanged to the output file.
+    let cases: [(DashDownloader, Vec<&str>, Vec<&str>); 7] = [
+        // The default role preference selects the Preselection with the main role.
+        (DashDownloader::new(url).best_quality(), vec!["main-1.m4s"], vec!["und"]),
+        // The components are downloaded with the main component first.
+        (commentary().best_quality(), vec!["main-1.m4s", "commentary-1.m4s"], vec!["eng", "eng"]),
+        (commentary().worst_quality(), vec!["main-1.m4s", "commentary-low-1.m4s"], vec!["eng", "eng"]),
+        (DashDownloader::new(url).prefer_preselection(String::from("200")),
+         vec!["main-1.m4s", "dialog-fr-1.m4s"], vec!["eng", "fra"]),
+        (DashDownloader::new(url).prefer_audio_language(String::from("fr")),
+         vec!["main-1.m4s", "dialog-fr-1.m4s"], vec!["eng", "fra"]),
+        (commentary().exclude_accessibility(vec![AccessibilityFeature::AudioDescription]),
+         vec!["main-1.m4s"], vec!["und"]),
+        // Between Preselections which match the preferences equally well, the highest
+        // selectionPriority wins.
+        (DashDownloader::new(url).best_quality().prefer_roles(vec![String::from("supplementary")]),
+         vec!["main-1.m4s", "commentary-1.m4s"], vec!["eng", "eng"]),
+    ];
+    for (downloader, expected, languages) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .fetch_video(false)
+            .download_to(&out).await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap().clone(), expected);
+        assert_eq!(ffprobe_audio_languages(&out), languages);
+    }
+    server_handle.shutdown();
+}
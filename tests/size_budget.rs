@@ -0,0 +1,79 @@
+//! Tests for stream selection according to a target output size
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test size_budget -- --show-output
+//
+
+pub mod common;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use common::{serve_manifests, ffprobe_stream_types, setup_logging};
+
+
+// The sizes of the Representations are estimated from @bandwidth and the Period duration, except for
+// the SegmentList Representation "vexact" whose SegmentURL@mediaRange attributes give its exact
+// size (1 000 000 octets, much less than its @bandwidth suggests).
+#[tokio::test]
+async fn test_size_budget() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT10S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT10S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="1000" duration="10000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="v500k" bandwidth="500000" width="640" height="360"/>
+      <Representation id="v2M" bandwidth="2000000" width="1280" height="720"/>
+      <Representation id="vexact" bandwidth="3000000" width="1920" height="1080">
+        <SegmentList timescale="1000" duration="5000">
+          <SegmentURL media="/media/vexact-1.m4s" mediaRange="0-499999"/>
+          <SegmentURL media="/media/vexact-2.m4s" mediaRange="500000-999999"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <SegmentTemplate timescale="1000" duration="10000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="a128k" bandwidth="128000"/>
+      <Representation id="a256k" bandwidth="256000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6688, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("budget.mp4");
+    let url = "http://localhost:6688/mpd";
+    let cases: [(DashDownloader, Vec<&str>, Vec<&str>); 7] = [
+        (DashDownloader::new(url).video_only().best_quality(),
+         vec!["vexact-1.m4s", "vexact-2.m4s"], vec!["video"]),
+        // v2M is estimated at 2 500 000 octets and doesn't fit in the budget, whereas the exact size
+        // of vexact does.
+        (DashDownloader::new(url).video_only().with_size_budget(2_000_000),
+         vec!["vexact-1.m4s", "vexact-2.m4s"], vec!["video"]),
+        (DashDownloader::new(url).video_only().with_size_budget(700_000),
+         vec!["v500k-1.m4s"], vec!["video"]),
+        // An explicit quality preference is respected within the budget, whatever the call order.
+        (DashDownloader::new(url).video_only().worst_quality().with_size_budget(2_000_000),
+         vec!["v500k-1.m4s"], vec!["video"]),
+        (DashDownloader::new(url).video_only().with_size_budget(2_000_000).worst_quality(),
+         vec!["v500k-1.m4s"], vec!["video"]),
+        // The audio stream leaves enough of the budget for the smallest video stream (625 000
+        // octets), and the video stream is selected within the remaining 880 000 octets.
+        (DashDownloader::new(url).with_size_budget(1_200_000),
+         vec!["a256k-1.m4s", "v500k-1.m4s"], vec!["video", "audio"]),
+        (DashDownloader::new(url).with_size_budget(900_000),
+         vec!["a128k-1.m4s", "v500k-1.m4s"], vec!["video", "audio"]),
+    ];
+    for (downloader, expected, streams) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .download_to(&out).await
+            .unwrap();
+        let mut fetched = requests.lock().unwrap().clone();
+        fetched.sort();
+        assert_eq!(fetched, expected);
+        assert_eq!(ffprobe_stream_types(&out), streams);
+    }
+    server_handle.shutdown();
+}
@@ -0,0 +1,150 @@
+//! Tests for the selection of spatially partitioned video (SRD tiles) and of Viewpoints
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test srd -- --show-output
+//
+
+pub mod common;
+use std::fs;
+use pretty_assertions::assert_eq;
+use dash_mpd::{parse, parse_srd, spatial_relationship, adaptation_viewpoints, SpatialRelationship};
+use dash_mpd::fetch::DashDownloader;
+use common::{serve_manifests, ffprobe_stream_types, setup_logging};
+
+
+// A panoramic main camera (source 1) available as a full frame and as four tiles, and a second
+// camera (source 2) with a higher bandwidth, each with its own audio track.
+const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT2S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT2S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640033">
+      <Viewpoint schemeIdUri="urn:example:camera" value="main-camera"/>
+      <SupplementalProperty schemeIdUri="urn:mpeg:dash:srd:2014" value="1,0,0,3840,2160,3840,2160"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="main-full" bandwidth="4000000" width="3840" height="2160"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <EssentialProperty schemeIdUri="urn:mpeg:dash:srd:2014" value="1,0,0,1920,1080"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="tile-a-lo" bandwidth="1000000" width="1920" height="1080"/>
+      <Representation id="tile-a" bandwidth="3000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <EssentialProperty schemeIdUri="urn:mpeg:dash:srd:2014" value="1,1920,0,1920,1080"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="tile-b" bandwidth="3000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="4" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <EssentialProperty schemeIdUri="urn:mpeg:dash:srd:2014" value="1,0,1080,1920,1080"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="tile-c" bandwidth="3000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="5" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <EssentialProperty schemeIdUri="urn:mpeg:dash:srd:2014" value="1,1920,1080,1920,1080"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="tile-d" bandwidth="3000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="6" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <Viewpoint schemeIdUri="urn:example:camera" value="cam2"/>
+      <SupplementalProperty schemeIdUri="urn:mpeg:dash:srd:2014" value="2,0,0,1,1,1,1"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="cam2" bandwidth="5000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="7" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <Viewpoint schemeIdUri="urn:example:camera" value="main-camera"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="audio-main" bandwidth="128000"/>
+    </AdaptationSet>
+    <AdaptationSet id="8" contentType="audio" mimeType="audio/mp4" codecs="mp4a.40.2" lang="en">
+      <Viewpoint schemeIdUri="urn:example:camera" value="cam2"/>
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/audio/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="audio-cam2" bandwidth="192000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+
+#[test]
+fn test_srd_parsing() {
+    assert_eq!(parse_srd("1,0,1080,1920,1080"),
+               Some(SpatialRelationship {
+                   source_id: 1, object_x: 0, object_y: 1080, object_width: 1920, object_height: 1080,
+                   total_width: None, total_height: None, spatial_set_id: None,
+               }));
+    let srd = parse_srd("0, 1, 2, 3, 4, 10, 20, 7").unwrap();
+    assert_eq!(srd.total_width, Some(10));
+    assert_eq!(srd.total_height, Some(20));
+    assert_eq!(srd.spatial_set_id, Some(7));
+    assert!(parse_srd("1,0,0,1920").is_none());
+    assert!(parse_srd("1,0,0,1920,1080,3840").is_none());
+    assert!(parse_srd("1,0,0,wide,1080").is_none());
+
+    let mpd = parse(MPD).unwrap();
+    let adaptations = &mpd.periods[0].adaptations;
+    let srd = spatial_relationship(&adaptations[3], None).unwrap();
+    assert_eq!((srd.source_id, srd.object_x, srd.object_y), (1, 0, 1080));
+    assert_eq!(spatial_relationship(&adaptations[0], None).unwrap().total_width, Some(3840));
+    assert!(spatial_relationship(&adaptations[6], None).is_none());
+    assert_eq!(adaptation_viewpoints(&adaptations[5]), vec!["cam2"]);
+    assert!(adaptation_viewpoints(&adaptations[1]).is_empty());
+}
+
+
+#[tokio::test]
+async fn test_srd_viewpoint_selection() {
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6694, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("match.mp4");
+    let url = "http://localhost:6694/mpd";
+    let cases: [(DashDownloader, Vec<&str>, &str); 4] = [
+        // The tiles are not alternatives to the full frame video streams.
+        (DashDownloader::new(url).best_quality().video_only(), vec!["cam2-1.m4s"], "video"),
+        (DashDownloader::new(url).best_quality().video_only().prefer_viewpoint("Main-Camera"),
+         vec!["main-full-1.m4s"], "video"),
+        (DashDownloader::new(url).best_quality().audio_only().prefer_viewpoint("main-camera"),
+         vec!["audio-main-1.m4s"], "audio"),
+        (DashDownloader::new(url).best_quality().audio_only().prefer_viewpoint("crane"),
+         vec!["audio-cam2-1.m4s"], "audio"),
+    ];
+    for (downloader, expected, stream_type) in cases {
+        requests.lock().unwrap().clear();
+        downloader
+            .download_to(&out).await
+            .unwrap();
+        let mut fetched = requests.lock().unwrap().clone();
+        fetched.sort();
+        assert_eq!(fetched, expected);
+        assert_eq!(ffprobe_stream_types(&out), vec![stream_type]);
+    }
+
+    // Download the four tiles of the main camera as well as the full frame.
+    requests.lock().unwrap().clear();
+    DashDownloader::new(url)
+        .best_quality()
+        .video_only()
+        .prefer_viewpoint("main-camera")
+        .fetch_srd_tiles(true)
+        .download_to(&out).await
+        .unwrap();
+    let mut fetched = requests.lock().unwrap().clone();
+    fetched.sort();
+    assert_eq!(fetched, vec!["main-full-1.m4s", "tile-a-1.m4s", "tile-b-1.m4s", "tile-c-1.m4s", "tile-d-1.m4s"]);
+    let tiles_dir = tmpd.path().join("match.tiles");
+    for i in 1..=4 {
+        assert_eq!(ffprobe_stream_types(&tiles_dir.join(format!("tile-1-0{i}.mp4"))), vec!["video"]);
+    }
+    let index: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(tiles_dir.join("tiles.json")).unwrap()).unwrap();
+    let tiles = index.as_array().unwrap();
+    assert_eq!(tiles.len(), 4);
+    assert_eq!(tiles[0]["file"], "tile-1-01.mp4");
+    assert_eq!(tiles[0]["representation_id"], "tile-a");
+    assert_eq!(tiles[3]["object_x"], 1920);
+    assert_eq!(tiles[3]["object_y"], 1080);
+    assert_eq!(tiles[3]["total_width"], 3840);
+    assert_eq!(tiles[3]["total_height"], 2160);
+    server_handle.shutdown();
+}
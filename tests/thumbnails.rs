@@ -0,0 +1,88 @@
+//! Tests for the download of thumbnail tracks and the handling of trick-mode video
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test thumbnails -- --show-output
+//
+
+pub mod common;
+use std::fs;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use common::{serve_manifests, ffprobe_stream_types, setup_logging};
+
+
+#[tokio::test]
+async fn test_thumbnails_trickmode() {
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT20S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT20S">
+    <AdaptationSet id="1" contentType="video" mimeType="video/mp4" codecs="avc1.640028">
+      <SegmentTemplate timescale="1000" duration="20000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="video" bandwidth="2000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="2" contentType="video" mimeType="video/mp4" codecs="avc1.640028" maxPlayoutRate="16">
+      <EssentialProperty schemeIdUri="http://dashif.org/guidelines/trickmode" value="1"/>
+      <SegmentTemplate timescale="1000" duration="20000" startNumber="1" media="/media/$RepresentationID$-$Number$.m4s"/>
+      <Representation id="trick" bandwidth="8000000" width="1920" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet id="3" contentType="image" mimeType="image/jpeg">
+      <SegmentTemplate timescale="1" duration="10" startNumber="1" media="/media/$RepresentationID$-$Number$.jpg"/>
+      <Representation id="thumbs-lo" bandwidth="1000" width="320" height="90">
+        <EssentialProperty schemeIdUri="http://dashif.org/thumbnail_tile" value="2x1"/>
+      </Representation>
+      <Representation id="thumbs-hi" bandwidth="4000" width="512" height="288">
+        <EssentialProperty schemeIdUri="http://dashif.org/thumbnail_tile" value="2x2"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    setup_logging();
+    let (server_handle, requests) = serve_manifests(6692, &[("/mpd", MPD)]).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("movie.mp4");
+    let url = "http://localhost:6692/mpd";
+
+    // The trick-mode AdaptationSet is not a candidate for the main video track.
+    DashDownloader::new(url)
+        .best_quality()
+        .video_only()
+        .download_to(&out).await
+        .unwrap();
+    assert_eq!(requests.lock().unwrap().clone(), vec!["video-1.m4s"]);
+    assert_eq!(ffprobe_stream_types(&out), vec!["video"]);
+
+    requests.lock().unwrap().clear();
+    DashDownloader::new(url)
+        .best_quality()
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_thumbnails(true)
+        .download_to(&out).await
+        .unwrap();
+    assert_eq!(requests.lock().unwrap().clone(), vec!["thumbs-hi-1.jpg", "thumbs-hi-2.jpg"]);
+    let tile = fs::read_to_string(tmpd.path().join("movie.thumbnails").join("tile-1-00002.jpg")).unwrap();
+    assert_eq!(tile, "thumbs-hi-2.jpg");
+    let vtt = fs::read_to_string(tmpd.path().join("movie.thumbnails.vtt")).unwrap();
+    assert!(vtt.starts_with("WEBVTT\n\n00:00:00.000 --> 00:00:02.500\nmovie.thumbnails/tile-1-00001.jpg#xywh=0,0,256,144\n"));
+    assert!(vtt.contains("00:00:17.500 --> 00:00:20.000\nmovie.thumbnails/tile-1-00002.jpg#xywh=256,144,256,144\n"));
+    assert_eq!(vtt.matches("-->").count(), 8);
+
+    requests.lock().unwrap().clear();
+    DashDownloader::new(url)
+        .worst_quality()
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_thumbnails(true)
+        .download_to(&out).await
+        .unwrap();
+    assert_eq!(requests.lock().unwrap().clone(), vec!["thumbs-lo-1.jpg", "thumbs-lo-2.jpg"]);
+    let tile = fs::read_to_string(tmpd.path().join("movie.thumbnails").join("tile-1-00001.jpg")).unwrap();
+    assert_eq!(tile, "thumbs-lo-1.jpg");
+    let vtt = fs::read_to_string(tmpd.path().join("movie.thumbnails.vtt")).unwrap();
+    assert!(vtt.starts_with("WEBVTT\n\n00:00:00.000 --> 00:00:05.000\nmovie.thumbnails/tile-1-00001.jpg#xywh=0,0,160,90\n"));
+    assert_eq!(vtt.matches("-->").count(), 4);
+    server_handle.shutdown();
+}
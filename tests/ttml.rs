@@ -0,0 +1,294 @@
+// Tests for the conversion of TTML (and STPP) subtitles to WebVTT and SRT, and for the extraction of
+// image subtitles from the IMSC1 image profile.
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test ttml -- --show-output
+
+
+pub mod common;
+use std::fs;
+use std::env;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::time::Duration;
+use axum::{routing::get, Router};
+use axum::extract::Path as AxumPath;
+use axum::response::{Response, IntoResponse};
+use axum::http::{header, StatusCode};
+use axum::body::Body;
+use axum_server::{Handle, bind};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use bytes::Bytes;
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use dash_mpd::stpp::StppDocument;
+use anyhow::Result;
+use common::setup_logging;
+
+
+const TTML_TICKS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter"
+    xmlns:tts="http://www.w3.org/ns/ttml#styling" ttp:tickRate="10000000" xml:lang="en">
+  <head>
+    <styling>
+      <style xml:id="base" tts:textAlign="center" tts:color="white"/>
+      <style xml:id="emphasis" style="base" tts:fontStyle="italic"/>
+    </styling>
+    <layout>
+      <region xml:id="bottom" tts:origin="10% 80%" tts:extent="80% 10%" tts:displayAlign="after"/>
+      <region xml:id="top" tts:origin="10% 5%" tts:extent="80% 10%"/>
+    </layout>
+  </head>
+  <body style="base">
+    <div begin="10000000t">
+      <p begin="0t" end="20000000t" region="bottom">Hello,
+         <span style="emphasis">world</span> &amp; all</p>
+      <p begin="30000000t" dur="15000000t" region="top">First line<br/>Second
+        line</p>
+      <p begin="50000000t" end="80000000t" region="bottom">Roll <span begin="10000000t">up</span></p>
+    </div>
+  </body>
+</tt>"#;
+
+#[test]
+fn test_ttml_ticks_spans_regions() {
+    let mut doc = StppDocument::new();
+    doc.add_content(TTML_TICKS).unwrap();
+    assert_eq!(doc.to_webvtt(), "WEBVTT\n\n\
+00:00:01.000 --> 00:00:03.000 line:90%,end position:10%,line-left size:80% align:center\n\
+Hello, <i>world</i> &amp; all\n\n\
+00:00:04.000 --> 00:00:05.500 line:5% position:10%,line-left size:80% align:center\n\
+First line\nSecond line\n\n\
+00:00:06.000 --> 00:00:07.000 line:90%,end position:10%,line-left size:80% align:center\n\
+Roll\n\n\
+00:00:07.000 --> 00:00:09.000 line:90%,end position:10%,line-left size:80% align:center\n\
+Roll up\n\n");
+    assert_eq!(doc.to_srt(), "1\n00:00:01,000 --> 00:00:03,000\nHello, <i>world</i> & all\n\n\
+2\n00:00:04,000 --> 00:00:05,500\nFirst line\nSecond line\n\n\
+3\n00:00:06,000 --> 00:00:07,000\nRoll\n\n\
+4\n00:00:07,000 --> 00:00:09,000\nRoll up\n\n");
+}
+
+#[test]
+fn test_ttml_frames_and_seq() {
+    // 30 frames per second with a multiplier of 1000/1001 (29.97 fps), and a seq time container.
+    let ttml = r#"<tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter"
+    xmlns:tts="http://www.w3.org/ns/ttml#styling" ttp:frameRate="30" ttp:frameRateMultiplier="1000 1001"
+    tts:extent="1920px 1080px">
+  <head><layout><region xml:id="r" tts:origin="192px 54px" tts:extent="1536px 108px"/></layout></head>
+  <body>
+    <div timeContainer="seq">
+      <p dur="2s" region="r" tts:fontWeight="bold">One</p>
+      <p dur="00:00:01:15">Two</p>
+      <p begin="500ms" dur="1s">Three</p>
+    </div>
+    <div><p begin="00:01:00:00" end="00:01:00:15.1">Frames</p></div>
+  </body>
+</tt>"#;
+    let mut doc = StppDocument::new();
+    doc.add_content(ttml).unwrap();
+    let cues = doc.cues();
+    assert_eq!(cues.len(), 4);
+    assert_eq!((cues[0].start_ms, cues[0].end_ms), (0, 2000));
+    assert_eq!(cues[0].payload, "<b>One</b>");
+    assert_eq!(cues[0].settings.as_deref(), Some("line:5% position:10%,line-left size:80%"));
+    // 1 second plus 15 frames at 29.97 fps
+    assert_eq!((cues[1].start_ms, cues[1].end_ms), (2000, 3501));
+    assert_eq!((cues[2].start_ms, cues[2].end_ms), (4001, 5001));
+    // 15 frames and 1 subframe, with the default of 1 subframe per frame
+    assert_eq!((cues[3].start_ms, cues[3].end_ms), (60000, 60534));
+}
+
+#[test]
+fn test_ttml_smpte_drop_frame() {
+    // Drop-frame NTSC time codes: frame numbers 0 and 1 are skipped at the start of each minute
+    // except every tenth minute, so 00:01:00:02 is the 1800th frame.
+    let ttml = |drop_mode: &str| format!(
+        r#"<tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter"
+    ttp:timeBase="smpte" ttp:dropMode="{drop_mode}" ttp:frameRate="30" ttp:frameRateMultiplier="1000 1001">
+  <body><div>
+    <p begin="00:01:00:02" end="00:01:00:12">Dropped</p>
+    <p begin="00:10:00:00" end="00:10:01:00">Tenth minute</p>
+  </div></body>
+</tt>"#);
+    let mut doc = StppDocument::new();
+    doc.add_content(&ttml("dropNTSC")).unwrap();
+    let cues = doc.cues();
+    assert_eq!((cues[0].start_ms, cues[0].end_ms), (60060, 60394));
+    assert_eq!((cues[1].start_ms, cues[1].end_ms), (599999, 601000));
+    let mut doc = StppDocument::new();
+    doc.add_content(&ttml("nonDrop")).unwrap();
+    let cues = doc.cues();
+    assert_eq!((cues[0].start_ms, cues[0].end_ms), (60067, 60400));
+}
+
+#[test]
+fn test_ttml_fragments_deduplicated() {
+    // A paragraph spanning a segment boundary is repeated in both fragments.
+    let frag = |begin: &str, end: &str, text: &str| format!(
+        r#"<tt xmlns="http://www.w3.org/ns/ttml"><body><div><p begin="{begin}" end="{end}">{text}</p></div></body></tt>"#);
+    let mut doc = StppDocument::new();
+    doc.add_content(&frag("00:00:01.000", "00:00:02.000", "Split")).unwrap();
+    doc.add_content(&frag("00:00:02.000", "00:00:03.000", "Split")).unwrap();
+    doc.add_content(&frag("00:00:02.500", "00:00:04.000", "Other")).unwrap();
+    let cues = doc.cues();
+    assert_eq!(cues.len(), 2);
+    assert_eq!((cues[0].start_ms, cues[0].end_ms), (1000, 3000));
+    assert_eq!((cues[1].start_ms, cues[1].end_ms), (2500, 4000));
+}
+
+#[test]
+fn test_ttml_period_offsets() {
+    let frag = |begin: &str, end: &str, text: &str| format!(
+        r#"<tt xmlns="http://www.w3.org/ns/ttml"><body><div><p begin="{begin}" end="{end}">{text}</p></div></body></tt>"#);
+    let mut doc = StppDocument::new();
+    doc.add_content(&frag("00:00:01.000", "00:00:02.000", "First Period")).unwrap();
+    // The second Period starts 30 seconds into the output media, and its times are relative to
+    // the start of the Period.
+    doc.set_offset_ms(30_000);
+    doc.add_content(&frag("00:00:01.000", "00:00:02.000", "Second Period")).unwrap();
+    let cues = doc.cues();
+    assert_eq!(cues.len(), 2);
+    assert_eq!((cues[0].start_ms, cues[0].end_ms), (1000, 2000));
+    assert_eq!((cues[1].start_ms, cues[1].end_ms), (31000, 32000));
+    // The merged TTML document places the second Period in a div that begins at its offset.
+    let merged = doc.to_string();
+    assert!(merged.contains(r#"begin="30s""#));
+    let mut reparsed = StppDocument::new();
+    reparsed.add_content(&merged).unwrap();
+    assert_eq!(reparsed.cues(), cues);
+}
+
+
+fn make_png(width: u32) -> Vec<u8> {
+    let img = image::RgbaImage::new(width, 2);
+    let mut png = Vec::new();
+    img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+    png
+}
+
+fn mp4_box(boxtype: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut b = Vec::new();
+    b.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    b.extend_from_slice(boxtype);
+    b.extend_from_slice(payload);
+    b
+}
+
+// An STPP media segment for the IMSC1 image profile, with the images stored as subsamples following
+// the TTML document in the mdat box.
+fn im1i_segment(begin: &str, end: &str, images: &[Vec<u8>]) -> Vec<u8> {
+    let refs: String = (1..=images.len())
+        .map(|i| format!(r#"<div region="r" begin="{begin}" end="{end}" smpte:backgroundImage="urn:mpeg:14496-30:subs:{i}"/>"#))
+        .collect();
+    let ttml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<tt xmlns="http://www.w3.org/ns/ttml" xmlns:tts="http://www.w3.org/ns/ttml#styling"
+    xmlns:smpte="http://www.smpte-ra.org/schemas/2052-1/2010/smpte-tt">
+  <head><layout><region xml:id="r" tts:origin="20% 80%" tts:extent="60% 15%"/></layout></head>
+  <body>{refs}</body>
+</tt>"#);
+    let mut mdat = ttml.into_bytes();
+    for img in images {
+        mdat.extend_from_slice(img);
+    }
+    let mut segment = mp4_box(b"styp", b"msdh\0\0\0\0msdhmsix");
+    segment.extend_from_slice(&mp4_box(b"mdat", &mdat));
+    segment
+}
+
+#[test]
+fn test_ttml_images() {
+    let png1 = make_png(1);
+    let png2 = make_png(2);
+    // Image embedded in the document as a smpte:image element
+    let ttml = format!(r##"<tt xmlns="http://www.w3.org/ns/ttml"
+    xmlns:smpte="http://www.smpte-ra.org/schemas/2052-1/2010/smpte-tt">
+  <head><metadata><smpte:image xml:id="img1" imagetype="PNG" encoding="Base64">{}</smpte:image></metadata></head>
+  <body><div begin="1s" end="2s" smpte:backgroundImage="#img1"/></body>
+</tt>"##, BASE64_STANDARD.encode(&png1));
+    let mut doc = StppDocument::new();
+    doc.add_content(&ttml).unwrap();
+    assert_eq!(doc.images().len(), 1);
+    assert_eq!(doc.images()[0].png, png1);
+    assert_eq!((doc.images()[0].start_ms, doc.images()[0].end_ms), (1000, 2000));
+
+    // Images stored as subsamples in fMP4 segments
+    let mut doc = StppDocument::new();
+    doc.add_from_mp4(&Bytes::from(im1i_segment("00:00:02.000", "00:00:04.000", &[png1.clone(), png2.clone()]))).unwrap();
+    doc.add_from_mp4(&Bytes::from(im1i_segment("00:00:04.000", "00:00:05.000", std::slice::from_ref(&png2)))).unwrap();
+    // The second image is repeated in the following segment, so is merged.
+    let images = doc.images();
+    assert_eq!(images.len(), 2);
+    assert_eq!(images[0].png, png1);
+    assert_eq!((images[0].start_ms, images[0].end_ms), (2000, 4000));
+    assert_eq!(images[1].png, png2);
+    assert_eq!((images[1].start_ms, images[1].end_ms), (2000, 5000));
+    assert_eq!(images[0].settings.as_deref(), Some("line:80% position:20%,line-left size:60%"));
+    assert!(doc.cues().is_empty());
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_ttml_download() -> Result<()> {
+    async fn send_mpd() -> impl IntoResponse {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT4S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT4S">
+    <AdaptationSet id="1" contentType="text" mimeType="application/mp4" lang="en">
+      <SegmentTemplate timescale="1000" duration="2000" startNumber="1" media="/subs/seg-$Number$.m4s"/>
+      <Representation id="en" codecs="stpp.ttml.im1t" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        ([(header::CONTENT_TYPE, "application/dash+xml")], xml)
+    }
+
+    async fn send_segment(AxumPath(name): AxumPath<String>) -> Response {
+        let (begin, end) = match name.as_str() {
+            "seg-1.m4s" => ("00:00:00.500", "00:00:02.000"),
+            "seg-2.m4s" => ("00:00:02.000", "00:00:03.000"),
+            _ => return StatusCode::NOT_FOUND.into_response(),
+        };
+        let ttml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<tt xmlns="http://www.w3.org/ns/ttml"><body><div><p begin="{begin}" end="{end}">Across segments</p></div></body></tt>"#);
+        let mut segment = mp4_box(b"styp", b"msdh\0\0\0\0msdhmsix");
+        segment.extend_from_slice(&mp4_box(b"mdat", ttml.as_bytes()));
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/mp4")
+            .body(Body::from(segment))
+            .unwrap()
+    }
+
+    setup_logging();
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/subs/{name}", get(send_segment));
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    let backend_handle = server_handle.clone();
+    let backend = async move {
+        bind("127.0.0.1:6674".parse().unwrap())
+            .handle(backend_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let outpath = env::temp_dir().join("ttml-convert.mp4");
+    DashDownloader::new("http://localhost:6674/mpd")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .download_to(&outpath).await
+        .unwrap();
+    let ttml = fs::read_to_string(outpath.with_extension("ttml"))?;
+    assert!(ttml.contains("Across segments"));
+    let vtt = fs::read_to_string(outpath.with_extension("vtt"))?;
+    assert_eq!(vtt, "WEBVTT\n\n00:00:00.500 --> 00:00:03.000\nAcross segments\n\n");
+    let srt = fs::read_to_string(outpath.with_extension("srt"))?;
+    assert_eq!(srt, "1\n00:00:00,500 --> 00:00:03,000\nAcross segments\n\n");
+    server_handle.shutdown();
+    Ok(())
+}
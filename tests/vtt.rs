@@ -0,0 +1,187 @@
+// Tests for merging segmented WebVTT subtitles
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test vtt -- --show-output
+
+
+pub mod common;
+use std::fs;
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+use axum::{routing::get, Router};
+use axum::extract::Path as AxumPath;
+use axum::response::IntoResponse;
+use axum::http::{header, StatusCode};
+use axum_server::{Handle, bind};
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use dash_mpd::vtt::VttDocument;
+use anyhow::Result;
+use common::setup_logging;
+
+
+const SEGMENT1: &str = "WEBVTT\r\n\
+Kind: captions\r\n\
+\r\n\
+STYLE\r\n\
+::cue { color: white }\r\n\
+\r\n\
+NOTE produced by our segmenter\r\n\
+\r\n\
+1\r\n\
+00:00.500 --> 00:01.500 line:90%\r\n\
+First cue\r\n\
+\r\n\
+2\r\n\
+00:00:03.000 --> 00:00:04.000\r\n\
+Crossing the\r\n\
+<i>segment boundary</i>\r\n";
+
+const SEGMENT2: &str = "WEBVTT\n\
+Kind: captions\n\
+\n\
+STYLE\n\
+::cue { color: white }\n\
+\n\
+2\n\
+00:00:03.000 --> 00:00:04.000\n\
+Crossing the\n\
+<i>segment boundary</i>\n\
+\n\
+00:00:04.000 --> 00:00:05.250 align:start\n\
+<v Bob>Last &amp; final</v>\n";
+
+
+#[test]
+fn test_vtt_merging() {
+    let mut doc = VttDocument::new();
+    doc.add_content(SEGMENT1).unwrap();
+    doc.add_content(SEGMENT2).unwrap();
+    assert_eq!(doc.cues().len(), 3);
+    assert_eq!(doc.to_string(), "WEBVTT\n\
+Kind: captions\n\
+\n\
+STYLE\n\
+::cue { color: white }\n\
+\n\
+1\n\
+00:00:00.500 --> 00:00:01.500 line:90%\n\
+First cue\n\
+\n\
+2\n\
+00:00:03.000 --> 00:00:04.000\n\
+Crossing the\n\
+<i>segment boundary</i>\n\
+\n\
+00:00:04.000 --> 00:00:05.250 align:start\n\
+<v Bob>Last &amp; final</v>\n\n");
+    assert_eq!(doc.to_srt(), "1\n\
+00:00:00,500 --> 00:00:01,500\n\
+First cue\n\
+\n\
+2\n\
+00:00:03,000 --> 00:00:04,000\n\
+Crossing the\n\
+<i>segment boundary</i>\n\
+\n\
+3\n\
+00:00:04,000 --> 00:00:05,250\n\
+Last & final\n\n");
+}
+
+#[test]
+fn test_vtt_split_cues() {
+    // A cue that spans a segment boundary, clipped to the segment boundaries in each segment.
+    let mut doc = VttDocument::new();
+    doc.add_content("WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nHello\n\n00:00:08.000 --> 00:00:10.000\nSplit").unwrap();
+    doc.add_content("WEBVTT\n\n00:00:10.000 --> 00:00:11.500\nSplit\n\n00:00:12.000 --> 00:00:13.000\nHello").unwrap();
+    let cues = doc.cues();
+    assert_eq!(cues.len(), 3);
+    assert_eq!((cues[1].start_ms, cues[1].end_ms), (8000, 11500));
+    assert_eq!((cues[2].start_ms, cues[2].end_ms), (12000, 13000));
+}
+
+#[test]
+fn test_vtt_timestamp_map() {
+    let mut doc = VttDocument::new();
+    // MPEGTS 900000 corresponds to 10 seconds.
+    doc.add_content("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\n00:00:01.000 --> 00:00:02.000\nTen").unwrap();
+    doc.add_content("WEBVTT\nX-TIMESTAMP-MAP=LOCAL:00:00:01.000,MPEGTS:900000\n\n00:00:03.000 --> 00:00:04.000\nEleven").unwrap();
+    doc.set_offset_ms(60_000);
+    doc.add_content("WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nNext period").unwrap();
+    let cues = doc.cues();
+    assert_eq!((cues[0].start_ms, cues[0].end_ms), (11000, 12000));
+    assert_eq!((cues[1].start_ms, cues[1].end_ms), (12000, 13000));
+    assert_eq!((cues[2].start_ms, cues[2].end_ms), (61000, 62000));
+    let vtt = doc.to_string();
+    assert!(!vtt.contains("X-TIMESTAMP-MAP"));
+    assert_eq!(vtt.matches("WEBVTT").count(), 1);
+    assert!(doc.add_content("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:foo,LOCAL:00:00:00.000\n").is_err());
+}
+
+
+// Subtitles in a multi-period manifest are merged into a single WebVTT file, with cue times
+// shifted by the start of each Period.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_vtt_multiperiod_download() -> Result<()> {
+    async fn send_mpd() -> impl IntoResponse {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT20S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="p1" start="PT0S">
+    <AdaptationSet id="1" contentType="text" mimeType="text/vtt" lang="en">
+      <SegmentTemplate timescale="1000" duration="10000" startNumber="1" media="/subs/p1-$Number$.vtt"/>
+      <Representation id="en" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+  <Period id="p2" start="PT10S">
+    <AdaptationSet id="1" contentType="text" mimeType="text/vtt" lang="en">
+      <SegmentTemplate timescale="1000" duration="10000" startNumber="1" media="/subs/p2-$Number$.vtt"/>
+      <Representation id="en" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        ([(header::CONTENT_TYPE, "application/dash+xml")], xml)
+    }
+
+    async fn send_segment(AxumPath(name): AxumPath<String>) -> impl IntoResponse {
+        let vtt = match name.as_str() {
+            "p1-1.vtt" => "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nFirst period\n",
+            "p2-1.vtt" => "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nSecond period\n",
+            _ => return StatusCode::NOT_FOUND.into_response(),
+        };
+        ([(header::CONTENT_TYPE, "text/vtt")], vtt).into_response()
+    }
+
+    setup_logging();
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/subs/{name}", get(send_segment));
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    let backend_handle = server_handle.clone();
+    let backend = async move {
+        bind("127.0.0.1:6673".parse().unwrap())
+            .handle(backend_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let outpath = env::temp_dir().join("vtt-multiperiod.mp4");
+    DashDownloader::new("http://localhost:6673/mpd")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .download_to(&outpath).await
+        .unwrap();
+    let vtt = fs::read_to_string(outpath.with_extension("vtt"))?;
+    assert_eq!(vtt, "WEBVTT\n\n\
+                     00:00:01.000 --> 00:00:02.000\nFirst period\n\n\
+                     00:00:11.000 --> 00:00:12.000\nSecond period\n\n");
+    let srt = fs::read_to_string(outpath.with_extension("srt"))?;
+    assert!(srt.contains("2\n00:00:11,000 --> 00:00:12,000\nSecond period\n"));
+    server_handle.shutdown();
+    Ok(())
+}
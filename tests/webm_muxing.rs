@@ -0,0 +1,447 @@
+// Tests for the native WebM/Matroska muxer and Period concatenation.
+//
+// These tests use synthetic WebM streams (a VP9 video stream and two Opus audio streams, whose
+// frames contain arbitrary data), generated with the webm-iterable crate and served by a local axum
+// server. They don't require any external muxing application.
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test webm_muxing -- --show-output
+
+pub mod common;
+use std::fs;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use axum::{routing::get, Router};
+use axum::extract::{Path as AxumPath, State};
+use axum::response::IntoResponse;
+use axum::http::{header, StatusCode};
+use axum_server::{Handle, bind};
+use webm_iterable::{WebmIterator, WebmWriter, WriteOptions};
+use webm_iterable::matroska_spec::{MatroskaSpec, Master};
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use common::setup_logging;
+
+
+const ADAPTATIONS: &str = r#"
+    <AdaptationSet contentType="video" mimeType="video/webm" segmentAlignment="true">
+      <Representation id="video" codecs="vp09.00.10.08" bandwidth="200000" width="320" height="240">
+        <SegmentTemplate timescale="1000" duration="160" startNumber="1"
+                         initialization="/media/video-init.webm" media="/media/video-$Number$.webm"/>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" mimeType="audio/webm" lang="en">
+      <Representation id="audio-en" codecs="opus" bandwidth="64000" audioSamplingRate="48000">
+        <SegmentTemplate timescale="1000" duration="160" startNumber="1"
+                         initialization="/media/en-init.webm" media="/media/en-$Number$.webm"/>
+      </Representation>
+    </AdaptationSet>"#;
+
+const AUDIO_FR: &str = r#"
+    <AdaptationSet contentType="audio" mimeType="audio/webm" lang="fr">
+      <Representation id="audio-fr" codecs="opus" bandwidth="64000" audioSamplingRate="48000">
+        <SegmentTemplate timescale="1000" duration="160" startNumber="1"
+                         initialization="/media/fr-init.webm" media="/media/fr-$Number$.webm"/>
+      </Representation>
+    </AdaptationSet>"#;
+
+// Frame durations in milliseconds (the default TimestampScale).
+const VIDEO_FRAME_DURATION: u64 = 40;
+const AUDIO_FRAME_DURATION: u64 = 20;
+
+fn single_period_mpd() -> String {
+    format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static"
+     profiles="urn:mpeg:dash:profile:isoff-live:2011"
+     mediaPresentationDuration="PT0.32S" minBufferTime="PT2S">
+  <Period id="1">{ADAPTATIONS}{AUDIO_FR}
+  </Period>
+</MPD>"#)
+}
+
+fn two_period_mpd() -> String {
+    format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static"
+     profiles="urn:mpeg:dash:profile:isoff-live:2011"
+     mediaPresentationDuration="PT0.64S" minBufferTime="PT2S">
+  <Period id="1" duration="PT0.32S">{ADAPTATIONS}
+  </Period>
+  <Period id="2" duration="PT0.32S">{ADAPTATIONS}
+  </Period>
+</MPD>"#)
+}
+
+// Serialize the children of a Segment element, which the writer requires to be written within a
+// Segment.
+fn encode_in_segment(tags: &[MatroskaSpec]) -> Vec<u8> {
+    let mut writer = WebmWriter::new(Vec::new());
+    writer.write_advanced(&MatroskaSpec::Segment(Master::Start), WriteOptions::is_unknown_sized_element()).unwrap();
+    for tag in tags {
+        writer.write(tag).unwrap();
+    }
+    writer.into_inner().unwrap()
+}
+
+// The content of video frame n (counting from zero over all segments).
+fn video_frame(n: usize) -> Vec<u8> {
+    vec![0x40 + n as u8; 100 + 10 * n]
+}
+
+// The content of audio frame n in the stream with the given marker octet.
+fn audio_frame(marker: u8, n: usize) -> Vec<u8> {
+    let mut s = vec![marker; 20 + n];
+    s[0] = n as u8;
+    s
+}
+
+// An initialization segment: the EBML header, and a Segment of unknown size containing the Info and
+// Tracks elements. The input streams use track number 1.
+fn init_segment(track: Vec<MatroskaSpec>) -> Vec<u8> {
+    let mut writer = WebmWriter::new(Vec::new());
+    writer.write(&MatroskaSpec::Ebml(Master::Full(vec![
+        MatroskaSpec::EbmlVersion(1),
+        MatroskaSpec::EbmlReadVersion(1),
+        MatroskaSpec::EbmlMaxIdLength(4),
+        MatroskaSpec::EbmlMaxSizeLength(8),
+        MatroskaSpec::DocType(String::from("webm")),
+        MatroskaSpec::DocTypeVersion(4),
+        MatroskaSpec::DocTypeReadVersion(2),
+    ]))).unwrap();
+    let mut buf = writer.into_inner().unwrap();
+    let mut entry = vec![
+        MatroskaSpec::TrackNumber(1),
+        MatroskaSpec::TrackUID(1),
+    ];
+    entry.extend(track);
+    buf.extend(encode_in_segment(&[
+        MatroskaSpec::Info(Master::Full(vec![
+            MatroskaSpec::TimestampScale(1_000_000),
+            MatroskaSpec::MuxingApp(String::from("test")),
+            MatroskaSpec::WritingApp(String::from("test")),
+        ])),
+        MatroskaSpec::Tracks(Master::Full(vec![
+            MatroskaSpec::TrackEntry(Master::Full(entry)),
+        ])),
+    ]));
+    buf
+}
+
+fn video_init() -> Vec<u8> {
+    init_segment(vec![
+        MatroskaSpec::TrackType(1),
+        MatroskaSpec::CodecID(String::from("V_VP9")),
+        MatroskaSpec::DefaultDuration(VIDEO_FRAME_DURATION * 1_000_000),
+        MatroskaSpec::Video(Master::Full(vec![
+            MatroskaSpec::PixelWidth(320),
+            MatroskaSpec::PixelHeight(240),
+        ])),
+    ])
+}
+
+fn audio_init() -> Vec<u8> {
+    init_segment(vec![
+        MatroskaSpec::TrackType(2),
+        MatroskaSpec::CodecID(String::from("A_OPUS")),
+        MatroskaSpec::CodecPrivate(b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00".to_vec()),
+        MatroskaSpec::DefaultDuration(AUDIO_FRAME_DURATION * 1_000_000),
+        MatroskaSpec::Audio(Master::Full(vec![
+            MatroskaSpec::SamplingFrequency(48000.0),
+            MatroskaSpec::Channels(2),
+        ])),
+    ])
+}
+
+fn simple_block(rel: i16, keyframe: bool, frame: &[u8]) -> MatroskaSpec {
+    let mut data = vec![0x81];
+    data.extend(rel.to_be_bytes());
+    data.push(if keyframe { 0x80 } else { 0 });
+    data.extend_from_slice(frame);
+    MatroskaSpec::SimpleBlock(data)
+}
+
+// A media segment is a single Cluster (without the Segment header).
+fn cluster(timestamp: u64, blocks: Vec<MatroskaSpec>) -> Vec<u8> {
+    let mut children = vec![MatroskaSpec::Timestamp(timestamp)];
+    children.extend(blocks);
+    encode_in_segment(&[MatroskaSpec::Cluster(Master::Full(children))])[12..].to_vec()
+}
+
+// Each video segment contains four frames, of which the first is a keyframe.
+fn video_segment(number: usize) -> Vec<u8> {
+    let first = (number - 1) * 4;
+    let blocks = (0..4)
+        .map(|i| simple_block((i as u64 * VIDEO_FRAME_DURATION) as i16, i == 0, &video_frame(first + i)))
+        .collect();
+    cluster(first as u64 * VIDEO_FRAME_DURATION, blocks)
+}
+
+// Each audio segment contains eight frames.
+fn audio_segment(marker: u8, number: usize) -> Vec<u8> {
+    let first = (number - 1) * 8;
+    let blocks = (0..8)
+        .map(|i| simple_block((i as u64 * AUDIO_FRAME_DURATION) as i16, true, &audio_frame(marker, first + i)))
+        .collect();
+    cluster(first as u64 * AUDIO_FRAME_DURATION, blocks)
+}
+
+fn media_files() -> HashMap<String, Vec<u8>> {
+    let mut files = HashMap::new();
+    files.insert(String::from("video-init.webm"), video_init());
+    files.insert(String::from("en-init.webm"), audio_init());
+    files.insert(String::from("fr-init.webm"), audio_init());
+    for n in 1..=2 {
+        files.insert(format!("video-{n}.webm"), video_segment(n));
+        files.insert(format!("en-{n}.webm"), audio_segment(0xE0, n));
+        files.insert(format!("fr-{n}.webm"), audio_segment(0xF0, n));
+    }
+    files
+}
+
+// The MPD manifest and the media files served by the test server.
+type ServerState = (String, HashMap<String, Vec<u8>>);
+
+async fn serve_media(server_handle: Handle<SocketAddr>, port: u16, mpd: String) {
+    async fn send_mpd(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+        ([(header::CONTENT_TYPE, "application/dash+xml")], state.0.clone())
+    }
+    async fn send_media(
+        AxumPath(name): AxumPath<String>,
+        State(state): State<Arc<ServerState>>) -> impl IntoResponse
+    {
+        match state.1.get(&name) {
+            Some(content) => ([(header::CONTENT_TYPE, "video/webm")], content.clone()).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/media/{name}", get(send_media))
+        .with_state(Arc::new((mpd, media_files())));
+    let backend = async move {
+        bind(format!("127.0.0.1:{port}").parse().unwrap())
+            .handle(server_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+}
+
+// The parsed content of a Matroska file produced by the native muxer.
+#[derive(Debug, Default)]
+struct Parsed {
+    // Offset of the start of the Segment data in the file.
+    segment_data_start: usize,
+    seeks: Vec<(Vec<u8>, u64)>,
+    duration: f64,
+    tracks: Vec<Vec<MatroskaSpec>>,
+    // Track number, absolute timestamp, keyframe flag and frame content of each block.
+    blocks: Vec<(u64, i64, bool, Vec<u8>)>,
+    cluster_count: usize,
+    cues: Vec<(u64, u64)>,
+}
+
+fn parse_output(content: &[u8]) -> Parsed {
+    let buffered = [
+        MatroskaSpec::SeekHead(Master::Start),
+        MatroskaSpec::Info(Master::Start),
+        MatroskaSpec::Tracks(Master::Start),
+        MatroskaSpec::Cluster(Master::Start),
+        MatroskaSpec::Cues(Master::Start),
+    ];
+    let mut iter = WebmIterator::new(content, &buffered);
+    let mut parsed = Parsed::default();
+    while let Some(tag) = iter.next() {
+        let offset = iter.last_emitted_tag_offset();
+        match tag.unwrap() {
+            MatroskaSpec::Segment(Master::Start) => {
+                // The Segment size is written as an 8-octet integer.
+                assert_eq!(content[offset + 4], 0x01);
+                parsed.segment_data_start = offset + 12;
+            },
+            MatroskaSpec::SeekHead(Master::Full(seeks)) => {
+                for s in seeks {
+                    if let MatroskaSpec::Seek(Master::Full(children)) = s {
+                        let id = children.iter().find_map(|c| match c {
+                            MatroskaSpec::SeekID(id) => Some(id.clone()),
+                            _ => None,
+                        }).unwrap();
+                        let pos = children.iter().find_map(|c| match c {
+                            MatroskaSpec::SeekPosition(p) => Some(*p),
+                            _ => None,
+                        }).unwrap();
+                        parsed.seeks.push((id, pos));
+                    }
+                }
+            },
+            MatroskaSpec::Info(Master::Full(children)) => {
+                for c in children {
+                    match c {
+                        MatroskaSpec::TimestampScale(ts) => assert_eq!(ts, 1_000_000),
+                        MatroskaSpec::Duration(d) => parsed.duration = d,
+                        _ => (),
+                    }
+                }
+            },
+            MatroskaSpec::Tracks(Master::Full(children)) => {
+                for c in children {
+                    if let MatroskaSpec::TrackEntry(Master::Full(entry)) = c {
+                        parsed.tracks.push(entry);
+                    }
+                }
+            },
+            MatroskaSpec::Cluster(Master::Full(children)) => {
+                parsed.cluster_count += 1;
+                let mut timestamp = 0;
+                for c in children {
+                    match c {
+                        MatroskaSpec::Timestamp(ts) => timestamp = ts as i64,
+                        MatroskaSpec::SimpleBlock(data) => {
+                            assert!(data[0] & 0x80 != 0);
+                            let rel = i16::from_be_bytes([data[1], data[2]]);
+                            parsed.blocks.push((u64::from(data[0] & 0x7F), timestamp + i64::from(rel),
+                                                data[3] & 0x80 != 0, data[4..].to_vec()));
+                        },
+                        _ => (),
+                    }
+                }
+            },
+            MatroskaSpec::Cues(Master::Full(children)) => {
+                for c in children {
+                    if let MatroskaSpec::CuePoint(Master::Full(cp)) = c {
+                        let time = cp.iter().find_map(|c| match c {
+                            MatroskaSpec::CueTime(t) => Some(*t),
+                            _ => None,
+                        }).unwrap();
+                        let pos = cp.iter().find_map(|c| match c {
+                            MatroskaSpec::CueTrackPositions(Master::Full(tp)) => tp.iter().find_map(|c| match c {
+                                MatroskaSpec::CueClusterPosition(p) => Some(*p),
+                                _ => None,
+                            }),
+                            _ => None,
+                        }).unwrap();
+                        parsed.cues.push((time, pos));
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+    parsed
+}
+
+fn entry_string(entry: &[MatroskaSpec], pred: fn(&MatroskaSpec) -> Option<&String>) -> Option<String> {
+    entry.iter().find_map(pred).cloned()
+}
+
+fn entry_unsigned(entry: &[MatroskaSpec], pred: fn(&MatroskaSpec) -> Option<u64>) -> Option<u64> {
+    entry.iter().find_map(pred)
+}
+
+// Check that the SeekHead and Cues reference the expected elements.
+fn check_index(content: &[u8], parsed: &Parsed) {
+    let ids: Vec<&[u8]> = parsed.seeks.iter().map(|(id, _)| id.as_slice()).collect();
+    assert_eq!(ids, vec![&[0x15, 0x49, 0xA9, 0x66][..], &[0x16, 0x54, 0xAE, 0x6B], &[0x1C, 0x53, 0xBB, 0x6B]]);
+    for (id, pos) in &parsed.seeks {
+        let start = parsed.segment_data_start + *pos as usize;
+        assert_eq!(&content[start..start + 4], id.as_slice());
+    }
+    assert!(!parsed.cues.is_empty());
+    for (_, pos) in &parsed.cues {
+        let start = parsed.segment_data_start + *pos as usize;
+        assert_eq!(&content[start..start + 4], &[0x1F, 0x43, 0xB6, 0x75]);
+    }
+}
+
+
+#[tokio::test]
+async fn test_webm_native_mux() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6679, single_period_mpd()).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("native.webm");
+    DashDownloader::new("http://localhost:6679/mpd")
+        .prefer_audio_languages(vec!["en", "fr"])
+        .with_muxer_preference("webm", "native")
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    let content = fs::read(&out).unwrap();
+    let parsed = parse_output(&content);
+    check_index(&content, &parsed);
+    assert!((parsed.duration - 320.0).abs() < 0.01);
+    assert_eq!(parsed.tracks.len(), 3);
+    let codecs: Vec<String> = parsed.tracks.iter()
+        .map(|t| entry_string(t, |c| match c { MatroskaSpec::CodecID(id) => Some(id), _ => None }).unwrap())
+        .collect();
+    assert_eq!(codecs, vec!["V_VP9", "A_OPUS", "A_OPUS"]);
+    let languages: Vec<Option<String>> = parsed.tracks.iter()
+        .map(|t| entry_string(t, |c| match c { MatroskaSpec::Language(l) => Some(l), _ => None }))
+        .collect();
+    assert_eq!(languages, vec![None, Some(String::from("eng")), Some(String::from("fre"))]);
+    let defaults: Vec<Option<u64>> = parsed.tracks.iter()
+        .map(|t| entry_unsigned(t, |c| match c { MatroskaSpec::FlagDefault(f) => Some(*f), _ => None }))
+        .collect();
+    assert_eq!(defaults, vec![None, Some(1), Some(0)]);
+    // The blocks of each track are in timestamp order, with the expected content.
+    let video: Vec<_> = parsed.blocks.iter().filter(|b| b.0 == 1).collect();
+    assert_eq!(video.len(), 8);
+    for (n, b) in video.iter().enumerate() {
+        assert_eq!(b.1, (n as u64 * VIDEO_FRAME_DURATION) as i64);
+        assert_eq!(b.2, n % 4 == 0);
+        assert_eq!(b.3, video_frame(n));
+    }
+    for (track, marker) in [(2, 0xE0), (3, 0xF0)] {
+        let audio: Vec<_> = parsed.blocks.iter().filter(|b| b.0 == track).collect();
+        assert_eq!(audio.len(), 16);
+        for (n, b) in audio.iter().enumerate() {
+            assert_eq!(b.1, (n as u64 * AUDIO_FRAME_DURATION) as i64);
+            assert_eq!(b.3, audio_frame(marker, n));
+        }
+    }
+    // Blocks are interleaved in timestamp order, and a new Cluster starts at each video keyframe.
+    assert!(parsed.blocks.windows(2).all(|w| w[0].1 <= w[1].1));
+    assert_eq!(parsed.cluster_count, 2);
+    assert_eq!(parsed.cues.iter().map(|c| c.0).collect::<Vec<u64>>(), vec![0, 160]);
+}
+
+
+#[tokio::test]
+async fn test_webm_native_concat_periods() {
+    setup_logging();
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    serve_media(server_handle.clone(), 6680, two_period_mpd()).await;
+    let tmpd = tempfile::tempdir().unwrap();
+    let out = tmpd.path().join("concat.webm");
+    DashDownloader::new("http://localhost:6680/mpd")
+        .with_muxer_preference("webm", "native")
+        .with_concat_preference("webm", "native")
+        .download_to(&out).await
+        .unwrap();
+    server_handle.shutdown();
+    // The file for the second Period has been merged into the output and deleted.
+    assert!(!tmpd.path().join("concat-p2.webm").exists());
+    let content = fs::read(&out).unwrap();
+    let parsed = parse_output(&content);
+    check_index(&content, &parsed);
+    assert!((parsed.duration - 640.0).abs() < 0.01);
+    assert_eq!(parsed.tracks.len(), 2);
+    // The timestamps of the second Period follow on from those of the first.
+    let video: Vec<_> = parsed.blocks.iter().filter(|b| b.0 == 1).collect();
+    assert_eq!(video.len(), 16);
+    for (n, b) in video.iter().enumerate() {
+        assert_eq!(b.1, (n as u64 * VIDEO_FRAME_DURATION) as i64);
+        assert_eq!(b.3, video_frame(n % 8));
+    }
+    let audio: Vec<_> = parsed.blocks.iter().filter(|b| b.0 == 2).collect();
+    assert_eq!(audio.len(), 32);
+    for (n, b) in audio.iter().enumerate() {
+        assert_eq!(b.1, (n as u64 * AUDIO_FRAME_DURATION) as i64);
+        assert_eq!(b.3, audio_frame(0xE0, n % 16));
+    }
+    assert_eq!(parsed.cues.iter().map(|c| c.0).collect::<Vec<u64>>(), vec![0, 160, 320, 480]);
+}
@@ -0,0 +1,342 @@
+// Tests for the native conversion of WVTT subtitles (WebVTT in fragmented MP4) to WebVTT and SRT
+//
+// To run tests while enabling printing to stdout/stderr
+//
+//    cargo test --test wvtt -- --show-output
+
+
+pub mod common;
+use std::fs;
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+use axum::{routing::get, Router};
+use axum::extract::Path as AxumPath;
+use axum::response::{Response, IntoResponse};
+use axum::http::{header, StatusCode};
+use axum::body::Body;
+use axum_server::{Handle, bind};
+use pretty_assertions::assert_eq;
+use dash_mpd::fetch::DashDownloader;
+use dash_mpd::wvtt::WvttDocument;
+use anyhow::Result;
+use common::setup_logging;
+
+
+fn mp4_box(boxtype: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut b = Vec::new();
+    b.extend_from_slice(&(8 + payload.len() as u32).to_be_bytes());
+    b.extend_from_slice(boxtype);
+    b.extend_from_slice(payload);
+    b
+}
+
+fn full_box(boxtype: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut p = vec![version];
+    p.extend_from_slice(&flags.to_be_bytes()[1..]);
+    p.extend_from_slice(payload);
+    mp4_box(boxtype, &p)
+}
+
+// An initialization segment for a wvtt track with the specified timescale.
+fn wvtt_init_segment(timescale: u32) -> Vec<u8> {
+    let mut mdhd = vec![0u8; 8];
+    mdhd.extend_from_slice(&timescale.to_be_bytes());
+    mdhd.extend_from_slice(&[0u8; 8]);
+    let vttc = mp4_box(b"vttC", b"WEBVTT\n\nSTYLE\n::cue { color: yellow }");
+    let mut wvtt = vec![0u8, 0, 0, 0, 0, 0, 0, 1];
+    wvtt.extend_from_slice(&vttc);
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend_from_slice(&mp4_box(b"wvtt", &wvtt));
+    let stbl = mp4_box(b"stbl", &full_box(b"stsd", 0, 0, &stsd));
+    let minf = mp4_box(b"minf", &stbl);
+    let mut mdia = full_box(b"mdhd", 0, 0, &mdhd);
+    mdia.extend_from_slice(&minf);
+    let trak = mp4_box(b"trak", &mp4_box(b"mdia", &mdia));
+    let mut moov = full_box(b"mvhd", 0, 0, &[0u8; 96]);
+    moov.extend_from_slice(&trak);
+    let mut init = mp4_box(b"ftyp", b"iso6\0\0\0\0iso6dash");
+    init.extend_from_slice(&mp4_box(b"moov", &moov));
+    init
+}
+
+fn cue_sample(cues: &[(Option<&str>, Option<&str>, &str)]) -> Vec<u8> {
+    if cues.is_empty() {
+        return mp4_box(b"vtte", &[]);
+    }
+    let mut sample = Vec::new();
+    for (id, settings, text) in cues {
+        let mut vttc = Vec::new();
+        if let Some(id) = id {
+            vttc.extend_from_slice(&mp4_box(b"iden", id.as_bytes()));
+        }
+        if let Some(settings) = settings {
+            vttc.extend_from_slice(&mp4_box(b"sttg", settings.as_bytes()));
+        }
+        vttc.extend_from_slice(&mp4_box(b"payl", text.as_bytes()));
+        sample.extend_from_slice(&mp4_box(b"vttc", &vttc));
+    }
+    sample
+}
+
+// A media segment containing samples (with their durations), starting at decode time tfdt.
+fn wvtt_media_segment(tfdt: u64, samples: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    // tfhd with default-base-is-moof, track_ID 1
+    let tfhd = full_box(b"tfhd", 0, 0x020000, &1u32.to_be_bytes());
+    let tfdt = full_box(b"tfdt", 1, 0, &tfdt.to_be_bytes());
+    let trun_len = 8 + 4 + 4 + 4 + 8 * samples.len();
+    let moof_len = 8 + 16 + 8 + tfhd.len() + tfdt.len() + trun_len;
+    let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+    trun.extend_from_slice(&((moof_len + 8) as u32).to_be_bytes());
+    for (duration, data) in samples {
+        trun.extend_from_slice(&duration.to_be_bytes());
+        trun.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    }
+    let mut traf = tfhd;
+    traf.extend_from_slice(&tfdt);
+    traf.extend_from_slice(&full_box(b"trun", 0, 0x301, &trun));
+    let mut moof = full_box(b"mfhd", 0, 0, &1u32.to_be_bytes());
+    moof.extend_from_slice(&mp4_box(b"traf", &traf));
+    let mut segment = mp4_box(b"moof", &moof);
+    assert_eq!(segment.len(), moof_len);
+    let mdat: Vec<u8> = samples.iter().flat_map(|s| s.1.clone()).collect();
+    segment.extend_from_slice(&mp4_box(b"mdat", &mdat));
+    segment
+}
+
+fn test_segments() -> Vec<Vec<u8>> {
+    // Timescale of 90000: each second is 90000 ticks.
+    let seg1 = wvtt_media_segment(0, &[
+        (90000, cue_sample(&[(Some("intro"), Some("line:90% align:center"), "<v Narrator>Hello &amp; welcome</v>")])),
+        (45000, cue_sample(&[])),
+        (45000, cue_sample(&[(None, None, "<i>Spanning</i> two segments")])),
+    ]);
+    let seg2 = wvtt_media_segment(180000, &[
+        (45000, cue_sample(&[(None, None, "<i>Spanning</i> two segments"),
+                             (None, Some("line:10%"), "Overlapping")])),
+        (45000, cue_sample(&[(None, Some("line:10%"), "Overlapping")])),
+        (90000, cue_sample(&[])),
+    ]);
+    vec![wvtt_init_segment(90000), seg1, seg2]
+}
+
+
+#[test]
+fn test_wvtt_conversion() {
+    let mut doc = WvttDocument::new();
+    for seg in test_segments() {
+        doc.add_from_mp4(&seg).unwrap();
+    }
+    assert_eq!(doc.cues().len(), 3);
+    assert_eq!(doc.cues()[1].start_ms, 1500);
+    assert_eq!(doc.cues()[1].end_ms, 2500);
+    let vtt = doc.to_webvtt();
+    assert_eq!(vtt, "WEBVTT\n\nSTYLE\n::cue { color: yellow }\n\n\
+                     intro\n00:00:00.000 --> 00:00:01.000 line:90% align:center\n<v Narrator>Hello &amp; welcome</v>\n\n\
+                     00:00:01.500 --> 00:00:02.500\n<i>Spanning</i> two segments\n\n\
+                     00:00:02.000 --> 00:00:03.000 line:10%\nOverlapping\n\n");
+    let srt = doc.to_srt();
+    assert_eq!(srt, "1\n00:00:00,000 --> 00:00:01,000\nHello & welcome\n\n\
+                     2\n00:00:01,500 --> 00:00:02,500\n<i>Spanning</i> two segments\n\n\
+                     3\n00:00:02,000 --> 00:00:03,000\nOverlapping\n\n");
+
+    // Without an initialization segment, the timescale can be specified explicitly.
+    let mut doc = WvttDocument::new();
+    doc.set_timescale(90000);
+    for seg in test_segments().iter().skip(1) {
+        doc.add_from_mp4(seg).unwrap();
+    }
+    assert_eq!(doc.cues().len(), 3);
+    assert!(doc.to_webvtt().starts_with("WEBVTT\n\nintro\n"));
+
+    let mut truncated = test_segments().remove(1);
+    truncated.truncate(truncated.len() - 10);
+    assert!(WvttDocument::new().add_from_mp4(&truncated).is_err());
+}
+
+
+#[test]
+fn test_wvtt_timing() {
+    // tfdt values counted from the Unix epoch at a 10 MHz timescale.
+    let epoch_secs: u64 = 2_000_000_000;
+    let mut doc = WvttDocument::new();
+    doc.set_timescale(10_000_000);
+    let seg = wvtt_media_segment(epoch_secs * 10_000_000, &[
+        (10_000_000, cue_sample(&[(None, None, "Live")])),
+    ]);
+    doc.add_from_mp4(&seg).unwrap();
+    assert_eq!(doc.cues()[0].start_ms, epoch_secs * 1000);
+    assert_eq!(doc.cues()[0].end_ms, epoch_secs * 1000 + 1000);
+
+    // Two trun boxes without a data_offset: the data of the second follows that of the first.
+    let first = cue_sample(&[(None, None, "First")]);
+    let second = cue_sample(&[(None, None, "Second")]);
+    let trun = |data: &[u8]| {
+        let mut p = 1u32.to_be_bytes().to_vec();
+        p.extend_from_slice(&1000u32.to_be_bytes());
+        p.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        full_box(b"trun", 0, 0x300, &p)
+    };
+    let mut traf = full_box(b"tfhd", 0, 0x020000, &1u32.to_be_bytes());
+    traf.extend_from_slice(&full_box(b"tfdt", 1, 0, &0u64.to_be_bytes()));
+    traf.extend_from_slice(&trun(&first));
+    traf.extend_from_slice(&trun(&second));
+    let mut moof = full_box(b"mfhd", 0, 0, &1u32.to_be_bytes());
+    moof.extend_from_slice(&mp4_box(b"traf", &traf));
+    let mut seg = mp4_box(b"moof", &moof);
+    seg.extend_from_slice(&mp4_box(b"mdat", &[first, second].concat()));
+    let mut doc = WvttDocument::new();
+    doc.set_timescale(1000);
+    doc.add_from_mp4(&seg).unwrap();
+    let cues: Vec<_> = doc.cues().iter().map(|c| (c.payload.as_str(), c.start_ms, c.end_ms)).collect();
+    assert_eq!(cues, vec![("First", 0, 1000), ("Second", 1000, 2000)]);
+
+    // The segments of a second Period, which starts 60 seconds into the output media.
+    doc.set_offset_ms(60_000);
+    doc.add_from_mp4(&seg).unwrap();
+    assert_eq!(doc.cues().len(), 4);
+    assert_eq!(doc.cues()[2].start_ms, 60_000);
+    assert_eq!(doc.cues()[3].end_ms, 62_000);
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_wvtt_download() -> Result<()> {
+    async fn send_mpd() -> impl IntoResponse {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT4S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT4S">
+    <AdaptationSet id="1" contentType="text" mimeType="application/mp4" lang="en">
+      <SegmentTemplate timescale="90000" duration="180000" startNumber="1"
+                       initialization="/subs/init.mp4" media="/subs/seg-$Number$.m4s"/>
+      <Representation id="en" codecs="wvtt" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        ([(header::CONTENT_TYPE, "application/dash+xml")], xml)
+    }
+
+    // Without an initialization segment, the timescale of the WVTT samples is only specified in the
+    // manifest.
+    async fn send_mpd_noinit() -> impl IntoResponse {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT4S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT4S">
+    <AdaptationSet id="1" contentType="text" mimeType="application/mp4" lang="en">
+      <SegmentTemplate timescale="90000" duration="180000" startNumber="1"
+                       media="/subs/seg-$Number$.m4s"/>
+      <Representation id="en" codecs="wvtt" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        ([(header::CONTENT_TYPE, "application/dash+xml")], xml)
+    }
+
+    // Two Periods with the same subtitle segments, whose cues are written to a single file.
+    async fn send_mpd_periods() -> impl IntoResponse {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT8S"
+     minBufferTime="PT2S" profiles="urn:mpeg:dash:profile:isoff-live:2011">
+  <Period id="1" duration="PT4S">
+    <AdaptationSet id="1" contentType="text" mimeType="application/mp4" lang="en">
+      <SegmentTemplate timescale="90000" duration="180000" startNumber="1"
+                       initialization="/subs/init.mp4" media="/subs/seg-$Number$.m4s"/>
+      <Representation id="en" codecs="wvtt" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+  <Period id="2" duration="PT4S">
+    <AdaptationSet id="1" contentType="text" mimeType="application/mp4" lang="en">
+      <SegmentTemplate timescale="90000" duration="180000" startNumber="1"
+                       initialization="/subs/init.mp4" media="/subs/seg-$Number$.m4s"/>
+      <Representation id="en" codecs="wvtt" bandwidth="1000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        ([(header::CONTENT_TYPE, "application/dash+xml")], xml)
+    }
+
+    async fn send_segment(AxumPath(name): AxumPath<String>) -> Response {
+        let segments = test_segments();
+        let data = match name.as_str() {
+            "init.mp4" => segments[0].clone(),
+            "seg-1.m4s" => segments[1].clone(),
+            "seg-2.m4s" => segments[2].clone(),
+            _ => return StatusCode::NOT_FOUND.into_response(),
+        };
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/mp4")
+            .body(Body::from(data))
+            .unwrap()
+    }
+
+    setup_logging();
+    let app = Router::new()
+        .route("/mpd", get(send_mpd))
+        .route("/mpd-noinit", get(send_mpd_noinit))
+        .route("/mpd-periods", get(send_mpd_periods))
+        .route("/subs/{name}", get(send_segment));
+    let server_handle: Handle<SocketAddr> = Handle::new();
+    let backend_handle = server_handle.clone();
+    let backend = async move {
+        bind("127.0.0.1:6672".parse().unwrap())
+            .handle(backend_handle)
+            .serve(app.into_make_service()).await
+            .unwrap()
+    };
+    tokio::spawn(backend);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let outpath = env::temp_dir().join("wvtt-native.mp4");
+    let vttpath = outpath.with_extension("vtt");
+    let srtpath = outpath.with_extension("srt");
+    let _ = fs::remove_file(&vttpath);
+    let _ = fs::remove_file(&srtpath);
+    // Make sure that we aren't relying on MP4Box for the conversion.
+    DashDownloader::new("http://localhost:6672/mpd")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .with_mp4box("/nonexistent/MP4Box")
+        .download_to(&outpath).await
+        .unwrap();
+    let vtt = fs::read_to_string(&vttpath)?;
+    assert!(vtt.starts_with("WEBVTT\n"));
+    assert!(vtt.contains("00:00:01.500 --> 00:00:02.500\n<i>Spanning</i> two segments"));
+    let srt = fs::read_to_string(&srtpath)?;
+    assert!(srt.contains("3\n00:00:02,000 --> 00:00:03,000\nOverlapping\n"));
+
+    let outpath = env::temp_dir().join("wvtt-noinit.mp4");
+    let vttpath = outpath.with_extension("vtt");
+    let _ = fs::remove_file(&vttpath);
+    DashDownloader::new("http://localhost:6672/mpd-noinit")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .with_mp4box("/nonexistent/MP4Box")
+        .download_to(&outpath).await
+        .unwrap();
+    let vtt = fs::read_to_string(&vttpath)?;
+    assert!(vtt.contains("00:00:00.000 --> 00:00:01.000 line:90% align:center\n"));
+    assert!(vtt.contains("00:00:01.500 --> 00:00:02.500\n<i>Spanning</i> two segments"));
+    assert!(vtt.contains("00:00:02.000 --> 00:00:03.000 line:10%\nOverlapping"));
+
+    let outpath = env::temp_dir().join("wvtt-periods.mp4");
+    let vttpath = outpath.with_extension("vtt");
+    let _ = fs::remove_file(&vttpath);
+    DashDownloader::new("http://localhost:6672/mpd-periods")
+        .fetch_audio(false)
+        .fetch_video(false)
+        .fetch_subtitles(true)
+        .with_mp4box("/nonexistent/MP4Box")
+        .download_to(&outpath).await
+        .unwrap();
+    let vtt = fs::read_to_string(&vttpath)?;
+    assert_eq!(vtt.matches("Overlapping").count(), 2);
+    assert!(vtt.contains("intro\n00:00:00.000 --> 00:00:01.000 line:90% align:center\n"));
+    assert!(vtt.contains("intro\n00:00:04.000 --> 00:00:05.000 line:90% align:center\n"));
+    assert!(vtt.contains("00:00:06.000 --> 00:00:07.000 line:10%\nOverlapping"));
+    server_handle.shutdown();
+    Ok(())
+}